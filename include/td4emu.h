@@ -0,0 +1,67 @@
+#ifndef TD4EMU_H
+#define TD4EMU_H
+
+/* Generated by cbindgen from src/capi.rs. Don't edit by hand. */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+typedef struct Td4Machine Td4Machine;
+
+typedef struct Td4State {
+  uint8_t pc;
+  uint8_t a;
+  uint8_t b;
+  uint8_t carry;
+  uint8_t input;
+  uint8_t output;
+  uint64_t cycles;
+  bool halted;
+} Td4State;
+
+// 直前に失敗した関数のエラーメッセージ。同じスレッドで次に失敗するまで有効
+const char *td4_last_error(void);
+
+// ソースコードをアセンブルして out に書き込み、バイト数を返す。失敗すると -1
+//
+// # Safety
+// source はNUL終端の文字列、out は capacity バイト書き込める領域であること
+int32_t td4_assemble(const char *source,
+                     uint8_t *out,
+                     size_t capacity);
+
+// ROMを読み込んだマシンを作る。profile が NULL なら td4-strict。失敗すると NULL
+//
+// # Safety
+// rom は len バイト読める領域、profile はNULLかNUL終端の文字列であること
+struct Td4Machine *td4_machine_new(const uint8_t *rom,
+                                   size_t len,
+                                   const char *profile);
+
+// td4_machine_new で作ったマシンを解放する。NULLなら何もしない
+//
+// # Safety
+// machine は td4_machine_new が返したもので、まだ解放していないこと
+void td4_machine_free(struct Td4Machine *machine);
+
+// 1命令実行して0を返す。停止していれば何もせず1、エラーなら -1
+//
+// # Safety
+// machine は td4_machine_new が返した有効なポインタであること
+int32_t td4_step(struct Td4Machine *machine);
+
+// 今の状態を state に書き込む。成功すると0、失敗すると -1
+//
+// # Safety
+// machine は有効なポインタ、state は Td4State を書き込める領域であること
+int32_t td4_get_state(const struct Td4Machine *machine, struct Td4State *state);
+
+// 入力ポートに値を入れる。成功すると0、レジスタの幅に収まらなければ -1
+//
+// # Safety
+// machine は td4_machine_new が返した有効なポインタであること
+int32_t td4_set_input(struct Td4Machine *machine,
+                      uint8_t value);
+
+#endif  /* TD4EMU_H */
@@ -0,0 +1,207 @@
+use crate::assembler::Assembler;
+use crate::debugger::parse_number;
+use crate::disassembler;
+use crate::error::EmulatorErr;
+use crate::machine::{Machine, StopReason};
+use crate::parser::Syntax;
+use crate::profile::MachineProfile;
+
+// td4asm の1行ごとの処理。命令を打つとその場でアセンブルしてバイトを見せる
+// : で始まる行はコマンド (:run, :rom, :input, :clear, :help)
+pub struct AsmRepl {
+    assembler: Assembler,
+    syntax: Syntax,
+    profile: MachineProfile,
+    // :run で入力ポート0に入れる値
+    input: u8,
+}
+
+pub const HELP: &str = "\
+instruction      assemble the line and show the bytes it placed
+:run [cycles]    run the program so far (at most 1000 cycles by default)
+:input n         set input port 0 for :run
+:rom             list the whole ROM
+:clear           start over with an empty ROM
+:help            show this message
+:quit            leave";
+
+impl AsmRepl {
+    pub fn new(syntax: Syntax, profile: MachineProfile) -> Self {
+        let mut assembler = Assembler::new(syntax);
+        assembler.set_mode(profile.mode.clone());
+        AsmRepl {
+            assembler,
+            syntax,
+            profile,
+            input: 0,
+        }
+    }
+
+    pub fn assembler(&self) -> &Assembler {
+        &self.assembler
+    }
+
+    // 1行を処理して表示するメッセージを返す
+    pub fn execute(&mut self, line: &str) -> Result<String, EmulatorErr> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [":run"] => self.run(1000),
+            [":run", cycles] => match cycles.parse() {
+                Ok(cycles) => self.run(cycles),
+                Err(_) => Err(EmulatorErr::new(&format!(
+                    "Invalid cycle count: {}",
+                    cycles
+                ))),
+            },
+            [":input", value] => {
+                self.input = parse_number(value)? & self.profile.register_mask();
+                Ok(format!("input = 0b{:04b}", self.input))
+            }
+            [":rom"] => Ok(disassembler::listing(
+                self.assembler.current_rom(),
+                Some(self.assembler.debug_info()),
+            )
+            .trim_end()
+            .to_string()),
+            [":clear"] => {
+                self.assembler = Assembler::new(self.syntax);
+                self.assembler.set_mode(self.profile.mode.clone());
+                Ok("cleared".to_string())
+            }
+            [":help"] => Ok(HELP.to_string()),
+            [command, ..] if command.starts_with(':') => Err(EmulatorErr::new(&format!(
+                "unknown command {}. Type :help for the list",
+                command
+            ))),
+            _ => self.assemble(line),
+        }
+    }
+
+    // 置いたバイトを1行ずつ表示し、最後にここまでのROMを表示する
+    fn assemble(&mut self, line: &str) -> Result<String, EmulatorErr> {
+        let start = self.assembler.address();
+        if self.assembler.push_line(line)?.is_none() {
+            return Ok(String::new());
+        }
+        let rom = self.assembler.current_rom();
+        let mut lines: Vec<String> = rom[start..]
+            .iter()
+            .enumerate()
+            .map(|(offset, byte)| {
+                format!(
+                    "0x{:x}  {:04b} {:04b}  {}",
+                    start + offset,
+                    byte >> 4,
+                    byte & 0x0f,
+                    disassembler::disassemble(*byte)
+                )
+            })
+            .collect();
+        let bytes: Vec<String> = rom.iter().map(|byte| format!("{:02x}", byte)).collect();
+        lines.push(format!(
+            "rom ({}/{}): {}",
+            rom.len(),
+            self.profile.rom_size,
+            bytes.join(" ")
+        ));
+        let unresolved: Vec<&str> = self
+            .assembler
+            .unresolved()
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect();
+        if !unresolved.is_empty() {
+            lines.push(format!("waiting for {}", unresolved.join(", ")));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    // ここまでのプログラムを最初から実行する
+    fn run(&self, cycles: usize) -> Result<String, EmulatorErr> {
+        let (rom, _) = self.assembler.finish()?;
+        let mut machine = Machine::new(self.profile.clone());
+        machine.set_quiet(true);
+        machine.load_rom(rom)?;
+        machine.set_inputs(&[self.input])?;
+        let stop = match machine.run(Some(cycles))? {
+            StopReason::CycleLimit => format!("stopped after {} cycles", cycles),
+            _ => format!("halted after {} cycles", machine.emulator().cycles()),
+        };
+        let emulator = machine.emulator();
+        let register = emulator.register();
+        let mut message = format!(
+            "{}: a={:04b} b={:04b} c={} pc=0x{:x} out={:04b}",
+            stop,
+            register.register_a(),
+            register.register_b(),
+            register.carry_flag(),
+            register.pc(),
+            emulator.output()
+        );
+        let outputs: Vec<String> = emulator
+            .output_history()
+            .iter()
+            .map(|(_, output)| format!("{:04b}", output))
+            .collect();
+        if !outputs.is_empty() {
+            message.push_str(&format!("\noutputs: {}", outputs.join(" ")));
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod asm_repl_tests {
+    use crate::asm_repl::AsmRepl;
+    use crate::parser::Syntax;
+    use crate::profile::MachineProfile;
+
+    #[test]
+    fn test_session() {
+        let mut repl = AsmRepl::new(Syntax::V1, MachineProfile::default());
+        assert_eq!(
+            repl.execute("mov A 0011").unwrap(),
+            "0x0  0011 0011  mov A 0011\nrom (1/16): 33"
+        );
+        assert_eq!(repl.execute("").unwrap(), "");
+        assert_eq!(
+            repl.execute("add A 0001").unwrap(),
+            "0x1  0000 0001  add A 0001\nrom (2/16): 33 01"
+        );
+        assert!(repl.execute("mov C 0001").is_err());
+        repl.execute("out B").unwrap();
+        repl.execute("mov B A").unwrap();
+        repl.execute("out B").unwrap();
+        assert_eq!(
+            repl.execute(":run").unwrap(),
+            "halted after 5 cycles: a=0100 b=0100 c=0 pc=0x5 out=0100\noutputs: 0000 0100"
+        );
+
+        assert_eq!(repl.execute(":input 0b0110").unwrap(), "input = 0b0110");
+        repl.execute(":clear").unwrap();
+        repl.execute("in A").unwrap();
+        repl.execute("jmp 0000").unwrap();
+        assert_eq!(
+            repl.execute(":run 4").unwrap(),
+            "stopped after 4 cycles: a=0110 b=0000 c=0 pc=0x0 out=0000"
+        );
+        assert!(repl.execute(":walk").is_err());
+    }
+
+    #[test]
+    fn test_forward_label() {
+        let mut repl = AsmRepl::new(Syntax::V2, MachineProfile::default());
+        assert_eq!(
+            repl.execute("jmp end").unwrap(),
+            "0x0  1111 0000  jmp 0000\nrom (1/16): f0\nwaiting for end"
+        );
+        // ラベルが決まるまで実行できない
+        assert!(repl.execute(":run").is_err());
+        repl.execute("out 1").unwrap();
+        assert_eq!(
+            repl.execute("end: out 2").unwrap(),
+            "0x2  1011 0010  out 0010\nrom (3/16): f2 b1 b2"
+        );
+        assert!(repl.execute(":run").unwrap().ends_with("outputs: 0010"));
+    }
+}
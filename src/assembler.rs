@@ -0,0 +1,331 @@
+use crate::compiler::Compiler;
+use crate::debug_info::DebugInfo;
+use crate::directive;
+use crate::error::EmulatorErr;
+use crate::macros::MacroExpander;
+use crate::mode::Mode;
+use crate::parser::{strip_comment, Parser, Syntax};
+use crate::token::Token;
+use std::collections::HashMap;
+
+// 入力された行をその場でアセンブルする (TUIやWebの入力欄から使う)
+// 新しい行だけをパースしてROMの後ろに置くので、エラーは打った行ですぐにわかる
+// まだ定義されていないラベルを使った行 (V2) は 0 を仮に置き、ラベルが定義されたときに書き直す
+#[derive(Clone)]
+pub struct Assembler {
+    syntax: Syntax,
+    expander: MacroExpander,
+    // in / out B のポート番号を読むかは実行するモードで決まる
+    mode: Mode,
+    compiler: Compiler,
+    // 受け付けた行。マクロの定義と .alias 以外は空にして、展開し直したときの行番号を合わせる
+    definitions: Vec<String>,
+    // .macro から .endm までの途中
+    defining: bool,
+    rom: Vec<u8>,
+    debug_info: DebugInfo,
+    labels: HashMap<String, i64>,
+    pending: Vec<Pending>,
+}
+
+// 前方参照のラベルが決まるのを待っている行
+#[derive(Clone)]
+struct Pending {
+    line: usize,
+    text: String,
+    address: usize,
+    // 待っているラベル
+    names: Vec<String>,
+    // この行で定義したラベル。書き直すときは定義し直さない
+    defined: Vec<String>,
+}
+
+impl Assembler {
+    pub fn new(syntax: Syntax) -> Self {
+        Self::with_expander(syntax, MacroExpander::new())
+    }
+
+    pub fn with_expander(syntax: Syntax, expander: MacroExpander) -> Self {
+        Assembler {
+            syntax,
+            expander,
+            mode: Mode::default(),
+            compiler: Compiler::new(),
+            definitions: Vec::new(),
+            defining: false,
+            rom: Vec::new(),
+            debug_info: DebugInfo::new(),
+            labels: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    // 1行をアセンブルして、置いたバイト (複数なら最後のもの) を返す
+    // 空行、コメント、ラベルだけの行やマクロの定義は何も置かないので None
+    // エラーになった行は受け付けず、それまでの状態はそのまま残る
+    pub fn push_line(&mut self, text: &str) -> Result<Option<u8>, EmulatorErr> {
+        let mut next = self.clone();
+        let byte = next.accept(text)?;
+        *self = next;
+        Ok(byte)
+    }
+
+    // ここまでのROM。前方参照の行はまだ 0 のまま
+    pub fn current_rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn debug_info(&self) -> &DebugInfo {
+        &self.debug_info
+    }
+
+    // 次の行が置かれるアドレス
+    pub fn address(&self) -> usize {
+        self.rom.len()
+    }
+
+    pub fn labels(&self) -> &HashMap<String, i64> {
+        &self.labels
+    }
+
+    // まだ定義されていないラベルの名前と、それを使った行番号
+    pub fn unresolved(&self) -> Vec<(usize, &str)> {
+        self.pending
+            .iter()
+            .flat_map(|pending| {
+                pending
+                    .names
+                    .iter()
+                    .map(|name| (pending.line, name.as_str()))
+            })
+            .collect()
+    }
+
+    // 全ての行を入力し終えたROM。ラベルが足りなければエラー
+    pub fn finish(&self) -> Result<(Vec<u8>, DebugInfo), EmulatorErr> {
+        if self.defining {
+            return Err(EmulatorErr::new(&format!(
+                "line {}: macro is missing .endm",
+                self.definitions.len()
+            )));
+        }
+        if let Some((line, name)) = self.unresolved().first() {
+            return Err(EmulatorErr::new(&format!(
+                "line {}: label {} is never defined",
+                line, name
+            )));
+        }
+        Ok((self.rom.clone(), self.debug_info.clone()))
+    }
+
+    fn accept(&mut self, text: &str) -> Result<Option<u8>, EmulatorErr> {
+        let line = self.definitions.len() + 1;
+        let text = match self.syntax {
+            Syntax::V1 => directive::strip(text),
+            Syntax::V2 => strip_comment(text).to_string(),
+        };
+        let op = text.split_whitespace().next().unwrap_or("");
+        // マクロの定義と別名はこれからの行を展開するときに使う
+        if self.defining || op == ".macro" || op == ".alias" {
+            self.defining = match op {
+                ".macro" => true,
+                ".endm" => false,
+                _ => self.defining,
+            };
+            self.definitions.push(text);
+            if !self.defining {
+                self.expander.expand(&self.definitions.join("\n"))?;
+            }
+            return Ok(None);
+        }
+        self.definitions.push(String::new());
+
+        let address = self.rom.len();
+        let tokens = match self.parse(&text, line, address, &self.labels) {
+            Ok((tokens, labels)) => {
+                self.labels = labels;
+                tokens
+            }
+            Err(err) => self.defer(&text, line, address).ok_or(err)?,
+        };
+        self.place(tokens)?;
+        self.resolve_pending()?;
+        Ok(self.rom[address..].last().copied())
+    }
+
+    // 1行を address から始まるものとしてトークンにする
+    // labels にこの行で定義したラベルを足したものも返す
+    fn parse(
+        &self,
+        text: &str,
+        line: usize,
+        address: usize,
+        labels: &HashMap<String, i64>,
+    ) -> Result<(Vec<Token>, HashMap<String, i64>), EmulatorErr> {
+        let mut source = self.definitions[..line - 1].to_vec();
+        source.push(text.to_string());
+        let lines = self
+            .expander
+            .expand(&source.join("\n"))?
+            .into_iter()
+            .filter(|expanded| expanded.file_line() == line)
+            .collect();
+        let mut parser = Parser::with_syntax(lines, self.syntax)?;
+        parser.set_mode(&self.mode);
+        parser.place_at(address, labels)?;
+        let tokens = parser.parse()?;
+        Ok((tokens, parser.labels().clone()))
+    }
+
+    // 知らない名前を 0 として読めるなら、前方参照とみなして後で書き直す
+    fn defer(&mut self, text: &str, line: usize, address: usize) -> Option<Vec<Token>> {
+        if self.syntax != Syntax::V2 {
+            return None;
+        }
+        let operands = text.split_once(':').map_or(text, |(_, rest)| rest);
+        let mut names: Vec<String> = operands
+            .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .skip_while(|word| word.is_empty())
+            .skip(1)
+            .filter(|word| word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_'))
+            .filter(|word| !matches!(*word, "A" | "B") && !self.labels.contains_key(*word))
+            .map(str::to_string)
+            .collect();
+        names.dedup();
+        // .org の行き先が決まらないと後ろのアドレスが決まらない
+        if names.is_empty() || operands.trim_start().starts_with(".org") {
+            return None;
+        }
+        let mut labels = self.labels.clone();
+        labels.extend(names.iter().map(|name| (name.clone(), 0)));
+        let (tokens, mut labels) = self.parse(text, line, address, &labels).ok()?;
+        labels.retain(|name, _| !names.contains(name));
+        let defined = labels
+            .keys()
+            .filter(|name| !self.labels.contains_key(*name))
+            .cloned()
+            .collect();
+        self.labels = labels;
+        self.pending.push(Pending {
+            line,
+            text: text.to_string(),
+            address,
+            names,
+            defined,
+        });
+        Some(tokens)
+    }
+
+    fn place(&mut self, tokens: Vec<Token>) -> Result<(), EmulatorErr> {
+        for token in tokens {
+            self.compiler
+                .place(token, &mut self.rom, &mut self.debug_info)?;
+        }
+        Ok(())
+    }
+
+    // ラベルが揃った前方参照の行をアセンブルし直して、仮に置いたバイトを書き換える
+    fn resolve_pending(&mut self) -> Result<(), EmulatorErr> {
+        let (ready, waiting): (Vec<Pending>, Vec<Pending>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|pending| {
+                pending
+                    .names
+                    .iter()
+                    .all(|name| self.labels.contains_key(name))
+            });
+        self.pending = waiting;
+        for pending in ready {
+            let mut labels = self.labels.clone();
+            labels.retain(|name, _| !pending.defined.contains(name));
+            let (tokens, _) = self.parse(&pending.text, pending.line, pending.address, &labels)?;
+            // 同じ行なので置くバイトの数は変わらない
+            let mut bytes = Vec::new();
+            let mut debug_info = DebugInfo::new();
+            for token in tokens {
+                self.compiler.place(token, &mut bytes, &mut debug_info)?;
+            }
+            self.rom[pending.address..pending.address + bytes.len()].copy_from_slice(&bytes);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod assembler_tests {
+    use crate::assembler::Assembler;
+    use crate::compiler::{assemble, assemble_with_syntax};
+    use crate::macros::MacroExpander;
+    use crate::parser::Syntax;
+
+    #[test]
+    fn test_push_line() {
+        let mut assembler = Assembler::new(Syntax::V1);
+        assert_eq!(assembler.push_line("mov A 0011").unwrap(), Some(0b00110011));
+        assert_eq!(assembler.push_line("").unwrap(), None);
+        // 打ち間違えた行は受け付けず、アドレスは進まない
+        let err = assembler.push_line("mov C 0001").unwrap_err();
+        assert_eq!(err.to_string(), "line 3: unknown register: C");
+        assert_eq!(assembler.address(), 1);
+        assert_eq!(assembler.push_line("jmp $").unwrap(), Some(0b11110001));
+        // 組み込みマクロは複数のバイトになる
+        assert_eq!(assembler.push_line("sec").unwrap(), Some(0b00000001));
+        assert_eq!(
+            assembler.current_rom(),
+            assemble("mov A 0011\njmp $\nsec").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_macros() {
+        let mut assembler = Assembler::new(Syntax::V1);
+        for line in [".macro blink value", "out value", "out 0000", ".endm"] {
+            assert_eq!(assembler.push_line(line).unwrap(), None);
+        }
+        assembler.push_line(".alias counter B").unwrap();
+        assembler.push_line("blink 1111").unwrap();
+        assembler.push_line("add counter 0001").unwrap();
+        assert_eq!(
+            assembler.current_rom(),
+            [0b10111111, 0b10110000, 0b01010001]
+        );
+        assert!(assembler.push_line("blink").is_err());
+    }
+
+    #[test]
+    fn test_forward_labels() {
+        let source = "start: in A\n        add A, 1   ; skip on overflow\n        jnc done\n        out 0b1111\ndone:   out B\n        jmp start";
+        let mut assembler = Assembler::new(Syntax::V2);
+        let mut lines = source.lines();
+        for line in lines.by_ref().take(4) {
+            assembler.push_line(line).unwrap();
+        }
+        // done はまだないので仮に 0 を置く
+        assert_eq!(assembler.current_rom()[2], 0b11100000);
+        assert_eq!(assembler.unresolved(), vec![(3, "done")]);
+        assert!(assembler.finish().is_err());
+
+        for line in lines {
+            assembler.push_line(line).unwrap();
+        }
+        assert!(assembler.unresolved().is_empty());
+        let (expected, _) =
+            assemble_with_syntax(source, &MacroExpander::new(), Syntax::V2).unwrap();
+        assert_eq!(assembler.finish().unwrap().0, expected);
+        assert_eq!(assembler.labels()["done"], 4);
+
+        // 同じラベルをもう一度定義した行は受け付けない
+        let err = assembler.push_line("done: out 0").unwrap_err();
+        assert_eq!(err.to_string(), "line 7: label done is already defined");
+
+        // 前方参照の行で定義したラベルは、書き直すときに定義し直さない
+        let mut assembler = Assembler::new(Syntax::V2);
+        assembler.push_line("top: jnc end").unwrap();
+        assembler.push_line("end: jmp top").unwrap();
+        assert_eq!(assembler.finish().unwrap().0, vec![0b11100001, 0b11110000]);
+    }
+}
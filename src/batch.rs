@@ -0,0 +1,102 @@
+// 互いに独立した実行をまとめて走らせる
+// parallel フィーチャーがあればrayonのスレッドプールに分け、なければ順に実行する
+// job はそれぞれ自分のエミュレータを作るので、スレッドのあいだで状態を共有しない
+// 結果は items と同じ順に並ぶ。スレッド数は RAYON_NUM_THREADS で変えられる
+#[cfg(feature = "parallel")]
+pub fn map<T, R, F>(items: Vec<T>, job: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync + Send,
+{
+    use rayon::prelude::*;
+    items.into_par_iter().map(job).collect()
+}
+
+#[cfg(not(feature = "parallel"))]
+pub fn map<T, R, F>(items: Vec<T>, job: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync + Send,
+{
+    items.into_iter().map(job).collect()
+}
+
+// job が Some を返した最初の item の位置と結果
+// 順に実行するときはそこで止め、並列のときも items の順で最初のものを返す
+#[cfg(feature = "parallel")]
+pub fn find_map_first<T, R, F>(items: Vec<T>, job: F) -> Option<(usize, R)>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> Option<R> + Sync + Send,
+{
+    use rayon::prelude::*;
+    items
+        .into_par_iter()
+        .enumerate()
+        .find_map_first(|(index, item)| job(item).map(|result| (index, result)))
+}
+
+#[cfg(not(feature = "parallel"))]
+pub fn find_map_first<T, R, F>(items: Vec<T>, job: F) -> Option<(usize, R)>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> Option<R> + Sync + Send,
+{
+    items
+        .into_iter()
+        .enumerate()
+        .find_map(|(index, item)| job(item).map(|result| (index, result)))
+}
+
+#[cfg(test)]
+mod batch_tests {
+    use crate::batch::{find_map_first, map};
+    use crate::emulator::CpuEmulator;
+    use crate::machine::Machine;
+    use crate::profile::MachineProfile;
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn test_map() {
+        // ワーカーごとに Machine を作って入力ごとに実行する
+        let outputs = map((0..16).collect(), |input: u8| {
+            let mut machine = Machine::new(MachineProfile::default());
+            machine.set_quiet(true);
+            machine
+                .load_source("in A\nadd A 0001\nmov B A\nout B\njmp 0100")
+                .unwrap();
+            machine.set_inputs(&[input]).unwrap();
+            machine.run(None).unwrap();
+            machine.emulator().output()
+        });
+        let expected: Vec<u8> = (0..16).map(|input| (input + 1) & 0b1111).collect();
+        assert_eq!(outputs, expected);
+
+        assert_send::<CpuEmulator>();
+        assert_send::<Machine>();
+    }
+
+    #[test]
+    fn test_find_map_first() {
+        let found = find_map_first((0..16).collect(), |n: u8| (n % 5 == 4).then_some(n * 2));
+        assert_eq!(found, Some((4, 8)));
+        assert_eq!(find_map_first(vec![1, 2], |_: u8| None::<u8>), None);
+
+        // 順に実行するときは見つかったところで止まる
+        #[cfg(not(feature = "parallel"))]
+        {
+            use std::sync::atomic::{AtomicUsize, Ordering};
+            let runs = AtomicUsize::new(0);
+            find_map_first((0..16).collect(), |n: u8| {
+                runs.fetch_add(1, Ordering::Relaxed);
+                (n == 2).then_some(n)
+            });
+            assert_eq!(runs.load(Ordering::Relaxed), 3);
+        }
+    }
+}
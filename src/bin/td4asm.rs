@@ -0,0 +1,55 @@
+use std::io::{BufRead, Write};
+use td4emu::asm_repl::AsmRepl;
+use td4emu::parser::Syntax;
+use td4emu::profile::MachineProfile;
+use td4emu::style::{self, ColorChoice};
+
+const USAGE: &str = "Usage: td4asm [--syntax v1|v2] [--profile td4-strict|td4-book|td4-extended] [--color auto|always|never]";
+
+// 1行ずつ命令を打ってエンコードを確かめる対話型のアセンブラ
+fn main() {
+    let mut syntax = Syntax::V1;
+    let mut profile = MachineProfile::default();
+    let mut color = ColorChoice::Auto;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| panic!("{} requires a value. {}", arg, USAGE))
+        };
+        let parsed = match arg.as_str() {
+            "--syntax" => value().parse().map(|parsed| syntax = parsed),
+            "--profile" => value().parse().map(|parsed| profile = parsed),
+            "--color" => value().parse().map(|parsed| color = parsed),
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => panic!("Unknown option: {}. {}", arg, USAGE),
+        };
+        parsed.unwrap_or_else(|err| panic!("{}", err));
+    }
+    style::init(color);
+
+    println!("td4asm: type an instruction to see its encoding, :help for commands");
+    let mut repl = AsmRepl::new(syntax, profile);
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("(td4asm 0x{:x}) ", repl.assembler().address());
+        std::io::stdout().flush().unwrap();
+
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        if matches!(line.trim(), ":quit" | ":q") {
+            break;
+        }
+        match repl.execute(&line) {
+            Ok(message) if message.is_empty() => (),
+            Ok(message) => println!("{}", message),
+            Err(err) => println!("{}", style::current().error(&err.to_string())),
+        }
+    }
+}
@@ -1,40 +1,2049 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, Write};
+use td4emu::capture::{self, Capture};
+use td4emu::compiler::{assemble_with_lines, assemble_with_profile};
+use td4emu::completions::{CommandLine, Shell};
+use td4emu::cost::TableCost;
+use td4emu::debug_info::DebugInfo;
+use td4emu::debugger::{self, Debugger, Snapshot};
+use td4emu::directive::ProgramConfig;
+use td4emu::disassembler::{self, DisasmStyle};
 use td4emu::emulator::CpuEmulator;
-use td4emu::port::Port;
+use td4emu::equiv::{self, EquivConfig};
+use td4emu::error::EmulatorErr;
+use td4emu::examples;
+use td4emu::expected_trace::{self, ExpectedTrace};
+use td4emu::explain::Explainer;
+use td4emu::fault::{self, Effect, Fault, FaultConfig};
+use td4emu::fuzz::{self, FuzzConfig, Semantics};
+use td4emu::grader::{self, GradeSpec};
+use td4emu::json::Json;
+use td4emu::lint::{self, Warning};
+use td4emu::machine::{Machine, StopReason};
+use td4emu::macros::MacroExpander;
+use td4emu::mode::{Extensions, Interrupt, Mode};
+use td4emu::model_check::{self, CheckConfig, Property};
+use td4emu::pack;
+use td4emu::parser::Syntax;
+use td4emu::patch::{self, Patch};
+use td4emu::pipeline::{self, PipelineSimulator};
+use td4emu::port::{InputStream, Ports};
+use td4emu::preset;
+use td4emu::profile::MachineProfile;
+use td4emu::profiler;
+use td4emu::quiz::Quiz;
 use td4emu::register::Register;
-use td4emu::rom::Rom;
-use td4emu::compiler::Compiler;
-use td4emu::parser::Parser;
+use td4emu::renderer::{OutputFormat, OutputStream};
+use td4emu::replay::ReplayManifest;
+use td4emu::rom::{Rom, RomMetadata};
+use td4emu::sandbox::ExecConfig;
+use td4emu::session::DebugSession;
+use td4emu::smt::{self, SmtConfig};
+use td4emu::state_graph;
+use td4emu::stimulus::Stimulus;
+use td4emu::style::{self, ColorChoice};
+use td4emu::svg;
+use td4emu::sweep;
+use td4emu::switches::{BitOrder, SwitchBank};
+use td4emu::symbolic::{self, SymbolicConfig};
+use td4emu::table::{self, TableFormat};
+use td4emu::testgen::{self, TestGenConfig};
+use td4emu::timing::{self, ClockJitter};
+use td4emu::tracer::{self, TraceRecord, TracerConfig};
+
+const USAGE: &str = "Usage: [command] [run | watch | debug] [--session file.td4dbg] [--preset name] [--profile td4-strict|td4-book|td4-extended] [--extended] [--interrupt bit:vector] [--ports n] [--outputs latched|pulsed] [--flag-model alu-carry|arithmetic-only|jumps-keep-carry] [--input-sampling direct|registered] [--beyond-rom zero|halt|error|wrap] [--debounce n] [--no-halt-on-self-jump] [--led | --out-format led|bin|dec|hex] [--show-output-history] [--explain] [--json] [--stats [--cost weights.toml]] [--clock hz [--jitter pct] [--drift pct] [--seed n]] [--gates] [--trace file [--trace-format text|binary] [--trace-last n]] [--no-builtin-macros] [--syntax v1|v2] [--allow-truncation] [--bit-order msb-first|lsb-first] [--cycles n] [--input n] [--stimulus script|file] [--emit-manifest file] [--out-stream path|- [--stream-cycles]] [--in-stream path|tcp:host:port|- [--in-default n]] [file_path | --example name]
+       [command] pipeline [--profile name] [--cycles n] [file_path | --example name]
+       [command] profile [--profile name] [--cycles n] [--annotate | --json] [file_path | --example name]
+       [command] table [--profile name] [--cycles n] [--table-format md|csv] [file_path | --example name]
+       [command] disasm [--disasm-style list] [file_path | --example name]
+       [command] dump [--profile name] [--syntax v1|v2] [--disasm-style list] [file_path | --example name]
+       [command] build output.td4rom [--profile name] [--name text] [--json] [file_path | --example name]
+       [command] switches [--bit-order msb-first|lsb-first] [file_path]
+       [command] compare capture.csv [--profile name] [--clock hz] [file_path | --example name]
+       [command] verify-trace [--profile name] [--input n] [file_path | --example name] expected_trace.csv
+       [command] trace-print [--disasm-style list] trace.bin
+       [command] trace-view trace.bin
+       [command] trace-view --live [--profile name] [--cycles n] [file_path | --example name]
+       [command] replay manifest.txt [--out-format led|bin|dec|hex]
+       [command] grade --spec spec.toml file_path...
+       [command] romdiff a.bin b.bin
+       [command] rompatch base.bin patch.toml [-o patched.bin] [--profile name]
+       [command] equiv a.sasm b.sasm [--registers] [--profile name] [--cycles n]
+       [command] sweep [--inputs 0..16] [--sequence | --json] [--profile name] [--cycles n] [file_path | --example name]
+       [command] symbolic [--registers] [--unroll n] [--profile name] [file_path | --example name]
+       [command] run --check property [--json] [--profile name] [--cycles n] [file_path | --example name]
+       [command] run --sweep-initial [--profile name] [--cycles n] [--input n] [file_path | --example name]
+       [command] run --inject reg_a:bit2@cycle7|all [--profile name] [--cycles n] [--input n] [file_path | --example name]
+       [command] testgen [-o spec.toml] [--registers] [--profile name] [--cycles n] [file_path | --example name]
+       [command] svg [-o state.svg | --frames dir] [--profile name] [--cycles n] [--input n] [file_path | --example name]
+       [command] record -o run.gif [--hz n] [--profile name] [--cycles n] [--input n] [file_path | --example name]
+       [command] stategraph [-o graph.dot] [--profile name] [file_path | --example name]
+       [command] smt [--steps n] [--profile name] [file_path | --example name]
+       [command] fuzz-run [--seed n] [--runs n] [--cycles n] [--gates]
+       [command] quiz [--questions n] [--seed n] [--extended]
+       [command] examples list
+       [command] pack dir [-o lab.td4pack]
+       [command] install lab.td4pack|http://host/lab.td4pack
+       [command] presets list
+       [command] web [--profile name] [--listen host:port]
+       [command] completions bash|zsh|fish
+       [command] man
+       every command also takes [--color auto|always|never] (NO_COLOR disables auto)";
+
+// プログラムの読み込み方に関するオプション
+#[derive(Clone)]
+struct LoadOptions {
+    example: Option<String>,
+    expander: MacroExpander,
+    syntax: Syntax,
+    // .dip のスイッチの並び
+    bit_order: BitOrder,
+    // 実行するプロファイルの名前。.td4rom を作ったときのものと比べる
+    profile: String,
+    // プログラムを組み立てて lint で調べるときのプロファイル
+    machine: MachineProfile,
+    // 4bitに収まらない即値を警告して下位4bitに切り詰める
+    allow_truncation: bool,
+}
+
+// run サブコマンドの表示や実行方法に関するオプション
+struct RunOptions {
+    format: OutputFormat,
+    show_history: bool,
+    show_stats: bool,
+    // 命令ごとに何が起きたかを1行ずつ説明する
+    explain: bool,
+    // 命令ごとのコスト。--stats で合計 (エネルギー) を表示する
+    cost: Option<TableCost>,
+    clock: Option<f64>,
+    jitter: ClockJitter,
+    gates: bool,
+    profile: MachineProfile,
+    // 実行のトレースを書き出すファイル
+    trace: Option<String>,
+    tracer: TracerConfig,
+    // run で実行する最大サイクル数
+    max_cycles: Option<usize>,
+    // 実行を再現するためのマニフェストを書き出すファイル
+    manifest: Option<String>,
+    // OUT命令の値を1行ずつ書き出すファイル。- なら標準出力
+    out_stream: Option<String>,
+    stream_cycles: bool,
+    // IN命令のたびに値を読むファイル、FIFO、TCPソケット
+    in_stream: Option<String>,
+    in_default: Option<u8>,
+    // 入力ポート0の初期値
+    input: Option<u8>,
+    // サイクルごとの入力ポート0の波形
+    stimulus: Option<Stimulus>,
+    // 表示の代わりに結果を1つのJSONにして書き出す
+    json: bool,
+}
+
+impl LoadOptions {
+    // ソースコードの ;!profile で決まったプロファイルで組み立て直す
+    fn with_machine(&self, profile: &MachineProfile) -> LoadOptions {
+        LoadOptions {
+            profile: profile.name.clone(),
+            machine: profile.clone(),
+            ..self.clone()
+        }
+    }
+}
+
+impl RunOptions {
+    // ソースコードの設定とコマンドラインの指定を合わせたものに置き換える
+    fn with_config(self, config: ProgramConfig) -> RunOptions {
+        RunOptions {
+            profile: config.profile.unwrap_or(self.profile),
+            clock: config.clock,
+            input: config.input,
+            max_cycles: config.max_cycles,
+            ..self
+        }
+    }
+}
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    style::init(take_color(&mut args));
+    // 結果をJSONで書き出す。エラーも標準出力にJSONで書く
+    let json = take_flag(&mut args, "--json");
+    if json {
+        set_json_panic_hook();
+    } else if style::current().color() {
+        set_panic_hook();
+    }
+
+    let format = match take_option(&mut args, "--out-format") {
+        Some(format) => format.parse().unwrap_or_else(|err| panic!("{}", err)),
+        None if take_flag(&mut args, "--led") => OutputFormat::Led,
+        None => OutputFormat::Decimal,
+    };
+
+    let profile_name = take_option(&mut args, "--profile");
+    // プロファイルとクロックの組み合わせ。個別の指定はプリセットより優先する
+    let preset = take_option(&mut args, "--preset").map(|name| {
+        if profile_name.is_some() {
+            panic!("--preset and --profile can't be used together");
+        }
+        let presets = preset::load().unwrap_or_else(|err| panic!("{}", err));
+        preset::find(&presets, &name)
+            .unwrap_or_else(|err| panic!("{}", err))
+            .clone()
+    });
+    let mut profile = match (&profile_name, &preset) {
+        (Some(name), _) => name.parse().unwrap_or_else(|err| panic!("{}", err)),
+        (None, Some(preset)) => preset.profile.clone(),
+        (None, None) => MachineProfile::default(),
+    };
+    // 割り込みとポートの追加は拡張モードでだけ使える
+    let interrupt = take_option(&mut args, "--interrupt").map(|spec| parse_interrupt(&spec));
+    let ports = take_number(&mut args, "--ports").map(|n| n as usize);
+    let extended = take_flag(&mut args, "--extended") || interrupt.is_some() || ports.is_some();
+    if extended {
+        profile.mode = Mode::Extended(Extensions {
+            interrupt,
+            ports: ports.unwrap_or(1),
+        });
+    }
+    // OUT命令の値を保つか、1命令だけ出すか
+    let outputs = take_option(&mut args, "--outputs");
+    if let Some(model) = &outputs {
+        profile.output_model = model.parse().unwrap_or_else(|err| panic!("{}", err));
+    }
+    // ALUを使わない命令でキャリーを0にするか
+    let flag_model = take_option(&mut args, "--flag-model");
+    if let Some(model) = &flag_model {
+        profile.flag_model = model.parse().unwrap_or_else(|err| panic!("{}", err));
+    }
+    // IN命令が入力ポートを読むタイミングとチャタリング除去
+    let sampling = take_option(&mut args, "--input-sampling");
+    if let Some(sampling) = &sampling {
+        profile.input_sampling = sampling.parse().unwrap_or_else(|err| panic!("{}", err));
+    }
+    // ROMの外を読んだときに0を読むか、止まるか、エラーにするか、先頭に戻るか
+    let beyond_rom = take_option(&mut args, "--beyond-rom");
+    if let Some(policy) = &beyond_rom {
+        profile.fetch_beyond_rom = policy.parse().unwrap_or_else(|err| panic!("{}", err));
+    }
+    let debounce = take_number(&mut args, "--debounce").map(|n| n as usize);
+    if let Some(debounce) = debounce {
+        profile.input_debounce = debounce;
+    }
+    // 自分自身へのジャンプで止めずに実機のように回り続ける
+    if take_flag(&mut args, "--no-halt-on-self-jump") {
+        profile.halt_on_self_jump = false;
+    }
+    profile.validate().unwrap_or_else(|err| panic!("{}", err));
+
+    let max_cycles = take_option(&mut args, "--cycles").map(|cycles| {
+        cycles
+            .parse::<usize>()
+            .unwrap_or_else(|_| panic!("Invalid cycle count: {}", cycles))
+    });
+    let input = take_option(&mut args, "--input")
+        .map(|value| debugger::parse_number(&value).unwrap_or_else(|err| panic!("{}", err)));
+    let seed = take_number(&mut args, "--seed");
+    // 実機のRC発振のようにクロックの周期をばらつかせる
+    let ratio = |name: &str, args: &mut Vec<String>| {
+        take_option(args, name)
+            .map(|text| timing::parse_ratio(&text).unwrap_or_else(|err| panic!("{}", err)))
+            .unwrap_or(0.0)
+    };
+    let jitter = ClockJitter {
+        jitter: ratio("--jitter", &mut args),
+        drift: ratio("--drift", &mut args),
+        seed: seed.unwrap_or(0),
+    };
+    jitter.validate().unwrap_or_else(|err| panic!("{}", err));
+    let options = RunOptions {
+        format,
+        show_history: take_flag(&mut args, "--show-output-history"),
+        show_stats: take_flag(&mut args, "--stats"),
+        explain: take_flag(&mut args, "--explain"),
+        cost: take_option(&mut args, "--cost").map(|path| {
+            std::fs::read_to_string(&path)
+                .map_err(|err| EmulatorErr::new(&format!("Failed to read {}: {}", path, err)))
+                .and_then(|text| text.parse())
+                .unwrap_or_else(|err| panic!("{}", err))
+        }),
+        gates: take_flag(&mut args, "--gates"),
+        profile,
+        trace: take_option(&mut args, "--trace"),
+        tracer: TracerConfig {
+            capacity: take_number(&mut args, "--trace-last").map(|n| n as usize),
+            encoding: match take_option(&mut args, "--trace-format") {
+                Some(format) => format.parse().unwrap_or_else(|err| panic!("{}", err)),
+                None => TracerConfig::default().encoding,
+            },
+        },
+        clock: take_option(&mut args, "--clock")
+            .map(|hz| match hz.parse::<f64>() {
+                Ok(hz) if hz > 0.0 => hz,
+                _ => panic!("Invalid clock frequency: {}", hz),
+            })
+            .or_else(|| preset.as_ref().and_then(|preset| preset.clock)),
+        jitter,
+        max_cycles,
+        manifest: take_option(&mut args, "--emit-manifest"),
+        out_stream: take_option(&mut args, "--out-stream"),
+        stream_cycles: take_flag(&mut args, "--stream-cycles"),
+        in_stream: take_option(&mut args, "--in-stream"),
+        in_default: take_option(&mut args, "--in-default")
+            .map(|value| debugger::parse_number(&value).unwrap_or_else(|err| panic!("{}", err))),
+        input,
+        // ファイルがあればそこから、なければ引数をそのまま読む
+        stimulus: take_option(&mut args, "--stimulus").map(|stimulus| {
+            std::fs::read_to_string(&stimulus)
+                .unwrap_or(stimulus)
+                .parse()
+                .unwrap_or_else(|err| panic!("{}", err))
+        }),
+        json,
+    };
+    // ソースコードの ;! の設定より優先する指定
+    let flags = ProgramConfig {
+        profile: (profile_name.is_some()
+            || preset.is_some()
+            || extended
+            || outputs.is_some()
+            || flag_model.is_some()
+            || sampling.is_some()
+            || beyond_rom.is_some()
+            || debounce.is_some())
+        .then(|| options.profile.clone()),
+        clock: options.clock,
+        input,
+        max_cycles,
+    };
+    let fuzz_config = FuzzConfig {
+        seed: seed.unwrap_or_else(|| {
+            // 指定がなければ時刻から決め、再現できるように表示する
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+            now.map(|now| now.as_secs()).unwrap_or(0)
+        }),
+        runs: take_number(&mut args, "--runs").unwrap_or(1000) as usize,
+        max_steps: max_cycles.unwrap_or(256),
+        semantics: if options.gates {
+            gates_semantics()
+        } else {
+            Semantics::Behavioral
+        },
+    };
+    // インストールしたパックのプログラム (--example パック名/プログラム名) はそのファイルを指定したことにする
+    if let Some(pos) = args.iter().position(|arg| arg == "--example") {
+        if args.get(pos + 1).is_some_and(|name| name.contains('/')) {
+            let path = pack::find_program(&packs_dir(), &args[pos + 1])
+                .unwrap_or_else(|err| panic!("{}", err));
+            args.splice(pos..pos + 2, [path.display().to_string()]);
+        }
+    }
+    let load_options = LoadOptions {
+        example: take_option(&mut args, "--example"),
+        expander: if take_flag(&mut args, "--no-builtin-macros") {
+            MacroExpander::without_builtins()
+        } else {
+            MacroExpander::new()
+        },
+        syntax: match take_option(&mut args, "--syntax") {
+            Some(syntax) => syntax.parse().unwrap_or_else(|err| panic!("{}", err)),
+            None => Syntax::V1,
+        },
+        bit_order: match take_option(&mut args, "--bit-order") {
+            Some(order) => order.parse().unwrap_or_else(|err| panic!("{}", err)),
+            None => BitOrder::MsbFirst,
+        },
+        profile: options.profile.name.clone(),
+        machine: options.profile.clone(),
+        allow_truncation: take_flag(&mut args, "--allow-truncation"),
+    };
+    let rom_name = take_option(&mut args, "--name");
+
+    let table_format = match take_option(&mut args, "--table-format") {
+        Some(format) => format.parse().unwrap_or_else(|err| panic!("{}", err)),
+        None => TableFormat::Markdown,
+    };
+    // disasm, dump, trace-print の逆アセンブルの書き方
+    let disasm_style: DisasmStyle = match take_option(&mut args, "--disasm-style") {
+        Some(style) => style.parse().unwrap_or_else(|err| panic!("{}", err)),
+        None => DisasmStyle::default(),
+    };
+    // trace-view でファイルを読む代わりにその場で実行する
+    let live = take_flag(&mut args, "--live");
+    // profile で実行回数のヒートマップを付けた逆アセンブル結果を表示する
+    let annotate = take_flag(&mut args, "--annotate");
+    // debug を始めるときに読み込むセッション
+    let session = take_option(&mut args, "--session");
+    // web で待ち受けるアドレス
+    let listen = take_option(&mut args, "--listen");
+    // grade の採点基準
+    let spec = take_option(&mut args, "--spec");
+    // sweep で試す入力ポートの値と、OUT命令の値を全て表示するか
+    let inputs = take_option(&mut args, "--inputs");
+    let sequence = take_flag(&mut args, "--sequence");
+    // equiv, symbolic, testgen でレジスタA, Bの初期値も全て試す
+    let registers = take_flag(&mut args, "--registers");
+    // symbolic でループを展開する深さ
+    let unroll = take_number(&mut args, "--unroll").map(|n| n as usize);
+    // svg で1サイクルごとの図を書き出すディレクトリ
+    let frames = take_option(&mut args, "--frames");
+    // record のGIFで1秒に進めるサイクル数
+    let hz = take_option(&mut args, "--hz").map(|hz| match hz.parse::<f64>() {
+        Ok(hz) if hz > 0.0 => hz,
+        _ => panic!("Invalid frame rate: {}", hz),
+    });
+    // quiz で出題する数
+    let questions = take_number(&mut args, "--questions").map(|n| n as usize);
+    // smt で展開するステップ数
+    let steps = take_number(&mut args, "--steps").map(|n| n as usize);
+    // run で実行する代わりに全ての入力で調べる性質
+    let check = take_option(&mut args, "--check");
+    // run で1bitの故障を起こして、起こさないときと出力を比べる。all なら全ての故障を試す
+    let inject = take_option(&mut args, "--inject");
+    // run で A, B, キャリーの全ての初期値から実行して、出力が初期値で変わるか調べる
+    let sweep_initial = take_flag(&mut args, "--sweep-initial");
+    // stategraph と testgen の書き出し先。なければ標準出力。rompatch ではなければ書き出さない。pack ではパック名から決める
+    let output_path = take_option(&mut args, "-o");
+
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    let (command, target) = match args.as_slice() {
+        [command @ ("run" | "debug" | "pipeline" | "disasm" | "switches" | "compare"
+        | "fuzz-run" | "watch" | "examples" | "presets" | "trace-print"
+        | "trace-view" | "profile" | "table" | "build" | "replay" | "grade" | "dump"
+        | "completions" | "man" | "web" | "sweep" | "equiv" | "symbolic" | "smt"
+        | "stategraph" | "verify-trace" | "testgen" | "romdiff" | "rompatch"
+        | "quiz" | "svg" | "record" | "pack" | "install"), target @ ..] => (*command, target),
+        target => ("run", target),
+    };
+
+    match (command, target) {
+        ("examples", ["list"]) => list_examples(),
+        ("presets", ["list"]) => list_presets(),
+        ("pack", [dir]) => build_pack(dir, output_path.as_deref()),
+        ("install", [source]) => install_pack(source),
+        ("completions", [shell]) => print_completions(shell),
+        ("man", []) => print!("{}", command_line().man("emulator for the TD4 4-bit CPU")),
+        ("run", _) if check.is_some() => check_property(
+            load(target, &load_options),
+            check.as_deref().unwrap(),
+            &CheckConfig {
+                profile: options.profile.clone(),
+                bound: max_cycles.unwrap_or(CheckConfig::default().bound),
+            },
+            json,
+        ),
+        ("run", _) if sweep_initial => check_initial_state(
+            load(target, &load_options),
+            &ExecConfig {
+                profile: options.profile.clone(),
+                input: input.unwrap_or(0),
+                max_cycles: max_cycles.unwrap_or(1000),
+                ..ExecConfig::default()
+            },
+        ),
+        ("run", _) if inject.is_some() => inject_fault(
+            load(target, &load_options),
+            inject.as_deref().unwrap(),
+            &FaultConfig {
+                profile: options.profile.clone(),
+                input: input.unwrap_or(0),
+                max_cycles: max_cycles.unwrap_or(FaultConfig::default().max_cycles),
+            },
+        ),
+        ("run", _) => {
+            let config = read_directives(target, &load_options)
+                .map(|directives| flags.or(directives))
+                .unwrap_or_else(|err| panic!("{}", err));
+            let options = options.with_config(config);
+            let load_options = load_options.with_machine(&options.profile);
+            run(load(target, &load_options), &options)
+        }
+        ("debug", _) => debug(target, &load_options, &options, session.as_deref()),
+        ("pipeline", _) => show_pipeline(
+            load(target, &load_options),
+            max_cycles.unwrap_or(100),
+            &options.profile,
+        ),
+        ("profile", _) if annotate => show_heatmap(
+            load_with_debug_info(target, &load_options),
+            max_cycles.unwrap_or(100_000),
+            &options.profile,
+            style::current().color(),
+        ),
+        ("profile", _) => show_profile(
+            load(target, &load_options),
+            max_cycles.unwrap_or(100_000),
+            &options.profile,
+            json,
+        ),
+        ("table", _) => show_table(
+            trace_live(
+                load(target, &load_options),
+                max_cycles.unwrap_or(20),
+                &options.profile,
+            ),
+            table_format,
+        ),
+        ("disasm", _) => show_listing(load_with_debug_info(target, &load_options), &disasm_style),
+        ("dump", _) => show_rom_table(
+            load_with_sources(target, &load_options),
+            options.profile.rom_size,
+            &disasm_style,
+        ),
+        ("switches", []) if load_options.example.is_none() => {
+            edit_switches(Ok(Vec::new()), load_options.bit_order, &options)
+        }
+        ("switches", _) => edit_switches(
+            load(target, &load_options),
+            load_options.bit_order,
+            &options,
+        ),
+        ("compare", [capture_path, target @ ..]) => {
+            compare(capture_path, load(target, &load_options), &options)
+        }
+        ("verify-trace", [target @ .., expected_path]) => verify_trace(
+            load(target, &load_options),
+            expected_path,
+            &options.profile,
+            input.unwrap_or(0),
+        ),
+        ("build", [output_path, target @ ..]) => build_container(
+            output_path,
+            load_with_debug_info(target, &load_options),
+            &load_options.profile,
+            rom_name,
+            json.then_some(&load_options.machine),
+        ),
+        ("replay", [manifest_path]) => replay(manifest_path, &options),
+        ("grade", [_, ..]) if spec.is_some() => grade(spec.as_deref().unwrap(), target),
+        ("sweep", _) => {
+            let config = read_directives(target, &load_options)
+                .map(|directives| flags.or(directives))
+                .unwrap_or_else(|err| panic!("{}", err));
+            let options = options.with_config(config);
+            let load_options = load_options.with_machine(&options.profile);
+            sweep(
+                load(target, &load_options),
+                inputs.as_deref(),
+                sequence,
+                &options,
+            )
+        }
+        ("romdiff", [left, right]) => rom_diff(
+            (left, load(&[left], &load_options)),
+            (right, load(&[right], &load_options)),
+        ),
+        ("rompatch", [base_path, patch_path]) => patch_rom(
+            base_path,
+            load_with_debug_info(&[base_path], &load_options),
+            patch_path,
+            output_path.as_deref(),
+            &options.profile,
+        ),
+        ("equiv", [left, right]) => equiv(
+            (left, load(&[left], &load_options)),
+            (right, load(&[right], &load_options)),
+            &EquivConfig {
+                profile: options.profile.clone(),
+                max_cycles: max_cycles.unwrap_or(1000),
+                registers,
+            },
+        ),
+        ("symbolic", _) => symbolic(
+            load(target, &load_options),
+            &SymbolicConfig {
+                profile: options.profile.clone(),
+                registers,
+                unroll: unroll.unwrap_or(SymbolicConfig::default().unroll),
+            },
+        ),
+        ("testgen", _) => generate_tests(
+            load(target, &load_options),
+            output_path.as_deref(),
+            &TestGenConfig {
+                profile: options.profile.clone(),
+                max_cycles: max_cycles.unwrap_or(TestGenConfig::default().max_cycles),
+                registers,
+            },
+        ),
+        ("svg", _) => export_svg(
+            load(target, &load_options),
+            (output_path.as_deref(), frames.as_deref()),
+            max_cycles,
+            input.unwrap_or(0),
+            &options.profile,
+        ),
+        ("record", _) => record(
+            load(target, &load_options),
+            output_path
+                .as_deref()
+                .unwrap_or_else(|| panic!("record needs -o file.gif")),
+            hz.unwrap_or(2.0),
+            (max_cycles.unwrap_or(100), input.unwrap_or(0)),
+            &options.profile,
+        ),
+        ("stategraph", _) => export_state_graph(
+            load(target, &load_options),
+            output_path.as_deref(),
+            &options.profile,
+        ),
+        ("smt", _) => export_smt(
+            load(target, &load_options),
+            &SmtConfig {
+                profile: options.profile.clone(),
+                steps: steps.unwrap_or(SmtConfig::default().steps),
+            },
+        ),
+        ("fuzz-run", []) => fuzz_run(fuzz_config),
+        ("quiz", []) => quiz(
+            Quiz::new(fuzz_config.seed, options.profile.mode.is_extended()),
+            questions.unwrap_or(10),
+        ),
+        ("trace-print", [trace_path]) => print_trace(trace_path, &disasm_style),
+        ("trace-view", _) if live => view_trace(trace_live(
+            load(target, &load_options),
+            max_cycles.unwrap_or(1000),
+            &options.profile,
+        )),
+        ("trace-view", [trace_path]) => {
+            view_trace(read_trace(trace_path).map(|records| (records, None)))
+        }
+        ("watch", [file_path]) => watch(file_path, &load_options, &options),
+        ("web", []) => web(
+            listen.as_deref().unwrap_or("127.0.0.1:8040"),
+            &options.profile,
+        ),
+        _ => panic!("Invalid args. {}", USAGE),
+    }
+}
+
+// ソースコードの ;! の設定を読む。ROMイメージやDIPスイッチのファイルには設定がない
+fn read_directives(target: &[&str], options: &LoadOptions) -> Result<ProgramConfig, EmulatorErr> {
+    let source = match (target, &options.example) {
+        ([], Some(name)) => match examples::find(name) {
+            Some(example) => example.source.to_string(),
+            None => return Ok(ProgramConfig::default()),
+        },
+        ([file_path], None) if !file_path.ends_with(".td4rom") && !file_path.ends_with(".dip") => {
+            match std::fs::read_to_string(file_path) {
+                Ok(source) => source,
+                Err(_) => return Ok(ProgramConfig::default()),
+            }
+        }
+        _ => return Ok(ProgramConfig::default()),
+    };
+    source.parse()
+}
+
+// ファイルか--exampleで指定されたプログラムを読み込む
+fn load(target: &[&str], options: &LoadOptions) -> Result<Vec<u8>, EmulatorErr> {
+    load_with_debug_info(target, options).map(|(program, _)| program)
+}
+
+fn load_with_debug_info(
+    target: &[&str],
+    options: &LoadOptions,
+) -> Result<(Vec<u8>, Option<DebugInfo>), EmulatorErr> {
+    match (target, &options.example) {
+        ([], Some(name)) => {
+            let example = examples::find(name).ok_or_else(|| {
+                EmulatorErr::new(&format!("Unknown example: {}. Try `examples list`", name))
+            })?;
+            // 同梱のサンプルはV1の文法で書かれている
+            let (program, debug_info) = assemble_with_profile(
+                example.source,
+                &options.expander,
+                Syntax::V1,
+                &options.machine,
+            )?;
+            Ok((program, Some(debug_info)))
+        }
+        ([file_path], None) => build_with_debug_info(file_path, options),
+        _ => panic!("Invalid args. {}", USAGE),
+    }
+}
+
+// 引数からフラグを取り除き、指定されていたかを返す
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != name);
+    args.len() != len
+}
+
+// 引数から値付きのオプションを取り除き、その値を返す
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let pos = args.iter().position(|arg| arg == name)?;
+    if pos + 1 >= args.len() {
+        panic!("{} requires a value. {}", name, USAGE);
+    }
+    let value = args.remove(pos + 1);
+    args.remove(pos);
+    Some(value)
+}
+
+// --color auto|always|never。値を省いた --color は always とみなす
+fn take_color(args: &mut Vec<String>) -> ColorChoice {
+    let pos = match args.iter().position(|arg| arg == "--color") {
+        Some(pos) => pos,
+        None => return ColorChoice::Auto,
+    };
+    args.remove(pos);
+    match args.get(pos).map(|value| value.parse()) {
+        Some(Ok(choice)) => {
+            args.remove(pos);
+            choice
+        }
+        _ => ColorChoice::Always,
+    }
+}
+
+// 色を付けるときはパニックのメッセージをエラーとして赤く表示する
+fn set_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .or_else(|| payload.downcast_ref::<&str>().copied())
+            .unwrap_or("unexpected error");
+        let style = style::current();
+        eprintln!("{} {}", style.red("error:"), style.error(message));
+    }));
+}
+
+// --json のときはエラーも {"command":..., "ok":false, "error":"..."} にする
+fn set_json_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .or_else(|| payload.downcast_ref::<&str>().copied())
+            .unwrap_or("unexpected error");
+        println!(
+            "{}",
+            Json::object([("ok", false.into()), ("error", message.into())])
+        );
+    }));
+}
+
+fn take_number(args: &mut Vec<String>, name: &str) -> Option<u64> {
+    take_option(args, name).map(|value| {
+        value
+            .parse::<u64>()
+            .unwrap_or_else(|_| panic!("Invalid value for {}: {}", name, value))
+    })
+}
+
+// "bit:vector" の形式で割り込みを指定する
+fn parse_interrupt(spec: &str) -> Interrupt {
+    let parsed = spec.split_once(':').and_then(|(bit, vector)| {
+        match (debugger::parse_number(bit), debugger::parse_number(vector)) {
+            (Ok(bit), Ok(vector)) if bit < 4 && vector < 16 => Some(Interrupt::new(bit, vector)),
+            _ => None,
+        }
+    });
+    parsed.unwrap_or_else(|| panic!("Invalid interrupt: {}. Use bit:vector like 0:0xc", spec))
+}
+
+fn list_examples() {
+    for example in examples::all() {
+        println!("{:<14} {}", example.name, example.description);
+    }
+    for installed in pack::installed(&packs_dir()) {
+        for program in &installed.programs {
+            let name = format!("{}/{}", installed.name, program.name);
+            println!("{:<14} {}", name, program.description);
+        }
+    }
+}
+
+// パックをインストールするディレクトリ
+fn packs_dir() -> std::path::PathBuf {
+    pack::packs_dir().unwrap_or_else(|| panic!("Set TD4EMU_PACKS to the directory for packs"))
+}
+
+// ディレクトリの pack.toml からパックを作る。書き出し先の既定は パック名.td4pack
+fn build_pack(dir: &str, output_path: Option<&str>) {
+    let (manifest, archive) =
+        pack::build(std::path::Path::new(dir)).unwrap_or_else(|err| panic!("{}", err));
+    let output_path = match output_path {
+        Some(path) => path.to_string(),
+        None => format!("{}.td4pack", manifest.name),
+    };
+    std::fs::write(&output_path, archive)
+        .unwrap_or_else(|err| panic!("Failed to write {}: {}", output_path, err));
+    println!(
+        "Packed {} {} ({} programs) into {}",
+        manifest.name,
+        manifest.version,
+        manifest.programs.len(),
+        output_path
+    );
+}
+
+fn install_pack(source: &str) {
+    let archive = if source.contains("://") {
+        pack::fetch(source)
+    } else {
+        std::fs::read(source)
+            .map_err(|err| EmulatorErr::new(&format!("Failed to read {}: {}", source, err)))
+    };
+    let archive = archive.unwrap_or_else(|err| panic!("{}", err));
+    let (manifest, dir) =
+        pack::install(&archive, &packs_dir()).unwrap_or_else(|err| panic!("{}", err));
+    println!(
+        "Installed {} {} into {}",
+        manifest.name,
+        manifest.version,
+        dir.display()
+    );
+    for program in &manifest.programs {
+        println!("  --example {}/{}", manifest.name, program.name);
+    }
+}
+
+fn list_presets() {
+    let presets = preset::load().unwrap_or_else(|err| panic!("{}", err));
+    for preset in presets {
+        println!("{:<18} {}", preset.name, preset.description);
+    }
+}
+
+// 使い方の文字列から読み取ったサブコマンドとオプション
+fn command_line() -> CommandLine {
+    let examples: Vec<&str> = examples::all().iter().map(|example| example.name).collect();
+    let presets: Vec<String> = preset::builtin()
+        .into_iter()
+        .map(|preset| preset.name)
+        .collect();
+    let presets: Vec<&str> = presets.iter().map(String::as_str).collect();
+    CommandLine::parse("td4emu", USAGE)
+        .with_choices("--example", &examples)
+        .with_choices("--preset", &presets)
+}
+
+fn print_completions(shell: &str) {
+    let shell: Shell = shell.parse().unwrap_or_else(|err| panic!("{}", err));
+    print!("{}", command_line().completions(shell));
+}
+
+fn build_with_debug_info(
+    file_path: &str,
+    options: &LoadOptions,
+) -> Result<(Vec<u8>, Option<DebugInfo>), EmulatorErr> {
+    if file_path.ends_with(".td4rom") {
+        let image = std::fs::read(file_path).map_err(|_| EmulatorErr::new("file not found"))?;
+        let (rom, metadata) = Rom::load_container(&image)?;
+        if metadata.profile != options.profile {
+            style::warn(&format!(
+                "{} was built for {} but runs on {}",
+                file_path, metadata.profile, options.profile
+            ));
+        }
+        return Ok((rom.into_bytes(), metadata.debug_info));
+    }
+    // .binはROMの中身をそのまま並べたイメージ
+    if file_path.ends_with(".bin") {
+        let image = std::fs::read(file_path).map_err(|_| EmulatorErr::new("file not found"))?;
+        return Ok((image, None));
+    }
+    let source =
+        std::fs::read_to_string(file_path).map_err(|_| EmulatorErr::new("file not found"))?;
+    // .dipはDIPスイッチの並びをそのまま書いたファイルなのでデバッグ情報はない
+    if file_path.ends_with(".dip") {
+        return Ok((
+            SwitchBank::from_listing_with(&source, options.bit_order)?
+                .to_rom()
+                .into_bytes(),
+            None,
+        ));
+    }
+    let (program, debug_info, lines) = assemble_with_lines(
+        &source,
+        &options.expander,
+        options.syntax,
+        options.allow_truncation,
+        &options.machine,
+    )?;
+    for warning in lint::lint(&program, &options.machine) {
+        match lines.get(warning.address as usize).copied().flatten() {
+            Some(line) => style::warn(&format!("line {}: {}", line, warning.message)),
+            None => style::warn(&format!("0x{:x}: {}", warning.address, warning.message)),
+        }
+    }
+    Ok((program, Some(debug_info)))
+}
+
+// ソースから組み立てたときは各アドレスに「行番号: ソースの行」を付ける
+type ProgramWithSources = (Vec<u8>, Option<DebugInfo>, Vec<Option<String>>);
+
+fn load_with_sources(
+    target: &[&str],
+    options: &LoadOptions,
+) -> Result<ProgramWithSources, EmulatorErr> {
+    let (source, syntax) = match (target, &options.example) {
+        ([], Some(name)) => match examples::find(name) {
+            Some(example) => (example.source.to_string(), Syntax::V1),
+            None => return load_without_sources(target, options),
+        },
+        ([file_path], None) if !file_path.ends_with(".td4rom") && !file_path.ends_with(".dip") => {
+            let source = std::fs::read_to_string(file_path)
+                .map_err(|_| EmulatorErr::new("file not found"))?;
+            (source, options.syntax)
+        }
+        _ => return load_without_sources(target, options),
+    };
+    let (program, debug_info, lines) = assemble_with_lines(
+        &source,
+        &options.expander,
+        syntax,
+        options.allow_truncation,
+        &options.machine,
+    )?;
+    let texts: Vec<&str> = source.lines().collect();
+    let sources = lines
+        .into_iter()
+        .map(|line| {
+            line.map(|line| {
+                let text = texts.get(line - 1).copied().unwrap_or("");
+                format!("{}: {}", line, text.trim())
+            })
+        })
+        .collect();
+    Ok((program, Some(debug_info), sources))
+}
+
+fn load_without_sources(
+    target: &[&str],
+    options: &LoadOptions,
+) -> Result<ProgramWithSources, EmulatorErr> {
+    load_with_debug_info(target, options)
+        .map(|(program, debug_info)| (program, debug_info, Vec::new()))
+}
+
+fn show_rom_table(
+    program: Result<ProgramWithSources, EmulatorErr>,
+    rows: usize,
+    style: &DisasmStyle,
+) {
+    match program {
+        Ok((program, debug_info, sources)) => print!(
+            "{}",
+            disassembler::rom_table(&program, rows, debug_info.as_ref(), &sources, style)
+        ),
+        Err(err) => panic!("{}", err),
+    }
+}
+
+fn show_listing(program: Result<(Vec<u8>, Option<DebugInfo>), EmulatorErr>, style: &DisasmStyle) {
+    match program {
+        Ok((program, debug_info)) => {
+            print!(
+                "{}",
+                disassembler::listing_with(&program, debug_info.as_ref(), style)
+            )
+        }
+        Err(err) => panic!("{}", err),
+    }
+}
+
+fn run(program: Result<Vec<u8>, EmulatorErr>, options: &RunOptions) {
+    let program = match program {
+        Ok(program) => program,
+        Err(err) => panic!("{}", err),
+    };
+
+    let mut machine = Machine::new(options.profile.clone());
+    machine.set_output_format(options.format);
+    machine.set_quiet(options.json);
+    machine.set_clock_jitter(options.jitter);
+    machine.set_clock(options.clock);
+    if let Some(cost) = &options.cost {
+        machine.set_cost_model(Box::new(cost.clone()));
+    }
+    if options.trace.is_some() {
+        machine.set_tracer(options.tracer);
+    }
+    if options.explain {
+        let explainer = Explainer::new(Box::new(std::io::stdout()), Register::new());
+        machine
+            .add_plugin(Box::new(explainer))
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+    if let Some(path) = &options.out_stream {
+        let writer: Box<dyn Write + Send> = if path == "-" {
+            // 標準出力には値だけを流す
+            machine.set_quiet(true);
+            Box::new(std::io::stdout())
+        } else {
+            match std::fs::File::create(path) {
+                Ok(file) => Box::new(file),
+                Err(err) => panic!("Failed to create {}: {}", path, err),
+            }
+        };
+        let stream = OutputStream::new(writer, options.format)
+            .with_cycles(options.stream_cycles)
+            .with_ports(options.profile.mode.port_count() > 1);
+        machine.add_output_observer(Box::new(stream));
+    }
+    if let Some(path) = &options.in_stream {
+        let reader = open_in_stream(path).unwrap_or_else(|err| panic!("{}", err));
+        machine.set_input_source(Box::new(InputStream::new(reader, options.in_default)));
+    }
+    // 実行が途中で失敗しても再現できるように先に書き出す
+    if let Some(path) = &options.manifest {
+        let mut manifest = ReplayManifest::new(program.clone(), options.profile.clone());
+        manifest.max_cycles = options.max_cycles;
+        manifest.input = options.input.unwrap_or(0);
+        if let Some(stimulus) = &options.stimulus {
+            manifest.inputs = stimulus.changes().to_vec();
+        }
+        if let Err(err) = std::fs::write(path, manifest.to_string()) {
+            panic!("Failed to write manifest to {}: {}", path, err);
+        }
+    }
+    if let Err(err) = machine.load_rom(program) {
+        panic!("{}", err);
+    }
+    if let Some(input) = options.input {
+        if let Err(err) = machine.emulator().set_input_port(0, input) {
+            panic!("{}", err);
+        }
+    }
+    if let Some(stimulus) = &options.stimulus {
+        machine.set_input_script(stimulus.changes().to_vec());
+    }
+    // ゲートレベルの実行は止まった理由を返さない
+    let result = match options.gates {
+        true => exec_gates(machine.emulator()).map(|_| None),
+        false => machine.run(options.max_cycles).map(Some),
+    };
+    // エラーで止まったときもそこまでのトレースは残す
+    if let (Some(path), Some(tracer)) = (&options.trace, machine.take_tracer()) {
+        if let Err(err) = std::fs::write(path, tracer.encode()) {
+            panic!("Failed to write trace to {}: {}", path, err);
+        }
+    }
+    let stop = match result {
+        Ok(stop) => stop,
+        Err(err) => panic!("{}", err),
+    };
+
+    if options.json {
+        let stop = stop.map(|stop| match stop {
+            StopReason::Halted => "halted".to_string(),
+            StopReason::Breakpoint(address) => format!("breakpoint 0x{:x}", address),
+            StopReason::CycleLimit => "cycle-limit".to_string(),
+            StopReason::Paused(address) => format!("paused 0x{:x}", address),
+        });
+        let emulator = machine.emulator();
+        let outputs = emulator
+            .output_history()
+            .into_iter()
+            .map(|(cycle, value)| Json::object([("cycle", cycle.into()), ("value", value.into())]));
+        let warnings = lint::lint(&emulator.rom(), &options.profile);
+        let json = Json::object([
+            ("command", "run".into()),
+            ("ok", true.into()),
+            ("stop", stop.into()),
+            ("state", Snapshot::take(emulator).to_json()),
+            ("outputs", Json::array(outputs)),
+            (
+                "warnings",
+                Json::array(warnings.iter().map(Warning::to_json)),
+            ),
+            ("stats", emulator.stats().to_json()),
+            (
+                "clock",
+                machine.clock_drift().map(|drift| drift.to_json()).into(),
+            ),
+        ]);
+        println!("{}", json);
+        return;
+    }
+
+    if options.show_history {
+        let renderer = options.format.renderer();
+        println!("Output history:");
+        for (cycle, output) in machine.emulator().output_history() {
+            println!("  cycle {:>4}: {}", cycle, renderer.render(output));
+        }
+    }
+
+    if options.show_stats {
+        let stats = machine.emulator().stats();
+        println!(
+            "Instructions: {}, Cycles: {}, CPI: {:.2}",
+            stats.instructions,
+            stats.cycles,
+            stats.cpi()
+        );
+        if options.cost.is_some() {
+            println!("Energy: {}", stats.energy);
+        }
+        for (address, branch) in &stats.branches {
+            println!("JNC 0x{:x}: {}", address, branch);
+        }
+        if let Some(drift) = machine.clock_drift() {
+            println!("{}", drift);
+        }
+    }
+}
+
+// tcp:host:port ならそのアドレスにつなぎ、- なら標準入力を読む
+fn open_in_stream(path: &str) -> Result<Box<dyn BufRead + Send>, EmulatorErr> {
+    let error =
+        |err: std::io::Error| EmulatorErr::new(&format!("Failed to open {}: {}", path, err));
+    if path == "-" {
+        return Ok(Box::new(std::io::BufReader::new(std::io::stdin())));
+    }
+    if let Some(address) = path.strip_prefix("tcp:") {
+        let stream = std::net::TcpStream::connect(address).map_err(error)?;
+        return Ok(Box::new(std::io::BufReader::new(stream)));
+    }
+    // FIFOは書き込む側が開くまでここで待つ
+    let file = std::fs::File::open(path).map_err(error)?;
+    Ok(Box::new(std::io::BufReader::new(file)))
+}
+
+// --emit-manifest で書き出した実行を再現し、最後の状態を表示する
+fn replay(manifest_path: &str, options: &RunOptions) {
+    let manifest = std::fs::read_to_string(manifest_path)
+        .map_err(|_| EmulatorErr::new("manifest not found"))
+        .and_then(|text| text.parse::<ReplayManifest>());
+    let (machine, stop) = match manifest.and_then(|manifest| Machine::run_from_manifest(&manifest))
+    {
+        Ok(result) => result,
+        Err(err) => panic!("{}", err),
+    };
+
+    let renderer = options.format.renderer();
+    for (cycle, output) in machine.emulator().output_history() {
+        println!("  cycle {:>4}: {}", cycle, renderer.render(output));
+    }
+    let register = machine.emulator().register();
+    println!(
+        "Stopped: {:?} at cycle {}  PC: 0x{:x}  A: 0b{:04b}  B: 0b{:04b}  C: {}",
+        stop,
+        machine.emulator().cycles(),
+        register.pc(),
+        register.register_a(),
+        register.register_b(),
+        register.carry_flag()
+    );
+}
+
+// 提出されたファイルをまとめて採点し、結果をCSVで出力する
+fn grade(spec_path: &str, files: &[&str]) {
+    let spec = std::fs::read_to_string(spec_path)
+        .map_err(|_| EmulatorErr::new("spec not found"))
+        .and_then(|text| text.parse::<GradeSpec>())
+        .unwrap_or_else(|err| panic!("{}", err));
+    print!("{}", grader::to_csv(&grader::grade(&spec, files)));
+}
+
+// 2つのプログラムの出力を全ての入力で比べる。違えば最初の反例を表示して終了コード1で終わる
+// 2つのROMの違うアドレスを逆アセンブルして並べる。違いがあれば終了コード1で終わる
+fn rom_diff(
+    (left_path, left): (&str, Result<Vec<u8>, EmulatorErr>),
+    (right_path, right): (&str, Result<Vec<u8>, EmulatorErr>),
+) {
+    let (left, right) = match (left, right) {
+        (Ok(left), Ok(right)) => (left, right),
+        (Err(err), _) | (_, Err(err)) => panic!("{}", err),
+    };
+    let diffs = patch::diff(&left, &right);
+    if diffs.is_empty() {
+        println!("The ROMs are identical ({} bytes)", left.len());
+        return;
+    }
+    print!("{}", patch::report(&diffs, (left_path, right_path)));
+    std::process::exit(1);
+}
+
+// パッチを当てて変わるところを表示し、output_path があれば書き出す
+// .td4rom に書き出すときは元のROMのデバッグ情報を引き継ぐ
+fn patch_rom(
+    base_path: &str,
+    base: Result<(Vec<u8>, Option<DebugInfo>), EmulatorErr>,
+    patch_path: &str,
+    output_path: Option<&str>,
+    profile: &MachineProfile,
+) {
+    let patch = std::fs::read_to_string(patch_path)
+        .map_err(|err| EmulatorErr::new(&format!("Failed to read {}: {}", patch_path, err)))
+        .and_then(|text| text.parse::<Patch>());
+    let ((base, debug_info), patch) = match (base, patch) {
+        (Ok(base), Ok(patch)) => (base, patch),
+        (Err(err), _) | (_, Err(err)) => panic!("{}", err),
+    };
+    let patched = patch
+        .apply(&base, profile.rom_size)
+        .unwrap_or_else(|err| panic!("{}", err));
+    print!(
+        "{}",
+        patch::report(&patch::diff(&base, &patched), (base_path, "patched"))
+    );
 
-    if args.len() != 2 {
-        panic!("Invalid args. Usage: [command] [file_path]");
+    let Some(output_path) = output_path else {
+        return;
+    };
+    let image = if output_path.ends_with(".td4rom") {
+        let metadata = RomMetadata {
+            profile: profile.name.clone(),
+            name: None,
+            debug_info,
+        };
+        Rom::new(patched).save_container(&metadata)
+    } else {
+        patched
+    };
+    std::fs::write(output_path, image)
+        .unwrap_or_else(|err| panic!("Failed to write {}: {}", output_path, err));
+    println!("Wrote {}", output_path);
+}
+
+fn equiv(
+    (left_path, left): (&str, Result<Vec<u8>, EmulatorErr>),
+    (right_path, right): (&str, Result<Vec<u8>, EmulatorErr>),
+    config: &EquivConfig,
+) {
+    let (left, right) = match (left, right) {
+        (Ok(left), Ok(right)) => (left, right),
+        (Err(err), _) | (_, Err(err)) => panic!("{}", err),
+    };
+    let result = equiv::check(&left, &right, config);
+    match result.counterexample {
+        None => println!(
+            "Equivalent for all {} cases within {} cycles",
+            result.cases, config.max_cycles
+        ),
+        Some(counterexample) => {
+            println!("Not equivalent for {}", counterexample.case);
+            println!("  {}: {}", left_path, counterexample.left);
+            println!("  {}: {}", right_path, counterexample.right);
+            std::process::exit(1);
+        }
+    }
+}
+
+// 入力ポートの値ごとに実行して真理値表のように表示する
+fn sweep(
+    program: Result<Vec<u8>, EmulatorErr>,
+    inputs: Option<&str>,
+    sequence: bool,
+    options: &RunOptions,
+) {
+    let program = program.unwrap_or_else(|err| panic!("{}", err));
+    let limit = options.profile.register_mask();
+    let inputs = match inputs {
+        Some(range) => sweep::parse_range(range, limit).unwrap_or_else(|err| panic!("{}", err)),
+        None => 0..=limit,
+    };
+    let config = ExecConfig {
+        profile: options.profile.clone(),
+        max_cycles: options.max_cycles.unwrap_or(1000),
+        ..ExecConfig::default()
+    };
+    let rows = sweep::sweep(&program, inputs, &config);
+    if options.json {
+        let json = Json::object([
+            ("command", "sweep".into()),
+            ("ok", true.into()),
+            ("rows", Json::array(rows.iter().map(|row| row.to_json()))),
+        ]);
+        println!("{}", json);
+        return;
+    }
+    print!(
+        "{}",
+        sweep::report(&rows, options.profile.register_bits, sequence)
+    );
+}
+
+// 初期値を全て試して、出力がリセット直後と変わるものがあれば終了コード1で終わる
+fn check_initial_state(program: Result<Vec<u8>, EmulatorErr>, config: &ExecConfig) {
+    let program = program.unwrap_or_else(|err| panic!("{}", err));
+    let result = sweep::sweep_initial(&program, config);
+    print!("{}", result);
+    if !result.is_robust() {
+        std::process::exit(1);
+    }
+}
+
+// 入力ポートを変数のまま実行して、道ごとの条件と出力の式を表示する
+fn symbolic(program: Result<Vec<u8>, EmulatorErr>, config: &SymbolicConfig) {
+    let program = program.unwrap_or_else(|err| panic!("{}", err));
+    let paths = symbolic::execute(&program, config).unwrap_or_else(|err| panic!("{}", err));
+    print!("{}", symbolic::report(&paths));
+}
+
+// 全ての入力で性質を調べる。成り立たなければ反例を表示して終了コード1で終わる
+fn check_property(
+    program: Result<Vec<u8>, EmulatorErr>,
+    property: &str,
+    config: &CheckConfig,
+    json: bool,
+) {
+    let program = program.unwrap_or_else(|err| panic!("{}", err));
+    let property: Property = property.parse().unwrap_or_else(|err| panic!("{}", err));
+    let result = model_check::check(&program, &property, config);
+    if json {
+        let json = Json::object([
+            ("command", "check".into()),
+            ("ok", true.into()),
+            ("property", property.to_string().into()),
+            ("bound", config.bound.into()),
+            ("result", result.to_json()),
+        ]);
+        println!("{}", json);
+        if !result.holds() {
+            std::process::exit(1);
+        }
+        return;
+    }
+    match result.counterexample {
+        None if result.exhaustive => {
+            println!("{}: holds for all {} inputs", property, result.inputs)
+        }
+        None => println!(
+            "{}: holds for all {} inputs within {} steps",
+            property, result.inputs, config.bound
+        ),
+        Some(trace) => {
+            println!("{}: fails for input {:04b}", property, trace.input);
+            print!("{}", trace);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn inject_fault(program: Result<Vec<u8>, EmulatorErr>, spec: &str, config: &FaultConfig) {
+    let program = program.unwrap_or_else(|err| panic!("{}", err));
+    if spec == "all" {
+        let reports = fault::sweep(&program, config);
+        let cycles = reports.last().map_or(0, |report| report.fault.cycle + 1);
+        println!(
+            "Injected {} single-bit faults over {} cycles",
+            reports.len(),
+            cycles
+        );
+        for effect in [
+            Effect::Masked,
+            Effect::Corrupted,
+            Effect::Hung,
+            Effect::Crashed,
+        ] {
+            let count = reports
+                .iter()
+                .filter(|report| report.effect == effect)
+                .count();
+            println!("{:<10} {}", effect, count);
+        }
+        return;
+    }
+    let fault: Fault = spec.parse().unwrap_or_else(|err| panic!("{}", err));
+    match fault::inject(&program, &fault, config) {
+        Ok(report) => print!("{}", report),
+        Err(err) => panic!("{}", err),
+    }
+}
+
+// 到達できる全ての状態の遷移図を dot 形式で書き出す
+// 全ての命令を通るテストベクタを grade の採点基準として書き出す
+fn generate_tests(
+    program: Result<Vec<u8>, EmulatorErr>,
+    output_path: Option<&str>,
+    config: &TestGenConfig,
+) {
+    let program = program.unwrap_or_else(|err| panic!("{}", err));
+    let vectors = testgen::generate(&program, config).unwrap_or_else(|err| panic!("{}", err));
+    match output_path {
+        Some(path) => {
+            if let Err(err) = std::fs::write(path, vectors.to_string()) {
+                panic!("Failed to write test vectors to {}: {}", path, err);
+            }
+            println!(
+                "Wrote {} cases covering {} of {} instructions to {}",
+                vectors.spec.cases.len(),
+                vectors.instructions - vectors.uncovered.len(),
+                vectors.instructions,
+                path
+            );
+        }
+        None => print!("{}", vectors),
+    }
+}
+
+// 止まるか max_cycles に達したときの状態を図にする
+// frames_dir があれば最初の状態と1サイクルごとの状態を frame-0000.svg から順に書き出す
+fn export_svg(
+    program: Result<Vec<u8>, EmulatorErr>,
+    (output_path, frames_dir): (Option<&str>, Option<&str>),
+    max_cycles: Option<usize>,
+    input: u8,
+    profile: &MachineProfile,
+) {
+    let program = program.unwrap_or_else(|err| panic!("{}", err));
+    let mut machine = Machine::new(profile.clone());
+    machine.set_quiet(true);
+    machine
+        .load_rom(program)
+        .and_then(|_| machine.emulator().set_input_port(0, input))
+        .unwrap_or_else(|err| panic!("{}", err));
+    let max_cycles = max_cycles.unwrap_or(1000);
+
+    let Some(dir) = frames_dir else {
+        machine
+            .run(Some(max_cycles))
+            .unwrap_or_else(|err| panic!("{}", err));
+        let image = svg::render(&Snapshot::take(machine.emulator()));
+        match output_path {
+            Some(path) => std::fs::write(path, image)
+                .unwrap_or_else(|err| panic!("Failed to write {}: {}", path, err)),
+            None => print!("{}", image),
+        }
+        return;
+    };
+    std::fs::create_dir_all(dir).unwrap_or_else(|err| panic!("Failed to create {}: {}", dir, err));
+    let frames = svg::frames(&mut machine, max_cycles).unwrap_or_else(|err| panic!("{}", err));
+    for (index, snapshot) in frames.iter().enumerate() {
+        let path = std::path::Path::new(dir).join(format!("frame-{:04}.svg", index));
+        std::fs::write(&path, svg::render(snapshot))
+            .unwrap_or_else(|err| panic!("Failed to write {}: {}", path.display(), err));
+    }
+    println!("Wrote {} frames to {}", frames.len(), dir);
+}
+
+fn export_state_graph(
+    program: Result<Vec<u8>, EmulatorErr>,
+    output_path: Option<&str>,
+    profile: &MachineProfile,
+) {
+    let program = program.unwrap_or_else(|err| panic!("{}", err));
+    let graph = state_graph::explore(&program, profile).unwrap_or_else(|err| panic!("{}", err));
+    let dot = graph.to_dot();
+    match output_path {
+        Some(path) => {
+            if let Err(err) = std::fs::write(path, dot) {
+                panic!("Failed to write state graph to {}: {}", path, err);
+            }
+            println!(
+                "Wrote {} states and {} transitions to {}",
+                graph.nodes.len(),
+                graph.edges.len(),
+                path
+            );
+        }
+        None => print!("{}", dot),
+    }
+}
+
+// 遷移関係を SMT-LIB で表示する
+fn export_smt(program: Result<Vec<u8>, EmulatorErr>, config: &SmtConfig) {
+    let program = program.unwrap_or_else(|err| panic!("{}", err));
+    print!(
+        "{}",
+        smt::export(&program, config).unwrap_or_else(|err| panic!("{}", err))
+    );
+}
+
+// 制御信号を表示しながら回路レベルで実行する
+#[cfg(feature = "gates")]
+fn exec_gates(emulator: &CpuEmulator) -> Result<(), EmulatorErr> {
+    while !emulator.does_halt() {
+        println!("{}", emulator.step_gates()?);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "gates"))]
+fn exec_gates(_emulator: &CpuEmulator) -> Result<(), EmulatorErr> {
+    panic!("gate-level simulation is not available. Rebuild with `--features gates`");
+}
+
+// バイナリ形式で保存したトレースを読める形で表示する
+fn print_trace(trace_path: &str, disasm: &DisasmStyle) {
+    match read_trace(trace_path) {
+        Ok(records) => print!(
+            "{}",
+            tracer::pretty_print_with(&records, style::current(), disasm)
+        ),
+        Err(err) => panic!("{}", err),
+    }
+}
+
+fn read_trace(trace_path: &str) -> Result<Vec<TraceRecord>, EmulatorErr> {
+    std::fs::read(trace_path)
+        .map_err(|_| EmulatorErr::new("trace file not found"))
+        .and_then(|bytes| tracer::decode_binary(&bytes))
+}
+
+// プログラムを停止するかmax_cyclesまで実行してトレースとROMを返す
+fn trace_live(
+    program: Result<Vec<u8>, EmulatorErr>,
+    max_cycles: usize,
+    profile: &MachineProfile,
+) -> Result<(Vec<TraceRecord>, Option<Vec<u8>>), EmulatorErr> {
+    let mut machine = Machine::new(profile.clone());
+    machine.set_quiet(true);
+    machine.set_tracer(TracerConfig::default());
+    machine.load_rom(program?)?;
+    // エラーで止まってもそこまでのトレースは見られるようにする
+    if let Err(err) = machine.run(Some(max_cycles)) {
+        eprintln!("{}", style::current().error(&err.to_string()));
     }
+    let records = machine.take_tracer().map(|tracer| tracer.records());
+    Ok((records.unwrap_or_default(), Some(machine.emulator().rom())))
+}
+
+// 1命令ごとの状態を表にして表示する
+fn show_table(
+    trace: Result<(Vec<TraceRecord>, Option<Vec<u8>>), EmulatorErr>,
+    format: TableFormat,
+) {
+    match trace {
+        Ok((records, _)) => print!("{}", table::state_table(&records, format)),
+        Err(err) => panic!("{}", err),
+    }
+}
+
+#[cfg(not(feature = "tui"))]
+fn view_trace(_trace: Result<(Vec<TraceRecord>, Option<Vec<u8>>), EmulatorErr>) {
+    panic!("trace viewer is not available. Rebuild with `--features tui`");
+}
+
+// 矢印キーでサイクルを行き来しながらトレースを表示する
+#[cfg(feature = "tui")]
+fn view_trace(trace: Result<(Vec<TraceRecord>, Option<Vec<u8>>), EmulatorErr>) {
+    use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+    use crossterm::{cursor, execute, terminal};
+    use td4emu::trace_viewer::TraceViewer;
 
-    let f = BufReader::new(File::open(args.get(1).unwrap()).expect("file not found"));
-    let operations= f.lines().map(|line| line.unwrap()).collect::<Vec<String>>();
+    const HELP: &str =
+        "Up/Down: step  PgUp/PgDn: 16 steps  Home/End  Tab: field  n/N: next/previous change  q: quit";
 
-    let mut parser = Parser::new(operations);
-    let tokens = match parser.parse() {
-        Ok(tokens) => tokens,
-        Err(err) => panic!("{:?}", err),
+    let (records, rom) = match trace {
+        Ok(trace) => trace,
+        Err(err) => panic!("{}", err),
     };
+    let mut viewer = TraceViewer::new(records);
+    if let Some(rom) = rom {
+        viewer.set_rom(&rom);
+    }
 
-    let compiler = Compiler::new();
-    let program = match compiler.compile(tokens) {
+    let mut stdout = std::io::stdout();
+    terminal::enable_raw_mode().expect("failed to enable raw mode");
+    execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide).unwrap();
+    let mut message = String::new();
+    loop {
+        // rawモードでは改行だけでは行頭に戻らない
+        let screen = format!("{}\n{}\n{}", viewer.render(), HELP, message).replace('\n', "\r\n");
+        execute!(
+            stdout,
+            terminal::Clear(terminal::ClearType::All),
+            cursor::MoveTo(0, 0)
+        )
+        .unwrap();
+        print!("{}", screen);
+        stdout.flush().unwrap();
+
+        let key = match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => key,
+            Ok(_) => continue,
+            Err(_) => break,
+        };
+        message.clear();
+        match key.code {
+            KeyCode::Down | KeyCode::Right | KeyCode::Char('j') => viewer.forward(1),
+            KeyCode::Up | KeyCode::Left | KeyCode::Char('k') => viewer.back(1),
+            KeyCode::PageDown => viewer.forward(16),
+            KeyCode::PageUp => viewer.back(16),
+            KeyCode::Home => viewer.first(),
+            KeyCode::End => viewer.last(),
+            KeyCode::Tab => viewer.select_next_field(),
+            KeyCode::Char('n') if !viewer.next_change() => {
+                message = format!("{} does not change after this point", viewer.field().name())
+            }
+            KeyCode::Char('N') if !viewer.prev_change() => {
+                message = format!(
+                    "{} does not change before this point",
+                    viewer.field().name()
+                )
+            }
+            KeyCode::Char('q') | KeyCode::Esc => break,
+            _ => (),
+        }
+    }
+    execute!(stdout, cursor::Show, terminal::LeaveAlternateScreen).unwrap();
+    terminal::disable_raw_mode().unwrap();
+}
+
+// アセンブルしたプログラムを .td4rom として保存する
+fn build_container(
+    output_path: &str,
+    program: Result<(Vec<u8>, Option<DebugInfo>), EmulatorErr>,
+    profile: &str,
+    name: Option<String>,
+    json: Option<&MachineProfile>,
+) {
+    let (program, debug_info) = match program {
         Ok(program) => program,
-        Err(err) => panic!("{:?}", err),
+        Err(err) => panic!("{}", err),
+    };
+    let warnings = json.map(|machine| lint::lint(&program, machine));
+    let bytes = program.len();
+    let metadata = RomMetadata {
+        profile: profile.to_string(),
+        name,
+        debug_info,
+    };
+    let image = Rom::new(program).save_container(&metadata);
+    std::fs::write(output_path, image).unwrap_or_else(|err| panic!("{}", err));
+    // --json のときだけ組み立てた結果を書く
+    if let Some(warnings) = warnings {
+        let json = Json::object([
+            ("command", "build".into()),
+            ("ok", true.into()),
+            ("output", output_path.into()),
+            ("bytes", bytes.into()),
+            (
+                "warnings",
+                Json::array(warnings.iter().map(Warning::to_json)),
+            ),
+        ]);
+        println!("{}", json);
+    }
+}
+
+// ロジックアナライザで記録した実機の出力とエミュレータの出力を比べる
+fn compare(capture_path: &str, program: Result<Vec<u8>, EmulatorErr>, options: &RunOptions) {
+    let actual = std::fs::read_to_string(capture_path)
+        .map_err(|_| EmulatorErr::new("capture file not found"))
+        .and_then(|text| Capture::from_csv(&text))
+        .and_then(|capture| capture.resample(options.clock));
+    let (actual, program) = match (actual, program) {
+        (Ok(actual), Ok(program)) => (actual, program),
+        (Err(err), _) | (_, Err(err)) => panic!("{}", err),
+    };
+
+    let mut emulator = CpuEmulator::with_profile(
+        Register::new(),
+        Ports::new(0b0000, 0b0000),
+        Rom::new(program),
+        options.profile.clone(),
+    );
+    emulator.set_quiet(true);
+    while !emulator.does_halt() && emulator.cycles() < actual.len() {
+        if let Err(err) = emulator.step() {
+            panic!("{}", err);
+        }
+    }
+    let expected = capture::output_levels(&emulator.output_history(), actual.len());
+
+    match capture::first_mismatch(&expected, &actual) {
+        None => println!("Output matches the emulator for {} cycles", actual.len()),
+        Some(mismatch) => {
+            println!(
+                "First mismatch at cycle {}: emulator {:04b}, capture {:04b}",
+                mismatch.cycle, mismatch.expected, mismatch.actual
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+// 手でトレースした期待値と1サイクルずつ比べる
+fn verify_trace(
+    program: Result<Vec<u8>, EmulatorErr>,
+    expected_path: &str,
+    profile: &MachineProfile,
+    input: u8,
+) {
+    let expected = std::fs::read_to_string(expected_path)
+        .map_err(|err| EmulatorErr::new(&format!("Failed to read {}: {}", expected_path, err)))
+        .and_then(|text| ExpectedTrace::from_csv(&text));
+    let (expected, program) = match (expected, program) {
+        (Ok(expected), Ok(program)) => (expected, program),
+        (Err(err), _) | (_, Err(err)) => panic!("{}", err),
+    };
+
+    match expected_trace::verify(&program, &expected, profile, input) {
+        Ok(None) => println!(
+            "The emulator matches all {} states of {}",
+            expected.rows.len(),
+            expected_path
+        ),
+        Ok(Some(divergence)) => {
+            print!("{}", divergence);
+            std::process::exit(1);
+        }
+        Err(err) => panic!("{}", err),
+    }
+}
+
+#[cfg(feature = "gates")]
+fn gates_semantics() -> Semantics {
+    Semantics::Gates
+}
+
+#[cfg(not(feature = "gates"))]
+fn gates_semantics() -> Semantics {
+    panic!("gate-level simulation is not available. Rebuild with `--features gates`");
+}
+
+// ランダムなROMを実行してパニックや不変条件の違反を探す
+fn fuzz_run(config: FuzzConfig) {
+    println!(
+        "Seed: {}, runs: {}, steps per run: {}, semantics: {:?}",
+        config.seed, config.runs, config.max_steps, config.semantics
+    );
+    let report = fuzz::fuzz_run(&config);
+    for finding in &report.findings {
+        println!("{}", finding);
+    }
+    println!(
+        "Halted: {}, errors: {}, still running: {}, findings: {}",
+        report.halted,
+        report.errors,
+        report.runs - report.halted - report.errors - report.findings.len(),
+        report.findings.len()
+    );
+    if !report.findings.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+// 命令とバイトの変換を出題する。空行かEOFで途中でもやめる
+fn quiz(mut quiz: Quiz, questions: usize) {
+    println!("Answer bytes as 8 bits (00110011) and instructions as in the assembler (mov A 0011)");
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    for number in 1..=questions {
+        let question = quiz.next_question();
+        print!("{}. {}: ", number, question.prompt());
+        std::io::stdout().flush().unwrap();
+
+        let answer = match lines.next() {
+            Some(Ok(line)) if !line.trim().is_empty() => line,
+            _ => break,
+        };
+        if quiz.answer(&question, &answer) {
+            println!("Correct");
+        } else {
+            println!("Wrong: {}", question.answer());
+        }
+    }
+    println!("Score: {}", quiz.score);
+}
+
+// DIPスイッチを1つずつ切り替えてROMを作る
+fn edit_switches(program: Result<Vec<u8>, EmulatorErr>, order: BitOrder, options: &RunOptions) {
+    let mut bank = match program.and_then(|program| SwitchBank::from_bytes(&program)) {
+        Ok(bank) => bank,
+        Err(err) => panic!("{}", err),
+    };
+    bank.set_bit_order(order);
+
+    println!(
+        "t <row> <bit>: toggle a switch, r <row> <8 bits>: set a row, w <file>: save, run, q: quit"
+    );
+    println!("{}", bank.render());
+
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("(switches) ");
+        std::io::stdout().flush().unwrap();
+
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words.as_slice() {
+            [] => continue,
+            ["q" | "quit"] => break,
+            ["t", row, bit] => match (row.parse(), bit.parse()) {
+                (Ok(row), Ok(bit)) => bank.toggle(row, bit),
+                _ => Err(EmulatorErr::new("usage: t <row> <bit>")),
+            },
+            ["r", row, bits @ ..] => match (row.parse(), u8::from_str_radix(&bits.concat(), 2)) {
+                // 左のスイッチから並べた8bitとして読む
+                (Ok(row), Ok(value)) => bank.set_row(row, order.arrange(value)),
+                _ => Err(EmulatorErr::new("usage: r <row> <8 bits>")),
+            },
+            ["w", file_path] => std::fs::write(file_path, bank.to_listing())
+                .map_err(|err| EmulatorErr::new(&err.to_string())),
+            ["run"] => {
+                run(Ok(bank.to_rom().into_bytes()), options);
+                continue;
+            }
+            _ => Err(EmulatorErr::new(&format!(
+                "Unknown command: {}",
+                line.trim()
+            ))),
+        };
+
+        match result {
+            Ok(_) => println!("{}", bank.render()),
+            Err(err) => println!("{}", err),
+        }
+    }
+}
+
+fn show_pipeline(
+    program: Result<Vec<u8>, EmulatorErr>,
+    max_cycles: usize,
+    profile: &MachineProfile,
+) {
+    let program = match program {
+        Ok(program) => program,
+        Err(err) => panic!("{}", err),
     };
 
     let rom = Rom::new(program);
     let register = Register::new();
-    let port = Port::new(0b0000, 0b0000);
-    let emulator = CpuEmulator::with(register, port, rom);
-    match emulator.exec() {
-        Ok(_) => (),
-        Err(err) => panic!("{:?}", err),
+    let port = Ports::new(0b0000, 0b0000);
+    let emulator = CpuEmulator::with_profile(register, port, rom, profile.clone());
+    let mut simulator = PipelineSimulator::new(&emulator);
+    let cycles = match simulator.run(max_cycles) {
+        Ok(cycles) => cycles,
+        Err(err) => panic!("{}", err),
+    };
+
+    print!("{}", pipeline::render_table(&cycles));
+    let stats = simulator.stats();
+    println!(
+        "Instructions: {}, Cycles: {}, CPI: {:.2}",
+        stats.instructions,
+        stats.cycles,
+        stats.cpi()
+    );
+}
+
+// 命令ごとの実行回数とループを表示する
+fn show_profile(
+    program: Result<Vec<u8>, EmulatorErr>,
+    max_cycles: usize,
+    profile: &MachineProfile,
+    json: bool,
+) {
+    let program = match program {
+        Ok(program) => program,
+        Err(err) => panic!("{}", err),
+    };
+
+    let mut emulator = CpuEmulator::with_profile(
+        Register::new(),
+        Ports::new(0b0000, 0b0000),
+        Rom::new(program),
+        profile.clone(),
+    );
+    emulator.set_quiet(true);
+    match profiler::profile(&emulator, max_cycles) {
+        Ok(profile) if json => println!(
+            "{}",
+            Json::object([("command", "profile".into()), ("ok", true.into())])
+                .with("profile", profile.to_json())
+        ),
+        Ok(profile) => print!("{}", profile.report()),
+        Err(err) => panic!("{}", err),
+    }
+}
+
+// 逆アセンブル結果の各行に実行回数と棒グラフを付けて表示する
+fn show_heatmap(
+    program: Result<(Vec<u8>, Option<DebugInfo>), EmulatorErr>,
+    max_cycles: usize,
+    profile: &MachineProfile,
+    color: bool,
+) {
+    let (program, debug_info) = match program {
+        Ok(program) => program,
+        Err(err) => panic!("{}", err),
+    };
+
+    let mut emulator = CpuEmulator::with_profile(
+        Register::new(),
+        Ports::new(0b0000, 0b0000),
+        Rom::new(program),
+        profile.clone(),
+    );
+    emulator.set_quiet(true);
+    match profiler::profile(&emulator, max_cycles) {
+        Ok(profile) => print!("{}", profile.annotated_listing(debug_info.as_ref(), color)),
+        Err(err) => panic!("{}", err),
+    }
+}
+
+fn debug(
+    target: &[&str],
+    load_options: &LoadOptions,
+    options: &RunOptions,
+    session_path: Option<&str>,
+) {
+    let session: Option<DebugSession> = session_path.map(|path| {
+        std::fs::read_to_string(path)
+            .map_err(|_| EmulatorErr::new("session file not found"))
+            .and_then(|text| text.parse())
+            .unwrap_or_else(|err| panic!("{}", err))
+    });
+    // プログラムを指定しなければセッションのファイルを読み直す。ファイルがなければ保存したROMを使う
+    let (program, path) = match (target, &load_options.example, &session) {
+        ([], None, Some(session)) => match &session.program {
+            Some(path) if std::path::Path::new(path).exists() => {
+                (load(&[path], load_options), Some(path.clone()))
+            }
+            _ => (Ok(session.rom.clone()), None),
+        },
+        ([file_path], None, _) => (load(target, load_options), Some(file_path.to_string())),
+        _ => (load(target, load_options), None),
+    };
+    let program = match program {
+        Ok(program) => program,
+        Err(err) => panic!("{}", err),
+    };
+
+    let rom = Rom::new(program);
+    let register = Register::new();
+    let port = Ports::new(0b0000, 0b0000);
+    let mut emulator = CpuEmulator::with_profile(register, port, rom, options.profile.clone());
+    emulator.set_renderer(options.format.renderer());
+    let mut debugger = Debugger::new(emulator).with_style(style::current());
+    if let Some(path) = path {
+        debugger = debugger.with_program(&path);
+    }
+    if let Some(session) = session {
+        match debugger.restore_session(&session) {
+            Ok(message) => println!("{}", message),
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("(td4) ");
+        std::io::stdout().flush().unwrap();
+
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        if matches!(line.trim(), "quit" | "q") {
+            break;
+        }
+        match debugger.execute(&line) {
+            Ok(message) if message.is_empty() => (),
+            Ok(message) => println!("{}", message),
+            Err(err) => println!("{}", style::current().error(&err.to_string())),
+        }
+    }
+}
+
+#[cfg(not(feature = "record"))]
+fn record(
+    _program: Result<Vec<u8>, EmulatorErr>,
+    _output_path: &str,
+    _hz: f64,
+    _run: (usize, u8),
+    _profile: &MachineProfile,
+) {
+    panic!("recording is not available. Rebuild with `--features record`");
+}
+
+// 1サイクルごとの図をGIFアニメーションにする
+#[cfg(feature = "record")]
+fn record(
+    program: Result<Vec<u8>, EmulatorErr>,
+    output_path: &str,
+    hz: f64,
+    (max_cycles, input): (usize, u8),
+    profile: &MachineProfile,
+) {
+    let program = program.unwrap_or_else(|err| panic!("{}", err));
+    let mut machine = Machine::new(profile.clone());
+    machine.set_quiet(true);
+    let frames = machine
+        .load_rom(program)
+        .and_then(|_| machine.emulator().set_input_port(0, input))
+        .and_then(|_| svg::frames(&mut machine, max_cycles))
+        .unwrap_or_else(|err| panic!("{}", err));
+    let file = std::fs::File::create(output_path)
+        .unwrap_or_else(|err| panic!("Failed to create {}: {}", output_path, err));
+    td4emu::record::record(&frames, hz, std::io::BufWriter::new(file))
+        .unwrap_or_else(|err| panic!("{}", err));
+    println!("Recorded {} frames to {}", frames.len(), output_path);
+}
+
+#[cfg(not(feature = "web"))]
+fn web(_address: &str, _profile: &MachineProfile) {
+    panic!("the web UI is not available. Rebuild with `--features web`");
+}
+
+// ブラウザでプログラムを書いて動かせるページを配信する
+#[cfg(feature = "web")]
+fn web(address: &str, profile: &MachineProfile) {
+    if let Err(err) = td4emu::web::serve(address, profile.clone()) {
+        panic!("{}", err);
+    }
+}
+
+#[cfg(not(feature = "watch"))]
+fn watch(_file_path: &str, _load_options: &LoadOptions, _options: &RunOptions) {
+    panic!("watch mode is not available. Rebuild with `--features watch`");
+}
+
+// ファイルが更新されるたびにアセンブルし直して再実行する
+#[cfg(feature = "watch")]
+fn watch(file_path: &str, load_options: &LoadOptions, options: &RunOptions) {
+    use notify::{RecursiveMode, Watcher};
+    use std::path::Path;
+    use std::sync::mpsc::channel;
+    use td4emu::machine::StopReason;
+
+    // 無限ループするプログラムでも次の更新を待てるように実行サイクル数を制限する
+    const MAX_CYCLES: usize = 1000;
+
+    let path = Path::new(file_path).canonicalize().expect("file not found");
+    let (tx, rx) = channel();
+    let mut watcher = notify::recommended_watcher(tx).expect("failed to start watcher");
+    // エディタがファイルを置き換えても追えるように親ディレクトリを監視する
+    watcher
+        .watch(path.parent().unwrap(), RecursiveMode::NonRecursive)
+        .expect("failed to watch file");
+
+    loop {
+        println!("--- {} ---", file_path);
+        match load(&[file_path], load_options) {
+            Ok(program) => {
+                let mut machine = Machine::new(options.profile.clone());
+                machine.set_output_format(options.format);
+                match machine
+                    .load_rom(program)
+                    .and_then(|_| machine.run(Some(MAX_CYCLES)))
+                {
+                    Ok(StopReason::CycleLimit) => println!("(stopped after {} cycles)", MAX_CYCLES),
+                    Ok(_) => (),
+                    Err(err) => eprintln!("{}", style::current().error(&err.to_string())),
+                }
+            }
+            Err(err) => eprintln!("{}", style::current().error(&err.to_string())),
+        }
+
+        // 対象ファイルへの変更を待つ
+        loop {
+            match rx.recv() {
+                Ok(Ok(event)) if event.paths.iter().any(|p| p == &path) => break,
+                Ok(_) => continue,
+                Err(_) => return,
+            }
+        }
+        // 保存時にまとめて届くイベントを読み捨てる
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        while rx.try_recv().is_ok() {}
     }
 }
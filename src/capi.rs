@@ -0,0 +1,266 @@
+use crate::compiler::assemble;
+use crate::emulator::CpuEmulator;
+use crate::error::EmulatorErr;
+use crate::port::Ports;
+use crate::profile::MachineProfile;
+use crate::register::Register;
+use crate::rom::Rom;
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+// C から使うための関数 (capi フィーチャー)
+// 失敗すると負の値かNULLを返し、理由は td4_last_error で読める
+// ヘッダは include/td4emu.h (cbindgen で生成する)
+
+// C側からは中身の見えないハンドル
+pub struct Td4Machine {
+    emulator: CpuEmulator,
+}
+
+// td4_get_state で書き込むCPUの状態
+#[repr(C)]
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct Td4State {
+    pub pc: u8,
+    pub a: u8,
+    pub b: u8,
+    pub carry: u8,
+    pub input: u8,
+    pub output: u8,
+    pub cycles: u64,
+    pub halted: bool,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_error(message: &str) {
+    // メッセージ中のNULは文字列の終わりと区別できないので取り除く
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|error| *error.borrow_mut() = message);
+}
+
+// panicをCに伝えないように止め、エラーは td4_last_error に残す
+fn guard<T>(failed: T, f: impl FnOnce() -> Result<T, EmulatorErr>) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(err)) => {
+            set_error(&err.to_string());
+            failed
+        }
+        Err(_) => {
+            set_error("td4emu panicked");
+            failed
+        }
+    }
+}
+
+unsafe fn read_str<'a>(text: *const c_char) -> Result<&'a str, EmulatorErr> {
+    if text.is_null() {
+        return Err(EmulatorErr::new("string is NULL"));
+    }
+    CStr::from_ptr(text)
+        .to_str()
+        .map_err(|_| EmulatorErr::new("string is not UTF-8"))
+}
+
+/// 直前に失敗した関数のエラーメッセージ。同じスレッドで次に失敗するまで有効
+#[no_mangle]
+pub extern "C" fn td4_last_error() -> *const c_char {
+    LAST_ERROR.with(|error| error.borrow().as_ptr())
+}
+
+/// ソースコードをアセンブルして out に書き込み、バイト数を返す。失敗すると -1
+///
+/// # Safety
+/// source はNUL終端の文字列、out は capacity バイト書き込める領域であること
+#[no_mangle]
+pub unsafe extern "C" fn td4_assemble(source: *const c_char, out: *mut u8, capacity: usize) -> i32 {
+    guard(-1, || {
+        let program = assemble(read_str(source)?)?;
+        if program.len() > capacity || out.is_null() {
+            return Err(EmulatorErr::new(&format!(
+                "the program is {} bytes but the buffer holds {}",
+                program.len(),
+                capacity
+            )));
+        }
+        ptr::copy_nonoverlapping(program.as_ptr(), out, program.len());
+        Ok(program.len() as i32)
+    })
+}
+
+/// ROMを読み込んだマシンを作る。profile が NULL なら td4-strict。失敗すると NULL
+///
+/// # Safety
+/// rom は len バイト読める領域、profile はNULLかNUL終端の文字列であること
+#[no_mangle]
+pub unsafe extern "C" fn td4_machine_new(
+    rom: *const u8,
+    len: usize,
+    profile: *const c_char,
+) -> *mut Td4Machine {
+    guard(ptr::null_mut(), || {
+        let profile = if profile.is_null() {
+            MachineProfile::default()
+        } else {
+            read_str(profile)?.parse()?
+        };
+        if rom.is_null() && len > 0 {
+            return Err(EmulatorErr::new("rom is NULL"));
+        }
+        if len > profile.rom_size {
+            return Err(EmulatorErr::new(&format!(
+                "Maximum memory size is {} but the program is {} bytes",
+                profile.rom_size, len
+            )));
+        }
+        let bytes = if len == 0 {
+            Vec::new()
+        } else {
+            std::slice::from_raw_parts(rom, len).to_vec()
+        };
+        let mut emulator =
+            CpuEmulator::with_profile(Register::new(), Ports::new(0, 0), Rom::new(bytes), profile);
+        emulator.set_quiet(true);
+        Ok(Box::into_raw(Box::new(Td4Machine { emulator })))
+    })
+}
+
+/// td4_machine_new で作ったマシンを解放する。NULLなら何もしない
+///
+/// # Safety
+/// machine は td4_machine_new が返したもので、まだ解放していないこと
+#[no_mangle]
+pub unsafe extern "C" fn td4_machine_free(machine: *mut Td4Machine) {
+    if !machine.is_null() {
+        drop(Box::from_raw(machine));
+    }
+}
+
+/// 1命令実行して0を返す。停止していれば何もせず1、エラーなら -1
+///
+/// # Safety
+/// machine は td4_machine_new が返した有効なポインタであること
+#[no_mangle]
+pub unsafe extern "C" fn td4_step(machine: *mut Td4Machine) -> i32 {
+    guard(-1, || {
+        let machine = machine
+            .as_mut()
+            .ok_or_else(|| EmulatorErr::new("machine is NULL"))?;
+        if machine.emulator.does_halt() {
+            return Ok(1);
+        }
+        machine.emulator.step()?;
+        Ok(0)
+    })
+}
+
+/// 今の状態を state に書き込む。成功すると0、失敗すると -1
+///
+/// # Safety
+/// machine は有効なポインタ、state は Td4State を書き込める領域であること
+#[no_mangle]
+pub unsafe extern "C" fn td4_get_state(machine: *const Td4Machine, state: *mut Td4State) -> i32 {
+    guard(-1, || {
+        let emulator = &machine
+            .as_ref()
+            .ok_or_else(|| EmulatorErr::new("machine is NULL"))?
+            .emulator;
+        let state = state
+            .as_mut()
+            .ok_or_else(|| EmulatorErr::new("state is NULL"))?;
+        let register = emulator.register();
+        *state = Td4State {
+            pc: register.pc(),
+            a: register.register_a(),
+            b: register.register_b(),
+            carry: register.carry_flag(),
+            input: emulator.input(),
+            output: emulator.output(),
+            cycles: emulator.cycles() as u64,
+            halted: emulator.does_halt(),
+        };
+        Ok(0)
+    })
+}
+
+/// 入力ポートに値を入れる。成功すると0、レジスタの幅に収まらなければ -1
+///
+/// # Safety
+/// machine は td4_machine_new が返した有効なポインタであること
+#[no_mangle]
+pub unsafe extern "C" fn td4_set_input(machine: *mut Td4Machine, value: u8) -> i32 {
+    guard(-1, || {
+        let machine = machine
+            .as_mut()
+            .ok_or_else(|| EmulatorErr::new("machine is NULL"))?;
+        machine.emulator.set_input_port(0, value)?;
+        Ok(0)
+    })
+}
+
+#[cfg(test)]
+mod capi_tests {
+    use crate::capi::{
+        td4_assemble, td4_get_state, td4_last_error, td4_machine_free, td4_machine_new,
+        td4_set_input, td4_step, Td4State,
+    };
+    use std::ffi::{CStr, CString};
+    use std::ptr;
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(td4_last_error()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_run_through_c_api() {
+        let source = CString::new("in A\nadd A 0011\nmov B A\nout B\njmp 0100").unwrap();
+        let mut rom = [0u8; 16];
+        let len = unsafe { td4_assemble(source.as_ptr(), rom.as_mut_ptr(), rom.len()) };
+        assert_eq!(len, 5);
+
+        let profile = CString::new("td4-book").unwrap();
+        let machine = unsafe { td4_machine_new(rom.as_ptr(), len as usize, profile.as_ptr()) };
+        assert!(!machine.is_null());
+        unsafe {
+            assert_eq!(td4_set_input(machine, 4), 0);
+            while td4_step(machine) == 0 {}
+
+            let mut state = Td4State::default();
+            assert_eq!(td4_get_state(machine, &mut state), 0);
+            assert_eq!((state.a, state.output, state.cycles), (7, 7, 4));
+            assert!(state.halted);
+            td4_machine_free(machine);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let source = CString::new("mov A 10000").unwrap();
+        let mut rom = [0u8; 16];
+        assert_eq!(
+            unsafe { td4_assemble(source.as_ptr(), rom.as_mut_ptr(), rom.len()) },
+            -1
+        );
+        assert!(last_error().contains("doesn't fit in 4 bits"));
+
+        let profile = CString::new("td5").unwrap();
+        let machine = unsafe { td4_machine_new(rom.as_ptr(), 1, profile.as_ptr()) };
+        assert!(machine.is_null());
+        assert!(last_error().starts_with("Unknown profile: td5"));
+
+        let machine = unsafe { td4_machine_new(rom.as_ptr(), 1, ptr::null()) };
+        unsafe {
+            assert_eq!(td4_set_input(machine, 16), -1);
+            assert_eq!(td4_step(ptr::null_mut()), -1);
+            assert_eq!(last_error(), "machine is NULL");
+            td4_machine_free(machine);
+        }
+    }
+}
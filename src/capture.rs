@@ -0,0 +1,211 @@
+use crate::error::EmulatorErr;
+
+// ロジックアナライザで実機の出力ポートを記録したCSV
+// ヘッダーは time,out0,out1,out2,out3 (out0が最下位ビット, timeは秒)
+// clk 列があればクロックの立ち上がりでサイクルを区切り、なければ周波数から区切る
+#[derive(Debug, PartialEq, Clone)]
+pub struct Capture {
+    // (時刻, 出力ポートの値)
+    samples: Vec<(f64, u8)>,
+    // クロックの立ち上がりの時刻
+    clock_edges: Option<Vec<f64>>,
+}
+
+// 実機と出力ポートの値が初めて食い違ったサイクル
+#[derive(Debug, PartialEq, Clone)]
+pub struct Mismatch {
+    pub cycle: usize,
+    pub expected: u8,
+    pub actual: u8,
+}
+
+impl Capture {
+    pub fn from_csv(text: &str) -> Result<Self, EmulatorErr> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty());
+        let (_, header) = lines
+            .next()
+            .ok_or_else(|| EmulatorErr::new("capture is empty"))?;
+        let columns: Vec<String> = header
+            .split(',')
+            .map(|column| column.trim().to_lowercase())
+            .collect();
+        let column = |name: &str| {
+            columns
+                .iter()
+                .position(|column| column == name)
+                .ok_or_else(|| EmulatorErr::new(&format!("capture has no {} column", name)))
+        };
+        let time = column("time")?;
+        let outs = [
+            column("out0")?,
+            column("out1")?,
+            column("out2")?,
+            column("out3")?,
+        ];
+        let clk = column("clk").ok();
+
+        let mut samples = Vec::new();
+        let mut clock_edges = Vec::new();
+        let mut last_clk = None;
+        for (index, line) in lines {
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+            let field = |position: usize| {
+                fields
+                    .get(position)
+                    .copied()
+                    .ok_or_else(|| EmulatorErr::new(&format!("line {}: missing column", index + 1)))
+            };
+            let time_text = field(time)?;
+            let time: f64 = time_text.parse().map_err(|_| {
+                EmulatorErr::new(&format!("line {}: invalid time {}", index + 1, time_text))
+            })?;
+            let bit = |position: usize| match field(position)? {
+                "0" => Ok(0),
+                "1" => Ok(1),
+                other => Err(EmulatorErr::new(&format!(
+                    "line {}: a signal must be 0 or 1: {}",
+                    index + 1,
+                    other
+                ))),
+            };
+
+            let mut value = 0;
+            for (bit_index, position) in outs.iter().enumerate() {
+                value |= bit(*position)? << bit_index;
+            }
+            samples.push((time, value));
+
+            if let Some(position) = clk {
+                let level = bit(position)?;
+                if last_clk == Some(0) && level == 1 {
+                    clock_edges.push(time);
+                }
+                last_clk = Some(level);
+            }
+        }
+
+        Ok(Capture {
+            samples,
+            clock_edges: clk.map(|_| clock_edges),
+        })
+    }
+
+    // サイクルごとの出力ポートの値に変換する (各サイクルの終わりの値)
+    // clk 列がなければ hz で区切る
+    pub fn resample(&self, hz: Option<f64>) -> Result<Vec<u8>, EmulatorErr> {
+        let boundaries: Vec<f64> = match (&self.clock_edges, hz) {
+            // 最初の立ち上がりまではリセット直後なので、その次から1サイクルと数える
+            (Some(edges), _) => edges.iter().skip(1).copied().collect(),
+            (None, Some(hz)) => {
+                let end = self.samples.last().map_or(0.0, |(time, _)| *time);
+                let start = self.samples.first().map_or(0.0, |(time, _)| *time);
+                let cycles = ((end - start) * hz).floor() as usize;
+                (1..=cycles)
+                    .map(|cycle| start + cycle as f64 / hz)
+                    .collect()
+            }
+            (None, None) => {
+                return Err(EmulatorErr::new(
+                    "capture has no clk column. Specify the clock frequency",
+                ))
+            }
+        };
+
+        Ok(boundaries
+            .iter()
+            .map(|boundary| {
+                self.samples
+                    .iter()
+                    .take_while(|(time, _)| time < boundary)
+                    .last()
+                    .map_or(0, |(_, value)| *value)
+            })
+            .collect())
+    }
+}
+
+// エミュレータの出力履歴をサイクルごとの値にする
+pub fn output_levels(history: &[(usize, u8)], cycles: usize) -> Vec<u8> {
+    (0..cycles)
+        .map(|cycle| {
+            history
+                .iter()
+                .take_while(|(written, _)| *written <= cycle)
+                .last()
+                .map_or(0, |(_, value)| *value)
+        })
+        .collect()
+}
+
+pub fn first_mismatch(expected: &[u8], actual: &[u8]) -> Option<Mismatch> {
+    expected
+        .iter()
+        .zip(actual)
+        .position(|(expected, actual)| expected != actual)
+        .map(|cycle| Mismatch {
+            cycle,
+            expected: expected[cycle],
+            actual: actual[cycle],
+        })
+}
+
+#[cfg(test)]
+mod capture_tests {
+    use crate::capture::{first_mismatch, output_levels, Capture, Mismatch};
+
+    const CAPTURE: &str = "time,out0,out1,out2,out3
+0.00,0,0,0,0
+0.15,1,0,0,0
+0.35,0,1,0,0
+0.45,1,1,0,0
+";
+
+    #[test]
+    fn test_resample_with_frequency() {
+        let capture = Capture::from_csv(CAPTURE).unwrap();
+        assert_eq!(capture.resample(Some(10.0)).unwrap(), vec![0, 1, 1, 2]);
+        assert!(capture.resample(None).is_err());
+    }
+
+    #[test]
+    fn test_resample_with_clock_column() {
+        let capture = Capture::from_csv(
+            "time,clk,out0,out1,out2,out3
+0,0,0,0,0,0
+1,1,0,0,0,0
+2,0,1,0,0,0
+3,1,1,0,0,0
+4,0,1,1,0,0
+5,1,1,1,0,0
+",
+        )
+        .unwrap();
+        assert_eq!(capture.resample(None).unwrap(), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_invalid_capture() {
+        assert!(Capture::from_csv("").is_err());
+        assert!(Capture::from_csv("time,out0,out1,out2").is_err());
+        assert!(Capture::from_csv("time,out0,out1,out2,out3\n0,2,0,0,0").is_err());
+        assert!(Capture::from_csv("time,out0,out1,out2,out3\n0,1,0").is_err());
+    }
+
+    #[test]
+    fn test_first_mismatch() {
+        let expected = output_levels(&[(0, 1), (2, 2)], 4);
+        assert_eq!(expected, vec![1, 1, 2, 2]);
+        assert_eq!(first_mismatch(&expected, &[1, 1, 2, 2]), None);
+        assert_eq!(
+            first_mismatch(&expected, &[1, 1, 3, 2]),
+            Some(Mismatch {
+                cycle: 2,
+                expected: 2,
+                actual: 3
+            })
+        );
+    }
+}
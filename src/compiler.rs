@@ -1,61 +1,201 @@
+use crate::debug_info::{DebugInfo, Region};
+use crate::directive;
 use crate::error::EmulatorErr;
-use crate::token::{Register, Token};
+use crate::instruction::Instruction;
+use crate::macros::MacroExpander;
+use crate::parser::{strip_comment, Parser, Syntax};
+use crate::profile::MachineProfile;
+use crate::token::Token;
 
-pub struct Compiler;
+// ソースコード全体をパースしてバイナリに変換する
+pub fn assemble(source: &str) -> Result<Vec<u8>, EmulatorErr> {
+    assemble_with_debug_info(source).map(|(program, _)| program)
+}
+
+// 逆アセンブラ向けにデバッグ情報も一緒に返す
+pub fn assemble_with_debug_info(source: &str) -> Result<(Vec<u8>, DebugInfo), EmulatorErr> {
+    assemble_with(source, &MacroExpander::new())
+}
+
+pub fn assemble_with(
+    source: &str,
+    expander: &MacroExpander,
+) -> Result<(Vec<u8>, DebugInfo), EmulatorErr> {
+    assemble_with_syntax(source, expander, Syntax::V1)
+}
+
+pub fn assemble_with_syntax(
+    source: &str,
+    expander: &MacroExpander,
+    syntax: Syntax,
+) -> Result<(Vec<u8>, DebugInfo), EmulatorErr> {
+    assemble_with_profile(source, expander, syntax, &MachineProfile::default())
+}
+
+// 実行するプロファイルに合わせて組み立てる。in / out B のポート番号は拡張モードでだけ書ける
+pub fn assemble_with_profile(
+    source: &str,
+    expander: &MacroExpander,
+    syntax: Syntax,
+    profile: &MachineProfile,
+) -> Result<(Vec<u8>, DebugInfo), EmulatorErr> {
+    assemble_with_lines(source, expander, syntax, false, profile)
+        .map(|(program, debug_info, _)| (program, debug_info))
+}
+
+// アセンブルしたバイト列、デバッグ情報と、各アドレスのバイトを生成した行番号 (1から)
+// .org で埋めたアドレスの行番号は None
+pub type AssembledWithLines = (Vec<u8>, DebugInfo, Vec<Option<usize>>);
+
+// ROMの表にソースを並べるために行番号も返す
+// allow_truncation なら4bitに収まらない即値を警告して下位4bitに切り詰める
+pub fn assemble_with_lines(
+    source: &str,
+    expander: &MacroExpander,
+    syntax: Syntax,
+    allow_truncation: bool,
+    profile: &MachineProfile,
+) -> Result<AssembledWithLines, EmulatorErr> {
+    // マクロの定義や呼び出しの行にもコメントを書けるように展開より先に取り除く
+    // ;! の実行の設定はどちらの文法でも読み飛ばす
+    let source = match syntax {
+        Syntax::V1 => directive::strip(source),
+        Syntax::V2 => source
+            .lines()
+            .map(strip_comment)
+            .collect::<Vec<_>>()
+            .join("\n"),
+    };
+    let mut parser = Parser::with_syntax(expander.expand(&source)?, syntax)?;
+    parser.set_allow_truncation(allow_truncation);
+    parser.set_mode(&profile.mode);
+    let tokens = parser.parse()?;
+
+    let compiler = Compiler::new();
+    let (program, debug_info) = compiler.compile_with_debug_info(tokens)?;
+    let mut lines = vec![None; program.len()];
+    for (address, line) in parser.source_lines() {
+        if let Some(slot) = lines.get_mut(*address) {
+            *slot = Some(*line);
+        }
+    }
+    Ok((program, debug_info, lines))
+}
+
+#[derive(Clone)]
+pub struct Compiler {
+    // .org で空いたアドレスを埋めるバイト
+    filler: u8,
+}
 
 impl Compiler {
     pub fn new() -> Self {
-        Compiler
+        Compiler { filler: 0 }
+    }
+
+    pub fn with_filler(filler: u8) -> Self {
+        Compiler { filler }
+    }
+
+    // テストでトークン列から直接組み立てる
+    #[cfg(test)]
+    pub(crate) fn compile(&self, tokens: Vec<Token>) -> Result<Vec<u8>, EmulatorErr> {
+        self.compile_with_debug_info(tokens)
+            .map(|(program, _)| program)
     }
 
-    pub fn compile(&self, tokens: Vec<Token>) -> Result<Vec<u8>, EmulatorErr> {
+    pub(crate) fn compile_with_debug_info(
+        &self,
+        tokens: Vec<Token>,
+    ) -> Result<(Vec<u8>, DebugInfo), EmulatorErr> {
         if tokens.is_empty() {
             return Err(EmulatorErr::new(
-                    "Failed to start to compile because token list is empty."
-                    ));
+                "Failed to start to compile because token list is empty.",
+            ));
         }
 
         let mut result = Vec::new();
-
+        let mut debug_info = DebugInfo::new();
         for token in tokens {
-            let program = match token {
-                Token::Mov(Register::A, im) => self.gen_bin_code(0b0011, im),
-                Token::Mov(Register::B, im) => self.gen_bin_code(0b0111, im),
-                Token::MovAB => self.gen_bin_code_with_zero_padding(0b0001),
-                Token::MovBA => self.gen_bin_code_with_zero_padding(0b0100),
-                Token::Add(Register::A, im) => self.gen_bin_code(0b0000, im),
-                Token::Add(Register::B, im) => self.gen_bin_code(0b0101, im),
-                Token::Jmp(im) => self.gen_bin_code(0b1111, im),
-                Token::Jnc(im) => self.gen_bin_code(0b1110, im),
-                Token::In(Register::A) => self.gen_bin_code_with_zero_padding(0b0010),
-                Token::In(Register::B) => self.gen_bin_code_with_zero_padding(0b0110),
-                Token::OutB => self.gen_bin_code_with_zero_padding(0b1001),
-                Token::OutIm(im) => self.gen_bin_code(0b1011, im),
-            };
-            result.push(program);
+            self.place(token, &mut result, &mut debug_info)?;
         }
-
-        Ok(result)
+        Ok((result, debug_info))
     }
 
-    fn gen_bin_code(&self, op: u8, im: u8) -> u8 {
-        let shift_op = op << 4;
-        let shift_data = im & 0x0f;
-        shift_op | shift_data
+    // トークンを1つ result の後ろに置く。Assembler は1行ずつこれを呼ぶ
+    pub(crate) fn place(
+        &self,
+        token: Token,
+        result: &mut Vec<u8>,
+        debug_info: &mut DebugInfo,
+    ) -> Result<(), EmulatorErr> {
+        if let Token::Org(address) = token {
+            if (address as usize) < result.len() {
+                return Err(EmulatorErr::new(&format!(
+                    ".org 0x{:x} overlaps code already placed at 0x0..0x{:x}",
+                    address,
+                    result.len() - 1
+                )));
+            }
+            // 指定アドレスまでの隙間は埋め草のデータとして扱う
+            while result.len() < address as usize {
+                result.push(self.filler);
+                debug_info.push(Region::Data);
+            }
+            return Ok(());
+        }
+
+        let instruction = match token {
+            Token::Mov(reg, im) => Instruction::Mov { reg, im },
+            Token::MovAB => Instruction::MovAB,
+            Token::MovBA => Instruction::MovBA,
+            Token::Add(reg, im) => Instruction::Add { reg, im },
+            Token::Jmp(im) => Instruction::Jmp { im },
+            Token::Jnc(im) => Instruction::Jnc { im },
+            Token::In(reg, port) => Instruction::In { reg, port },
+            Token::OutB(port) => Instruction::OutB { port },
+            Token::OutIm(im) => Instruction::OutIm { im },
+            Token::Call(im) => Instruction::Call { im },
+            Token::Ret => Instruction::Ret,
+            Token::Sub(reg, im) => Instruction::Sub { reg, im },
+            Token::Cmp => Instruction::Cmp,
+            Token::Ld => Instruction::Ld,
+            Token::St => Instruction::St,
+            Token::Swap => Instruction::Swap,
+            Token::Byte(data) => {
+                result.push(data);
+                debug_info.push(Region::Data);
+                return Ok(());
+            }
+            Token::Org(_) => unreachable!("handled above"),
+        };
+        result.push(instruction.encode());
+        debug_info.push(Region::Code);
+        Ok(())
     }
+}
 
-    fn gen_bin_code_with_zero_padding(&self, op: u8) -> u8 {
-        let shift_op = op << 4;
-        let zero_padding = 0b0000 & 0x0f;
-        shift_op | zero_padding
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[cfg(test)]
 mod compiler_tests {
     use crate::compiler::Compiler;
+    use crate::compiler::{
+        assemble, assemble_with_debug_info, assemble_with_lines, assemble_with_syntax,
+    };
+    use crate::debug_info::Region;
+    use crate::examples;
+    use crate::macros::MacroExpander;
+    use crate::parser::Syntax;
+    use crate::profile::MachineProfile;
     use crate::token::Register;
-    use crate::token::Token::{Add, In, Jmp, Jnc, Mov, MovAB, MovBA, OutB, OutIm};
+    use crate::token::Token::{
+        Add, Byte, Call, Cmp, In, Jmp, Jnc, Mov, MovAB, MovBA, Org, OutB, OutIm, Ret, Sub,
+    };
 
     #[test]
     fn test_compile_mov_a() {
@@ -115,13 +255,13 @@ mod compiler_tests {
     #[test]
     fn test_compile_in_a() {
         let compiler = Compiler::new();
-        let program = compiler.compile(vec![In(Register::A)]);
+        let program = compiler.compile(vec![In(Register::A, 0)]);
         assert_eq!(program.unwrap(), vec![0b00100000]);
     }
     #[test]
     fn test_compile_in_b() {
         let compiler = Compiler::new();
-        let program = compiler.compile(vec![In(Register::B)]);
+        let program = compiler.compile(vec![In(Register::B, 0)]);
         assert_eq!(program.unwrap(), vec![0b01100000]);
     }
 
@@ -135,7 +275,111 @@ mod compiler_tests {
     #[test]
     fn test_compile_out_b() {
         let compiler = Compiler::new();
-        let program = compiler.compile(vec![OutB]);
+        let program = compiler.compile(vec![OutB(0)]);
         assert_eq!(program.unwrap(), vec![0b10010000]);
     }
+
+    #[test]
+    fn test_compile_call_ret() {
+        let compiler = Compiler::new();
+        let program = compiler.compile(vec![Call(3), Ret]);
+        assert_eq!(program.unwrap(), vec![0b10000011, 0b10100000]);
+    }
+
+    #[test]
+    fn test_compile_sub_cmp() {
+        let compiler = Compiler::new();
+        let program = compiler.compile(vec![Sub(Register::A, 1), Sub(Register::B, 2), Cmp]);
+        assert_eq!(program.unwrap(), vec![0b11000001, 0b11010010, 0b10100001]);
+    }
+
+    #[test]
+    fn test_compile_byte() {
+        let compiler = Compiler::new();
+        let program = compiler.compile(vec![Byte(0b10110001), OutB(0)]);
+        assert_eq!(program.unwrap(), vec![0b10110001, 0b10010000]);
+    }
+
+    #[test]
+    fn test_data_regions() {
+        let source = "jmp 0011\n.data 0x12, 'A'\n.byte 0b10110001\nout B\n";
+        let (program, debug_info) = assemble_with_debug_info(source).unwrap();
+        assert_eq!(
+            program,
+            vec![0b11110011, 0x12, 0x41, 0b10110001, 0b10010000]
+        );
+        assert_eq!(debug_info.region(0), Some(Region::Code));
+        assert!(debug_info.is_data(1));
+        assert!(debug_info.is_data(2));
+        assert!(debug_info.is_data(3));
+        assert_eq!(debug_info.region(4), Some(Region::Code));
+        assert_eq!(debug_info.region(5), None);
+
+        assert_eq!(assemble(".data ','").unwrap(), vec![b',']);
+    }
+
+    #[test]
+    fn test_org_fills_gap() {
+        let compiler = Compiler::with_filler(0xff);
+        let program = compiler.compile(vec![Jnc(3), Org(3), OutB(0), Org(4), OutIm(1)]);
+        assert_eq!(
+            program.unwrap(),
+            vec![0b11100011, 0xff, 0xff, 0b10010000, 0b10110001]
+        );
+
+        let (_, debug_info) = assemble_with_debug_info("out B\n.org 2\nout B").unwrap();
+        assert!(debug_info.is_data(1));
+        assert_eq!(debug_info.region(2), Some(Region::Code));
+    }
+
+    #[test]
+    fn test_org_overlap() {
+        let compiler = Compiler::new();
+        assert!(compiler
+            .compile(vec![OutB(0), OutB(0), Org(1), OutB(0)])
+            .is_err());
+    }
+
+    #[test]
+    fn test_syntax_v2_matches_v1() {
+        let v1 = examples::find("ramen_timer").unwrap().source;
+        let v2 = "; ramen timer
+                  out 0b0111    # start
+        wait1:    add A, 1
+                  jnc wait1
+        wait2:    add A, 1
+                  jnc wait2
+                  out 0b0110
+        wait3:    add A, 1
+                  jnc wait3
+        wait4:    add A, 1
+                  jnc wait4
+        blink:    out 0
+                  out 0b0100
+                  add A, 1
+                  jnc blink
+                  out 0b1000
+        end:      jmp end";
+        let expander = MacroExpander::new();
+        assert_eq!(
+            assemble_with_syntax(v2, &expander, Syntax::V2).unwrap(),
+            assemble_with_syntax(v1, &expander, Syntax::V1).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_source_lines() {
+        // マクロの中身は呼び出した行、.org の埋め草は None
+        let source = ".macro blink\nout 0b1111\nout 0\n.endm\n\nblink\n.org 3\n.data 1, 2";
+        let (program, _, lines) = assemble_with_lines(
+            source,
+            &MacroExpander::new(),
+            Syntax::V2,
+            false,
+            &MachineProfile::default(),
+        )
+        .unwrap();
+        assert_eq!(program.len(), 5);
+        assert_eq!(lines, vec![Some(6), Some(6), None, Some(8), Some(8)]);
+    }
 }
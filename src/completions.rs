@@ -0,0 +1,489 @@
+use crate::error::EmulatorErr;
+use std::str::FromStr;
+
+// CLIの使い方の文字列からシェルの補完スクリプトとmanページを作る
+// サブコマンドやオプションを増やしても使い方に書けば補完にも載る
+//
+//     [command] table [--profile name] [--table-format md|csv] [file_path | --example name]
+//
+// の行からは table コマンドと、値を取る --profile と md か csv を取る --table-format を読み取る
+#[derive(Debug, PartialEq, Clone)]
+pub struct Flag {
+    pub name: String,
+    // 値を取るオプションなら値の説明 (name, file など)
+    pub value: Option<String>,
+    // 値の候補。ファイル名など自由な値なら空
+    pub choices: Vec<String>,
+}
+
+impl Flag {
+    // --trace file や --spec spec.toml のように値がファイルのパスか
+    pub fn takes_file(&self) -> bool {
+        self.value.as_deref().is_some_and(|value| {
+            value.contains("file") || value.contains("path") || value.contains('.')
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Command {
+    pub name: String,
+    pub flags: Vec<Flag>,
+    // completions bash|zsh|fish のように決まった引数の候補
+    pub arguments: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CommandLine {
+    pub program: String,
+    // コマンドごとの使い方の行
+    pub usages: Vec<String>,
+    // 最初のコマンドはサブコマンドを省いたときに実行される
+    pub commands: Vec<Command>,
+    // すべてのコマンドに共通のオプション
+    pub global: Vec<Flag>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl FromStr for Shell {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(EmulatorErr::new(&format!("Unknown shell: {}", s))),
+        }
+    }
+}
+
+impl CommandLine {
+    // "[command]" で始まる行がコマンド、それ以外の行のオプションは共通のオプション
+    pub fn parse(program: &str, usage: &str) -> CommandLine {
+        let mut command_line = CommandLine {
+            program: program.to_string(),
+            usages: Vec::new(),
+            commands: Vec::new(),
+            global: Vec::new(),
+        };
+        for line in usage.lines() {
+            let line = line.trim().trim_start_matches("Usage:").trim();
+            let rest = match line.strip_prefix("[command]") {
+                Some(rest) => rest.trim(),
+                None => {
+                    merge_flags(&mut command_line.global, parse_flags(line));
+                    continue;
+                }
+            };
+            command_line.usages.push(rest.to_string());
+            // [run | watch | debug] のように複数のコマンドが同じ使い方のこともある
+            let (names, rest) = match rest.strip_prefix('[') {
+                Some(group) => {
+                    let (names, rest) = group.split_once(']').unwrap_or((group, ""));
+                    (names.split('|').map(str::trim).collect::<Vec<_>>(), rest)
+                }
+                None => rest
+                    .split_once(' ')
+                    .map_or((vec![rest], ""), |(name, rest)| (vec![name], rest)),
+            };
+            for name in names {
+                let command = match command_line
+                    .commands
+                    .iter_mut()
+                    .position(|command| command.name == name)
+                {
+                    Some(index) => &mut command_line.commands[index],
+                    None => {
+                        command_line.commands.push(Command {
+                            name: name.to_string(),
+                            flags: Vec::new(),
+                            arguments: Vec::new(),
+                        });
+                        command_line.commands.last_mut().unwrap()
+                    }
+                };
+                merge_flags(&mut command.flags, parse_flags(rest));
+                command.arguments.extend(parse_arguments(rest));
+            }
+        }
+        command_line.share_choices();
+        command_line
+    }
+
+    // --example name のように使い方に書けない候補を後から足す
+    pub fn with_choices(mut self, flag: &str, choices: &[&str]) -> Self {
+        let choices: Vec<String> = choices.iter().map(|choice| choice.to_string()).collect();
+        for command in &mut self.commands {
+            for found in command.flags.iter_mut().filter(|found| found.name == flag) {
+                found.choices = choices.clone();
+            }
+        }
+        self
+    }
+
+    pub fn completions(&self, shell: Shell) -> String {
+        match shell {
+            Shell::Bash => self.bash(),
+            // zsh では bash の補完関数をそのまま使う
+            Shell::Zsh => format!(
+                "#compdef {}\nautoload -U +X bashcompinit && bashcompinit\n{}",
+                self.program,
+                self.bash()
+            ),
+            Shell::Fish => self.fish(),
+        }
+    }
+
+    // ある行で --profile td4-strict|td4-book と書いた候補を --profile name の行でも使う
+    fn share_choices(&mut self) {
+        let known: Vec<Flag> = self
+            .commands
+            .iter()
+            .flat_map(|command| command.flags.iter())
+            .filter(|flag| !flag.choices.is_empty())
+            .cloned()
+            .collect();
+        for flag in self
+            .commands
+            .iter_mut()
+            .flat_map(|command| command.flags.iter_mut())
+            .filter(|flag| flag.value.is_some() && flag.choices.is_empty())
+        {
+            if let Some(found) = known.iter().find(|found| found.name == flag.name) {
+                flag.choices = found.choices.clone();
+            }
+        }
+    }
+
+    fn all_flags(&self) -> Vec<&Flag> {
+        let mut flags: Vec<&Flag> = Vec::new();
+        for flag in self
+            .commands
+            .iter()
+            .flat_map(|command| command.flags.iter())
+            .chain(self.global.iter())
+        {
+            if !flags.iter().any(|found| found.name == flag.name) {
+                flags.push(flag);
+            }
+        }
+        flags
+    }
+
+    fn bash(&self) -> String {
+        let function = format!("_{}", self.program.replace('-', "_"));
+        let names = |flags: &[Flag]| {
+            flags
+                .iter()
+                .map(|flag| flag.name.as_str())
+                .chain(self.global.iter().map(|flag| flag.name.as_str()))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        let mut script = format!(
+            "{}() {{\n    local cur prev command\n    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"\n    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"\n    command=\"${{COMP_WORDS[1]}}\"\n\n    case \"$prev\" in\n",
+            function
+        );
+        // 値を取るオプションの直後は値の候補か、候補がなければファイル名を補完する
+        for flag in self.all_flags() {
+            if flag.value.is_none() {
+                continue;
+            }
+            let reply = if !flag.choices.is_empty() {
+                format!("compgen -W \"{}\" -- \"$cur\"", flag.choices.join(" "))
+            } else if flag.takes_file() {
+                "compgen -f -- \"$cur\"".to_string()
+            } else {
+                // 数や周波数は補完しない
+                "true".to_string()
+            };
+            script.push_str(&format!(
+                "        {})\n            COMPREPLY=($({}))\n            return\n            ;;\n",
+                flag.name, reply
+            ));
+        }
+        script.push_str("    esac\n\n    local opts\n    case \"$command\" in\n");
+        for command in &self.commands {
+            let mut words = command.arguments.clone();
+            words.push(names(&command.flags));
+            script.push_str(&format!(
+                "        {})\n            opts=\"{}\"\n            ;;\n",
+                command.name,
+                words.join(" ")
+            ));
+        }
+        // サブコマンドを省いたときは最初のコマンドのオプションとサブコマンドの名前
+        let default = self.commands.first().map(|command| names(&command.flags));
+        let commands: Vec<&str> = self.commands.iter().map(|c| c.name.as_str()).collect();
+        script.push_str(&format!(
+            "        *)\n            opts=\"{} {}\"\n            ;;\n    esac\n",
+            commands.join(" "),
+            default.unwrap_or_default()
+        ));
+        script.push_str(&format!(
+            "    COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))\n    if [[ \"$cur\" != -* ]]; then\n        COMPREPLY+=($(compgen -f -- \"$cur\"))\n    fi\n}}\ncomplete -F {} {}\n",
+            function, self.program
+        ));
+        script
+    }
+
+    fn fish(&self) -> String {
+        let program = &self.program;
+        let commands: Vec<&str> = self.commands.iter().map(|c| c.name.as_str()).collect();
+        let mut script = format!(
+            "complete -c {} -n __fish_use_subcommand -f -a \"{}\"\n",
+            program,
+            commands.join(" ")
+        );
+        let complete = |condition: &str, flag: &Flag| {
+            let mut line = format!(
+                "complete -c {}{} -l {}",
+                program,
+                condition,
+                flag.name.trim_start_matches("--")
+            );
+            if flag.value.is_some() {
+                line.push_str(" -r");
+            }
+            if !flag.choices.is_empty() {
+                line.push_str(&format!(" -f -a \"{}\"", flag.choices.join(" ")));
+            } else if flag.value.is_some() && !flag.takes_file() {
+                line.push_str(" -f");
+            }
+            line.push('\n');
+            line
+        };
+        for flag in &self.global {
+            script.push_str(&complete("", flag));
+        }
+        for (index, command) in self.commands.iter().enumerate() {
+            // 最初のコマンドのオプションはサブコマンドを省いても使える
+            let condition = if index == 0 {
+                format!(
+                    " -n \"__fish_use_subcommand; or __fish_seen_subcommand_from {}\"",
+                    command.name
+                )
+            } else {
+                format!(" -n \"__fish_seen_subcommand_from {}\"", command.name)
+            };
+            if !command.arguments.is_empty() {
+                script.push_str(&format!(
+                    "complete -c {}{} -f -a \"{}\"\n",
+                    program,
+                    condition,
+                    command.arguments.join(" ")
+                ));
+            }
+            for flag in &command.flags {
+                script.push_str(&complete(&condition, flag));
+            }
+        }
+        script
+    }
+
+    // man 1 のページ (roff)
+    pub fn man(&self, description: &str) -> String {
+        let program = self.program.to_uppercase();
+        let mut page = format!(
+            ".TH {} 1\n.SH NAME\n{} \\- {}\n.SH SYNOPSIS\n",
+            program,
+            self.program,
+            roff(description)
+        );
+        for usage in &self.usages {
+            page.push_str(&format!(".B {}\n{}\n.br\n", self.program, roff(usage)));
+        }
+        page.push_str(&format!(
+            ".SH COMMANDS\nWithout a command, {} runs the program like \\fBrun\\fR.\n",
+            self.program
+        ));
+        for command in &self.commands {
+            page.push_str(&format!(".TP\n.B {}\n", roff(&command.name)));
+            let flags: Vec<String> = command.flags.iter().map(man_flag).collect();
+            if flags.is_empty() {
+                page.push_str("No options.\n");
+            } else {
+                page.push_str(&format!("Options: {}\n", flags.join(", ")));
+            }
+        }
+        page.push_str(".SH OPTIONS\nEvery command also takes:\n");
+        for flag in &self.global {
+            page.push_str(&format!(".TP\n{}\n", man_flag(flag)));
+            if !flag.choices.is_empty() {
+                page.push_str(&format!("One of {}.\n", roff(&flag.choices.join(", "))));
+            }
+        }
+        page
+    }
+}
+
+fn man_flag(flag: &Flag) -> String {
+    match &flag.value {
+        Some(value) => format!("\\fB{}\\fR \\fI{}\\fR", roff(&flag.name), roff(value)),
+        None => format!("\\fB{}\\fR", roff(&flag.name)),
+    }
+}
+
+// roff ではハイフンとバックスラッシュをエスケープする
+fn roff(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('-', "\\-")
+}
+
+// 行の中の --name と、その直後に書いた値を取り出す
+fn parse_flags(text: &str) -> Vec<Flag> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let mut flags = Vec::new();
+    for (index, word) in words.iter().enumerate() {
+        let name = word.trim_start_matches('[');
+        if !name.starts_with("--") {
+            continue;
+        }
+        // [--extended] のように閉じていれば値を取らない
+        let closed = name.ends_with(']');
+        let name = name.trim_end_matches(']');
+        let value = match words.get(index + 1) {
+            Some(next) if !closed && !next.starts_with(['[', '|', '-']) => {
+                Some(next.trim_end_matches(']'))
+            }
+            _ => None,
+        };
+        flags.push(Flag {
+            name: name.to_string(),
+            value: value.map(str::to_string),
+            choices: value.and_then(choices).unwrap_or_default(),
+        });
+    }
+    flags
+}
+
+// オプションの値ではない、決まった引数の候補
+fn parse_arguments(text: &str) -> Vec<String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    words
+        .iter()
+        .enumerate()
+        .filter(|(index, word)| {
+            let after_flag =
+                *index > 0 && words[index - 1].trim_start_matches('[').starts_with("--");
+            !after_flag && !word.starts_with(['[', '-'])
+        })
+        .filter_map(|(_, word)| choices(word))
+        .flatten()
+        .collect()
+}
+
+// md|csv のような候補の並び。path|tcp:host:port|- のような説明は候補にしない
+fn choices(word: &str) -> Option<Vec<String>> {
+    let word = word.trim_matches(['[', ']']);
+    let choices: Vec<&str> = word.split('|').collect();
+    let literal = |choice: &&str| {
+        choice.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+            && choice
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    };
+    if choices.len() > 1 && choices.iter().all(literal) {
+        Some(choices.iter().map(|choice| choice.to_string()).collect())
+    } else {
+        None
+    }
+}
+
+fn merge_flags(flags: &mut Vec<Flag>, found: Vec<Flag>) {
+    for flag in found {
+        if !flags.iter().any(|known| known.name == flag.name) {
+            flags.push(flag);
+        }
+    }
+}
+
+#[cfg(test)]
+mod completions_tests {
+    use crate::completions::{CommandLine, Shell};
+
+    const USAGE: &str = "Usage: [command] [run | debug] [--profile td4-strict|td4-book] [--led | --out-format led|hex] [--trace file [--trace-last n]] [--in-stream path|tcp:host:port|-] [file_path | --example name]
+       [command] table [--profile name] [--annotate] [file_path | --example name]
+       [command] completions bash|zsh|fish
+       every command also takes [--color auto|always|never]";
+
+    #[test]
+    fn test_parse() {
+        let command_line = CommandLine::parse("td4emu", USAGE);
+        let names: Vec<&str> = command_line
+            .commands
+            .iter()
+            .map(|command| command.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["run", "debug", "table", "completions"]);
+
+        let run = &command_line.commands[0];
+        let flags: Vec<(&str, Option<&str>)> = run
+            .flags
+            .iter()
+            .map(|flag| (flag.name.as_str(), flag.value.as_deref()))
+            .collect();
+        assert_eq!(
+            flags,
+            vec![
+                ("--profile", Some("td4-strict|td4-book")),
+                ("--led", None),
+                ("--out-format", Some("led|hex")),
+                ("--trace", Some("file")),
+                ("--trace-last", Some("n")),
+                ("--in-stream", Some("path|tcp:host:port|-")),
+                ("--example", Some("name")),
+            ]
+        );
+        assert_eq!(run.flags[2].choices, vec!["led", "hex"]);
+        assert!(run.flags[5].choices.is_empty());
+
+        // table の --profile name にも候補を使う
+        let table = &command_line.commands[2];
+        assert_eq!(table.flags[0].choices, vec!["td4-strict", "td4-book"]);
+        assert_eq!(table.flags[1].value, None);
+        assert_eq!(
+            command_line.commands[3].arguments,
+            vec!["bash", "zsh", "fish"]
+        );
+        assert_eq!(
+            command_line.global[0].choices,
+            vec!["auto", "always", "never"]
+        );
+    }
+
+    #[test]
+    fn test_completions() {
+        let command_line =
+            CommandLine::parse("td4emu", USAGE).with_choices("--example", &["adder", "counter"]);
+        let bash = command_line.completions(Shell::Bash);
+        assert!(bash
+            .contains("--profile)\n            COMPREPLY=($(compgen -W \"td4-strict td4-book\""));
+        assert!(bash.contains("--example)\n            COMPREPLY=($(compgen -W \"adder counter\""));
+        assert!(bash.contains("--trace)\n            COMPREPLY=($(compgen -f"));
+        assert!(bash.contains("--trace-last)\n            COMPREPLY=($(true))"));
+        assert!(bash.contains("opts=\"bash zsh fish --color\""));
+        assert!(bash.ends_with("complete -F _td4emu td4emu\n"));
+
+        let zsh = command_line.completions(Shell::Zsh);
+        assert!(zsh.starts_with("#compdef td4emu\n"));
+
+        let fish = command_line.completions(Shell::Fish);
+        assert!(fish.contains("-n __fish_use_subcommand -f -a \"run debug table completions\""));
+        assert!(fish.contains(
+            "-n \"__fish_seen_subcommand_from table\" -l profile -r -f -a \"td4-strict td4-book\""
+        ));
+
+        let man = command_line.man("TD4 emulator");
+        assert!(man.starts_with(".TH TD4EMU 1\n.SH NAME\ntd4emu \\- TD4 emulator\n"));
+        assert_eq!(man.matches(".B td4emu\n").count(), 3);
+        assert!(man.contains(".B table\nOptions: \\fB\\-\\-profile\\fR \\fIname\\fR"));
+    }
+}
@@ -0,0 +1,265 @@
+use crate::debugger::parse_number;
+use crate::emulator::CpuEmulator;
+use crate::error::EmulatorErr;
+
+// デバッガのブレークポイントに付ける条件式
+// `A == 3`, `out == 0b1111 && carry`, `pc >= 4 || B != 0` のようにフィールドと数値を比べる
+// 比較演算子のないフィールドは0でなければ真とする。&& は || より先に結びつく
+#[derive(Debug, PartialEq, Clone)]
+pub struct Condition {
+    text: String,
+    expr: Expr,
+}
+
+// 条件式から参照できるマシンの状態
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Field {
+    A,
+    B,
+    Carry,
+    Pc,
+    Input,
+    Output,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Operand {
+    Field(Field),
+    Value(u8),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Expr {
+    Compare(Operand, &'static str, Operand),
+    Truthy(Operand),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+// 長いものから順に照合する。先頭の6つが比較演算子
+const OPERATORS: [&str; 8] = ["==", "!=", "<=", ">=", "<", ">", "&&", "||"];
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Self, EmulatorErr> {
+        let tokens = tokenize(text)?;
+        let mut parser = ConditionParser { tokens, pos: 0 };
+        let expr = parser.or()?;
+
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(EmulatorErr::new(&format!(
+                "Unexpected {} in condition: {}",
+                token, text
+            )));
+        }
+        Ok(Condition {
+            text: text.trim().to_string(),
+            expr,
+        })
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn evaluate(&self, emulator: &CpuEmulator) -> bool {
+        self.evaluate_with(&|field| field.read(emulator))
+    }
+
+    // エミュレータ以外の状態 (isa::ArchState など) で評価するときはフィールドの読み方を渡す
+    pub fn evaluate_with(&self, read: &dyn Fn(Field) -> u8) -> bool {
+        evaluate(&self.expr, read)
+    }
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "A" => Some(Field::A),
+            "B" => Some(Field::B),
+            "C" | "CARRY" => Some(Field::Carry),
+            "PC" => Some(Field::Pc),
+            "IN" | "INPUT" => Some(Field::Input),
+            "OUT" | "OUTPUT" => Some(Field::Output),
+            _ => None,
+        }
+    }
+
+    pub fn read(&self, emulator: &CpuEmulator) -> u8 {
+        let register = emulator.register();
+        match self {
+            Field::A => register.register_a(),
+            Field::B => register.register_b(),
+            Field::Carry => register.carry_flag(),
+            Field::Pc => register.pc(),
+            Field::Input => emulator.input(),
+            Field::Output => emulator.output(),
+        }
+    }
+}
+
+fn evaluate(expr: &Expr, read: &dyn Fn(Field) -> u8) -> bool {
+    let value = |operand: &Operand| match operand {
+        Operand::Field(field) => read(*field),
+        Operand::Value(value) => *value,
+    };
+
+    match expr {
+        Expr::Compare(left, op, right) => {
+            let (left, right) = (value(left), value(right));
+            match *op {
+                "==" => left == right,
+                "!=" => left != right,
+                "<" => left < right,
+                "<=" => left <= right,
+                ">" => left > right,
+                ">=" => left >= right,
+                _ => unreachable!("only comparisons are parsed into Compare"),
+            }
+        }
+        Expr::Truthy(operand) => value(operand) != 0,
+        Expr::And(left, right) => evaluate(left, read) && evaluate(right, read),
+        Expr::Or(left, right) => evaluate(left, read) || evaluate(right, read),
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, EmulatorErr> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+        } else if c.is_ascii_alphanumeric() {
+            let start = pos;
+            while pos < chars.len() && chars[pos].is_ascii_alphanumeric() {
+                pos += 1;
+            }
+            tokens.push(chars[start..pos].iter().collect());
+        } else {
+            let rest: String = chars[pos..].iter().collect();
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(op.to_string());
+                    pos += op.len();
+                }
+                None => {
+                    return Err(EmulatorErr::new(&format!(
+                        "Unexpected character '{}' in condition: {}",
+                        c, text
+                    )))
+                }
+            }
+        }
+    }
+
+    if tokens.is_empty() {
+        return Err(EmulatorErr::new("Condition is empty"));
+    }
+    Ok(tokens)
+}
+
+struct ConditionParser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl ConditionParser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|token| token.as_str())
+    }
+
+    fn or(&mut self) -> Result<Expr, EmulatorErr> {
+        let mut expr = self.and()?;
+        while self.peek() == Some("||") {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, EmulatorErr> {
+        let mut expr = self.comparison()?;
+        while self.peek() == Some("&&") {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.comparison()?));
+        }
+        Ok(expr)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, EmulatorErr> {
+        let left = self.operand()?;
+        let op = match self.peek() {
+            Some(op) => OPERATORS[..6]
+                .iter()
+                .find(|candidate| **candidate == op)
+                .copied(),
+            None => None,
+        };
+
+        match op {
+            Some(op) => {
+                self.pos += 1;
+                Ok(Expr::Compare(left, op, self.operand()?))
+            }
+            None => Ok(Expr::Truthy(left)),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, EmulatorErr> {
+        let token = self
+            .peek()
+            .ok_or_else(|| EmulatorErr::new("Condition ends unexpectedly"))?
+            .to_string();
+        self.pos += 1;
+
+        if let Some(field) = Field::from_name(&token) {
+            return Ok(Operand::Field(field));
+        }
+        if token.starts_with(|c: char| c.is_ascii_digit()) {
+            return parse_number(&token).map(Operand::Value);
+        }
+        Err(EmulatorErr::new(&format!(
+            "Unknown field in condition: {}",
+            token
+        )))
+    }
+}
+
+#[cfg(test)]
+mod condition_tests {
+    use crate::condition::Condition;
+    use crate::emulator::CpuEmulator;
+    use crate::port::Ports;
+    use crate::register::Register;
+    use crate::rom::Rom;
+
+    #[test]
+    fn test_evaluate() {
+        let mut register = Register::new();
+        register.set_register_a(3);
+        register.set_carry_flag(1);
+        let emu = CpuEmulator::with(register, Ports::new(0b0101, 0b1111), Rom::new(vec![]));
+
+        let holds = |text: &str| Condition::parse(text).unwrap().evaluate(&emu);
+        assert!(holds("A == 3"));
+        assert!(holds("a==0b0011"));
+        assert!(!holds("B != 0"));
+        assert!(holds("carry"));
+        assert!(!holds("b"));
+        assert!(holds("out == 0b1111 && in >= 5"));
+        assert!(holds("pc > 0 || A < 4"));
+        assert!(!holds("pc > 0 || A < 4 && B == 1"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Condition::parse("").is_err());
+        assert!(Condition::parse("X == 1").is_err());
+        assert!(Condition::parse("A ==").is_err());
+        assert!(Condition::parse("A = 1").is_err());
+        assert!(Condition::parse("A == 1 B").is_err());
+        assert_eq!(Condition::parse(" carry ").unwrap().text(), "carry");
+    }
+}
@@ -0,0 +1,143 @@
+use crate::error::EmulatorErr;
+use crate::grader::parse_integer;
+use crate::op::Opcode;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+// 命令ごとのコスト (エネルギー)。命令数とは別の基準で最適化の課題を出すのに使う
+// 実行した命令のコストの合計は ExecStats の energy に入る
+pub trait CostModel: Send {
+    fn cost(&self, opcode: &Opcode) -> u64;
+
+    // td4-book で何もせずに進む未定義のopcode
+    fn undefined_cost(&self) -> u64 {
+        1
+    }
+}
+
+// どの命令もコスト1 (energy は命令数と同じになる)
+pub struct UniformCost;
+
+impl CostModel for UniformCost {
+    fn cost(&self, _opcode: &Opcode) -> u64 {
+        1
+    }
+}
+
+// 命令ごとにコストを指定する。TOMLのうち key = 整数 の行だけを読む
+//
+//     # 書いていない命令と未定義のopcodeのコスト
+//     default = 1
+//     out_b = 4
+//     jmp = 2
+//
+// 命令の名前は Opcode の名前を小文字にして _ で区切ったもの (add_a, mov_a2b, out_im など)
+#[derive(Debug, PartialEq, Clone)]
+pub struct TableCost {
+    default: u64,
+    table: HashMap<Opcode, u64>,
+}
+
+impl TableCost {
+    pub fn new(default: u64) -> Self {
+        Self {
+            default,
+            table: HashMap::new(),
+        }
+    }
+
+    pub fn with(mut self, opcode: Opcode, cost: u64) -> Self {
+        self.table.insert(opcode, cost);
+        self
+    }
+}
+
+impl CostModel for TableCost {
+    fn cost(&self, opcode: &Opcode) -> u64 {
+        *self.table.get(opcode).unwrap_or(&self.default)
+    }
+
+    fn undefined_cost(&self) -> u64 {
+        self.default
+    }
+}
+
+impl FromStr for TableCost {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut cost = TableCost::new(1);
+        for (number, line) in s.lines().enumerate() {
+            let error =
+                |message: &str| EmulatorErr::new(&format!("cost line {}: {}", number + 1, message));
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error(&format!("can't read {}", line)))?;
+            let key = key.trim();
+            let value = parse_integer(value.trim()).ok_or_else(|| error("invalid value"))?;
+            match key {
+                "default" => cost.default = value,
+                _ => {
+                    let opcode = Opcode::ALL
+                        .iter()
+                        .find(|opcode| name(opcode) == key)
+                        .ok_or_else(|| error(&format!("unknown instruction {}", key)))?;
+                    cost.table.insert(*opcode, value);
+                }
+            }
+        }
+        Ok(cost)
+    }
+}
+
+// MovA2B -> mov_a2b
+fn name(opcode: &Opcode) -> String {
+    let mut name = String::new();
+    for c in format!("{:?}", opcode).chars() {
+        if c.is_ascii_uppercase() && name.ends_with(|c: char| c.is_ascii_lowercase()) {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    name
+}
+
+#[cfg(test)]
+mod cost_tests {
+    use crate::cost::{CostModel, TableCost, UniformCost};
+    use crate::op::Opcode;
+
+    #[test]
+    fn test_parse() {
+        let cost: TableCost = "
+# 出力はLEDを光らせるので高い
+default = 2
+out_b = 0x10
+mov_a2b = 1
+"
+        .parse()
+        .unwrap();
+        assert_eq!(
+            cost,
+            TableCost::new(2)
+                .with(Opcode::OutB, 16)
+                .with(Opcode::MovA2B, 1)
+        );
+        assert_eq!(cost.cost(&Opcode::OutB), 16);
+        assert_eq!(cost.cost(&Opcode::Jmp), 2);
+        assert_eq!(cost.undefined_cost(), 2);
+        assert_eq!(UniformCost.cost(&Opcode::OutB), 1);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = "default = 1\nout = 3".parse::<TableCost>().unwrap_err();
+        assert_eq!(err.to_string(), "cost line 2: unknown instruction out");
+        assert!("jmp".parse::<TableCost>().is_err());
+        assert!("jmp = -1".parse::<TableCost>().is_err());
+    }
+}
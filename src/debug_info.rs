@@ -0,0 +1,41 @@
+// アセンブル時に分かるROMの各アドレスの情報
+// 逆アセンブル時に命令とデータを区別するのに使う
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Region {
+    Code,
+    Data,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct DebugInfo {
+    regions: Vec<Region>,
+}
+
+impl DebugInfo {
+    pub fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+        }
+    }
+
+    // 次のアドレスの種類を記録する
+    pub fn push(&mut self, region: Region) {
+        self.regions.push(region);
+    }
+
+    pub fn region(&self, address: u8) -> Option<Region> {
+        self.regions.get(address as usize).copied()
+    }
+
+    pub fn is_data(&self, address: u8) -> bool {
+        self.region(address) == Some(Region::Data)
+    }
+
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+}
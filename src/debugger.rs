@@ -0,0 +1,836 @@
+use crate::condition::Condition;
+use crate::emulator::{CpuEmulator, PokeTarget};
+use crate::error::EmulatorErr;
+use crate::json::Json;
+use crate::port::Ports;
+use crate::register::Register;
+use crate::session::DebugSession;
+use crate::stack::Stack;
+use crate::style::Style;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+// デバッガの操作で発生したイベント
+#[derive(Debug, PartialEq)]
+pub enum DebugEvent {
+    Poked {
+        target: PokeTarget,
+        old: u8,
+        new: u8,
+    },
+    BreakpointHit(u8),
+    // break on で指定した条件が成り立った
+    ConditionHit(String),
+    Halted,
+}
+
+// ある時点のマシン全体の状態
+// テキストでは1行に決まった順で書く。セッション、diff、verify-trace、web UI で同じ形を使う
+//
+//     pc=0x3 a=0b0101 b=0b0000 c=0 in=0b0011 out=0b1000 stack=02,07 shadow=0x1 cycles=42 rom=31,01
+#[derive(Debug, PartialEq, Clone)]
+pub struct Snapshot {
+    pub register: Register,
+    pub input: u8,
+    pub output: u8,
+    pub rom: Vec<u8>,
+    pub stack: Vec<u8>,
+    pub shadow_pc: u8,
+    pub cycles: usize,
+}
+
+// スナップショットから値が変わったフィールド
+#[derive(Debug, PartialEq, Clone)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: String,
+    pub after: String,
+}
+
+impl Snapshot {
+    pub fn take(emulator: &CpuEmulator) -> Self {
+        Snapshot {
+            register: emulator.register(),
+            input: emulator.input(),
+            output: emulator.output(),
+            rom: emulator.rom(),
+            stack: emulator.stack().entries().to_vec(),
+            shadow_pc: emulator.shadow_pc(),
+            cycles: emulator.cycles(),
+        }
+    }
+
+    // state は Display の1行。数値はほかのフィールドで読める
+    pub fn to_json(&self) -> Json {
+        Json::object([
+            ("pc", self.register.pc().into()),
+            ("a", self.register.register_a().into()),
+            ("b", self.register.register_b().into()),
+            ("carry", self.register.carry_flag().into()),
+            ("input", self.input.into()),
+            ("output", self.output.into()),
+            ("stack", Json::array(self.stack.iter().copied())),
+            ("shadow_pc", self.shadow_pc.into()),
+            ("cycles", self.cycles.into()),
+            ("rom", Json::array(self.rom.iter().copied())),
+            ("state", self.to_string().into()),
+        ])
+    }
+
+    // エミュレータをこの状態に戻す (ROMはそのまま)
+    pub fn restore(&self, emulator: &CpuEmulator) -> Result<(), EmulatorErr> {
+        let mut stack = Stack::new();
+        for address in &self.stack {
+            stack.push(*address)?;
+        }
+        emulator.restore(
+            self.register.clone(),
+            Ports::new(self.input, self.output),
+            stack,
+            self.shadow_pc,
+            self.cycles,
+        );
+        Ok(())
+    }
+
+    // 1行の形の項目名と値。ROMは1バイトずつ別の項目にする
+    pub fn fields(&self) -> Vec<(String, String)> {
+        let nibble = |value: u8| format!("0b{:04b}", value);
+        let address = |value: u8| format!("0x{:x}", value);
+        let mut fields = vec![
+            ("pc".to_string(), address(self.register.pc())),
+            ("a".to_string(), nibble(self.register.register_a())),
+            ("b".to_string(), nibble(self.register.register_b())),
+            ("c".to_string(), self.register.carry_flag().to_string()),
+            ("in".to_string(), nibble(self.input)),
+            ("out".to_string(), nibble(self.output)),
+            ("stack".to_string(), hex_list(&self.stack)),
+            ("shadow".to_string(), address(self.shadow_pc)),
+            ("cycles".to_string(), self.cycles.to_string()),
+        ];
+        for (i, byte) in self.rom.iter().enumerate() {
+            fields.push((format!("rom[0x{:x}]", i), format!("{:02x}", byte)));
+        }
+        fields
+    }
+
+    // selfからafterへの変化を返す
+    pub fn diff(&self, after: &Snapshot) -> Vec<FieldChange> {
+        self.fields()
+            .into_iter()
+            .zip(after.fields())
+            .filter(|((_, before), (_, after))| before != after)
+            .map(|((field, before), (_, after))| FieldChange {
+                field,
+                before,
+                after,
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields: Vec<String> = self
+            .fields()
+            .into_iter()
+            .take_while(|(name, _)| !name.starts_with("rom"))
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        write!(f, "{} rom={}", fields.join(" "), hex_list(&self.rom))
+    }
+}
+
+// 書いていない項目は初期値にする
+impl FromStr for Snapshot {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut snapshot = Snapshot {
+            register: Register::new(),
+            input: 0,
+            output: 0,
+            rom: Vec::new(),
+            stack: Vec::new(),
+            shadow_pc: 0,
+            cycles: 0,
+        };
+        for field in s.split_whitespace() {
+            let (name, value) = field
+                .split_once('=')
+                .ok_or_else(|| EmulatorErr::new(&format!("invalid field {}", field)))?;
+            let invalid = || EmulatorErr::new(&format!("invalid value for {}: {}", name, value));
+            match name {
+                "pc" => snapshot.register.set_pc(parse_number(value)?),
+                "a" => snapshot.register.set_register_a(parse_number(value)?),
+                "b" => snapshot.register.set_register_b(parse_number(value)?),
+                "c" => snapshot.register.set_carry_flag(parse_number(value)?),
+                "in" => snapshot.input = parse_number(value)?,
+                "out" => snapshot.output = parse_number(value)?,
+                "stack" => snapshot.stack = parse_hex_list(value).ok_or_else(invalid)?,
+                "shadow" => snapshot.shadow_pc = parse_number(value)?,
+                "cycles" => snapshot.cycles = value.parse().map_err(|_| invalid())?,
+                "rom" => snapshot.rom = parse_hex_list(value).ok_or_else(invalid)?,
+                _ => return Err(EmulatorErr::new(&format!("unknown field {}", name))),
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+// 02,07 のようにカンマで区切った16進数のバイト
+fn hex_list(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn parse_hex_list(text: &str) -> Option<Vec<u8>> {
+    text.split(',')
+        .filter(|byte| !byte.is_empty())
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect()
+}
+
+// until, next-out のためにその実行の間だけ置く一時的な停止条件
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum TemporaryBreak {
+    // ユーザーのブレークポイントを無視してこのアドレスまで実行する
+    Address(u8),
+    // 次のOUT命令を実行するまで実行する
+    NextOut,
+}
+
+pub struct Debugger {
+    emulator: CpuEmulator,
+    // アドレスごとのブレークポイントと、止まるための条件 (なければ毎回止まる)
+    breakpoints: BTreeMap<u8, Option<Condition>>,
+    // アドレスに関係なく成り立った瞬間に止まる条件
+    watches: Vec<Condition>,
+    events: Vec<DebugEvent>,
+    snapshots: BTreeMap<String, Snapshot>,
+    style: Style,
+    // 前回表示した PC, A, B, C, IN, OUT (変わった値を目立たせる)
+    shown: Option<[u8; 6]>,
+    // 読み込んだプログラムのファイル (セッションに保存する)
+    program: Option<String>,
+}
+
+impl Debugger {
+    pub fn new(emulator: CpuEmulator) -> Self {
+        Self {
+            emulator,
+            breakpoints: BTreeMap::new(),
+            watches: Vec::new(),
+            events: Vec::new(),
+            snapshots: BTreeMap::new(),
+            style: Style::default(),
+            shown: None,
+            program: None,
+        }
+    }
+
+    pub fn with_program(mut self, path: &str) -> Self {
+        self.program = Some(path.to_string());
+        self
+    }
+
+    // 端末に表示するときの色付け
+    pub fn with_style(mut self, style: Style) -> Self {
+        self.style = style;
+        self
+    }
+
+    pub fn emulator(&self) -> &CpuEmulator {
+        &self.emulator
+    }
+
+    pub fn events(&self) -> &[DebugEvent] {
+        &self.events
+    }
+
+    pub fn snapshot(&self, name: &str) -> Option<&Snapshot> {
+        self.snapshots.get(name)
+    }
+
+    // 今のプログラム、状態、ブレークポイントとスナップショット
+    pub fn session(&self) -> DebugSession {
+        DebugSession {
+            program: self.program.clone(),
+            rom: self.emulator.rom(),
+            state: Snapshot::take(&self.emulator),
+            breakpoints: self
+                .breakpoints()
+                .lines()
+                .map(|breakpoint| format!("break {}", breakpoint))
+                .collect(),
+            snapshots: self
+                .snapshots
+                .iter()
+                .map(|(name, snapshot)| (name.clone(), snapshot.clone()))
+                .collect(),
+        }
+    }
+
+    // ブレークポイントとスナップショットを戻す
+    // プログラムが保存したときと同じならマシンの状態も戻し、変わっていればリセットする
+    pub fn restore_session(&mut self, session: &DebugSession) -> Result<String, EmulatorErr> {
+        self.breakpoints.clear();
+        self.watches.clear();
+        for breakpoint in &session.breakpoints {
+            self.execute(breakpoint)?;
+        }
+        self.snapshots = session.snapshots.iter().cloned().collect();
+        let message = if session.rom == self.emulator.rom() {
+            session.state.restore(&self.emulator)?;
+            format!("Restored the session at cycle {}", session.state.cycles)
+        } else {
+            self.emulator.reset();
+            "The program has changed since the session was saved. \
+             Restored breakpoints and snapshots and reset the machine"
+                .to_string()
+        };
+        self.shown = None;
+        Ok(format!("{}\n{}", message, self.state()))
+    }
+
+    // 1行分のコマンドを実行し、表示するメッセージを返す
+    pub fn execute(&mut self, command: &str) -> Result<String, EmulatorErr> {
+        let words: Vec<&str> = command.split_whitespace().collect();
+
+        match words.as_slice() {
+            [] => Ok(String::new()),
+            ["step" | "s"] => self.step(1),
+            ["step" | "s", count] => self.step(parse_number(count)? as usize),
+            ["continue" | "c"] => self.cont(None),
+            ["until" | "u", address] => {
+                let address = parse_address(address)?;
+                self.cont(Some(TemporaryBreak::Address(address)))
+            }
+            ["next-out"] => self.cont(Some(TemporaryBreak::NextOut)),
+            ["break" | "b", "on", condition @ ..] if !condition.is_empty() => {
+                let condition = Condition::parse(&condition.join(" "))?;
+                let message = format!("Break on {}", condition.text());
+                self.watches.push(condition);
+                Ok(message)
+            }
+            ["break" | "b", address] => {
+                let address = parse_address(address)?;
+                self.breakpoints.insert(address, None);
+                Ok(format!("Breakpoint at 0x{:x}", address))
+            }
+            ["break" | "b", address, "if", condition @ ..] if !condition.is_empty() => {
+                let address = parse_address(address)?;
+                let condition = Condition::parse(&condition.join(" "))?;
+                let message = format!("Breakpoint at 0x{:x} if {}", address, condition.text());
+                self.breakpoints.insert(address, Some(condition));
+                Ok(message)
+            }
+            ["breakpoints"] => Ok(self.breakpoints()),
+            ["delete" | "d", "on", condition @ ..] if !condition.is_empty() => {
+                let text = condition.join(" ");
+                match self.watches.iter().position(|watch| watch.text() == text) {
+                    Some(index) => {
+                        self.watches.remove(index);
+                        Ok(format!("Deleted break on {}", text))
+                    }
+                    None => Err(EmulatorErr::new(&format!("No break on {}", text))),
+                }
+            }
+            ["delete" | "d", address] => {
+                let address = parse_address(address)?;
+                if self.breakpoints.remove(&address).is_some() {
+                    Ok(format!("Deleted breakpoint at 0x{:x}", address))
+                } else {
+                    Err(EmulatorErr::new(&format!(
+                        "No breakpoint at 0x{:x}",
+                        address
+                    )))
+                }
+            }
+            ["regs" | "info" | "i"] => Ok(self.state()),
+            ["set", name, value] => {
+                let target = match name.to_uppercase().as_str() {
+                    "A" => PokeTarget::RegisterA,
+                    "B" => PokeTarget::RegisterB,
+                    "C" | "CARRY" => PokeTarget::CarryFlag,
+                    "PC" => PokeTarget::Pc,
+                    "IN" | "INPUT" => PokeTarget::Input,
+                    _ => return Err(EmulatorErr::new(&format!("Unknown register: {}", name))),
+                };
+                self.poke(target, parse_number(value)?)
+            }
+            ["poke", address, value] => {
+                let target = PokeTarget::Rom(parse_address(address)?);
+                self.poke(target, parse_number(value)?)
+            }
+            ["snapshot" | "snap", name] => {
+                self.snapshots
+                    .insert(name.to_string(), Snapshot::take(&self.emulator));
+                Ok(format!("Saved snapshot {}", name))
+            }
+            ["snapshots"] => Ok(self
+                .snapshots
+                .keys()
+                .cloned()
+                .collect::<Vec<String>>()
+                .join("\n")),
+            ["diff", name] => self.diff(name),
+            ["save-session", path] => {
+                std::fs::write(path, self.session().to_string())
+                    .map_err(|err| EmulatorErr::new(&format!("can't write {}: {}", path, err)))?;
+                Ok(format!("Saved the session to {}", path))
+            }
+            ["load-session", path] => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|err| EmulatorErr::new(&format!("can't read {}: {}", path, err)))?;
+                self.restore_session(&text.parse()?)
+            }
+            ["reset"] => {
+                self.emulator.reset();
+                Ok(self.state())
+            }
+            ["help" | "h"] => Ok(HELP.to_string()),
+            _ => Err(EmulatorErr::new(&format!(
+                "Unknown command: {}. Type `help` for usage",
+                command.trim()
+            ))),
+        }
+    }
+
+    fn step(&mut self, count: usize) -> Result<String, EmulatorErr> {
+        for _ in 0..count {
+            if self.emulator.does_halt() {
+                break;
+            }
+            self.emulator.step()?;
+        }
+        self.check_halt();
+        Ok(self.state())
+    }
+
+    fn cont(&mut self, temporary: Option<TemporaryBreak>) -> Result<String, EmulatorErr> {
+        // until はユーザーのブレークポイントと条件で止まらない
+        let user_breaks = !matches!(temporary, Some(TemporaryBreak::Address(_)));
+
+        // 停止中のブレークポイントで止まり続けないように最初の1命令は必ず実行する
+        let mut first = true;
+        while !self.emulator.does_halt() {
+            let pc = self.emulator.register().pc();
+            if !first && temporary == Some(TemporaryBreak::Address(pc)) {
+                return Ok(self.state());
+            }
+            if !first && user_breaks && self.breaks_at(pc) {
+                self.events.push(DebugEvent::BreakpointHit(pc));
+                return Ok(format!("Breakpoint at 0x{:x}\n{}", pc, self.state()));
+            }
+
+            // 成り立ったままの条件で止まり続けないように、偽から真に変わったときだけ止まる
+            let before: Vec<bool> = self
+                .watches
+                .iter()
+                .map(|watch| watch.evaluate(&self.emulator))
+                .collect();
+            let outputs = self.emulator.output_count();
+            self.emulator.step()?;
+            first = false;
+
+            if temporary == Some(TemporaryBreak::NextOut) && self.emulator.output_count() > outputs
+            {
+                return Ok(self.state());
+            }
+            if !user_breaks {
+                continue;
+            }
+            let hit = self
+                .watches
+                .iter()
+                .zip(before)
+                .find(|(watch, held)| !held && watch.evaluate(&self.emulator))
+                .map(|(watch, _)| watch.text().to_string());
+            if let Some(text) = hit {
+                let message = format!("Condition {} holds\n{}", text, self.state());
+                self.events.push(DebugEvent::ConditionHit(text));
+                return Ok(message);
+            }
+        }
+        self.check_halt();
+        Ok(self.state())
+    }
+
+    fn breaks_at(&self, pc: u8) -> bool {
+        match self.breakpoints.get(&pc) {
+            Some(Some(condition)) => condition.evaluate(&self.emulator),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    fn breakpoints(&self) -> String {
+        let addresses = self
+            .breakpoints
+            .iter()
+            .map(|(address, condition)| match condition {
+                Some(condition) => format!("0x{:x} if {}", address, condition.text()),
+                None => format!("0x{:x}", address),
+            });
+        let watches = self
+            .watches
+            .iter()
+            .map(|watch| format!("on {}", watch.text()));
+        addresses.chain(watches).collect::<Vec<String>>().join("\n")
+    }
+
+    fn poke(&mut self, target: PokeTarget, value: u8) -> Result<String, EmulatorErr> {
+        let old = self.emulator.poke(target, value)?;
+        self.events.push(DebugEvent::Poked {
+            target,
+            old,
+            new: value,
+        });
+        Ok(format!("{:?}: {} -> {}", target, old, value))
+    }
+
+    fn diff(&self, name: &str) -> Result<String, EmulatorErr> {
+        let snapshot = self
+            .snapshots
+            .get(name)
+            .ok_or_else(|| EmulatorErr::new(&format!("No snapshot named {}", name)))?;
+        let changes = snapshot.diff(&Snapshot::take(&self.emulator));
+        if changes.is_empty() {
+            return Ok(format!("No changes since {}", name));
+        }
+        Ok(changes
+            .iter()
+            .map(|change| format!("{}: {} -> {}", change.field, change.before, change.after))
+            .collect::<Vec<String>>()
+            .join("\n"))
+    }
+
+    fn check_halt(&mut self) {
+        if self.emulator.does_halt() {
+            self.events.push(DebugEvent::Halted);
+        }
+    }
+
+    fn state(&mut self) -> String {
+        let register = self.emulator.register();
+        let halted = if self.emulator.does_halt() {
+            " (halted)"
+        } else {
+            ""
+        };
+        let values = [
+            register.pc(),
+            register.register_a(),
+            register.register_b(),
+            register.carry_flag(),
+            self.emulator.input(),
+            self.emulator.output(),
+        ];
+        let shown = self.shown.replace(values);
+        let changed = |index: usize| shown.is_some_and(|shown| shown[index] != values[index]);
+        let style = self.style;
+        format!(
+            "PC: {} A: {} B: {} C: {} IN: {} OUT: {}{}",
+            style.changed(&format!("0x{:x}", values[0]), changed(0)),
+            style.changed(&format!("0b{:04b}", values[1]), changed(1)),
+            style.changed(&format!("0b{:04b}", values[2]), changed(2)),
+            style.changed(&values[3].to_string(), changed(3)),
+            style.changed(&format!("0b{:04b}", values[4]), changed(4)),
+            style.changed(&format!("0b{:04b}", values[5]), changed(5)),
+            halted
+        )
+    }
+}
+
+const HELP: &str = "step [n] | s       execute n instructions (default 1)
+continue | c       run until a breakpoint or halt
+break <addr> | b   set a breakpoint
+break <addr> if <condition>
+                   stop at <addr> only when the condition holds (e.g. A == 3)
+break on <condition>
+                   stop when the condition becomes true (e.g. out == 0b1111, carry)
+until <addr> | u   run until PC reaches <addr>, ignoring breakpoints
+next-out           run until the next OUT instruction executes
+breakpoints        list breakpoints
+delete <addr> | d  delete a breakpoint
+delete on <condition>
+                   delete a break on condition
+regs | info | i    show registers and ports
+set <reg> <value>  set A, B, C, PC or IN
+poke <addr> <byte> overwrite a ROM byte
+snapshot <name>    save the machine state as <name>
+snapshots          list saved snapshots
+diff <name>        show what changed since snapshot <name>
+reset              reset registers and ports
+save-session <file>
+                   save breakpoints, snapshots and the machine state
+load-session <file>
+                   restore a saved session
+quit | q           exit the debugger";
+
+// 0x, 0b で始まる16進数, 2進数と10進数を受け付ける
+pub fn parse_number(text: &str) -> Result<u8, EmulatorErr> {
+    let parsed = if let Some(hex) = text.strip_prefix("0x") {
+        u8::from_str_radix(hex, 16)
+    } else if let Some(bin) = text.strip_prefix("0b") {
+        u8::from_str_radix(bin, 2)
+    } else {
+        text.parse::<u8>()
+    };
+    parsed.map_err(|_| EmulatorErr::new(&format!("Failed to parse number: {}", text)))
+}
+
+fn parse_address(text: &str) -> Result<u8, EmulatorErr> {
+    let address = parse_number(text)?;
+    if address > 0x0f {
+        return Err(EmulatorErr::new(&format!(
+            "Address {} is out of range",
+            text
+        )));
+    }
+    Ok(address)
+}
+
+#[cfg(test)]
+mod debugger_tests {
+    use crate::debugger::{parse_number, DebugEvent, Debugger, Snapshot};
+    use crate::emulator::{CpuEmulator, PokeTarget};
+    use crate::port::Ports;
+    use crate::register::Register;
+    use crate::rom::Rom;
+    use crate::style::Style;
+
+    fn debugger(program: Vec<u8>) -> Debugger {
+        let emu = CpuEmulator::with(
+            Register::new(),
+            Ports::new(0b0000, 0b0000),
+            Rom::new(program),
+        );
+        Debugger::new(emu)
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("5").unwrap(), 5);
+        assert_eq!(parse_number("0x3").unwrap(), 3);
+        assert_eq!(parse_number("0b10110001").unwrap(), 0b10110001);
+        assert!(parse_number("A").is_err());
+    }
+
+    #[test]
+    fn test_set_register() {
+        let mut dbg = debugger(vec![0b01000000]);
+        assert!(dbg.execute("set A 5").is_ok());
+        assert!(dbg.execute("set A 16").is_err());
+        assert!(dbg.execute("set X 1").is_err());
+        assert!(dbg.execute("step").is_ok());
+
+        assert_eq!(dbg.emulator().register().register_b(), 5);
+        assert_eq!(
+            dbg.events()[0],
+            DebugEvent::Poked {
+                target: PokeTarget::RegisterA,
+                old: 0,
+                new: 5
+            }
+        );
+    }
+
+    #[test]
+    fn test_poke_rom() {
+        let mut dbg = debugger(vec![0b00110001, 0b10010000]);
+        assert!(dbg.execute("poke 0x1 0b10110001").is_ok());
+        assert!(dbg.execute("poke 0x2 0b10110001").is_err());
+        assert!(dbg.execute("continue").is_ok());
+
+        assert_eq!(dbg.emulator().output(), 0b0001);
+        assert_eq!(dbg.events().last(), Some(&DebugEvent::Halted));
+    }
+
+    #[test]
+    fn test_highlight_changes() {
+        let emu = CpuEmulator::with(
+            Register::new(),
+            Ports::new(0b0000, 0b0000),
+            Rom::new(vec![0b00000001, 0b01010000]),
+        );
+        let mut dbg = Debugger::new(emu).with_style(Style::new(true));
+        assert!(!dbg.execute("regs").unwrap().contains('\x1b'));
+        assert_eq!(
+            dbg.execute("step").unwrap(),
+            "PC: \x1b[1;36m0x1\x1b[0m A: \x1b[1;36m0b0001\x1b[0m B: 0b0000 C: 0 IN: 0b0000 OUT: 0b0000"
+        );
+    }
+
+    #[test]
+    fn test_session() {
+        let program = vec![0b00000001, 0b00000001, 0b11110000];
+        let mut dbg = debugger(program.clone()).with_program("count.sasm");
+        dbg.execute("break 2 if A == 4").unwrap();
+        dbg.execute("break on carry").unwrap();
+        dbg.execute("step").unwrap();
+        dbg.execute("snapshot one").unwrap();
+        dbg.execute("step").unwrap();
+        let session = dbg.session();
+        assert_eq!(session.program.as_deref(), Some("count.sasm"));
+        assert_eq!(
+            session.breakpoints,
+            vec!["break 0x2 if A == 4", "break on carry"]
+        );
+
+        let mut restored = debugger(program);
+        let message = restored.restore_session(&session).unwrap();
+        assert!(message.starts_with("Restored the session at cycle 2"));
+        assert_eq!(restored.emulator().register().register_a(), 2);
+        assert_eq!(
+            restored.execute("breakpoints").unwrap(),
+            "0x2 if A == 4\non carry"
+        );
+        assert_eq!(
+            restored.execute("diff one").unwrap(),
+            "pc: 0x1 -> 0x2\na: 0b0001 -> 0b0010\ncycles: 1 -> 2"
+        );
+
+        // 直したプログラムではブレークポイントだけ戻す
+        let mut edited = debugger(vec![0b00000010, 0b11110000]);
+        let message = edited.restore_session(&session).unwrap();
+        assert!(message.starts_with("The program has changed"));
+        assert_eq!(edited.emulator().register().register_a(), 0);
+        assert!(edited.snapshot("one").is_some());
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let mut dbg = debugger(vec![0b00000001, 0b00000001, 0b11110000]);
+        assert!(dbg.execute("break 2").is_ok());
+        assert!(dbg.execute("c").is_ok());
+        assert_eq!(dbg.emulator().register().pc(), 2);
+        assert_eq!(dbg.emulator().register().register_a(), 2);
+
+        assert!(dbg.execute("c").is_ok());
+        assert_eq!(dbg.emulator().register().register_a(), 4);
+        assert_eq!(
+            dbg.events(),
+            &[DebugEvent::BreakpointHit(2), DebugEvent::BreakpointHit(2)]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_text() {
+        // mov A 0011, out 0101 を実行した直後
+        let mut dbg = debugger(vec![0b00110011, 0b10110101]);
+        assert!(dbg.execute("step 2").is_ok());
+        let snapshot = Snapshot::take(dbg.emulator());
+        let line = "pc=0x2 a=0b0011 b=0b0000 c=0 in=0b0000 out=0b0101 stack= shadow=0x0 cycles=2 rom=33,b5";
+        assert_eq!(snapshot.to_string(), line);
+        assert_eq!(line.parse::<Snapshot>().unwrap(), snapshot);
+
+        // 書いていない項目は初期値で、順番は問わない
+        let partial: Snapshot = "cycles=7 stack=02,07 pc=0x1".parse().unwrap();
+        assert_eq!(partial.register.pc(), 1);
+        assert_eq!(partial.stack, vec![2, 7]);
+        assert_eq!(
+            partial.to_string(),
+            "pc=0x1 a=0b0000 b=0b0000 c=0 in=0b0000 out=0b0000 stack=02,07 shadow=0x0 cycles=7 rom="
+        );
+        assert_eq!(
+            "pc=0x1 x=2".parse::<Snapshot>().unwrap_err().to_string(),
+            "unknown field x"
+        );
+        assert!("rom=3g".parse::<Snapshot>().is_err());
+        assert!("pc".parse::<Snapshot>().is_err());
+    }
+
+    #[test]
+    fn test_snapshot_diff() {
+        // mov A 0011, out 0101, poke後に比べる
+        let mut dbg = debugger(vec![0b00110011, 0b10110101, 0b00000001]);
+        assert!(dbg.execute("snapshot start").is_ok());
+        assert!(dbg.execute("diff missing").is_err());
+        assert_eq!(dbg.execute("diff start").unwrap(), "No changes since start");
+
+        assert!(dbg.execute("step 2").is_ok());
+        assert!(dbg.execute("poke 0x2 0b00000010").is_ok());
+        assert_eq!(
+            dbg.execute("diff start").unwrap(),
+            "pc: 0x0 -> 0x2\na: 0b0000 -> 0b0011\nout: 0b0000 -> 0b0101\ncycles: 0 -> 2\nrom[0x2]: 01 -> 02"
+        );
+
+        // 同じ名前で保存し直すと上書きする
+        assert!(dbg.execute("snap start").is_ok());
+        assert_eq!(dbg.execute("diff start").unwrap(), "No changes since start");
+        assert_eq!(dbg.snapshot("start").unwrap().register.pc(), 2);
+        assert_eq!(dbg.execute("snapshots").unwrap(), "start");
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        // add A 0001, jmp 0000 の無限ループ
+        let mut dbg = debugger(vec![0b00000001, 0b11110000]);
+        assert!(dbg.execute("break 0x1 if A == 3").is_ok());
+        assert!(dbg.execute("break 0x1 if X == 3").is_err());
+        assert!(dbg.execute("c").is_ok());
+        assert_eq!(dbg.emulator().register().register_a(), 3);
+        assert_eq!(dbg.emulator().register().pc(), 1);
+        assert_eq!(dbg.execute("breakpoints").unwrap(), "0x1 if A == 3");
+    }
+
+    #[test]
+    fn test_break_on() {
+        // add A 1111, out 1111, jmp 0000
+        let mut dbg = debugger(vec![0b00001111, 0b10111111, 0b11110000]);
+        assert!(dbg.execute("break on out == 0b1111").is_ok());
+        assert!(dbg.execute("b on carry").is_ok());
+
+        // 2周目のaddでキャリーが立つ
+        assert_eq!(
+            dbg.execute("c").unwrap().lines().next(),
+            Some("Condition out == 0b1111 holds")
+        );
+        assert_eq!(dbg.emulator().register().pc(), 2);
+        assert!(dbg.execute("c").is_ok());
+        assert_eq!(dbg.emulator().register().carry_flag(), 1);
+        assert_eq!(
+            dbg.events(),
+            &[
+                DebugEvent::ConditionHit("out == 0b1111".to_string()),
+                DebugEvent::ConditionHit("carry".to_string())
+            ]
+        );
+
+        assert!(dbg.execute("delete on carry").is_ok());
+        assert!(dbg.execute("delete on carry").is_err());
+        assert_eq!(dbg.execute("breakpoints").unwrap(), "on out == 0b1111");
+    }
+
+    #[test]
+    fn test_until_and_next_out() {
+        // add A 0001, add A 0001, out 0011, jmp 0000
+        let mut dbg = debugger(vec![0b00000001, 0b00000001, 0b10110011, 0b11110000]);
+        assert!(dbg.execute("break 1").is_ok());
+        assert!(dbg.execute("until 3").is_ok());
+        assert_eq!(dbg.emulator().register().pc(), 3);
+        assert_eq!(dbg.emulator().register().register_a(), 2);
+        assert!(dbg.events().is_empty());
+
+        // 同じアドレスにいても一周して戻ってくる
+        assert!(dbg.execute("u 3").is_ok());
+        assert_eq!(dbg.emulator().register().register_a(), 4);
+        assert_eq!(dbg.emulator().output_count(), 2);
+
+        // next-out はユーザーのブレークポイントでも止まる
+        assert!(dbg.execute("next-out").is_ok());
+        assert_eq!(dbg.events(), &[DebugEvent::BreakpointHit(1)]);
+
+        // OUTを実行した直後で止まる
+        assert!(dbg.execute("next-out").is_ok());
+        assert_eq!(dbg.emulator().register().pc(), 3);
+        assert_eq!(dbg.emulator().output(), 0b0011);
+        assert_eq!(dbg.emulator().output_count(), 3);
+    }
+}
@@ -0,0 +1,180 @@
+use crate::debugger::parse_number;
+use crate::error::EmulatorErr;
+use crate::parser::strip_comment;
+use crate::profile::MachineProfile;
+use std::str::FromStr;
+
+// ソースコードの ;! で始まる行に書いた実行の設定
+//
+//     ;!profile extended
+//     ;!clock 10hz
+//     ;!input 0b0101
+//     ;!cycles 100
+//
+// コマンドラインで同じ設定を指定したときはそちらを優先する
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct ProgramConfig {
+    pub profile: Option<MachineProfile>,
+    pub clock: Option<f64>,
+    // 入力ポート0の初期値
+    pub input: Option<u8>,
+    pub max_cycles: Option<usize>,
+}
+
+pub const PREFIX: &str = ";!";
+
+impl ProgramConfig {
+    // self で指定していない設定を fallback から補う
+    pub fn or(self, fallback: ProgramConfig) -> ProgramConfig {
+        ProgramConfig {
+            profile: self.profile.or(fallback.profile),
+            clock: self.clock.or(fallback.clock),
+            input: self.input.or(fallback.input),
+            max_cycles: self.max_cycles.or(fallback.max_cycles),
+        }
+    }
+}
+
+impl FromStr for ProgramConfig {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = ProgramConfig::default();
+        for (number, line) in s.lines().enumerate() {
+            let directive = match line.trim().strip_prefix(PREFIX) {
+                Some(directive) => directive,
+                None => continue,
+            };
+            let error = |message: String| {
+                EmulatorErr::new(&format!(
+                    "line {}: {}{}: {}",
+                    number + 1,
+                    PREFIX,
+                    directive,
+                    message
+                ))
+            };
+            // 設定のあとにはコメントを書ける
+            let (name, value) = strip_comment(directive)
+                .trim()
+                .split_once(char::is_whitespace)
+                .unwrap_or((strip_comment(directive).trim(), ""));
+            let value = value.trim();
+            match name {
+                "profile" => {
+                    config.profile =
+                        Some(parse_profile(value).map_err(|err| error(err.to_string()))?)
+                }
+                "clock" => {
+                    config.clock = Some(
+                        parse_clock(value)
+                            .ok_or_else(|| error("invalid clock frequency".to_string()))?,
+                    )
+                }
+                "input" => {
+                    config.input = Some(parse_number(value).map_err(|err| error(err.to_string()))?)
+                }
+                "cycles" => {
+                    config.max_cycles = Some(
+                        value
+                            .parse()
+                            .map_err(|_| error("invalid cycle count".to_string()))?,
+                    )
+                }
+                _ => return Err(error("unknown directive".to_string())),
+            }
+        }
+        if let (Some(profile), Some(input)) = (&config.profile, config.input) {
+            if input > profile.register_mask() {
+                return Err(EmulatorErr::new(&format!(
+                    "input {} doesn't fit in {} bits",
+                    input, profile.register_bits
+                )));
+            }
+        }
+        Ok(config)
+    }
+}
+
+// アセンブラには設定の行を空行として渡す (V1の文法はコメントを受け付けない)
+pub fn strip(source: &str) -> String {
+    source
+        .lines()
+        .map(|line| {
+            if line.trim().starts_with(PREFIX) {
+                ""
+            } else {
+                line
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// td4- を省いた名前でもよい
+fn parse_profile(name: &str) -> Result<MachineProfile, EmulatorErr> {
+    name.parse()
+        .or_else(|err: EmulatorErr| format!("td4-{}", name).parse().map_err(|_| err))
+}
+
+// 10, 10hz, 2.5khz, 1mhz
+fn parse_clock(text: &str) -> Option<f64> {
+    let text = text.to_ascii_lowercase();
+    let (number, scale) = if let Some(number) = text.strip_suffix("mhz") {
+        (number, 1e6)
+    } else if let Some(number) = text.strip_suffix("khz") {
+        (number, 1e3)
+    } else {
+        (text.strip_suffix("hz").unwrap_or(&text), 1.0)
+    };
+    match number.trim().parse::<f64>() {
+        Ok(hz) if hz > 0.0 => Some(hz * scale),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod directive_tests {
+    use crate::compiler::assemble;
+    use crate::directive::{strip, ProgramConfig};
+    use crate::profile::MachineProfile;
+
+    const SOURCE: &str =
+        ";!profile extended ; 拡張命令\n;!clock 2.5khz\n  ;!input 0b0101\nin A\nout B";
+
+    #[test]
+    fn test_parse() {
+        let config: ProgramConfig = SOURCE.parse().unwrap();
+        assert_eq!(config.profile, Some(MachineProfile::td4_extended()));
+        assert_eq!(config.clock, Some(2500.0));
+        assert_eq!(config.input, Some(0b0101));
+        assert_eq!(config.max_cycles, None);
+
+        let flags = ProgramConfig {
+            clock: Some(1.0),
+            max_cycles: Some(10),
+            ..ProgramConfig::default()
+        };
+        let merged = flags.or(config);
+        assert_eq!((merged.clock, merged.input), (Some(1.0), Some(0b0101)));
+        assert_eq!(merged.max_cycles, Some(10));
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = "out B\n;!speed 10hz".parse::<ProgramConfig>().unwrap_err();
+        assert_eq!(err.to_string(), "line 2: ;!speed 10hz: unknown directive");
+        assert!(";!clock 0hz".parse::<ProgramConfig>().is_err());
+        assert!(";!profile td5".parse::<ProgramConfig>().is_err());
+        assert!(";!input 16".parse::<ProgramConfig>().is_ok());
+        assert!(";!profile book\n;!input 16"
+            .parse::<ProgramConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_assemble_ignores_directives() {
+        assert_eq!(assemble(SOURCE).unwrap(), vec![0b00100000, 0b10010000]);
+        assert_eq!(strip(SOURCE).lines().count(), 5);
+    }
+}
@@ -0,0 +1,392 @@
+use crate::debug_info::DebugInfo;
+use crate::error::EmulatorErr;
+use crate::instruction::{Instruction, Reg};
+use crate::op::{LowBits, Opcode};
+use std::fmt;
+use std::str::FromStr;
+
+// 逆アセンブルの書き方 (--disasm-style upper,hex,book,no-raw)
+// 教科書や他のエミュレータの表記に合わせて、そのまま貼り付けられるようにする
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DisasmStyle {
+    pub case: LetterCase,
+    pub radix: Radix,
+    pub dialect: Dialect,
+    // 一覧とトレースにバイトの列を付ける
+    pub raw: bool,
+}
+
+// ニーモニックの大文字・小文字。レジスタ名はいつも大文字
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LetterCase {
+    Lower,
+    Upper,
+}
+
+// 即値とポート番号の基数
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Radix {
+    Binary,
+    Hex,
+    Decimal,
+}
+
+// オペランドの区切り方
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Dialect {
+    // mov A 0011 (--syntax v1)
+    V1,
+    // mov A, 0b0011 (--syntax v2)
+    V2,
+    // MOV A,0011 (本の命令表)
+    Book,
+}
+
+impl Default for DisasmStyle {
+    fn default() -> Self {
+        DisasmStyle {
+            case: LetterCase::Lower,
+            radix: Radix::Binary,
+            dialect: Dialect::V1,
+            raw: true,
+        }
+    }
+}
+
+impl DisasmStyle {
+    pub const KEYWORDS: &'static [&'static str] = &[
+        "lower", "upper", "bin", "hex", "dec", "v1", "v2", "book", "raw", "no-raw",
+    ];
+
+    fn immediate(&self, value: u8) -> String {
+        match (self.radix, self.dialect) {
+            (Radix::Binary, Dialect::V2) => format!("0b{:04b}", value),
+            (Radix::Binary, _) => format!("{:04b}", value),
+            (Radix::Hex, _) => format!("0x{:x}", value),
+            (Radix::Decimal, _) => value.to_string(),
+        }
+    }
+}
+
+// 書かなかった項目は既定のまま。同じ項目を2回書いたら後のものを使う
+impl FromStr for DisasmStyle {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut style = DisasmStyle::default();
+        for keyword in s.split(',').map(str::trim) {
+            match keyword {
+                "lower" => style.case = LetterCase::Lower,
+                "upper" => style.case = LetterCase::Upper,
+                "bin" => style.radix = Radix::Binary,
+                "hex" => style.radix = Radix::Hex,
+                "dec" => style.radix = Radix::Decimal,
+                "v1" => style.dialect = Dialect::V1,
+                "v2" => style.dialect = Dialect::V2,
+                "book" => style.dialect = Dialect::Book,
+                "raw" => style.raw = true,
+                "no-raw" => style.raw = false,
+                _ => {
+                    return Err(EmulatorErr::new(&format!(
+                        "Unknown disassembly style: {}. Use a comma-separated list of {}",
+                        keyword,
+                        DisasmStyle::KEYWORDS.join(", ")
+                    )))
+                }
+            }
+        }
+        Ok(style)
+    }
+}
+
+impl fmt::Display for DisasmStyle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let case = match self.case {
+            LetterCase::Lower => "lower",
+            LetterCase::Upper => "upper",
+        };
+        let radix = match self.radix {
+            Radix::Binary => "bin",
+            Radix::Hex => "hex",
+            Radix::Decimal => "dec",
+        };
+        let dialect = match self.dialect {
+            Dialect::V1 => "v1",
+            Dialect::V2 => "v2",
+            Dialect::Book => "book",
+        };
+        let raw = if self.raw { "raw" } else { "no-raw" };
+        write!(f, "{},{},{},{}", case, radix, dialect, raw)
+    }
+}
+
+// 1バイトをアセンブリのソースに戻す
+// 命令として読めないバイトは .byte として出力する
+pub fn disassemble(data: u8) -> String {
+    disassemble_with(data, &DisasmStyle::default())
+}
+
+pub fn disassemble_with(data: u8, style: &DisasmStyle) -> String {
+    let (opcode, im) = match Opcode::decode(data) {
+        Some(decoded) => decoded,
+        None => return data_directive(data),
+    };
+    // オペランドのない命令は即値が0のときだけ元に戻せる
+    if opcode.low_bits() == LowBits::Zero && im != 0 {
+        return data_directive(data);
+    }
+
+    format_instruction(&Instruction::new(opcode, im), style)
+}
+
+// 既定の書き方なら Instruction の Display と同じになる
+fn format_instruction(instruction: &Instruction, style: &DisasmStyle) -> String {
+    let register = |reg: &Reg| match reg {
+        Reg::A => "A".to_string(),
+        Reg::B => "B".to_string(),
+    };
+    let (mnemonic, operands) = match instruction {
+        Instruction::Mov { reg, im } => ("mov", vec![register(reg), style.immediate(*im)]),
+        Instruction::MovAB => ("mov", vec!["A".to_string(), "B".to_string()]),
+        Instruction::MovBA => ("mov", vec!["B".to_string(), "A".to_string()]),
+        Instruction::Add { reg, im } => ("add", vec![register(reg), style.immediate(*im)]),
+        // ポート0は省略する
+        Instruction::In { reg, port: 0 } => ("in", vec![register(reg)]),
+        Instruction::In { reg, port } => ("in", vec![register(reg), style.immediate(*port)]),
+        Instruction::OutIm { im } => ("out", vec![style.immediate(*im)]),
+        Instruction::OutB { port: 0 } => ("out", vec!["B".to_string()]),
+        Instruction::OutB { port } => ("out", vec!["B".to_string(), style.immediate(*port)]),
+        Instruction::Jmp { im } => ("jmp", vec![style.immediate(*im)]),
+        Instruction::Jnc { im } => ("jnc", vec![style.immediate(*im)]),
+        Instruction::Call { im } => ("call", vec![style.immediate(*im)]),
+        Instruction::Ret => ("ret", vec![]),
+        Instruction::Sub { reg, im } => ("sub", vec![register(reg), style.immediate(*im)]),
+        Instruction::Cmp => ("cmp", vec!["A".to_string(), "B".to_string()]),
+        Instruction::Ld => ("ld", vec!["A".to_string(), "[B]".to_string()]),
+        Instruction::St => ("st", vec!["[B]".to_string(), "A".to_string()]),
+        Instruction::Swap => ("swap", vec!["A".to_string(), "B".to_string()]),
+    };
+    let mnemonic = match style.case {
+        LetterCase::Lower => mnemonic.to_string(),
+        LetterCase::Upper => mnemonic.to_uppercase(),
+    };
+    if operands.is_empty() {
+        return mnemonic;
+    }
+    let separator = match style.dialect {
+        Dialect::V1 => " ",
+        Dialect::V2 => ", ",
+        Dialect::Book => ",",
+    };
+    format!("{} {}", mnemonic, operands.join(separator))
+}
+
+fn data_directive(data: u8) -> String {
+    format!(".byte 0b{:08b}", data)
+}
+
+// ROM全体をアドレス付きで逆アセンブルする
+// デバッグ情報があれば .byte / .data で置かれた領域はデータとして表示する
+pub fn listing(rom: &[u8], debug_info: Option<&DebugInfo>) -> String {
+    listing_with(rom, debug_info, &DisasmStyle::default())
+}
+
+pub fn listing_with(rom: &[u8], debug_info: Option<&DebugInfo>, style: &DisasmStyle) -> String {
+    annotated_listing(rom, debug_info, style, |_| String::new())
+}
+
+// 各行の後ろに annotate(address) が返す文字列を付ける (実行回数など)
+pub fn annotated_listing(
+    rom: &[u8],
+    debug_info: Option<&DebugInfo>,
+    style: &DisasmStyle,
+    annotate: impl Fn(u8) -> String,
+) -> String {
+    let mut listing = String::new();
+    for (address, data) in rom.iter().enumerate() {
+        let is_data = debug_info.is_some_and(|info| info.is_data(address as u8));
+        let source = if is_data {
+            data_directive(*data)
+        } else {
+            disassemble_with(*data, style)
+        };
+        let raw = match style.raw {
+            true => format!("{:08b}  ", data),
+            false => String::new(),
+        };
+        let annotation = annotate(address as u8);
+        let line = if annotation.is_empty() {
+            format!("0x{:x}  {}{}\n", address, raw, source)
+        } else {
+            format!("0x{:x}  {}{:<18}  {}\n", address, raw, source, annotation)
+        };
+        listing.push_str(&line);
+    }
+    listing
+}
+
+// 本のROMの表と同じく全アドレスを1行ずつ並べる。プログラムの後ろの空きは0で埋める
+// sources[address] はそのバイトを生成したソースの行
+// style.raw でなければ data と hex の列を省く
+pub fn rom_table(
+    rom: &[u8],
+    rows: usize,
+    debug_info: Option<&DebugInfo>,
+    sources: &[Option<String>],
+    style: &DisasmStyle,
+) -> String {
+    let rows = rows.max(rom.len());
+    // アドレスはPCと同じく2進数で書く。16行なら4桁
+    let width = (usize::BITS - rows.saturating_sub(1).leading_zeros()).max(1) as usize;
+    let raw_header = match style.raw {
+        true => format!("{:<9}  {:<3}  ", "data", "hex"),
+        false => String::new(),
+    };
+    let mut table = format!(
+        "{:<width$}  {}{:<18}  {}\n",
+        "addr",
+        raw_header,
+        "instruction",
+        "source",
+        width = width.max(4)
+    );
+    for address in 0..rows {
+        let data = rom.get(address).copied().unwrap_or(0);
+        let is_data = debug_info.is_some_and(|info| info.is_data(address as u8));
+        let instruction = if is_data {
+            data_directive(data)
+        } else {
+            disassemble_with(data, style)
+        };
+        let source = sources
+            .get(address)
+            .and_then(|source| source.as_deref())
+            .unwrap_or("");
+        let raw = match style.raw {
+            true => format!("{:04b} {:04b}  {:02x}   ", data >> 4, data & 0x0f, data),
+            false => String::new(),
+        };
+        let line = format!(
+            "{:0width$b}{:pad$}  {}{:<18}  {}",
+            address,
+            "",
+            raw,
+            instruction,
+            source,
+            width = width,
+            pad = 4usize.saturating_sub(width)
+        );
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+#[cfg(test)]
+mod disassembler_tests {
+    use crate::compiler::{assemble, assemble_with_debug_info, assemble_with_profile};
+    use crate::disassembler::{
+        disassemble, disassemble_with, listing, listing_with, rom_table, DisasmStyle,
+    };
+    use crate::macros::MacroExpander;
+    use crate::parser::Syntax;
+    use crate::profile::MachineProfile;
+
+    #[test]
+    fn test_round_trip() {
+        let source = "mov A 0011\nadd B 0001\nmov A B\nmov B A\nin A\nin B\nout B\nout 1010\njnc 0001\njmp 0000\ncall 0011\nret\nsub A 0001\nsub B 1111\ncmp A B\nld A [B]\nst [B] A\nswap A B";
+        let program = assemble(source).unwrap();
+        let disassembled: Vec<String> = program.iter().map(|data| disassemble(*data)).collect();
+        assert_eq!(disassembled.join("\n"), source);
+    }
+
+    #[test]
+    fn test_undefined_opcode() {
+        assert_eq!(disassemble(0b10100101), ".byte 0b10100101");
+        assert_eq!(disassemble(0b00010001), ".byte 0b00010001");
+        // 入出力命令の下位4bitはポート番号
+        assert_eq!(disassemble(0b10010001), "out B 0001");
+        assert_eq!(disassemble(0b01100010), "in B 0010");
+    }
+
+    #[test]
+    fn test_listing_marks_data() {
+        let (program, debug_info) = assemble_with_debug_info("out B\n.byte 0b10110001").unwrap();
+        assert_eq!(
+            listing(&program, Some(&debug_info)),
+            "0x0  10010000  out B\n0x1  10110001  .byte 0b10110001\n"
+        );
+        assert_eq!(
+            listing(&program, None),
+            "0x0  10010000  out B\n0x1  10110001  out 0001\n"
+        );
+    }
+
+    #[test]
+    fn test_rom_table() {
+        let (program, debug_info) = assemble_with_debug_info("out B\n.byte 0b10110001").unwrap();
+        let sources = [Some("1: out B".to_string())];
+        let table = rom_table(
+            &program,
+            16,
+            Some(&debug_info),
+            &sources,
+            &DisasmStyle::default(),
+        );
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 17);
+        assert_eq!(lines[0], "addr  data       hex  instruction         source");
+        assert_eq!(
+            lines[1],
+            "0000  1001 0000  90   out B               1: out B"
+        );
+        assert_eq!(lines[2], "0001  1011 0001  b1   .byte 0b10110001");
+        assert_eq!(lines[16], "1111  0000 0000  00   add A 0000");
+    }
+
+    #[test]
+    fn test_style() {
+        let style: DisasmStyle = "upper,hex,book".parse().unwrap();
+        assert_eq!(disassemble_with(0b00110011, &style), "MOV A,0x3");
+        assert_eq!(disassemble_with(0b01000000, &style), "MOV B,A");
+        assert_eq!(disassemble_with(0b10010010, &style), "OUT B,0x2");
+        assert_eq!(disassemble_with(0b10100000, &style), "RET");
+        assert_eq!(disassemble_with(0b10100010, &style), "LD A,[B]");
+        let style: DisasmStyle = "dec".parse().unwrap();
+        assert_eq!(disassemble_with(0b11111100, &style), "jmp 12");
+        assert_eq!(style.to_string(), "lower,dec,v1,raw");
+        assert!("upper,octal".parse::<DisasmStyle>().is_err());
+
+        let (program, debug_info) = assemble_with_debug_info("out B\n.byte 0b10110001").unwrap();
+        let style: DisasmStyle = "no-raw,upper".parse().unwrap();
+        assert_eq!(
+            listing_with(&program, Some(&debug_info), &style),
+            "0x0  OUT B\n0x1  .byte 0b10110001\n"
+        );
+        let table = rom_table(&program, 2, None, &[], &style);
+        assert_eq!(
+            table,
+            "addr  instruction         source\n0     OUT B\n1     OUT 0001\n"
+        );
+    }
+
+    #[test]
+    fn test_style_round_trip() {
+        // v2 の書き方で逆アセンブルしたものは --syntax v2 でアセンブルし直せる
+        // ポート番号付きの in / out B もあるので拡張モードで組み立てる
+        let extended = MachineProfile::td4_extended();
+        for radix in ["bin", "hex", "dec"] {
+            let style: DisasmStyle = format!("v2,{}", radix).parse().unwrap();
+            for data in 0..=255u8 {
+                let source = disassemble_with(data, &style);
+                if source.starts_with(".byte") {
+                    continue;
+                }
+                let (program, _) =
+                    assemble_with_profile(&source, &MacroExpander::new(), Syntax::V2, &extended)
+                        .unwrap();
+                assert_eq!(program, vec![data], "{}", source);
+            }
+        }
+    }
+}
@@ -1,198 +1,1135 @@
+use crate::cost::{CostModel, UniformCost};
 use crate::error::EmulatorErr;
-use crate::op::Opcode;
-use crate::port::Port;
+#[cfg(feature = "gates")]
+use crate::gates::{self, DatapathCycle};
+use crate::instruction::{Instruction, Reg};
+use crate::mmio::{Mapping, MemoryMap, Mmio};
+use crate::mode::{Mode, SaveTarget};
+use crate::op::{LowBits, Opcode};
+use crate::plugin::{CustomInstruction, InstructionState};
+use crate::port::{InputFilter, InputSource, OutputObserver, Ports};
+use crate::profile::{
+    FetchBeyondRom, InputSampling, MachineProfile, OutputModel, UndefinedOpcodePolicy,
+};
 use crate::register::Register;
+use crate::renderer::{DecimalRenderer, OutputRenderer};
 use crate::rom::Rom;
-use num_traits::FromPrimitive;
-use std::cell::RefCell;
+use crate::stack::Stack;
+use crate::style;
+use crate::timing::{BranchStats, ExecStats, TimingModel, UniformTiming};
+use crate::tracer::{TraceRecord, Tracer};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::RangeInclusive;
+use std::thread;
+use std::time::Duration;
+
+// 実行途中に値を書き換えられる場所
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PokeTarget {
+    RegisterA,
+    RegisterB,
+    CarryFlag,
+    Pc,
+    Input,
+    Rom(u8),
+}
 
 pub struct CpuEmulator {
     register: RefCell<Register>,
     rom: RefCell<Rom>,
-    port: RefCell<Port>,
+    ports: RefCell<Ports>,
+    // CALL/RETの戻りアドレス (拡張モード)
+    stack: RefCell<Stack>,
+    // ld / st でアクセスするRAMや装置 (拡張モード)
+    memory: RefCell<MemoryMap>,
+    renderer: Box<dyn OutputRenderer>,
+    timing: Box<dyn TimingModel>,
+    cost: Box<dyn CostModel>,
+    instructions: Cell<usize>,
+    cycles: Cell<usize>,
+    // 実行した命令のコストの合計
+    energy: Cell<u64>,
+    // JNC命令のアドレスごとの分岐の結果
+    branches: RefCell<BTreeMap<u8, BranchStats>>,
+    // trueならOUT命令の出力や警告を表示しない
+    quiet: bool,
+    // デコードの警告を出したROMのアドレス。ループの中の命令で同じ警告を繰り返さない
+    warned: RefCell<BTreeSet<u8>>,
+    profile: MachineProfile,
+    // 割り込みの立ち上がり検出のための前回のステップの入力
+    last_input: Cell<u8>,
+    // 割り込まれたPCの保存先 (SaveTarget::Shadow のとき)
+    shadow_pc: Cell<u8>,
+    // 設定されていれば1命令ごとの状態を記録する
+    tracer: RefCell<Option<Tracer>>,
+    output_observers: RefCell<Vec<Box<dyn OutputObserver>>>,
+    // 直前の命令で出力ポートに書き込んだ (ポート, 値)
+    output_writes: RefCell<Vec<(usize, u8)>>,
+    // 設定されていればIN命令のたびに入力ポートの値をここから読む
+    input_source: RefCell<Option<Box<dyn InputSource>>>,
+    // IN命令に見せる入力ポートの値 (プロファイルの input_sampling と input_debounce)
+    input_filter: RefCell<InputFilter>,
+    // プラグインが足した命令 (命令の1バイトごと)。拡張モードでだけ実行する
+    custom_instructions: RefCell<BTreeMap<u8, Box<dyn CustomInstruction>>>,
 }
 
 impl CpuEmulator {
     // register, rom, portの指定なしにオブジェクトを生成することはないのでnew関数を削除
 
-    pub fn with(register: Register, port: Port, rom: Rom) -> Self {
+    pub fn with(register: Register, port: Ports, rom: Rom) -> Self {
+        Self::with_profile(register, port, rom, MachineProfile::default())
+    }
+
+    // ポートの数はプロファイルのモードに合わせる
+    pub fn with_profile(
+        register: Register,
+        mut ports: Ports,
+        rom: Rom,
+        profile: MachineProfile,
+    ) -> Self {
+        profile.validate().unwrap_or_else(|err| panic!("{}", err));
         assert!(
-            rom.size() <= 16,
-            "Maximum memory size is 16. This program can't work."
+            rom.bytes().len() <= profile.rom_size,
+            "Maximum memory size is {}. This program can't work.",
+            profile.rom_size
         );
+        let input = ports.input();
+        ports.resize(profile.mode.port_count());
+        let input_filter = InputFilter::new(ports.inputs(), profile.input_debounce);
         Self {
             register: RefCell::new(register),
-            port: RefCell::new(port),
+            ports: RefCell::new(ports),
             rom: RefCell::new(rom),
+            stack: RefCell::new(Stack::new()),
+            memory: RefCell::new(MemoryMap::new()),
+            renderer: Box::new(DecimalRenderer),
+            timing: Box::new(UniformTiming),
+            cost: Box::new(UniformCost),
+            instructions: Cell::new(0),
+            cycles: Cell::new(0),
+            energy: Cell::new(0),
+            branches: RefCell::new(BTreeMap::new()),
+            quiet: false,
+            warned: RefCell::new(BTreeSet::new()),
+            profile,
+            last_input: Cell::new(input),
+            shadow_pc: Cell::new(0),
+            tracer: RefCell::new(None),
+            output_observers: RefCell::new(Vec::new()),
+            output_writes: RefCell::new(Vec::new()),
+            input_source: RefCell::new(None),
+            input_filter: RefCell::new(input_filter),
+            custom_instructions: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    // OUT命令で出力ポートの値を表示する形式を変更する
+    pub fn set_renderer(&mut self, renderer: Box<dyn OutputRenderer>) {
+        self.renderer = renderer;
+    }
+
+    pub fn set_timing_model(&mut self, timing: Box<dyn TimingModel>) {
+        self.timing = timing;
+    }
+
+    pub fn set_cost_model(&mut self, cost: Box<dyn CostModel>) {
+        self.cost = cost;
+    }
+
+    // 別のCpuEmulatorに付け替えるために取り外す。代わりに UniformCost になる
+    pub fn take_cost_model(&mut self) -> Box<dyn CostModel> {
+        std::mem::replace(&mut self.cost, Box::new(UniformCost))
+    }
+
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = RefCell::new(Some(tracer));
+    }
+
+    // 記録したトレースを取り出す。以降は記録しない
+    pub fn take_tracer(&self) -> Option<Tracer> {
+        self.tracer.borrow_mut().take()
+    }
+
+    // 記録を続けたままこれまでのトレースを読む
+    pub fn trace_records(&self) -> Vec<TraceRecord> {
+        self.tracer
+            .borrow()
+            .as_ref()
+            .map(|tracer| tracer.records())
+            .unwrap_or_default()
+    }
+
+    pub fn add_output_observer(&mut self, observer: Box<dyn OutputObserver>) {
+        self.output_observers.borrow_mut().push(observer);
+    }
+
+    // 別のCpuEmulatorに付け替えるために取り外す
+    pub fn take_output_observers(&mut self) -> Vec<Box<dyn OutputObserver>> {
+        self.output_observers.take()
+    }
+
+    pub fn set_input_source(&mut self, source: Box<dyn InputSource>) {
+        self.input_source = RefCell::new(Some(source));
+    }
+
+    pub fn take_input_source(&mut self) -> Option<Box<dyn InputSource>> {
+        self.input_source.take()
+    }
+
+    pub fn add_custom_instruction(&mut self, data: u8, instruction: Box<dyn CustomInstruction>) {
+        self.custom_instructions
+            .borrow_mut()
+            .insert(data, instruction);
+    }
+
+    // 別のCpuEmulatorに付け替えるために取り外す
+    pub fn take_custom_instructions(&mut self) -> BTreeMap<u8, Box<dyn CustomInstruction>> {
+        self.custom_instructions.take()
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.ports.borrow_mut().resize(mode.port_count());
+        self.profile.mode = mode;
+    }
+
+    pub fn mode(&self) -> &Mode {
+        &self.profile.mode
+    }
+
+    pub fn profile(&self) -> &MachineProfile {
+        &self.profile
+    }
+
+    // ld / st のアドレスの割り当てを入れ替える
+    pub fn set_memory_map(&mut self, memory: MemoryMap) {
+        self.memory = RefCell::new(memory);
+    }
+
+    // 既定の割り当ての上に装置を重ねる
+    pub fn map_device(&mut self, range: RangeInclusive<u8>, device: Box<dyn Mmio>) {
+        self.memory.borrow_mut().map(range, device);
+    }
+
+    pub fn stack(&self) -> Stack {
+        self.stack.borrow().clone()
+    }
+
+    // 割り込みで保存されたPC
+    pub fn shadow_pc(&self) -> u8 {
+        self.shadow_pc.get()
+    }
+
+    // 大量に実行するときのためにOUT命令や警告の表示を止める
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    // ROMはそのままにレジスタ、キャリー、PC、出力ポートを初期状態に戻す
+    pub fn reset(&self) {
+        *self.register.borrow_mut() = Register::new();
+        let input = self.ports.borrow().input();
+        self.ports.borrow_mut().clear_outputs();
+        self.instructions.set(0);
+        self.cycles.set(0);
+        self.energy.set(0);
+        self.branches.borrow_mut().clear();
+        self.last_input.set(input);
+        self.shadow_pc.set(0);
+        *self.stack.borrow_mut() = Stack::new();
+        self.memory.borrow_mut().reset();
+        self.reset_input_filter();
+    }
+
+    // 実行した命令のコストを足す。None は未定義のopcode
+    fn charge(&self, opcode: Option<Opcode>) {
+        let cost = match opcode {
+            Some(opcode) => self.cost.cost(&opcode),
+            None => self.cost.undefined_cost(),
+        };
+        self.energy.set(self.energy.get() + cost);
+    }
+
+    // 今の入力ポートの値を確定した値としてチャタリング除去をやり直す
+    fn reset_input_filter(&self) {
+        *self.input_filter.borrow_mut() =
+            InputFilter::new(self.ports.borrow().inputs(), self.profile.input_debounce);
+    }
+
+    pub fn reset_with(&self, register: Register, mut ports: Ports) {
+        self.last_input.set(ports.input());
+        ports.resize(self.profile.mode.port_count());
+        *self.register.borrow_mut() = register;
+        *self.ports.borrow_mut() = ports;
+        self.instructions.set(0);
+        self.cycles.set(0);
+        self.energy.set(0);
+        self.branches.borrow_mut().clear();
+        self.shadow_pc.set(0);
+        *self.stack.borrow_mut() = Stack::new();
+        self.memory.borrow_mut().reset();
+        self.reset_input_filter();
+    }
+
+    // 保存しておいた状態に戻す (デバッガのセッションの復元)
+    pub fn restore(
+        &self,
+        register: Register,
+        ports: Ports,
+        stack: Stack,
+        shadow_pc: u8,
+        cycles: usize,
+    ) {
+        self.reset_with(register, ports);
+        *self.stack.borrow_mut() = stack;
+        self.shadow_pc.set(shadow_pc);
+        self.cycles.set(cycles);
+    }
+
+    // これまでに消費したクロック数
+    pub fn cycles(&self) -> usize {
+        self.cycles.get()
+    }
+
+    pub fn stats(&self) -> ExecStats {
+        ExecStats {
+            instructions: self.instructions.get(),
+            cycles: self.cycles.get(),
+            energy: self.energy.get(),
+            branches: self.branches.borrow().clone(),
+        }
+    }
+
+    pub fn register(&self) -> Register {
+        self.register.borrow().clone()
+    }
+
+    pub fn input(&self) -> u8 {
+        self.ports.borrow().input()
+    }
+
+    pub fn output(&self) -> u8 {
+        self.ports.borrow().output()
+    }
+
+    // 拡張モードで増やしたポートも含めた数
+    pub fn port_count(&self) -> usize {
+        self.ports.borrow().count()
+    }
+
+    // 存在しないポートはNone
+    pub fn input_port(&self, port: usize) -> Option<u8> {
+        self.ports.borrow().input_at(port)
+    }
+
+    pub fn output_port(&self, port: usize) -> Option<u8> {
+        self.ports.borrow().output_at(port)
+    }
+
+    // フロントエンドから入力ポートに値を入れる。ポート0は PokeTarget::Input と同じ
+    pub fn set_input_port(&self, port: usize, value: u8) -> Result<(), EmulatorErr> {
+        let limit = self.profile.register_mask();
+        if value > limit {
+            return Err(EmulatorErr::new(&format!(
+                "Input port {} can't hold {}. Maximum value is {}",
+                port, value, limit
+            )));
         }
+        if !self.ports.borrow_mut().set_input_at(port, value) {
+            return Err(EmulatorErr::new(&format!(
+                "Input port {} doesn't exist",
+                port
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn rom(&self) -> Vec<u8> {
+        self.rom.borrow().bytes().to_vec()
+    }
+
+    // ROMの各アドレスをデコードした命令
+    pub fn decoded_rom(&self) -> Vec<Option<Instruction>> {
+        self.rom.borrow().decoded().to_vec()
+    }
+
+    // レジスタやROMの値を書き換え、書き換える前の値を返す
+    pub fn poke(&self, target: PokeTarget, value: u8) -> Result<u8, EmulatorErr> {
+        let limit = match target {
+            PokeTarget::CarryFlag => 1,
+            PokeTarget::Rom(_) => 0xff,
+            PokeTarget::Pc => self.profile.pc_mask(),
+            _ => self.profile.register_mask(),
+        };
+        if value > limit {
+            return Err(EmulatorErr::new(&format!(
+                "{:?} can't hold {}. Maximum value is {}",
+                target, value, limit
+            )));
+        }
+
+        let mut register = self.register.borrow_mut();
+        let old = match target {
+            PokeTarget::RegisterA => {
+                let old = register.register_a();
+                register.set_register_a(value);
+                old
+            }
+            PokeTarget::RegisterB => {
+                let old = register.register_b();
+                register.set_register_b(value);
+                old
+            }
+            PokeTarget::CarryFlag => {
+                let old = register.carry_flag();
+                register.set_carry_flag(value);
+                old
+            }
+            PokeTarget::Pc => {
+                let old = register.pc();
+                register.set_pc(value);
+                old
+            }
+            PokeTarget::Input => {
+                let old = self.ports.borrow().input();
+                self.ports.borrow_mut().set_input(value);
+                old
+            }
+            PokeTarget::Rom(address) => {
+                if address as usize >= self.rom.borrow().size() {
+                    return Err(EmulatorErr::new(&format!(
+                        "ROM address {} is out of range",
+                        address
+                    )));
+                }
+                let old = self.rom.borrow().read(address);
+                self.rom.borrow_mut().write(address, value);
+                // 書き換えた命令はもう一度確かめる
+                self.warned.borrow_mut().remove(&address);
+                old
+            }
+        };
+
+        Ok(old)
+    }
+
+    // OUT命令で出力ポートに書き込まれた (サイクル, 値) の履歴
+    pub fn output_history(&self) -> Vec<(usize, u8)> {
+        self.ports.borrow().output_history().to_vec()
+    }
+
+    // 直前に実行した命令で出力ポートに書き込んだ (ポート, 値)
+    pub fn last_output_writes(&self) -> Vec<(usize, u8)> {
+        self.output_writes.borrow().clone()
+    }
+
+    // OUT命令を実行した回数
+    pub fn output_count(&self) -> usize {
+        self.ports.borrow().output_history().len()
     }
 
     // fetch, decode関数はexecからしか呼ばないのでpub -> privateに変更
-    fn fetch(&self) -> u8 {
+    // ROMを読み込んだときにデコードしておいた命令も一緒に返す
+    // ROMの外はプロファイルの fetch_beyond_rom に従う。None なら何も実行しない
+    fn fetch_decoded(&self) -> Result<Option<(u8, Option<Instruction>)>, EmulatorErr> {
         let pc = self.register.borrow().pc();
-        if self.rom.borrow().size() <= pc {
-            return 0;
+        let rom = self.rom.borrow();
+        if (pc as usize) < rom.size() {
+            return Ok(Some((rom.read(pc), rom.decoded()[pc as usize])));
+        }
+        match self.profile.fetch_beyond_rom {
+            // poke などで外に置かれたPCも折り返した値をレジスタに書き戻す
+            FetchBeyondRom::Wrap if rom.size() > 0 => {
+                let address = FetchBeyondRom::Wrap.wrap(pc, rom.size());
+                self.register.borrow_mut().set_pc(address);
+                Ok(Some((rom.read(address), rom.decoded()[address as usize])))
+            }
+            FetchBeyondRom::ReturnZero | FetchBeyondRom::Wrap => {
+                Ok(Some((0, Instruction::decode(0))))
+            }
+            FetchBeyondRom::Halt => Ok(None),
+            FetchBeyondRom::Error => Err(EmulatorErr::new(&format!(
+                "PC 0x{:x} is past the end of the {} byte ROM",
+                pc,
+                rom.size()
+            ))),
         }
+    }
 
-        let code = self.rom.borrow().read(pc);
+    // 未定義のopcodeをプロファイルの方針で読み飛ばすときはNoneを返す
+    fn decode(
+        &self,
+        data: u8,
+        decoded: Option<Instruction>,
+    ) -> Result<Option<Instruction>, EmulatorErr> {
+        let instruction = match decoded {
+            Some(instruction) => instruction,
+            None => return self.undefined(data, "No match for opcode"),
+        };
+        let (opcode, im) = (instruction.opcode(), data & 0x0f);
 
-        code
+        // 拡張命令は標準モードでは未定義のopcodeと同じ扱い
+        if opcode.is_extended() && !self.profile.mode.is_extended() {
+            return self.undefined(
+                data,
+                &format!("{:?} is only available in extended mode", opcode),
+            );
+        }
+        // オペランドのない命令の下位4bitは0のはず
+        if opcode.low_bits() == LowBits::Zero && im != 0 {
+            self.warn_once(&format!("immediate {:04b} of {:?} is ignored", im, opcode));
+        }
+        match instruction.port() {
+            Some(port) if port as usize >= self.ports.borrow().count() => {
+                // 本のTD4では下位4bitを使わないので従来どおり無視してポート0を使う
+                if self.profile.mode.is_extended() {
+                    return Err(EmulatorErr::new(&format!(
+                        "{:?} uses port {} but there are only {} ports",
+                        opcode,
+                        port,
+                        self.ports.borrow().count()
+                    )));
+                }
+                self.warn_once(&format!("immediate {:04b} of {:?} is ignored", im, opcode));
+                Ok(Some(Instruction::new(opcode, 0)))
+            }
+            _ => Ok(Some(instruction)),
+        }
     }
 
-    fn decode(&self, data: u8) -> Result<(Opcode, u8), EmulatorErr> {
-        let op = data >> 4;
-        let im = data & 0x0f;
-
-        if let Some(opcode) = FromPrimitive::from_u8(op) {
-            match opcode {
-                Opcode::AddA
-                | Opcode::AddB
-                | Opcode::MovA
-                | Opcode::MovB
-                | Opcode::MovA2B
-                | Opcode::MovB2A
-                | Opcode::Jmp
-                | Opcode::Jnc
-                | Opcode::OutIm => Ok((opcode, im)),
-                Opcode::InA | Opcode::InB | Opcode::OutB => Ok((opcode, 0)), // imidiate data is always 0
+    fn undefined(&self, data: u8, message: &str) -> Result<Option<Instruction>, EmulatorErr> {
+        match self.profile.undefined_opcode_policy {
+            UndefinedOpcodePolicy::Error => Err(EmulatorErr::new(message)),
+            UndefinedOpcodePolicy::Nop => {
+                self.warn_once(&format!("undefined instruction {:08b} is skipped", data));
+                Ok(None)
             }
-        } else {
-            // never come
-            Err(EmulatorErr::new("No match for opcode"))
+        }
+    }
+
+    // 今のPCの命令について、そのアドレスで初めてなら警告する
+    fn warn_once(&self, message: &str) {
+        let pc = self.register.borrow().pc();
+        if !self.quiet && self.warned.borrow_mut().insert(pc) {
+            style::warn(&format!("0x{:x}: {}", pc, message));
         }
     }
 
     pub fn exec(&self) -> Result<(), EmulatorErr> {
         loop {
-            let data = self.fetch();
-            let (opcode, im) = self.decode(data)?;
-
-            match opcode {
-                Opcode::MovA => self.mov_a(im),
-                Opcode::MovB => self.mov_b(im),
-                Opcode::AddA => self.add_a(im),
-                Opcode::AddB => self.add_b(im),
-                Opcode::MovA2B => self.mov_a2b(),
-                Opcode::MovB2A => self.mov_b2a(),
-                Opcode::Jmp => self.jmp(im),
-                Opcode::Jnc => self.jnc(im),
-                Opcode::InA => self.in_a(),
-                Opcode::InB => self.in_b(),
-                Opcode::OutB => self.out_b(),
-                Opcode::OutIm => self.out_im(im),
-            };
-
-            // To prevent infinite loop
-            if opcode != Opcode::Jmp && opcode != Opcode::Jnc {
-                self.register.borrow_mut().incr_pc();
+            self.step()?;
+            if self.does_halt() {
+                return Ok(());
             }
+        }
+    }
+
+    // クロック周波数hzで実時間に合わせて実行する
+    pub fn exec_with_clock(&self, hz: f64) -> Result<(), EmulatorErr> {
+        loop {
+            let before = self.cycles.get();
+            self.step()?;
+            let elapsed = (self.cycles.get() - before) as f64;
+            thread::sleep(Duration::from_secs_f64(elapsed / hz));
             if self.does_halt() {
                 return Ok(());
             }
         }
     }
 
+    // 1命令だけ実行する
+    pub fn step(&self) -> Result<(), EmulatorErr> {
+        self.end_pulses();
+        self.check_interrupt();
+        let pc = self.register.borrow().pc();
+        let cycle = self.cycles.get();
+        let Some((data, decoded)) = self.fetch_decoded()? else {
+            return Ok(());
+        };
+        self.execute(data, decoded)?;
+        self.clock_inputs(self.cycles.get() - cycle);
+        #[cfg(feature = "debug")]
+        self.check_invariants(cycle, data)?;
+
+        if let Some(tracer) = self.tracer.borrow_mut().as_mut() {
+            tracer.record(TraceRecord {
+                cycle,
+                pc,
+                instruction: data,
+                register: self.register(),
+                output: self.output(),
+                flag_model: Some(self.profile.flag_model),
+            });
+        }
+        Ok(())
+    }
+
+    fn execute(&self, data: u8, decoded: Option<Instruction>) -> Result<(), EmulatorErr> {
+        if self.profile.mode.is_extended() {
+            if let Some(instruction) = self.custom_instructions.borrow_mut().get_mut(&data) {
+                return self.execute_custom(data, instruction.as_mut());
+            }
+        }
+        let instruction = match self.decode(data, decoded)? {
+            Some(decoded) => decoded,
+            None => {
+                self.incr_pc();
+                self.instructions.set(self.instructions.get() + 1);
+                self.cycles.set(self.cycles.get() + 1);
+                self.charge(None);
+                return Ok(());
+            }
+        };
+
+        match instruction {
+            Instruction::Mov { reg: Reg::A, im } => self.mov_a(im),
+            Instruction::Mov { reg: Reg::B, im } => self.mov_b(im),
+            Instruction::Add { reg: Reg::A, im } => self.add_a(im),
+            Instruction::Add { reg: Reg::B, im } => self.add_b(im),
+            Instruction::MovAB => self.mov_a2b(),
+            Instruction::MovBA => self.mov_b2a(),
+            Instruction::Jmp { im } => self.jmp(im),
+            Instruction::Jnc { im } => self.jnc(im),
+            Instruction::In { reg: Reg::A, port } => self.in_a(port)?,
+            Instruction::In { reg: Reg::B, port } => self.in_b(port)?,
+            Instruction::OutB { port } => self.out_b(port),
+            Instruction::OutIm { im } => self.out_im(im),
+            Instruction::Call { im } => self.call(im)?,
+            Instruction::Ret => self.ret()?,
+            Instruction::Sub { reg: Reg::A, im } => self.sub_a(im),
+            Instruction::Sub { reg: Reg::B, im } => self.sub_b(im),
+            Instruction::Cmp => self.cmp(),
+            Instruction::Ld => self.ld()?,
+            Instruction::St => self.st(),
+            Instruction::Swap => self.swap(),
+        };
+
+        // To prevent infinite loop
+        if !matches!(
+            instruction,
+            Instruction::Jmp { .. }
+                | Instruction::Jnc { .. }
+                | Instruction::Call { .. }
+                | Instruction::Ret
+        ) {
+            self.incr_pc();
+        }
+        self.instructions.set(self.instructions.get() + 1);
+        self.cycles
+            .set(self.cycles.get() + self.timing.cycles(&instruction.opcode()));
+        self.charge(Some(instruction.opcode()));
+
+        Ok(())
+    }
+
+    // 命令デコーダ、データセレクタ、ALUの信号を順に計算して1命令実行する
+    #[cfg(feature = "gates")]
+    pub fn step_gates(&self) -> Result<DatapathCycle, EmulatorErr> {
+        self.end_pulses();
+        let (instruction, _) = self
+            .fetch_decoded()?
+            .ok_or_else(|| EmulatorErr::new("the CPU is halted past the end of the ROM"))?;
+        let (op, im) = (instruction >> 4, instruction & 0x0f);
+        let register = self.register();
+
+        let signals = gates::instruction_decoder(op, register.carry_flag());
+        let selector_out = gates::data_selector(
+            &signals,
+            register.register_a(),
+            register.register_b(),
+            self.sampled_input(0),
+        );
+        let (alu_out, carry_out) = gates::alu(selector_out, im);
+
+        // クロックの立ち上がりでLOADが有効なレジスタにALUの出力をラッチする
+        {
+            let mut next = self.register.borrow_mut();
+            if signals.load[0] {
+                next.set_register_a(alu_out);
+            }
+            if signals.load[1] {
+                next.set_register_b(alu_out);
+            }
+            let pc = if signals.load[3] {
+                alu_out
+            } else {
+                register.pc().wrapping_add(1)
+            };
+            next.set_pc(self.next_pc(pc));
+            next.set_carry_flag(carry_out);
+        }
+        if signals.load[2] {
+            self.ports
+                .borrow_mut()
+                .write_output(self.cycles.get(), alu_out);
+            self.output_written(0);
+        }
+
+        #[cfg(feature = "debug")]
+        self.check_invariants(self.cycles.get(), instruction)?;
+
+        let opcode = Opcode::decode(instruction).map(|(opcode, _)| opcode);
+        let cycles = match opcode {
+            Some(opcode) => self.timing.cycles(&opcode),
+            None => 1,
+        };
+        self.charge(opcode);
+        self.instructions.set(self.instructions.get() + 1);
+        self.cycles.set(self.cycles.get() + cycles);
+        self.clock_inputs(cycles);
+
+        Ok(DatapathCycle {
+            pc: register.pc(),
+            instruction,
+            signals,
+            selector_out,
+            alu_out,
+            carry_out,
+        })
+    }
+
+    // 1命令実行したあとの状態がプロファイルのビット幅に収まっているか確かめる (debug フィーチャー)
+    // PCだけはROMの末尾の次 (停止) を指してよい
+    #[cfg(feature = "debug")]
+    fn check_invariants(&self, cycle: usize, instruction: u8) -> Result<(), EmulatorErr> {
+        let violation = |message: String| {
+            Err(EmulatorErr::invariant_violation(
+                cycle,
+                instruction,
+                &message,
+            ))
+        };
+        let mask = self.profile.register_mask();
+        let register = self.register();
+        for (name, value) in [("A", register.register_a()), ("B", register.register_b())] {
+            if value > mask {
+                return violation(format!(
+                    "register {} is {} but it has only {} bits",
+                    name, value, self.profile.register_bits
+                ));
+            }
+        }
+        if register.carry_flag() > 1 {
+            return violation(format!("carry flag is {}", register.carry_flag()));
+        }
+        if register.pc() as usize > self.profile.rom_size {
+            return violation(format!(
+                "PC is 0x{:x} but the ROM has {} bytes",
+                register.pc(),
+                self.profile.rom_size
+            ));
+        }
+        let ports = self.ports.borrow();
+        for (direction, values) in [("input", ports.inputs()), ("output", ports.outputs())] {
+            for (port, value) in values.iter().enumerate() {
+                if *value > mask {
+                    return violation(format!(
+                        "{} port {} is {} but it has only {} bits",
+                        direction, port, value, self.profile.register_bits
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 拡張モードで入力ビットが立ち上がっていればPCを保存してベクタへ飛ぶ
+    // プラグインの命令には1クロックかかり、コストは未定義の命令と同じとする
+    fn execute_custom(
+        &self,
+        data: u8,
+        instruction: &mut dyn CustomInstruction,
+    ) -> Result<(), EmulatorErr> {
+        let register = self.register();
+        let mut state = InstructionState {
+            a: register.register_a(),
+            b: register.register_b(),
+            carry: register.carry_flag(),
+            input: self.sampled_input(0),
+            output: None,
+        };
+        instruction.execute(&mut state)?;
+        let mask = self.profile.register_mask();
+        if state.a > mask
+            || state.b > mask
+            || state.carry > 1
+            || state.output.is_some_and(|output| output > mask)
+        {
+            return Err(EmulatorErr::new(&format!(
+                "Instruction {:08b} left a value that doesn't fit: {:?}",
+                data, state
+            )));
+        }
+
+        {
+            let mut register = self.register.borrow_mut();
+            register.set_register_a(state.a);
+            register.set_register_b(state.b);
+            register.set_carry_flag(state.carry);
+        }
+        if let Some(output) = state.output {
+            self.ports
+                .borrow_mut()
+                .write_output(self.cycles.get(), output);
+            self.output_written(0);
+        }
+        self.incr_pc();
+        self.instructions.set(self.instructions.get() + 1);
+        self.cycles.set(self.cycles.get() + 1);
+        self.charge(None);
+        Ok(())
+    }
+
+    fn check_interrupt(&self) {
+        let input = self.ports.borrow().input();
+        let previous = self.last_input.replace(input);
+        let interrupt = match self.profile.mode.extensions().and_then(|ext| ext.interrupt) {
+            Some(interrupt) if interrupt.is_triggered(previous, input) => interrupt,
+            _ => return,
+        };
+
+        let vector = self.next_pc(interrupt.vector);
+        let mut register = self.register.borrow_mut();
+        let pc = register.pc();
+        match interrupt.save_to {
+            SaveTarget::RegisterB => register.set_register_b(pc),
+            SaveTarget::Shadow => self.shadow_pc.set(pc),
+        }
+        register.set_pc(vector);
+    }
+
     // fetchで判定するより前に判定
-    fn does_halt(&self) -> bool {
-        self.register.borrow().pc() >= self.rom.borrow().size()
+    // 自分自身へのジャンプ (halt) もそれ以上状態が変わらないので、プロファイルが許せば停止とみなす
+    // ROMの外に出たら止まる。ただし Wrap では先頭からの続きを読むので止まらない
+    pub fn does_halt(&self) -> bool {
+        let pc = self.register.borrow().pc();
+        let rom = self.rom.borrow();
+        let address = self.profile.fetch_beyond_rom.wrap(pc, rom.size());
+        if address as usize >= rom.size() {
+            return true;
+        }
+        self.profile.halt_on_self_jump && rom.read(address) == Opcode::Jmp.encode(pc)
+    }
+
+    // PCはプロファイルの pc_bits の幅で桁あふれする (4bitなら 0xf の次は0)
+    // Wrap ではROMの外に出たPCをROMの中に折り返してからレジスタに書く
+    fn next_pc(&self, pc: u8) -> u8 {
+        let size = self.rom.borrow().size();
+        self.profile
+            .fetch_beyond_rom
+            .wrap(pc & self.profile.pc_mask(), size)
+    }
+
+    fn set_pc(&self, pc: u8) {
+        let pc = self.next_pc(pc);
+        self.register.borrow_mut().set_pc(pc);
+    }
+
+    fn incr_pc(&self) {
+        let pc = self.register.borrow().pc();
+        self.set_pc(pc.wrapping_add(1));
+    }
+
+    // 算術命令以外のキャリーの扱いはプロファイルのフラグモデルによる
+    fn clear_carry(&self) {
+        if !self.profile.flag_model.keeps_carry(false) {
+            self.register.borrow_mut().set_carry_flag(0);
+        }
+    }
+
+    // JMP, JNC (とそれを使う CALL, RET) のキャリー
+    fn clear_carry_on_jump(&self) {
+        if !self.profile.flag_model.keeps_carry(true) {
+            self.register.borrow_mut().set_carry_flag(0);
+        }
     }
 
     fn mov_a(&self, im: u8) {
         // registerの値を変更するので可変参照
         // opcodeに対する処理の内容は本p.230を参照
         self.register.borrow_mut().set_register_a(im);
-        self.register.borrow_mut().set_carry_flag(0);
+        self.clear_carry();
     }
 
     fn mov_b(&self, im: u8) {
         self.register.borrow_mut().set_register_b(im);
-        self.register.borrow_mut().set_carry_flag(0);
+        self.clear_carry();
     }
 
     fn mov_a2b(&self) {
         let register_b = self.register.borrow().register_b();
         self.register.borrow_mut().set_register_a(register_b);
-        self.register.borrow_mut().set_carry_flag(0);
+        self.clear_carry();
     }
 
     fn mov_b2a(&self) {
         let register_a = self.register.borrow().register_a();
         self.register.borrow_mut().set_register_b(register_a);
-        self.register.borrow_mut().set_carry_flag(0);
+        self.clear_carry();
+    }
+
+    fn swap(&self) {
+        let register = self.register();
+        self.register
+            .borrow_mut()
+            .set_register_a(register.register_b());
+        self.register
+            .borrow_mut()
+            .set_register_b(register.register_a());
+        self.clear_carry();
     }
 
     fn add_a(&self, im: u8) {
-        let existence = self.register.borrow().register_a();
-        let new_value = existence + im;
+        let existence = self.register.borrow().register_a() as u16;
+        let new_value = existence + im as u16;
 
-        if new_value > 0x0f {
+        // 桁あふれしなければキャリーは0になる (キャリーフラグは毎クロックALUの出力をラッチする)
+        let mask = self.profile.register_mask();
+        if new_value > mask as u16 {
             self.register.borrow_mut().set_carry_flag(1);
+        } else {
+            self.register.borrow_mut().set_carry_flag(0);
         }
 
-        self.register.borrow_mut().set_register_a(new_value & 0x0f);
+        self.register
+            .borrow_mut()
+            .set_register_a(new_value as u8 & mask);
     }
 
     fn add_b(&self, im: u8) {
-        let existence = self.register.borrow().register_b();
-        let new_value = existence + im;
+        let existence = self.register.borrow().register_b() as u16;
+        let new_value = existence + im as u16;
 
-        if new_value > 0x0f {
+        let mask = self.profile.register_mask();
+        if new_value > mask as u16 {
             self.register.borrow_mut().set_carry_flag(1);
+        } else {
+            self.register.borrow_mut().set_carry_flag(0);
+        }
+
+        self.register
+            .borrow_mut()
+            .set_register_b(new_value as u8 & mask);
+    }
+
+    // 減算ではキャリーをボロー (引けなかったら1) として使う
+    fn subtract(&self, lhs: u8, im: u8) -> u8 {
+        let borrow = if lhs < im { 1 } else { 0 };
+        self.register.borrow_mut().set_carry_flag(borrow);
+        lhs.wrapping_sub(im) & self.profile.register_mask()
+    }
+
+    fn sub_a(&self, im: u8) {
+        let existence = self.register.borrow().register_a();
+        let new_value = self.subtract(existence, im);
+        self.register.borrow_mut().set_register_a(new_value);
+    }
+
+    fn sub_b(&self, im: u8) {
+        let existence = self.register.borrow().register_b();
+        let new_value = self.subtract(existence, im);
+        self.register.borrow_mut().set_register_b(new_value);
+    }
+
+    // A - B のボローだけをキャリーに反映し、レジスタは書き換えない
+    fn cmp(&self) {
+        let register = self.register();
+        self.subtract(register.register_a(), register.register_b());
+    }
+
+    // レジスタBが指すアドレスを読む。何も割り当てていなければ0
+    fn ld(&self) -> Result<(), EmulatorErr> {
+        let address = self.register.borrow().register_b();
+        let value = match self.memory.borrow_mut().find(address) {
+            Some((Mapping::Ports, port)) => self.read_input(port as usize)?,
+            Some((Mapping::Device(device), offset)) => device.read(offset),
+            None => 0,
+        };
+        self.register
+            .borrow_mut()
+            .set_register_a(value & self.profile.register_mask());
+        self.clear_carry();
+        Ok(())
+    }
+
+    // ポートに割り当てたアドレスへの書き込みは out B と同じく出力になる
+    fn st(&self) {
+        let register = self.register();
+        let (address, value) = (register.register_b(), register.register_a());
+        let port = match self.memory.borrow_mut().find(address) {
+            Some((Mapping::Ports, port)) => Some(port),
+            Some((Mapping::Device(device), offset)) => {
+                device.write(offset, value);
+                None
+            }
+            None => None,
+        };
+        if let Some(port) = port {
+            let written =
+                self.ports
+                    .borrow_mut()
+                    .write_output_at(self.cycles.get(), port as usize, value);
+            if written {
+                self.output_written(port as usize);
+            }
+        }
+        self.clear_carry();
+    }
+
+    // 入力ソースがあれば次の値を入力ポートに入れてから読む
+    fn read_input(&self, port: usize) -> Result<u8, EmulatorErr> {
+        let next = match self.input_source.borrow_mut().as_mut() {
+            Some(source) => source.next_input(self.cycles.get(), port)?,
+            None => None,
+        };
+        if let Some(value) = next {
+            self.set_input_port(port, value)?;
         }
+        Ok(self.sampled_input(port))
+    }
+
+    // プロファイルの input_sampling と input_debounce を通した入力ポートの値
+    fn sampled_input(&self, port: usize) -> u8 {
+        let input = self.input_port(port).unwrap_or(0);
+        let filter = self.input_filter.borrow();
+        let sampled = match self.profile.input_sampling {
+            InputSampling::Direct => filter.peek(port, input),
+            InputSampling::Registered => filter.value(port),
+        };
+        sampled.unwrap_or(input)
+    }
 
-        self.register.borrow_mut().set_register_b(new_value & 0x0f);
+    // 命令を実行し終えるクロックの立ち上がりで入力ポートの値を取り込む
+    fn clock_inputs(&self, cycles: usize) {
+        self.input_filter
+            .borrow_mut()
+            .clock(self.ports.borrow().inputs(), cycles);
     }
 
-    fn in_a(&self) {
-        let input_port = self.port.borrow().input();
+    fn in_a(&self, port: u8) -> Result<(), EmulatorErr> {
+        let input_port = self.read_input(port as usize)?;
         self.register.borrow_mut().set_register_a(input_port);
-        self.register.borrow_mut().set_carry_flag(0);
+        self.clear_carry();
+        Ok(())
     }
 
-    fn in_b(&self) {
-        let input_port = self.port.borrow().input();
+    fn in_b(&self, port: u8) -> Result<(), EmulatorErr> {
+        let input_port = self.read_input(port as usize)?;
         self.register.borrow_mut().set_register_b(input_port);
-        self.register.borrow_mut().set_carry_flag(0);
+        self.clear_carry();
+        Ok(())
     }
 
     fn out_im(&self, im: u8) {
-        self.port.borrow_mut().set_output(im);
-        self.register.borrow_mut().set_carry_flag(0);
-        println!("Port (B) Out: {}", self.port.borrow().output());
+        self.ports.borrow_mut().write_output(self.cycles.get(), im);
+        self.clear_carry();
+        self.output_written(0);
     }
 
-    fn out_b(&self) {
+    fn out_b(&self, port: u8) {
         let register_b = self.register.borrow().register_b();
-        self.port.borrow_mut().set_output(register_b);
-        self.register.borrow_mut().set_carry_flag(0);
-        println!("Port (B) Out: {}", self.port.borrow().output());
+        self.ports
+            .borrow_mut()
+            .write_output_at(self.cycles.get(), port as usize, register_b);
+        self.clear_carry();
+        self.output_written(port as usize);
+    }
+
+    // パルス出力では前の命令で書き込んだポートをこの命令の始めに0に戻し、オブザーバーに知らせる
+    fn end_pulses(&self) {
+        let written = std::mem::take(&mut *self.output_writes.borrow_mut());
+        if self.profile.output_model != OutputModel::Pulsed {
+            return;
+        }
+        for (port, _) in written {
+            self.ports.borrow_mut().clear_output_at(port);
+            for observer in self.output_observers.borrow_mut().iter_mut() {
+                observer.on_output(self.cycles.get(), port, 0);
+            }
+        }
+    }
+
+    // 出力ポートに書き込んだことをオブザーバーに知らせて表示する
+    fn output_written(&self, port: usize) {
+        let output = self.output_port(port).unwrap_or(0);
+        self.output_writes.borrow_mut().push((port, output));
+        for observer in self.output_observers.borrow_mut().iter_mut() {
+            observer.on_output(self.cycles.get(), port, output);
+        }
+        if self.quiet {
+            return;
+        }
+        match port {
+            0 => println!("Port (B) Out: {}", self.renderer.render(output)),
+            _ => println!("Port {} Out: {}", port, self.renderer.render(output)),
+        }
+    }
+
+    // 次の命令のアドレスをスタックに積んでジャンプする
+    fn call(&self, im: u8) -> Result<(), EmulatorErr> {
+        let pc = self.register.borrow().pc();
+        self.stack
+            .borrow_mut()
+            .push(pc.wrapping_add(1) & self.profile.pc_mask())?;
+        self.jmp(im);
+        Ok(())
+    }
+
+    fn ret(&self) -> Result<(), EmulatorErr> {
+        let address = self.stack.borrow_mut().pop()?;
+        self.jmp(address);
+        Ok(())
     }
 
     fn jmp(&self, im: u8) {
-        self.register.borrow_mut().set_pc(im);
-        self.register.borrow_mut().set_carry_flag(0);
+        self.set_pc(im);
+        self.clear_carry_on_jump();
     }
 
     fn jnc(&self, im: u8) {
-        if self.register.borrow().carry_flag() == 0 {
-            self.register.borrow_mut().set_pc(im);
+        let pc = self.register.borrow().pc();
+        let taken = self.register.borrow().carry_flag() == 0;
+        {
+            let mut branches = self.branches.borrow_mut();
+            let stats = branches.entry(pc).or_default();
+            if taken {
+                stats.taken += 1;
+            } else {
+                stats.not_taken += 1;
+            }
         }
-        self.register.borrow_mut().set_carry_flag(0);
+
+        if taken {
+            self.set_pc(im);
+        } else {
+            // 分岐しないときは次の命令へ進む
+            self.incr_pc();
+        }
+        self.clear_carry_on_jump();
     }
 }
 
 #[cfg(test)]
 mod cpu_tests {
-    use crate::emulator::CpuEmulator;
-    use crate::port::Port;
+    use crate::emulator::{CpuEmulator, PokeTarget};
+    use crate::instruction::{Instruction, Reg};
+    use crate::mmio::{Mmio, PORTS_ADDRESS};
+    use crate::mode::{Extensions, Interrupt, Mode, SaveTarget};
+    use crate::op::Opcode;
+    use crate::port::Ports;
+    use crate::profile::{
+        FetchBeyondRom, FlagModel, InputSampling, MachineProfile, UndefinedOpcodePolicy,
+    };
     use crate::register::Register;
     use crate::rom::Rom;
+    use crate::timing::TableTiming;
+    use crate::tracer::{Tracer, TracerConfig};
 
     #[test]
     fn test_mov_a() {
         let rom = Rom::new(vec![0b00110001]);
         let register = Register::new();
-        let port = Port::new(0b0000, 0b0000);
+        let port = Ports::new(0b0000, 0b0000);
         let emu = CpuEmulator::with(register, port, rom);
         let proceeded = emu.exec();
 
@@ -203,11 +1140,25 @@ mod cpu_tests {
         assert_eq!(emu.register.borrow().carry_flag(), 0);
     }
 
+    #[test]
+    fn test_warns_once_per_address() {
+        // in A 0001, mov A B 0001, jmp 0000 を繰り返しても警告は2つのアドレスで1回ずつ
+        let rom = Rom::new(vec![0b00100001, 0b00010001, 0b11110000]);
+        let emu = CpuEmulator::with(Register::new(), Ports::new(0, 0), rom);
+        for _ in 0..30 {
+            emu.step().unwrap();
+        }
+        assert_eq!(*emu.warned.borrow(), [0, 1].into_iter().collect());
+
+        emu.poke(PokeTarget::Rom(1), 0b00010000).unwrap();
+        assert_eq!(*emu.warned.borrow(), [0].into_iter().collect());
+    }
+
     #[test]
     fn test_mov_b() {
         let rom = Rom::new(vec![0b01110001]);
         let register = Register::new();
-        let port = Port::new(0b0000, 0b0000);
+        let port = Ports::new(0b0000, 0b0000);
         let emu = CpuEmulator::with(register, port, rom);
         let proceeded = emu.exec();
 
@@ -223,7 +1174,7 @@ mod cpu_tests {
         let rom = Rom::new(vec![0b00010000]);
         let mut register = Register::new();
         register.set_register_b(2);
-        let port = Port::new(0b0000, 0b0000);
+        let port = Ports::new(0b0000, 0b0000);
         let emu = CpuEmulator::with(register, port, rom);
 
         assert_eq!(emu.register.borrow().register_a(), 0);
@@ -242,7 +1193,7 @@ mod cpu_tests {
         let rom = Rom::new(vec![0b01000000]);
         let mut register = Register::new();
         register.set_register_a(2);
-        let port = Port::new(0b0000, 0b0000);
+        let port = Ports::new(0b0000, 0b0000);
         let emu = CpuEmulator::with(register, port, rom);
 
         assert_eq!(emu.register.borrow().register_b(), 0);
@@ -261,7 +1212,7 @@ mod cpu_tests {
         let rom = Rom::new(vec![0b00000001]);
         let mut register = Register::new();
         register.set_register_a(1);
-        let port = Port::new(0b0000, 0b0000);
+        let port = Ports::new(0b0000, 0b0000);
         let emu = CpuEmulator::with(register, port, rom);
         let proceeded = emu.exec();
 
@@ -277,7 +1228,7 @@ mod cpu_tests {
         let rom = Rom::new(vec![0b01010001]);
         let mut register = Register::new();
         register.set_register_b(1);
-        let port = Port::new(0b0000, 0b0000);
+        let port = Ports::new(0b0000, 0b0000);
         let emu = CpuEmulator::with(register, port, rom);
         let proceeded = emu.exec();
 
@@ -288,11 +1239,39 @@ mod cpu_tests {
         assert_eq!(emu.register.borrow().carry_flag(), 0);
     }
 
+    #[test]
+    fn test_add_a_clears_carry() {
+        let rom = Rom::new(vec![0b00000001]);
+        let mut register = Register::new();
+        register.set_carry_flag(1);
+        let port = Ports::new(0b0000, 0b0000);
+        let emu = CpuEmulator::with(register, port, rom);
+        let proceeded = emu.exec();
+
+        assert!(proceeded.is_ok());
+        assert_eq!(emu.register.borrow().register_a(), 1);
+        assert_eq!(emu.register.borrow().carry_flag(), 0);
+    }
+
+    #[test]
+    fn test_add_b_with_carrying() {
+        let rom = Rom::new(vec![0b01010011]);
+        let mut register = Register::new();
+        register.set_register_b(0b1110);
+        let port = Ports::new(0b0000, 0b0000);
+        let emu = CpuEmulator::with(register, port, rom);
+        let proceeded = emu.exec();
+
+        assert!(proceeded.is_ok());
+        assert_eq!(emu.register.borrow().register_b(), 1);
+        assert_eq!(emu.register.borrow().carry_flag(), 1);
+    }
+
     #[test]
     fn test_jmp() {
         let rom = Rom::new(vec![0b11110010, 0b00110001, 0b01110010]);
         let register = Register::new();
-        let port = Port::new(0b0000, 0b0000);
+        let port = Ports::new(0b0000, 0b0000);
         let emu = CpuEmulator::with(register, port, rom);
         let proceeded = emu.exec();
 
@@ -302,11 +1281,310 @@ mod cpu_tests {
         assert_eq!(emu.register.borrow().register_b(), 2);
     }
 
+    #[test]
+    #[should_panic(expected = "register_bits and pc_bits must be between 1 and 8")]
+    fn test_with_invalid_profile() {
+        let profile = MachineProfile {
+            register_bits: 16,
+            ..MachineProfile::default()
+        };
+        CpuEmulator::with_profile(Register::new(), Ports::new(0, 0), Rom::new(vec![]), profile);
+    }
+
+    #[test]
+    fn test_jmp_to_self_halts() {
+        // out 0001, jmp 0001
+        let rom = Rom::new(vec![0b10110001, 0b11110001, 0b10110010]);
+        let emu = CpuEmulator::with(Register::new(), Ports::new(0b0000, 0b0000), rom);
+        assert!(!emu.does_halt());
+        emu.exec().unwrap();
+
+        assert!(emu.does_halt());
+        assert_eq!(emu.register.borrow().pc(), 1);
+        assert_eq!(emu.output(), 1);
+
+        // 止めないプロファイルでは実機と同じく同じジャンプを繰り返す
+        let rom = Rom::new(vec![0b10110001, 0b11110001, 0b10110010]);
+        let emu = CpuEmulator::with_profile(
+            Register::new(),
+            Ports::new(0b0000, 0b0000),
+            rom,
+            MachineProfile {
+                halt_on_self_jump: false,
+                ..MachineProfile::default()
+            },
+        );
+        for _ in 0..3 {
+            emu.step().unwrap();
+            assert!(!emu.does_halt());
+        }
+        assert_eq!((emu.register.borrow().pc(), emu.cycles()), (1, 3));
+    }
+
+    #[test]
+    fn test_multiple_ports() {
+        // in A 0010, mov B A, out B 0011, out B
+        let rom = Rom::new(vec![0b00100010, 0b01000000, 0b10010011, 0b10010000]);
+        let mut emu = CpuEmulator::with(Register::new(), Ports::new(0b0001, 0b0000), rom);
+        emu.set_quiet(true);
+        emu.set_mode(Mode::Extended(Extensions {
+            ports: 4,
+            ..Extensions::default()
+        }));
+        assert_eq!(emu.port_count(), 4);
+        emu.set_input_port(2, 0b0110).unwrap();
+        assert!(emu.set_input_port(4, 0b0110).is_err());
+        assert!(emu.set_input_port(2, 0b10000).is_err());
+
+        emu.exec().unwrap();
+        assert_eq!(emu.output_port(3), Some(0b0110));
+        assert_eq!(emu.output(), 0b0110);
+        assert_eq!(emu.output_port(4), None);
+        // 履歴に残るのはポート0への出力だけ
+        assert_eq!(emu.output_history(), vec![(3, 0b0110)]);
+
+        // 存在しないポートはエラー
+        emu.reset();
+        assert_eq!(emu.input_port(2), Some(0b0110));
+        emu.set_mode(Mode::Extended(Extensions::default()));
+        assert!(emu.step().is_err());
+    }
+
+    #[test]
+    fn test_load_store() {
+        // mov B 0011, mov A 0101, st [B] A, mov A 0000, ld A [B], mov B 1111, st [B] A, ld A [B]
+        let program = vec![
+            0b01110011, 0b00110101, 0b10100011, 0b00110000, 0b10100010, 0b01111111, 0b10100011,
+            0b10100010,
+        ];
+        let rom = Rom::new(program.clone());
+        let mut emu = CpuEmulator::with(Register::new(), Ports::new(0b1001, 0b0000), rom);
+        emu.set_quiet(true);
+        // 標準モードでは未定義のopcode
+        assert!(emu.exec().is_err());
+
+        emu.reset();
+        emu.set_mode(Mode::Extended(Extensions::default()));
+        for _ in 0..5 {
+            emu.step().unwrap();
+        }
+        // RAMに書いた値が読める
+        assert_eq!(emu.register().register_a(), 0b0101);
+        emu.exec().unwrap();
+        // 0xf は出力ポートと入力ポート
+        assert_eq!(emu.output_history(), vec![(6, 0b0101)]);
+        assert_eq!(emu.register().register_a(), 0b1001);
+
+        // 読むたびに1ずつ増える装置を0x3に割り当てる
+        struct Counter(u8);
+        impl Mmio for Counter {
+            fn read(&mut self, _offset: u8) -> u8 {
+                self.0 += 1;
+                self.0
+            }
+            fn write(&mut self, _offset: u8, _value: u8) {}
+        }
+        let mut emu = CpuEmulator::with(Register::new(), Ports::new(0, 0), Rom::new(program));
+        emu.set_quiet(true);
+        emu.set_mode(Mode::Extended(Extensions::default()));
+        emu.map_device(0x3..=0x3, Box::new(Counter(0)));
+        for _ in 0..5 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.register().register_a(), 1);
+    }
+
+    #[test]
+    fn test_port_bits_ignored_in_standard_mode() {
+        // in A 0010
+        let rom = Rom::new(vec![0b00100010]);
+        let mut emu = CpuEmulator::with(Register::new(), Ports::new(0b0101, 0b0000), rom);
+        emu.set_quiet(true);
+        emu.exec().unwrap();
+        assert_eq!(emu.register().register_a(), 0b0101);
+        assert_eq!(emu.port_count(), 1);
+    }
+
+    #[test]
+    fn test_interrupt_on_rising_edge() {
+        // 0: add A 0001, 1: jmp 0000, 2: out 1111 (ベクタ)
+        let rom = Rom::new(vec![0b00000001, 0b11110000, 0b10111111]);
+        let mut emu = CpuEmulator::with(Register::new(), Ports::new(0b0000, 0b0000), rom);
+        emu.set_mode(Mode::Extended(Extensions {
+            interrupt: Some(Interrupt::new(2, 2)),
+            ..Extensions::default()
+        }));
+
+        emu.step().unwrap();
+        // 監視していないビットでは割り込まない
+        emu.poke(PokeTarget::Input, 0b0011).unwrap();
+        emu.step().unwrap();
+        assert_eq!(emu.register().pc(), 0);
+
+        emu.poke(PokeTarget::Input, 0b0111).unwrap();
+        emu.step().unwrap();
+        assert_eq!(emu.output(), 0b1111);
+        assert_eq!(emu.register().register_b(), 0);
+        assert_eq!(emu.register().pc(), 3);
+
+        // 立ち上がりでなければもう一度は割り込まない
+        emu.reset_with(Register::new(), Ports::new(0b0111, 0));
+        emu.step().unwrap();
+        assert_eq!(emu.register().pc(), 1);
+    }
+
+    #[test]
+    fn test_interrupt_saves_to_shadow() {
+        let rom = Rom::new(vec![0b00000001, 0b00000001, 0b00000001]);
+        let mut emu = CpuEmulator::with(Register::new(), Ports::new(0b0000, 0b0000), rom);
+        let interrupt = Interrupt::new(0, 0).save_to(SaveTarget::Shadow);
+        emu.set_mode(Mode::Extended(Extensions {
+            interrupt: Some(interrupt),
+            ..Extensions::default()
+        }));
+
+        emu.step().unwrap();
+        emu.step().unwrap();
+        emu.poke(PokeTarget::Input, 0b0001).unwrap();
+        emu.step().unwrap();
+        assert_eq!(emu.shadow_pc(), 2);
+        assert_eq!(emu.register().register_b(), 0);
+        assert_eq!(emu.register().pc(), 1);
+    }
+
+    #[test]
+    fn test_no_interrupt_in_standard_mode() {
+        let rom = Rom::new(vec![0b00000001, 0b00000001]);
+        let emu = CpuEmulator::with(Register::new(), Ports::new(0b0000, 0b0000), rom);
+        emu.poke(PokeTarget::Input, 0b1111).unwrap();
+        emu.step().unwrap();
+        assert_eq!(emu.register().pc(), 1);
+    }
+
+    #[test]
+    fn test_call_ret() {
+        // 0: call 0011, 1: out B, 2: jmp 0110, 3: mov B 0101, 4: call 0111, 5: ret, 7: ret
+        let rom = Rom::new(vec![
+            0b10000011, 0b10010000, 0b11110110, 0b01110101, 0b10000111, 0b10100000, 0b00000000,
+            0b10100000,
+        ]);
+        let mut emu = CpuEmulator::with(Register::new(), Ports::new(0b0000, 0b0000), rom);
+        emu.set_mode(Mode::Extended(Extensions::default()));
+        emu.step().unwrap();
+        emu.step().unwrap();
+        emu.step().unwrap();
+        assert_eq!(emu.register().pc(), 7);
+        assert_eq!(emu.stack().entries(), &[1, 5]);
+        emu.step().unwrap();
+        emu.step().unwrap();
+        assert_eq!(emu.register().pc(), 1);
+        assert!(emu.stack().is_empty());
+        emu.step().unwrap();
+        assert_eq!(emu.output(), 0b0101);
+    }
+
+    #[test]
+    fn test_sub_cmp() {
+        let extended = |rom: Vec<u8>, a: u8, b: u8| {
+            let mut register = Register::new();
+            register.set_register_a(a);
+            register.set_register_b(b);
+            let mut emu = CpuEmulator::with(register, Ports::new(0b0000, 0b0000), Rom::new(rom));
+            emu.set_mode(Mode::Extended(Extensions::default()));
+            emu
+        };
+
+        // sub A 0011
+        let emu = extended(vec![0b11000011], 5, 0);
+        emu.step().unwrap();
+        assert_eq!(emu.register().register_a(), 2);
+        assert_eq!(emu.register().carry_flag(), 0);
+
+        // sub B 0011 (ボローが出る)
+        let emu = extended(vec![0b11010011], 0, 1);
+        emu.step().unwrap();
+        assert_eq!(emu.register().register_b(), 0b1110);
+        assert_eq!(emu.register().carry_flag(), 1);
+
+        // cmp A B
+        let emu = extended(vec![0b10100001, 0b10100001], 3, 7);
+        emu.step().unwrap();
+        assert_eq!(emu.register().carry_flag(), 1);
+        assert_eq!(emu.register().register_a(), 3);
+        assert_eq!(emu.register().register_b(), 7);
+        emu.poke(PokeTarget::RegisterB, 3).unwrap();
+        emu.step().unwrap();
+        assert_eq!(emu.register().carry_flag(), 0);
+        assert_eq!(emu.register().pc(), 2);
+
+        // 標準モードでは未定義
+        let emu = CpuEmulator::with(
+            Register::new(),
+            Ports::new(0b0000, 0b0000),
+            Rom::new(vec![0b11000001]),
+        );
+        assert!(emu.step().is_err());
+    }
+
+    #[test]
+    fn test_call_ret_errors() {
+        let emu = CpuEmulator::with(
+            Register::new(),
+            Ports::new(0b0000, 0b0000),
+            Rom::new(vec![0b10000000]),
+        );
+        // 標準モードでは未定義
+        assert!(emu.step().is_err());
+
+        let mut emu = CpuEmulator::with(
+            Register::new(),
+            Ports::new(0b0000, 0b0000),
+            Rom::new(vec![0b10000000, 0b10100000]),
+        );
+        emu.set_mode(Mode::Extended(Extensions::default()));
+        for _ in 0..4 {
+            emu.step().unwrap();
+        }
+        assert!(emu.step().is_err());
+
+        emu.reset();
+        emu.poke(PokeTarget::Pc, 1).unwrap();
+        assert!(emu.step().is_err());
+    }
+
+    #[test]
+    fn test_jnc_taken() {
+        let rom = Rom::new(vec![0b11100010, 0b00110001, 0b01110010]);
+        let register = Register::new();
+        let port = Ports::new(0b0000, 0b0000);
+        let emu = CpuEmulator::with(register, port, rom);
+        let proceeded = emu.exec();
+
+        assert!(proceeded.is_ok());
+        assert_eq!(emu.register.borrow().register_a(), 0);
+        assert_eq!(emu.register.borrow().register_b(), 2);
+    }
+
+    #[test]
+    fn test_jnc_not_taken() {
+        let rom = Rom::new(vec![0b11100010, 0b00110001]);
+        let mut register = Register::new();
+        register.set_carry_flag(1);
+        let port = Ports::new(0b0000, 0b0000);
+        let emu = CpuEmulator::with(register, port, rom);
+        let proceeded = emu.exec();
+
+        assert!(proceeded.is_ok());
+        assert_eq!(emu.register.borrow().pc(), 2);
+        assert_eq!(emu.register.borrow().register_a(), 1);
+        assert_eq!(emu.register.borrow().carry_flag(), 0);
+    }
+
     #[test]
     fn test_port_in_a() {
         let rom = Rom::new(vec![0b00100000]);
         let register = Register::new();
-        let port = Port::new(0b0001, 0b0000);
+        let port = Ports::new(0b0001, 0b0000);
         let emu = CpuEmulator::with(register, port, rom);
         let proceeded = emu.exec();
 
@@ -315,11 +1593,46 @@ mod cpu_tests {
         assert_eq!(emu.register.borrow().carry_flag(), 0);
     }
 
+    #[test]
+    fn test_input_sampling() {
+        // 各ステップの前に入力ポートへ入れる値と、IN A で読めた値
+        let read = |sampling, debounce, inputs: &[u8]| {
+            let profile = MachineProfile {
+                input_sampling: sampling,
+                input_debounce: debounce,
+                ..MachineProfile::default()
+            };
+            let rom = Rom::new(vec![0b00100000; inputs.len()]);
+            let emu = CpuEmulator::with_profile(Register::new(), Ports::new(0, 0), rom, profile);
+            inputs
+                .iter()
+                .map(|&input| {
+                    emu.set_input_port(0, input).unwrap();
+                    emu.step().unwrap();
+                    emu.register().register_a()
+                })
+                .collect::<Vec<_>>()
+        };
+        let inputs = [5, 0, 5, 5, 5];
+        assert_eq!(read(InputSampling::Direct, 0, &inputs), vec![5, 0, 5, 5, 5]);
+        // クロックに同期させると1命令遅れて見える
+        assert_eq!(
+            read(InputSampling::Registered, 0, &inputs),
+            vec![0, 5, 0, 5, 5]
+        );
+        // 揺れているあいだは前の値のまま
+        assert_eq!(read(InputSampling::Direct, 2, &inputs), vec![0, 0, 0, 5, 5]);
+        assert_eq!(
+            read(InputSampling::Registered, 2, &inputs),
+            vec![0, 0, 0, 0, 5]
+        );
+    }
+
     #[test]
     fn test_port_in_b() {
         let rom = Rom::new(vec![0b01100000]);
         let register = Register::new();
-        let port = Port::new(0b0011, 0b0000);
+        let port = Ports::new(0b0011, 0b0000);
         let emu = CpuEmulator::with(register, port, rom);
         let proceeded = emu.exec();
 
@@ -333,12 +1646,12 @@ mod cpu_tests {
         let rom = Rom::new(vec![0b10010000]);
         let mut register = Register::new();
         register.set_register_b(0b0011);
-        let port = Port::new(0b0000, 0b0000);
+        let port = Ports::new(0b0000, 0b0000);
         let emu = CpuEmulator::with(register, port, rom);
         let proceeded = emu.exec();
 
         assert!(proceeded.is_ok());
-        assert_eq!(emu.port.borrow().output(), 0b0011);
+        assert_eq!(emu.ports.borrow().output(), 0b0011);
         assert_eq!(emu.register.borrow().carry_flag(), 0);
     }
 
@@ -346,12 +1659,524 @@ mod cpu_tests {
     fn test_port_out_im() {
         let rom = Rom::new(vec![0b10110011]);
         let register = Register::new();
-        let port = Port::new(0b0000, 0b0000);
+        let port = Ports::new(0b0000, 0b0000);
         let emu = CpuEmulator::with(register, port, rom);
         let proceeded = emu.exec();
 
         assert!(proceeded.is_ok());
-        assert_eq!(emu.port.borrow().output(), 0b0011);
+        assert_eq!(emu.ports.borrow().output(), 0b0011);
+        assert_eq!(emu.register.borrow().carry_flag(), 0);
+    }
+
+    #[test]
+    fn test_reset() {
+        let rom = Rom::new(vec![0b00110001, 0b01110010, 0b10110011, 0b00001111]);
+        let register = Register::new();
+        let port = Ports::new(0b0101, 0b0000);
+        let emu = CpuEmulator::with(register, port, rom);
+        assert!(emu.exec().is_ok());
+        assert_eq!(emu.register.borrow().carry_flag(), 1);
+
+        emu.reset();
+
+        assert_eq!(emu.register.borrow().register_a(), 0);
+        assert_eq!(emu.register.borrow().register_b(), 0);
+        assert_eq!(emu.register.borrow().pc(), 0);
         assert_eq!(emu.register.borrow().carry_flag(), 0);
+        assert_eq!(emu.ports.borrow().output(), 0b0000);
+        assert_eq!(emu.ports.borrow().input(), 0b0101);
+        assert_eq!(emu.rom.borrow().size(), 4);
+
+        // the program runs again from the beginning
+        assert!(emu.exec().is_ok());
+        assert_eq!(emu.register.borrow().register_b(), 2);
+        assert_eq!(emu.ports.borrow().output(), 0b0011);
+    }
+
+    #[test]
+    fn test_reset_with() {
+        let rom = Rom::new(vec![0b00100000]);
+        let register = Register::new();
+        let port = Ports::new(0b0001, 0b0000);
+        let emu = CpuEmulator::with(register, port, rom);
+        assert!(emu.exec().is_ok());
+        assert_eq!(emu.register.borrow().register_a(), 1);
+
+        let mut register = Register::new();
+        register.set_register_b(0b0111);
+        emu.reset_with(register, Ports::new(0b1000, 0b0000));
+
+        assert_eq!(emu.register.borrow().pc(), 0);
+        assert_eq!(emu.register.borrow().register_b(), 0b0111);
+        assert!(emu.exec().is_ok());
+        assert_eq!(emu.register.borrow().register_a(), 0b1000);
+    }
+
+    #[test]
+    fn test_step() {
+        let rom = Rom::new(vec![0b00110001, 0b01110010]);
+        let register = Register::new();
+        let port = Ports::new(0b0000, 0b0000);
+        let emu = CpuEmulator::with(register, port, rom);
+
+        assert!(emu.step().is_ok());
+        assert_eq!(emu.register.borrow().register_a(), 1);
+        assert_eq!(emu.register.borrow().register_b(), 0);
+        assert!(!emu.does_halt());
+
+        assert!(emu.step().is_ok());
+        assert_eq!(emu.register.borrow().register_b(), 2);
+        assert!(emu.does_halt());
+    }
+
+    #[test]
+    fn test_output_history() {
+        let rom = Rom::new(vec![
+            0b10110001, 0b00110010, 0b10110010, 0b01110100, 0b10010000,
+        ]);
+        let register = Register::new();
+        let port = Ports::new(0b0000, 0b0000);
+        let emu = CpuEmulator::with(register, port, rom);
+        assert!(emu.exec().is_ok());
+
+        assert_eq!(emu.cycles(), 5);
+        assert_eq!(
+            emu.output_history(),
+            vec![(0, 0b0001), (2, 0b0010), (4, 0b0100)]
+        );
+
+        emu.reset();
+        assert_eq!(emu.cycles(), 0);
+        assert!(emu.output_history().is_empty());
+    }
+
+    #[test]
+    fn test_poke() {
+        let rom = Rom::new(vec![0b00110001, 0b10010000]);
+        let register = Register::new();
+        let port = Ports::new(0b0000, 0b0000);
+        let emu = CpuEmulator::with(register, port, rom);
+
+        assert_eq!(emu.poke(PokeTarget::RegisterB, 0b0101).unwrap(), 0);
+        assert_eq!(emu.poke(PokeTarget::CarryFlag, 1).unwrap(), 0);
+        assert_eq!(emu.poke(PokeTarget::Input, 0b1111).unwrap(), 0);
+        assert_eq!(
+            emu.poke(PokeTarget::Rom(1), 0b10110111).unwrap(),
+            0b10010000
+        );
+        assert_eq!(emu.register().register_b(), 0b0101);
+        assert_eq!(emu.register().carry_flag(), 1);
+        assert_eq!(emu.input(), 0b1111);
+
+        assert!(emu.exec().is_ok());
+        assert_eq!(emu.output(), 0b0111);
+    }
+
+    #[test]
+    fn test_poke_out_of_range() {
+        let rom = Rom::new(vec![0b00110001]);
+        let register = Register::new();
+        let port = Ports::new(0b0000, 0b0000);
+        let emu = CpuEmulator::with(register, port, rom);
+
+        assert!(emu.poke(PokeTarget::RegisterA, 0b10000).is_err());
+        assert!(emu.poke(PokeTarget::CarryFlag, 2).is_err());
+        assert!(emu.poke(PokeTarget::Rom(1), 0).is_err());
+        assert_eq!(emu.register().register_a(), 0);
+    }
+
+    #[test]
+    fn test_timing_model() {
+        let rom = Rom::new(vec![0b00110001, 0b11110010, 0b10110001]);
+        let register = Register::new();
+        let port = Ports::new(0b0000, 0b0000);
+        let mut emu = CpuEmulator::with(register, port, rom);
+        emu.set_timing_model(Box::new(TableTiming::new(1).with(Opcode::Jmp, 3)));
+        assert!(emu.exec().is_ok());
+
+        let stats = emu.stats();
+        assert_eq!(stats.instructions, 3);
+        assert_eq!(stats.cycles, 5);
+        assert_eq!(emu.output_history(), vec![(4, 0b0001)]);
+    }
+
+    #[test]
+    fn test_branch_stats() {
+        // mov A 1100, add A 0001, jnc 0001, out 1111
+        let rom = Rom::new(vec![0b00111100, 0b00000001, 0b11100001, 0b10111111]);
+        let emu = CpuEmulator::with(Register::new(), Ports::new(0b0000, 0b0000), rom);
+        emu.exec().unwrap();
+
+        let branch = emu.stats().branches[&2];
+        assert_eq!((branch.taken, branch.not_taken), (3, 1));
+        assert_eq!(branch.to_string(), "taken 3/4 (75%)");
+        assert!(!branch.is_one_sided());
+
+        emu.reset();
+        assert!(emu.stats().branches.is_empty());
+    }
+
+    #[test]
+    fn test_book_profile() {
+        // add A 1111, add A 0001 (キャリーが立つ), mov B 0011, 未定義命令
+        let mut emu = CpuEmulator::with_profile(
+            Register::new(),
+            Ports::new(0b0000, 0b0000),
+            Rom::new(vec![0b00001111, 0b00000001, 0b01110011, 0b10000000]),
+            MachineProfile::td4_book(),
+        );
+        emu.set_quiet(true);
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        // 本の命令表どおりMOVではキャリーが変わらない
+        assert_eq!(emu.register().carry_flag(), 1);
+        // 未定義の命令は読み飛ばす
+        emu.step().unwrap();
+        assert_eq!(emu.register().pc(), 4);
+        assert_eq!(emu.stats().instructions, 4);
+
+        // 実機どおりならMOVでキャリーが落ちる
+        let emu = CpuEmulator::with(
+            Register::new(),
+            Ports::new(0b0000, 0b0000),
+            Rom::new(vec![0b00001111, 0b00000001, 0b01110011]),
+        );
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.register().carry_flag(), 0);
+    }
+
+    #[test]
+    fn test_jumps_keep_carry() {
+        // jmp 0001, jnc 0010, mov B 0011
+        let rom = vec![0b11110001, 0b11100010, 0b01110011];
+        let run = |profile: MachineProfile| {
+            let mut register = Register::new();
+            register.set_carry_flag(1);
+            let emu = CpuEmulator::with_profile(
+                register,
+                Ports::new(0b0000, 0b0000),
+                Rom::new(rom.clone()),
+                profile,
+            );
+            (0..3)
+                .map(|_| {
+                    emu.step().unwrap();
+                    emu.register().carry_flag()
+                })
+                .collect::<Vec<u8>>()
+        };
+        // 実機どおりならJMPでキャリーが落ちる
+        assert_eq!(run(MachineProfile::default()), vec![0, 0, 0]);
+        // ジャンプではキャリーが残り、MOVで落ちる
+        let profile = MachineProfile {
+            flag_model: FlagModel::JumpsKeepCarry,
+            ..MachineProfile::default()
+        };
+        assert_eq!(run(profile), vec![1, 1, 0]);
+    }
+
+    #[test]
+    fn test_fetch_beyond_rom() {
+        // add A 0001, add A 0010 の2バイトで、PCはROMの外の0x2から始める
+        let emulator = |fetch_beyond_rom: FetchBeyondRom| {
+            let mut register = Register::new();
+            register.set_pc(2);
+            CpuEmulator::with_profile(
+                register,
+                Ports::new(0b0000, 0b0000),
+                Rom::new(vec![0b00000001, 0b00000010]),
+                MachineProfile {
+                    fetch_beyond_rom,
+                    ..MachineProfile::default()
+                },
+            )
+        };
+
+        // 厳密モードの既定はエラー
+        assert_eq!(
+            MachineProfile::default().fetch_beyond_rom,
+            FetchBeyondRom::Error
+        );
+        let emu = emulator(FetchBeyondRom::Error);
+        assert!(emu.does_halt());
+        assert_eq!(
+            emu.step().unwrap_err().to_string(),
+            "PC 0x2 is past the end of the 2 byte ROM"
+        );
+
+        // 0を読んで add A 0000 として進む
+        let emu = emulator(FetchBeyondRom::ReturnZero);
+        emu.step().unwrap();
+        assert_eq!((emu.register().pc(), emu.register().register_a()), (3, 0));
+
+        // 何もせずに止まったまま
+        let emu = emulator(FetchBeyondRom::Halt);
+        assert!(emu.does_halt());
+        emu.step().unwrap();
+        assert_eq!(emu.register().pc(), 2);
+
+        // 0x2 は0x0に折り返して add A 0001 を実行し、PCも折り返した先から進む
+        let emu = emulator(FetchBeyondRom::Wrap);
+        assert!(!emu.does_halt());
+        emu.step().unwrap();
+        assert_eq!((emu.register().pc(), emu.register().register_a()), (1, 1));
+        emu.step().unwrap();
+        assert_eq!((emu.register().pc(), emu.register().register_a()), (0, 3));
+    }
+
+    #[test]
+    fn test_pc_wraps_at_pc_bits() {
+        // 4bitのPCは0xfの次に0へ戻る。5bitなら0x10へ進む
+        for (pc_bits, rom_size, next) in [(4, 16, 0x0), (5, 32, 0x10)] {
+            let profile = MachineProfile {
+                rom_size,
+                pc_bits,
+                ..MachineProfile::default()
+            };
+            let emu = CpuEmulator::with_profile(
+                Register::new(),
+                Ports::new(0b0000, 0b0000),
+                Rom::new(vec![0b00000001; rom_size]),
+                profile,
+            );
+            emu.poke(PokeTarget::Pc, 0xf).unwrap();
+            emu.step().unwrap();
+            assert_eq!(emu.register().pc(), next, "{} bits", pc_bits);
+        }
+
+        // CALL が積む戻り先も同じ幅で折り返す
+        let emu = CpuEmulator::with_profile(
+            Register::new(),
+            Ports::new(0b0000, 0b0000),
+            Rom::new(vec![0b10100000; 16]),
+            MachineProfile::td4_extended(),
+        );
+        emu.poke(PokeTarget::Pc, 0xf).unwrap();
+        emu.poke(PokeTarget::Rom(0xf), 0b10000101).unwrap();
+        emu.step().unwrap();
+        emu.step().unwrap();
+        assert_eq!(emu.register().pc(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_profile_rom_size() {
+        let profile = MachineProfile {
+            rom_size: 2,
+            pc_bits: 1,
+            ..MachineProfile::default()
+        };
+        CpuEmulator::with_profile(
+            Register::new(),
+            Ports::new(0b0000, 0b0000),
+            Rom::new(vec![0; 3]),
+            profile,
+        );
+    }
+
+    #[test]
+    fn test_tracer() {
+        // add A 0001 を3回, out 0101
+        let mut emu = CpuEmulator::with(
+            Register::new(),
+            Ports::new(0b0000, 0b0000),
+            Rom::new(vec![0b00000001, 0b00000001, 0b00000001, 0b10110101]),
+        );
+        emu.set_quiet(true);
+        emu.set_tracer(Tracer::new(TracerConfig {
+            capacity: Some(2),
+            ..TracerConfig::default()
+        }));
+        emu.exec().unwrap();
+
+        let tracer = emu.take_tracer().unwrap();
+        let records = tracer.records();
+        assert_eq!(tracer.dropped(), 2);
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].cycle, records[0].pc), (2, 2));
+        assert_eq!(records[0].register.register_a(), 3);
+        assert_eq!(records[1].instruction, 0b10110101);
+        assert_eq!(records[1].output, 0b0101);
+        assert!(emu.take_tracer().is_none());
+    }
+
+    // 命令表から計算した1命令あとの (A, B, キャリー, PC, 出力)。エラーで止まるべきなら None
+    // 入出力ポートは1つ、ROMは同じ1バイトで埋めてあり、RAMとスタックは空とする
+    fn expected_step(
+        profile: &MachineProfile,
+        data: u8,
+        start: &Register,
+        input: u8,
+    ) -> Option<(u8, u8, u8, u8, u8)> {
+        let (a, b, carry, pc) = (
+            start.register_a(),
+            start.register_b(),
+            start.carry_flag(),
+            start.pc(),
+        );
+        let extended = profile.mode.is_extended();
+        let keep = |jump: bool| match profile.flag_model.keeps_carry(jump) {
+            true => carry,
+            false => 0,
+        };
+        let next = (pc + 1) & profile.pc_mask();
+        let instruction = match Instruction::decode(data) {
+            Some(instruction) if extended || !instruction.opcode().is_extended() => instruction,
+            _ => {
+                return (profile.undefined_opcode_policy == UndefinedOpcodePolicy::Nop)
+                    .then_some((a, b, carry, next, 0))
+            }
+        };
+        // 標準モードではポート番号を無視してポート0を使う
+        if extended && instruction.port().unwrap_or(0) != 0 {
+            return None;
+        }
+        let sum = |value: u8, im: u8| ((value + im) & 0x0f, (value + im > 0x0f) as u8);
+        let difference = |value: u8, im: u8| (value.wrapping_sub(im) & 0x0f, (value < im) as u8);
+        Some(match instruction {
+            Instruction::Mov { reg: Reg::A, im } => (im, b, keep(false), next, 0),
+            Instruction::Mov { reg: Reg::B, im } => (a, im, keep(false), next, 0),
+            Instruction::MovAB => (b, b, keep(false), next, 0),
+            Instruction::MovBA => (a, a, keep(false), next, 0),
+            Instruction::Add { reg: Reg::A, im } => {
+                let (a, carry) = sum(a, im);
+                (a, b, carry, next, 0)
+            }
+            Instruction::Add { reg: Reg::B, im } => {
+                let (b, carry) = sum(b, im);
+                (a, b, carry, next, 0)
+            }
+            Instruction::In { reg: Reg::A, .. } => (input, b, keep(false), next, 0),
+            Instruction::In { reg: Reg::B, .. } => (a, input, keep(false), next, 0),
+            Instruction::OutIm { im } => (a, b, keep(false), next, im),
+            Instruction::OutB { .. } => (a, b, keep(false), next, b),
+            Instruction::Jmp { im } | Instruction::Call { im } => (a, b, keep(true), im, 0),
+            Instruction::Jnc { im } => {
+                let pc = if carry == 0 { im } else { next };
+                (a, b, keep(true), pc, 0)
+            }
+            Instruction::Ret => return None,
+            Instruction::Sub { reg: Reg::A, im } => {
+                let (a, carry) = difference(a, im);
+                (a, b, carry, next, 0)
+            }
+            Instruction::Sub { reg: Reg::B, im } => {
+                let (b, carry) = difference(b, im);
+                (a, b, carry, next, 0)
+            }
+            Instruction::Cmp => (a, b, difference(a, b).1, next, 0),
+            // アドレス 0xf はポート0、ほかは空のRAM
+            Instruction::Ld => {
+                let a = if b == PORTS_ADDRESS { input } else { 0 };
+                (a, b, keep(false), next, 0)
+            }
+            Instruction::St => {
+                let output = if b == PORTS_ADDRESS { a } else { 0 };
+                (a, b, keep(false), next, output)
+            }
+            Instruction::Swap => (b, a, keep(false), next, 0),
+        })
+    }
+
+    // 全てのバイトを、全てのA, B, キャリーから1命令実行して命令表と比べる
+    // PCと入力ポートもA, Bから決めて0..16を一通り試す
+    #[test]
+    fn test_exhaustive_semantics() {
+        let profiles = [
+            MachineProfile::td4_strict(),
+            MachineProfile::td4_book(),
+            MachineProfile::td4_extended(),
+            MachineProfile {
+                flag_model: FlagModel::JumpsKeepCarry,
+                ..MachineProfile::td4_extended()
+            },
+        ];
+        for profile in profiles {
+            for data in 0..=0xff {
+                let rom = Rom::new(vec![data; 16]);
+                let mut emu = CpuEmulator::with_profile(
+                    Register::new(),
+                    Ports::new(0, 0),
+                    rom,
+                    profile.clone(),
+                );
+                emu.set_quiet(true);
+                for (a, b, carry) in (0..16)
+                    .flat_map(|a| (0..16).flat_map(move |b| (0..2).map(move |carry| (a, b, carry))))
+                {
+                    let mut start = Register::new();
+                    start.set_register_a(a);
+                    start.set_register_b(b);
+                    start.set_carry_flag(carry);
+                    start.set_pc(a ^ b);
+                    let input = (a + b + carry) & 0x0f;
+                    emu.reset_with(start.clone(), Ports::new(input, 0));
+
+                    let case = format!(
+                        "{} {:08b} from a={} b={} carry={} pc={}",
+                        profile.name,
+                        data,
+                        a,
+                        b,
+                        carry,
+                        start.pc()
+                    );
+                    let expected = expected_step(&profile, data, &start, input);
+                    match (emu.step(), expected) {
+                        (Ok(()), Some((a, b, carry, pc, output))) => {
+                            let register = emu.register();
+                            assert_eq!(
+                                (
+                                    register.register_a(),
+                                    register.register_b(),
+                                    register.carry_flag(),
+                                    register.pc(),
+                                    emu.output()
+                                ),
+                                (a, b, carry, pc, output),
+                                "{}",
+                                case
+                            );
+                        }
+                        (Err(_), None) => (),
+                        (result, expected) => {
+                            panic!("{}: got {:?}, expected {:?}", case, result, expected)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    fn test_invariant_violation() {
+        use crate::error::ErrorKind;
+
+        // 2bitのレジスタに mov A 1111 の即値はそのままでは入らない
+        let profile = MachineProfile {
+            register_bits: 2,
+            ..MachineProfile::td4_strict()
+        };
+        let rom = Rom::new(vec![0b00110001, 0b00111111]);
+        let mut emu = CpuEmulator::with_profile(Register::new(), Ports::new(0, 0), rom, profile);
+        emu.set_quiet(true);
+        emu.step().unwrap();
+
+        let err = emu.step().unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ErrorKind::InvariantViolation {
+                cycle: 1,
+                instruction: 0b00111111
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "invariant violated at cycle 1 after 00111111: register A is 15 but it has only 2 bits"
+        );
     }
 }
@@ -0,0 +1,248 @@
+use crate::batch;
+use crate::emulator::CpuEmulator;
+use crate::port::Ports;
+use crate::profile::MachineProfile;
+use crate::register::Register;
+use crate::rom::Rom;
+use std::fmt;
+
+// 2つのプログラムが同じ出力をするかを入力の全ての値で確かめる (td4emu equiv)
+// プログラムを書き換えたり短くしたりしたあとに、動作が変わっていないことを確かめるのに使う
+#[derive(Debug, PartialEq, Clone)]
+pub struct EquivConfig {
+    pub profile: MachineProfile,
+    // 1回の実行で使ってよいサイクル数。止まらないプログラムはここまでの出力を比べる
+    pub max_cycles: usize,
+    // trueならレジスタA, Bの初期値も全て試す
+    pub registers: bool,
+}
+
+impl Default for EquivConfig {
+    fn default() -> Self {
+        EquivConfig {
+            profile: MachineProfile::default(),
+            max_cycles: 1000,
+            registers: false,
+        }
+    }
+}
+
+// 実行を始めるときの入力ポートとレジスタの値
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Case {
+    pub input: u8,
+    pub a: u8,
+    pub b: u8,
+}
+
+// OUT命令で書き込んだ値の並びと、実行がどう終わったか
+#[derive(Debug, PartialEq, Clone)]
+pub struct Outcome {
+    pub outputs: Vec<u8>,
+    pub end: End,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum End {
+    Halted,
+    // max_cycles までに止まらなかった
+    Running,
+    Fault(String),
+}
+
+impl Outcome {
+    // 止まらなかったほうは続きを出力するかもしれないので、短いほうが長いほうの先頭と同じならよい
+    pub fn matches(&self, other: &Outcome) -> bool {
+        match (&self.end, &other.end) {
+            (End::Running, End::Running) => {
+                let length = self.outputs.len().min(other.outputs.len());
+                self.outputs[..length] == other.outputs[..length]
+            }
+            _ => self == other,
+        }
+    }
+}
+
+// 出力が最初に食い違ったケース
+#[derive(Debug, PartialEq, Clone)]
+pub struct Counterexample {
+    pub case: Case,
+    pub left: Outcome,
+    pub right: Outcome,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct EquivResult {
+    // 試したケースの数
+    pub cases: usize,
+    pub counterexample: Option<Counterexample>,
+}
+
+impl EquivResult {
+    pub fn is_equivalent(&self) -> bool {
+        self.counterexample.is_none()
+    }
+}
+
+// 全てのケースで left と right を実行して出力を比べる。食い違ったケースのうち最初のものを返す
+pub fn check(left: &[u8], right: &[u8], config: &EquivConfig) -> EquivResult {
+    let mask = config.profile.register_mask();
+    let registers = if config.registers { mask } else { 0 };
+    let mut cases = Vec::new();
+    for input in 0..=mask {
+        for a in 0..=registers {
+            for b in 0..=registers {
+                cases.push(Case { input, a, b });
+            }
+        }
+    }
+    let total = cases.len();
+    // 最初に食い違ったケースで打ち切り、cases はそこまでに調べた数にする
+    let found = batch::find_map_first(cases, |case| {
+        let (left, right) = (run(left, case, config), run(right, case, config));
+        (!left.matches(&right)).then_some(Counterexample { case, left, right })
+    });
+    match found {
+        Some((index, counterexample)) => EquivResult {
+            cases: index + 1,
+            counterexample: Some(counterexample),
+        },
+        None => EquivResult {
+            cases: total,
+            counterexample: None,
+        },
+    }
+}
+
+pub fn run(rom: &[u8], case: Case, config: &EquivConfig) -> Outcome {
+    let mut register = Register::new();
+    register.set_register_a(case.a);
+    register.set_register_b(case.b);
+    run_from(rom, register, case.input, config)
+}
+
+// register の状態から実行する。キャリーなど Case にない値も決められる
+pub fn run_from(rom: &[u8], register: Register, input: u8, config: &EquivConfig) -> Outcome {
+    let mut emulator = CpuEmulator::with_profile(
+        register,
+        Ports::new(input, 0),
+        Rom::new(rom.to_vec()),
+        config.profile.clone(),
+    );
+    emulator.set_quiet(true);
+
+    let mut end = End::Halted;
+    while !emulator.does_halt() {
+        if emulator.cycles() >= config.max_cycles {
+            end = End::Running;
+            break;
+        }
+        if let Err(err) = emulator.step() {
+            end = End::Fault(err.to_string());
+            break;
+        }
+    }
+    Outcome {
+        outputs: emulator
+            .output_history()
+            .into_iter()
+            .map(|(_, output)| output)
+            .collect(),
+        end,
+    }
+}
+
+impl fmt::Display for Case {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "input {:04b}, A {:04b}, B {:04b}",
+            self.input, self.a, self.b
+        )
+    }
+}
+
+// "out 0011 0100, halted" の形
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let outputs: Vec<String> = self
+            .outputs
+            .iter()
+            .map(|output| format!("{:04b}", output))
+            .collect();
+        match outputs.is_empty() {
+            true => write!(f, "no out")?,
+            false => write!(f, "out {}", outputs.join(" "))?,
+        }
+        match &self.end {
+            End::Halted => write!(f, ", halted"),
+            End::Running => write!(f, ", still running"),
+            End::Fault(message) => write!(f, ", {}", message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod equiv_tests {
+    use crate::compiler::assemble;
+    use crate::equiv::{check, Case, End, EquivConfig};
+
+    #[test]
+    fn test_equivalent() {
+        // 3を足すのと1を3回足すのは同じ
+        let left = assemble("in A\nadd A 0011\nmov B A\nout B").unwrap();
+        let right = assemble("in B\nadd B 0001\nadd B 0001\nadd B 0001\nout B").unwrap();
+        let result = check(&left, &right, &EquivConfig::default());
+        assert!(result.is_equivalent());
+        assert_eq!(result.cases, 16);
+
+        // 止まらないプログラムは max_cycles までの出力を比べる
+        let left = assemble("out 0001\nout 0010\njmp 0000").unwrap();
+        let right = assemble("out 0001\nadd A 0000\nout 0010\njmp 0000").unwrap();
+        assert!(check(&left, &right, &EquivConfig::default()).is_equivalent());
+    }
+
+    #[test]
+    fn test_counterexample() {
+        // B の初期値を使ってしまう
+        let left = assemble("in A\nmov B A\nout B").unwrap();
+        let right = assemble("in A\nadd B 0000\nout B").unwrap();
+        let config = EquivConfig::default();
+        let result = check(&left, &right, &config);
+        let counterexample = result.counterexample.unwrap();
+        assert_eq!(
+            counterexample.case,
+            Case {
+                input: 1,
+                a: 0,
+                b: 0
+            }
+        );
+        assert_eq!(counterexample.left.to_string(), "out 0001, halted");
+        assert_eq!(counterexample.right.to_string(), "out 0000, halted");
+
+        // レジスタの初期値を変えると違いが出る
+        let left = assemble("out B").unwrap();
+        let right = assemble("out 0000").unwrap();
+        assert!(check(&left, &right, &config).is_equivalent());
+        let config = EquivConfig {
+            registers: true,
+            ..config
+        };
+        let result = check(&left, &right, &config);
+        assert_eq!(result.cases, 2);
+        assert_eq!(
+            result.counterexample.unwrap().case,
+            Case {
+                input: 0,
+                a: 0,
+                b: 1
+            }
+        );
+
+        // 止まるものと止まらないもの
+        let right = assemble("out B\njmp 0000").unwrap();
+        let result = check(&left, &right, &EquivConfig::default());
+        assert_eq!(result.counterexample.unwrap().right.end, End::Running);
+    }
+}
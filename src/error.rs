@@ -1,12 +1,46 @@
+use std::fmt;
+
+// エラーの種類。呼び出し側で区別したいものだけを分ける
+#[derive(Debug, PartialEq, Clone)]
+pub enum ErrorKind {
+    Other,
+    // debug フィーチャーの自己検査で見つかった、ありえない状態 (エミュレータのバグ)
+    InvariantViolation { cycle: usize, instruction: u8 },
+}
+
 #[derive(Debug)]
 pub struct EmulatorErr {
     msg: String,
+    kind: ErrorKind,
 }
 
 impl EmulatorErr {
     pub fn new(msg: &str) -> Self {
         Self {
             msg: msg.to_string(),
+            kind: ErrorKind::Other,
+        }
+    }
+
+    pub fn invariant_violation(cycle: usize, instruction: u8, msg: &str) -> Self {
+        Self {
+            msg: format!(
+                "invariant violated at cycle {} after {:08b}: {}",
+                cycle, instruction, msg
+            ),
+            kind: ErrorKind::InvariantViolation { cycle, instruction },
         }
     }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+}
+
+impl fmt::Display for EmulatorErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
 }
+
+impl std::error::Error for EmulatorErr {}
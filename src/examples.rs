@@ -0,0 +1,133 @@
+// クレートに埋め込んだTD4のサンプルプログラム集
+pub struct Example {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub source: &'static str,
+}
+
+const EXAMPLES: [Example; 7] = [
+    Example {
+        name: "simple_calc",
+        description: "Add 1 to 1 and output the result",
+        source: include_str!("../example/simple_calc.sasm"),
+    },
+    Example {
+        name: "adder",
+        description: "Add 3 to the input port value and output it",
+        source: include_str!("../example/adder.sasm"),
+    },
+    Example {
+        name: "counter",
+        description: "Count up from 0 to 15 on the output port",
+        source: include_str!("../example/counter.sasm"),
+    },
+    Example {
+        name: "flashing_led",
+        description: "Flash the LEDs from both ends forever",
+        source: include_str!("../example/flashing_led.sasm"),
+    },
+    Example {
+        name: "knight_rider",
+        description: "Sweep a single LED back and forth forever",
+        source: include_str!("../example/knight_rider.sasm"),
+    },
+    Example {
+        name: "ramen_timer",
+        description: "Ramen timer from the book (blinks when the time is up)",
+        source: include_str!("../example/ramen_timer.sasm"),
+    },
+    Example {
+        name: "subroutine",
+        description: "Blink twice using a CALL/RET subroutine (runs in extended mode)",
+        source: include_str!("../example/subroutine.sasm"),
+    },
+];
+
+pub fn all() -> &'static [Example] {
+    &EXAMPLES
+}
+
+pub fn find(name: &str) -> Option<&'static Example> {
+    EXAMPLES.iter().find(|example| example.name == name)
+}
+
+#[cfg(test)]
+mod examples_tests {
+    use crate::compiler::assemble;
+    use crate::examples;
+    use crate::machine::Machine;
+    use crate::profile::MachineProfile;
+    use crate::testing::{run_program, TestConfig};
+
+    #[test]
+    fn test_all_examples_assemble() {
+        for example in examples::all() {
+            let program = assemble(example.source).unwrap();
+            assert!(program.len() <= 16, "{} is too large", example.name);
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        assert!(examples::find("knight_rider").is_some());
+        assert!(examples::find("unknown").is_none());
+    }
+
+    #[test]
+    fn test_counter() {
+        let source = examples::find("counter").unwrap().source;
+        run_program(source, TestConfig::default())
+            .unwrap()
+            .assert_output_sequence(&(0..16).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_adder() {
+        let source = examples::find("adder").unwrap().source;
+        run_program(source, TestConfig::with_input(0b0100))
+            .unwrap()
+            .assert_output_sequence(&[0b0111])
+            .assert_halts_within(4);
+    }
+
+    #[test]
+    fn test_subroutine() {
+        let source = examples::find("subroutine").unwrap().source;
+        let config = TestConfig {
+            profile: MachineProfile::td4_extended(),
+            ..TestConfig::default()
+        };
+        run_program(source, config)
+            .unwrap()
+            .assert_output_sequence(&[0b0101, 0, 0b1010, 0])
+            .assert_halts_within(12);
+    }
+
+    // 無限に続くLEDのパターンは2周分を確かめる
+    fn expect_led_pattern(name: &str, pattern: &[u8]) {
+        let mut machine = Machine::new(MachineProfile::default());
+        machine.set_quiet(true);
+        machine
+            .load_source(examples::find(name).unwrap().source)
+            .unwrap();
+        machine.expect_outputs(pattern).expect_outputs(pattern);
+    }
+
+    #[test]
+    fn test_knight_rider() {
+        expect_led_pattern(
+            "knight_rider",
+            &[0b0001, 0b0010, 0b0100, 0b1000, 0b0100, 0b0010],
+        );
+    }
+
+    #[test]
+    fn test_flashing_led() {
+        expect_led_pattern(
+            "flashing_led",
+            &[
+                0b0011, 0b0110, 0b1100, 0b1000, 0b1000, 0b1100, 0b0110, 0b0011, 0b0001,
+            ],
+        );
+    }
+}
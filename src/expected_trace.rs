@@ -0,0 +1,309 @@
+use crate::debugger::Snapshot;
+use crate::disassembler::disassemble;
+use crate::emulator::CpuEmulator;
+use crate::error::EmulatorErr;
+use crate::port::Ports;
+use crate::profile::MachineProfile;
+use crate::register::Register;
+use crate::rom::Rom;
+use std::fmt;
+
+// 手でトレースした実行の期待値を書いたCSV
+// ヘッダーは cycle,pc,a,b,c,out のうち使う列だけ並べる (大文字でもよい)
+// 値は本の表どおり2進数で書く (0x, 0b も使える)。空欄か - の値は確かめない
+// cycle 列がなければ、行ごとに0サイクル目 (リセット直後) から1つずつ進める
+#[derive(Debug, PartialEq, Clone)]
+pub struct ExpectedTrace {
+    pub rows: Vec<ExpectedState>,
+}
+
+// そのサイクルが終わった直後にあるはずの状態
+#[derive(Debug, PartialEq, Clone)]
+pub struct ExpectedState {
+    // CSVの行番号
+    pub line: usize,
+    pub cycle: usize,
+    pub pc: Option<u8>,
+    pub a: Option<u8>,
+    pub b: Option<u8>,
+    pub carry: Option<u8>,
+    pub out: Option<u8>,
+}
+
+// エミュレータの実際の状態
+#[derive(Debug, PartialEq, Clone)]
+pub struct ActualState {
+    pub state: Snapshot,
+    // 直前に実行した命令のアドレスと中身
+    pub last_instruction: Option<(u8, u8)>,
+}
+
+// 期待値と初めて食い違った状態
+#[derive(Debug, PartialEq, Clone)]
+pub struct Divergence {
+    pub expected: ExpectedState,
+    pub actual: ActualState,
+}
+
+fn parse_value(text: &str) -> Result<u8, String> {
+    let parsed = if let Some(hex) = text.strip_prefix("0x") {
+        u8::from_str_radix(hex, 16)
+    } else {
+        u8::from_str_radix(text.strip_prefix("0b").unwrap_or(text), 2)
+    };
+    parsed.map_err(|_| format!("invalid value {}", text))
+}
+
+impl ExpectedTrace {
+    pub fn from_csv(text: &str) -> Result<Self, EmulatorErr> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty());
+        let (_, header) = lines
+            .next()
+            .ok_or_else(|| EmulatorErr::new("expected trace is empty"))?;
+        let columns: Vec<String> = header
+            .split(',')
+            .map(|column| column.trim().to_lowercase())
+            .collect();
+        let column = |names: &[&str]| {
+            columns
+                .iter()
+                .position(|column| names.contains(&column.as_str()))
+        };
+        let cycle = column(&["cycle"]);
+        let pc = column(&["pc"]);
+        let a = column(&["a"]);
+        let b = column(&["b"]);
+        let carry = column(&["c", "carry"]);
+        let out = column(&["out"]);
+        if [pc, a, b, carry, out].iter().all(|column| column.is_none()) {
+            return Err(EmulatorErr::new(
+                "expected trace has none of the pc, a, b, c and out columns",
+            ));
+        }
+
+        let mut rows: Vec<ExpectedState> = Vec::new();
+        for (index, line) in lines {
+            let error = |message: &str| {
+                EmulatorErr::new(&format!("expected trace line {}: {}", index + 1, message))
+            };
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+            let field = |position: Option<usize>| match position {
+                Some(position) => match fields.get(position) {
+                    Some(&"") | Some(&"-") => Ok(None),
+                    Some(text) => parse_value(text).map(Some).map_err(|err| error(&err)),
+                    None => Err(error("missing column")),
+                },
+                None => Ok(None),
+            };
+
+            let next_cycle = rows.last().map_or(0, |row| row.cycle + 1);
+            let cycle = match cycle.map(|position| fields.get(position)) {
+                Some(Some(text)) => {
+                    let cycle: usize = text
+                        .parse()
+                        .map_err(|_| error(&format!("invalid cycle {}", text)))?;
+                    if cycle < next_cycle {
+                        return Err(error("cycles must increase"));
+                    }
+                    cycle
+                }
+                Some(None) => return Err(error("missing column")),
+                None => next_cycle,
+            };
+            rows.push(ExpectedState {
+                line: index + 1,
+                cycle,
+                pc: field(pc)?,
+                a: field(a)?,
+                b: field(b)?,
+                carry: field(carry)?,
+                out: field(out)?,
+            });
+        }
+        Ok(ExpectedTrace { rows })
+    }
+}
+
+impl ExpectedState {
+    fn matches(&self, actual: &Snapshot) -> bool {
+        [
+            (self.pc, actual.register.pc()),
+            (self.a, actual.register.register_a()),
+            (self.b, actual.register.register_b()),
+            (self.carry, actual.register.carry_flag()),
+            (self.out, actual.output),
+        ]
+        .iter()
+        .all(|(expected, actual)| expected.is_none_or(|expected| expected == *actual))
+    }
+}
+
+// プログラムを実行し、期待値の各行のサイクルで状態を比べる
+// 止まった後は状態が変わらないものとして比べ続ける
+pub fn verify(
+    rom: &[u8],
+    expected: &ExpectedTrace,
+    profile: &MachineProfile,
+    input: u8,
+) -> Result<Option<Divergence>, EmulatorErr> {
+    let mut emulator = CpuEmulator::with_profile(
+        Register::new(),
+        Ports::new(input, 0b0000),
+        Rom::new(rom.to_vec()),
+        profile.clone(),
+    );
+    emulator.set_quiet(true);
+    let mut last_instruction = None;
+
+    for row in &expected.rows {
+        while emulator.cycles() < row.cycle && !emulator.does_halt() {
+            let pc = emulator.register().pc();
+            last_instruction = Some((pc, rom.get(pc as usize).copied().unwrap_or(0)));
+            emulator.step()?;
+        }
+        let state = Snapshot::take(&emulator);
+        // 複数サイクルの命令があるとそのサイクルの終わりの状態がないこともある
+        if !row.matches(&state) || (state.cycles != row.cycle && !emulator.does_halt()) {
+            return Ok(Some(Divergence {
+                expected: row.clone(),
+                actual: ActualState {
+                    state,
+                    last_instruction,
+                },
+            }));
+        }
+    }
+    Ok(None)
+}
+
+//        expected  emulator
+// PC     0011      0100  *
+// A      0001      0001
+// ...
+// state  pc=0x4 a=0b0001 ...
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "First divergence at cycle {} (line {} of the expected trace)",
+            self.expected.cycle, self.expected.line
+        )?;
+        match self.actual.last_instruction {
+            Some((pc, data)) => writeln!(f, ", after {} at 0x{:x}", disassemble(data), pc)?,
+            None => writeln!(f, ", at reset")?,
+        }
+        let state = &self.actual.state;
+        if state.cycles > self.expected.cycle {
+            writeln!(
+                f,
+                "The instruction was still running: the emulator went on to cycle {}",
+                state.cycles
+            )?;
+        }
+        writeln!(f, "       expected  emulator")?;
+        let rows = [
+            ("PC", self.expected.pc, state.register.pc()),
+            ("A", self.expected.a, state.register.register_a()),
+            ("B", self.expected.b, state.register.register_b()),
+            ("C", self.expected.carry, state.register.carry_flag()),
+            ("OUT", self.expected.out, state.output),
+        ];
+        for (name, expected, actual) in rows {
+            let (expected, actual) = if name == "C" {
+                (expected.map(|value| value.to_string()), actual.to_string())
+            } else {
+                (
+                    expected.map(|value| format!("{:04b}", value)),
+                    format!("{:04b}", actual),
+                )
+            };
+            let mark = match &expected {
+                Some(expected) if *expected != actual => "  *",
+                _ => "",
+            };
+            writeln!(
+                f,
+                "{:<6} {:<9} {}{}",
+                name,
+                expected.as_deref().unwrap_or("-"),
+                actual,
+                mark
+            )?;
+        }
+        writeln!(f, "state  {}", state)
+    }
+}
+
+#[cfg(test)]
+mod expected_trace_tests {
+    use crate::compiler::assemble;
+    use crate::expected_trace::{verify, ExpectedTrace};
+    use crate::profile::MachineProfile;
+
+    // add A 0001 を繰り返して、桁あふれしたら out 1111
+    const SOURCE: &str = "add A 0001\njnc 0000\nout 1111";
+
+    #[test]
+    fn test_verify() {
+        let rom = assemble(SOURCE).unwrap();
+        let expected = ExpectedTrace::from_csv(
+            "PC,A,B,C,OUT,note
+0000,0000,0000,0,0000,reset
+0001,0001,0000,0,0000,add
+0000,0001,-,0,0000,jnc
+0001,0010,,,,
+",
+        )
+        .unwrap();
+        assert_eq!(expected.rows.len(), 4);
+        assert_eq!(expected.rows[3].cycle, 3);
+        assert_eq!(
+            verify(&rom, &expected, &MachineProfile::default(), 0).unwrap(),
+            None
+        );
+
+        // jnc はキャリーが立っていなければ飛ぶのに、手では次へ進めてしまった
+        let expected = ExpectedTrace::from_csv("cycle,pc,a\n0,0,0\n2,0010,0001").unwrap();
+        let divergence = verify(&rom, &expected, &MachineProfile::default(), 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(divergence.expected.line, 3);
+        assert_eq!(divergence.actual.state.register.pc(), 0);
+        assert_eq!(
+            divergence.to_string(),
+            "First divergence at cycle 2 (line 3 of the expected trace), after jnc 0000 at 0x1
+       expected  emulator
+PC     0010      0000  *
+A      0001      0001
+B      -         0000
+C      -         0
+OUT    -         0000
+state  pc=0x0 a=0b0001 b=0b0000 c=0 in=0b0000 out=0b0000 stack= shadow=0x0 cycles=2 rom=01,e0,bf
+"
+        );
+    }
+
+    #[test]
+    fn test_after_halt() {
+        // 止まった後は同じ状態が続く
+        let rom = assemble("out 0011\njmp 0001").unwrap();
+        let expected = ExpectedTrace::from_csv("cycle,pc,out\n1,1,11\n100,1,0011").unwrap();
+        assert_eq!(
+            verify(&rom, &expected, &MachineProfile::default(), 0).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_invalid_expected_trace() {
+        assert!(ExpectedTrace::from_csv("").is_err());
+        assert!(ExpectedTrace::from_csv("cycle,note\n0,reset").is_err());
+        let err = ExpectedTrace::from_csv("pc,a\n0,2").unwrap_err();
+        assert_eq!(err.to_string(), "expected trace line 2: invalid value 2");
+        assert!(ExpectedTrace::from_csv("cycle,a\n3,0\n2,0").is_err());
+        assert!(ExpectedTrace::from_csv("pc,a\n0").is_err());
+    }
+}
@@ -0,0 +1,263 @@
+use crate::disassembler::disassemble;
+use crate::isa::{self, CarryEffect, Location, PcEffect, Transfer};
+use crate::op::Opcode;
+use crate::plugin::Plugin;
+use crate::register::Register;
+use crate::tracer::TraceRecord;
+use std::io::Write;
+
+// 1命令で何が起きたかを英語の1文にする (td4emu run --explain)
+// 命令の意味は isa.rs の表から取り、値は実行前後のレジスタから読む
+//
+//     mov A 0011: register A set to 3 (0011), carry cleared; PC 0x2 -> 0x3
+pub fn explain(before: &Register, record: &TraceRecord) -> String {
+    let after = &record.register;
+    let name = disassemble(record.instruction);
+    let semantics = Opcode::decode(record.instruction)
+        .and_then(|(opcode, _)| isa::semantics(opcode).map(|semantics| (semantics, opcode)));
+    let Some((semantics, opcode)) = semantics else {
+        return format!(
+            "{}: not an instruction of the TD4, nothing changed; PC 0x{:x} -> 0x{:x}",
+            name,
+            record.pc,
+            after.pc()
+        );
+    };
+    let im = record.instruction & 0x0f;
+
+    let mut parts = Vec::new();
+    if let Some(transfer) = transfer(semantics.transfer, opcode, im, before, after) {
+        parts.push(transfer);
+    }
+    let carry = match (semantics.carry, before.carry_flag(), after.carry_flag()) {
+        (CarryEffect::Carry, _, 1) => Some("carry set (the result overflowed 4 bits)"),
+        (CarryEffect::Carry, _, _) => Some("carry cleared (no overflow)"),
+        (CarryEffect::Borrow, _, 1) => Some("carry set (it had to borrow)"),
+        (CarryEffect::Borrow, _, _) => Some("carry cleared (no borrow)"),
+        (CarryEffect::Unaffected, 1, 0) => Some("carry cleared"),
+        (CarryEffect::Unaffected, _, _) => None,
+    };
+    parts.extend(carry.map(str::to_string));
+
+    let pc = format!("PC 0x{:x} -> 0x{:x}", record.pc, after.pc());
+    let pc = match semantics.pc {
+        PcEffect::Next => pc,
+        PcEffect::Jump if after.pc() == record.pc => {
+            format!("jumped to itself, so the program has halted; {}", pc)
+        }
+        PcEffect::Jump => format!("jumped; {}", pc),
+        PcEffect::JumpIfNoCarry if before.carry_flag() == 0 => {
+            format!("carry was 0, so it jumped; {}", pc)
+        }
+        PcEffect::JumpIfNoCarry => format!("carry was 1, so it didn't jump; {}", pc),
+        PcEffect::Call => format!("called a subroutine, saving the return address; {}", pc),
+        PcEffect::Return => format!("returned from the subroutine; {}", pc),
+    };
+
+    if parts.is_empty() {
+        format!("{}: {}", name, pc)
+    } else {
+        format!("{}: {}; {}", name, parts.join(", "), pc)
+    }
+}
+
+// 値は 3 (0011) のように10進数と2進数で書く
+fn value(value: u8) -> String {
+    format!("{} ({:04b})", value, value)
+}
+
+fn transfer(
+    transfer: Transfer,
+    opcode: Opcode,
+    im: u8,
+    before: &Register,
+    after: &Register,
+) -> Option<String> {
+    let name = |location: Location| match location {
+        Location::A => "register A".to_string(),
+        Location::B => "register B".to_string(),
+        Location::Immediate => "the immediate".to_string(),
+        Location::Input => "the input port".to_string(),
+        // 標準モードの OUT B はポート番号を無視する
+        Location::Output if opcode == Opcode::OutB && im != 0 => format!("output port {}", im),
+        Location::Output => "the output port".to_string(),
+        Location::Memory => format!("memory at 0x{:x} (register B)", before.register_b()),
+    };
+    let read = |location: Location, register: &Register| match location {
+        Location::A => register.register_a(),
+        Location::B => register.register_b(),
+        _ => im,
+    };
+    match transfer {
+        Transfer::None => None,
+        Transfer::Move { to, from } => {
+            // 入力とメモリから読んだ値は、書き込んだ先のレジスタに残っている
+            let written = match from {
+                Location::Input | Location::Memory => read(to, after),
+                _ => read(from, before),
+            };
+            let source = match from {
+                Location::Immediate => String::new(),
+                from => format!(" from {}", name(from)),
+            };
+            Some(format!("{} set to {}{}", name(to), value(written), source))
+        }
+        Transfer::Add { to, from } => Some(format!(
+            "added {} to {}: {} -> {}",
+            read(from, before),
+            name(to),
+            read(to, before),
+            read(to, after)
+        )),
+        Transfer::Sub { to, from } => Some(format!(
+            "subtracted {} from {}: {} -> {}",
+            read(from, before),
+            name(to),
+            read(to, before),
+            read(to, after)
+        )),
+        Transfer::Compare { lhs, rhs } => Some(format!(
+            "compared {} ({}) with {} ({}) without changing them",
+            name(lhs),
+            read(lhs, before),
+            name(rhs),
+            read(rhs, before)
+        )),
+        Transfer::Exchange { lhs, rhs } => Some(format!(
+            "swapped {} and {}: now {} and {}",
+            name(lhs),
+            name(rhs),
+            read(lhs, after),
+            read(rhs, after)
+        )),
+    }
+}
+
+// 実行のたびに説明を1行ずつ書き出すプラグイン
+pub struct Explainer {
+    writer: Box<dyn Write + Send>,
+    register: Register,
+}
+
+impl Explainer {
+    // register は最初の命令を実行する前の状態
+    pub fn new(writer: Box<dyn Write + Send>, register: Register) -> Self {
+        Explainer { writer, register }
+    }
+}
+
+impl Plugin for Explainer {
+    fn name(&self) -> &str {
+        "explain"
+    }
+
+    fn on_trace(&mut self, record: &TraceRecord) {
+        let line = explain(&self.register, record);
+        // 書き出せなくても実行は止めない
+        let _ = writeln!(self.writer, "cycle {:>4}: {}", record.cycle, line);
+        self.register = record.register.clone();
+    }
+}
+
+#[cfg(test)]
+mod explain_tests {
+    use crate::explain::{explain, Explainer};
+    use crate::machine::Machine;
+    use crate::profile::MachineProfile;
+    use crate::register::Register;
+    use crate::tracer::TraceRecord;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    fn record(pc: u8, instruction: u8, register: &Register) -> TraceRecord {
+        TraceRecord {
+            cycle: 0,
+            pc,
+            instruction,
+            register: register.clone(),
+            output: 0,
+            flag_model: None,
+        }
+    }
+
+    #[test]
+    fn test_explain() {
+        let mut before = Register::new();
+        before.set_pc(2);
+        before.set_carry_flag(1);
+        let mut after = before.clone();
+        after.set_pc(3);
+        after.set_register_a(3);
+        after.set_carry_flag(0);
+        assert_eq!(
+            explain(&before, &record(2, 0b00110011, &after)),
+            "mov A 0011: register A set to 3 (0011), carry cleared; PC 0x2 -> 0x3"
+        );
+
+        // 15 + 1 で桁あふれ
+        before.set_register_a(15);
+        before.set_carry_flag(0);
+        after.set_register_a(0);
+        after.set_carry_flag(1);
+        assert_eq!(
+            explain(&before, &record(2, 0b00000001, &after)),
+            "add A 0001: added 1 to register A: 15 -> 0, \
+             carry set (the result overflowed 4 bits); PC 0x2 -> 0x3"
+        );
+
+        // キャリーが1なのでJNCは飛ばない
+        assert_eq!(
+            explain(
+                &after,
+                &record(3, 0b11100000, &{
+                    let mut next = after.clone();
+                    next.set_pc(4);
+                    next.set_carry_flag(0);
+                    next
+                })
+            ),
+            "jnc 0000: carry cleared; carry was 1, so it didn't jump; PC 0x3 -> 0x4"
+        );
+        assert_eq!(
+            explain(&before, &record(2, 0b11110010, &before)),
+            "jmp 0010: jumped to itself, so the program has halted; PC 0x2 -> 0x2"
+        );
+    }
+
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_explainer() {
+        let text = Arc::new(Mutex::new(Vec::new()));
+        let mut machine = Machine::new(MachineProfile::default());
+        machine.set_quiet(true);
+        machine
+            .add_plugin(Box::new(Explainer::new(
+                Box::new(Shared(text.clone())),
+                Register::new(),
+            )))
+            .unwrap();
+        machine
+            .load_source("in A\nmov B A\nout B\njmp 0011")
+            .unwrap();
+        machine.emulator().set_input_port(0, 0b0101).unwrap();
+        machine.run(None).unwrap();
+        let text = String::from_utf8(text.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            text,
+            "cycle    0: in A: register A set to 5 (0101) from the input port; PC 0x0 -> 0x1\n\
+             cycle    1: mov B A: register B set to 5 (0101) from register A; PC 0x1 -> 0x2\n\
+             cycle    2: out B: the output port set to 5 (0101) from register B; PC 0x2 -> 0x3\n"
+        );
+    }
+}
@@ -0,0 +1,303 @@
+use crate::error::EmulatorErr;
+use std::collections::HashMap;
+
+// 即値に書ける定数式を評価する
+// 整数 (10進, 0x, 0b), 文字リテラル 'A', 現在のアドレス $, 括弧, 単項 ~ -, + - << >> & ^ | に対応する
+// 演算子の優先順位はC言語と同じ
+pub fn evaluate(text: &str) -> Result<i64, EmulatorErr> {
+    evaluate_with(text, None, &HashMap::new())
+}
+
+// $ を現在の命令のアドレスとして評価する
+pub fn evaluate_at(text: &str, here: u8) -> Result<i64, EmulatorErr> {
+    evaluate_with(text, Some(here as i64), &HashMap::new())
+}
+
+// ラベルなどの名前を symbols の値として評価する
+pub fn evaluate_with_symbols(
+    text: &str,
+    here: u8,
+    symbols: &HashMap<String, i64>,
+) -> Result<i64, EmulatorErr> {
+    evaluate_with(text, Some(here as i64), symbols)
+}
+
+fn evaluate_with(
+    text: &str,
+    here: Option<i64>,
+    symbols: &HashMap<String, i64>,
+) -> Result<i64, EmulatorErr> {
+    let tokens = tokenize(text, here, symbols)?;
+    let mut evaluator = Evaluator { tokens, pos: 0 };
+    let value = evaluator.or()?;
+
+    if let Some(token) = evaluator.tokens.get(evaluator.pos) {
+        return Err(EmulatorErr::new(&format!(
+            "Unexpected {:?} in expression: {}",
+            token, text
+        )));
+    }
+    Ok(value)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum ExprToken {
+    Number(i64),
+    Op(&'static str),
+    LParen,
+    RParen,
+}
+
+const OPERATORS: [&str; 9] = ["<<", ">>", "&", "|", "^", "~", "+", "-", "*"];
+
+fn tokenize(
+    text: &str,
+    here: Option<i64>,
+    symbols: &HashMap<String, i64>,
+) -> Result<Vec<ExprToken>, EmulatorErr> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+        } else if c == '(' {
+            tokens.push(ExprToken::LParen);
+            pos += 1;
+        } else if c == ')' {
+            tokens.push(ExprToken::RParen);
+            pos += 1;
+        } else if c == '$' {
+            let address = here.ok_or_else(|| {
+                EmulatorErr::new(&format!("$ can't be used in this expression: {}", text))
+            })?;
+            tokens.push(ExprToken::Number(address));
+            pos += 1;
+        } else if c == '\'' {
+            // 'A' のような1文字のリテラル
+            match (chars.get(pos + 1), chars.get(pos + 2)) {
+                (Some(c), Some('\'')) => tokens.push(ExprToken::Number(*c as i64)),
+                _ => {
+                    return Err(EmulatorErr::new(&format!(
+                        "Invalid character literal in expression: {}",
+                        text
+                    )))
+                }
+            }
+            pos += 3;
+        } else if c.is_ascii_digit() {
+            let start = pos;
+            while pos < chars.len() && chars[pos].is_ascii_alphanumeric() {
+                pos += 1;
+            }
+            let literal: String = chars[start..pos].iter().collect();
+            tokens.push(ExprToken::Number(parse_integer(&literal)?));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = pos;
+            while pos < chars.len() && (chars[pos].is_ascii_alphanumeric() || chars[pos] == '_') {
+                pos += 1;
+            }
+            let name: String = chars[start..pos].iter().collect();
+            let value = symbols.get(&name).ok_or_else(|| {
+                EmulatorErr::new(&format!("Unknown name {} in expression: {}", name, text))
+            })?;
+            tokens.push(ExprToken::Number(*value));
+        } else {
+            let rest: String = chars[pos..].iter().collect();
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| {
+                    EmulatorErr::new(&format!("Unexpected '{}' in expression: {}", c, text))
+                })?;
+            tokens.push(ExprToken::Op(op));
+            pos += op.len();
+        }
+    }
+
+    Ok(tokens)
+}
+
+// 0x, 0b で始まる16進数, 2進数と10進数
+fn parse_integer(literal: &str) -> Result<i64, EmulatorErr> {
+    let parsed = if let Some(hex) = literal.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(bin) = literal.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2)
+    } else {
+        literal.parse::<i64>()
+    };
+    parsed.map_err(|_| EmulatorErr::new(&format!("Failed to parse number: {}", literal)))
+}
+
+struct Evaluator {
+    tokens: Vec<ExprToken>,
+    pos: usize,
+}
+
+impl Evaluator {
+    fn or(&mut self) -> Result<i64, EmulatorErr> {
+        let mut value = self.xor()?;
+        while self.consume("|") {
+            value |= self.xor()?;
+        }
+        Ok(value)
+    }
+
+    fn xor(&mut self) -> Result<i64, EmulatorErr> {
+        let mut value = self.and()?;
+        while self.consume("^") {
+            value ^= self.and()?;
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<i64, EmulatorErr> {
+        let mut value = self.shift()?;
+        while self.consume("&") {
+            value &= self.shift()?;
+        }
+        Ok(value)
+    }
+
+    fn shift(&mut self) -> Result<i64, EmulatorErr> {
+        let mut value = self.additive()?;
+        loop {
+            if self.consume("<<") {
+                let rhs = self.shift_amount()?;
+                value <<= rhs;
+            } else if self.consume(">>") {
+                let rhs = self.shift_amount()?;
+                value >>= rhs;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn shift_amount(&mut self) -> Result<i64, EmulatorErr> {
+        let amount = self.additive()?;
+        if !(0..32).contains(&amount) {
+            return Err(EmulatorErr::new(&format!(
+                "Shift amount {} is out of range",
+                amount
+            )));
+        }
+        Ok(amount)
+    }
+
+    fn additive(&mut self) -> Result<i64, EmulatorErr> {
+        let mut value = self.multiplicative()?;
+        loop {
+            if self.consume("+") {
+                value = value.wrapping_add(self.multiplicative()?);
+            } else if self.consume("-") {
+                value = value.wrapping_sub(self.multiplicative()?);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<i64, EmulatorErr> {
+        let mut value = self.unary()?;
+        while self.consume("*") {
+            value = value.wrapping_mul(self.unary()?);
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i64, EmulatorErr> {
+        if self.consume("~") {
+            Ok(!self.unary()?)
+        } else if self.consume("-") {
+            Ok(self.unary()?.wrapping_neg())
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, EmulatorErr> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(ExprToken::Number(value)) => Ok(value),
+            Some(ExprToken::LParen) => {
+                let value = self.or()?;
+                if self.tokens.get(self.pos) != Some(&ExprToken::RParen) {
+                    return Err(EmulatorErr::new("Missing ')' in expression"));
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            Some(token) => Err(EmulatorErr::new(&format!(
+                "Unexpected {:?} in expression",
+                token
+            ))),
+            None => Err(EmulatorErr::new("Unexpected end of expression")),
+        }
+    }
+
+    fn consume(&mut self, op: &str) -> bool {
+        if matches!(self.tokens.get(self.pos), Some(ExprToken::Op(current)) if *current == op) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod expr_tests {
+    use crate::expr::{evaluate, evaluate_at, evaluate_with_symbols};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_literals() {
+        assert_eq!(evaluate("5").unwrap(), 5);
+        assert_eq!(evaluate("0x0F").unwrap(), 15);
+        assert_eq!(evaluate("0b1010").unwrap(), 10);
+        assert_eq!(evaluate("'A'").unwrap(), 65);
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(evaluate("'A' & 0x0F").unwrap(), 1);
+        assert_eq!(evaluate("1 << 2").unwrap(), 4);
+        assert_eq!(evaluate("0b1000 >> 3").unwrap(), 1);
+        assert_eq!(evaluate("1 | 2 ^ 3").unwrap(), 1);
+        assert_eq!(evaluate("~0 & 0xf").unwrap(), 15);
+        assert_eq!(evaluate("2 + 3 * 2").unwrap(), 8);
+        assert_eq!(evaluate("(2 + 3) * 2").unwrap(), 10);
+        assert_eq!(evaluate("1 + 1 << 1").unwrap(), 4);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(evaluate("").is_err());
+        assert!(evaluate("1 +").is_err());
+        assert!(evaluate("(1").is_err());
+        assert!(evaluate("1 2").is_err());
+        assert!(evaluate("0x").is_err());
+        assert!(evaluate("'A").is_err());
+        assert!(evaluate("1 << 64").is_err());
+        assert!(evaluate("B").is_err());
+        assert!(evaluate("99999999999 * 99999999999").is_ok());
+        assert!(evaluate("$").is_err());
+    }
+
+    #[test]
+    fn test_current_address() {
+        assert_eq!(evaluate_at("$", 3).unwrap(), 3);
+        assert_eq!(evaluate_at("$ + 1", 3).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let symbols = HashMap::from([("loop_2".to_string(), 5)]);
+        assert_eq!(evaluate_with_symbols("loop_2 + 1", 0, &symbols).unwrap(), 6);
+        assert!(evaluate_with_symbols("loop", 0, &symbols).is_err());
+    }
+}
@@ -0,0 +1,420 @@
+use crate::batch;
+use crate::emulator::{CpuEmulator, PokeTarget};
+use crate::error::EmulatorErr;
+use crate::port::Ports;
+use crate::profile::MachineProfile;
+use crate::register::Register;
+use crate::rom::Rom;
+use std::fmt;
+use std::str::FromStr;
+
+// 1bitの故障を起こす場所
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FaultTarget {
+    RegisterA,
+    RegisterB,
+    Carry,
+    // ROMのアドレス
+    Rom(u8),
+}
+
+// cycle サイクル目の命令を実行する直前に target の bit を反転する
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Fault {
+    pub target: FaultTarget,
+    pub bit: u8,
+    pub cycle: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FaultConfig {
+    pub profile: MachineProfile,
+    pub input: u8,
+    // 止まらないプログラムはここで打ち切る
+    pub max_cycles: usize,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        FaultConfig {
+            profile: MachineProfile::default(),
+            input: 0,
+            max_cycles: 1000,
+        }
+    }
+}
+
+// 実行の終わり方
+#[derive(Debug, PartialEq, Clone)]
+pub enum Ending {
+    Halted(usize),
+    CycleLimit,
+    Fault(String),
+}
+
+// 故障の影響
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Effect {
+    // 出力は故障がないときと同じ
+    Masked,
+    // 出力が変わった
+    Corrupted,
+    // 故障がなければ止まるのに、止まらなくなった
+    Hung,
+    // 命令の実行でエラーになった
+    Crashed,
+}
+
+// 実行の結果。outputs はOUT命令で書き込まれた (サイクル, 値)
+#[derive(Debug, PartialEq, Clone)]
+pub struct FaultRun {
+    pub outputs: Vec<(usize, u8)>,
+    pub ending: Ending,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FaultReport {
+    pub fault: Fault,
+    pub baseline: FaultRun,
+    pub faulty: FaultRun,
+    pub effect: Effect,
+}
+
+// OUT命令で書き込んだ (サイクル, 値)。そこまで書き込まなければ None
+pub type OutputWrite = Option<(usize, u8)>;
+
+impl FaultReport {
+    // 出力が初めて食い違ったOUTの番号と、それぞれの書き込み
+    pub fn divergence(&self) -> Option<(usize, OutputWrite, OutputWrite)> {
+        let baseline = &self.baseline.outputs;
+        let faulty = &self.faulty.outputs;
+        (0..baseline.len().max(faulty.len()))
+            .find(|index| baseline.get(*index) != faulty.get(*index))
+            .map(|index| {
+                (
+                    index,
+                    baseline.get(index).copied(),
+                    faulty.get(index).copied(),
+                )
+            })
+    }
+}
+
+fn execute(rom: &[u8], fault: Option<&Fault>, config: &FaultConfig) -> FaultRun {
+    let mut emulator = CpuEmulator::with_profile(
+        Register::new(),
+        Ports::new(config.input, 0b0000),
+        Rom::new(rom.to_vec()),
+        config.profile.clone(),
+    );
+    emulator.set_quiet(true);
+    let mut injected = fault.is_none();
+    let ending = loop {
+        if !injected && emulator.cycles() >= fault.unwrap().cycle {
+            if let Err(err) = flip(&emulator, fault.unwrap()) {
+                break Ending::Fault(err.to_string());
+            }
+            injected = true;
+        }
+        if emulator.does_halt() {
+            break Ending::Halted(emulator.cycles());
+        }
+        if emulator.cycles() >= config.max_cycles {
+            break Ending::CycleLimit;
+        }
+        if let Err(err) = emulator.step() {
+            break Ending::Fault(err.to_string());
+        }
+    };
+    FaultRun {
+        outputs: emulator.output_history(),
+        ending,
+    }
+}
+
+fn flip(emulator: &CpuEmulator, fault: &Fault) -> Result<(), EmulatorErr> {
+    let register = emulator.register();
+    let (target, value) = match fault.target {
+        FaultTarget::RegisterA => (PokeTarget::RegisterA, register.register_a()),
+        FaultTarget::RegisterB => (PokeTarget::RegisterB, register.register_b()),
+        FaultTarget::Carry => (PokeTarget::CarryFlag, register.carry_flag()),
+        FaultTarget::Rom(address) => (
+            PokeTarget::Rom(address),
+            emulator.rom().get(address as usize).copied().unwrap_or(0),
+        ),
+    };
+    emulator.poke(target, value ^ (1 << fault.bit))?;
+    Ok(())
+}
+
+fn width(target: FaultTarget, profile: &MachineProfile) -> u8 {
+    match target {
+        FaultTarget::RegisterA | FaultTarget::RegisterB => profile.register_bits,
+        FaultTarget::Carry => 1,
+        FaultTarget::Rom(_) => 8,
+    }
+}
+
+// 故障があるときとないときを実行して比べる
+pub fn inject(rom: &[u8], fault: &Fault, config: &FaultConfig) -> Result<FaultReport, EmulatorErr> {
+    if fault.bit >= width(fault.target, &config.profile) {
+        return Err(EmulatorErr::new(&format!(
+            "{} has no bit {}",
+            target_name(fault.target),
+            fault.bit
+        )));
+    }
+    if let FaultTarget::Rom(address) = fault.target {
+        if address as usize >= rom.len() {
+            return Err(EmulatorErr::new(&format!(
+                "ROM address {} is out of the program",
+                address
+            )));
+        }
+    }
+    let baseline = execute(rom, None, config);
+    Ok(report(*fault, baseline, rom, config))
+}
+
+fn report(fault: Fault, baseline: FaultRun, rom: &[u8], config: &FaultConfig) -> FaultReport {
+    let faulty = execute(rom, Some(&fault), config);
+    let effect = match (&baseline.ending, &faulty.ending) {
+        (_, Ending::Fault(_)) => Effect::Crashed,
+        (Ending::Halted(_), Ending::CycleLimit) => Effect::Hung,
+        _ if faulty.outputs != baseline.outputs => Effect::Corrupted,
+        _ => Effect::Masked,
+    };
+    FaultReport {
+        fault,
+        baseline,
+        faulty,
+        effect,
+    }
+}
+
+// 故障がないときに実行するサイクルのそれぞれで、全てのレジスタ, キャリー, ROMの全てのbitを1つずつ反転する
+pub fn sweep(rom: &[u8], config: &FaultConfig) -> Vec<FaultReport> {
+    let baseline = execute(rom, None, config);
+    let cycles = match baseline.ending {
+        Ending::Halted(cycles) => cycles,
+        Ending::CycleLimit => config.max_cycles,
+        Ending::Fault(_) => return Vec::new(),
+    };
+    let mut targets = vec![
+        FaultTarget::RegisterA,
+        FaultTarget::RegisterB,
+        FaultTarget::Carry,
+    ];
+    targets.extend((0..rom.len()).map(|address| FaultTarget::Rom(address as u8)));
+
+    let mut faults = Vec::new();
+    for cycle in 0..cycles {
+        for target in &targets {
+            for bit in 0..width(*target, &config.profile) {
+                faults.push(Fault {
+                    target: *target,
+                    bit,
+                    cycle,
+                });
+            }
+        }
+    }
+    batch::map(faults, |fault| report(fault, baseline.clone(), rom, config))
+}
+
+fn target_name(target: FaultTarget) -> String {
+    match target {
+        FaultTarget::RegisterA => "reg_a".to_string(),
+        FaultTarget::RegisterB => "reg_b".to_string(),
+        FaultTarget::Carry => "carry".to_string(),
+        FaultTarget::Rom(address) => format!("rom{}", address),
+    }
+}
+
+// reg_a:bit2@cycle7, carry@cycle3, rom5:bit0@cycle0 の形
+impl FromStr for Fault {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            EmulatorErr::new(&format!(
+                "Invalid fault {}. Write it like reg_a:bit2@cycle7",
+                s
+            ))
+        };
+        let (location, cycle) = s.trim().split_once('@').ok_or_else(invalid)?;
+        let cycle = cycle
+            .strip_prefix("cycle")
+            .and_then(|cycle| cycle.parse().ok())
+            .ok_or_else(invalid)?;
+        let (target, bit) = match location.split_once(':') {
+            Some((target, bit)) => (
+                target,
+                bit.strip_prefix("bit")
+                    .and_then(|bit| bit.parse().ok())
+                    .ok_or_else(invalid)?,
+            ),
+            None => (location, 0),
+        };
+        let target = match target {
+            "reg_a" | "a" => FaultTarget::RegisterA,
+            "reg_b" | "b" => FaultTarget::RegisterB,
+            "carry" | "c" => FaultTarget::Carry,
+            _ => FaultTarget::Rom(
+                target
+                    .strip_prefix("rom")
+                    .and_then(|address| crate::debugger::parse_number(address).ok())
+                    .ok_or_else(invalid)?,
+            ),
+        };
+        Ok(Fault { target, bit, cycle })
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:bit{}@cycle{}",
+            target_name(self.target),
+            self.bit,
+            self.cycle
+        )
+    }
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Effect::Masked => "masked",
+            Effect::Corrupted => "corrupted",
+            Effect::Hung => "hung",
+            Effect::Crashed => "crashed",
+        };
+        f.pad(name)
+    }
+}
+
+impl fmt::Display for Ending {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ending::Halted(cycles) => write!(f, "halted after {} cycles", cycles),
+            Ending::CycleLimit => write!(f, "still running at the cycle limit"),
+            Ending::Fault(message) => write!(f, "stopped: {}", message),
+        }
+    }
+}
+
+// fault reg_a:bit2@cycle7: corrupted
+// first difference at OUT #1: 0010 at cycle 4 without the fault, 0110 at cycle 4 with it
+// without the fault: halted after 8 cycles
+// with the fault:    halted after 8 cycles
+impl fmt::Display for FaultReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "fault {}: {}", self.fault, self.effect)?;
+        let write = |output: OutputWrite| match output {
+            Some((cycle, value)) => format!("{:04b} at cycle {}", value, cycle),
+            None => "nothing".to_string(),
+        };
+        if let Some((index, baseline, faulty)) = self.divergence() {
+            writeln!(
+                f,
+                "first difference at OUT #{}: {} without the fault, {} with it",
+                index,
+                write(baseline),
+                write(faulty)
+            )?;
+        }
+        writeln!(f, "without the fault: {}", self.baseline.ending)?;
+        writeln!(f, "with the fault:    {}", self.faulty.ending)
+    }
+}
+
+#[cfg(test)]
+mod fault_tests {
+    use crate::compiler::assemble;
+    use crate::fault::{inject, sweep, Effect, Ending, Fault, FaultConfig, FaultTarget};
+
+    // 0011 と 0001 を足して出力する
+    const SOURCE: &str = "mov A 0011\nadd A 0001\nmov B A\nout B\nout 1111";
+
+    #[test]
+    fn test_inject() {
+        let rom = assemble(SOURCE).unwrap();
+        let fault: Fault = "reg_a:bit1@cycle2".parse().unwrap();
+        let report = inject(&rom, &fault, &FaultConfig::default()).unwrap();
+        assert_eq!(report.effect, Effect::Corrupted);
+        assert_eq!(
+            report.divergence(),
+            Some((0, Some((3, 0b0100)), Some((3, 0b0110))))
+        );
+        assert_eq!(
+            report.to_string(),
+            "fault reg_a:bit1@cycle2: corrupted
+first difference at OUT #0: 0100 at cycle 3 without the fault, 0110 at cycle 3 with it
+without the fault: halted after 5 cycles
+with the fault:    halted after 5 cycles
+"
+        );
+
+        // 書き込まれる前のBの故障は上書きされて消える
+        let fault: Fault = "reg_b:bit3@cycle1".parse().unwrap();
+        let report = inject(&rom, &fault, &FaultConfig::default()).unwrap();
+        assert_eq!(report.effect, Effect::Masked);
+        assert_eq!(report.divergence(), None);
+
+        assert!(inject(
+            &rom,
+            &"reg_a:bit4@cycle0".parse().unwrap(),
+            &FaultConfig::default()
+        )
+        .is_err());
+        assert!(inject(
+            &rom,
+            &"rom5:bit0@cycle0".parse().unwrap(),
+            &FaultConfig::default()
+        )
+        .is_err());
+
+        // jnc 0000 が jmp 0000 に化けてループから抜けられなくなる
+        let rom = assemble("add A 0100\njnc 0000\nout 1111").unwrap();
+        let fault = Fault {
+            target: FaultTarget::Rom(1),
+            bit: 4,
+            cycle: 0,
+        };
+        let report = inject(&rom, &fault, &FaultConfig::default()).unwrap();
+        assert_eq!(report.baseline.ending, Ending::Halted(9));
+        assert_eq!(report.faulty.ending, Ending::CycleLimit);
+        assert_eq!(report.effect, Effect::Hung);
+    }
+
+    #[test]
+    fn test_parse() {
+        let fault: Fault = "carry@cycle3".parse().unwrap();
+        assert_eq!(fault.to_string(), "carry:bit0@cycle3");
+        let fault: Fault = "rom0x2:bit7@cycle0".parse().unwrap();
+        assert_eq!(fault.target, FaultTarget::Rom(2));
+        assert!("reg_a:bit2".parse::<Fault>().is_err());
+        assert!("reg_c:bit2@cycle1".parse::<Fault>().is_err());
+        assert!("reg_a:2@cycle1".parse::<Fault>().is_err());
+    }
+
+    #[test]
+    fn test_sweep() {
+        let rom = assemble(SOURCE).unwrap();
+        let reports = sweep(&rom, &FaultConfig::default());
+        // 5サイクル x (A 4bit + B 4bit + キャリー + ROM 5バイト x 8bit)
+        assert_eq!(reports.len(), 5 * (4 + 4 + 1 + 40));
+        let count = |effect| {
+            reports
+                .iter()
+                .filter(|report| report.effect == effect)
+                .count()
+        };
+        assert!(count(Effect::Masked) > 0);
+        assert!(count(Effect::Corrupted) > 0);
+        // 標準モードでは拡張命令に化けるとエラーになる
+        assert!(count(Effect::Crashed) > 0);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::emulator::CpuEmulator;
+use crate::port::Ports;
+use crate::profile::MachineProfile;
+use crate::register::Register;
+use crate::rom::Rom;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+// シードから同じ列を再現できる乱数 (xorshift64*)
+// 外部クレートのバージョンで列が変わらないように自前で持つ
+pub struct Xorshift {
+    state: u64,
+}
+
+const SEED_MIX: u64 = 0x9e37_79b9_7f4a_7c15;
+
+impl Xorshift {
+    pub fn new(seed: u64) -> Self {
+        // 状態が0だと0しか出なくなるので、混ぜた結果が0になるシードでは別の値から始める
+        let state = seed ^ SEED_MIX;
+        Xorshift {
+            state: if state == 0 { SEED_MIX } else { state },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub fn next_byte(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+}
+
+// 命令の実行方法
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Semantics {
+    Behavioral,
+    #[cfg(feature = "gates")]
+    Gates,
+}
+
+#[derive(Debug, Clone)]
+pub struct FuzzConfig {
+    pub seed: u64,
+    pub runs: usize,
+    pub max_steps: usize,
+    pub semantics: Semantics,
+}
+
+impl Default for FuzzConfig {
+    fn default() -> Self {
+        FuzzConfig {
+            seed: 0,
+            runs: 1000,
+            max_steps: 256,
+            semantics: Semantics::Behavioral,
+        }
+    }
+}
+
+// パニックか不変条件の違反が見つかったROM
+#[derive(Debug, PartialEq, Clone)]
+pub struct Finding {
+    pub run: usize,
+    pub rom: Vec<u8>,
+    pub input: u8,
+    pub step: usize,
+    pub description: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rom: Vec<String> = self
+            .rom
+            .iter()
+            .map(|data| format!("{:02x}", data))
+            .collect();
+        write!(
+            f,
+            "run {}: rom [{}] input {:04b}, step {}: {}",
+            self.run,
+            rom.join(" "),
+            self.input,
+            self.step,
+            self.description
+        )
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct FuzzReport {
+    pub runs: usize,
+    pub halted: usize,
+    // 未定義のopcodeなどでエラーになったROMの数 (想定内の結果)
+    pub errors: usize,
+    pub findings: Vec<Finding>,
+}
+
+// シードからROMサイズいっぱいのランダムなROMを作って決められたステップ数だけ実行する
+pub fn fuzz_run(config: &FuzzConfig) -> FuzzReport {
+    let mut rng = Xorshift::new(config.seed);
+    let mut report = FuzzReport::default();
+    let profile = MachineProfile::default();
+
+    for run in 0..config.runs {
+        let rom: Vec<u8> = (0..profile.rom_size).map(|_| rng.next_byte()).collect();
+        let input = rng.next_byte() & profile.register_mask();
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            run_one(&rom, input, config.max_steps, config.semantics)
+        }));
+        let finding = |step, description| Finding {
+            run,
+            rom: rom.clone(),
+            input,
+            step,
+            description,
+        };
+
+        report.runs += 1;
+        match result {
+            Ok(Outcome::Halted) => report.halted += 1,
+            Ok(Outcome::Running) => (),
+            Ok(Outcome::Error) => report.errors += 1,
+            Ok(Outcome::Violation(step, description)) => {
+                report.findings.push(finding(step, description))
+            }
+            Err(payload) => {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                report
+                    .findings
+                    .push(finding(0, format!("panicked: {}", message)))
+            }
+        }
+    }
+
+    report
+}
+
+enum Outcome {
+    Halted,
+    Running,
+    Error,
+    Violation(usize, String),
+}
+
+fn run_one(rom: &[u8], input: u8, max_steps: usize, semantics: Semantics) -> Outcome {
+    let mut emulator = CpuEmulator::with(
+        Register::new(),
+        Ports::new(input, 0),
+        Rom::new(rom.to_vec()),
+    );
+    emulator.set_quiet(true);
+
+    for step in 1..=max_steps {
+        if emulator.does_halt() {
+            return Outcome::Halted;
+        }
+        let result = match semantics {
+            Semantics::Behavioral => emulator.step(),
+            #[cfg(feature = "gates")]
+            Semantics::Gates => emulator.step_gates().map(|_| ()),
+        };
+        if result.is_err() {
+            return Outcome::Error;
+        }
+        if let Some(description) = check_invariants(&emulator) {
+            return Outcome::Violation(step, description);
+        }
+    }
+
+    if emulator.does_halt() {
+        Outcome::Halted
+    } else {
+        Outcome::Running
+    }
+}
+
+// プロファイルのビット幅のCPUとしてありえない状態になっていないか確かめる
+// PCがROMの末尾の次を指すのはプログラムの終わりなので許す
+fn check_invariants(emulator: &CpuEmulator) -> Option<String> {
+    let register = emulator.register();
+    let mask = emulator.profile().register_mask();
+    let checks = [
+        ("register A", register.register_a(), mask),
+        ("register B", register.register_b(), mask),
+        ("carry flag", register.carry_flag(), 1),
+        ("output", emulator.output(), mask),
+        ("pc", register.pc(), emulator.rom().len() as u8),
+    ];
+    checks
+        .iter()
+        .find(|(_, value, limit)| value > limit)
+        .map(|(name, value, limit)| format!("{} = {} exceeds {}", name, value, limit))
+}
+
+#[cfg(test)]
+mod fuzz_tests {
+    use crate::fuzz::{fuzz_run, FuzzConfig, Xorshift};
+
+    #[test]
+    fn test_same_seed_same_sequence() {
+        let mut a = Xorshift::new(42);
+        let mut b = Xorshift::new(42);
+        let mut c = Xorshift::new(43);
+        let first: Vec<u8> = (0..16).map(|_| a.next_byte()).collect();
+        let second: Vec<u8> = (0..16).map(|_| b.next_byte()).collect();
+        let other: Vec<u8> = (0..16).map(|_| c.next_byte()).collect();
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+
+        // 混ぜると状態が0になるシードでも0だけの列にならない
+        let mut zero = Xorshift::new(0x9e37_79b9_7f4a_7c15);
+        assert!((0..16).any(|_| zero.next_u64() != 0));
+    }
+
+    #[test]
+    fn test_fuzz_run_finds_nothing() {
+        let config = FuzzConfig {
+            seed: 7,
+            runs: 500,
+            ..FuzzConfig::default()
+        };
+        let report = fuzz_run(&config);
+        assert_eq!(report.runs, 500);
+        assert!(report.findings.is_empty(), "{}", report.findings[0]);
+        assert_eq!(report, fuzz_run(&config));
+    }
+}
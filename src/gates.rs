@@ -0,0 +1,163 @@
+// TD4のデータパスを制御信号のレベルで再現する (本の回路図に対応)
+// 命令デコーダ、データセレクタ、ALU(4bit加算器)を通して命令を実行する
+use std::fmt;
+
+// 命令デコーダの出力。本の回路では負論理だがここでは有効な時にtrueとする
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ControlSignals {
+    pub select_a: bool,
+    pub select_b: bool,
+    pub load: [bool; 4], // LOAD0: A, LOAD1: B, LOAD2: 出力ポート, LOAD3: PC
+}
+
+// 1クロック分のデータパスの様子
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DatapathCycle {
+    pub pc: u8,
+    pub instruction: u8,
+    pub signals: ControlSignals,
+    pub selector_out: u8,
+    pub alu_out: u8,
+    pub carry_out: u8,
+}
+
+impl fmt::Display for DatapathCycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bit = |signal: bool| if signal { '1' } else { '0' };
+        write!(
+            f,
+            "PC=0x{:x} INST={:08b} SELECT_B={} SELECT_A={} LOAD0..3={}{}{}{} SEL={:04b} ALU={:04b} C={}",
+            self.pc,
+            self.instruction,
+            bit(self.signals.select_b),
+            bit(self.signals.select_a),
+            bit(self.signals.load[0]),
+            bit(self.signals.load[1]),
+            bit(self.signals.load[2]),
+            bit(self.signals.load[3]),
+            self.selector_out,
+            self.alu_out,
+            self.carry_out
+        )
+    }
+}
+
+// オペコード4bitとキャリーフラグから制御信号を作る
+pub fn instruction_decoder(op: u8, carry: u8) -> ControlSignals {
+    let bit = |n: u8| op >> n & 1 == 1;
+    let (op0, op1, op2, op3) = (bit(0), bit(1), bit(2), bit(3));
+
+    ControlSignals {
+        select_a: op0 || op3,
+        select_b: op1,
+        load: [
+            !op3 && !op2,
+            !op3 && op2,
+            op3 && !op2,
+            // JMPは常に、JNCはキャリーが0の時だけPCにロードする
+            op3 && op2 && (op0 || carry == 0),
+        ],
+    }
+}
+
+// SELECT_B, SELECT_Aの組み合わせでALUに入力する値を選ぶ
+pub fn data_selector(signals: &ControlSignals, a: u8, b: u8, input: u8) -> u8 {
+    match (signals.select_b, signals.select_a) {
+        (false, false) => a,
+        (false, true) => b,
+        (true, false) => input,
+        (true, true) => 0b0000,
+    }
+}
+
+// 4bit加算器。(和, キャリー出力)を返す
+pub fn alu(lhs: u8, im: u8) -> (u8, u8) {
+    let sum = (lhs & 0x0f) + (im & 0x0f);
+    (sum & 0x0f, sum >> 4)
+}
+
+#[cfg(test)]
+mod gates_tests {
+    use crate::emulator::CpuEmulator;
+    use crate::gates::{alu, data_selector, instruction_decoder};
+    use crate::op::Opcode;
+    use crate::port::Ports;
+    use crate::register::Register;
+    use crate::rom::Rom;
+
+    #[test]
+    fn test_instruction_decoder() {
+        // MOV A, Im
+        let signals = instruction_decoder(0b0011, 0);
+        assert!(signals.select_a && signals.select_b);
+        assert_eq!(signals.load, [true, false, false, false]);
+
+        // OUT B
+        let signals = instruction_decoder(0b1001, 0);
+        assert!(signals.select_a && !signals.select_b);
+        assert_eq!(signals.load, [false, false, true, false]);
+
+        // JNC
+        assert!(instruction_decoder(0b1110, 0).load[3]);
+        assert!(!instruction_decoder(0b1110, 1).load[3]);
+        // JMP
+        assert!(instruction_decoder(0b1111, 1).load[3]);
+    }
+
+    #[test]
+    fn test_data_selector_and_alu() {
+        let signals = instruction_decoder(0b0110, 0); // IN B
+        assert_eq!(data_selector(&signals, 1, 2, 3), 3);
+        assert_eq!(alu(0b1111, 0b0001), (0b0000, 1));
+        assert_eq!(alu(0b0111, 0b0001), (0b1000, 0));
+    }
+
+    // 回路レベルの実行と命令レベルの実行が全ての命令で一致することを確かめる
+    #[test]
+    fn test_cross_check_with_behavioral() {
+        for op in 0..16u8 {
+            let opcode = Opcode::decode(op << 4).map(|(opcode, _)| opcode);
+            // 拡張命令は実機の回路にはない
+            let opcode = match opcode {
+                Some(opcode) if !opcode.is_extended() => opcode,
+                _ => continue,
+            };
+            let operand_less = matches!(
+                opcode,
+                Opcode::MovA2B | Opcode::MovB2A | Opcode::InA | Opcode::InB | Opcode::OutB
+            );
+
+            for im in 0..16u8 {
+                if operand_less && im != 0 {
+                    continue;
+                }
+                for (a, b, carry) in [(0, 0, 0), (3, 9, 1), (15, 1, 0), (7, 15, 1)] {
+                    let emulator = || {
+                        let mut register = Register::new();
+                        register.set_register_a(a);
+                        register.set_register_b(b);
+                        register.set_carry_flag(carry);
+                        CpuEmulator::with(
+                            register,
+                            Ports::new(0b0101, 0b0000),
+                            Rom::new(vec![op << 4 | im]),
+                        )
+                    };
+                    let behavioral = emulator();
+                    let gates = emulator();
+                    behavioral.step().unwrap();
+                    gates.step_gates().unwrap();
+
+                    let (expected, actual) = (behavioral.register(), gates.register());
+                    let context =
+                        format!("op={:04b} im={:04b} a={} b={} c={}", op, im, a, b, carry);
+                    assert_eq!(expected.register_a(), actual.register_a(), "{}", context);
+                    assert_eq!(expected.register_b(), actual.register_b(), "{}", context);
+                    assert_eq!(expected.carry_flag(), actual.carry_flag(), "{}", context);
+                    assert_eq!(expected.pc(), actual.pc(), "{}", context);
+                    assert_eq!(behavioral.output(), gates.output(), "{}", context);
+                }
+            }
+        }
+    }
+}
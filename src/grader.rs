@@ -0,0 +1,543 @@
+use crate::batch;
+use crate::compiler::assemble_with_profile;
+use crate::error::EmulatorErr;
+use crate::macros::MacroExpander;
+use crate::matcher::{Evaluator, OutputMatcher};
+use crate::parser::Syntax;
+use crate::profile::MachineProfile;
+use crate::register::Register;
+use crate::sandbox::{execute_watched, ExecConfig, ExecError};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+
+// 課題の採点基準。TOMLのうち採点に使う部分だけを読む
+//
+//     profile = "td4-book"
+//     max_cycles = 1000
+//     max_bytes = 16
+//
+//     [[case]]
+//     name = "3 + 4"
+//     input = 3
+//     outputs = [7]
+//
+// ケースに a = 1, b = 2 と書くとそのレジスタの値から実行を始める (省略すると0)
+// outputs の代わりに expect = ["within 20 cycles output becomes 0b1000"] のように出力の性質を書ける
+#[derive(Debug, PartialEq, Clone)]
+pub struct GradeSpec {
+    pub profile: MachineProfile,
+    // 1ケースあたりの最大サイクル数。これまでに止まらなければ不合格
+    // 実行時間などほかの上限は ExecConfig の既定値を使う
+    pub max_cycles: usize,
+    pub max_bytes: Option<usize>,
+    pub cases: Vec<GradeCase>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct GradeCase {
+    pub name: String,
+    pub input: u8,
+    // 実行を始めるときのレジスタAとB
+    pub a: u8,
+    pub b: u8,
+    // OUT命令で書き込まれるべき値の並び
+    pub outputs: Vec<u8>,
+    // 出力が満たすべき条件。空でなければ outputs の代わりにこれで判定し、止まらなくてもよい
+    pub expect: Vec<OutputMatcher>,
+}
+
+impl Default for GradeSpec {
+    fn default() -> Self {
+        GradeSpec {
+            profile: MachineProfile::default(),
+            max_cycles: 1000,
+            max_bytes: None,
+            cases: Vec::new(),
+        }
+    }
+}
+
+// 1つの提出ファイルの採点結果
+#[derive(Debug, PartialEq, Clone)]
+pub struct GradeResult {
+    pub file: String,
+    pub passed: bool,
+    // 一番長くかかったケースのサイクル数
+    pub cycles: usize,
+    pub bytes: usize,
+    // 最初に見つかった不合格の理由
+    pub error: Option<String>,
+}
+
+// 提出ファイルを順に採点する
+// 読み込みやアセンブルの失敗、パニックはそのファイルの不合格として記録し、残りの採点を続ける
+pub fn grade(spec: &GradeSpec, files: &[&str]) -> Vec<GradeResult> {
+    batch::map(files.to_vec(), |file| {
+        let mut result = GradeResult {
+            file: file.to_string(),
+            passed: false,
+            cycles: 0,
+            bytes: 0,
+            error: None,
+        };
+        let graded = panic::catch_unwind(AssertUnwindSafe(|| {
+            std::fs::read_to_string(file)
+                .map_err(|_| EmulatorErr::new("file not found"))
+                .and_then(|source| grade_source(spec, &source, &mut result))
+        }));
+        match graded {
+            Ok(Ok(())) => result.passed = true,
+            Ok(Err(err)) => result.error = Some(err.to_string()),
+            Err(payload) => {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                result.error = Some(format!("panicked: {}", message));
+            }
+        }
+        result
+    })
+}
+
+// ソースコードを全ケースで実行し、サイクル数とバイト数を result に書き込む
+pub fn grade_source(
+    spec: &GradeSpec,
+    source: &str,
+    result: &mut GradeResult,
+) -> Result<(), EmulatorErr> {
+    let (bytes, _) =
+        assemble_with_profile(source, &MacroExpander::new(), Syntax::V1, &spec.profile)?;
+    result.bytes = bytes.len();
+    if let Some(max_bytes) = spec.max_bytes {
+        if bytes.len() > max_bytes {
+            return Err(EmulatorErr::new(&format!(
+                "program is {} bytes but the limit is {}",
+                bytes.len(),
+                max_bytes
+            )));
+        }
+    }
+
+    for case in &spec.cases {
+        let mut register = Register::new();
+        register.set_register_a(case.a);
+        register.set_register_b(case.b);
+        let config = ExecConfig {
+            profile: spec.profile.clone(),
+            input: case.input,
+            register,
+            max_cycles: spec.max_cycles,
+            ..ExecConfig::default()
+        };
+        // 条件が全て決まったところで実行を打ち切る
+        let mut evaluator = Evaluator::new(case.expect.clone());
+        let watching = !case.expect.is_empty();
+        let run = execute_watched(&bytes, &config, &mut |cycle, output| {
+            evaluator.observe(cycle, output);
+            watching && evaluator.is_settled()
+        });
+        let run = match run {
+            Ok(run) => run,
+            // 条件で判定するケースは max_cycles まで見たら終わり
+            Err(ExecError::CycleLimit(cycles)) if watching => {
+                result.cycles = result.cycles.max(cycles);
+                evaluator
+                    .finish(cycles)
+                    .map_err(|err| EmulatorErr::new(&format!("case {}: {}", case.name, err)))?;
+                continue;
+            }
+            Err(err) => {
+                if let ExecError::CycleLimit(cycle) | ExecError::Fault { cycle, .. } = err {
+                    result.cycles = result.cycles.max(cycle);
+                }
+                return Err(EmulatorErr::new(&format!("case {}: {}", case.name, err)));
+            }
+        };
+        result.cycles = result.cycles.max(run.cycles);
+        if watching {
+            evaluator
+                .finish(run.cycles)
+                .map_err(|err| EmulatorErr::new(&format!("case {}: {}", case.name, err)))?;
+            continue;
+        }
+
+        let outputs: Vec<u8> = run.outputs.iter().map(|(_, output)| *output).collect();
+        if outputs != case.outputs {
+            return Err(EmulatorErr::new(&format!(
+                "case {}: expected outputs {:?} but got {:?}",
+                case.name, case.outputs, outputs
+            )));
+        }
+    }
+    Ok(())
+}
+
+// file,result,cycles,bytes,error の形のCSV
+pub fn to_csv(results: &[GradeResult]) -> String {
+    let mut csv = String::from("file,result,cycles,bytes,error\n");
+    for result in results {
+        csv.push_str(&format!(
+            "{},{},{},{},{}\n",
+            csv_field(&result.file),
+            if result.passed { "pass" } else { "fail" },
+            result.cycles,
+            result.bytes,
+            csv_field(result.error.as_deref().unwrap_or(""))
+        ));
+    }
+    csv
+}
+
+// エラーメッセージにはカンマが含まれるので必要なときだけ引用符で囲む
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+enum Value {
+    Integer(u64),
+    Text(String),
+    Array(Vec<u64>),
+    Texts(Vec<String>),
+}
+
+impl FromStr for GradeSpec {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut spec = GradeSpec::default();
+
+        for (number, line) in s.lines().enumerate() {
+            let error =
+                |message: &str| EmulatorErr::new(&format!("spec line {}: {}", number + 1, message));
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line == "[[case]]" {
+                spec.cases.push(GradeCase {
+                    name: (spec.cases.len() + 1).to_string(),
+                    input: 0,
+                    a: 0,
+                    b: 0,
+                    outputs: Vec::new(),
+                    expect: Vec::new(),
+                });
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error(&format!("can't read {}", line)))?;
+            let key = key.trim();
+            let value = parse_value(value.trim()).ok_or_else(|| error("invalid value"))?;
+
+            match (spec.cases.last_mut(), key, value) {
+                (None, "profile", Value::Text(name)) => spec.profile = name.parse()?,
+                (None, "max_cycles", Value::Integer(max_cycles)) => {
+                    spec.max_cycles = max_cycles as usize
+                }
+                (None, "max_bytes", Value::Integer(max_bytes)) => {
+                    spec.max_bytes = Some(max_bytes as usize)
+                }
+                (Some(case), "name", Value::Text(name)) => case.name = name,
+                (Some(case), "input", Value::Integer(input)) => {
+                    case.input = u8::try_from(input)
+                        .map_err(|_| error(&format!("input {} is too large", input)))?
+                }
+                (Some(case), "a", Value::Integer(value)) => {
+                    case.a = u8::try_from(value)
+                        .map_err(|_| error(&format!("a {} is too large", value)))?
+                }
+                (Some(case), "b", Value::Integer(value)) => {
+                    case.b = u8::try_from(value)
+                        .map_err(|_| error(&format!("b {} is too large", value)))?
+                }
+                (Some(case), "outputs", Value::Array(outputs)) => {
+                    case.outputs = outputs
+                        .into_iter()
+                        .map(u8::try_from)
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| error("outputs must be bytes"))?
+                }
+                (Some(case), "expect", Value::Texts(matchers)) => {
+                    case.expect = matchers
+                        .iter()
+                        .map(|matcher| matcher.parse())
+                        .collect::<Result<_, _>>()
+                        .map_err(|err: EmulatorErr| error(&err.to_string()))?
+                }
+                _ => return Err(error(&format!("unexpected key {}", key))),
+            }
+        }
+
+        if spec.cases.is_empty() {
+            return Err(EmulatorErr::new("spec has no [[case]]"));
+        }
+        let mask = spec.profile.register_mask();
+        for case in &spec.cases {
+            if case.input & !mask != 0 {
+                return Err(EmulatorErr::new(&format!(
+                    "case {}: input {} doesn't fit in the input port",
+                    case.name, case.input
+                )));
+            }
+            if (case.a | case.b) & !mask != 0 {
+                return Err(EmulatorErr::new(&format!(
+                    "case {}: a and b must fit in {} bits",
+                    case.name, spec.profile.register_bits
+                )));
+            }
+            if !case.outputs.is_empty() && !case.expect.is_empty() {
+                return Err(EmulatorErr::new(&format!(
+                    "case {}: write either outputs or expect",
+                    case.name
+                )));
+            }
+        }
+        Ok(spec)
+    }
+}
+
+// FromStr で読める形で書き出す。a と b は0でなければ書く
+impl fmt::Display for GradeSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "profile = \"{}\"", self.profile.name)?;
+        writeln!(f, "max_cycles = {}", self.max_cycles)?;
+        if let Some(max_bytes) = self.max_bytes {
+            writeln!(f, "max_bytes = {}", max_bytes)?;
+        }
+        for case in &self.cases {
+            writeln!(f, "\n[[case]]")?;
+            writeln!(f, "name = \"{}\"", case.name)?;
+            writeln!(f, "input = 0b{:04b}", case.input)?;
+            if case.a != 0 {
+                writeln!(f, "a = 0b{:04b}", case.a)?;
+            }
+            if case.b != 0 {
+                writeln!(f, "b = 0b{:04b}", case.b)?;
+            }
+            if !case.expect.is_empty() {
+                let matchers: Vec<String> = case
+                    .expect
+                    .iter()
+                    .map(|matcher| format!("\"{}\"", matcher))
+                    .collect();
+                writeln!(f, "expect = [{}]", matchers.join(", "))?;
+                continue;
+            }
+            let outputs: Vec<String> = case
+                .outputs
+                .iter()
+                .map(|output| format!("0b{:04b}", output))
+                .collect();
+            writeln!(f, "outputs = [{}]", outputs.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+// 文字列の中の # はコメントではない
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..index],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn parse_value(text: &str) -> Option<Value> {
+    if let Some(text) = text.strip_prefix('"') {
+        return text
+            .strip_suffix('"')
+            .map(|text| Value::Text(text.to_string()));
+    }
+    if let Some(items) = text.strip_prefix('[') {
+        let items = items.strip_suffix(']')?.trim();
+        if items.is_empty() {
+            return Some(Value::Array(Vec::new()));
+        }
+        // 文字列の配列。条件にカンマは出てこないのでカンマで区切るだけでよい
+        if items.starts_with('"') {
+            return items
+                .split(',')
+                .map(|item| {
+                    let item = item.trim().strip_prefix('"')?.strip_suffix('"')?;
+                    Some(item.to_string())
+                })
+                .collect::<Option<_>>()
+                .map(Value::Texts);
+        }
+        return items
+            .split(',')
+            .map(|item| parse_integer(item.trim()))
+            .collect::<Option<_>>()
+            .map(Value::Array);
+    }
+    parse_integer(text).map(Value::Integer)
+}
+
+// TOMLと同じく 0x と 0b の接頭辞と _ の区切りを受け付ける
+pub fn parse_integer(text: &str) -> Option<u64> {
+    let text = text.replace('_', "");
+    if let Some(hex) = text.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0b") {
+        u64::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod grader_tests {
+    use crate::grader::{grade, grade_source, to_csv, GradeResult, GradeSpec};
+    use crate::matcher::OutputMatcher;
+
+    const SPEC: &str = "
+# 入力に3を足して出力する
+max_cycles = 20
+max_bytes = 8
+
+[[case]]
+name = \"zero\"
+input = 0
+outputs = [3]
+
+[[case]]
+input = 0b0100 # 2つめ
+outputs = [7]
+";
+
+    fn result() -> GradeResult {
+        GradeResult {
+            file: "a.sasm".to_string(),
+            passed: false,
+            cycles: 0,
+            bytes: 0,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        let spec: GradeSpec = SPEC.parse().unwrap();
+        assert_eq!(spec.max_cycles, 20);
+        assert_eq!(spec.max_bytes, Some(8));
+        assert_eq!(spec.cases.len(), 2);
+        assert_eq!(spec.cases[1].name, "2");
+        assert_eq!(spec.cases[1].input, 4);
+        assert_eq!(spec.cases[1].outputs, vec![7]);
+
+        let err = "[[case]]\ninput = 16".parse::<GradeSpec>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "case 1: input 16 doesn't fit in the input port"
+        );
+        let err = "[[case]]\nexpected = [1]".parse::<GradeSpec>().unwrap_err();
+        assert_eq!(err.to_string(), "spec line 2: unexpected key expected");
+        assert!("max_cycles = 10".parse::<GradeSpec>().is_err());
+    }
+
+    #[test]
+    fn test_grade_source() {
+        let spec: GradeSpec = SPEC.parse().unwrap();
+        let mut passed = result();
+        let source = "in A\nadd A 0011\nmov B A\nout B\njmp 0100";
+        assert!(grade_source(&spec, source, &mut passed).is_ok());
+        assert_eq!((passed.cycles, passed.bytes), (4, 5));
+
+        let mut wrong = result();
+        let err = grade_source(&spec, "out 0011\njmp 0001", &mut wrong).unwrap_err();
+        assert_eq!(err.to_string(), "case 2: expected outputs [7] but got [3]");
+
+        let mut endless = result();
+        let err = grade_source(&spec, "out 0011\njmp 0000", &mut endless).unwrap_err();
+        assert_eq!(err.to_string(), "case zero: didn't halt within 20 cycles");
+        assert_eq!(endless.cycles, 20);
+    }
+
+    #[test]
+    fn test_expect() {
+        let spec: GradeSpec = "
+max_cycles = 50
+
+[[case]]
+name = \"blink\"
+expect = [\"within 4 cycles output becomes 0b1000\", \"output toggles bit3 at least 4 times\"]
+
+[[case]]
+name = \"count\"
+expect = [\"output is monotonically increasing\"]
+"
+        .parse()
+        .unwrap();
+        assert_eq!(spec.cases[1].expect, vec![OutputMatcher::Increasing]);
+        assert_eq!(spec.to_string().parse::<GradeSpec>().unwrap(), spec);
+
+        // 止まらない点滅でも条件が決まったところで打ち切って合格にする
+        let mut blink = result();
+        let source = "out 1000\nout 0000\njmp 0000";
+        let mut first = spec.clone();
+        first.cases.truncate(1);
+        assert!(grade_source(&first, source, &mut blink).is_ok());
+        assert_eq!(blink.cycles, 5);
+
+        let mut counter = result();
+        let source = "add A 0001\nmov B A\nout B\njmp 0000";
+        let err = grade_source(&spec, source, &mut counter).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "case blink: within 4 cycles output becomes 0b1000, but output was 0b0001 at cycle 3"
+        );
+
+        // 1, 2, 3, ... と数えて 15 から 0 に戻ったところで外れる
+        let mut spec = spec;
+        spec.cases.remove(0);
+        assert!(grade_source(&spec, source, &mut counter).is_ok());
+        assert_eq!(counter.cycles, 50);
+        spec.max_cycles = 100;
+        let err = grade_source(&spec, source, &mut counter).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "case count: output is monotonically increasing, but 0b0000 came after 0b1111 at cycle 62"
+        );
+
+        let err = "[[case]]\noutputs = [1]\nexpect = [\"output never becomes 2\"]"
+            .parse::<GradeSpec>()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "case 1: write either outputs or expect");
+        let err = "[[case]]\nexpect = [\"output wiggles\"]"
+            .parse::<GradeSpec>()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "spec line 2: matcher output wiggles: unknown matcher"
+        );
+    }
+
+    #[test]
+    fn test_grade_isolates_errors() {
+        let spec: GradeSpec = SPEC.parse().unwrap();
+        let results = grade(&spec, &["missing.sasm", "example/counter.sasm"]);
+        assert_eq!(results[0].error.as_deref(), Some("file not found"));
+        assert!(!results[1].passed);
+
+        let mut failed = result();
+        failed.file = "a, \"b\".sasm".to_string();
+        failed.error = Some("case 2: expected outputs [7] but got [3]".to_string());
+        assert_eq!(
+            to_csv(&[failed]),
+            "file,result,cycles,bytes,error\n\"a, \"\"b\"\".sasm\",fail,0,0,case 2: expected outputs [7] but got [3]\n"
+        );
+    }
+}
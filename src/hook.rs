@@ -0,0 +1,51 @@
+use crate::instruction::Instruction;
+use crate::register::Register;
+
+// 1命令ごとに呼ばれる検査。Machine::add_hook で登録する
+// ブレークポイントやウォッチポイントより細かい条件で止めたいとき、不変条件を確かめたいときに使う
+//
+//     machine.add_hook(Hook::post(|state| match state.register.register_b() {
+//         b if b > 9 => HookAction::Abort(format!("B went past 9: {}", b)),
+//         _ => HookAction::Continue,
+//     }));
+pub enum Hook {
+    // 命令を実行する前。Pause なら命令を実行せずに止まる
+    PreInstruction(HookFn),
+    // 命令を実行した直後
+    PostInstruction(HookFn),
+}
+
+pub type HookFn = Box<dyn FnMut(&HookState) -> HookAction + Send>;
+
+impl Hook {
+    pub fn pre(hook: impl FnMut(&HookState) -> HookAction + Send + 'static) -> Self {
+        Hook::PreInstruction(Box::new(hook))
+    }
+
+    pub fn post(hook: impl FnMut(&HookState) -> HookAction + Send + 'static) -> Self {
+        Hook::PostInstruction(Box::new(hook))
+    }
+}
+
+// フックが受け取る状態。PreInstruction なら実行前、PostInstruction なら実行後のレジスタとポート
+#[derive(Debug, PartialEq, Clone)]
+pub struct HookState {
+    pub cycle: usize,
+    // 実行する (した) 命令のアドレスとその中身
+    pub pc: u8,
+    pub byte: u8,
+    // 命令として読めないバイトやプラグインの命令なら None
+    pub instruction: Option<Instruction>,
+    pub register: Register,
+    pub input: u8,
+    pub output: u8,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum HookAction {
+    Continue,
+    // run を StopReason::Paused で止める。もう一度 run すれば続きから実行する
+    Pause,
+    // run をエラーで止める
+    Abort(String),
+}
@@ -0,0 +1,239 @@
+use crate::op::Opcode;
+pub use crate::token::Register as Reg;
+use std::fmt;
+
+// オペランドまで含めてデコードした1命令
+// オペランドのない命令は即値を持たないので、誤って即値を使うことがない
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Instruction {
+    Mov { reg: Reg, im: u8 },
+    // mov A B (BをAに転送) と mov B A
+    MovAB,
+    MovBA,
+    Add { reg: Reg, im: u8 },
+    // port は拡張モードでポートを増やしたときの番号
+    In { reg: Reg, port: u8 },
+    OutIm { im: u8 },
+    OutB { port: u8 },
+    Jmp { im: u8 },
+    Jnc { im: u8 },
+    // 以下は拡張モードだけの命令
+    Call { im: u8 },
+    Ret,
+    Sub { reg: Reg, im: u8 },
+    Cmp,
+    // ld A [B] と st [B] A
+    Ld,
+    St,
+    // swap A B
+    Swap,
+}
+
+impl Instruction {
+    // opcodeと下位4bitから命令を組み立てる。オペランドのない命令は下位4bitを無視する
+    pub fn new(opcode: Opcode, im: u8) -> Self {
+        let im = im & 0x0f;
+        match opcode {
+            Opcode::MovA => Instruction::Mov { reg: Reg::A, im },
+            Opcode::MovB => Instruction::Mov { reg: Reg::B, im },
+            Opcode::MovA2B => Instruction::MovAB,
+            Opcode::MovB2A => Instruction::MovBA,
+            Opcode::AddA => Instruction::Add { reg: Reg::A, im },
+            Opcode::AddB => Instruction::Add { reg: Reg::B, im },
+            Opcode::InA => Instruction::In {
+                reg: Reg::A,
+                port: im,
+            },
+            Opcode::InB => Instruction::In {
+                reg: Reg::B,
+                port: im,
+            },
+            Opcode::OutIm => Instruction::OutIm { im },
+            Opcode::OutB => Instruction::OutB { port: im },
+            Opcode::Jmp => Instruction::Jmp { im },
+            Opcode::Jnc => Instruction::Jnc { im },
+            Opcode::Call => Instruction::Call { im },
+            Opcode::Ret => Instruction::Ret,
+            Opcode::SubA => Instruction::Sub { reg: Reg::A, im },
+            Opcode::SubB => Instruction::Sub { reg: Reg::B, im },
+            Opcode::Cmp => Instruction::Cmp,
+            Opcode::Ld => Instruction::Ld,
+            Opcode::St => Instruction::St,
+            Opcode::Swap => Instruction::Swap,
+        }
+    }
+
+    pub fn decode(data: u8) -> Option<Self> {
+        Opcode::decode(data).map(|(opcode, im)| Self::new(opcode, im))
+    }
+
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Instruction::Mov { reg: Reg::A, .. } => Opcode::MovA,
+            Instruction::Mov { reg: Reg::B, .. } => Opcode::MovB,
+            Instruction::MovAB => Opcode::MovA2B,
+            Instruction::MovBA => Opcode::MovB2A,
+            Instruction::Add { reg: Reg::A, .. } => Opcode::AddA,
+            Instruction::Add { reg: Reg::B, .. } => Opcode::AddB,
+            Instruction::In { reg: Reg::A, .. } => Opcode::InA,
+            Instruction::In { reg: Reg::B, .. } => Opcode::InB,
+            Instruction::OutIm { .. } => Opcode::OutIm,
+            Instruction::OutB { .. } => Opcode::OutB,
+            Instruction::Jmp { .. } => Opcode::Jmp,
+            Instruction::Jnc { .. } => Opcode::Jnc,
+            Instruction::Call { .. } => Opcode::Call,
+            Instruction::Ret => Opcode::Ret,
+            Instruction::Sub { reg: Reg::A, .. } => Opcode::SubA,
+            Instruction::Sub { reg: Reg::B, .. } => Opcode::SubB,
+            Instruction::Cmp => Opcode::Cmp,
+            Instruction::Ld => Opcode::Ld,
+            Instruction::St => Opcode::St,
+            Instruction::Swap => Opcode::Swap,
+        }
+    }
+
+    // 即値をとる命令ならその値
+    pub fn immediate(&self) -> Option<u8> {
+        match self {
+            Instruction::Mov { im, .. }
+            | Instruction::Add { im, .. }
+            | Instruction::OutIm { im }
+            | Instruction::Jmp { im }
+            | Instruction::Jnc { im }
+            | Instruction::Call { im }
+            | Instruction::Sub { im, .. } => Some(*im),
+            Instruction::MovAB
+            | Instruction::MovBA
+            | Instruction::In { .. }
+            | Instruction::OutB { .. }
+            | Instruction::Ret
+            | Instruction::Cmp
+            | Instruction::Ld
+            | Instruction::St
+            | Instruction::Swap => None,
+        }
+    }
+
+    // 入出力命令ならポート番号
+    pub fn port(&self) -> Option<u8> {
+        match self {
+            Instruction::In { port, .. } | Instruction::OutB { port } => Some(*port),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> u8 {
+        let low = self.immediate().or(self.port()).unwrap_or(0);
+        self.opcode().encode(low)
+    }
+}
+
+// 正規形のアセンブリ。即値とポート番号は4桁の2進数で書き、ポート0は省略する
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let register = |reg: &Reg| match reg {
+            Reg::A => "A",
+            Reg::B => "B",
+        };
+        match self {
+            Instruction::Add { reg, im } => write!(f, "add {} {:04b}", register(reg), im),
+            Instruction::Mov { reg, im } => write!(f, "mov {} {:04b}", register(reg), im),
+            Instruction::Jmp { im } => write!(f, "jmp {:04b}", im),
+            Instruction::Jnc { im } => write!(f, "jnc {:04b}", im),
+            Instruction::OutIm { im } => write!(f, "out {:04b}", im),
+            Instruction::Call { im } => write!(f, "call {:04b}", im),
+            Instruction::Sub { reg, im } => write!(f, "sub {} {:04b}", register(reg), im),
+            Instruction::MovAB => write!(f, "mov A B"),
+            Instruction::MovBA => write!(f, "mov B A"),
+            Instruction::In { reg, port: 0 } => write!(f, "in {}", register(reg)),
+            Instruction::In { reg, port } => write!(f, "in {} {:04b}", register(reg), port),
+            Instruction::OutB { port: 0 } => write!(f, "out B"),
+            Instruction::OutB { port } => write!(f, "out B {:04b}", port),
+            Instruction::Ret => write!(f, "ret"),
+            Instruction::Cmp => write!(f, "cmp A B"),
+            Instruction::Ld => write!(f, "ld A [B]"),
+            Instruction::St => write!(f, "st [B] A"),
+            Instruction::Swap => write!(f, "swap A B"),
+        }
+    }
+}
+
+// Rustのコードで組み立てたプログラムを、アセンブラでそのまま読めるソースにする
+// 1行1命令で、assemble すると encode と同じバイト列になる
+pub fn to_sasm(program: &[Instruction]) -> String {
+    program
+        .iter()
+        .map(|instruction| format!("{}\n", instruction))
+        .collect()
+}
+
+pub fn to_bytes(program: &[Instruction]) -> Vec<u8> {
+    program.iter().map(Instruction::encode).collect()
+}
+
+#[cfg(test)]
+mod instruction_tests {
+    use crate::compiler::assemble;
+    use crate::instruction::{to_bytes, to_sasm, Instruction, Reg};
+
+    #[test]
+    fn test_round_trip() {
+        for data in 0..=255u8 {
+            let instruction = Instruction::decode(data).unwrap();
+            let encoded = instruction.encode();
+            assert_eq!(Instruction::decode(encoded), Some(instruction));
+            // 即値やポート番号をとる命令と下位4bitで区別する命令はそのまま戻る
+            if instruction.immediate().is_some()
+                || instruction.port().is_some()
+                || matches!(
+                    instruction,
+                    Instruction::Cmp | Instruction::Ld | Instruction::St | Instruction::Swap
+                )
+            {
+                assert_eq!(encoded, data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            Instruction::decode(0b00110101),
+            Some(Instruction::Mov { reg: Reg::A, im: 5 })
+        );
+        assert_eq!(Instruction::decode(0b10100001), Some(Instruction::Cmp));
+        // オペランドのない命令に即値は残らない
+        assert_eq!(Instruction::decode(0b00010011), Some(Instruction::MovAB));
+        assert_eq!(Instruction::MovAB.encode(), 0b00010000);
+        // 入出力命令の下位4bitはポート番号
+        assert_eq!(
+            Instruction::decode(0b01100011),
+            Some(Instruction::In {
+                reg: Reg::B,
+                port: 3
+            })
+        );
+        assert_eq!(Instruction::OutB { port: 2 }.encode(), 0b10010010);
+    }
+
+    #[test]
+    fn test_to_sasm() {
+        let program = vec![
+            Instruction::In {
+                reg: Reg::A,
+                port: 0,
+            },
+            Instruction::Add { reg: Reg::A, im: 3 },
+            Instruction::MovBA,
+            Instruction::OutB { port: 0 },
+            Instruction::Jnc { im: 0 },
+            Instruction::OutIm { im: 0b1111 },
+        ];
+        let source = to_sasm(&program);
+        assert_eq!(
+            source,
+            "in A\nadd A 0011\nmov B A\nout B\njnc 0000\nout 1111\n"
+        );
+        assert_eq!(assemble(&source).unwrap(), to_bytes(&program));
+    }
+}
@@ -0,0 +1,518 @@
+use crate::error::EmulatorErr;
+use crate::mmio::PORTS_ADDRESS;
+use crate::op::{LowBits, Opcode};
+use crate::profile::{MachineProfile, UndefinedOpcodePolicy};
+use crate::register::Register;
+use crate::stack::DEPTH;
+
+// 命令の意味を宣言的に書いた表 (実行できるISAの仕様)
+// CpuEmulator とは独立に、この表だけから1命令実行したあとの状態を計算できる
+// 命令を増やすときは op.rs の命令表と一緒にここにも1行足す
+
+// 命令を実行できる条件。満たさなければエラー
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Precondition {
+    // 標準モードでは未定義のopcodeとして扱う
+    ExtendedMode,
+    // 下位4bitのポートがあること。標準モードでは下位4bitを無視してポート0を使う
+    PortExists,
+    StackNotFull,
+    StackNotEmpty,
+}
+
+// 値の読み書きをする場所
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Location {
+    Immediate,
+    A,
+    B,
+    // 下位4bitで選んだ入出力ポート
+    Input,
+    Output,
+    // レジスタBが指すアドレス
+    Memory,
+}
+
+// レジスタ間の転送とALUの計算
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Transfer {
+    None,
+    Move { to: Location, from: Location },
+    // to + from を to に書き込む
+    Add { to: Location, from: Location },
+    // to - from を to に書き込む
+    Sub { to: Location, from: Location },
+    // lhs - rhs を計算するだけで書き込まない
+    Compare { lhs: Location, rhs: Location },
+    // lhs と rhs の値を入れ替える
+    Exchange { lhs: Location, rhs: Location },
+}
+
+// キャリーフラグの変化
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CarryEffect {
+    // ALUを使わない命令。AluCarry では0になり、ArithmeticOnly では変わらない
+    // JumpsKeepCarry ではジャンプする命令だけ変わらない
+    Unaffected,
+    // 加算の桁あふれ
+    Carry,
+    // 減算で引けなかったら1
+    Borrow,
+}
+
+// 次に実行するアドレス
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PcEffect {
+    Next,
+    // 即値のアドレスへ
+    Jump,
+    // 実行前のキャリーが0なら即値のアドレスへ
+    JumpIfNoCarry,
+    // 次のアドレスをスタックに積んで即値のアドレスへ
+    Call,
+    Return,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Semantics {
+    pub opcode: Opcode,
+    pub requires: &'static [Precondition],
+    pub transfer: Transfer,
+    pub carry: CarryEffect,
+    pub pc: PcEffect,
+}
+
+macro_rules! isa {
+    ($($opcode:ident: [$($requires:ident),*], $transfer:expr, $carry:ident, $pc:ident;)*) => {
+        pub const ISA: &[Semantics] = &[
+            $(Semantics {
+                opcode: Opcode::$opcode,
+                requires: &[$(Precondition::$requires),*],
+                transfer: $transfer,
+                carry: CarryEffect::$carry,
+                pc: PcEffect::$pc,
+            },)*
+        ];
+    };
+}
+
+use Location::*;
+
+isa! {
+    AddA: [], Transfer::Add { to: A, from: Immediate }, Carry, Next;
+    MovA2B: [], Transfer::Move { to: A, from: B }, Unaffected, Next;
+    InA: [PortExists], Transfer::Move { to: A, from: Input }, Unaffected, Next;
+    MovA: [], Transfer::Move { to: A, from: Immediate }, Unaffected, Next;
+    MovB2A: [], Transfer::Move { to: B, from: A }, Unaffected, Next;
+    AddB: [], Transfer::Add { to: B, from: Immediate }, Carry, Next;
+    InB: [PortExists], Transfer::Move { to: B, from: Input }, Unaffected, Next;
+    MovB: [], Transfer::Move { to: B, from: Immediate }, Unaffected, Next;
+    OutB: [PortExists], Transfer::Move { to: Output, from: B }, Unaffected, Next;
+    // ポートは常に0
+    OutIm: [], Transfer::Move { to: Output, from: Immediate }, Unaffected, Next;
+    Jnc: [], Transfer::None, Unaffected, JumpIfNoCarry;
+    Jmp: [], Transfer::None, Unaffected, Jump;
+    Call: [ExtendedMode, StackNotFull], Transfer::None, Unaffected, Call;
+    Ret: [ExtendedMode, StackNotEmpty], Transfer::None, Unaffected, Return;
+    SubA: [ExtendedMode], Transfer::Sub { to: A, from: Immediate }, Borrow, Next;
+    SubB: [ExtendedMode], Transfer::Sub { to: B, from: Immediate }, Borrow, Next;
+    Cmp: [ExtendedMode], Transfer::Compare { lhs: A, rhs: B }, Borrow, Next;
+    Ld: [ExtendedMode], Transfer::Move { to: A, from: Memory }, Unaffected, Next;
+    St: [ExtendedMode], Transfer::Move { to: Memory, from: A }, Unaffected, Next;
+    Swap: [ExtendedMode], Transfer::Exchange { lhs: A, rhs: B }, Unaffected, Next;
+}
+
+pub fn semantics(opcode: Opcode) -> Option<&'static Semantics> {
+    ISA.iter().find(|semantics| semantics.opcode == opcode)
+}
+
+// 仕様の上で命令が読み書きする状態
+#[derive(Debug, PartialEq, Clone)]
+pub struct ArchState {
+    pub register: Register,
+    pub inputs: Vec<u8>,
+    pub outputs: Vec<u8>,
+    // 古いものから順に並べた戻りアドレス
+    pub stack: Vec<u8>,
+    // 既定のメモリマップで 0x0 から PORTS_ADDRESS の手前までにあるRAM
+    pub ram: Vec<u8>,
+}
+
+impl ArchState {
+    pub fn new(profile: &MachineProfile) -> Self {
+        let ports = profile.mode.port_count();
+        ArchState {
+            register: Register::new(),
+            inputs: vec![0; ports],
+            outputs: vec![0; ports],
+            stack: Vec::new(),
+            ram: vec![0; PORTS_ADDRESS as usize],
+        }
+    }
+
+    fn read(&self, location: Location, im: u8, port: usize) -> u8 {
+        match location {
+            Immediate => im,
+            A => self.register.register_a(),
+            B => self.register.register_b(),
+            Input => self.inputs[port],
+            Output => self.outputs[port],
+            Memory => match self.register.register_b() {
+                PORTS_ADDRESS => self.inputs[0],
+                address => self.ram.get(address as usize).copied().unwrap_or(0),
+            },
+        }
+    }
+
+    fn write(&mut self, location: Location, port: usize, value: u8) {
+        match location {
+            A => self.register.set_register_a(value),
+            B => self.register.set_register_b(value),
+            Output => self.outputs[port] = value,
+            Memory => match self.register.register_b() {
+                PORTS_ADDRESS => self.outputs[0] = value,
+                address => {
+                    if let Some(cell) = self.ram.get_mut(address as usize) {
+                        *cell = value;
+                    }
+                }
+            },
+            Immediate | Input => (),
+        }
+    }
+}
+
+// 1バイトの命令を state の上で実行したあとの状態を表だけから計算する
+pub fn apply(
+    profile: &MachineProfile,
+    state: &ArchState,
+    data: u8,
+) -> Result<ArchState, EmulatorErr> {
+    let mut next = state.clone();
+    let register = &state.register;
+    let (semantics, im) = match Opcode::decode(data).and_then(|(opcode, im)| {
+        semantics(opcode)
+            .filter(|semantics| {
+                profile.mode.is_extended()
+                    || !semantics.requires.contains(&Precondition::ExtendedMode)
+            })
+            .map(|semantics| (semantics, im))
+    }) {
+        Some(decoded) => decoded,
+        None => {
+            return match profile.undefined_opcode_policy {
+                UndefinedOpcodePolicy::Error => Err(EmulatorErr::new("No match for opcode")),
+                UndefinedOpcodePolicy::Nop => {
+                    next.register
+                        .set_pc(register.pc().wrapping_add(1) & profile.pc_mask());
+                    Ok(next)
+                }
+            };
+        }
+    };
+
+    let mut port = 0;
+    if semantics.opcode.low_bits() == LowBits::Port {
+        port = im as usize;
+    }
+    for precondition in semantics.requires {
+        match precondition {
+            Precondition::ExtendedMode => (),
+            Precondition::PortExists if port >= state.inputs.len() => {
+                if profile.mode.is_extended() {
+                    return Err(EmulatorErr::new(&format!("port {} doesn't exist", port)));
+                }
+                port = 0;
+            }
+            Precondition::PortExists => (),
+            Precondition::StackNotFull if state.stack.len() >= DEPTH => {
+                return Err(EmulatorErr::new("Stack overflow"));
+            }
+            Precondition::StackNotEmpty if state.stack.is_empty() => {
+                return Err(EmulatorErr::new("Stack underflow"));
+            }
+            Precondition::StackNotFull | Precondition::StackNotEmpty => (),
+        }
+    }
+
+    let mask = profile.register_mask();
+    let read = |location| state.read(location, im, port);
+    // ALUの出すキャリーかボロー
+    let alu_carry = match semantics.transfer {
+        Transfer::None => 0,
+        Transfer::Move { to, from } => {
+            let value = match to {
+                Output => read(from),
+                _ => read(from) & mask,
+            };
+            next.write(to, port, value);
+            0
+        }
+        Transfer::Add { to, from } => {
+            let sum = read(to) as u16 + read(from) as u16;
+            next.write(to, port, sum as u8 & mask);
+            (sum > mask as u16) as u8
+        }
+        Transfer::Sub { to, from } => {
+            let (lhs, rhs) = (read(to), read(from));
+            next.write(to, port, lhs.wrapping_sub(rhs) & mask);
+            (lhs < rhs) as u8
+        }
+        Transfer::Compare { lhs, rhs } => (read(lhs) < read(rhs)) as u8,
+        Transfer::Exchange { lhs, rhs } => {
+            next.write(lhs, port, read(rhs));
+            next.write(rhs, port, read(lhs));
+            0
+        }
+    };
+    match semantics.carry {
+        CarryEffect::Unaffected
+            if profile
+                .flag_model
+                .keeps_carry(semantics.pc != PcEffect::Next) => {}
+        CarryEffect::Unaffected => next.register.set_carry_flag(0),
+        CarryEffect::Carry | CarryEffect::Borrow => next.register.set_carry_flag(alu_carry),
+    }
+
+    // PCは pc_bits の幅で桁あふれする
+    let following = register.pc().wrapping_add(1) & profile.pc_mask();
+    let pc = match semantics.pc {
+        PcEffect::Next => following,
+        PcEffect::Jump => im,
+        PcEffect::JumpIfNoCarry if register.carry_flag() == 0 => im,
+        PcEffect::JumpIfNoCarry => following,
+        PcEffect::Call => {
+            next.stack.push(following);
+            im
+        }
+        PcEffect::Return => next.stack.pop().unwrap_or(0),
+    };
+    next.register.set_pc(pc & profile.pc_mask());
+    Ok(next)
+}
+
+#[cfg(test)]
+mod isa_tests {
+    use crate::emulator::{CpuEmulator, PokeTarget};
+    use crate::isa::{apply, semantics, ArchState, ISA};
+    use crate::mmio::{MemoryMap, Mmio, PORTS_ADDRESS};
+    use crate::mode::{Extensions, Mode};
+    use crate::op::{LowBits, Opcode};
+    use crate::port::Ports;
+    use crate::profile::MachineProfile;
+    use crate::register::Register;
+    use crate::rom::Rom;
+    use crate::stack::DEPTH;
+    use std::sync::{Arc, Mutex};
+
+    // テストから中身を読めるRAM
+    struct SharedRam(Arc<Mutex<Vec<u8>>>);
+
+    impl Mmio for SharedRam {
+        fn read(&mut self, offset: u8) -> u8 {
+            self.0.lock().unwrap()[offset as usize]
+        }
+
+        fn write(&mut self, offset: u8, value: u8) {
+            self.0.lock().unwrap()[offset as usize] = value;
+        }
+    }
+
+    fn profiles() -> Vec<MachineProfile> {
+        vec![
+            MachineProfile::td4_strict(),
+            MachineProfile::td4_book(),
+            MachineProfile::td4_extended(),
+            MachineProfile {
+                mode: Mode::Extended(Extensions {
+                    ports: 2,
+                    ..Extensions::default()
+                }),
+                ..MachineProfile::td4_extended()
+            },
+        ]
+    }
+
+    // state の命令を CpuEmulator で実行する。スタックは call 0 を depth 回実行して積む
+    fn emulate(
+        profile: &MachineProfile,
+        state: &ArchState,
+        data: u8,
+        depth: usize,
+    ) -> Option<ArchState> {
+        let pc = state.register.pc();
+        let mut rom = vec![0; profile.rom_size];
+        rom[0] = Opcode::Call.encode(0);
+        rom[pc as usize] = data;
+        let mut emulator = CpuEmulator::with_profile(
+            Register::new(),
+            Ports::new(0, 0),
+            Rom::new(rom),
+            profile.clone(),
+        );
+        emulator.set_quiet(true);
+        let ram = Arc::new(Mutex::new(state.ram.clone()));
+        let mut memory = MemoryMap::empty();
+        memory.map(0..=PORTS_ADDRESS - 1, Box::new(SharedRam(ram.clone())));
+        memory.map_ports(PORTS_ADDRESS..=PORTS_ADDRESS);
+        emulator.set_memory_map(memory);
+
+        for _ in 0..depth {
+            emulator.step().unwrap();
+        }
+        let register = &state.register;
+        emulator.poke(PokeTarget::Pc, pc).unwrap();
+        emulator
+            .poke(PokeTarget::RegisterA, register.register_a())
+            .unwrap();
+        emulator
+            .poke(PokeTarget::RegisterB, register.register_b())
+            .unwrap();
+        emulator
+            .poke(PokeTarget::CarryFlag, register.carry_flag())
+            .unwrap();
+        for (port, input) in state.inputs.iter().enumerate() {
+            emulator.set_input_port(port, *input).unwrap();
+        }
+
+        emulator.step().ok()?;
+        let ports = emulator.port_count();
+        let ram = ram.lock().unwrap().clone();
+        Some(ArchState {
+            register: emulator.register(),
+            inputs: (0..ports)
+                .filter_map(|port| emulator.input_port(port))
+                .collect(),
+            outputs: (0..ports)
+                .filter_map(|port| emulator.output_port(port))
+                .collect(),
+            stack: emulator.stack().entries().to_vec(),
+            ram,
+        })
+    }
+
+    // opcode の全ての組み合わせで表とエミュレータの結果が一致することを確かめる
+    // プロファイル × キャリー × 境界の即値 × 境界のA, B × PC × スタックの深さ
+    fn check(opcode: Opcode) {
+        for profile in profiles() {
+            let mask = profile.register_mask();
+            let boundaries = [0, 1, mask / 2, mask / 2 + 1, mask - 1, mask];
+            let depths = if profile.mode.is_extended() {
+                vec![0, 1, DEPTH]
+            } else {
+                vec![0]
+            };
+            let last = (profile.rom_size - 1) as u8;
+
+            let mut checked = 0;
+            // 下位4bitが固定の命令はその値だけ
+            let ims = match opcode.low_bits() {
+                LowBits::Function(function) => vec![function],
+                _ => vec![0, 1, 0b0111, 0b1000, 0b1110, 0b1111],
+            };
+            for im in ims {
+                let data = opcode.op() << 4 | im;
+                if Opcode::decode(data).map(|(decoded, _)| decoded) != Some(opcode) {
+                    continue;
+                }
+                for carry in 0..=1 {
+                    for a in boundaries {
+                        for b in boundaries {
+                            for (pc, &depth) in [1, last]
+                                .iter()
+                                .flat_map(|pc| depths.iter().map(move |depth| (*pc, depth)))
+                            {
+                                let mut state = ArchState::new(&profile);
+                                state.register.set_pc(pc);
+                                state.register.set_register_a(a);
+                                state.register.set_register_b(b);
+                                state.register.set_carry_flag(carry);
+                                for (port, input) in state.inputs.iter_mut().enumerate() {
+                                    *input = (0b1010 >> port) & mask;
+                                }
+                                for (address, cell) in state.ram.iter_mut().enumerate() {
+                                    *cell = (mask - address as u8) & mask;
+                                }
+                                state.stack = vec![1; depth];
+
+                                let expected = apply(&profile, &state, data).ok();
+                                let actual = emulate(&profile, &state, data, depth);
+                                assert_eq!(
+                                    actual, expected,
+                                    "{} {:08b} from {:?}",
+                                    profile.name, data, state
+                                );
+                                checked += 1;
+                            }
+                        }
+                    }
+                }
+            }
+            assert!(checked > 0, "{:?} was never checked", opcode);
+        }
+    }
+
+    // opcodeごとにテストを1つずつ作る
+    macro_rules! isa_tests {
+        ($($name:ident: $opcode:ident,)*) => {
+            const TESTED: &[Opcode] = &[$(Opcode::$opcode,)*];
+
+            $(
+                #[test]
+                fn $name() {
+                    check(Opcode::$opcode);
+                }
+            )*
+        };
+    }
+
+    isa_tests! {
+        test_spec_add_a: AddA,
+        test_spec_mov_a2b: MovA2B,
+        test_spec_in_a: InA,
+        test_spec_mov_a: MovA,
+        test_spec_mov_b2a: MovB2A,
+        test_spec_add_b: AddB,
+        test_spec_in_b: InB,
+        test_spec_mov_b: MovB,
+        test_spec_out_b: OutB,
+        test_spec_out_im: OutIm,
+        test_spec_jnc: Jnc,
+        test_spec_jmp: Jmp,
+        test_spec_call: Call,
+        test_spec_ret: Ret,
+        test_spec_sub_a: SubA,
+        test_spec_sub_b: SubB,
+        test_spec_cmp: Cmp,
+        test_spec_ld: Ld,
+        test_spec_st: St,
+        test_spec_swap: Swap,
+    }
+
+    #[test]
+    fn test_spec_covers_every_opcode() {
+        for opcode in Opcode::ALL {
+            assert_eq!(
+                ISA.iter()
+                    .filter(|semantics| semantics.opcode == *opcode)
+                    .count(),
+                1,
+                "{:?} must be in ISA exactly once",
+                opcode
+            );
+            assert!(TESTED.contains(opcode), "{:?} has no spec test", opcode);
+            assert!(semantics(*opcode).is_some());
+        }
+        assert_eq!(ISA.len(), Opcode::ALL.len());
+    }
+
+    #[test]
+    fn test_undefined_opcodes() {
+        // 標準モードでは拡張命令 (call 0011) は未定義
+        let state = ArchState::new(&MachineProfile::td4_strict());
+        assert!(apply(&MachineProfile::td4_strict(), &state, 0b10000011).is_err());
+        let next = apply(&MachineProfile::td4_book(), &state, 0b10000011).unwrap();
+        assert_eq!(next.register.pc(), 1);
+        assert!(next.stack.is_empty());
+    }
+}
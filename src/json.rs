@@ -0,0 +1,159 @@
+use std::fmt;
+
+// --json で書き出す値。読み込みはしないので、書き出しに要るものだけを持つ
+// 外部クレートを増やさないように自前で持ち、Display で1行のJSONにする
+#[derive(Debug, PartialEq, Clone)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<Json>),
+    // キーは書いた順に並ぶ
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object<const N: usize>(fields: [(&str, Json); N]) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    pub fn array<T: Into<Json>>(items: impl IntoIterator<Item = T>) -> Json {
+        Json::Array(items.into_iter().map(Into::into).collect())
+    }
+
+    // オブジェクトの最後にキーを足す。オブジェクトでなければそのまま返す
+    pub fn with(mut self, key: &str, value: impl Into<Json>) -> Json {
+        if let Json::Object(fields) = &mut self {
+            fields.push((key.to_string(), value.into()));
+        }
+        self
+    }
+}
+
+// JSONの文字列リテラルにする
+pub fn string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Int(value) => write!(f, "{}", value),
+            // JSONには NaN や無限大がない
+            Json::Float(value) if !value.is_finite() => write!(f, "null"),
+            Json::Float(value) => write!(f, "{}", value),
+            Json::String(text) => write!(f, "{}", string(text)),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in fields.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", string(key), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Json::Bool(value)
+    }
+}
+
+impl From<u8> for Json {
+    fn from(value: u8) -> Self {
+        Json::Int(value as i64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Self {
+        Json::Int(value as i64)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(value: u64) -> Self {
+        Json::Int(value as i64)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(value: f64) -> Self {
+        Json::Float(value)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self {
+        Json::String(value)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+#[cfg(test)]
+mod json_tests {
+    use crate::json::Json;
+
+    #[test]
+    fn test_display() {
+        let json = Json::object([
+            ("name", "a \"b\"\n".into()),
+            ("bytes", Json::array([0x31u8, 0x01])),
+            ("cpi", 1.5.into()),
+            ("energy", Json::from(None::<u64>)),
+        ])
+        .with("halted", true)
+        .with("nan", f64::NAN);
+        assert_eq!(
+            json.to_string(),
+            "{\"name\":\"a \\\"b\\\"\\n\",\"bytes\":[49,1],\"cpi\":1.5,\"energy\":null,\"halted\":true,\"nan\":null}"
+        );
+        assert_eq!(Json::array(Vec::<Json>::new()).to_string(), "[]");
+        assert_eq!(Json::from(2.0).to_string(), "2");
+    }
+}
@@ -1,10 +1,84 @@
+pub mod asm_repl;
+pub mod assembler;
+pub mod batch;
+pub mod cost;
+pub mod debug_info;
+pub mod debugger;
+pub mod directive;
+pub mod disassembler;
 pub mod emulator;
+pub mod equiv;
 pub mod error;
+pub mod examples;
+pub mod expected_trace;
+pub mod explain;
+pub mod expr;
+pub mod fault;
+pub mod fuzz;
+#[cfg(feature = "gates")]
+pub mod gates;
+pub mod grader;
+pub mod hook;
+pub mod instruction;
+pub mod isa;
+pub mod json;
+pub mod lint;
+pub mod machine;
+pub mod macros;
+pub mod matcher;
+pub mod mmio;
+pub mod mode;
+pub mod model_check;
 pub mod op;
+pub mod pack;
+pub mod pipeline;
+pub mod plugin;
 pub mod port;
+pub mod prelude;
+pub mod preset;
+pub mod profile;
+pub mod profiler;
+pub mod program;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod quiz;
+#[cfg(feature = "record")]
+pub mod record;
 pub mod register;
+pub mod renderer;
+pub mod replay;
 pub mod rom;
+pub mod sandbox;
+pub mod session;
+pub mod smt;
+pub mod stack;
+pub mod state_graph;
+pub mod stimulus;
+pub mod svg;
+pub mod sweep;
+pub mod switches;
+pub mod symbolic;
+pub mod table;
+pub mod testgen;
+pub mod testing;
+pub mod timing;
 
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod capture;
 pub mod compiler;
+pub mod condition;
 pub mod parser;
-pub mod token;
+pub mod patch;
+mod token;
+pub mod tracer;
+#[cfg(feature = "web")]
+pub mod web;
+
+// td4emu コマンドのためのもの。ライブラリとしての互換性は保証しない
+#[doc(hidden)]
+pub mod completions;
+#[doc(hidden)]
+pub mod style;
+#[doc(hidden)]
+pub mod trace_viewer;
@@ -0,0 +1,201 @@
+use crate::disassembler::disassemble;
+use crate::isa::{semantics, CarryEffect, PcEffect, Precondition, Semantics};
+use crate::json::Json;
+use crate::op::Opcode;
+use crate::profile::MachineProfile;
+use std::collections::{BTreeMap, BTreeSet};
+
+// 組み立てたROMを実行せずに調べて、ほぼ確実に誤りである書き方を警告する
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Warning {
+    pub address: u8,
+    pub message: String,
+}
+
+impl Warning {
+    pub fn to_json(&self) -> Json {
+        Json::object([
+            ("address", self.address.into()),
+            ("message", self.message.as_str().into()),
+        ])
+    }
+}
+
+pub fn lint(rom: &[u8], profile: &MachineProfile) -> Vec<Warning> {
+    always_taken_jnc(rom, profile)
+}
+
+fn decode(rom: &[u8], profile: &MachineProfile, address: u8) -> Option<(&'static Semantics, u8)> {
+    let data = *rom.get(address as usize)?;
+    Opcode::decode(data).and_then(|(opcode, im)| {
+        semantics(opcode)
+            .filter(|semantics| {
+                profile.mode.is_extended()
+                    || !semantics.requires.contains(&Precondition::ExtendedMode)
+            })
+            .map(|semantics| (semantics, im))
+    })
+}
+
+// 次に実行しうるアドレス。RET はどの CALL の次にも戻りうるものとする
+fn successors(rom: &[u8], profile: &MachineProfile, address: u8, returns: &[u8]) -> Vec<u8> {
+    let following = address.wrapping_add(1);
+    let next = match decode(rom, profile, address) {
+        Some((semantics, im)) => match semantics.pc {
+            PcEffect::Next => vec![following],
+            // 自分へのジャンプは停止
+            PcEffect::Jump if im == address => vec![],
+            PcEffect::Jump | PcEffect::Call => vec![im],
+            PcEffect::JumpIfNoCarry => vec![following, im],
+            PcEffect::Return => returns.to_vec(),
+        },
+        // 未定義のopcodeは読み飛ばすか、そこで止まる
+        None => vec![following],
+    };
+    next.into_iter()
+        .filter(|address| (*address as usize) < rom.len())
+        .collect()
+}
+
+// 0番地から実行しうるアドレスと、それぞれの直前に実行しうるアドレス
+fn predecessors(rom: &[u8], profile: &MachineProfile) -> BTreeMap<u8, Vec<u8>> {
+    let returns: Vec<u8> = (0..rom.len() as u8)
+        .filter(|address| {
+            decode(rom, profile, *address)
+                .is_some_and(|(semantics, _)| semantics.pc == PcEffect::Call)
+        })
+        .map(|address| address.wrapping_add(1))
+        .collect();
+    let mut predecessors: BTreeMap<u8, Vec<u8>> = BTreeMap::new();
+    if rom.is_empty() {
+        return predecessors;
+    }
+    predecessors.insert(0, Vec::new());
+    let mut pending = vec![0];
+    while let Some(address) = pending.pop() {
+        for next in successors(rom, profile, address, &returns) {
+            if !predecessors.contains_key(&next) {
+                pending.push(next);
+            }
+            predecessors.entry(next).or_default().push(address);
+        }
+    }
+    predecessors
+}
+
+// キャリーを0にしてしまう命令を必ず通ってから実行される JNC は必ずジャンプする
+// ALUを使わない命令は、AluCarry ではキャリーを0にし、ArithmeticOnly では素通しにする
+// JumpsKeepCarry ではジャンプする命令だけ素通しにする
+fn always_taken_jnc(rom: &[u8], profile: &MachineProfile) -> Vec<Warning> {
+    let predecessors = predecessors(rom, profile);
+    let mut warnings = Vec::new();
+
+    for (address, _) in predecessors.iter() {
+        match decode(rom, profile, *address) {
+            Some((semantics, _)) if semantics.pc == PcEffect::JumpIfNoCarry => (),
+            _ => continue,
+        }
+        // さかのぼってキャリーを決めている命令を探す
+        let mut clearers = BTreeSet::new();
+        let mut from_reset = false;
+        let mut may_carry = false;
+        let mut visited = BTreeSet::new();
+        let mut pending = vec![*address];
+        while let Some(current) = pending.pop() {
+            if current == 0 {
+                from_reset = true;
+            }
+            for previous in &predecessors[&current] {
+                let effect = decode(rom, profile, *previous)
+                    .map(|(semantics, _)| (semantics.carry, semantics.pc != PcEffect::Next));
+                match effect {
+                    Some((CarryEffect::Carry | CarryEffect::Borrow, _)) => may_carry = true,
+                    Some((CarryEffect::Unaffected, jump))
+                        if !profile.flag_model.keeps_carry(jump) =>
+                    {
+                        clearers.insert(*previous);
+                    }
+                    _ => {
+                        if visited.insert(*previous) {
+                            pending.push(*previous);
+                        }
+                    }
+                }
+            }
+        }
+        if may_carry {
+            continue;
+        }
+
+        let mut causes: Vec<String> = clearers
+            .iter()
+            .map(|clearer| format!("{} at 0x{:x}", disassemble(rom[*clearer as usize]), clearer))
+            .collect();
+        if from_reset {
+            causes.push("reset".to_string());
+        }
+        warnings.push(Warning {
+            address: *address,
+            message: format!(
+                "{} always jumps: the carry is always 0 here (cleared by {})",
+                disassemble(rom[*address as usize]),
+                causes.join(", ")
+            ),
+        });
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod lint_tests {
+    use crate::compiler::assemble;
+    use crate::lint::lint;
+    use crate::profile::{FlagModel, MachineProfile};
+
+    #[test]
+    fn test_always_taken_jnc() {
+        // add の直後に mov を挟んだのでキャリーが消える
+        let rom =
+            assemble("in A\nadd A 0011\nmov B 0001\njnc 0110\nout 1111\njmp 0101\nout B").unwrap();
+        let warnings = lint(&rom, &MachineProfile::default());
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].address, 3);
+        assert_eq!(
+            warnings[0].message,
+            "jnc 0110 always jumps: the carry is always 0 here (cleared by mov B 0001 at 0x2)"
+        );
+
+        // ArithmeticOnly では mov はキャリーを変えない
+        let profile = MachineProfile {
+            flag_model: FlagModel::ArithmeticOnly,
+            ..MachineProfile::default()
+        };
+        assert!(lint(&rom, &profile).is_empty());
+
+        // JNC もキャリーを0にする
+        let rom = assemble("in A\nadd A 0011\njnc 0100\nmov A 0000\njnc 0000\nout B").unwrap();
+        let warnings = lint(&rom, &MachineProfile::default());
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].message,
+            "jnc 0000 always jumps: the carry is always 0 here (cleared by jnc 0100 at 0x2, mov A 0000 at 0x3)"
+        );
+        // どちらかの道で add を通れば警告しない
+        let rom = assemble("in A\nadd A 1000\njnc 0100\nadd A 1000\njnc 0000\nout B").unwrap();
+        assert!(lint(&rom, &MachineProfile::default()).is_empty());
+    }
+
+    #[test]
+    fn test_reset() {
+        let profile = MachineProfile {
+            flag_model: FlagModel::ArithmeticOnly,
+            ..MachineProfile::default()
+        };
+        let rom = assemble("mov A 0001\njnc 0011\nout B\nout 0001").unwrap();
+        let warnings = lint(&rom, &profile);
+        assert_eq!(
+            warnings[0].message,
+            "jnc 0011 always jumps: the carry is always 0 here (cleared by reset)"
+        );
+    }
+}
@@ -0,0 +1,972 @@
+use crate::compiler::assemble_with_profile;
+use crate::cost::CostModel;
+use crate::emulator::{CpuEmulator, PokeTarget};
+use crate::error::EmulatorErr;
+use crate::hook::{Hook, HookAction, HookState};
+use crate::instruction::Instruction;
+use crate::macros::MacroExpander;
+use crate::op::{LowBits, Opcode};
+use crate::parser::Syntax;
+use crate::plugin::{self, Plugin};
+use crate::port::{InputSource, OutputObserver, Ports};
+use crate::profile::MachineProfile;
+use crate::register::Register;
+use crate::renderer::OutputFormat;
+use crate::replay::ReplayManifest;
+use crate::rom::Rom;
+use crate::timing::{Clock, ClockDrift, ClockJitter};
+use crate::tracer::{TraceRecord, Tracer, TracerConfig};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+// 入出力ポートにつなぐ周辺装置
+pub trait Device: Send {
+    // 1命令実行するたびに呼ばれる。出力ポートの値を受け取り、入力ポートに入れる値を返す (Noneなら変えない)
+    fn tick(&mut self, cycle: usize, output: u8) -> Option<u8>;
+
+    fn reset(&mut self) {}
+}
+
+// run が止まった理由
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StopReason {
+    Halted,
+    Breakpoint(u8),
+    // max_cyclesに達した
+    CycleLimit,
+    // フックが HookAction::Pause を返した。値は次に実行する命令のアドレス
+    Paused(u8),
+}
+
+// tick で外部のシミュレータに渡す1クロック分の信号
+#[derive(Debug, PartialEq, Clone)]
+pub struct TickSignals {
+    pub cycle: usize,
+    // 実行した命令のアドレスとその中身
+    pub pc: u8,
+    pub instruction: u8,
+    // このクロックで出力ポートに書き込んだ (ポート, 値)
+    pub port_writes: Vec<(usize, u8)>,
+    pub halted: bool,
+}
+
+// expect_outputs が出力の確認を諦めるまでのサイクル数
+pub const EXPECT_CYCLES: usize = 1000;
+
+// expect_outputs_within で期待するOUTの値
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Expected {
+    Out(u8),
+    // 値を問わない
+    Any,
+}
+
+impl Expected {
+    pub fn matches(&self, output: u8) -> bool {
+        match self {
+            Expected::Out(expected) => *expected == output,
+            Expected::Any => true,
+        }
+    }
+}
+
+impl From<u8> for Expected {
+    fn from(output: u8) -> Self {
+        Expected::Out(output)
+    }
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expected::Out(output) => write!(f, "{:04b}", output),
+            Expected::Any => write!(f, "****"),
+        }
+    }
+}
+
+// ROM, CPU, 周辺装置, クロック, トレース, ブレークポイントをまとめて扱う
+// CLIやこれから作るフロントエンドはこれを通して実行し、動作をそろえる
+pub struct Machine {
+    emulator: CpuEmulator,
+    profile: MachineProfile,
+    format: OutputFormat,
+    quiet: bool,
+    devices: Vec<Box<dyn Device>>,
+    // Some なら実時間に合わせて実行する
+    clock: Option<Clock>,
+    jitter: ClockJitter,
+    tracer: Option<TracerConfig>,
+    breakpoints: BTreeSet<u8>,
+    // (サイクル, 値) の順に入力ポートを書き換える予定と、次に書き換える位置
+    inputs: Vec<(usize, u8)>,
+    next_input: usize,
+    plugins: Vec<Box<dyn Plugin>>,
+    // プラグインが足した命令の1バイトと、それを足したプラグインの名前
+    custom_instructions: BTreeMap<u8, String>,
+    hooks: Vec<Hook>,
+    // 実行前のフックで止めたサイクル。再開したときはその命令のフックを呼び直さない
+    paused_before: Option<usize>,
+    // フックが止めたアドレス。run が受け取って StopReason::Paused にする
+    paused: Option<u8>,
+}
+
+impl Machine {
+    pub fn new(profile: MachineProfile) -> Self {
+        let mut machine = Machine {
+            emulator: CpuEmulator::with_profile(
+                Register::new(),
+                Ports::new(0b0000, 0b0000),
+                Rom::new(Vec::new()),
+                profile.clone(),
+            ),
+            profile,
+            format: OutputFormat::Decimal,
+            quiet: false,
+            devices: Vec::new(),
+            clock: None,
+            jitter: ClockJitter::default(),
+            tracer: None,
+            breakpoints: BTreeSet::new(),
+            inputs: Vec::new(),
+            next_input: 0,
+            plugins: Vec::new(),
+            custom_instructions: BTreeMap::new(),
+            hooks: Vec::new(),
+            paused_before: None,
+            paused: None,
+        };
+        machine.configure();
+        machine
+    }
+
+    // マニフェストのとおりに初期状態を作って実行する。止まったあとのMachineも返す
+    pub fn run_from_manifest(
+        manifest: &ReplayManifest,
+    ) -> Result<(Machine, StopReason), EmulatorErr> {
+        let mut machine = Machine::new(manifest.profile.clone());
+        machine.set_quiet(true);
+        machine.load_rom(manifest.rom.clone())?;
+        machine.set_input_script(manifest.inputs.clone());
+
+        let register = &manifest.register;
+        let mask = manifest.profile.register_mask();
+        if register.pc() > manifest.profile.pc_mask()
+            || register.register_a() > mask
+            || register.register_b() > mask
+            || register.carry_flag() > 1
+            || manifest.input > mask
+        {
+            return Err(EmulatorErr::new(
+                "The initial state in the manifest doesn't fit the profile",
+            ));
+        }
+        // 割り込みの立ち上がり検出も初期入力から始める
+        machine
+            .emulator
+            .reset_with(register.clone(), Ports::new(manifest.input, 0b0000));
+
+        let stop = machine.run(manifest.max_cycles)?;
+        Ok((machine, stop))
+    }
+
+    // 入力ポートを決まったサイクルで書き換える。周辺装置と違って実行のたびに同じ結果になる
+    pub fn set_input_script(&mut self, mut inputs: Vec<(usize, u8)>) {
+        inputs.sort_by_key(|(cycle, _)| *cycle);
+        self.inputs = inputs;
+        self.next_input = 0;
+    }
+
+    pub fn set_output_format(&mut self, format: OutputFormat) {
+        self.format = format;
+        self.emulator.set_renderer(format.renderer());
+    }
+
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+        self.emulator.set_quiet(quiet);
+    }
+
+    pub fn set_clock(&mut self, clock: Option<f64>) {
+        self.clock = clock.map(|hz| Clock::new(hz, self.jitter));
+    }
+
+    // クロックを揺らす。ずれの累計は最初から数え直す
+    pub fn set_clock_jitter(&mut self, jitter: ClockJitter) {
+        self.jitter = jitter;
+        self.set_clock(self.clock.as_ref().map(Clock::hz));
+    }
+
+    // 実時間で実行していれば、理想の時間と実際にかかった時間
+    pub fn clock_drift(&self) -> Option<ClockDrift> {
+        self.clock.as_ref().map(Clock::drift)
+    }
+
+    // 次に読み込むプログラムから記録を始める
+    pub fn set_tracer(&mut self, config: TracerConfig) {
+        self.tracer = Some(config);
+        self.emulator.set_tracer(Tracer::new(config));
+    }
+
+    pub fn take_tracer(&self) -> Option<Tracer> {
+        self.emulator.take_tracer()
+    }
+
+    pub fn trace_records(&self) -> Vec<TraceRecord> {
+        self.emulator.trace_records()
+    }
+
+    // 読み込み直したプログラムにも引き継ぐ
+    pub fn add_output_observer(&mut self, observer: Box<dyn OutputObserver>) {
+        self.emulator.add_output_observer(observer);
+    }
+
+    pub fn set_input_source(&mut self, source: Box<dyn InputSource>) {
+        self.emulator.set_input_source(source);
+    }
+
+    // 読み込み直したプログラムにも引き継ぐ
+    pub fn set_cost_model(&mut self, cost: Box<dyn CostModel>) {
+        self.emulator.set_cost_model(cost);
+    }
+
+    pub fn attach_device(&mut self, device: Box<dyn Device>) {
+        self.devices.push(device);
+    }
+
+    // プラグインの周辺装置と命令を登録する。命令は拡張モードでしか使えない
+    pub fn add_plugin(&mut self, mut plugin: Box<dyn Plugin>) -> Result<(), EmulatorErr> {
+        let instructions = plugin.instructions();
+        if !instructions.is_empty() && !self.profile.mode.is_extended() {
+            return Err(EmulatorErr::new(&format!(
+                "Plugin {} adds instructions, which need extended mode",
+                plugin.name()
+            )));
+        }
+        let mut added = BTreeMap::new();
+        for (function, instruction) in instructions {
+            let data = plugin::encode(function)?;
+            if let Some(owner) = self.custom_instructions.get(&data) {
+                return Err(EmulatorErr::new(&format!(
+                    "Instruction {:08b} of plugin {} is already added by plugin {}",
+                    data,
+                    plugin.name(),
+                    owner
+                )));
+            }
+            if added.insert(data, instruction).is_some() {
+                return Err(EmulatorErr::new(&format!(
+                    "Plugin {} adds instruction {:08b} twice",
+                    plugin.name(),
+                    data
+                )));
+            }
+        }
+
+        for (data, instruction) in added {
+            self.custom_instructions
+                .insert(data, plugin.name().to_string());
+            self.emulator.add_custom_instruction(data, instruction);
+        }
+        for device in plugin.devices() {
+            self.attach_device(device);
+        }
+        self.plugins.push(plugin);
+        Ok(())
+    }
+
+    // 登録した順に呼ぶ。Continue 以外を返したらそのあとのフックは呼ばない
+    pub fn add_hook(&mut self, hook: Hook) {
+        self.hooks.push(hook);
+    }
+
+    pub fn add_breakpoint(&mut self, address: u8) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u8) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn emulator(&self) -> &CpuEmulator {
+        &self.emulator
+    }
+
+    pub fn load_source(&mut self, source: &str) -> Result<(), EmulatorErr> {
+        self.load_source_with(source, &MacroExpander::new())
+    }
+
+    pub fn load_source_with(
+        &mut self,
+        source: &str,
+        expander: &MacroExpander,
+    ) -> Result<(), EmulatorErr> {
+        let (program, _) = assemble_with_profile(source, expander, Syntax::V1, &self.profile)?;
+        self.load_rom(program)
+    }
+
+    pub fn load_rom(&mut self, program: Vec<u8>) -> Result<(), EmulatorErr> {
+        if program.len() > self.profile.rom_size {
+            return Err(EmulatorErr::new(&format!(
+                "Maximum memory size is {} but the program is {} bytes",
+                self.profile.rom_size,
+                program.len()
+            )));
+        }
+        let input = self.emulator.input();
+        let observers = self.emulator.take_output_observers();
+        let source = self.emulator.take_input_source();
+        let cost = self.emulator.take_cost_model();
+        let instructions = self.emulator.take_custom_instructions();
+        self.emulator = CpuEmulator::with_profile(
+            Register::new(),
+            Ports::new(input, 0b0000),
+            Rom::new(program),
+            self.profile.clone(),
+        );
+        self.configure();
+        for observer in observers {
+            self.emulator.add_output_observer(observer);
+        }
+        if let Some(source) = source {
+            self.emulator.set_input_source(source);
+        }
+        self.emulator.set_cost_model(cost);
+        for (data, instruction) in instructions {
+            self.emulator.add_custom_instruction(data, instruction);
+        }
+        self.devices.iter_mut().for_each(|device| device.reset());
+        self.next_input = 0;
+        Ok(())
+    }
+
+    // ROMはそのままに初期状態に戻す
+    pub fn reset(&mut self) {
+        self.emulator.reset();
+        if let Some(config) = self.tracer {
+            self.emulator.set_tracer(Tracer::new(config));
+        }
+        self.devices.iter_mut().for_each(|device| device.reset());
+        self.next_input = 0;
+        self.paused_before = None;
+        self.paused = None;
+    }
+
+    // 新しく作ったCpuEmulatorに設定を引き継ぐ
+    fn configure(&mut self) {
+        self.emulator.set_renderer(self.format.renderer());
+        self.emulator.set_quiet(self.quiet);
+        if let Some(config) = self.tracer {
+            self.emulator.set_tracer(Tracer::new(config));
+        }
+    }
+
+    // 1命令実行して周辺装置を動かす
+    pub fn step(&mut self) -> Result<(), EmulatorErr> {
+        let before = self.emulator.cycles();
+        self.execute()?;
+
+        if let Some(clock) = self.clock.as_mut() {
+            let elapsed = clock.advance(self.emulator.cycles() - before);
+            thread::sleep(Duration::from_secs_f64(elapsed));
+        }
+        Ok(())
+    }
+
+    // 外からクロックを与えて1クロックだけ進める。ほかのシミュレータの部品として動かすとき用
+    // Machine では全命令が1クロックなので1命令実行する
+    // 停止 (自分へのジャンプ) していても実機と同じく命令を実行し続ける
+    pub fn tick(&mut self) -> Result<TickSignals, EmulatorErr> {
+        let cycle = self.emulator.cycles();
+        let pc = self.emulator.register().pc();
+        let instruction = self.emulator.rom().get(pc as usize).copied().unwrap_or(0);
+        self.execute()?;
+        Ok(TickSignals {
+            cycle,
+            pc,
+            instruction,
+            port_writes: self.emulator.last_output_writes(),
+            halted: self.emulator.does_halt(),
+        })
+    }
+
+    // ポート0から順に入力ポートの値を入れる。tick の合間に呼ぶ
+    pub fn set_inputs(&mut self, inputs: &[u8]) -> Result<(), EmulatorErr> {
+        if inputs.len() > self.emulator.port_count() {
+            return Err(EmulatorErr::new(&format!(
+                "{} inputs are given but there are only {} ports",
+                inputs.len(),
+                self.emulator.port_count()
+            )));
+        }
+        for (port, value) in inputs.iter().enumerate() {
+            self.emulator.set_input_port(port, *value)?;
+        }
+        Ok(())
+    }
+
+    // 入力の予定と周辺装置を反映して1命令実行する
+    fn execute(&mut self) -> Result<(), EmulatorErr> {
+        let before = self.emulator.cycles();
+        while let Some((cycle, input)) = self.inputs.get(self.next_input) {
+            if *cycle > before {
+                break;
+            }
+            self.emulator.poke(PokeTarget::Input, *input)?;
+            self.next_input += 1;
+        }
+        let pc = self.emulator.register().pc();
+        if self.paused_before != Some(before) && self.call_hooks(before, pc, true)? {
+            self.paused_before = Some(before);
+            self.paused = Some(pc);
+            return Ok(());
+        }
+        self.paused_before = None;
+        self.emulator.step()?;
+
+        let output = self.emulator.output();
+        for device in self.devices.iter_mut() {
+            if let Some(input) = device.tick(before, output) {
+                self.emulator.poke(PokeTarget::Input, input)?;
+            }
+        }
+        if !self.plugins.is_empty() {
+            let record = TraceRecord {
+                cycle: before,
+                pc,
+                instruction: self.emulator.rom().get(pc as usize).copied().unwrap_or(0),
+                register: self.emulator.register(),
+                output,
+                flag_model: Some(self.profile.flag_model),
+            };
+            for plugin in self.plugins.iter_mut() {
+                plugin.on_trace(&record);
+            }
+        }
+        if self.call_hooks(before, pc, false)? {
+            self.paused = Some(self.emulator.register().pc());
+        }
+        Ok(())
+    }
+
+    // pre なら実行前のフック、でなければ実行後のフックを呼ぶ。止めるなら true
+    // cycle と pc は実行する (した) 命令のもの
+    fn call_hooks(&mut self, cycle: usize, pc: u8, pre: bool) -> Result<bool, EmulatorErr> {
+        if self.hooks.is_empty() {
+            return Ok(false);
+        }
+        let byte = self.emulator.rom().get(pc as usize).copied().unwrap_or(0);
+        let state = HookState {
+            cycle,
+            pc,
+            byte,
+            instruction: Opcode::decode(byte)
+                .filter(|(opcode, im)| opcode.low_bits() != LowBits::Zero || *im == 0)
+                .map(|(opcode, im)| Instruction::new(opcode, im)),
+            register: self.emulator.register(),
+            input: self.emulator.input(),
+            output: self.emulator.output(),
+        };
+        for hook in self.hooks.iter_mut() {
+            let action = match hook {
+                Hook::PreInstruction(hook) if pre => hook(&state),
+                Hook::PostInstruction(hook) if !pre => hook(&state),
+                _ => continue,
+            };
+            match action {
+                HookAction::Continue => (),
+                HookAction::Pause => return Ok(true),
+                HookAction::Abort(reason) => {
+                    return Err(EmulatorErr::new(&format!(
+                        "0x{:x}: aborted by a hook: {}",
+                        pc, reason
+                    )))
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    // 停止するか、ブレークポイントに着くか、max_cyclesに達するまで実行する
+    // 止まっているブレークポイントから再開できるように最初の1命令は必ず実行する
+    pub fn run(&mut self, max_cycles: Option<usize>) -> Result<StopReason, EmulatorErr> {
+        let mut first = true;
+        loop {
+            if self.emulator.does_halt() {
+                return Ok(StopReason::Halted);
+            }
+            if max_cycles.is_some_and(|max| self.emulator.cycles() >= max) {
+                return Ok(StopReason::CycleLimit);
+            }
+            let pc = self.emulator.register().pc();
+            if !first && self.breakpoints.contains(&pc) {
+                return Ok(StopReason::Breakpoint(pc));
+            }
+            self.step()?;
+            first = false;
+            if let Some(pc) = self.paused.take() {
+                return Ok(StopReason::Paused(pc));
+            }
+        }
+    }
+
+    // 今の状態から実行を続け、OUT命令で書き込まれる値が expected の順になることを確かめる
+    // 違う値が出たとき、途中で停止したとき、EXPECT_CYCLES サイクル以内に出そろわないときはpanicする
+    //
+    //     machine.load_source(examples::find("knight_rider").unwrap().source)?;
+    //     machine.expect_outputs(&[0b0001, 0b0010, 0b0100, 0b1000]);
+    #[track_caller]
+    pub fn expect_outputs(&mut self, expected: &[u8]) -> &mut Self {
+        let expected: Vec<Expected> = expected
+            .iter()
+            .map(|output| Expected::from(*output))
+            .collect();
+        self.expect_outputs_within(&expected, EXPECT_CYCLES)
+    }
+
+    // Expected::Any で値を問わない出力を混ぜられる。max_cycles は呼び出してからのサイクル数
+    #[track_caller]
+    pub fn expect_outputs_within(&mut self, expected: &[Expected], max_cycles: usize) -> &mut Self {
+        let start = self.emulator.cycles();
+        let mut actual = Vec::new();
+        while actual.len() < expected.len() {
+            let shown: Vec<String> = actual
+                .iter()
+                .map(|output| format!("{:04b}", output))
+                .collect();
+            if self.emulator.does_halt() {
+                panic!(
+                    "expected {} outputs but the program halted after [{}]",
+                    expected.len(),
+                    shown.join(", ")
+                );
+            }
+            if self.emulator.cycles() - start >= max_cycles {
+                panic!(
+                    "expected {} outputs within {} cycles but got [{}]",
+                    expected.len(),
+                    max_cycles,
+                    shown.join(", ")
+                );
+            }
+
+            let count = self.emulator.output_count();
+            if let Err(err) = self.step() {
+                panic!("{}", err);
+            }
+            // 1命令で書き込まれる値は高々1つ
+            if self.emulator.output_count() > count {
+                let output = self.emulator.output();
+                let index = actual.len();
+                if !expected[index].matches(output) {
+                    panic!(
+                        "output #{} was {:04b} but expected {}",
+                        index, output, expected[index]
+                    );
+                }
+                actual.push(output);
+            }
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod machine_tests {
+    use crate::cost::TableCost;
+    use crate::error::EmulatorErr;
+    use crate::examples;
+    use crate::hook::{Hook, HookAction};
+    use crate::machine::{Device, Expected, Machine, StopReason, TickSignals};
+    use crate::mode::{Extensions, Mode};
+    use crate::op::Opcode;
+    use crate::plugin::{self, CustomInstruction, InstructionState, Plugin};
+    use crate::port::{InputStream, OutputObserver};
+    use crate::profile::{MachineProfile, OutputModel};
+    use crate::replay::ReplayManifest;
+    use crate::timing::ClockJitter;
+    use crate::tracer::TracerConfig;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    // 出力ポートの値を1足して入力ポートに返す
+    struct Loopback {
+        ticks: usize,
+    }
+
+    impl Device for Loopback {
+        fn tick(&mut self, _cycle: usize, output: u8) -> Option<u8> {
+            self.ticks += 1;
+            Some((output + 1) & 0x0f)
+        }
+
+        fn reset(&mut self) {
+            self.ticks = 0;
+        }
+    }
+
+    fn machine() -> Machine {
+        let mut machine = Machine::new(MachineProfile::default());
+        machine.set_quiet(true);
+        machine
+    }
+
+    #[test]
+    fn test_load_and_run() {
+        let mut machine = machine();
+        assert_eq!(machine.run(None).unwrap(), StopReason::Halted);
+
+        machine.load_source("mov A 0011\nmov B A\nout B\n").unwrap();
+        assert_eq!(machine.run(None).unwrap(), StopReason::Halted);
+        assert_eq!(machine.emulator().output(), 0b0011);
+
+        machine.reset();
+        assert_eq!(machine.emulator().output(), 0);
+        machine.step().unwrap();
+        assert_eq!(machine.emulator().register().register_a(), 0b0011);
+
+        assert!(machine.load_rom(vec![0; 17]).is_err());
+        assert!(machine.load_source("mov C 0001").is_err());
+    }
+
+    #[test]
+    fn test_clock_drift() {
+        let mut machine = machine();
+        assert_eq!(machine.clock_drift(), None);
+        // 周期がいつも10%長いクロックで、20クロックの理想の時間は2ms
+        machine.set_clock(Some(10_000.0));
+        machine.set_clock_jitter(ClockJitter {
+            drift: 0.1,
+            ..ClockJitter::default()
+        });
+        machine.load_source("add A 0001\njmp 0000").unwrap();
+        machine.run(Some(20)).unwrap();
+        let drift = machine.clock_drift().unwrap();
+        assert!((drift.ideal - 0.002).abs() < 1e-9);
+        assert!((drift.ratio() - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_breakpoint_and_cycle_limit() {
+        let mut machine = machine();
+        machine
+            .load_source("add A 0001\nout 0001\njmp 0000")
+            .unwrap();
+        machine.add_breakpoint(1);
+        assert_eq!(machine.run(None).unwrap(), StopReason::Breakpoint(1));
+        assert_eq!(machine.run(None).unwrap(), StopReason::Breakpoint(1));
+        assert_eq!(machine.emulator().register().register_a(), 2);
+
+        assert!(machine.remove_breakpoint(1));
+        assert_eq!(machine.run(Some(10)).unwrap(), StopReason::CycleLimit);
+        assert_eq!(machine.emulator().cycles(), 10);
+    }
+
+    #[test]
+    fn test_hooks() {
+        let mut machine = machine();
+        machine
+            .load_source("add A 0001\nout 0001\njmp 0000")
+            .unwrap();
+        // out を実行する前に止める。再開したら同じ命令では止まらない
+        machine.add_hook(Hook::pre(|state| match state.instruction {
+            Some(instruction) if instruction.opcode() == Opcode::OutIm => HookAction::Pause,
+            _ => HookAction::Continue,
+        }));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        machine.add_hook(Hook::post(move |state| {
+            log.lock().unwrap().push((state.cycle, state.pc));
+            match state.register.register_a() {
+                3 => HookAction::Abort("A reached 3".to_string()),
+                _ => HookAction::Continue,
+            }
+        }));
+        assert_eq!(machine.run(None).unwrap(), StopReason::Paused(1));
+        assert_eq!(machine.emulator().output(), 0);
+        assert_eq!(machine.run(None).unwrap(), StopReason::Paused(1));
+        assert_eq!(machine.emulator().output(), 1);
+        assert_eq!(*seen.lock().unwrap(), vec![(0, 0), (1, 1), (2, 2), (3, 0)]);
+
+        let err = machine.run(None).unwrap_err();
+        assert_eq!(err.to_string(), "0x0: aborted by a hook: A reached 3");
+    }
+
+    #[test]
+    fn test_device_and_tracer() {
+        let mut machine = machine();
+        machine.attach_device(Box::new(Loopback { ticks: 0 }));
+        machine.set_tracer(TracerConfig::default());
+        // out 0100 のあと装置が入力に 0101 を入れる
+        machine.load_source("out 0100\nin A\n").unwrap();
+        machine.run(None).unwrap();
+        assert_eq!(machine.emulator().register().register_a(), 0b0101);
+        assert_eq!(machine.take_tracer().unwrap().records().len(), 2);
+    }
+
+    // 知らされた (サイクル, ポート, 値) を共有のVecに積む
+    struct Recorder(Arc<Mutex<Vec<(usize, usize, u8)>>>);
+
+    impl OutputObserver for Recorder {
+        fn on_output(&mut self, cycle: usize, port: usize, value: u8) {
+            self.0.lock().unwrap().push((cycle, port, value));
+        }
+    }
+
+    #[test]
+    fn test_output_observer() {
+        let outputs = Arc::new(Mutex::new(Vec::new()));
+        let mut machine = machine();
+        machine.add_output_observer(Box::new(Recorder(outputs.clone())));
+        // プログラムを読み込み直しても外れない
+        machine
+            .load_source("out 0001\nmov B 0010\nout B\n")
+            .unwrap();
+        machine.run(None).unwrap();
+        assert_eq!(
+            *outputs.lock().unwrap(),
+            vec![(0, 0, 0b0001), (2, 0, 0b0010)]
+        );
+    }
+
+    #[test]
+    fn test_pulsed_outputs() {
+        let outputs = Arc::new(Mutex::new(Vec::new()));
+        let mut machine = Machine::new(MachineProfile {
+            output_model: OutputModel::Pulsed,
+            ..MachineProfile::default()
+        });
+        machine.set_quiet(true);
+        machine.add_output_observer(Box::new(Recorder(outputs.clone())));
+        machine
+            .load_source("out 0001\nout 0011\nmov A 0001\nout 0100\nmov A 0000")
+            .unwrap();
+        machine.run(Some(4)).unwrap();
+        // 書き込んだ値は次の命令の始めに0に戻る
+        assert_eq!(
+            *outputs.lock().unwrap(),
+            vec![(0, 0, 1), (1, 0, 0), (1, 0, 3), (2, 0, 0), (3, 0, 4)]
+        );
+        assert_eq!(machine.emulator().output(), 0b0100);
+        machine.run(None).unwrap();
+        assert_eq!(machine.emulator().output(), 0);
+        assert_eq!(
+            machine.emulator().output_history(),
+            vec![(0, 1), (1, 3), (3, 4)]
+        );
+    }
+
+    #[test]
+    fn test_tick() {
+        let mut profile = MachineProfile::td4_extended();
+        profile.mode = Mode::Extended(Extensions {
+            interrupt: None,
+            ports: 2,
+        });
+        let mut machine = Machine::new(profile);
+        machine.set_quiet(true);
+        machine
+            .load_source("in A 0001\nmov B A\nout B 0001\nout 0111\njmp 0100")
+            .unwrap();
+        machine.set_inputs(&[0, 0b0101]).unwrap();
+        let signals: Vec<TickSignals> = (0..5).map(|_| machine.tick().unwrap()).collect();
+        assert_eq!(signals[0].instruction, 0b00100001);
+        assert_eq!(signals[2].port_writes, vec![(1, 0b0101)]);
+        assert_eq!(signals[3].port_writes, vec![(0, 0b0111)]);
+        assert!(!signals[2].halted && signals[3].halted);
+        // 停止していても命令を実行し続ける
+        assert_eq!((signals[4].cycle, signals[4].pc), (4, 4));
+        assert_eq!(machine.tick().unwrap().cycle, 5);
+
+        assert!(machine.set_inputs(&[0, 0, 0]).is_err());
+        assert!(machine.set_inputs(&[16]).is_err());
+    }
+
+    #[test]
+    fn test_input_source() {
+        let mut machine = machine();
+        machine.set_input_source(Box::new(InputStream::new(Cursor::new("1\n2\n16\n"), None)));
+        machine
+            .load_source("in A\nmov B A\nout B\nin B\nout B\nin A\n")
+            .unwrap();
+        // 3回目のINで読んだ16は4bitに入らない
+        assert!(machine.run(None).is_err());
+        assert_eq!(machine.emulator().output_history(), vec![(2, 1), (4, 2)]);
+    }
+
+    #[test]
+    fn test_cost_model() {
+        let mut machine = machine();
+        machine.set_cost_model(Box::new(TableCost::new(1).with(Opcode::OutIm, 5)));
+        // 読み込み直しても引き継ぐ
+        machine
+            .load_source("out 0001\nadd A 0001\nout 0010")
+            .unwrap();
+        machine.run(None).unwrap();
+        let stats = machine.emulator().stats();
+        assert_eq!((stats.instructions, stats.energy), (3, 11));
+        machine.reset();
+        assert_eq!(machine.emulator().stats().energy, 0);
+    }
+
+    #[test]
+    fn test_expect_outputs() {
+        let mut machine = machine();
+        machine
+            .load_source(examples::find("knight_rider").unwrap().source)
+            .unwrap();
+        machine
+            .expect_outputs(&[0b0001, 0b0010, 0b0100, 0b1000])
+            .expect_outputs(&[0b0100, 0b0010, 0b0001]);
+        machine.expect_outputs_within(&[Expected::Any, Expected::Any, 0b1000.into()], 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "output #1 was 0010 but expected 0100")]
+    fn test_expect_outputs_mismatch() {
+        let mut machine = machine();
+        machine
+            .load_source(examples::find("knight_rider").unwrap().source)
+            .unwrap();
+        machine.expect_outputs(&[0b0001, 0b0100]);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected 2 outputs within 10 cycles but got [0001]")]
+    fn test_expect_outputs_timeout() {
+        let mut machine = machine();
+        machine
+            .load_source("out 0001\nadd A 0001\njmp 0001")
+            .unwrap();
+        machine.expect_outputs_within(&[Expected::Any, Expected::Any], 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected 2 outputs but the program halted after [0001]")]
+    fn test_expect_outputs_halt() {
+        let mut machine = machine();
+        machine.load_source("out 0001").unwrap();
+        machine.expect_outputs(&[0b0001, 0b0001]);
+    }
+
+    #[test]
+    fn test_run_from_manifest() {
+        // 入力をBに移して出力し続ける
+        let source = "in A\nmov B A\nout B\njmp 0000";
+        let mut manifest = ReplayManifest::new(
+            crate::compiler::assemble(source).unwrap(),
+            MachineProfile::default(),
+        );
+        manifest.input = 0b0001;
+        manifest.inputs = vec![(4, 0b0010), (8, 0b0100)];
+        manifest.max_cycles = Some(12);
+
+        let text = manifest.to_string();
+        let (machine, stop) = Machine::run_from_manifest(&text.parse().unwrap()).unwrap();
+        assert_eq!(stop, StopReason::CycleLimit);
+        assert_eq!(
+            machine.emulator().output_history(),
+            vec![(2, 0b0001), (6, 0b0010), (10, 0b0100)]
+        );
+        // 何度実行しても同じ
+        let (again, _) = Machine::run_from_manifest(&manifest).unwrap();
+        assert_eq!(
+            again.emulator().output_history(),
+            machine.emulator().output_history()
+        );
+
+        manifest.input = 0b10000;
+        assert!(Machine::run_from_manifest(&manifest).is_err());
+    }
+
+    // 入力ポートに温度を入れる装置と、A と B の排他的論理和を A に入れる命令を足す
+    struct Sensor {
+        traced: Arc<Mutex<Vec<u8>>>,
+    }
+
+    struct Thermometer;
+
+    impl Device for Thermometer {
+        fn tick(&mut self, _cycle: usize, _output: u8) -> Option<u8> {
+            Some(0b0110)
+        }
+    }
+
+    struct Xor;
+
+    impl CustomInstruction for Xor {
+        fn execute(&mut self, state: &mut InstructionState) -> Result<(), EmulatorErr> {
+            state.a ^= state.b;
+            state.carry = 0;
+            Ok(())
+        }
+    }
+
+    impl Plugin for Sensor {
+        fn name(&self) -> &str {
+            "sensor"
+        }
+
+        fn devices(&mut self) -> Vec<Box<dyn Device>> {
+            vec![Box::new(Thermometer)]
+        }
+
+        fn instructions(&mut self) -> Vec<(u8, Box<dyn CustomInstruction>)> {
+            vec![(0b0101, Box::new(Xor))]
+        }
+
+        fn on_trace(&mut self, record: &crate::tracer::TraceRecord) {
+            self.traced.lock().unwrap().push(record.pc);
+        }
+    }
+
+    #[test]
+    fn test_plugin() {
+        let traced = Arc::new(Mutex::new(Vec::new()));
+        let mut machine = Machine::new(MachineProfile::td4_extended());
+        machine.set_quiet(true);
+        machine
+            .add_plugin(Box::new(Sensor {
+                traced: traced.clone(),
+            }))
+            .unwrap();
+        // 読み込み直しても命令は残る
+        machine
+            .load_source(
+                "mov A 0011
+",
+            )
+            .unwrap();
+        machine
+            .load_source(
+                "mov B 0101
+in A
+.byte 0b10100101
+mov B A
+out B
+",
+            )
+            .unwrap();
+        machine.run(None).unwrap();
+        assert_eq!(machine.emulator().output(), 0b0110 ^ 0b0101);
+        assert_eq!(*traced.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+
+        // 同じ命令は2度足せない
+        let err = machine
+            .add_plugin(Box::new(Sensor {
+                traced: traced.clone(),
+            }))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Instruction 10100101 of plugin sensor is already added by plugin sensor"
+        );
+        // 標準モードには空いているopcodeがない
+        let mut machine = Machine::new(MachineProfile::default());
+        assert!(machine.add_plugin(Box::new(Sensor { traced })).is_err());
+        // SWAP までが使っている
+        assert!(plugin::encode(0b0100).is_err());
+    }
+}
@@ -0,0 +1,449 @@
+use crate::error::EmulatorErr;
+use std::collections::HashMap;
+
+// パースの前にマクロを展開するパス
+// 展開した行にも元の行番号を残してエラーが呼び出し位置を指すようにする
+#[derive(Debug, PartialEq, Clone)]
+pub struct SourceLine {
+    // ソース上の行番号 (ユーザー定義マクロの中身ならその定義の行)
+    pub number: usize,
+    pub text: String,
+    // どのマクロを何行目で展開した結果か (内側から順に)
+    pub expanded_at: Vec<(String, usize)>,
+}
+
+impl SourceLine {
+    pub fn new(number: usize, text: impl Into<String>) -> Self {
+        SourceLine {
+            number,
+            text: text.into(),
+            expanded_at: Vec::new(),
+        }
+    }
+
+    // ファイル上の行番号。マクロの中身なら一番外側の呼び出しの行
+    pub fn file_line(&self) -> usize {
+        self.expanded_at
+            .last()
+            .map_or(self.number, |(_, line)| *line)
+    }
+
+    // "line 2 in macro inc expanded at line 7" のようなエラーメッセージ用の位置
+    pub fn location(&self) -> String {
+        let mut location = format!("line {}", self.number);
+        for (name, line) in &self.expanded_at {
+            location.push_str(&format!(" in macro {} expanded at line {}", name, line));
+        }
+        location
+    }
+}
+
+// マクロの展開が深すぎるときは再帰しているとみなす
+pub const MAX_EXPANSION_DEPTH: usize = 16;
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<SourceLine>,
+}
+
+// 組み込みマクロとその展開先
+// nop と clc はどちらも桁上がりしない add A 0000 (実機と同じくキャリーは0になる)
+// sec は A を 1111 にしてから 1 を足し、桁上がりでキャリーを立てる (A は 0000 になる)
+// halt は自分自身へのジャンプで止まる
+pub const BUILTIN_MACROS: [(&str, &[&str]); 4] = [
+    ("nop", &["add A 0000"]),
+    ("clc", &["add A 0000"]),
+    ("sec", &["mov A 1111", "add A 0001"]),
+    ("halt", &["jmp $"]),
+];
+
+#[derive(Clone)]
+pub struct MacroExpander {
+    builtins: bool,
+}
+
+impl MacroExpander {
+    pub fn new() -> Self {
+        MacroExpander { builtins: true }
+    }
+
+    // 組み込みマクロを展開しない (同じ名前を別の用途に使いたいとき用)
+    pub fn without_builtins() -> Self {
+        MacroExpander { builtins: false }
+    }
+
+    // .macro name arg1 arg2 ... から .endm までを定義として取り出し、呼び出しを展開する
+    // そのあと .alias で付けたレジスタの別名を A / B に戻す
+    pub fn expand(&self, source: &str) -> Result<Vec<SourceLine>, EmulatorErr> {
+        let (macros, lines) = Self::collect_definitions(source)?;
+
+        let mut result = Vec::new();
+        for line in lines {
+            self.expand_line(line, &macros, 0, &mut result)?;
+        }
+        resolve_aliases(result)
+    }
+
+    fn collect_definitions(
+        source: &str,
+    ) -> Result<(HashMap<String, Macro>, Vec<SourceLine>), EmulatorErr> {
+        let mut macros = HashMap::new();
+        let mut lines = Vec::new();
+        // 定義中のマクロの名前と定義を始めた行
+        let mut defining: Option<(String, usize, Macro)> = None;
+
+        for (index, text) in source.lines().enumerate() {
+            let line = SourceLine::new(index + 1, text);
+            let words: Vec<&str> = text.split_whitespace().collect();
+
+            match (words.first().copied(), &mut defining) {
+                (Some(".macro"), Some(_)) => {
+                    return Err(EmulatorErr::new(&format!(
+                        "{}: macros can't be defined inside a macro",
+                        line.location()
+                    )));
+                }
+                (Some(".macro"), None) => {
+                    let name = words.get(1).ok_or_else(|| {
+                        EmulatorErr::new(&format!("{}: .macro needs a name", line.location()))
+                    })?;
+                    if macros.contains_key(*name) {
+                        return Err(EmulatorErr::new(&format!(
+                            "{}: macro {} is already defined",
+                            line.location(),
+                            name
+                        )));
+                    }
+                    let params = words[2..].iter().map(|param| param.to_string()).collect();
+                    let body = Vec::new();
+                    defining = Some((name.to_string(), line.number, Macro { params, body }));
+                }
+                (Some(".endm"), _) => {
+                    let (name, _, definition) = defining.take().ok_or_else(|| {
+                        EmulatorErr::new(&format!("{}: .endm without .macro", line.location()))
+                    })?;
+                    macros.insert(name, definition);
+                }
+                (_, Some((_, _, definition))) => definition.body.push(line),
+                (_, None) => lines.push(line),
+            }
+        }
+
+        if let Some((name, number, _)) = defining {
+            return Err(EmulatorErr::new(&format!(
+                "line {}: macro {} is missing .endm",
+                number, name
+            )));
+        }
+        Ok((macros, lines))
+    }
+
+    fn expand_line(
+        &self,
+        line: SourceLine,
+        macros: &HashMap<String, Macro>,
+        depth: usize,
+        result: &mut Vec<SourceLine>,
+    ) -> Result<(), EmulatorErr> {
+        let mut words = line.text.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let rest: Vec<&str> = words.collect();
+
+        if let Some(definition) = macros.get(name) {
+            if depth >= MAX_EXPANSION_DEPTH {
+                return Err(EmulatorErr::new(&format!(
+                    "{}: macro {} is nested more than {} levels (recursive macro?)",
+                    line.location(),
+                    name,
+                    MAX_EXPANSION_DEPTH
+                )));
+            }
+
+            let args = Self::arguments(&rest.join(" "));
+            if args.len() != definition.params.len() {
+                return Err(EmulatorErr::new(&format!(
+                    "{}: macro {} takes {} argument(s) but {} were given",
+                    line.location(),
+                    name,
+                    definition.params.len(),
+                    args.len()
+                )));
+            }
+
+            for body_line in &definition.body {
+                let mut expanded_at = vec![(name.to_string(), line.number)];
+                expanded_at.extend(line.expanded_at.iter().cloned());
+                let expanded = SourceLine {
+                    number: body_line.number,
+                    text: substitute(&body_line.text, &definition.params, &args),
+                    expanded_at,
+                };
+                self.expand_line(expanded, macros, depth + 1, result)?;
+            }
+            return Ok(());
+        }
+
+        match self.builtin(name) {
+            // 組み込みマクロはオペランドを取らないので、付いていればそのまま残してパーサーに任せる
+            Some(body) if rest.is_empty() => {
+                for text in body {
+                    result.push(SourceLine {
+                        text: text.to_string(),
+                        ..line.clone()
+                    });
+                }
+            }
+            _ => result.push(line),
+        }
+        Ok(())
+    }
+
+    // 引数はカンマがあればカンマで、なければ空白で区切る
+    fn arguments(text: &str) -> Vec<String> {
+        if text.trim().is_empty() {
+            Vec::new()
+        } else if text.contains(',') {
+            text.split(',').map(|arg| arg.trim().to_string()).collect()
+        } else {
+            text.split_whitespace().map(|arg| arg.to_string()).collect()
+        }
+    }
+
+    fn builtin(&self, name: &str) -> Option<&'static [&'static str]> {
+        if !self.builtins {
+            return None;
+        }
+        let name = name.to_lowercase();
+        BUILTIN_MACROS
+            .iter()
+            .find(|(macro_name, _)| *macro_name == name)
+            .map(|(_, body)| *body)
+    }
+}
+
+// 本体の中の引数名を単語単位で実引数に置き換える ('a' のような文字リテラルの中は置き換えない)
+fn substitute(text: &str, params: &[String], args: &[String]) -> String {
+    let mut result = String::new();
+    let mut word = String::new();
+    let mut in_quote = false;
+
+    let flush = |word: &mut String, result: &mut String| {
+        match params.iter().position(|param| param == word) {
+            Some(index) => result.push_str(&args[index]),
+            None => result.push_str(word),
+        }
+        word.clear();
+    };
+
+    for c in text.chars() {
+        if !in_quote && (c.is_ascii_alphanumeric() || c == '_') {
+            word.push(c);
+            continue;
+        }
+        flush(&mut word, &mut result);
+        if c == '\'' {
+            in_quote = !in_quote;
+        }
+        result.push(c);
+    }
+    flush(&mut word, &mut result);
+    result
+}
+
+// .alias name A のあとの行では name を単語単位で A に置き換える
+// マクロを展開したあとに置き換えるので、マクロの引数に別名を渡せる
+fn resolve_aliases(lines: Vec<SourceLine>) -> Result<Vec<SourceLine>, EmulatorErr> {
+    let mut names = Vec::new();
+    let mut registers = Vec::new();
+    let mut result = Vec::new();
+
+    for mut line in lines {
+        let words: Vec<&str> = line.text.split_whitespace().collect();
+        if words.first() != Some(&".alias") {
+            line.text = substitute(&line.text, &names, &registers);
+            result.push(line);
+            continue;
+        }
+        // 別名に別名を付けてもよい
+        let register = words
+            .get(2)
+            .map(|word| substitute(word, &names, &registers));
+        match (&words[1..], register.as_deref()) {
+            ([name, _], Some(register @ ("A" | "B"))) if is_alias_name(name) => {
+                if names.iter().any(|defined| defined == name) {
+                    return Err(EmulatorErr::new(&format!(
+                        "{}: alias {} is already defined",
+                        line.location(),
+                        name
+                    )));
+                }
+                names.push(name.to_string());
+                registers.push(register.to_string());
+            }
+            _ => {
+                return Err(EmulatorErr::new(&format!(
+                    "{}: write .alias name A or .alias name B",
+                    line.location()
+                )))
+            }
+        }
+    }
+    Ok(result)
+}
+
+// 置き換えられるのは英数字と _ だけの名前。レジスタ名そのものは別名にできない
+fn is_alias_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !matches!(name, "A" | "B")
+}
+
+impl Default for MacroExpander {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod macros_tests {
+    use crate::compiler::assemble;
+    use crate::macros::MacroExpander;
+
+    #[test]
+    fn test_expand_builtins() {
+        let lines = MacroExpander::new().expand("out B\nSEC\nhalt").unwrap();
+        let texts: Vec<(usize, &str)> = lines
+            .iter()
+            .map(|line| (line.number, line.text.as_str()))
+            .collect();
+        assert_eq!(
+            texts,
+            vec![
+                (1, "out B"),
+                (2, "mov A 1111"),
+                (2, "add A 0001"),
+                (3, "jmp $")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_builtins_assemble() {
+        let program = assemble("nop\nclc\nsec\nhalt").unwrap();
+        assert_eq!(
+            program,
+            vec![0b00000000, 0b00000000, 0b00111111, 0b00000001, 0b11110100]
+        );
+    }
+
+    #[test]
+    fn test_without_builtins() {
+        let lines = MacroExpander::without_builtins().expand("nop").unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].text, "nop");
+    }
+
+    #[test]
+    fn test_user_macro() {
+        let source = ".macro blink on off
+out on
+out off
+.endm
+.macro twice a
+add a 0001
+add a 0001
+.endm
+blink 0b0101, ~0b0101 & 0xf
+twice B
+halt";
+        let program = assemble(source).unwrap();
+        assert_eq!(
+            program,
+            vec![0b10110101, 0b10111010, 0b01010001, 0b01010001, 0b11110100]
+        );
+    }
+
+    #[test]
+    fn test_errors_point_at_body_and_call_site() {
+        let source = ".macro load value\nmov A value\n.endm\nout B\nload 16";
+        let err = assemble(source).unwrap_err().to_string();
+        assert!(
+            err.starts_with("line 2 in macro load expanded at line 5:"),
+            "{}",
+            err
+        );
+
+        let nested = ".macro inner\njmp 99\n.endm\n.macro outer\ninner\n.endm\nouter";
+        let err = assemble(nested).unwrap_err().to_string();
+        assert!(
+            err.starts_with(
+                "line 2 in macro inner expanded at line 5 in macro outer expanded at line 7:"
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_macro_definition_errors() {
+        let expander = MacroExpander::new();
+        assert!(expander.expand(".macro loop\nloop\n.endm\nloop").is_err());
+        assert!(expander.expand(".macro m a\nout a\n.endm\nm").is_err());
+        assert!(expander.expand(".macro m\nout B").is_err());
+        assert!(expander.expand(".endm").is_err());
+        assert!(expander.expand(".macro m\n.macro n\n.endm\n.endm").is_err());
+        assert!(expander.expand(".macro m\n.endm\n.macro m\n.endm").is_err());
+    }
+
+    #[test]
+    fn test_substitute_skips_char_literals() {
+        let lines = MacroExpander::new()
+            .expand(".macro show a\nout a & 'a'\n.endm\nshow 1")
+            .unwrap();
+        assert_eq!(lines[0].text, "out 1 & 'a'");
+    }
+
+    #[test]
+    fn test_aliases() {
+        let source = ".alias counter B
+.macro bump reg
+add reg 0001
+.endm
+mov counter 0000
+bump counter
+out counter
+.alias acc A
+.alias total acc
+swap total counter";
+        let program = MacroExpander::new().expand(source).unwrap();
+        let texts: Vec<&str> = program.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, vec!["mov B 0000", "add B 0001", "out B", "swap A B"]);
+        assert_eq!(
+            program[1].location(),
+            "line 3 in macro bump expanded at line 6"
+        );
+    }
+
+    #[test]
+    fn test_alias_errors() {
+        let expander = MacroExpander::new();
+        for source in [
+            ".alias counter C",
+            ".alias counter",
+            ".alias A B",
+            ".alias 2x A",
+            ".alias x A\n.alias x B",
+        ] {
+            assert!(expander.expand(source).is_err(), "{}", source);
+        }
+        let err = expander
+            .expand("out B\n.alias x A\n.alias x B")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "line 3: alias x is already defined");
+        let err = expander.expand(".alias x C").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1: write .alias name A or .alias name B"
+        );
+    }
+}
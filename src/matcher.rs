@@ -0,0 +1,304 @@
+use crate::debugger::parse_number;
+use crate::error::EmulatorErr;
+use std::fmt;
+use std::str::FromStr;
+
+// 出力の並び全体ではなく性質を確かめる採点の条件 (grade の expect)
+//
+//     within 20 cycles output becomes 0b1000
+//     output toggles bit3 at least 4 times
+//     output is monotonically increasing
+//     output never becomes 0b1111
+//
+// 止まらないプログラム (点滅やカウンタ) もこれで採点できる
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum OutputMatcher {
+    // 最初の cycles サイクルのうちに OUT でその値を書く
+    Within { cycles: usize, value: u8 },
+    // 出力ポートのそのbitが times 回以上変わる。ポートはリセット直後の0から数える
+    Toggles { bit: u8, times: usize },
+    // OUT で書く値が前に書いた値より小さく (大きく) ならない。同じ値が続くのはよい
+    Increasing,
+    Decreasing,
+    Never { value: u8 },
+}
+
+impl FromStr for OutputMatcher {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |message: &str| EmulatorErr::new(&format!("matcher {}: {}", s.trim(), message));
+        let count = |text: &str| {
+            text.parse::<usize>()
+                .map_err(|_| error(&format!("{} is not a count", text)))
+        };
+        let value = |text: &str| parse_number(text).map_err(|err| error(&err.to_string()));
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words.as_slice() {
+            ["within", cycles, "cycles", "output", "becomes", output] => {
+                Ok(OutputMatcher::Within {
+                    cycles: count(cycles)?,
+                    value: value(output)?,
+                })
+            }
+            ["output", "toggles", bit, "at", "least", times, "times"] => {
+                let bit = bit
+                    .strip_prefix("bit")
+                    .and_then(|bit| bit.parse::<u8>().ok())
+                    .filter(|bit| *bit < 8)
+                    .ok_or_else(|| error(&format!("{} is not bit0 to bit7", bit)))?;
+                Ok(OutputMatcher::Toggles {
+                    bit,
+                    times: count(times)?,
+                })
+            }
+            ["output", "is", "monotonically", "increasing"] => Ok(OutputMatcher::Increasing),
+            ["output", "is", "monotonically", "decreasing"] => Ok(OutputMatcher::Decreasing),
+            ["output", "never", "becomes", output] => Ok(OutputMatcher::Never {
+                value: value(output)?,
+            }),
+            _ => Err(error("unknown matcher")),
+        }
+    }
+}
+
+impl fmt::Display for OutputMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputMatcher::Within { cycles, value } => {
+                write!(f, "within {} cycles output becomes 0b{:04b}", cycles, value)
+            }
+            OutputMatcher::Toggles { bit, times } => {
+                write!(f, "output toggles bit{} at least {} times", bit, times)
+            }
+            OutputMatcher::Increasing => write!(f, "output is monotonically increasing"),
+            OutputMatcher::Decreasing => write!(f, "output is monotonically decreasing"),
+            OutputMatcher::Never { value } => write!(f, "output never becomes 0b{:04b}", value),
+        }
+    }
+}
+
+// 実行しながら出力を1サイクルずつ受け取って条件を判定する
+// 全ての条件が決まったら (またはどれかが外れたら) 実行を打ち切ってよい
+pub struct Evaluator {
+    matchers: Vec<OutputMatcher>,
+    // 決まった結果。Err は外れた理由
+    verdicts: Vec<Option<Result<(), String>>>,
+    // Toggles で数えたbitの変化
+    toggles: Vec<usize>,
+    // 出力ポートの今の値と最後に OUT で書いた値
+    port: u8,
+    last: Option<u8>,
+}
+
+impl Evaluator {
+    pub fn new(matchers: Vec<OutputMatcher>) -> Self {
+        let count = matchers.len();
+        Evaluator {
+            matchers,
+            verdicts: vec![None; count],
+            toggles: vec![0; count],
+            port: 0,
+            last: None,
+        }
+    }
+
+    // cycle の命令を実行し終えたところで呼ぶ。output はその命令が出力ポート0に書いた値
+    pub fn observe(&mut self, cycle: usize, output: Option<u8>) {
+        for (index, matcher) in self.matchers.iter().enumerate() {
+            if self.verdicts[index].is_some() {
+                continue;
+            }
+            let verdict = &mut self.verdicts[index];
+            match (matcher, output) {
+                (OutputMatcher::Within { value, .. }, Some(output)) if output == *value => {
+                    *verdict = Some(Ok(()))
+                }
+                (OutputMatcher::Within { cycles, .. }, _) if cycle + 1 >= *cycles => {
+                    let output = output.unwrap_or(self.port);
+                    *verdict = Some(Err(format!(
+                        "output was 0b{:04b} at cycle {}",
+                        output, cycle
+                    )))
+                }
+                (OutputMatcher::Toggles { bit, times }, Some(output)) => {
+                    if (self.port ^ output) >> bit & 1 == 1 {
+                        self.toggles[index] += 1;
+                    }
+                    if self.toggles[index] >= *times {
+                        *verdict = Some(Ok(()));
+                    }
+                }
+                (OutputMatcher::Increasing, Some(output)) => match self.last {
+                    Some(last) if output < last => {
+                        *verdict = Some(Err(format!(
+                            "0b{:04b} came after 0b{:04b} at cycle {}",
+                            output, last, cycle
+                        )))
+                    }
+                    _ => (),
+                },
+                (OutputMatcher::Decreasing, Some(output)) => match self.last {
+                    Some(last) if output > last => {
+                        *verdict = Some(Err(format!(
+                            "0b{:04b} came after 0b{:04b} at cycle {}",
+                            output, last, cycle
+                        )))
+                    }
+                    _ => (),
+                },
+                (OutputMatcher::Never { value }, Some(output)) if output == *value => {
+                    *verdict = Some(Err(format!("it did at cycle {}", cycle)))
+                }
+                _ => (),
+            }
+        }
+        if let Some(output) = output {
+            self.port = output;
+            self.last = Some(output);
+        }
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.verdicts
+            .iter()
+            .any(|verdict| matches!(verdict, Some(Err(_))))
+            || self.verdicts.iter().all(Option::is_some)
+    }
+
+    // cycles サイクルで実行を終えたときの結果。外れた最初の条件をエラーにする
+    // まだ決まっていない Within と Toggles は外れ、Increasing などは最後まで守られたので合う
+    pub fn finish(&self, cycles: usize) -> Result<(), EmulatorErr> {
+        for (index, matcher) in self.matchers.iter().enumerate() {
+            let reason = match (&self.verdicts[index], matcher) {
+                (Some(Ok(())), _) => continue,
+                (Some(Err(reason)), _) => reason.clone(),
+                (None, OutputMatcher::Within { .. }) => {
+                    format!("the program stopped after {} cycles", cycles)
+                }
+                (None, OutputMatcher::Toggles { bit, .. }) => format!(
+                    "bit{} toggled {} times in {} cycles",
+                    bit, self.toggles[index], cycles
+                ),
+                (None, _) => continue,
+            };
+            return Err(EmulatorErr::new(&format!("{}, but {}", matcher, reason)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod matcher_tests {
+    use crate::matcher::{Evaluator, OutputMatcher};
+
+    fn evaluate(matcher: &str, outputs: &[(usize, u8)], cycles: usize) -> Result<(), String> {
+        let mut evaluator = Evaluator::new(vec![matcher.parse().unwrap()]);
+        for cycle in 0..cycles {
+            let output = outputs
+                .iter()
+                .find(|(at, _)| *at == cycle)
+                .map(|(_, output)| *output);
+            evaluator.observe(cycle, output);
+            if evaluator.is_settled() {
+                break;
+            }
+        }
+        evaluator.finish(cycles).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn test_parse() {
+        let matcher: OutputMatcher = "within 20 cycles output becomes 8".parse().unwrap();
+        assert_eq!(
+            matcher,
+            OutputMatcher::Within {
+                cycles: 20,
+                value: 8
+            }
+        );
+        assert_eq!(
+            matcher.to_string(),
+            "within 20 cycles output becomes 0b1000"
+        );
+        for text in [
+            "output toggles bit3 at least 4 times",
+            "output is monotonically increasing",
+            "output never becomes 0b1111",
+        ] {
+            let matcher: OutputMatcher = text.parse().unwrap();
+            assert_eq!(matcher.to_string(), text);
+        }
+        let err = "output toggles bit9 at least 1 times"
+            .parse::<OutputMatcher>()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "matcher output toggles bit9 at least 1 times: bit9 is not bit0 to bit7"
+        );
+        assert!("output is sorted".parse::<OutputMatcher>().is_err());
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let within = "within 4 cycles output becomes 0b1000";
+        assert_eq!(evaluate(within, &[(1, 4), (3, 8)], 100), Ok(()));
+        assert_eq!(
+            evaluate(within, &[(1, 4), (4, 8)], 100),
+            Err(
+                "within 4 cycles output becomes 0b1000, but output was 0b0100 at cycle 3"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            evaluate(within, &[], 2),
+            Err(
+                "within 4 cycles output becomes 0b1000, but the program stopped after 2 cycles"
+                    .to_string()
+            )
+        );
+
+        // 0 -> 8 -> 0 -> 9 で bit3 は3回変わる
+        let toggles = "output toggles bit3 at least 3 times";
+        assert_eq!(evaluate(toggles, &[(0, 8), (2, 0), (4, 9)], 100), Ok(()));
+        assert_eq!(
+            evaluate(toggles, &[(0, 8), (2, 9)], 10),
+            Err(
+                "output toggles bit3 at least 3 times, but bit3 toggled 1 times in 10 cycles"
+                    .to_string()
+            )
+        );
+
+        let increasing = "output is monotonically increasing";
+        assert_eq!(evaluate(increasing, &[(0, 1), (1, 1), (2, 3)], 5), Ok(()));
+        assert_eq!(
+            evaluate(increasing, &[(0, 2), (1, 1)], 5),
+            Err(
+                "output is monotonically increasing, but 0b0001 came after 0b0010 at cycle 1"
+                    .to_string()
+            )
+        );
+        assert!(evaluate("output is monotonically decreasing", &[(0, 2), (1, 1)], 5).is_ok());
+        assert!(evaluate("output never becomes 15", &[(0, 14), (1, 15)], 5).is_err());
+    }
+
+    #[test]
+    fn test_settles_early() {
+        let mut evaluator = Evaluator::new(vec![
+            "within 10 cycles output becomes 1".parse().unwrap(),
+            "output toggles bit0 at least 1 times".parse().unwrap(),
+        ]);
+        evaluator.observe(0, None);
+        assert!(!evaluator.is_settled());
+        evaluator.observe(1, Some(1));
+        assert!(evaluator.is_settled());
+        assert!(evaluator.finish(2).is_ok());
+
+        // Increasing は最後まで見ないと決まらない
+        let mut evaluator = Evaluator::new(vec![OutputMatcher::Increasing]);
+        evaluator.observe(0, Some(1));
+        assert!(!evaluator.is_settled());
+        evaluator.observe(1, Some(0));
+        assert!(evaluator.is_settled());
+    }
+}
@@ -0,0 +1,138 @@
+use std::ops::RangeInclusive;
+
+// 拡張モードの ld / st でアクセスするアドレス空間 (レジスタBで指す4bit) に置く装置
+// offset は割り当てた範囲の先頭からの位置
+pub trait Mmio: Send {
+    fn read(&mut self, offset: u8) -> u8;
+    fn write(&mut self, offset: u8, value: u8);
+    // エミュレータをリセットしたときに呼ばれる
+    fn reset(&mut self) {}
+}
+
+// 読み書きできるだけのメモリ
+pub struct Ram {
+    cells: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new(size: usize) -> Self {
+        Self {
+            cells: vec![0; size],
+        }
+    }
+
+    pub fn cells(&self) -> &[u8] {
+        &self.cells
+    }
+}
+
+impl Mmio for Ram {
+    fn read(&mut self, offset: u8) -> u8 {
+        self.cells.get(offset as usize).copied().unwrap_or(0)
+    }
+
+    fn write(&mut self, offset: u8, value: u8) {
+        if let Some(cell) = self.cells.get_mut(offset as usize) {
+            *cell = value;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.cells.iter_mut().for_each(|cell| *cell = 0);
+    }
+}
+
+// アドレスに割り当てたもの。入出力ポートはエミュレータが持っているので目印だけ置く
+pub enum Mapping {
+    // 読むと入力ポート、書くと出力ポート (offset がポート番号)
+    Ports,
+    Device(Box<dyn Mmio>),
+}
+
+// アドレスと装置の対応。後から割り当てたものが優先される
+pub struct MemoryMap {
+    regions: Vec<(RangeInclusive<u8>, Mapping)>,
+}
+
+pub const PORTS_ADDRESS: u8 = 0xf;
+
+impl MemoryMap {
+    // 何も割り当てていないアドレスは読むと0で、書き込みは捨てられる
+    pub fn empty() -> Self {
+        Self {
+            regions: Vec::new(),
+        }
+    }
+
+    // 0x0..=0xe がRAM、0xf がポート0
+    pub fn new() -> Self {
+        let mut map = Self::empty();
+        map.map(
+            0x0..=PORTS_ADDRESS - 1,
+            Box::new(Ram::new(PORTS_ADDRESS as usize)),
+        );
+        map.map_ports(PORTS_ADDRESS..=PORTS_ADDRESS);
+        map
+    }
+
+    pub fn map(&mut self, range: RangeInclusive<u8>, device: Box<dyn Mmio>) {
+        self.regions.push((range, Mapping::Device(device)));
+    }
+
+    pub fn map_ports(&mut self, range: RangeInclusive<u8>) {
+        self.regions.push((range, Mapping::Ports));
+    }
+
+    // アドレスを受け持つものと、その中でのoffset
+    pub fn find(&mut self, address: u8) -> Option<(&mut Mapping, u8)> {
+        self.regions
+            .iter_mut()
+            .rev()
+            .find(|(range, _)| range.contains(&address))
+            .map(|(range, mapping)| (mapping, address - range.start()))
+    }
+
+    pub fn reset(&mut self) {
+        for (_, mapping) in self.regions.iter_mut() {
+            if let Mapping::Device(device) = mapping {
+                device.reset();
+            }
+        }
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod mmio_tests {
+    use crate::mmio::{Mapping, MemoryMap, Ram};
+
+    fn read(map: &mut MemoryMap, address: u8) -> Option<u8> {
+        match map.find(address) {
+            Some((Mapping::Device(device), offset)) => Some(device.read(offset)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let mut map = MemoryMap::new();
+        match map.find(0x3) {
+            Some((Mapping::Device(ram), 3)) => ram.write(3, 0b0101),
+            _ => panic!("0x3 should be RAM"),
+        }
+        assert_eq!(read(&mut map, 0x3), Some(0b0101));
+        assert!(matches!(map.find(0xf), Some((Mapping::Ports, 0))));
+
+        // 後から割り当てた装置がRAMより優先される
+        map.map(0x2..=0x3, Box::new(Ram::new(2)));
+        assert_eq!(read(&mut map, 0x3), Some(0));
+
+        map.reset();
+        assert!(MemoryMap::empty().find(0x0).is_none());
+    }
+}
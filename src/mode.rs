@@ -0,0 +1,101 @@
+// 命令セットのモード
+// Standard は本のTD4そのもの、Extended は教材向けの拡張 (実機にはない動作) を有効にする
+#[derive(Debug, PartialEq, Clone, Default)]
+pub enum Mode {
+    #[default]
+    Standard,
+    Extended(Extensions),
+}
+
+impl Mode {
+    pub fn extensions(&self) -> Option<&Extensions> {
+        match self {
+            Mode::Standard => None,
+            Mode::Extended(extensions) => Some(extensions),
+        }
+    }
+
+    pub fn is_extended(&self) -> bool {
+        self.extensions().is_some()
+    }
+
+    // 標準モードでは入力と出力が1つずつ
+    pub fn port_count(&self) -> usize {
+        self.extensions().map_or(1, |extensions| extensions.ports)
+    }
+}
+
+// 拡張モードで有効にする機能
+#[derive(Debug, PartialEq, Clone)]
+pub struct Extensions {
+    pub interrupt: Option<Interrupt>,
+    // 入出力ポートの数 (1..=16)。in A / in B / out B の下位4bitでポートを選ぶ
+    pub ports: usize,
+}
+
+impl Default for Extensions {
+    fn default() -> Self {
+        Extensions {
+            interrupt: None,
+            ports: 1,
+        }
+    }
+}
+
+// 入力ポートの1bitの立ち上がりで割り込みのようにPCをベクタへ飛ばす
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Interrupt {
+    // 監視する入力ポートのビット (0..3)
+    pub bit: u8,
+    // 立ち上がりの次のサイクルで実行するアドレス
+    pub vector: u8,
+    // 割り込まれたPCの保存先
+    pub save_to: SaveTarget,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SaveTarget {
+    RegisterB,
+    // レジスタを壊さないように専用のシャドウレジスタに保存する
+    Shadow,
+}
+
+impl Interrupt {
+    pub fn new(bit: u8, vector: u8) -> Self {
+        Interrupt {
+            bit,
+            vector,
+            save_to: SaveTarget::RegisterB,
+        }
+    }
+
+    pub fn save_to(self, save_to: SaveTarget) -> Self {
+        Interrupt { save_to, ..self }
+    }
+
+    // 前回と今回の入力から立ち上がりを検出する
+    // 入力ポートの外のビットを指していたら割り込まない
+    pub fn is_triggered(&self, previous_input: u8, input: u8) -> bool {
+        match 1u8.checked_shl(self.bit as u32) {
+            Some(mask) => previous_input & mask == 0 && input & mask != 0,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod mode_tests {
+    use crate::mode::Interrupt;
+
+    #[test]
+    fn test_interrupt_trigger() {
+        let interrupt = Interrupt::new(2, 0);
+        assert!(interrupt.is_triggered(0b0000, 0b0100));
+        assert!(!interrupt.is_triggered(0b0100, 0b0100));
+        assert!(!interrupt.is_triggered(0b0100, 0b0000));
+
+        // 8bit以上を指していてもパニックせず、割り込みは起きない
+        let interrupt = Interrupt::new(8, 0);
+        assert!(!interrupt.is_triggered(0b0000, 0b1111_1111));
+    }
+}
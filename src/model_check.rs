@@ -0,0 +1,401 @@
+use crate::condition::{Condition, Field};
+use crate::error::EmulatorErr;
+use crate::isa::{apply, ArchState};
+use crate::json::Json;
+use crate::op::Opcode;
+use crate::profile::MachineProfile;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+// 入力ポートの値ごとに状態遷移をたどって時相的な性質を調べる有界モデル検査
+// 入力が変わらなければ次の状態は1つに決まるので、状態グラフは停止するか
+// 以前の状態に戻ってループする1本の道になる。道は isa::apply で作る
+#[derive(Debug, PartialEq, Clone)]
+pub enum Property {
+    // いつかは成り立つ
+    Eventually(Condition),
+    // 一度も成り立たない
+    Never(Condition),
+    // 常に成り立つ
+    Always(Condition),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CheckConfig {
+    pub profile: MachineProfile,
+    // 1つの入力でたどる状態の数の上限
+    pub bound: usize,
+}
+
+impl Default for CheckConfig {
+    fn default() -> Self {
+        CheckConfig {
+            profile: MachineProfile::default(),
+            // PC, A, B, キャリーの 2^13 通り
+            bound: 1 << 13,
+        }
+    }
+}
+
+// 道の終わり方
+#[derive(Debug, PartialEq, Clone)]
+pub enum Ending {
+    Halted,
+    // 同じ状態に戻った。値はループの始まりのステップ
+    Loops(usize),
+    Fault(String),
+    // 上限までたどったが終わらなかった
+    Bound,
+}
+
+// 反例 (または性質を破った状態までの証拠) になる実行
+#[derive(Debug, PartialEq, Clone)]
+pub struct Trace {
+    pub input: u8,
+    pub states: Vec<ArchState>,
+    // None なら最後の状態で性質が破れた
+    pub ending: Option<Ending>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CheckResult {
+    pub inputs: usize,
+    pub counterexample: Option<Trace>,
+    // 上限に届かずに全ての道をたどり終えたか
+    pub exhaustive: bool,
+}
+
+impl CheckResult {
+    pub fn holds(&self) -> bool {
+        self.counterexample.is_none()
+    }
+
+    // 反例は入力と、ステップごとの状態と、終わり方
+    pub fn to_json(&self) -> Json {
+        let counterexample = self.counterexample.as_ref().map(|trace| {
+            let ending = match &trace.ending {
+                None => Json::Null,
+                Some(Ending::Halted) => "halted".into(),
+                Some(Ending::Loops(_)) => "loops".into(),
+                Some(Ending::Fault(message)) => format!("fault: {}", message).into(),
+                Some(Ending::Bound) => "bound".into(),
+            };
+            Json::object([
+                ("input", trace.input.into()),
+                (
+                    "steps",
+                    Json::array(trace.states.iter().map(|state| {
+                        let register = &state.register;
+                        Json::object([
+                            ("pc", register.pc().into()),
+                            ("a", register.register_a().into()),
+                            ("b", register.register_b().into()),
+                            ("carry", register.carry_flag().into()),
+                            ("output", state.outputs[0].into()),
+                        ])
+                    })),
+                ),
+                ("ending", ending),
+            ])
+        });
+        Json::object([
+            ("holds", self.holds().into()),
+            ("inputs", self.inputs.into()),
+            ("exhaustive", self.exhaustive.into()),
+            ("counterexample", counterexample.into()),
+        ])
+    }
+}
+
+fn read(state: &ArchState, field: Field) -> u8 {
+    match field {
+        Field::A => state.register.register_a(),
+        Field::B => state.register.register_b(),
+        Field::Carry => state.register.carry_flag(),
+        Field::Pc => state.register.pc(),
+        Field::Input => state.inputs[0],
+        Field::Output => state.outputs[0],
+    }
+}
+
+// エミュレータの does_halt と同じく、ROMの外に出たら止まる。ただし Wrap では折り返して続ける
+fn does_halt(rom: &[u8], profile: &MachineProfile, state: &ArchState) -> bool {
+    let pc = state.register.pc();
+    match rom.get(profile.fetch_beyond_rom.wrap(pc, rom.len()) as usize) {
+        Some(data) => profile.halt_on_self_jump && *data == Opcode::Jmp.encode(pc),
+        None => true,
+    }
+}
+
+// ループを見つけるための状態の値
+type Key = (u8, u8, u8, u8, Vec<u8>, Vec<u8>, Vec<u8>);
+
+fn key(state: &ArchState) -> Key {
+    let register = &state.register;
+    (
+        register.pc(),
+        register.register_a(),
+        register.register_b(),
+        register.carry_flag(),
+        state.outputs.clone(),
+        state.stack.clone(),
+        state.ram.clone(),
+    )
+}
+
+pub fn check(rom: &[u8], property: &Property, config: &CheckConfig) -> CheckResult {
+    let mask = config.profile.register_mask();
+    let mut exhaustive = true;
+    for input in 0..=mask {
+        let (trace, found) = explore(rom, input, property, config);
+        if trace.ending == Some(Ending::Bound) {
+            exhaustive = false;
+        }
+        let holds = match property {
+            Property::Eventually(_) => found,
+            Property::Never(_) | Property::Always(_) => !found,
+        };
+        if !holds {
+            return CheckResult {
+                inputs: input as usize + 1,
+                counterexample: Some(trace),
+                exhaustive,
+            };
+        }
+    }
+    CheckResult {
+        inputs: mask as usize + 1,
+        counterexample: None,
+        exhaustive,
+    }
+}
+
+// 探している状態 (eventually なら性質が成り立つ状態、それ以外なら破る状態) が
+// 見つかるか道が終わるまでたどる
+fn explore(rom: &[u8], input: u8, property: &Property, config: &CheckConfig) -> (Trace, bool) {
+    let target = |state: &ArchState| match property {
+        Property::Eventually(condition) | Property::Never(condition) => {
+            condition.evaluate_with(&|field| read(state, field))
+        }
+        Property::Always(condition) => !condition.evaluate_with(&|field| read(state, field)),
+    };
+    let mut state = ArchState::new(&config.profile);
+    state.inputs[0] = input;
+    let mut states = Vec::new();
+    let mut seen = HashMap::new();
+
+    let ending = loop {
+        let found = target(&state);
+        states.push(state.clone());
+        if found {
+            return (
+                Trace {
+                    input,
+                    states,
+                    ending: None,
+                },
+                true,
+            );
+        }
+        if does_halt(rom, &config.profile, &state) {
+            break Ending::Halted;
+        }
+        if let Some(step) = seen.insert(key(&state), states.len() - 1) {
+            states.pop();
+            break Ending::Loops(step);
+        }
+        if states.len() >= config.bound {
+            break Ending::Bound;
+        }
+        let fetch_beyond_rom = config.profile.fetch_beyond_rom;
+        let pc = fetch_beyond_rom.wrap(state.register.pc(), rom.len());
+        state = match apply(&config.profile, &state, rom[pc as usize]) {
+            Ok(mut next) => {
+                // エミュレータと同じく折り返したPCをレジスタに書き戻す
+                let pc = fetch_beyond_rom.wrap(next.register.pc(), rom.len());
+                next.register.set_pc(pc);
+                next
+            }
+            Err(err) => break Ending::Fault(err.to_string()),
+        };
+    };
+    (
+        Trace {
+            input,
+            states,
+            ending: Some(ending),
+        },
+        false,
+    )
+}
+
+impl FromStr for Property {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (operator, condition) = s.split_once(char::is_whitespace).ok_or_else(|| {
+            EmulatorErr::new("write eventually, never or always before the condition")
+        })?;
+        let condition = Condition::parse(condition)?;
+        match operator {
+            "eventually" => Ok(Property::Eventually(condition)),
+            "never" => Ok(Property::Never(condition)),
+            "always" => Ok(Property::Always(condition)),
+            _ => Err(EmulatorErr::new(&format!(
+                "Unknown temporal operator {} (use eventually, never or always)",
+                operator
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Property {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Property::Eventually(condition) => write!(f, "eventually {}", condition.text()),
+            Property::Never(condition) => write!(f, "never {}", condition.text()),
+            Property::Always(condition) => write!(f, "always {}", condition.text()),
+        }
+    }
+}
+
+// step  pc    A     B     C  out
+// 0     0x0   0000  0000  0  0000
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "step  pc    A     B     C  out")?;
+        for (step, state) in self.states.iter().enumerate() {
+            let register = &state.register;
+            writeln!(
+                f,
+                "{:<5} 0x{:<3x} {:04b}  {:04b}  {}  {:04b}",
+                step,
+                register.pc(),
+                register.register_a(),
+                register.register_b(),
+                register.carry_flag(),
+                state.outputs[0]
+            )?;
+        }
+        match &self.ending {
+            None => Ok(()),
+            Some(Ending::Halted) => writeln!(f, "halted"),
+            Some(Ending::Loops(step)) => writeln!(f, "then loops back to step {}", step),
+            Some(Ending::Fault(message)) => writeln!(f, "stopped: {}", message),
+            Some(Ending::Bound) => writeln!(f, "stopped after {} steps", self.states.len()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod model_check_tests {
+    use crate::compiler::assemble;
+    use crate::model_check::{check, CheckConfig, Ending, Property};
+    use crate::profile::{FetchBeyondRom, MachineProfile};
+
+    fn check_text(source: &str, property: &str) -> crate::model_check::CheckResult {
+        let rom = assemble(source).unwrap();
+        let property: Property = property.parse().unwrap();
+        check(&rom, &property, &CheckConfig::default())
+    }
+
+    #[test]
+    fn test_eventually() {
+        // 入力に1ずつ足して桁あふれしたら 1000 を出力する
+        let source = "in A\nadd A 0001\njnc 0001\nout 1000\njmp 0100";
+        let result = check_text(source, "eventually out==0b1000");
+        assert!(result.holds());
+        assert_eq!(result.inputs, 16);
+        assert!(result.exhaustive);
+
+        // 1 を足し続けるだけで出力しない
+        let result = check_text("add A 0001\njmp 0000", "eventually out == 0b1000");
+        let trace = result.counterexample.unwrap();
+        assert_eq!(trace.input, 0);
+        assert_eq!(trace.ending, Some(Ending::Loops(0)));
+        assert_eq!(trace.states.len(), 32);
+
+        // halt で止めないプロファイルでは同じジャンプを繰り返すループになる
+        let rom = assemble("out 0001\njmp 0001").unwrap();
+        let property: Property = "eventually out == 0b1000".parse().unwrap();
+        let result = check(&rom, &property, &CheckConfig::default());
+        assert_eq!(result.counterexample.unwrap().ending, Some(Ending::Halted));
+        let config = CheckConfig {
+            profile: MachineProfile {
+                halt_on_self_jump: false,
+                ..MachineProfile::default()
+            },
+            ..CheckConfig::default()
+        };
+        let result = check(&rom, &property, &config);
+        assert_eq!(
+            result.counterexample.unwrap().ending,
+            Some(Ending::Loops(1))
+        );
+    }
+
+    #[test]
+    fn test_fetch_beyond_rom() {
+        // 3命令の後ろはROMの外。ふつうはそこで止まるが Wrap では先頭に戻って続ける
+        let rom = assemble("add A 0001\nmov B A\nout B").unwrap();
+        let property: Property = "eventually out == 0b0011".parse().unwrap();
+        let result = check(&rom, &property, &CheckConfig::default());
+        assert_eq!(result.counterexample.unwrap().ending, Some(Ending::Halted));
+
+        let config = CheckConfig {
+            profile: MachineProfile {
+                fetch_beyond_rom: FetchBeyondRom::Wrap,
+                ..MachineProfile::default()
+            },
+            ..CheckConfig::default()
+        };
+        let result = check(&rom, &property, &config);
+        assert!(result.holds());
+        let result = check(&rom, &"always pc < 3".parse().unwrap(), &config);
+        assert!(result.holds());
+    }
+
+    #[test]
+    fn test_to_json() {
+        let result = check_text("out 0001\njmp 0001", "always out == 0b0000");
+        let json = result.to_json().to_string();
+        assert!(json.starts_with(
+            "{\"holds\":false,\"inputs\":1,\"exhaustive\":true,\"counterexample\":{\"input\":0,"
+        ));
+        assert!(json.contains("{\"pc\":1,\"a\":0,\"b\":0,\"carry\":0,\"output\":1}"));
+        assert_eq!(
+            check_text("out 0001\njmp 0001", "always out < 2")
+                .to_json()
+                .to_string(),
+            "{\"holds\":true,\"inputs\":16,\"exhaustive\":true,\"counterexample\":null}"
+        );
+    }
+
+    #[test]
+    fn test_never_and_always() {
+        let source = "in A\nadd A 0011\njnc 0100\nout 1111\nout 0001";
+        let result = check_text(source, "never pc==0x3");
+        let trace = result.counterexample.unwrap();
+        assert_eq!(trace.input, 0b1101);
+        assert_eq!(trace.ending, None);
+        assert_eq!(trace.states.last().unwrap().register.pc(), 3);
+        assert!(trace
+            .to_string()
+            .starts_with("step  pc    A     B     C  out\n0     0x0   0000"));
+
+        assert!(check_text(source, "always B == 0").holds());
+        assert!(check_text(source, "never out == 0b0010").holds());
+        assert!(!check_text(source, "always out == 0").holds());
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!("sometimes A == 1".parse::<Property>().is_err());
+        assert!("eventually".parse::<Property>().is_err());
+        assert!("never X == 1".parse::<Property>().is_err());
+        let property: Property = " always  carry ".parse().unwrap();
+        assert_eq!(property.to_string(), "always carry");
+    }
+}
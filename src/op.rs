@@ -1,17 +1,144 @@
-use num_derive::FromPrimitive;
-
-#[derive(Debug, PartialEq, FromPrimitive)]
-pub enum Opcode {
-    AddA = 0b0000,
-    AddB = 0b0101,
-    MovA = 0b0011,
-    MovB = 0b0111,
-    MovA2B = 0b0001,
-    MovB2A = 0b0100,
-    Jmp = 0b1111,
-    Jnc = 0b1110,
-    InA = 0b0010,
-    InB = 0b0110,
-    OutB = 0b1001,
-    OutIm = 0b1011,
+// 命令の下位4bitの使い方
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LowBits {
+    // 即値
+    Immediate,
+    // オペランドなし。0でなければならない
+    Zero,
+    // 同じopcodeを共有する命令を区別する固定値
+    Function(u8),
+    // 入出力ポートの番号。ポートが1つしかなければ0
+    Port,
+}
+
+// 命令表から Opcode と、その符号化 (encode) と復号 (decode) をまとめて作る
+// 命令を増やすときはこの表と CpuEmulator の実行部分だけを変更すればよい
+macro_rules! opcodes {
+    ($($name:ident = $op:literal, $low:expr;)*) => {
+        #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+        pub enum Opcode {
+            $($name,)*
+        }
+
+        impl Opcode {
+            pub const ALL: &'static [Opcode] = &[$(Opcode::$name,)*];
+
+            // 上位4bit
+            pub fn op(&self) -> u8 {
+                match self {
+                    $(Opcode::$name => $op,)*
+                }
+            }
+
+            pub fn low_bits(&self) -> LowBits {
+                use LowBits::*;
+                match self {
+                    $(Opcode::$name => $low,)*
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    AddA = 0b0000, Immediate;
+    MovA2B = 0b0001, Zero;
+    InA = 0b0010, Port;
+    MovA = 0b0011, Immediate;
+    MovB2A = 0b0100, Zero;
+    AddB = 0b0101, Immediate;
+    InB = 0b0110, Port;
+    MovB = 0b0111, Immediate;
+    OutB = 0b1001, Port;
+    OutIm = 0b1011, Immediate;
+    Jnc = 0b1110, Immediate;
+    Jmp = 0b1111, Immediate;
+    // 以下は拡張モードだけの命令 (本のTD4では未定義のopcode)
+    Call = 0b1000, Immediate;
+    Ret = 0b1010, Zero;
+    SubA = 0b1100, Immediate;
+    SubB = 0b1101, Immediate;
+    // 空いているopcodeが足りないので RET と同じ 1010 を使い、即値 0001 で区別する
+    Cmp = 0b1010, Function(0b0001);
+    // レジスタBが指すアドレスとのAの読み書き。アドレスはメモリマップでRAMや装置に振り分ける
+    Ld = 0b1010, Function(0b0010);
+    St = 0b1010, Function(0b0011);
+    // AとBの入れ替え (標準モードでは作業用のレジスタがないので3命令の入れ替えもできない)
+    Swap = 0b1010, Function(0b0100);
+}
+
+impl Opcode {
+    pub fn is_extended(&self) -> bool {
+        matches!(
+            self,
+            Opcode::Call
+                | Opcode::Ret
+                | Opcode::SubA
+                | Opcode::SubB
+                | Opcode::Cmp
+                | Opcode::Ld
+                | Opcode::St
+                | Opcode::Swap
+        )
+    }
+
+    // 即値もポート番号もとらない命令では im を無視する
+    pub fn encode(&self, im: u8) -> u8 {
+        let low = match self.low_bits() {
+            LowBits::Immediate | LowBits::Port => im & 0x0f,
+            LowBits::Zero => 0,
+            LowBits::Function(function) => function,
+        };
+        self.op() << 4 | low
+    }
+
+    // 1バイトをopcodeと下位4bitに分ける。下位4bitが固定の命令を優先する
+    pub fn decode(data: u8) -> Option<(Opcode, u8)> {
+        let (op, im) = (data >> 4, data & 0x0f);
+        let candidates = Self::ALL.iter().filter(|opcode| opcode.op() == op);
+        candidates
+            .clone()
+            .find(|opcode| opcode.low_bits() == LowBits::Function(im))
+            .or_else(|| {
+                candidates
+                    .clone()
+                    .find(|opcode| !matches!(opcode.low_bits(), LowBits::Function(_)))
+            })
+            .map(|opcode| (*opcode, im))
+    }
+}
+
+#[cfg(test)]
+mod op_tests {
+    use crate::op::{LowBits, Opcode};
+
+    #[test]
+    fn test_round_trip() {
+        for opcode in Opcode::ALL {
+            let data = opcode.encode(0b0101);
+            let expected = match opcode.low_bits() {
+                LowBits::Immediate | LowBits::Port => 0b0101,
+                LowBits::Zero => 0,
+                LowBits::Function(function) => function,
+            };
+            assert_eq!(
+                Opcode::decode(data),
+                Some((*opcode, expected)),
+                "{:?}",
+                opcode
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Opcode::decode(0b00110001), Some((Opcode::MovA, 1)));
+        assert_eq!(Opcode::decode(0b10100000), Some((Opcode::Ret, 0)));
+        assert_eq!(Opcode::decode(0b10100001), Some((Opcode::Cmp, 1)));
+        // 即値が0でないオペランドなしの命令も命令としては読める
+        assert_eq!(Opcode::decode(0b10100101), Some((Opcode::Ret, 5)));
+        assert_eq!(Opcode::decode(0b10100100), Some((Opcode::Swap, 4)));
+        assert_eq!(Opcode::decode(0b10100010), Some((Opcode::Ld, 2)));
+        assert_eq!(Opcode::MovA2B.encode(0b1111), 0b00010000);
+    }
 }
@@ -0,0 +1,581 @@
+use crate::error::EmulatorErr;
+use crate::grader::GradeSpec;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// 授業の教材をまとめて配るためのプログラムパック (.td4pack)
+// 中身は pack.toml と、それが挙げるソース、ROM、入出力の仕様、資料を入れた tar アーカイブ
+// インストールしたパックのプログラムは --example パック名/プログラム名 で読み込める
+pub const MANIFEST: &str = "pack.toml";
+
+// pack.toml。TOMLのうちパックに使う部分だけを読む
+//
+//     name = "lab1"
+//     version = "1.0"
+//     description = "Week 1: counting"
+//     docs = ["README.md"]
+//
+//     [[program]]
+//     name = "counter"
+//     source = "counter.sasm"
+//     spec = "counter_spec.toml"
+//     description = "Count up from 0 to 15"
+//
+// プログラムには source (アセンブリ) か rom (.td4rom などのROMイメージ) のどちらかを書く
+// spec は grade --spec に渡す採点基準
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct PackManifest {
+    pub name: String,
+    pub version: String,
+    pub description: String,
+    pub docs: Vec<String>,
+    pub programs: Vec<PackProgram>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct PackProgram {
+    pub name: String,
+    pub description: String,
+    pub source: Option<String>,
+    pub rom: Option<String>,
+    pub spec: Option<String>,
+}
+
+impl PackProgram {
+    // 読み込むファイル (ソースかROM)
+    pub fn file(&self) -> &str {
+        self.source.as_deref().or(self.rom.as_deref()).unwrap_or("")
+    }
+}
+
+impl PackManifest {
+    // パックに入れるファイル。pack.toml 自身は含まない
+    pub fn files(&self) -> Vec<&str> {
+        let mut files: Vec<&str> = Vec::new();
+        let programs = self
+            .programs
+            .iter()
+            .flat_map(|program| [Some(program.file()), program.spec.as_deref()])
+            .flatten();
+        for file in programs.chain(self.docs.iter().map(String::as_str)) {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+        files
+    }
+
+    pub fn program(&self, name: &str) -> Option<&PackProgram> {
+        self.programs.iter().find(|program| program.name == name)
+    }
+}
+
+impl FromStr for PackManifest {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut manifest = PackManifest::default();
+        for (number, line) in s.lines().enumerate() {
+            let error = |message: &str| {
+                EmulatorErr::new(&format!("{} line {}: {}", MANIFEST, number + 1, message))
+            };
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line == "[[program]]" {
+                manifest.programs.push(PackProgram::default());
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error(&format!("can't read {}", line)))?;
+            let (key, value) = (key.trim(), value.trim());
+            let text = || {
+                value
+                    .strip_prefix('"')
+                    .and_then(|text| text.strip_suffix('"'))
+                    .map(str::to_string)
+                    .ok_or_else(|| error(&format!("{} must be a string", key)))
+            };
+            match (manifest.programs.last_mut(), key) {
+                (None, "name") => manifest.name = text()?,
+                (None, "version") => manifest.version = text()?,
+                (None, "description") => manifest.description = text()?,
+                (None, "docs") => {
+                    manifest.docs = parse_strings(value)
+                        .ok_or_else(|| error("docs must be a list of strings"))?
+                }
+                (Some(program), "name") => program.name = text()?,
+                (Some(program), "description") => program.description = text()?,
+                (Some(program), "source") => program.source = Some(text()?),
+                (Some(program), "rom") => program.rom = Some(text()?),
+                (Some(program), "spec") => program.spec = Some(text()?),
+                _ => return Err(error(&format!("unexpected key {}", key))),
+            }
+        }
+        manifest.validate()?;
+        Ok(manifest)
+    }
+}
+
+impl PackManifest {
+    fn validate(&self) -> Result<(), EmulatorErr> {
+        check_name("pack", &self.name)?;
+        for program in &self.programs {
+            check_name("program", &program.name)?;
+            if program.source.is_some() == program.rom.is_some() {
+                return Err(EmulatorErr::new(&format!(
+                    "program {} needs either source or rom",
+                    program.name
+                )));
+            }
+            if self
+                .programs
+                .iter()
+                .filter(|other| other.name == program.name)
+                .count()
+                > 1
+            {
+                return Err(EmulatorErr::new(&format!(
+                    "program {} is listed twice",
+                    program.name
+                )));
+            }
+        }
+        for file in self.files() {
+            check_path(file)?;
+        }
+        Ok(())
+    }
+}
+
+// ["a", "b"]
+fn parse_strings(text: &str) -> Option<Vec<String>> {
+    let items = text.strip_prefix('[')?.strip_suffix(']')?.trim();
+    if items.is_empty() {
+        return Some(Vec::new());
+    }
+    items
+        .split(',')
+        .map(|item| {
+            item.trim()
+                .strip_prefix('"')?
+                .strip_suffix('"')
+                .map(str::to_string)
+        })
+        .collect()
+}
+
+// パック名とプログラム名はディレクトリ名と --example に使う
+fn check_name(kind: &str, name: &str) -> Result<(), EmulatorErr> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(EmulatorErr::new(&format!(
+            "{} name {:?} may only use letters, digits, - and _",
+            kind, name
+        )));
+    }
+    Ok(())
+}
+
+// アーカイブの外に書き出さないよう、絶対パスと .. を拒む
+fn check_path(path: &str) -> Result<(), EmulatorErr> {
+    let escapes = Path::new(path)
+        .components()
+        .any(|component| !matches!(component, std::path::Component::Normal(_)));
+    if path.is_empty() || escapes {
+        return Err(EmulatorErr::new(&format!(
+            "{} must be a relative path inside the pack",
+            path
+        )));
+    }
+    Ok(())
+}
+
+// dir/pack.toml と、それが挙げるファイルを1つのアーカイブにする
+pub fn build(dir: &Path) -> Result<(PackManifest, Vec<u8>), EmulatorErr> {
+    let read = |name: &str| {
+        let path = dir.join(name);
+        std::fs::read(&path)
+            .map_err(|err| EmulatorErr::new(&format!("Failed to read {}: {}", path.display(), err)))
+    };
+    let text = read(MANIFEST)?;
+    let manifest: PackManifest = String::from_utf8_lossy(&text).parse()?;
+    let mut files = vec![(MANIFEST.to_string(), text)];
+    for file in manifest.files() {
+        files.push((file.to_string(), read(file)?));
+    }
+    // 採点基準は配る前に読めるか確かめる
+    for program in &manifest.programs {
+        if let Some(spec) = &program.spec {
+            String::from_utf8_lossy(&read(spec)?)
+                .parse::<GradeSpec>()
+                .map_err(|err| EmulatorErr::new(&format!("{}: {}", spec, err)))?;
+        }
+    }
+    Ok((manifest, write_tar(&files)?))
+}
+
+// アーカイブを root/パック名 に展開する。同じ名前のパックは置き換える
+pub fn install(archive: &[u8], root: &Path) -> Result<(PackManifest, PathBuf), EmulatorErr> {
+    let files = read_tar(archive)?;
+    let text = files
+        .iter()
+        .find(|(name, _)| name == MANIFEST)
+        .map(|(_, data)| String::from_utf8_lossy(data))
+        .ok_or_else(|| EmulatorErr::new(&format!("the pack has no {}", MANIFEST)))?;
+    let manifest: PackManifest = text.parse()?;
+    for file in manifest.files() {
+        if !files.iter().any(|(name, _)| name == file) {
+            return Err(EmulatorErr::new(&format!("the pack is missing {}", file)));
+        }
+    }
+    for (name, _) in &files {
+        check_path(name)?;
+    }
+
+    let dir = root.join(&manifest.name);
+    let error = |err: std::io::Error| {
+        EmulatorErr::new(&format!(
+            "Failed to install into {}: {}",
+            dir.display(),
+            err
+        ))
+    };
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).map_err(error)?;
+    }
+    for (name, data) in &files {
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(error)?;
+        }
+        std::fs::write(&path, data).map_err(error)?;
+    }
+    Ok((manifest, dir))
+}
+
+// パックを置くディレクトリ。TD4EMU_PACKS があればそれ、なければ ~/.local/share/td4emu/packs
+pub fn packs_dir() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("TD4EMU_PACKS") {
+        return Some(PathBuf::from(path));
+    }
+    let data = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share"))
+        })?;
+    Some(data.join("td4emu").join("packs"))
+}
+
+// インストールしたパックを名前順に返す。読めない pack.toml のディレクトリは飛ばす
+pub fn installed(root: &Path) -> Vec<PackManifest> {
+    let mut packs: Vec<PackManifest> = match std::fs::read_dir(root) {
+        Ok(entries) => entries
+            .flatten()
+            .filter_map(|entry| std::fs::read_to_string(entry.path().join(MANIFEST)).ok())
+            .filter_map(|text| text.parse().ok())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    packs.sort_by(|a, b| a.name.cmp(&b.name));
+    packs
+}
+
+// パック名/プログラム名 のファイルの場所
+pub fn find_program(root: &Path, name: &str) -> Result<PathBuf, EmulatorErr> {
+    let (pack, program) = name
+        .split_once('/')
+        .ok_or_else(|| EmulatorErr::new(&format!("{} is not pack/program", name)))?;
+    let dir = root.join(pack);
+    let manifest: PackManifest = std::fs::read_to_string(dir.join(MANIFEST))
+        .map_err(|_| {
+            EmulatorErr::new(&format!(
+                "Unknown pack: {}. Install it with `td4emu install`",
+                pack
+            ))
+        })?
+        .parse()?;
+    let program = manifest
+        .program(program)
+        .ok_or_else(|| EmulatorErr::new(&format!("Pack {} has no program {}", pack, program)))?;
+    Ok(dir.join(program.file()))
+}
+
+// http:// のURLからパックを取ってくる。https は扱えないので、ダウンロードしてからファイルを渡す
+pub fn fetch(url: &str) -> Result<Vec<u8>, EmulatorErr> {
+    let rest = url.strip_prefix("http://").ok_or_else(|| {
+        EmulatorErr::new(&format!(
+            "can't fetch {}: only http:// is supported. Download the pack and install the file",
+            url
+        ))
+    })?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let address = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{}:80", host),
+    };
+    let error =
+        |err: std::io::Error| EmulatorErr::new(&format!("Failed to fetch {}: {}", url, err));
+    let mut stream = TcpStream::connect(&address).map_err(error)?;
+    // HTTP/1.0 なら chunked で返ってこない
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: td4emu\r\n\r\n",
+        path, host
+    );
+    stream.write_all(request.as_bytes()).map_err(error)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).map_err(error)?;
+
+    let end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| EmulatorErr::new(&format!("Failed to fetch {}: broken response", url)))?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let status = head.lines().next().unwrap_or("");
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(EmulatorErr::new(&format!(
+            "Failed to fetch {}: {}",
+            url, status
+        )));
+    }
+    Ok(response[end + 4..].to_vec())
+}
+
+// ustar 形式の tar。ふつうの tar コマンドでも中身を見られる
+const BLOCK: usize = 512;
+
+pub fn write_tar(files: &[(String, Vec<u8>)]) -> Result<Vec<u8>, EmulatorErr> {
+    let mut archive = Vec::new();
+    for (name, data) in files {
+        if name.len() > 100 {
+            return Err(EmulatorErr::new(&format!(
+                "{} is too long for a pack",
+                name
+            )));
+        }
+        let mut header = [0u8; BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let mut field = |offset: usize, text: &str| {
+            header[offset..offset + text.len()].copy_from_slice(text.as_bytes())
+        };
+        field(100, "0000644");
+        field(108, "0000000");
+        field(116, "0000000");
+        field(124, &format!("{:011o}", data.len()));
+        // 同じ中身から同じアーカイブができるよう、時刻は0にする
+        field(136, "00000000000");
+        field(156, "0");
+        field(257, "ustar");
+        field(263, "00");
+        field(148, "        ");
+        let checksum: u32 = header.iter().map(|byte| *byte as u32).sum();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().div_ceil(BLOCK) * BLOCK, 0);
+    }
+    archive.resize(archive.len() + BLOCK * 2, 0);
+    Ok(archive)
+}
+
+// 普通のファイルだけを (名前, 中身) で返す。ディレクトリなどは読み飛ばす
+pub fn read_tar(archive: &[u8]) -> Result<Vec<(String, Vec<u8>)>, EmulatorErr> {
+    let broken = || EmulatorErr::new("the pack is not a tar archive");
+    let octal = |field: &[u8]| {
+        let text = String::from_utf8_lossy(field);
+        let text = text.trim_matches(|c: char| c == '\0' || c == ' ');
+        usize::from_str_radix(text, 8).map_err(|_| broken())
+    };
+    let mut files = Vec::new();
+    let mut offset = 0;
+    while offset + BLOCK <= archive.len() {
+        let header = &archive[offset..offset + BLOCK];
+        if header.iter().all(|byte| *byte == 0) {
+            return Ok(files);
+        }
+        let mut blank = header.to_vec();
+        blank[148..156].fill(b' ');
+        let checksum: usize = blank.iter().map(|byte| *byte as usize).sum();
+        if octal(&header[148..156])? != checksum {
+            return Err(broken());
+        }
+        let name_end = header[..100]
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(100);
+        let mut name = String::from_utf8_lossy(&header[..name_end]).to_string();
+        // ustar の prefix に入った長いパス
+        let prefix_end = header[345..500]
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(155);
+        if prefix_end > 0 && &header[257..262] == b"ustar" {
+            name = format!(
+                "{}/{}",
+                String::from_utf8_lossy(&header[345..345 + prefix_end]),
+                name
+            );
+        }
+        let size = octal(&header[124..136])?;
+        let start = offset + BLOCK;
+        let data = archive.get(start..start + size).ok_or_else(broken)?;
+        if matches!(header[156], b'0' | 0) {
+            files.push((name.trim_start_matches("./").to_string(), data.to_vec()));
+        }
+        offset = start + size.div_ceil(BLOCK) * BLOCK;
+    }
+    Err(broken())
+}
+
+#[cfg(test)]
+mod pack_tests {
+    use crate::pack::{
+        build, fetch, find_program, install, installed, read_tar, write_tar, PackManifest,
+    };
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    const MANIFEST: &str = "
+name = \"lab1\"
+version = \"1.0\"
+description = \"Week 1\"
+docs = [\"README.md\"]
+
+[[program]]
+name = \"counter\"
+source = \"counter.sasm\"  # 0から15まで数える
+spec = \"counter.toml\"
+
+[[program]]
+name = \"blink\"
+rom = \"roms/blink.bin\"
+";
+
+    #[test]
+    fn test_manifest() {
+        let manifest: PackManifest = MANIFEST.parse().unwrap();
+        assert_eq!(manifest.name, "lab1");
+        assert_eq!(manifest.programs.len(), 2);
+        assert_eq!(manifest.program("blink").unwrap().file(), "roms/blink.bin");
+        assert_eq!(
+            manifest.files(),
+            vec![
+                "counter.sasm",
+                "counter.toml",
+                "roms/blink.bin",
+                "README.md"
+            ]
+        );
+
+        let err = "name = \"lab 1\"".parse::<PackManifest>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "pack name \"lab 1\" may only use letters, digits, - and _"
+        );
+        let escaping = "name = \"lab\"\n[[program]]\nname = \"x\"\nsource = \"../x.sasm\"";
+        assert!(escaping.parse::<PackManifest>().is_err());
+        let neither = "name = \"lab\"\n[[program]]\nname = \"x\"";
+        assert!(neither.parse::<PackManifest>().is_err());
+    }
+
+    #[test]
+    fn test_tar_round_trip() {
+        let files = vec![
+            ("pack.toml".to_string(), b"name = \"lab\"\n".to_vec()),
+            ("roms/empty.bin".to_string(), Vec::new()),
+            ("big.sasm".to_string(), vec![b'a'; 700]),
+        ];
+        let archive = write_tar(&files).unwrap();
+        assert_eq!(archive.len() % 512, 0);
+        assert_eq!(read_tar(&archive).unwrap(), files);
+
+        let mut broken = archive.clone();
+        broken[0] = b'P';
+        assert!(read_tar(&broken).is_err());
+        assert!(read_tar(b"not a tar").is_err());
+    }
+
+    #[test]
+    fn test_build_and_install() {
+        let dir = std::env::temp_dir().join(format!("td4emu-pack-{}", std::process::id()));
+        let source = dir.join("source");
+        std::fs::create_dir_all(source.join("roms")).unwrap();
+        std::fs::write(source.join("pack.toml"), MANIFEST).unwrap();
+        std::fs::write(source.join("counter.sasm"), "add A 0001\nout A\n").unwrap();
+        std::fs::write(
+            source.join("counter.toml"),
+            "[[case]]\ninput = 0\noutputs = [1]\n",
+        )
+        .unwrap();
+        std::fs::write(source.join("roms/blink.bin"), [0b10111111]).unwrap();
+        // 資料が足りなければ作れない
+        assert!(build(&source).is_err());
+        std::fs::write(source.join("README.md"), "# Lab 1\n").unwrap();
+
+        let (manifest, archive) = build(&source).unwrap();
+        assert_eq!(manifest.name, "lab1");
+        let packs = dir.join("packs");
+        let (_, installed_dir) = install(&archive, &packs).unwrap();
+        assert_eq!(
+            std::fs::read(installed_dir.join("roms/blink.bin")).unwrap(),
+            [0b10111111]
+        );
+        assert_eq!(
+            find_program(&packs, "lab1/counter").unwrap(),
+            packs.join("lab1").join("counter.sasm")
+        );
+        assert!(find_program(&packs, "lab1/timer").is_err());
+        assert!(find_program(&packs, "lab2/counter").is_err());
+        assert_eq!(installed(&packs), vec![manifest]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fetch() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            for body in ["pack", ""] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 256];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buffer).unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                assert!(request.starts_with(b"GET /lab1.td4pack HTTP/1.0\r\n"));
+                let status = if body.is_empty() {
+                    "404 Not Found"
+                } else {
+                    "200 OK"
+                };
+                write!(
+                    stream,
+                    "HTTP/1.0 {}\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        let url = format!("http://{}/lab1.td4pack", address);
+        assert_eq!(fetch(&url).unwrap(), b"pack");
+        let err = fetch(&url).unwrap_err();
+        assert!(err.to_string().ends_with("HTTP/1.0 404 Not Found"));
+        server.join().unwrap();
+        assert!(fetch("https://example.com/lab1.td4pack").is_err());
+    }
+}
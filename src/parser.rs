@@ -1,153 +1,817 @@
 use crate::error::EmulatorErr;
+use crate::expr;
+use crate::macros::SourceLine;
+use crate::mode::Mode;
+use crate::style;
 use crate::token::{Register, Token};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+// アセンブリの文法のバージョン。どちらも同じ Token 列になる
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Syntax {
+    // 空白区切りで、0と1だけの即値を2進数として読む従来の文法
+    // 既存のソースが同じバイト列にアセンブルされるように変更しない
+    #[default]
+    V1,
+    // ラベル (name:), カンマ区切りのオペランド, ; と # のコメントが使える
+    // 即値は 0b / 0x が付いていなければ10進数として読む
+    V2,
+}
 
 pub struct Parser {
     pos: usize,
-    source: Vec<String>,
+    source: Vec<(String, Vec<String>)>,
+    // エラーメッセージに出す現在の行の位置
+    location: String,
+    // 次のトークンが置かれるROMのアドレス ($ の値)
+    address: usize,
+    syntax: Syntax,
+    // ラベルのアドレス (V2)
+    labels: HashMap<String, i64>,
+    // source の各行のファイル上の行番号。マクロの中身なら展開した行
+    numbers: Vec<usize>,
+    // 命令やデータを置いた (アドレス, 行番号)
+    placed: Vec<(usize, usize)>,
+    // trueなら4bitに収まらない即値をエラーにせず下位4bitに切り詰める
+    allow_truncation: bool,
+    // trueなら in A / out B の後ろのポート番号を読む (拡張モード)
+    port_operands: bool,
 }
 
 impl Parser {
     pub fn new(operations: Vec<String>) -> Parser {
+        let lines = operations
+            .into_iter()
+            .enumerate()
+            .map(|(index, text)| SourceLine::new(index + 1, text))
+            .collect();
+        Self::from_lines(lines)
+    }
+
+    // マクロ展開後の行を元の行番号付きで受け取る
+    pub fn from_lines(lines: Vec<SourceLine>) -> Parser {
         let mut source = Vec::new();
-        for operation in operations {
-            let split: Vec<&str> = operation.split(' ').collect();
-            for line in split {
-                let cloned = line.to_string();
-                source.push(cloned);
-            }
+        let mut numbers = Vec::new();
+        for line in lines {
+            numbers.push(line.file_line());
+            let split: Vec<String> = line
+                .text
+                .split_whitespace()
+                .map(|word| word.to_string())
+                .collect();
+            source.push((line.location(), split));
         }
 
-        Parser { pos: 0, source }
+        Parser {
+            pos: 0,
+            source,
+            location: String::new(),
+            address: 0,
+            syntax: Syntax::V1,
+            labels: HashMap::new(),
+            numbers,
+            placed: Vec::new(),
+            allow_truncation: false,
+            port_operands: false,
+        }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<Token>, EmulatorErr> {
-        let mut result = Vec::new();
-
-        loop {
-            let op = self.source.get(self.pos);
+    pub fn with_syntax(lines: Vec<SourceLine>, syntax: Syntax) -> Result<Parser, EmulatorErr> {
+        match syntax {
+            Syntax::V1 => Ok(Self::from_lines(lines)),
+            Syntax::V2 => Self::from_lines_v2(lines),
+        }
+    }
 
-            if op.is_none() {
-                break;
+    // V2のフロントエンド。コメントとラベルを取り除き、オペランドをV1と同じ形に並べ直す
+    fn from_lines_v2(lines: Vec<SourceLine>) -> Result<Parser, EmulatorErr> {
+        let mut source = Vec::new();
+        let mut definitions = Vec::new();
+        let mut numbers = Vec::new();
+        for line in lines {
+            numbers.push(line.file_line());
+            let location = line.location();
+            let mut text = strip_comment(&line.text).trim().to_string();
+            if let Some((label, rest)) = text.split_once(':') {
+                if is_identifier(label.trim()) {
+                    definitions.push((label.trim().to_string(), source.len(), location.clone()));
+                    text = rest.trim().to_string();
+                }
             }
+            // .data の値はカンマで区切るのでそのまま残す
+            if !text.starts_with(".data") {
+                text = text.replace(',', " ");
+            }
+            let split = text
+                .split_whitespace()
+                .map(|word| word.to_string())
+                .collect();
+            source.push((location, split));
+        }
 
-            let op = op.unwrap();
-
-            if op == "mov" {
-                self.pos += 1;
-                let lhs = self
-                    .source
-                    .get(self.pos)
-                    .ok_or_else(|| EmulatorErr::new("Failed to parse mov left hand side value"))?;
-
-                self.pos += 1;
-                let rhs = self
-                    .source
-                    .get(self.pos)
-                    .ok_or_else(|| EmulatorErr::new("Failed to parse mov right hand side value"))?;
-
-                let token = if lhs == "B" && rhs == "A" {
-                    Token::MovBA
-                } else if lhs == "A" && rhs == "B" {
-                    Token::MovAB
-                } else {
-                    Token::Mov(
-                        Register::from(lhs.to_string()), 
-                        self.from_binary_to_decimal(rhs)?,
-                    )
-                };
+        let mut parser = Parser {
+            pos: 0,
+            source,
+            location: String::new(),
+            address: 0,
+            syntax: Syntax::V2,
+            labels: HashMap::new(),
+            numbers,
+            placed: Vec::new(),
+            allow_truncation: false,
+            port_operands: false,
+        };
+        parser.resolve_labels(definitions)?;
+        Ok(parser)
+    }
 
-                result.push(token);
+    // 各行が置かれるアドレスを数えてラベルの値を決める
+    fn resolve_labels(
+        &mut self,
+        definitions: Vec<(String, usize, String)>,
+    ) -> Result<(), EmulatorErr> {
+        let mut addresses = Vec::new();
+        let mut address = 0;
+        for (location, line) in &self.source {
+            addresses.push(address);
+            match line.first().map(|op| op.as_str()) {
+                None => (),
+                Some(".org") => {
+                    address = expr::evaluate_with_symbols(
+                        &line[1..].join(" "),
+                        address as u8,
+                        &self.labels_before(&definitions, &addresses),
+                    )
+                    .map_err(|err| EmulatorErr::new(&format!("{}: {}", location, err)))?
+                        as usize;
+                }
+                Some(".data") => address += split_data(&line[1..].join(" ")).len(),
+                Some(_) => address += 1,
             }
+        }
+        addresses.push(address);
 
-            if op == "add" {
-                self.pos += 1;
-                let lhs = self
-                    .source
-                    .get(self.pos)
-                    .ok_or_else(|| EmulatorErr::new("Failed to parse add left hand side value"))?;
-
-                self.pos += 1;
-                let rhs = self
-                    .source
-                    .get(self.pos)
-                    .ok_or_else(|| EmulatorErr::new("Failed to parse add right hand side value"))?;
-
-                let token = Token::Add(
-                    Register::from(lhs.to_string()), 
-                    self.from_binary_to_decimal(rhs)?,
-                    );
-
-                result.push(token);
+        for (name, index, location) in definitions {
+            if self.labels.contains_key(&name) {
+                return Err(EmulatorErr::new(&format!(
+                    "{}: label {} is already defined",
+                    location, name
+                )));
             }
+            self.labels.insert(name, addresses[index] as i64);
+        }
+        Ok(())
+    }
 
-            if op == "jmp" {
-                self.pos += 1;
-                let im = self
-                    .source
-                    .get(self.pos)
-                    .ok_or_else(|| EmulatorErr::new("Failed to parse jmp im value"))?;
+    // .org の式に使えるのはそれより前に定義したラベルだけ
+    fn labels_before(
+        &self,
+        definitions: &[(String, usize, String)],
+        addresses: &[usize],
+    ) -> HashMap<String, i64> {
+        definitions
+            .iter()
+            .filter(|(_, index, _)| *index < addresses.len())
+            .map(|(name, index, _)| (name.clone(), addresses[*index] as i64))
+            .collect()
+    }
 
-                result.push(Token::Jmp(self.from_binary_to_decimal(im)?));
-            }
+    pub(crate) fn parse(&mut self) -> Result<Vec<Token>, EmulatorErr> {
+        let mut result = Vec::new();
+
+        while let Some((location, line)) = self.source.get(self.pos) {
+            self.pos += 1;
+            self.location = location.clone();
+            let number = self.numbers[self.pos - 1];
 
-            if op == "jnc" {
-                self.pos += 1;
-                let im = self
-                    .source
-                    .get(self.pos)
-                    .ok_or_else(|| EmulatorErr::new("Failed to parse jnc im value"))?;
-                result.push(Token::Jnc(self.from_binary_to_decimal(im)?));
+            if line.is_empty() {
+                continue;
             }
 
-            if op == "in" {
-                self.pos += 1;
-                let lhs = self.source.get(self.pos).unwrap();
-                result.push(Token::In(Register::from(lhs.to_string())));
+            let op = &line[0];
+            let operands = &line[1..];
+            if self.syntax == Syntax::V1 {
+                self.check_v1(line)?;
             }
+            self.check_arity(op, operands)?;
 
-            if op == "out" {
-                self.pos += 1;
-                let im = self.source.get(self.pos).unwrap();
-                let im = self
-                    .source
-                    .get(self.pos)
-                    .ok_or_else(|| EmulatorErr::new("Failed to parse out im value"))?;
-                if im == "B" {
-                    result.push(Token::OutB);
-                } else {
-                    result.push(Token::OutIm(self.from_binary_to_decimal(im)?));
+            // .data は複数のバイトを並べられるので1行から複数のトークンができる
+            if op == ".data" {
+                for item in split_data(&operands.join(" ")) {
+                    let words: Vec<String> = item.split_whitespace().map(String::from).collect();
+                    result.push(Token::Byte(self.value(&words, 0xff)?));
+                    self.placed.push((self.address, number));
+                    self.address += 1;
                 }
+                continue;
             }
 
-            self.pos += 1;
+            let token = match op.as_str() {
+                "mov" => match (operands[0].as_str(), &operands[1..]) {
+                    ("B", [rhs]) if rhs == "A" => Token::MovBA,
+                    ("A", [rhs]) if rhs == "B" => Token::MovAB,
+                    (lhs, _) => Token::Mov(self.register(lhs)?, self.immediate(op, operands, 1)?),
+                },
+                "add" => Token::Add(
+                    self.register(&operands[0])?,
+                    self.immediate(op, operands, 1)?,
+                ),
+                "jmp" => Token::Jmp(self.immediate(op, operands, 0)?),
+                "jnc" => Token::Jnc(self.immediate(op, operands, 0)?),
+                // in A / out B の後ろにポート番号を書ける (拡張モード)
+                "in" => Token::In(self.register(&operands[0])?, self.port(op, operands)?),
+                "out" => match operands {
+                    [rhs, ..] if rhs == "B" => Token::OutB(self.port(op, operands)?),
+                    _ => Token::OutIm(self.immediate(op, operands, 0)?),
+                },
+                "sub" => Token::Sub(
+                    self.register(&operands[0])?,
+                    self.immediate(op, operands, 1)?,
+                ),
+                // A - B のボローだけをキャリーに反映する
+                "cmp" => match (operands[0].as_str(), operands[1].as_str()) {
+                    ("A", "B") => Token::Cmp,
+                    _ => {
+                        return Err(EmulatorErr::new(&format!(
+                            "{}: cmp only compares A with B (cmp A B)",
+                            self.location
+                        )))
+                    }
+                },
+                // アドレスはレジスタBだけで指定できる
+                "ld" | "st" => match (op.as_str(), operands[0].as_str(), operands[1].as_str()) {
+                    ("ld", "A", "[B]") => Token::Ld,
+                    ("st", "[B]", "A") => Token::St,
+                    _ => {
+                        return Err(EmulatorErr::new(&format!(
+                            "{}: write ld A [B] or st [B] A",
+                            self.location
+                        )))
+                    }
+                },
+                // 入れ替えなのでオペランドの順番は問わない
+                "swap" => match (operands[0].as_str(), operands[1].as_str()) {
+                    ("A", "B") | ("B", "A") => Token::Swap,
+                    _ => {
+                        return Err(EmulatorErr::new(&format!(
+                            "{}: swap exchanges A and B (swap A B)",
+                            self.location
+                        )))
+                    }
+                },
+                "call" => Token::Call(self.immediate(op, operands, 0)?),
+                "ret" => Token::Ret,
+                ".byte" => Token::Byte(self.value(operands, 0xff)?),
+                ".org" => Token::Org(self.value(operands, 0x0f)?),
+                _ => unreachable!("arity check rejects unknown mnemonics"),
+            };
+
+            self.address = match token {
+                Token::Org(address) => address as usize,
+                _ => {
+                    self.placed.push((self.address, number));
+                    self.address + 1
+                }
+            };
+            result.push(token);
         }
 
         Ok(result)
     }
 
-    fn from_binary_to_decimal(&self, text: impl Into<String>) -> Result<u8, EmulatorErr> {
+    // 1行ずつ組み立てるときに、この行が address から始まるものとして読む (Assembler)
+    // この行で定義したラベルをずらし、それまでに定義されたラベルと合わせる
+    pub fn place_at(
+        &mut self,
+        address: usize,
+        labels: &HashMap<String, i64>,
+    ) -> Result<(), EmulatorErr> {
+        self.address = address;
+        let defined = std::mem::take(&mut self.labels);
+        self.labels = labels.clone();
+        for (name, offset) in defined {
+            if self.labels.contains_key(&name) {
+                return Err(EmulatorErr::new(&format!(
+                    "{}: label {} is already defined",
+                    self.source.first().map_or("", |(location, _)| location),
+                    name
+                )));
+            }
+            self.labels.insert(name, address as i64 + offset);
+        }
+        Ok(())
+    }
+
+    pub fn labels(&self) -> &HashMap<String, i64> {
+        &self.labels
+    }
+
+    // parse したあと、各アドレスに置いたバイトがソースの何行目から来たか
+    pub fn source_lines(&self) -> &[(usize, usize)] {
+        &self.placed
+    }
+
+    // number of operands each mnemonic takes and whether the last one is an immediate,
+    // None for unknown mnemonics
+    fn operand_count(op: &str) -> Option<(usize, bool)> {
+        match op {
+            "mov" | "add" | "sub" => Some((2, true)),
+            "cmp" | "ld" | "st" | "swap" => Some((2, false)),
+            "jmp" | "jnc" | "out" | "call" => Some((1, true)),
+            "ret" => Some((0, false)),
+            "in" => Some((1, true)),
+            ".byte" | ".data" | ".org" => Some((1, true)),
+            _ => None,
+        }
+    }
+
+    // V2の書き方が混ざっていれば、どうすればよいかを伝える
+    fn check_v1(&self, line: &[String]) -> Result<(), EmulatorErr> {
+        if line[0].ends_with(':') || line.iter().any(|word| word.starts_with([';', '#'])) {
+            return Err(EmulatorErr::new(&format!(
+                "{}: labels and comments need --syntax v2",
+                self.location
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_arity(&self, op: &str, operands: &[String]) -> Result<(), EmulatorErr> {
+        let (expected, immediate) = Self::operand_count(op).ok_or_else(|| {
+            EmulatorErr::new(&format!("{}: unknown instruction: {}", self.location, op))
+        })?;
+
+        // 即値は空白を含む式を書けるので行末までを1つのオペランドとみなす
+        if operands.len() < expected || (!immediate && operands.len() > expected) {
+            return Err(self.arity_error(op, expected, operands.len()));
+        }
+
+        Ok(())
+    }
+
+    fn arity_error(&self, op: &str, expected: usize, given: usize) -> EmulatorErr {
+        EmulatorErr::new(&format!(
+            "{}: {} takes {} operand(s) but {} were given",
+            self.location, op, expected, given
+        ))
+    }
+
+    fn register(&self, text: &str) -> Result<Register, EmulatorErr> {
+        match text {
+            "A" | "B" => Ok(Register::from(text.to_string())),
+            _ => Err(EmulatorErr::new(&format!(
+                "{}: unknown register: {}",
+                self.location, text
+            ))),
+        }
+    }
+
+    pub fn set_allow_truncation(&mut self, allow: bool) {
+        self.allow_truncation = allow;
+    }
+
+    // ポートを選べるのは拡張モードだけ。本のTD4では in A 0001 は余分なオペランド
+    pub fn set_mode(&mut self, mode: &Mode) {
+        self.port_operands = mode.is_extended();
+    }
+
+    // operands[position..] を即値として読み、4bitに収まるか確かめる
+    fn immediate(&self, op: &str, operands: &[String], position: usize) -> Result<u8, EmulatorErr> {
+        let words = &operands[position..];
+        // 式として読めない余分な単語はオペランドの数の誤りとして報告する
+        if words.len() > 1 && self.evaluate(&words.join(" ")).is_err() {
+            return Err(self.arity_error(op, position + 1, operands.len()));
+        }
+        let value = self.number(words)?;
+        if (0..=0x0f).contains(&value) {
+            return Ok(value as u8);
+        }
+
+        let text = words.join(" ");
+        let instruction = format!("{} {}", op, operands.join(" "));
+        let truncated = (value & 0x0f) as u8;
+        if self.allow_truncation {
+            style::warn(&format!(
+                "{}: {}: immediate {} = {} is truncated to {:04b}",
+                self.location, instruction, text, value, truncated
+            ));
+            return Ok(truncated);
+        }
+        let mut message = format!(
+            "{}: {}: immediate {} = {} doesn't fit in 4 bits (max 15)",
+            self.location, instruction, text, value
+        );
+        let is_jump = matches!(op, "jmp" | "jnc" | "call");
+        if is_jump {
+            // PCは4bitなので16番地以降は0番地から数え直した位置になる
+            message.push_str(&format!(
+                ". Addresses are taken modulo 16, so this would be address {} ({:04b})",
+                truncated, truncated
+            ));
+        } else if (-0x0f..0).contains(&value) && op == "add" {
+            // 即値は符号なしなので、引き算は16から引いた値を足して書く
+            message.push_str(&format!(
+                ". Immediates are unsigned; to subtract {} add {} ({:04b})",
+                -value, truncated, truncated
+            ));
+        } else if value < 0 {
+            message.push_str(". Immediates are unsigned");
+        }
+        if is_jump || value < 0 {
+            message.push_str(". --allow-truncation keeps the lower 4 bits");
+        }
+        Err(EmulatorErr::new(&message))
+    }
+
+    // operands[1..] があればポート番号として読む。省略したらポート0
+    fn port(&self, op: &str, operands: &[String]) -> Result<u8, EmulatorErr> {
+        match operands.len() {
+            1 => Ok(0),
+            given if !self.port_operands => Err(self.arity_error(op, 1, given)),
+            _ => self.immediate(op, operands, 1),
+        }
+    }
+
+    // 式を評価して 0..=max に収まるか確かめる (即値は4bit, データは8bit)
+    fn value(&self, words: &[String], max: i64) -> Result<u8, EmulatorErr> {
+        let text = words.join(" ");
+        let value = self.number(words)?;
+
+        if !(0..=max).contains(&value) {
+            let (kind, bits) = if max == 0x0f {
+                ("immediate", 4)
+            } else {
+                ("data", 8)
+            };
+            return Err(EmulatorErr::new(&format!(
+                "{}: {} {} = {} doesn't fit in {} bits (0..{})",
+                self.location, kind, text, value, bits, max
+            )));
+        }
+
+        Ok(value as u8)
+    }
+
+    fn number(&self, words: &[String]) -> Result<i64, EmulatorErr> {
+        let text = words.join(" ");
+        let value = match (self.syntax, words) {
+            // 0と1だけの数値は従来通り2進数として読む
+            (Syntax::V1, [word]) if word.chars().all(|c| c == '0' || c == '1') => {
+                Self::from_binary_to_decimal(word)?
+            }
+            // V1から移したソースの 0011 を黙って11と読まないようにする
+            (Syntax::V2, [word])
+                if word.len() > 1
+                    && word.starts_with('0')
+                    && word.chars().all(|c| c.is_ascii_digit()) =>
+            {
+                return Err(EmulatorErr::new(&format!(
+                    "{}: {} is ambiguous in syntax v2. Write 0b{} for binary",
+                    self.location, word, word
+                )));
+            }
+            _ => self
+                .evaluate(&text)
+                .map_err(|err| EmulatorErr::new(&format!("{}: {}", self.location, err)))?,
+        };
+        Ok(value)
+    }
+
+    fn evaluate(&self, text: &str) -> Result<i64, EmulatorErr> {
+        expr::evaluate_with_symbols(text, self.address as u8, &self.labels)
+    }
+
+    fn from_binary_to_decimal(text: impl Into<String>) -> Result<i64, EmulatorErr> {
         let ret = text.into();
-        let binary_to_decimal = u8::from_str_radix(&ret, 2);
-        binary_to_decimal.map_err(|_| EmulatorErr::new(&format!("Failed to parse string: {}",ret)))
+        let binary_to_decimal = i64::from_str_radix(&ret, 2);
+        binary_to_decimal.map_err(|_| EmulatorErr::new(&format!("Failed to parse string: {}", ret)))
+    }
+}
+
+// 文字リテラルの中を除いて ; か # から行末までを取り除く
+pub fn strip_comment(text: &str) -> &str {
+    let mut in_quote = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            '\'' => in_quote = !in_quote,
+            ';' | '#' if !in_quote => return &text[..index],
+            _ => (),
+        }
+    }
+    text
+}
+
+// .data の値を文字リテラルの外のカンマで区切る (',' を1つの値として読む)
+fn split_data(text: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut in_quote = false;
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        match c {
+            '\'' => in_quote = !in_quote,
+            ',' if !in_quote => {
+                items.push(&text[start..index]);
+                start = index + 1;
+            }
+            _ => (),
+        }
+    }
+    items.push(&text[start..]);
+    items
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl FromStr for Syntax {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(Syntax::V1),
+            "v2" => Ok(Syntax::V2),
+            _ => Err(EmulatorErr::new(&format!("Unknown syntax: {}", s))),
+        }
     }
 }
 
 #[cfg(test)]
 mod parser_tests {
-    use crate::parser::Parser;
+    use crate::error::EmulatorErr;
+    use crate::macros::SourceLine;
+    use crate::parser::{Parser, Syntax};
+    use crate::profile::MachineProfile;
+    use crate::token::Token::{
+        Add, Byte, Cmp, In, Jmp, Jnc, Ld, Mov, Org, OutB, OutIm, St, Sub, Swap,
+    };
+    use crate::token::{Register, Token};
 
     #[test]
     fn parse_simple() {
+        let code = vec!["mov A 0001".to_string(), "add A 0001".to_string()];
+        let mut parser = Parser::new(code);
+        let result = parser.parse().unwrap();
+        assert_eq!(result.len(), 2);
+    }
+
+    #[test]
+    fn parse_skips_empty_lines() {
+        let code = vec!["".to_string(), "out B".to_string(), "  ".to_string()];
+        let mut parser = Parser::new(code);
+        let result = parser.parse().unwrap();
+        assert_eq!(result.len(), 1);
+    }
+
+    #[test]
+    fn parse_rejects_extra_operand() {
+        let code = vec!["in A 0001".to_string()];
+        let mut parser = Parser::new(code);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn parse_port_number() {
         let code = vec![
-            "mov".to_string(),
-            "A".to_string(),
-            "0001".to_string(),
-            "add".to_string(),
-            "A".to_string(),
-            "0001".to_string(),
+            "in A".to_string(),
+            "in B 0010".to_string(),
+            "out B 3".to_string(),
+        ];
+        let mut parser = Parser::new(code.clone());
+        parser.set_mode(&MachineProfile::td4_extended().mode);
+        assert_eq!(
+            parser.parse().unwrap(),
+            vec![In(Register::A, 0), In(Register::B, 2), OutB(3)]
+        );
+        // 本のTD4ではポートを選べない
+        let err = Parser::new(code).parse().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: in takes 1 operand(s) but 2 were given"
+        );
+        let mut parser = Parser::new(vec!["in A 0001 0001".to_string()]);
+        parser.set_mode(&MachineProfile::td4_extended().mode);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn parse_rejects_missing_operand() {
+        let code = vec!["mov A".to_string(), "out B".to_string()];
+        let mut parser = Parser::new(code);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn parse_rejects_unknown_instruction() {
+        let code = vec!["nop".to_string()];
+        let mut parser = Parser::new(code);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn parse_expression_immediates() {
+        let code = vec![
+            "mov A 'A' & 0x0F".to_string(),
+            "add B 1 << 2".to_string(),
+            "out (0b11 + 1) | 1".to_string(),
+            "jmp 0xf".to_string(),
+            "jnc 0011".to_string(),
         ];
         let mut parser = Parser::new(code);
         let result = parser.parse().unwrap();
-        assert_eq!(result.len(), 2);
+        assert_eq!(
+            result,
+            vec![
+                Mov(Register::A, 1),
+                Add(Register::B, 4),
+                OutIm(5),
+                Jmp(15),
+                Jnc(3)
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_out_of_range_immediate() {
+        for line in ["mov A 10000", "jmp 16", "add A 1 << 4", "out 0 - 1"] {
+            let mut parser = Parser::new(vec![line.to_string()]);
+            assert!(parser.parse().is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn parse_explains_out_of_range_immediate() {
+        let error = |line: &str| {
+            let mut parser = Parser::new(vec![line.to_string()]);
+            parser.parse().unwrap_err().to_string()
+        };
+        assert_eq!(
+            error("mov A 10000"),
+            "line 1: mov A 10000: immediate 10000 = 16 doesn't fit in 4 bits (max 15)"
+        );
+        assert!(
+            error("jmp 20").contains("Addresses are taken modulo 16, so this would be address 4")
+        );
+        assert!(error("add A 0 - 1").contains("to subtract 1 add 15 (1111)"));
+        // 9桁以上の2進数も桁あふれとして報告する
+        assert!(error("mov B 100000000").contains("= 256 doesn't fit in 4 bits"));
+
+        let mut parser = Parser::new(vec!["jmp 10100".to_string(), "add A 0 - 1".to_string()]);
+        parser.set_allow_truncation(true);
+        assert_eq!(
+            parser.parse().unwrap(),
+            vec![Jmp(0b0100), Add(Register::A, 0b1111)]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_unknown_register() {
+        let mut parser = Parser::new(vec!["mov C 0001".to_string()]);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn parse_data_directives() {
+        let code = vec![
+            ".byte 0b10110001".to_string(),
+            ".data 0x12, 'A', 1 << 7".to_string(),
+            ".data ',', 0x2c".to_string(),
+            "out B".to_string(),
+        ];
+        let mut parser = Parser::new(code);
+        let result = parser.parse().unwrap();
+        assert_eq!(
+            result,
+            vec![
+                Byte(0b10110001),
+                Byte(0x12),
+                Byte(0x41),
+                Byte(0x80),
+                Byte(0x2c),
+                Byte(0x2c),
+                OutB(0)
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_invalid_data() {
+        for line in [".byte 256", ".byte", ".data 1,", ".data 1, 0x100"] {
+            let mut parser = Parser::new(vec![line.to_string()]);
+            assert!(parser.parse().is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn parse_org() {
+        let mut parser = Parser::new(vec![".org 0xC".to_string(), "out B".to_string()]);
+        assert_eq!(parser.parse().unwrap(), vec![Org(12), OutB(0)]);
+
+        let mut parser = Parser::new(vec![".org 16".to_string()]);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn parse_sub_cmp() {
+        let code = vec![
+            "sub A 3".to_string(),
+            "sub B 0001".to_string(),
+            "cmp A B".to_string(),
+        ];
+        let mut parser = Parser::new(code);
+        assert_eq!(
+            parser.parse().unwrap(),
+            vec![Sub(Register::A, 3), Sub(Register::B, 1), Cmp]
+        );
+
+        for line in ["cmp B A", "cmp A", "cmp A 1"] {
+            let mut parser = Parser::new(vec![line.to_string()]);
+            assert!(parser.parse().is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn parse_ld_st() {
+        let mut parser = Parser::new(vec!["ld A [B]".to_string(), "st [B] A".to_string()]);
+        assert_eq!(parser.parse().unwrap(), vec![Ld, St]);
+        assert_eq!(parse_v2("ld A, [B]").unwrap(), vec![Ld]);
+
+        for line in ["ld B [A]", "st A [B]", "ld A"] {
+            let mut parser = Parser::new(vec![line.to_string()]);
+            assert!(parser.parse().is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn parse_swap() {
+        let mut parser = Parser::new(vec!["swap A B".to_string(), "swap B A".to_string()]);
+        assert_eq!(parser.parse().unwrap(), vec![Swap, Swap]);
+        assert_eq!(parse_v2("swap A, B").unwrap(), vec![Swap]);
+
+        for line in ["swap", "swap A A", "swap A B C"] {
+            let mut parser = Parser::new(vec![line.to_string()]);
+            assert!(parser.parse().is_err(), "{}", line);
+        }
+    }
+
+    fn parse_v2(source: &str) -> Result<Vec<Token>, EmulatorErr> {
+        let lines = source
+            .lines()
+            .enumerate()
+            .map(|(index, text)| SourceLine::new(index + 1, text))
+            .collect();
+        Parser::with_syntax(lines, Syntax::V2)?.parse()
+    }
+
+    #[test]
+    fn parse_v2_labels_and_comments() {
+        let source = "; counter\nstart: mov A, 0   # from zero\nloop:\n  add A, 1\n  jnc loop\n  jmp start\nend: out ';'  & 0x0f";
+        assert_eq!(
+            parse_v2(source).unwrap(),
+            vec![
+                Mov(Register::A, 0),
+                Add(Register::A, 1),
+                Jnc(1),
+                Jmp(0),
+                OutIm(11)
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_v2_radix() {
+        // V1では0と1だけの数値は2進数、V2では10進数
+        let mut parser = Parser::new(vec!["mov A 10".to_string()]);
+        assert_eq!(parser.parse().unwrap(), vec![Mov(Register::A, 2)]);
+        assert_eq!(parse_v2("mov A 10").unwrap(), vec![Mov(Register::A, 10)]);
+        assert_eq!(parse_v2("mov A 0b0011").unwrap(), vec![Mov(Register::A, 3)]);
+        assert!(parse_v2("mov A 0011").is_err());
+        assert_eq!(
+            parse_v2(".data 1, 2\nhere: jmp here").unwrap(),
+            vec![Byte(1), Byte(2), Jmp(2)]
+        );
+        // 文字リテラルのカンマは値の区切りに数えない
+        assert_eq!(
+            parse_v2(".data ',', 2\nhere: jmp here").unwrap(),
+            vec![Byte(b','), Byte(2), Jmp(2)]
+        );
+    }
+
+    #[test]
+    fn parse_v2_errors() {
+        assert!(parse_v2("a: out 1\na: out 2").is_err());
+        assert!(parse_v2("jmp nowhere").is_err());
+
+        // V1ではラベルやコメントを使えない
+        let mut parser = Parser::new(vec!["loop: out 0001".to_string()]);
+        let err = parser.parse().unwrap_err();
+        assert!(format!("{}", err).contains("--syntax v2"));
+        let mut parser = Parser::new(vec!["out 0001 ; comment".to_string()]);
+        assert!(parser.parse().is_err());
     }
 }
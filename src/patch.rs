@@ -0,0 +1,284 @@
+use crate::compiler::assemble;
+use crate::disassembler::disassemble;
+use crate::error::EmulatorErr;
+use crate::grader::parse_integer;
+use std::fmt;
+use std::str::FromStr;
+
+// 2つのROMで中身が違うアドレス (td4emu romdiff)。片方にしかないアドレスは None
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ByteDiff {
+    pub address: usize,
+    pub left: Option<u8>,
+    pub right: Option<u8>,
+}
+
+pub fn diff(left: &[u8], right: &[u8]) -> Vec<ByteDiff> {
+    (0..left.len().max(right.len()))
+        .map(|address| ByteDiff {
+            address,
+            left: left.get(address).copied(),
+            right: right.get(address).copied(),
+        })
+        .filter(|diff| diff.left != diff.right)
+        .collect()
+}
+
+// アドレスごとに両方のバイトと逆アセンブル結果を並べる
+//
+//     addr  a.bin                 b.bin
+//     0x3   11100001  jnc 0001    11100000  jnc 0000
+//     0x5   -                     10110011  out 0011
+pub fn report(diffs: &[ByteDiff], names: (&str, &str)) -> String {
+    let side = |byte: Option<u8>| match byte {
+        Some(byte) => format!("{:08b}  {}", byte, disassemble(byte)),
+        None => "-".to_string(),
+    };
+    let rows: Vec<(String, String, String)> = diffs
+        .iter()
+        .map(|diff| {
+            (
+                format!("0x{:x}", diff.address),
+                side(diff.left),
+                side(diff.right),
+            )
+        })
+        .collect();
+    let left_width = rows
+        .iter()
+        .map(|(_, left, _)| left.len())
+        .chain([names.0.len()])
+        .max()
+        .unwrap();
+
+    let mut text = format!(
+        "{:<5} {:<width$}  {}\n",
+        "addr",
+        names.0,
+        names.1,
+        width = left_width
+    );
+    for (address, left, right) in rows {
+        text.push_str(&format!(
+            "{:<5} {:<width$}  {}\n",
+            address,
+            left,
+            right,
+            width = left_width
+        ));
+    }
+    text
+}
+
+// ROMに当てる書き換えの並び (td4emu rompatch)。TOMLのうち使う部分だけを読む
+//
+//     [[patch]]
+//     address = 0x3
+//     byte = 0b11100000     # asm = "jnc 0000" でもよい
+//     expect = 0b11100001   # 省略できる。元のバイトが違えば当てない
+#[derive(Debug, PartialEq, Clone)]
+pub struct Patch {
+    pub edits: Vec<Edit>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Edit {
+    pub address: usize,
+    pub byte: u8,
+    pub expect: Option<u8>,
+}
+
+impl FromStr for Patch {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // address と byte がそろっているかは [[patch]] ごとに最後に確かめる
+        let mut edits: Vec<(Option<usize>, Option<u8>, Option<u8>)> = Vec::new();
+        for (number, line) in s.lines().enumerate() {
+            let error = |message: &str| {
+                EmulatorErr::new(&format!("patch line {}: {}", number + 1, message))
+            };
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line == "[[patch]]" {
+                edits.push((None, None, None));
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error(&format!("can't read {}", line)))?;
+            let (key, value) = (key.trim(), value.trim());
+            let edit = edits
+                .last_mut()
+                .ok_or_else(|| error(&format!("{} must be inside a [[patch]]", key)))?;
+            let byte = || {
+                parse_integer(value)
+                    .and_then(|value| u8::try_from(value).ok())
+                    .ok_or_else(|| error(&format!("invalid byte {}", value)))
+            };
+            match key {
+                "address" => {
+                    edit.0 = Some(
+                        parse_integer(value)
+                            .ok_or_else(|| error(&format!("invalid address {}", value)))?
+                            as usize,
+                    )
+                }
+                "byte" => edit.1 = Some(byte()?),
+                "asm" => {
+                    let source = value
+                        .strip_prefix('"')
+                        .and_then(|value| value.strip_suffix('"'))
+                        .ok_or_else(|| error("asm must be a quoted instruction"))?;
+                    let bytes = assemble(source).map_err(|err| error(&err.to_string()))?;
+                    match bytes.as_slice() {
+                        [byte] => edit.1 = Some(*byte),
+                        _ => return Err(error("asm must be exactly one instruction")),
+                    }
+                }
+                "expect" => edit.2 = Some(byte()?),
+                _ => return Err(error(&format!("unexpected key {}", key))),
+            }
+        }
+
+        if edits.is_empty() {
+            return Err(EmulatorErr::new("patch has no [[patch]]"));
+        }
+        edits
+            .into_iter()
+            .enumerate()
+            .map(|(index, edit)| match edit {
+                (Some(address), Some(byte), expect) => Ok(Edit {
+                    address,
+                    byte,
+                    expect,
+                }),
+                _ => Err(EmulatorErr::new(&format!(
+                    "patch {}: needs an address and a byte or asm",
+                    index + 1
+                ))),
+            })
+            .collect::<Result<_, _>>()
+            .map(|edits| Patch { edits })
+    }
+}
+
+impl Patch {
+    // 書き換えたROMを返す。ROMの後ろに足すときは間を0で埋める
+    // rom_size を超えるアドレスや、expect と違う元のバイトがあれば何も書き換えない
+    pub fn apply(&self, rom: &[u8], rom_size: usize) -> Result<Vec<u8>, EmulatorErr> {
+        let mut patched = rom.to_vec();
+        for (index, edit) in self.edits.iter().enumerate() {
+            let error = |message: &str| {
+                EmulatorErr::new(&format!(
+                    "patch {} at 0x{:x}: {}",
+                    index + 1,
+                    edit.address,
+                    message
+                ))
+            };
+            if edit.address >= rom_size {
+                return Err(error(&format!("the ROM has only {} bytes", rom_size)));
+            }
+            if let Some(expect) = edit.expect {
+                let actual = patched.get(edit.address).copied();
+                if actual != Some(expect) {
+                    return Err(error(&format!(
+                        "expected {:08b} but the ROM has {}",
+                        expect,
+                        actual.map_or("nothing".to_string(), |byte| format!("{:08b}", byte))
+                    )));
+                }
+            }
+            if patched.len() <= edit.address {
+                patched.resize(edit.address + 1, 0);
+            }
+            patched[edit.address] = edit.byte;
+        }
+        Ok(patched)
+    }
+}
+
+impl fmt::Display for Edit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "0x{:x}: {:08b}  {}",
+            self.address,
+            self.byte,
+            disassemble(self.byte)
+        )
+    }
+}
+
+#[cfg(test)]
+mod patch_tests {
+    use crate::compiler::assemble;
+    use crate::patch::{diff, report, ByteDiff, Patch};
+
+    #[test]
+    fn test_diff() {
+        let left = assemble("add A 0001\njnc 0001").unwrap();
+        let right = assemble("add A 0001\njnc 0000\nout 0011").unwrap();
+        let diffs = diff(&left, &right);
+        assert_eq!(
+            diffs[1],
+            ByteDiff {
+                address: 2,
+                left: None,
+                right: Some(0b10110011)
+            }
+        );
+        assert_eq!(
+            report(&diffs, ("a.bin", "b.bin")),
+            "addr  a.bin               b.bin\n\
+             0x1   11100001  jnc 0001  11100000  jnc 0000\n\
+             0x2   -                   10110011  out 0011\n"
+        );
+        assert!(diff(&left, &left).is_empty());
+    }
+
+    #[test]
+    fn test_patch() {
+        let patch: Patch = "
+# jnc の飛び先を直して、最後に out を足す
+[[patch]]
+address = 0x1
+asm = \"jnc 0000\"
+expect = 0b11100001
+
+[[patch]]
+address = 3
+byte = 0b10110011
+"
+        .parse()
+        .unwrap();
+        let rom = assemble("add A 0001\njnc 0001").unwrap();
+        assert_eq!(
+            patch.apply(&rom, 16).unwrap(),
+            vec![0b00000001, 0b11100000, 0, 0b10110011]
+        );
+        assert_eq!(patch.edits[0].to_string(), "0x1: 11100000  jnc 0000");
+
+        // 元のバイトが expect と違う
+        let err = patch.apply(&[0, 0], 16).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "patch 1 at 0x1: expected 11100001 but the ROM has 00000000"
+        );
+        assert!(patch.apply(&rom, 3).is_err());
+
+        assert!("address = 1".parse::<Patch>().is_err());
+        let err = "[[patch]]\naddress = 1".parse::<Patch>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "patch 1: needs an address and a byte or asm"
+        );
+        assert!("[[patch]]\naddress = 1\nasm = \"\""
+            .parse::<Patch>()
+            .is_err());
+        assert!("[[patch]]\nbyte = 256".parse::<Patch>().is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use crate::emulator::CpuEmulator;
+use crate::error::EmulatorErr;
+use crate::timing::ExecStats;
+
+// 1クロックごとの各ステージに入っている命令のアドレス
+#[derive(Debug, PartialEq, Clone)]
+pub struct PipelineCycle {
+    pub cycle: usize,
+    pub fetch: Option<u8>,
+    pub decode: Option<u8>,
+    pub execute: Option<u8>,
+    pub flush: bool, // JMP/JNCで分岐したためフェッチ済みの命令を捨てた
+}
+
+// フェッチ、デコード、実行の3段パイプラインとして命令の流れを可視化する
+// 命令の実行自体はCpuEmulatorに任せるので、レジスタやポートの結果はパイプラインなしと同じになる
+pub struct PipelineSimulator<'a> {
+    emulator: &'a CpuEmulator,
+    fetch: Option<u8>,
+    decode: Option<u8>,
+    execute: Option<u8>,
+    next_fetch: u8,
+    cycles: usize,
+    instructions: usize,
+}
+
+impl<'a> PipelineSimulator<'a> {
+    pub fn new(emulator: &'a CpuEmulator) -> Self {
+        let next_fetch = emulator.register().pc();
+        Self {
+            emulator,
+            fetch: None,
+            decode: None,
+            execute: None,
+            next_fetch,
+            cycles: 0,
+            instructions: 0,
+        }
+    }
+
+    // 1クロック進めてそのクロックのステージの状態を返す。停止していればNone
+    pub fn tick(&mut self) -> Result<Option<PipelineCycle>, EmulatorErr> {
+        if self.emulator.does_halt() {
+            return Ok(None);
+        }
+
+        self.execute = self.decode;
+        self.decode = self.fetch;
+        self.fetch = if (self.next_fetch as usize) < self.emulator.rom().len() {
+            self.next_fetch += 1;
+            Some(self.next_fetch - 1)
+        } else {
+            None
+        };
+
+        let mut flush = false;
+        if let Some(address) = self.execute {
+            self.emulator.step()?;
+            self.instructions += 1;
+
+            // 次の命令でなければ分岐したので後続のステージを捨てて分岐先からフェッチし直す
+            let pc = self.emulator.register().pc();
+            if pc != address + 1 {
+                flush = true;
+                self.next_fetch = pc;
+            }
+        }
+
+        let cycle = PipelineCycle {
+            cycle: self.cycles,
+            fetch: self.fetch,
+            decode: self.decode,
+            execute: self.execute,
+            flush,
+        };
+        self.cycles += 1;
+
+        if flush {
+            self.fetch = None;
+            self.decode = None;
+        }
+
+        Ok(Some(cycle))
+    }
+
+    // 停止するかmax_cyclesに達するまで実行する
+    pub fn run(&mut self, max_cycles: usize) -> Result<Vec<PipelineCycle>, EmulatorErr> {
+        let mut cycles = Vec::new();
+        while cycles.len() < max_cycles {
+            match self.tick()? {
+                Some(cycle) => cycles.push(cycle),
+                None => break,
+            }
+        }
+        Ok(cycles)
+    }
+
+    pub fn stats(&self) -> ExecStats {
+        ExecStats {
+            instructions: self.instructions,
+            cycles: self.cycles,
+            ..ExecStats::default()
+        }
+    }
+}
+
+// パイプラインの状態を表形式の文字列にする
+pub fn render_table(cycles: &[PipelineCycle]) -> String {
+    let stage = |address: Option<u8>| match address {
+        Some(address) => format!("0x{:x}", address),
+        None => "-".to_string(),
+    };
+
+    let mut table = String::from("cycle  IF   ID   EX\n");
+    for cycle in cycles {
+        table.push_str(&format!(
+            "{:>5}  {:<4} {:<4} {:<4}{}\n",
+            cycle.cycle,
+            stage(cycle.fetch),
+            stage(cycle.decode),
+            stage(cycle.execute),
+            if cycle.flush { " flush" } else { "" }
+        ));
+    }
+    table
+}
+
+#[cfg(test)]
+mod pipeline_tests {
+    use crate::emulator::CpuEmulator;
+    use crate::pipeline::PipelineSimulator;
+    use crate::port::Ports;
+    use crate::register::Register;
+    use crate::rom::Rom;
+
+    fn emulator(program: Vec<u8>) -> CpuEmulator {
+        CpuEmulator::with(
+            Register::new(),
+            Ports::new(0b0000, 0b0000),
+            Rom::new(program),
+        )
+    }
+
+    #[test]
+    fn test_straight_line() {
+        let emu = emulator(vec![0b00110001, 0b00000001, 0b01000000, 0b10010000]);
+        let mut pipeline = PipelineSimulator::new(&emu);
+        let cycles = pipeline.run(100).unwrap();
+
+        // 2クロックでパイプラインが埋まり、その後は1クロック1命令
+        assert_eq!(cycles.len(), 6);
+        assert_eq!(cycles[0].fetch, Some(0));
+        assert_eq!(cycles[0].execute, None);
+        assert_eq!(cycles[2].execute, Some(0));
+        assert_eq!(cycles[2].decode, Some(1));
+        assert_eq!(cycles[2].fetch, Some(2));
+        assert_eq!(cycles[5].execute, Some(3));
+        assert!(cycles.iter().all(|cycle| !cycle.flush));
+
+        assert_eq!(emu.output(), 2);
+        assert_eq!(pipeline.stats().instructions, 4);
+        assert_eq!(pipeline.stats().cycles, 6);
+    }
+
+    #[test]
+    fn test_jmp_flushes() {
+        let emu = emulator(vec![0b11110011, 0b00110001, 0b00110010, 0b01110011]);
+        let mut pipeline = PipelineSimulator::new(&emu);
+        let cycles = pipeline.run(100).unwrap();
+
+        assert!(cycles[2].flush);
+        assert_eq!(cycles[2].execute, Some(0));
+        assert_eq!(cycles[3].fetch, Some(3));
+        assert_eq!(cycles[3].decode, None);
+        assert_eq!(cycles[5].execute, Some(3));
+        assert_eq!(cycles.len(), 6);
+
+        // パイプラインなしと同じ結果になる
+        let reference = emulator(vec![0b11110011, 0b00110001, 0b00110010, 0b01110011]);
+        assert!(reference.exec().is_ok());
+        assert_eq!(
+            emu.register().register_a(),
+            reference.register().register_a()
+        );
+        assert_eq!(
+            emu.register().register_b(),
+            reference.register().register_b()
+        );
+        assert_eq!(emu.register().register_a(), 0);
+    }
+
+    #[test]
+    fn test_max_cycles() {
+        // jmp 0001, jmp 0000 (自分自身へのジャンプは停止とみなされるので2命令でループする)
+        let emu = emulator(vec![0b11110001, 0b11110000]);
+        let mut pipeline = PipelineSimulator::new(&emu);
+        assert_eq!(pipeline.run(10).unwrap().len(), 10);
+    }
+}
@@ -0,0 +1,55 @@
+use crate::error::EmulatorErr;
+use crate::machine::Device;
+use crate::op::Opcode;
+use crate::tracer::TraceRecord;
+use std::ops::RangeInclusive;
+
+// クレートを書き換えずに周辺装置や命令を足すための拡張。Machine::add_plugin で登録する
+// 共有ライブラリから読み込む仕組みは持たないので、使う側のクレートで実装して登録する
+pub trait Plugin: Send {
+    fn name(&self) -> &str;
+
+    // 入出力ポートにつなぐ周辺装置。登録したときに1度だけ呼ばれる
+    fn devices(&mut self) -> Vec<Box<dyn Device>> {
+        Vec::new()
+    }
+
+    // 追加する命令と、それに割り当てる下位4bit (FREE_FUNCTIONS のどれか)
+    fn instructions(&mut self) -> Vec<(u8, Box<dyn CustomInstruction>)> {
+        Vec::new()
+    }
+
+    // 命令を1つ実行するたびに、その直後の状態を受け取る
+    fn on_trace(&mut self, _record: &TraceRecord) {}
+}
+
+// 追加した命令から見える状態。書き換えた値がCPUに戻る
+#[derive(Debug, PartialEq, Clone)]
+pub struct InstructionState {
+    pub a: u8,
+    pub b: u8,
+    pub carry: u8,
+    // 入力ポート0の値
+    pub input: u8,
+    // Some にすると出力ポート0に書き込む
+    pub output: Option<u8>,
+}
+
+pub trait CustomInstruction: Send {
+    // 1クロックで実行する。PCは次の命令に進む
+    fn execute(&mut self, state: &mut InstructionState) -> Result<(), EmulatorErr>;
+}
+
+// 拡張モードの 1010 のうち RET, CMP, LD, ST, SWAP が使っていない下位4bit
+pub const FREE_FUNCTIONS: RangeInclusive<u8> = 0b0101..=0b1111;
+
+// 下位4bitから命令の1バイトを作る。アセンブラでは .byte で書く
+pub fn encode(function: u8) -> Result<u8, EmulatorErr> {
+    if !FREE_FUNCTIONS.contains(&function) {
+        return Err(EmulatorErr::new(&format!(
+            "{:04b} is used by the built-in instructions. Use 0101..1111",
+            function
+        )));
+    }
+    Ok(Opcode::Ret.op() << 4 | function)
+}
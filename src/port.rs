@@ -1,22 +1,307 @@
-pub struct Port {
-    input: u8,
-    output: u8,
+use crate::debugger::parse_number;
+use crate::error::EmulatorErr;
+use std::io::BufRead;
+
+// OUT命令などで出力ポートに書き込まれるたびに呼ばれる
+// cycle は書き込んだ命令を実行し始めたサイクル
+pub trait OutputObserver: Send {
+    fn on_output(&mut self, cycle: usize, port: usize, value: u8);
+}
+
+// IN命令などで入力ポートを読む直前に呼ばれ、Some なら読む前にその値を入力ポートに入れる
+pub trait InputSource: Send {
+    fn next_input(&mut self, cycle: usize, port: usize) -> Result<Option<u8>, EmulatorErr>;
+}
+
+// ファイル、FIFO、ソケットなどから1行に1つずつ値を読む入力ソース (--in-stream)
+// 行の最後の語を値とするので --out-stream --stream-cycles の出力もそのまま読める
+// 読み終えたあとは default があればその値、なければ入力ポートの値を変えない
+pub struct InputStream<R: BufRead> {
+    reader: R,
+    default: Option<u8>,
+    line: usize,
+}
+
+impl<R: BufRead> InputStream<R> {
+    pub fn new(reader: R, default: Option<u8>) -> Self {
+        InputStream {
+            reader,
+            default,
+            line: 0,
+        }
+    }
+}
+
+impl<R: BufRead + Send> InputSource for InputStream<R> {
+    fn next_input(&mut self, _cycle: usize, _port: usize) -> Result<Option<u8>, EmulatorErr> {
+        let mut text = String::new();
+        loop {
+            text.clear();
+            // 値が届くまでブロックする
+            let read = self
+                .reader
+                .read_line(&mut text)
+                .map_err(|err| EmulatorErr::new(&format!("Failed to read the input: {}", err)))?;
+            if read == 0 {
+                return Ok(self.default);
+            }
+            self.line += 1;
+            if let Some(value) = text.split_whitespace().last() {
+                return parse_number(value).map(Some).map_err(|_| {
+                    EmulatorErr::new(&format!(
+                        "input line {}: invalid value {}",
+                        self.line, value
+                    ))
+                });
+            }
+        }
+    }
 }
 
-impl Port {
+// 入出力ポート。本のTD4は入力と出力が1つずつで、拡張モードでは増やせる
+// 番号を指定しない input / output などはポート0を読み書きする
+pub struct Ports {
+    inputs: Vec<u8>,
+    outputs: Vec<u8>,
+    history: Vec<(usize, u8)>, // (cycle, output) of every write to output port 0
+}
+
+impl Ports {
     pub fn new(input: u8, output: u8) -> Self {
-        Self { input, output }
+        Self {
+            inputs: vec![input],
+            outputs: vec![output],
+            history: Vec::new(),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.inputs.len()
+    }
+
+    // ポートの数を変える。増やしたポートの値は0
+    pub fn resize(&mut self, count: usize) {
+        self.inputs.resize(count.max(1), 0);
+        self.outputs.resize(count.max(1), 0);
+    }
+
+    // 入力はそのままに出力と履歴を消す
+    pub fn clear_outputs(&mut self) {
+        self.outputs.iter_mut().for_each(|output| *output = 0);
+        self.history.clear();
     }
 
     pub fn input(&self) -> u8 {
-        self.input
+        self.inputs[0]
+    }
+
+    pub fn set_input(&mut self, im: u8) {
+        self.inputs[0] = im;
     }
 
     pub fn output(&self) -> u8 {
-        self.output
+        self.outputs[0]
     }
 
     pub fn set_output(&mut self, im: u8) {
-        self.output = im;
+        self.outputs[0] = im;
+    }
+
+    // 存在しないポートはNone
+    pub fn input_at(&self, port: usize) -> Option<u8> {
+        self.inputs.get(port).copied()
+    }
+
+    pub fn output_at(&self, port: usize) -> Option<u8> {
+        self.outputs.get(port).copied()
+    }
+
+    pub fn set_input_at(&mut self, port: usize, im: u8) -> bool {
+        match self.inputs.get_mut(port) {
+            Some(input) => {
+                *input = im;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn inputs(&self) -> &[u8] {
+        &self.inputs
+    }
+
+    pub fn outputs(&self) -> &[u8] {
+        &self.outputs
+    }
+
+    // OUT命令による書き込みは実行サイクルと一緒に記録する
+    pub fn write_output(&mut self, cycle: usize, im: u8) {
+        self.write_output_at(cycle, 0, im);
+    }
+
+    // ポート0以外への書き込みは履歴に残さない
+    pub fn write_output_at(&mut self, cycle: usize, port: usize, im: u8) -> bool {
+        match self.outputs.get_mut(port) {
+            Some(output) => *output = im,
+            None => return false,
+        }
+        if port == 0 {
+            self.history.push((cycle, im));
+        }
+        true
+    }
+
+    // パルス出力の終わり。OUT命令による書き込みではないので履歴には残さない
+    pub fn clear_output_at(&mut self, port: usize) {
+        if let Some(output) = self.outputs.get_mut(port) {
+            *output = 0;
+        }
+    }
+
+    pub fn output_history(&self) -> &[(usize, u8)] {
+        &self.history
+    }
+}
+
+// 入力ポートの同期とチャタリング除去 (MachineProfile の input_sampling と input_debounce)
+// 命令を実行し終えるクロックの立ち上がりごとに clock で入力ポートの値を取り込む
+#[derive(Debug, Clone)]
+pub struct InputFilter {
+    // 値が確定するのに必要な、同じ値が続くクロック数
+    debounce: usize,
+    ports: Vec<FilteredInput>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FilteredInput {
+    // 最後に取り込んだ値とそれが続いたクロック数
+    last: u8,
+    stable: usize,
+    // IN命令に見せる確定した値
+    value: u8,
+}
+
+impl FilteredInput {
+    fn next(self, input: u8, cycles: usize, debounce: usize) -> Self {
+        let stable = match input == self.last {
+            true => (self.stable + cycles).min(debounce),
+            false => cycles.min(debounce),
+        };
+        FilteredInput {
+            last: input,
+            stable,
+            value: if stable >= debounce {
+                input
+            } else {
+                self.value
+            },
+        }
+    }
+}
+
+impl InputFilter {
+    // 今の入力ポートの値は確定しているものとして始める
+    pub fn new(inputs: &[u8], debounce: usize) -> Self {
+        let debounce = debounce.max(1);
+        InputFilter {
+            debounce,
+            ports: inputs
+                .iter()
+                .map(|&input| FilteredInput {
+                    last: input,
+                    stable: debounce,
+                    value: input,
+                })
+                .collect(),
+        }
+    }
+
+    // cycles クロックのあいだ inputs の値だった
+    pub fn clock(&mut self, inputs: &[u8], cycles: usize) {
+        for (port, &input) in self.ports.iter_mut().zip(inputs) {
+            *port = port.next(input, cycles, self.debounce);
+        }
+    }
+
+    // 最後のクロックの立ち上がりで確定していた値
+    pub fn value(&self, port: usize) -> Option<u8> {
+        self.ports.get(port).map(|port| port.value)
+    }
+
+    // 命令の実行中に input を読んだときに確定している値 (クロックに同期させない場合)
+    pub fn peek(&self, port: usize, input: u8) -> Option<u8> {
+        self.ports
+            .get(port)
+            .map(|port| port.next(input, 1, self.debounce).value)
+    }
+}
+
+#[cfg(test)]
+mod port_tests {
+    use crate::port::{InputFilter, InputSource, InputStream, Ports};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_multiple_ports() {
+        let mut ports = Ports::new(0b0001, 0b0000);
+        assert_eq!(ports.count(), 1);
+        assert_eq!(ports.input_at(1), None);
+
+        ports.resize(4);
+        assert_eq!(ports.inputs(), &[0b0001, 0, 0, 0]);
+        assert!(ports.set_input_at(3, 0b0101));
+        assert!(!ports.set_input_at(4, 0b0101));
+        assert_eq!(ports.input_at(3), Some(0b0101));
+
+        assert!(ports.write_output_at(5, 2, 0b1000));
+        ports.write_output(6, 0b0011);
+        assert_eq!(ports.outputs(), &[0b0011, 0, 0b1000, 0]);
+        assert_eq!(ports.output_history(), &[(6, 0b0011)]);
+
+        ports.clear_outputs();
+        assert_eq!(ports.outputs(), &[0, 0, 0, 0]);
+        assert_eq!(ports.inputs(), &[0b0001, 0, 0, 0b0101]);
+        assert!(ports.output_history().is_empty());
+    }
+
+    #[test]
+    fn test_input_stream() {
+        let mut stream = InputStream::new(Cursor::new("3\n\n12 0b0101\nport2 0x7\n"), Some(0));
+        let mut next = || stream.next_input(0, 0).unwrap();
+        assert_eq!(next(), Some(3));
+        assert_eq!(next(), Some(0b0101));
+        assert_eq!(next(), Some(7));
+        assert_eq!(next(), Some(0));
+
+        let mut stream = InputStream::new(Cursor::new("1\nabc\n"), None);
+        assert_eq!(stream.next_input(0, 0).unwrap(), Some(1));
+        assert_eq!(
+            stream.next_input(0, 0).unwrap_err().to_string(),
+            "input line 2: invalid value abc"
+        );
+        assert_eq!(stream.next_input(0, 0).unwrap(), None);
+    }
+
+    #[test]
+    fn test_input_filter() {
+        let mut filter = InputFilter::new(&[0b0000, 0b0001], 3);
+        assert_eq!(filter.peek(0, 0b0001), Some(0b0000));
+        // 値が揺れているあいだは前の値のまま
+        for input in [0b0001, 0b0000, 0b0001, 0b0001] {
+            filter.clock(&[input, 0b0001], 1);
+            assert_eq!(filter.value(0), Some(0b0000));
+        }
+        assert_eq!(filter.peek(0, 0b0001), Some(0b0001));
+        filter.clock(&[0b0001, 0b0001], 1);
+        assert_eq!(filter.value(0), Some(0b0001));
+        assert_eq!(filter.value(1), Some(0b0001));
+        assert_eq!(filter.value(2), None);
+
+        // 何クロックもかかる命令のあいだ変わらなければ確定する
+        filter.clock(&[0b0100, 0b0001], 3);
+        assert_eq!(filter.value(0), Some(0b0100));
+
+        let filter = InputFilter::new(&[0b0000], 0);
+        assert_eq!(filter.peek(0, 0b0110), Some(0b0110));
     }
 }
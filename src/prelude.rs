@@ -0,0 +1,48 @@
+// ライブラリとして使うときによく使うものをまとめたもの
+//
+//     use td4emu::prelude::*;
+//
+// ここに並べたものは内部の作りを変えても名前と使い方を保つ
+pub use crate::compiler::{assemble, assemble_with};
+pub use crate::emulator::{CpuEmulator, PokeTarget};
+pub use crate::error::EmulatorErr;
+pub use crate::hook::{Hook, HookAction, HookState};
+pub use crate::machine::{Device, Expected, Machine, StopReason};
+pub use crate::macros::MacroExpander;
+pub use crate::op::Opcode;
+pub use crate::plugin::{CustomInstruction, InstructionState, Plugin};
+pub use crate::port::Ports;
+pub use crate::profile::MachineProfile;
+pub use crate::register::Register;
+pub use crate::rom::Rom;
+
+#[cfg(test)]
+mod prelude_tests {
+    use crate::debug_info::Region;
+    use crate::prelude::*;
+
+    #[test]
+    fn test_prelude() {
+        let mut machine = Machine::new(MachineProfile::default());
+        machine.set_quiet(true);
+        machine
+            .load_rom(assemble("mov B 0101\nout B").unwrap())
+            .unwrap();
+        assert_eq!(machine.run(None).unwrap(), StopReason::Halted);
+        assert_eq!(machine.emulator().output(), 0b0101);
+
+        let emulator = CpuEmulator::with(
+            Register::new(),
+            Ports::new(0b0000, 0b0000),
+            Rom::new(vec![Opcode::MovA.encode(0b0011)]),
+        );
+        emulator.step().unwrap();
+        assert_eq!(emulator.register().register_a(), 0b0011);
+
+        // assemble_with が返す DebugInfo も公開されたモジュールから使える
+        let (program, debug_info) =
+            assemble_with("out 0001\n.data 0x3", &MacroExpander::new()).unwrap();
+        assert_eq!(program, vec![0b10110001, 0x3]);
+        assert_eq!(debug_info.region(1), Some(Region::Data));
+    }
+}
@@ -0,0 +1,238 @@
+use crate::error::EmulatorErr;
+use crate::grader::parse_integer;
+use crate::profile::{FetchBeyondRom, FlagModel, MachineProfile};
+use std::path::PathBuf;
+
+// プロファイルとクロックの組み合わせに名前を付けたもの (--preset)
+// フラグの扱いやROMの外の読み方を1つずつ指定しなくても済むようにする
+#[derive(Debug, PartialEq, Clone)]
+pub struct Preset {
+    pub name: String,
+    pub description: String,
+    pub profile: MachineProfile,
+    // 指定があれば --clock の代わりに使う
+    pub clock: Option<f64>,
+}
+
+// 同梱のプリセット
+pub fn builtin() -> Vec<Preset> {
+    vec![
+        Preset {
+            name: "book".to_string(),
+            description: "the instruction table of the book, read literally".to_string(),
+            profile: MachineProfile::td4_book(),
+            clock: None,
+        },
+        Preset {
+            name: "hardware-faithful".to_string(),
+            description: "the board: empty switches read 0, 10 Hz clock".to_string(),
+            profile: MachineProfile {
+                fetch_beyond_rom: FetchBeyondRom::ReturnZero,
+                ..MachineProfile::td4_strict()
+            },
+            clock: Some(10.0),
+        },
+        Preset {
+            name: "playground".to_string(),
+            description: "extended instructions, forgiving carry, stops at the end".to_string(),
+            profile: MachineProfile {
+                flag_model: FlagModel::ArithmeticOnly,
+                fetch_beyond_rom: FetchBeyondRom::Halt,
+                ..MachineProfile::td4_extended()
+            },
+            clock: None,
+        },
+    ]
+}
+
+// 設定ファイルの場所。TD4EMU_CONFIG があればそれ、なければ ~/.config/td4emu/config.toml
+pub fn config_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("TD4EMU_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config.join("td4emu").join("config.toml"))
+}
+
+// 同梱のプリセットに設定ファイルのものを足す。同じ名前なら設定ファイルが優先
+pub fn load() -> Result<Vec<Preset>, EmulatorErr> {
+    let mut presets = builtin();
+    let path = match config_path() {
+        Some(path) if path.exists() => path,
+        _ => return Ok(presets),
+    };
+    let text = std::fs::read_to_string(&path)
+        .map_err(|err| EmulatorErr::new(&format!("Failed to read {}: {}", path.display(), err)))?;
+    for preset in parse_presets(&text)? {
+        presets.retain(|builtin| builtin.name != preset.name);
+        presets.push(preset);
+    }
+    Ok(presets)
+}
+
+pub fn find<'a>(presets: &'a [Preset], name: &str) -> Result<&'a Preset, EmulatorErr> {
+    presets
+        .iter()
+        .find(|preset| preset.name == name)
+        .ok_or_else(|| {
+            let names: Vec<&str> = presets.iter().map(|preset| preset.name.as_str()).collect();
+            EmulatorErr::new(&format!(
+                "Unknown preset: {}. Choose from {}",
+                name,
+                names.join(", ")
+            ))
+        })
+}
+
+// 設定ファイルの [preset.名前] の表を読む。ほかの表は読み飛ばす
+//
+//     [preset.lab]
+//     description = "what the lab board does"
+//     profile = "td4-book"
+//     flag_model = "jumps-keep-carry"
+//     beyond_rom = "wrap"
+//     clock = 1
+//
+// 書ける値は outputs, input_sampling, debounce も含めて同じ名前のコマンドラインの指定と同じ
+// halt_on_self_jump = false は --no-halt-on-self-jump にあたる
+pub fn parse_presets(text: &str) -> Result<Vec<Preset>, EmulatorErr> {
+    let mut presets: Vec<Preset> = Vec::new();
+    // 今読んでいるのがプリセットの表か
+    let mut in_preset = false;
+    for (number, line) in text.lines().enumerate() {
+        let error =
+            |message: &str| EmulatorErr::new(&format!("config line {}: {}", number + 1, message));
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(table) = line.strip_prefix('[') {
+            let table = table
+                .strip_suffix(']')
+                .ok_or_else(|| error(&format!("can't read {}", line)))?;
+            in_preset = match table.trim().strip_prefix("preset.") {
+                Some(name) if !name.is_empty() => {
+                    presets.push(Preset {
+                        name: name.to_string(),
+                        description: String::new(),
+                        profile: MachineProfile::default(),
+                        clock: None,
+                    });
+                    true
+                }
+                _ => false,
+            };
+            continue;
+        }
+        if !in_preset {
+            continue;
+        }
+        let preset = presets.last_mut().unwrap();
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error(&format!("can't read {}", line)))?;
+        let key = key.trim();
+        let value = value.trim();
+        let text = value
+            .strip_prefix('"')
+            .and_then(|text| text.strip_suffix('"'));
+        let profile = &mut preset.profile;
+        match (key, text) {
+            ("description", Some(text)) => preset.description = text.to_string(),
+            // 表のほかの値より先に書く
+            ("profile", Some(name)) => *profile = name.parse()?,
+            ("flag_model", Some(model)) => profile.flag_model = model.parse()?,
+            ("beyond_rom", Some(policy)) => profile.fetch_beyond_rom = policy.parse()?,
+            ("outputs", Some(model)) => profile.output_model = model.parse()?,
+            ("input_sampling", Some(sampling)) => profile.input_sampling = sampling.parse()?,
+            ("debounce", None) => {
+                profile.input_debounce =
+                    parse_integer(value).ok_or_else(|| error("invalid debounce"))? as usize
+            }
+            ("halt_on_self_jump", None) => {
+                profile.halt_on_self_jump = value
+                    .parse()
+                    .map_err(|_| error(&format!("invalid halt_on_self_jump {}", value)))?
+            }
+            ("clock", None) => match value.parse::<f64>() {
+                Ok(hz) if hz > 0.0 => preset.clock = Some(hz),
+                _ => return Err(error(&format!("invalid clock frequency {}", value))),
+            },
+            _ => return Err(error(&format!("unexpected key {}", key))),
+        }
+    }
+    for preset in &presets {
+        preset.profile.validate()?;
+    }
+    Ok(presets)
+}
+
+#[cfg(test)]
+mod preset_tests {
+    use crate::preset::{builtin, find, parse_presets};
+    use crate::profile::{FetchBeyondRom, FlagModel, MachineProfile};
+
+    #[test]
+    fn test_builtin() {
+        let presets = builtin();
+        for preset in &presets {
+            assert!(preset.profile.validate().is_ok());
+        }
+        assert_eq!(
+            find(&presets, "book").unwrap().profile,
+            MachineProfile::td4_book()
+        );
+        assert!(find(&presets, "playground")
+            .unwrap()
+            .profile
+            .mode
+            .is_extended());
+        let err = find(&presets, "lab").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown preset: lab. Choose from book, hardware-faithful, playground"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let presets = parse_presets(
+            "
+color = \"never\"
+
+[preset.lab]
+description = \"what the lab board does\"
+profile = \"td4-book\"  # 未定義のopcodeは読み飛ばす
+flag_model = \"jumps-keep-carry\"
+beyond_rom = \"wrap\"
+debounce = 3
+halt_on_self_jump = false
+clock = 0.5
+
+[other]
+anything = 1
+",
+        )
+        .unwrap();
+        assert_eq!(presets.len(), 1);
+        let lab = &presets[0];
+        assert_eq!(lab.name, "lab");
+        assert_eq!(lab.description, "what the lab board does");
+        assert_eq!(lab.profile.name, "td4-book");
+        assert_eq!(lab.profile.flag_model, FlagModel::JumpsKeepCarry);
+        assert_eq!(lab.profile.fetch_beyond_rom, FetchBeyondRom::Wrap);
+        assert_eq!(lab.profile.input_debounce, 3);
+        assert!(!lab.profile.halt_on_self_jump);
+        assert_eq!(lab.clock, Some(0.5));
+
+        let err = parse_presets("[preset.lab]\nclock = fast").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "config line 2: invalid clock frequency fast"
+        );
+        assert!(parse_presets("[preset.lab]\nflag_model = \"sticky\"").is_err());
+        assert!(parse_presets("[preset.lab]\ncycles = 10").is_err());
+    }
+}
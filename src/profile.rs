@@ -0,0 +1,344 @@
+use crate::error::EmulatorErr;
+use crate::mode::{Extensions, Mode};
+use std::str::FromStr;
+
+// 本の命令表にないopcodeを実行したときの扱い
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum UndefinedOpcodePolicy {
+    Error,
+    // 何もせず次の命令へ進む
+    Nop,
+}
+
+// キャリーフラグの更新のしかた
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FlagModel {
+    // 実機の回路どおり毎クロックALUのキャリーをラッチする (ADD以外では0になる)
+    AluCarry,
+    // 本の命令表どおり算術命令だけがキャリーを変える
+    ArithmeticOnly,
+    // AluCarry と同じだが、ジャンプ命令 (JMP, JNC, CALL, RET) はキャリーを変えない
+    // JMP/JNC がキャリーに触れないと書いている解説に合わせたもの
+    JumpsKeepCarry,
+}
+
+impl FlagModel {
+    pub const NAMES: [&'static str; 3] = ["alu-carry", "arithmetic-only", "jumps-keep-carry"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FlagModel::AluCarry => "alu-carry",
+            FlagModel::ArithmeticOnly => "arithmetic-only",
+            FlagModel::JumpsKeepCarry => "jumps-keep-carry",
+        }
+    }
+
+    // ALUを使わない命令がキャリーをそのまま残すか。残さなければ0にする
+    pub fn keeps_carry(&self, jump: bool) -> bool {
+        match self {
+            FlagModel::AluCarry => false,
+            FlagModel::ArithmeticOnly => true,
+            FlagModel::JumpsKeepCarry => jump,
+        }
+    }
+}
+
+impl FromStr for FlagModel {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "alu-carry" => Ok(FlagModel::AluCarry),
+            "arithmetic-only" => Ok(FlagModel::ArithmeticOnly),
+            "jumps-keep-carry" => Ok(FlagModel::JumpsKeepCarry),
+            _ => Err(EmulatorErr::new(&format!(
+                "Unknown flag model: {}. Choose from {}",
+                s,
+                Self::NAMES.join(", ")
+            ))),
+        }
+    }
+}
+
+// OUT命令で書き込んだ値の保ち方
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OutputModel {
+    // 次に書き込むまで値を保つ (実機のラッチ)
+    Latched,
+    // 書き込んだ次の命令の始めに0に戻る (ブザーやシフトレジスタのクロックの実験用)
+    Pulsed,
+}
+
+impl FromStr for OutputModel {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "latched" => Ok(OutputModel::Latched),
+            "pulsed" => Ok(OutputModel::Pulsed),
+            _ => Err(EmulatorErr::new(&format!("Unknown output model: {}", s))),
+        }
+    }
+}
+
+// IN命令が入力ポートの値を取り込むタイミング
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum InputSampling {
+    // 命令を実行しているあいだの値を読む (スイッチを直結した実機)
+    Direct,
+    // 入力を1段のフリップフロップでクロックに同期させ、命令の始めのクロックの立ち上がりの値を読む
+    Registered,
+}
+
+impl FromStr for InputSampling {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(InputSampling::Direct),
+            "registered" => Ok(InputSampling::Registered),
+            _ => Err(EmulatorErr::new(&format!("Unknown input sampling: {}", s))),
+        }
+    }
+}
+
+// PCがROMの末尾より後ろを指しているときに命令を読む動作
+// ふつうの実行はそこで止まるので、止まった後も実行を続けたとき (Machine::tick など) に効く
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FetchBeyondRom {
+    // 0 (add A 0000) を読む。スイッチを全て下ろしたままの実機の行と同じ
+    ReturnZero,
+    // 何も実行せず、止まったままにする
+    Halt,
+    Error,
+    // ROMの大きさで割った余りのアドレスを読む。止まらずに先頭から実行し直す
+    Wrap,
+}
+
+impl FetchBeyondRom {
+    pub const NAMES: [&'static str; 4] = ["zero", "halt", "error", "wrap"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FetchBeyondRom::ReturnZero => "zero",
+            FetchBeyondRom::Halt => "halt",
+            FetchBeyondRom::Error => "error",
+            FetchBeyondRom::Wrap => "wrap",
+        }
+    }
+
+    // Wrap ならROMの外のPCをROMの中に折り返す。それ以外はそのまま
+    pub fn wrap(&self, pc: u8, rom_size: usize) -> u8 {
+        match self {
+            FetchBeyondRom::Wrap if rom_size > 0 && pc as usize >= rom_size => {
+                (pc as usize % rom_size) as u8
+            }
+            _ => pc,
+        }
+    }
+}
+
+impl FromStr for FetchBeyondRom {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(FetchBeyondRom::ReturnZero),
+            "halt" => Ok(FetchBeyondRom::Halt),
+            "error" => Ok(FetchBeyondRom::Error),
+            "wrap" => Ok(FetchBeyondRom::Wrap),
+            _ => Err(EmulatorErr::new(&format!(
+                "Unknown fetch-beyond-ROM behavior: {}. Choose from {}",
+                s,
+                Self::NAMES.join(", ")
+            ))),
+        }
+    }
+}
+
+// 機種ごとに変わる定数と動作をまとめたもの
+#[derive(Debug, PartialEq, Clone)]
+pub struct MachineProfile {
+    pub name: String,
+    pub rom_size: usize,
+    pub pc_bits: u8,
+    pub register_bits: u8,
+    pub undefined_opcode_policy: UndefinedOpcodePolicy,
+    pub flag_model: FlagModel,
+    pub output_model: OutputModel,
+    pub input_sampling: InputSampling,
+    // 入力ポートの値がこのクロック数だけ変わらなければIN命令に見せる (チャタリング除去、0なら無効)
+    pub input_debounce: usize,
+    pub fetch_beyond_rom: FetchBeyondRom,
+    // 自分自身へのジャンプ (halt マクロ) で止まったことにするか
+    // 実機はそこで同じ命令を繰り返し続ける。false ならエミュレータもそうする
+    pub halt_on_self_jump: bool,
+    pub mode: Mode,
+}
+
+pub const PROFILE_NAMES: [&str; 3] = ["td4-strict", "td4-book", "td4-extended"];
+
+impl MachineProfile {
+    // 実機の回路に忠実なTD4 (既定)
+    pub fn td4_strict() -> Self {
+        MachineProfile {
+            name: "td4-strict".to_string(),
+            rom_size: 16,
+            pc_bits: 4,
+            register_bits: 4,
+            undefined_opcode_policy: UndefinedOpcodePolicy::Error,
+            flag_model: FlagModel::AluCarry,
+            output_model: OutputModel::Latched,
+            input_sampling: InputSampling::Direct,
+            input_debounce: 0,
+            fetch_beyond_rom: FetchBeyondRom::Error,
+            halt_on_self_jump: true,
+            mode: Mode::Standard,
+        }
+    }
+
+    // 本の命令表を文字どおりに読んだTD4
+    pub fn td4_book() -> Self {
+        MachineProfile {
+            name: "td4-book".to_string(),
+            undefined_opcode_policy: UndefinedOpcodePolicy::Nop,
+            flag_model: FlagModel::ArithmeticOnly,
+            fetch_beyond_rom: FetchBeyondRom::ReturnZero,
+            ..Self::td4_strict()
+        }
+    }
+
+    // 拡張命令や割り込みを使えるTD4
+    pub fn td4_extended() -> Self {
+        MachineProfile {
+            name: "td4-extended".to_string(),
+            mode: Mode::Extended(Extensions::default()),
+            ..Self::td4_strict()
+        }
+    }
+
+    pub fn register_mask(&self) -> u8 {
+        Self::mask(self.register_bits)
+    }
+
+    pub fn pc_mask(&self) -> u8 {
+        Self::mask(self.pc_bits)
+    }
+
+    // 16bit以上でもシフトがあふれないようにする (validate の前に呼ばれることもある)
+    fn mask(bits: u8) -> u8 {
+        1u16.checked_shl(bits as u32)
+            .map_or(u16::MAX, |bit| bit - 1) as u8
+    }
+
+    // プロファイル同士の値が矛盾していないか確かめる
+    pub fn validate(&self) -> Result<(), EmulatorErr> {
+        if !(1..=8).contains(&self.register_bits) || !(1..=8).contains(&self.pc_bits) {
+            return Err(EmulatorErr::new(
+                "register_bits and pc_bits must be between 1 and 8",
+            ));
+        }
+        // ポート番号は命令の下位4bitで選ぶ
+        if !(1..=16).contains(&self.mode.port_count()) {
+            return Err(EmulatorErr::new("ports must be between 1 and 16"));
+        }
+        // ROMの全てのアドレスをPCで指せる必要がある
+        if self.rom_size > self.pc_mask() as usize + 1 || self.rom_size == 0 {
+            return Err(EmulatorErr::new(&format!(
+                "rom_size {} can't be addressed with a {} bit PC",
+                self.rom_size, self.pc_bits
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Default for MachineProfile {
+    fn default() -> Self {
+        Self::td4_strict()
+    }
+}
+
+impl FromStr for MachineProfile {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "td4-strict" => Ok(Self::td4_strict()),
+            "td4-book" => Ok(Self::td4_book()),
+            "td4-extended" => Ok(Self::td4_extended()),
+            _ => Err(EmulatorErr::new(&format!(
+                "Unknown profile: {}. Choose from {}",
+                s,
+                PROFILE_NAMES.join(", ")
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod profile_tests {
+    use crate::mode::{Extensions, Mode};
+    use crate::profile::{FlagModel, MachineProfile, PROFILE_NAMES};
+
+    #[test]
+    fn test_presets() {
+        for name in PROFILE_NAMES {
+            let profile: MachineProfile = name.parse().unwrap();
+            assert_eq!(profile.name, name);
+            assert!(profile.validate().is_ok());
+            assert_eq!(profile.register_mask(), 0x0f);
+        }
+        assert!("td5".parse::<MachineProfile>().is_err());
+        assert!(MachineProfile::td4_extended().mode.is_extended());
+    }
+
+    #[test]
+    fn test_flag_models() {
+        for name in FlagModel::NAMES {
+            assert_eq!(name.parse::<FlagModel>().unwrap().name(), name);
+        }
+        assert!("sticky".parse::<FlagModel>().is_err());
+        assert!(!FlagModel::AluCarry.keeps_carry(true));
+        assert!(FlagModel::JumpsKeepCarry.keeps_carry(true));
+        assert!(!FlagModel::JumpsKeepCarry.keeps_carry(false));
+    }
+
+    #[test]
+    fn test_validate() {
+        let profile = MachineProfile {
+            rom_size: 32,
+            ..MachineProfile::default()
+        };
+        assert!(profile.validate().is_err());
+
+        let profile = MachineProfile {
+            rom_size: 32,
+            pc_bits: 5,
+            register_bits: 8,
+            ..MachineProfile::default()
+        };
+        assert!(profile.validate().is_ok());
+        assert_eq!(profile.register_mask(), 0xff);
+        assert_eq!(profile.pc_mask(), 0x1f);
+
+        let profile = MachineProfile {
+            mode: Mode::Extended(Extensions {
+                ports: 17,
+                ..Extensions::default()
+            }),
+            ..MachineProfile::default()
+        };
+        assert!(profile.validate().is_err());
+
+        // 範囲外の幅でもマスクの計算でパニックしない
+        let profile = MachineProfile {
+            register_bits: 16,
+            pc_bits: 20,
+            ..MachineProfile::default()
+        };
+        assert_eq!(profile.register_mask(), 0xff);
+        assert_eq!(profile.pc_mask(), 0xff);
+        assert!(profile.validate().is_err());
+    }
+}
@@ -0,0 +1,365 @@
+use crate::debug_info::DebugInfo;
+use crate::disassembler::{annotated_listing, disassemble, DisasmStyle};
+use crate::emulator::CpuEmulator;
+use crate::error::EmulatorErr;
+use crate::instruction::Instruction;
+use crate::json::Json;
+use crate::timing::BranchStats;
+use std::collections::BTreeMap;
+
+// ヒートマップの棒の長さ (最も多く実行した命令の長さ)
+const BAR_WIDTH: usize = 20;
+
+// 後ろ向きのJMP/JNCで囲まれた範囲をループとみなす
+#[derive(Debug, PartialEq, Clone)]
+pub struct LoopInfo {
+    // ループの先頭 (ジャンプ先) と末尾 (ジャンプ命令) のアドレス
+    pub start: u8,
+    pub end: u8,
+    // 後ろ向きのジャンプが成立した回数
+    pub back_jumps: usize,
+    // 外からループに入った回数と、ループの先頭を実行した回数
+    pub entries: usize,
+    pub iterations: usize,
+    // ループ内で実行した命令数
+    pub instructions: usize,
+}
+
+impl LoopInfo {
+    // 1回入ったときに平均何周するか
+    pub fn average_iterations(&self) -> f64 {
+        if self.entries == 0 {
+            return 0.0;
+        }
+        self.iterations as f64 / self.entries as f64
+    }
+}
+
+// アドレスごとの実行回数
+#[derive(Debug, PartialEq, Clone)]
+pub struct ExecutionProfile {
+    rom: Vec<u8>,
+    counts: Vec<usize>,
+    // 後ろ向きのジャンプ以外でそのアドレスに来た回数
+    entries: Vec<usize>,
+    back_jumps: BTreeMap<(u8, u8), usize>,
+    // JNC命令ごとの分岐した回数としなかった回数
+    branches: BTreeMap<u8, BranchStats>,
+}
+
+// 停止するかmax_cyclesに達するまで実行して命令ごとの実行回数を数える
+pub fn profile(emulator: &CpuEmulator, max_cycles: usize) -> Result<ExecutionProfile, EmulatorErr> {
+    let rom = emulator.rom();
+    let decoded = emulator.decoded_rom();
+    let mut counts = vec![0; rom.len()];
+    let mut entries = vec![0; rom.len()];
+    let mut back_jumps = BTreeMap::new();
+    let mut jumped_back = false;
+    let before = emulator.stats().branches;
+
+    while !emulator.does_halt() && emulator.cycles() < max_cycles {
+        let pc = emulator.register().pc();
+        emulator.step()?;
+        counts[pc as usize] += 1;
+        if !jumped_back {
+            entries[pc as usize] += 1;
+        }
+
+        let target = emulator.register().pc();
+        jumped_back = matches!(
+            decoded[pc as usize],
+            Some(Instruction::Jmp { .. } | Instruction::Jnc { .. })
+        ) && target <= pc;
+        if jumped_back {
+            *back_jumps.entry((target, pc)).or_insert(0) += 1;
+        }
+    }
+
+    // 呼び出す前に実行した分は数えない
+    let mut branches = emulator.stats().branches;
+    for (address, stats) in branches.iter_mut() {
+        if let Some(old) = before.get(address) {
+            stats.taken -= old.taken;
+            stats.not_taken -= old.not_taken;
+        }
+    }
+    branches.retain(|_, stats| stats.total() > 0);
+
+    Ok(ExecutionProfile {
+        rom,
+        counts,
+        entries,
+        back_jumps,
+        branches,
+    })
+}
+
+impl ExecutionProfile {
+    pub fn counts(&self) -> &[usize] {
+        &self.counts
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    pub fn branches(&self) -> &BTreeMap<u8, BranchStats> {
+        &self.branches
+    }
+
+    // 実行回数の多い順にn個のアドレスを返す
+    pub fn hottest(&self, n: usize) -> Vec<(u8, usize)> {
+        let mut addresses: Vec<(u8, usize)> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(address, count)| (address as u8, *count))
+            .collect();
+        addresses.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        addresses.truncate(n);
+        addresses
+    }
+
+    // ループ内で実行した命令数の多い順に返す
+    pub fn loops(&self) -> Vec<LoopInfo> {
+        let mut loops: Vec<LoopInfo> = self
+            .back_jumps
+            .iter()
+            .map(|(&(start, end), &back_jumps)| LoopInfo {
+                start,
+                end,
+                back_jumps,
+                entries: self.entries[start as usize],
+                iterations: self.counts[start as usize],
+                instructions: self.counts[start as usize..=end as usize].iter().sum(),
+            })
+            .collect();
+        loops.sort_by_key(|info| std::cmp::Reverse(info.instructions));
+        loops
+    }
+
+    fn percentage(&self, count: usize) -> f64 {
+        match self.total() {
+            0 => 0.0,
+            total => count as f64 * 100.0 / total as f64,
+        }
+    }
+
+    // 逆アセンブル結果の各行に実行回数と、最も多く実行した命令に対する割合の棒を付ける
+    // color なら棒を実行回数の多さに応じて青, 緑, 黄, 赤で色付けする (ANSIエスケープシーケンス)
+    pub fn annotated_listing(&self, debug_info: Option<&DebugInfo>, color: bool) -> String {
+        let max = self.counts.iter().copied().max().unwrap_or(0);
+        annotated_listing(&self.rom, debug_info, &DisasmStyle::default(), |address| {
+            let count = self.counts[address as usize];
+            let ratio = match count {
+                0 => 0.0,
+                _ => count as f64 / max as f64,
+            };
+            // 1回でも実行していれば少なくとも1文字は表示する
+            let filled = match count {
+                0 => 0,
+                _ => ((ratio * BAR_WIDTH as f64).round() as usize).max(1),
+            };
+            let mut bar = format!("{}{}", "#".repeat(filled), ".".repeat(BAR_WIDTH - filled));
+            if color && count > 0 {
+                let code = match ratio {
+                    ratio if ratio < 0.25 => 34,
+                    ratio if ratio < 0.5 => 32,
+                    ratio if ratio < 0.75 => 33,
+                    _ => 31,
+                };
+                bar = format!("\x1b[{}m{}\x1b[0m", code, bar);
+            }
+            match self.branches.get(&address) {
+                Some(branch) => format!("{:>8}  {}  {}", count, bar, branch),
+                None => format!("{:>8}  {}", count, bar),
+            }
+        })
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::object([
+            ("instructions", self.total().into()),
+            (
+                "addresses",
+                Json::array(self.rom.iter().zip(&self.counts).enumerate().map(
+                    |(address, (byte, count))| {
+                        Json::object([
+                            ("address", address.into()),
+                            ("byte", (*byte).into()),
+                            ("instruction", disassemble(*byte).into()),
+                            ("count", (*count).into()),
+                            ("percent", self.percentage(*count).into()),
+                        ])
+                    },
+                )),
+            ),
+            (
+                "loops",
+                Json::array(self.loops().iter().map(|info| {
+                    Json::object([
+                        ("start", info.start.into()),
+                        ("end", info.end.into()),
+                        ("entries", info.entries.into()),
+                        ("iterations", info.iterations.into()),
+                        ("instructions", info.instructions.into()),
+                        ("average_iterations", info.average_iterations().into()),
+                    ])
+                })),
+            ),
+            (
+                "branches",
+                Json::array(self.branches.iter().map(|(address, branch)| {
+                    Json::object([
+                        ("address", (*address).into()),
+                        ("taken", branch.taken.into()),
+                        ("not_taken", branch.not_taken.into()),
+                    ])
+                })),
+            ),
+        ])
+    }
+
+    // 命令ごとの実行割合を付けた逆アセンブル結果とループの一覧
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        for (address, (byte, count)) in self.rom.iter().zip(&self.counts).enumerate() {
+            report.push_str(&format!(
+                "0x{:x}  {:08b}  {:<12}  {:>6.1}%  {}\n",
+                address,
+                byte,
+                disassemble(*byte),
+                self.percentage(*count),
+                count
+            ));
+        }
+
+        let hottest: Vec<String> = self
+            .hottest(3)
+            .iter()
+            .map(|(address, count)| format!("0x{:x} ({:.1}%)", address, self.percentage(*count)))
+            .collect();
+        report.push_str(&format!("Hottest: {}\n", hottest.join(", ")));
+        for info in self.loops() {
+            report.push_str(&format!(
+                "Loop 0x{:x}..0x{:x}: {:.1}% of instructions, {:.1} iterations per entry on average\n",
+                info.start,
+                info.end,
+                self.percentage(info.instructions),
+                info.average_iterations()
+            ));
+        }
+        for (address, branch) in &self.branches {
+            report.push_str(&format!("JNC 0x{:x}: {}\n", address, branch));
+        }
+        report.push_str(&format!("Instructions: {}\n", self.total()));
+        report
+    }
+}
+
+#[cfg(test)]
+mod profiler_tests {
+    use crate::compiler::assemble_with_debug_info;
+    use crate::emulator::CpuEmulator;
+    use crate::port::Ports;
+    use crate::profile::{FetchBeyondRom, MachineProfile};
+    use crate::profiler::profile;
+    use crate::register::Register;
+    use crate::rom::Rom;
+
+    fn emulator(program: Vec<u8>) -> CpuEmulator {
+        let mut emu = CpuEmulator::with(
+            Register::new(),
+            Ports::new(0b0000, 0b0000),
+            Rom::new(program),
+        );
+        emu.set_quiet(true);
+        emu
+    }
+
+    #[test]
+    fn test_counter_loop() {
+        // mov A 1100, add A 0001, jnc 0001, out 1111
+        let emu = emulator(vec![0b00111100, 0b00000001, 0b11100001, 0b10111111]);
+        let profile = profile(&emu, 1000).unwrap();
+
+        assert_eq!(profile.counts(), &[1, 4, 4, 1]);
+        assert_eq!(profile.total(), 10);
+        assert_eq!(profile.hottest(2), vec![(1, 4), (2, 4)]);
+
+        let loops = profile.loops();
+        assert_eq!(loops.len(), 1);
+        assert_eq!((loops[0].start, loops[0].end), (1, 2));
+        assert_eq!(loops[0].back_jumps, 3);
+        assert_eq!(loops[0].entries, 1);
+        assert_eq!(loops[0].instructions, 8);
+        assert_eq!(loops[0].average_iterations(), 4.0);
+
+        let report = profile.report();
+        assert!(report.contains("0x1  00000001  add A 0001      40.0%  4\n"));
+        assert!(report.contains("Hottest: 0x1 (40.0%), 0x2 (40.0%), 0x0 (10.0%)\n"));
+        assert!(report.contains("Loop 0x1..0x2: 80.0% of instructions, 4.0 iterations per entry"));
+        assert!(report.contains("JNC 0x2: taken 3/4 (75%)\n"));
+    }
+
+    #[test]
+    fn test_fetch_beyond_rom_wraps() {
+        // add A 0001, add A 0001 の後ろは先頭に折り返す
+        let mut emu = CpuEmulator::with_profile(
+            Register::new(),
+            Ports::new(0b0000, 0b0000),
+            Rom::new(vec![0b00000001, 0b00000001]),
+            MachineProfile {
+                fetch_beyond_rom: FetchBeyondRom::Wrap,
+                ..MachineProfile::default()
+            },
+        );
+        emu.set_quiet(true);
+        let profile = profile(&emu, 6).unwrap();
+        assert_eq!(profile.counts(), &[3, 3]);
+        assert_eq!(profile.total(), 6);
+        assert_eq!(emu.register().register_a(), 6);
+    }
+
+    #[test]
+    fn test_annotated_listing() {
+        let (program, debug_info) = assemble_with_debug_info(
+            "mov A 1100\nadd A 0001\njnc 0001\nout 1111\nhalt\n.byte 0xa5",
+        )
+        .unwrap();
+        let profile = profile(&emulator(program), 1000).unwrap();
+
+        let listing = profile.annotated_listing(Some(&debug_info), false);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(
+            lines[0],
+            "0x0  00111100  mov A 1100                 1  #####..............."
+        );
+        assert_eq!(
+            lines[1],
+            "0x1  00000001  add A 0001                 4  ####################"
+        );
+        assert_eq!(
+            lines[2],
+            "0x2  11100001  jnc 0001                   4  ####################  taken 3/4 (75%)"
+        );
+        assert_eq!(
+            lines[5],
+            "0x5  10100101  .byte 0b10100101           0  ...................."
+        );
+
+        let colored = profile.annotated_listing(None, true);
+        assert!(colored.contains("\x1b[32m#####...............\x1b[0m"));
+        assert!(colored.contains("\x1b[31m####################\x1b[0m"));
+    }
+
+    #[test]
+    fn test_max_cycles() {
+        // add A 0001, jmp 0000 の無限ループ
+        let emu = emulator(vec![0b00000001, 0b11110000]);
+        let profile = profile(&emu, 100).unwrap();
+        assert_eq!(profile.total(), 100);
+        assert_eq!(profile.loops()[0].average_iterations(), 50.0);
+    }
+}
@@ -0,0 +1,464 @@
+use crate::debug_info::{DebugInfo, Region};
+use crate::error::EmulatorErr;
+use crate::instruction::Instruction;
+use crate::op::Opcode;
+use crate::rom::Rom;
+use std::collections::HashMap;
+
+// Rustのコードで組み立てたプログラム。アセンブラの出力と同じくバイト列とデバッグ情報を持つ
+#[derive(Debug, PartialEq, Clone)]
+pub struct Program {
+    bytes: Vec<u8>,
+    debug_info: DebugInfo,
+    labels: HashMap<String, u8>,
+}
+
+impl Program {
+    pub fn builder() -> ProgramBuilder {
+        ProgramBuilder::new()
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn debug_info(&self) -> &DebugInfo {
+        &self.debug_info
+    }
+
+    pub fn label(&self, name: &str) -> Option<u8> {
+        self.labels.get(name).copied()
+    }
+
+    // assemble_with_debug_info と同じ形で取り出す
+    pub fn into_parts(self) -> (Vec<u8>, DebugInfo) {
+        (self.bytes, self.debug_info)
+    }
+}
+
+// ジャンプ先。ラベル名かアドレスで指定する
+#[derive(Debug, PartialEq, Clone)]
+pub enum Target {
+    Label(String),
+    Address(u8),
+}
+
+impl From<&str> for Target {
+    fn from(name: &str) -> Self {
+        Target::Label(name.to_string())
+    }
+}
+
+impl From<String> for Target {
+    fn from(name: String) -> Self {
+        Target::Label(name)
+    }
+}
+
+impl From<u8> for Target {
+    fn from(address: u8) -> Self {
+        Target::Address(address)
+    }
+}
+
+#[derive(Clone)]
+enum Item {
+    Op(Opcode, u8),
+    Jump(Opcode, Target),
+    Byte(u8),
+    Org(u8),
+    Label(String),
+}
+
+// Program::builder().mov_a(1).label("loop").add_a(1).out_b().jmp("loop").build()
+// 即値やラベルの範囲は build でまとめて確かめる
+#[derive(Default)]
+pub struct ProgramBuilder {
+    items: Vec<Item>,
+}
+
+impl ProgramBuilder {
+    pub fn new() -> Self {
+        Self { items: Vec::new() }
+    }
+
+    fn op(mut self, opcode: Opcode, im: u8) -> Self {
+        self.items.push(Item::Op(opcode, im));
+        self
+    }
+
+    fn jump(mut self, opcode: Opcode, target: impl Into<Target>) -> Self {
+        self.items.push(Item::Jump(opcode, target.into()));
+        self
+    }
+
+    pub fn mov_a(self, im: u8) -> Self {
+        self.op(Opcode::MovA, im)
+    }
+
+    pub fn mov_b(self, im: u8) -> Self {
+        self.op(Opcode::MovB, im)
+    }
+
+    // mov A B (BをAに転送)
+    pub fn mov_ab(self) -> Self {
+        self.op(Opcode::MovA2B, 0)
+    }
+
+    pub fn mov_ba(self) -> Self {
+        self.op(Opcode::MovB2A, 0)
+    }
+
+    pub fn add_a(self, im: u8) -> Self {
+        self.op(Opcode::AddA, im)
+    }
+
+    pub fn add_b(self, im: u8) -> Self {
+        self.op(Opcode::AddB, im)
+    }
+
+    pub fn in_a(self) -> Self {
+        self.op(Opcode::InA, 0)
+    }
+
+    pub fn in_b(self) -> Self {
+        self.op(Opcode::InB, 0)
+    }
+
+    pub fn out(self, im: u8) -> Self {
+        self.op(Opcode::OutIm, im)
+    }
+
+    pub fn out_b(self) -> Self {
+        self.op(Opcode::OutB, 0)
+    }
+
+    pub fn jmp(self, target: impl Into<Target>) -> Self {
+        self.jump(Opcode::Jmp, target)
+    }
+
+    pub fn jnc(self, target: impl Into<Target>) -> Self {
+        self.jump(Opcode::Jnc, target)
+    }
+
+    // 以下は拡張モードだけの命令
+    pub fn call(self, target: impl Into<Target>) -> Self {
+        self.jump(Opcode::Call, target)
+    }
+
+    pub fn ret(self) -> Self {
+        self.op(Opcode::Ret, 0)
+    }
+
+    pub fn sub_a(self, im: u8) -> Self {
+        self.op(Opcode::SubA, im)
+    }
+
+    pub fn sub_b(self, im: u8) -> Self {
+        self.op(Opcode::SubB, im)
+    }
+
+    pub fn cmp(self) -> Self {
+        self.op(Opcode::Cmp, 0)
+    }
+
+    // swap A B
+    pub fn swap(self) -> Self {
+        self.op(Opcode::Swap, 0)
+    }
+
+    // ld A [B]
+    pub fn ld(self) -> Self {
+        self.op(Opcode::Ld, 0)
+    }
+
+    // st [B] A
+    pub fn st(self) -> Self {
+        self.op(Opcode::St, 0)
+    }
+
+    // 拡張モードのポート番号を指定する場合などに命令をそのまま置く
+    pub fn instruction(self, instruction: Instruction) -> Self {
+        let low = instruction.immediate().or(instruction.port()).unwrap_or(0);
+        self.op(instruction.opcode(), low)
+    }
+
+    // .byte と同じく生のデータを置く
+    pub fn byte(mut self, data: u8) -> Self {
+        self.items.push(Item::Byte(data));
+        self
+    }
+
+    // .org と同じく以降を置くアドレスを指定する
+    pub fn org(mut self, address: u8) -> Self {
+        self.items.push(Item::Org(address));
+        self
+    }
+
+    pub fn label(mut self, name: &str) -> Self {
+        self.items.push(Item::Label(name.to_string()));
+        self
+    }
+
+    pub fn build(self) -> Result<Program, EmulatorErr> {
+        build(&self.items)
+    }
+}
+
+// 1パス目でラベルのアドレスを決め、2パス目でバイトにする
+fn build(items: &[Item]) -> Result<Program, EmulatorErr> {
+    let mut labels = HashMap::new();
+    let mut address = 0usize;
+    for item in items {
+        match item {
+            Item::Label(name) => {
+                if address > 0xff {
+                    return Err(EmulatorErr::new(&format!(
+                        "label {} is past the end of the ROM",
+                        name
+                    )));
+                }
+                if labels.insert(name.clone(), address as u8).is_some() {
+                    return Err(EmulatorErr::new(&format!(
+                        "label {} is already defined",
+                        name
+                    )));
+                }
+            }
+            Item::Org(org) => {
+                if (*org as usize) < address {
+                    return Err(EmulatorErr::new(&format!(
+                        ".org 0x{:x} overlaps code already placed at 0x0..0x{:x}",
+                        org,
+                        address - 1
+                    )));
+                }
+                address = *org as usize;
+            }
+            _ => address += 1,
+        }
+        if address > 0x100 {
+            return Err(EmulatorErr::new("program doesn't fit in 256 bytes"));
+        }
+    }
+
+    let mut bytes = Vec::new();
+    let mut debug_info = DebugInfo::new();
+    for item in items {
+        let (opcode, im) = match item {
+            Item::Label(_) => continue,
+            Item::Org(org) => {
+                // 隙間はアセンブラと同じく0のデータで埋める
+                while bytes.len() < *org as usize {
+                    bytes.push(0);
+                    debug_info.push(Region::Data);
+                }
+                continue;
+            }
+            Item::Byte(data) => {
+                bytes.push(*data);
+                debug_info.push(Region::Data);
+                continue;
+            }
+            Item::Op(opcode, im) => (*opcode, *im),
+            Item::Jump(opcode, Target::Address(im)) => (*opcode, *im),
+            Item::Jump(opcode, Target::Label(name)) => {
+                let im = labels
+                    .get(name)
+                    .copied()
+                    .ok_or_else(|| EmulatorErr::new(&format!("Unknown label {}", name)))?;
+                // 16番地以降のラベルには4bitのジャンプでは届かない
+                if im > 0x0f {
+                    return Err(EmulatorErr::new(&format!(
+                        "label {} at 0x{:x} is out of reach of {:?}",
+                        name, im, opcode
+                    )));
+                }
+                (*opcode, im)
+            }
+        };
+        if im > 0x0f {
+            return Err(EmulatorErr::new(&format!(
+                "0x{:x}: immediate {} of {:?} doesn't fit in 4 bits (0..15)",
+                bytes.len(),
+                im,
+                opcode
+            )));
+        }
+        bytes.push(Instruction::new(opcode, im).encode());
+        debug_info.push(Region::Code);
+    }
+
+    Ok(Program {
+        bytes,
+        debug_info,
+        labels,
+    })
+}
+
+// 文を並べて組み立てる書き方。生成器のループの中から少しずつ置いていくときに使う
+//
+//     let mut builder = RomBuilder::new();
+//     builder.label("loop");
+//     builder.instruction(Instruction::Add { reg: Reg::A, im: 1 });
+//     builder.jnc_to("loop");
+//     let rom = builder.build()?;
+//
+// ラベルへのジャンプは build でアドレスを埋める。後ろで定義するラベルにも飛べる
+#[derive(Default)]
+pub struct RomBuilder {
+    items: Vec<Item>,
+}
+
+impl RomBuilder {
+    pub fn new() -> Self {
+        Self { items: Vec::new() }
+    }
+
+    pub fn instruction(&mut self, instruction: Instruction) -> &mut Self {
+        let low = instruction.immediate().or(instruction.port()).unwrap_or(0);
+        self.items.push(Item::Op(instruction.opcode(), low));
+        self
+    }
+
+    // 命令として読めるかは確かめずにそのまま置く
+    pub fn byte(&mut self, data: u8) -> &mut Self {
+        self.items.push(Item::Byte(data));
+        self
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) -> &mut Self {
+        self.items.extend(data.iter().map(|data| Item::Byte(*data)));
+        self
+    }
+
+    pub fn org(&mut self, address: u8) -> &mut Self {
+        self.items.push(Item::Org(address));
+        self
+    }
+
+    // 次に置くバイトのアドレスに名前を付ける
+    pub fn label(&mut self, name: &str) -> &mut Self {
+        self.items.push(Item::Label(name.to_string()));
+        self
+    }
+
+    pub fn jmp_to(&mut self, label: &str) -> &mut Self {
+        self.items.push(Item::Jump(Opcode::Jmp, label.into()));
+        self
+    }
+
+    pub fn jnc_to(&mut self, label: &str) -> &mut Self {
+        self.items.push(Item::Jump(Opcode::Jnc, label.into()));
+        self
+    }
+
+    // 拡張モードだけの命令
+    pub fn call_to(&mut self, label: &str) -> &mut Self {
+        self.items.push(Item::Jump(Opcode::Call, label.into()));
+        self
+    }
+
+    // 次に置くバイトのアドレス
+    pub fn address(&self) -> usize {
+        self.items.iter().fold(0, |address, item| match item {
+            Item::Label(_) => address,
+            Item::Org(org) => *org as usize,
+            _ => address + 1,
+        })
+    }
+
+    // エラーは ProgramBuilder と同じ。組み立てたあとも続けて置ける
+    pub fn build(&self) -> Result<Rom, EmulatorErr> {
+        build(&self.items).map(|program| Rom::new(program.into_parts().0))
+    }
+
+    // ラベルとデバッグ情報も欲しいとき
+    pub fn build_program(&self) -> Result<Program, EmulatorErr> {
+        build(&self.items)
+    }
+}
+
+#[cfg(test)]
+mod program_tests {
+    use crate::compiler::assemble_with_debug_info;
+    use crate::instruction::{Instruction, Reg};
+    use crate::program::{Program, ProgramBuilder, RomBuilder};
+
+    #[test]
+    fn test_builder_matches_assembler() {
+        let program = Program::builder()
+            .mov_a(1)
+            .label("loop")
+            .add_a(1)
+            .mov_ba()
+            .out_b()
+            .jnc("loop")
+            .jmp(0)
+            .org(8)
+            .byte(0b10100101)
+            .build()
+            .unwrap();
+        let source =
+            "mov A 0001\nadd A 0001\nmov B A\nout B\njnc 0001\njmp 0000\n.org 8\n.byte 0b10100101";
+        assert_eq!(program.label("loop"), Some(1));
+        assert_eq!(
+            program.into_parts(),
+            assemble_with_debug_info(source).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_builder_errors() {
+        let error = |builder: ProgramBuilder| builder.build().unwrap_err().to_string();
+        assert_eq!(
+            error(Program::builder().jmp("nowhere")),
+            "Unknown label nowhere"
+        );
+        assert_eq!(
+            error(Program::builder().label("a").label("a")),
+            "label a is already defined"
+        );
+        assert_eq!(
+            error(Program::builder().out_b().mov_a(16)),
+            "0x1: immediate 16 of MovA doesn't fit in 4 bits (0..15)"
+        );
+        assert_eq!(
+            error(Program::builder().org(16).label("far").jmp("far")),
+            "label far at 0x10 is out of reach of Jmp"
+        );
+    }
+
+    #[test]
+    fn test_rom_builder() {
+        let mut builder = RomBuilder::new();
+        builder.instruction(Instruction::Mov { reg: Reg::A, im: 1 });
+        // 後ろで定義するラベルにも飛べる
+        builder.jmp_to("start");
+        builder.bytes(&[0xaa, 0x55]);
+        builder.label("start");
+        for _ in 0..2 {
+            builder.instruction(Instruction::Add { reg: Reg::A, im: 1 });
+        }
+        builder.jnc_to("start");
+        assert_eq!(builder.address(), 7);
+        let rom = builder.build().unwrap();
+        assert_eq!(
+            rom.bytes(),
+            [0b00110001, 0b11110100, 0xaa, 0x55, 0b00000001, 0b00000001, 0b11100100]
+        );
+        assert_eq!(builder.build_program().unwrap().label("start"), Some(4));
+
+        builder.org(3);
+        assert_eq!(
+            builder.build().unwrap_err().to_string(),
+            ".org 0x3 overlaps code already placed at 0x0..0x6"
+        );
+        let mut builder = RomBuilder::new();
+        builder.jnc_to("missing");
+        assert_eq!(
+            builder.build().unwrap_err().to_string(),
+            "Unknown label missing"
+        );
+    }
+}
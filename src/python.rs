@@ -0,0 +1,229 @@
+// pyo3 0.22 のマクロが展開するコードで出る誤検知
+#![allow(clippy::useless_conversion)]
+
+use crate::compiler;
+use crate::emulator::PokeTarget;
+use crate::error::EmulatorErr;
+use crate::machine::{self, StopReason};
+use crate::tracer::TracerConfig;
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+// Pythonの td4emu モジュール (python フィーチャー)
+//
+//     import td4emu
+//     machine = td4emu.Machine(td4emu.assemble("in A\nadd A 0011\nmov B A\nout B"), input=4)
+//     machine.run()
+//     machine.output, machine.trace[-1]["a"]
+
+impl From<EmulatorErr> for PyErr {
+    fn from(err: EmulatorErr) -> Self {
+        PyRuntimeError::new_err(err.to_string())
+    }
+}
+
+// ソースコードをアセンブルしたバイト列
+#[pyfunction]
+fn assemble<'py>(py: Python<'py>, source: &str) -> PyResult<Bound<'py, PyBytes>> {
+    let program = compiler::assemble(source)?;
+    Ok(PyBytes::new_bound(py, &program))
+}
+
+// machine::Machine をそのまま包む
+#[pyclass(name = "Machine")]
+struct Machine {
+    machine: machine::Machine,
+}
+
+#[pymethods]
+impl Machine {
+    // trace_last を指定すると直近のそのサイクル数だけトレースを残す
+    #[new]
+    #[pyo3(signature = (rom = Vec::new(), profile = "td4-strict", input = 0, trace_last = None))]
+    fn new(rom: Vec<u8>, profile: &str, input: u8, trace_last: Option<usize>) -> PyResult<Self> {
+        let mut machine = machine::Machine::new(profile.parse()?);
+        machine.set_quiet(true);
+        machine.set_tracer(TracerConfig {
+            capacity: trace_last,
+            ..TracerConfig::default()
+        });
+        machine.load_rom(rom)?;
+        machine.emulator().set_input_port(0, input)?;
+        Ok(Machine { machine })
+    }
+
+    fn step(&mut self) -> PyResult<()> {
+        Ok(self.machine.step()?)
+    }
+
+    // 止まった理由を "halted", "breakpoint", "cycle_limit", "paused" で返す
+    #[pyo3(signature = (max_cycles = 1000))]
+    fn run(&mut self, max_cycles: usize) -> PyResult<&'static str> {
+        let stop = match self.machine.run(Some(max_cycles))? {
+            StopReason::Halted => "halted",
+            StopReason::Breakpoint(_) => "breakpoint",
+            StopReason::CycleLimit => "cycle_limit",
+            StopReason::Paused(_) => "paused",
+        };
+        Ok(stop)
+    }
+
+    fn reset(&mut self) {
+        self.machine.reset();
+    }
+
+    fn add_breakpoint(&mut self, address: u8) {
+        self.machine.add_breakpoint(address);
+    }
+
+    #[getter]
+    fn pc(&self) -> u8 {
+        self.machine.emulator().register().pc()
+    }
+
+    #[setter]
+    fn set_pc(&self, value: u8) -> PyResult<()> {
+        self.poke(PokeTarget::Pc, value)
+    }
+
+    #[getter]
+    fn a(&self) -> u8 {
+        self.machine.emulator().register().register_a()
+    }
+
+    #[setter]
+    fn set_a(&self, value: u8) -> PyResult<()> {
+        self.poke(PokeTarget::RegisterA, value)
+    }
+
+    #[getter]
+    fn b(&self) -> u8 {
+        self.machine.emulator().register().register_b()
+    }
+
+    #[setter]
+    fn set_b(&self, value: u8) -> PyResult<()> {
+        self.poke(PokeTarget::RegisterB, value)
+    }
+
+    #[getter]
+    fn carry(&self) -> u8 {
+        self.machine.emulator().register().carry_flag()
+    }
+
+    #[setter]
+    fn set_carry(&self, value: u8) -> PyResult<()> {
+        self.poke(PokeTarget::CarryFlag, value)
+    }
+
+    // 入出力ポート0。拡張モードのほかのポートは input_port / output_port で読む
+    #[getter]
+    fn input(&self) -> u8 {
+        self.machine.emulator().input()
+    }
+
+    #[setter]
+    fn set_input(&self, value: u8) -> PyResult<()> {
+        Ok(self.machine.emulator().set_input_port(0, value)?)
+    }
+
+    #[getter]
+    fn output(&self) -> u8 {
+        self.machine.emulator().output()
+    }
+
+    fn input_port(&self, port: usize) -> Option<u8> {
+        self.machine.emulator().input_port(port)
+    }
+
+    fn output_port(&self, port: usize) -> Option<u8> {
+        self.machine.emulator().output_port(port)
+    }
+
+    fn set_input_port(&self, port: usize, value: u8) -> PyResult<()> {
+        Ok(self.machine.emulator().set_input_port(port, value)?)
+    }
+
+    #[getter]
+    fn port_count(&self) -> usize {
+        self.machine.emulator().port_count()
+    }
+
+    #[getter]
+    fn cycles(&self) -> usize {
+        self.machine.emulator().cycles()
+    }
+
+    #[getter]
+    fn halted(&self) -> bool {
+        self.machine.emulator().does_halt()
+    }
+
+    // OUT命令で書き込まれた (サイクル, 値) のリスト
+    #[getter]
+    fn output_history(&self) -> Vec<(usize, u8)> {
+        self.machine.emulator().output_history()
+    }
+
+    // 1命令ごとに cycle, pc, instruction と実行後の a, b, carry, output を持つdictのリスト
+    #[getter]
+    fn trace<'py>(&self, py: Python<'py>) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        self.machine
+            .trace_records()
+            .into_iter()
+            .map(|record| {
+                let entry = PyDict::new_bound(py);
+                entry.set_item("cycle", record.cycle)?;
+                entry.set_item("pc", record.pc)?;
+                entry.set_item("instruction", record.instruction)?;
+                entry.set_item("a", record.register.register_a())?;
+                entry.set_item("b", record.register.register_b())?;
+                entry.set_item("carry", record.register.carry_flag())?;
+                entry.set_item("output", record.output)?;
+                Ok(entry)
+            })
+            .collect()
+    }
+}
+
+impl Machine {
+    fn poke(&self, target: PokeTarget, value: u8) -> PyResult<()> {
+        self.machine.emulator().poke(target, value)?;
+        Ok(())
+    }
+}
+
+#[pymodule]
+fn td4emu(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(assemble, module)?)?;
+    module.add_class::<Machine>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod python_tests {
+    use crate::python::{assemble, Machine};
+    use pyo3::prelude::*;
+
+    #[test]
+    fn test_machine() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let rom = assemble(py, "in A\nadd A 0011\nmov B A\nout B\njmp 0100").unwrap();
+            let mut machine = Machine::new(rom.as_bytes().to_vec(), "td4-book", 4, None).unwrap();
+            assert_eq!(machine.run(1000).unwrap(), "halted");
+            assert_eq!((machine.a(), machine.output(), machine.cycles()), (7, 7, 4));
+            assert_eq!(machine.output_history(), vec![(3, 7)]);
+
+            let trace = machine.trace(py).unwrap();
+            assert_eq!(trace.len(), 4);
+            let a: u8 = trace[1].get_item("a").unwrap().unwrap().extract().unwrap();
+            assert_eq!(a, 7);
+
+            assert!(machine.set_input(16).is_err());
+            assert!(assemble(py, "mov A 10000").is_err());
+            assert!(Machine::new(Vec::new(), "td5", 0, None).is_err());
+        });
+    }
+}
@@ -0,0 +1,175 @@
+use crate::compiler::assemble;
+use crate::disassembler::disassemble;
+use crate::fuzz::Xorshift;
+use crate::grader::parse_integer;
+use crate::op::{LowBits, Opcode};
+use std::fmt;
+
+// 命令の符号化を練習する問題 (td4emu quiz)
+// 答え合わせはアセンブラと逆アセンブラに任せるので、命令表と食い違うことはない
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Question {
+    // 命令を見せて1バイトを答えさせる
+    Encode(u8),
+    // 1バイトを見せて命令を答えさせる
+    Decode(u8),
+}
+
+impl Question {
+    pub fn byte(&self) -> u8 {
+        match self {
+            Question::Encode(byte) | Question::Decode(byte) => *byte,
+        }
+    }
+
+    pub fn prompt(&self) -> String {
+        match self {
+            Question::Encode(byte) => format!("Encode {}", disassemble(*byte)),
+            Question::Decode(byte) => format!("Decode {:08b}", byte),
+        }
+    }
+
+    pub fn answer(&self) -> String {
+        match self {
+            Question::Encode(byte) => format!("{:08b}", byte),
+            Question::Decode(byte) => disassemble(*byte),
+        }
+    }
+
+    // バイトは 00110011, 0011 0011, 0b00110011, 0x33 のどれでもよい
+    // 命令は同じバイトにアセンブルされれば正解にする (mov A 3 と mov A 0011 など)
+    pub fn check(&self, answer: &str) -> bool {
+        let answer = answer.trim();
+        match self {
+            Question::Encode(byte) => {
+                let digits: String = answer.split_whitespace().collect();
+                let value = if digits.len() == 8 && digits.chars().all(|c| c == '0' || c == '1') {
+                    u8::from_str_radix(&digits, 2).ok().map(u64::from)
+                } else {
+                    parse_integer(&digits)
+                };
+                value == Some(*byte as u64)
+            }
+            Question::Decode(byte) => {
+                matches!(assemble(answer).as_deref(), Ok([assembled]) if assembled == byte)
+            }
+        }
+    }
+}
+
+// 正解した数と出題した数
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Score {
+    pub correct: usize,
+    pub asked: usize,
+}
+
+impl fmt::Display for Score {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.correct, self.asked)?;
+        if self.asked > 0 {
+            write!(
+                f,
+                " ({:.0}%)",
+                self.correct as f64 * 100.0 / self.asked as f64
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// シードが同じなら同じ順に出題する
+pub struct Quiz {
+    rng: Xorshift,
+    opcodes: Vec<Opcode>,
+    pub score: Score,
+}
+
+impl Quiz {
+    // extended なら拡張モードの命令も出題する
+    pub fn new(seed: u64, extended: bool) -> Self {
+        Quiz {
+            rng: Xorshift::new(seed),
+            opcodes: Opcode::ALL
+                .iter()
+                .copied()
+                .filter(|opcode| extended || !opcode.is_extended())
+                .collect(),
+            score: Score::default(),
+        }
+    }
+
+    pub fn next_question(&mut self) -> Question {
+        let opcode = self.opcodes[self.rng.next_u64() as usize % self.opcodes.len()];
+        // ポート番号は拡張モードの設定しだいなので、いつもポート0にする
+        let im = match opcode.low_bits() {
+            LowBits::Immediate => self.rng.next_byte() & 0x0f,
+            LowBits::Zero | LowBits::Function(_) | LowBits::Port => 0,
+        };
+        let byte = opcode.encode(im);
+        if self.rng.next_byte() & 1 == 0 {
+            Question::Encode(byte)
+        } else {
+            Question::Decode(byte)
+        }
+    }
+
+    // 答え合わせをして点数をつける
+    pub fn answer(&mut self, question: &Question, answer: &str) -> bool {
+        let correct = question.check(answer);
+        self.score.asked += 1;
+        if correct {
+            self.score.correct += 1;
+        }
+        correct
+    }
+}
+
+#[cfg(test)]
+mod quiz_tests {
+    use crate::quiz::{Question, Quiz, Score};
+
+    #[test]
+    fn test_check() {
+        let question = Question::Encode(0b00110011);
+        assert_eq!(question.prompt(), "Encode mov A 0011");
+        for answer in ["00110011", "0011 0011", "0b0011_0011", "0x33", "51"] {
+            assert!(question.check(answer), "{}", answer);
+        }
+        assert!(!question.check("00110010"));
+        assert!(!question.check("mov A 0011"));
+
+        let question = Question::Decode(0b11100101);
+        assert_eq!(question.prompt(), "Decode 11100101");
+        assert_eq!(question.answer(), "jnc 0101");
+        assert!(question.check("jnc 0101"));
+        assert!(question.check("  jnc 5 "));
+        assert!(!question.check("jmp 0101"));
+        assert!(!question.check("jnc 0101\njnc 0101"));
+        assert!(!question.check("11100101"));
+    }
+
+    #[test]
+    fn test_quiz() {
+        // 出題した命令は逆アセンブルした答えで必ず正解になる
+        let mut quiz = Quiz::new(3, true);
+        let questions: Vec<Question> = (0..50).map(|_| quiz.next_question()).collect();
+        for question in &questions {
+            assert!(quiz.answer(question, &question.answer()), "{:?}", question);
+        }
+        assert!(questions.iter().any(|q| matches!(q, Question::Encode(_))));
+        assert!(questions.iter().any(|q| matches!(q, Question::Decode(_))));
+        assert!(!quiz.answer(&questions[0], "?"));
+        assert_eq!(quiz.score.to_string(), "50/51 (98%)");
+
+        // 標準モードでは拡張命令を出さず、同じシードなら同じ問題になる
+        let mut quiz = Quiz::new(3, false);
+        let mut again = Quiz::new(3, false);
+        for _ in 0..50 {
+            let question = quiz.next_question();
+            assert!(question.byte() >> 4 != 0b1010);
+            assert_eq!(again.next_question(), question);
+        }
+        assert_eq!(Score::default().to_string(), "0/0");
+    }
+}
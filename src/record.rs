@@ -0,0 +1,83 @@
+use crate::debugger::Snapshot;
+use crate::error::EmulatorErr;
+use crate::svg;
+use resvg::{tiny_skia, usvg};
+use std::io::Write;
+
+// 1サイクルごとの状態を svg.rs の図にして、GIFアニメーションにつなぐ (td4emu record)
+// hz は1秒に進めるサイクル数。最後のフレームのあとは最初に戻って繰り返す
+pub fn record<W: Write>(frames: &[Snapshot], hz: f64, writer: W) -> Result<(), EmulatorErr> {
+    let error = |message: &str| EmulatorErr::new(&format!("Failed to record: {}", message));
+    if frames.is_empty() {
+        return Err(error("there are no frames"));
+    }
+    // GIFの待ち時間は1/100秒単位で、2より短いとブラウザが遅くしてしまう
+    let delay = (100.0 / hz).round().clamp(2.0, u16::MAX as f64) as u16;
+
+    let mut options = usvg::Options::default();
+    options.fontdb_mut().load_system_fonts();
+    // 大きさは最初のフレームで決める。ROMの長さは変わらないので全フレームが同じ大きさになる
+    let mut writer = Some(writer);
+    let mut encoder = None;
+    for snapshot in frames {
+        let pixmap = render(snapshot, &options)?;
+        let (width, height) = (pixmap.width() as u16, pixmap.height() as u16);
+        let encoder = match &mut encoder {
+            Some(encoder) => encoder,
+            None => {
+                let mut gif = gif::Encoder::new(writer.take().unwrap(), width, height, &[])
+                    .map_err(|err| error(&err.to_string()))?;
+                gif.set_repeat(gif::Repeat::Infinite)
+                    .map_err(|err| error(&err.to_string()))?;
+                encoder.insert(gif)
+            }
+        };
+        let mut rgba = pixmap.take();
+        let mut frame = gif::Frame::from_rgba_speed(width, height, &mut rgba, 20);
+        frame.delay = delay;
+        encoder
+            .write_frame(&frame)
+            .map_err(|err| error(&err.to_string()))?;
+    }
+    Ok(())
+}
+
+// 図を1枚の画像にする。背景は白で塗ってあるので透明な画素は残らない
+fn render(snapshot: &Snapshot, options: &usvg::Options) -> Result<tiny_skia::Pixmap, EmulatorErr> {
+    let error = |message: &str| EmulatorErr::new(&format!("Failed to render a frame: {}", message));
+    let tree = usvg::Tree::from_str(&svg::render(snapshot), options)
+        .map_err(|err| error(&err.to_string()))?;
+    let size = tree.size().to_int_size();
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+        .ok_or_else(|| error("the image is empty"))?;
+    resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+    Ok(pixmap)
+}
+
+#[cfg(test)]
+mod record_tests {
+    use crate::machine::Machine;
+    use crate::profile::MachineProfile;
+    use crate::record::record;
+    use crate::svg::frames;
+
+    #[test]
+    fn test_record() {
+        let mut machine = Machine::new(MachineProfile::default());
+        machine.set_quiet(true);
+        machine.load_source("out 0001\nout 0011\njmp 0010").unwrap();
+        let snapshots = frames(&mut machine, 100).unwrap();
+        let mut gif = Vec::new();
+        record(&snapshots, 2.0, &mut gif).unwrap();
+        assert!(gif.starts_with(b"GIF89a"));
+
+        // 1サイクル0.5秒で、状態ごとに1フレーム
+        let mut decoder = gif::DecodeOptions::new().read_info(gif.as_slice()).unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        assert_eq!(delays, vec![50; snapshots.len()]);
+        assert!(record(&[], 2.0, Vec::new()).is_err());
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Register {
     register_a: u8, // register a
     register_b: u8, // register b
@@ -25,8 +25,9 @@ impl Register {
         self.pc = new_value;
     }
 
+    // 8bitのPCでは0xffの次は0に戻る
     pub fn incr_pc(&mut self) {
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
     }
 
     pub fn carry_flag(&self) -> u8 {
@@ -54,6 +55,12 @@ impl Register {
     }
 }
 
+impl Default for Register {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::register::Register;
@@ -0,0 +1,236 @@
+use crate::error::EmulatorErr;
+use crate::port::OutputObserver;
+use crate::style::{self, Style};
+use std::io::Write;
+use std::str::FromStr;
+
+// 出力ポートの4bitを表示用の文字列に変換する
+pub trait OutputRenderer: Send {
+    fn render(&self, value: u8) -> String;
+}
+
+// LEDの点灯状態として表示する (最上位bitが左)
+pub struct LedRenderer;
+
+impl OutputRenderer for LedRenderer {
+    fn render(&self, value: u8) -> String {
+        (0..4)
+            .rev()
+            .map(|bit| if value >> bit & 1 == 1 { '●' } else { '○' })
+            .collect()
+    }
+}
+
+// 点灯しているLEDを赤く表示する (--color)
+pub struct ColorLedRenderer;
+
+impl OutputRenderer for ColorLedRenderer {
+    fn render(&self, value: u8) -> String {
+        let style = Style::new(true);
+        (0..4)
+            .rev()
+            .map(|bit| {
+                if value >> bit & 1 == 1 {
+                    style.red("●")
+                } else {
+                    "○".to_string()
+                }
+            })
+            .collect()
+    }
+}
+
+pub struct BinaryRenderer;
+
+impl OutputRenderer for BinaryRenderer {
+    fn render(&self, value: u8) -> String {
+        format!("0b{:04b}", value & 0x0f)
+    }
+}
+
+pub struct DecimalRenderer;
+
+impl OutputRenderer for DecimalRenderer {
+    fn render(&self, value: u8) -> String {
+        format!("{}", value)
+    }
+}
+
+pub struct HexRenderer;
+
+impl OutputRenderer for HexRenderer {
+    fn render(&self, value: u8) -> String {
+        format!("0x{:x}", value)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OutputFormat {
+    Led,
+    Binary,
+    Decimal,
+    Hex,
+}
+
+impl OutputFormat {
+    // 端末に表示するためのレンダラー。色を付ける設定ならLEDに色を付ける
+    pub fn renderer(&self) -> Box<dyn OutputRenderer> {
+        match self {
+            OutputFormat::Led if style::current().color() => Box::new(ColorLedRenderer),
+            _ => self.plain_renderer(),
+        }
+    }
+
+    // ファイルやパイプに書き出すためのレンダラー。色を付けない
+    pub fn plain_renderer(&self) -> Box<dyn OutputRenderer> {
+        match self {
+            OutputFormat::Led => Box::new(LedRenderer),
+            OutputFormat::Binary => Box::new(BinaryRenderer),
+            OutputFormat::Decimal => Box::new(DecimalRenderer),
+            OutputFormat::Hex => Box::new(HexRenderer),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "led" => Ok(OutputFormat::Led),
+            "bin" | "binary" => Ok(OutputFormat::Binary),
+            "dec" | "decimal" => Ok(OutputFormat::Decimal),
+            "hex" => Ok(OutputFormat::Hex),
+            _ => Err(EmulatorErr::new(&format!("Unknown output format: {}", s))),
+        }
+    }
+}
+
+// OUT命令で書き込まれた値を1行ずつ書き出し、すぐにflushする (--out-stream)
+// ほかのプロセスがパイプやファイルから出力を順に読めるようにする
+//
+//     12 0b0011           (with_cycles)
+//     13 port2 0b1000     (with_ports)
+pub struct OutputStream<W: Write> {
+    writer: W,
+    renderer: Box<dyn OutputRenderer>,
+    cycles: bool,
+    ports: bool,
+    // 書き込めなくなったら (読み手がパイプを閉じたなど) それ以降は書かない
+    closed: bool,
+}
+
+impl<W: Write> OutputStream<W> {
+    pub fn new(writer: W, format: OutputFormat) -> Self {
+        OutputStream {
+            writer,
+            renderer: format.plain_renderer(),
+            cycles: false,
+            ports: false,
+            closed: false,
+        }
+    }
+
+    // 行の先頭に書き込んだサイクルを付ける
+    pub fn with_cycles(mut self, cycles: bool) -> Self {
+        self.cycles = cycles;
+        self
+    }
+
+    // ポートが複数あるときにポート0以外の行にポート番号を付ける
+    pub fn with_ports(mut self, ports: bool) -> Self {
+        self.ports = ports;
+        self
+    }
+
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+}
+
+impl<W: Write + Send> OutputObserver for OutputStream<W> {
+    fn on_output(&mut self, cycle: usize, port: usize, value: u8) {
+        if self.closed {
+            return;
+        }
+        let mut line = String::new();
+        if self.cycles {
+            line.push_str(&format!("{} ", cycle));
+        }
+        if self.ports && port != 0 {
+            line.push_str(&format!("port{} ", port));
+        }
+        line.push_str(&self.renderer.render(value));
+        let written = writeln!(self.writer, "{}", line).and_then(|_| self.writer.flush());
+        if let Err(err) = written {
+            style::warn(&format!("stopped streaming the output: {}", err));
+            self.closed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod renderer_tests {
+    use crate::port::OutputObserver;
+    use crate::renderer::{
+        BinaryRenderer, ColorLedRenderer, DecimalRenderer, HexRenderer, LedRenderer, OutputFormat,
+        OutputRenderer, OutputStream,
+    };
+
+    #[test]
+    fn test_render_led() {
+        assert_eq!(LedRenderer.render(0b1001), "●○○●");
+        assert_eq!(LedRenderer.render(0b0000), "○○○○");
+        assert_eq!(LedRenderer.render(0b0111), "○●●●");
+        assert_eq!(
+            ColorLedRenderer.render(0b0011),
+            "○○\x1b[31m●\x1b[0m\x1b[31m●\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn test_render_binary() {
+        assert_eq!(BinaryRenderer.render(0b1001), "0b1001");
+        assert_eq!(BinaryRenderer.render(0b0001), "0b0001");
+    }
+
+    #[test]
+    fn test_render_decimal() {
+        assert_eq!(DecimalRenderer.render(0b1001), "9");
+    }
+
+    #[test]
+    fn test_render_hex() {
+        assert_eq!(HexRenderer.render(0b1001), "0x9");
+        assert_eq!(HexRenderer.render(0b1111), "0xf");
+    }
+
+    #[test]
+    fn test_parse_output_format() {
+        assert_eq!("led".parse::<OutputFormat>().unwrap(), OutputFormat::Led);
+        assert_eq!("bin".parse::<OutputFormat>().unwrap(), OutputFormat::Binary);
+        assert_eq!(
+            "dec".parse::<OutputFormat>().unwrap(),
+            OutputFormat::Decimal
+        );
+        assert_eq!("hex".parse::<OutputFormat>().unwrap(), OutputFormat::Hex);
+        assert!("oct".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_output_stream() {
+        let mut stream = OutputStream::new(Vec::new(), OutputFormat::Binary)
+            .with_cycles(true)
+            .with_ports(true);
+        stream.on_output(12, 0, 0b0011);
+        stream.on_output(13, 2, 0b1000);
+        assert_eq!(
+            String::from_utf8_lossy(stream.writer()),
+            "12 0b0011\n13 port2 0b1000\n"
+        );
+
+        let mut stream = OutputStream::new(Vec::new(), OutputFormat::Decimal);
+        stream.on_output(12, 0, 0b0011);
+        assert_eq!(stream.writer(), b"3\n");
+    }
+}
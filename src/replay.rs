@@ -0,0 +1,366 @@
+use crate::debugger::parse_number;
+use crate::error::EmulatorErr;
+use crate::mode::{Extensions, Interrupt, Mode, SaveTarget};
+use crate::profile::{InputSampling, MachineProfile, OutputModel};
+use crate::register::Register;
+use std::fmt;
+use std::str::FromStr;
+
+const VERSION: u8 = 1;
+
+// 1回の実行の結果を左右するものをすべて書き出したもの
+// バグ報告にこのファイルを1つ添えれば Machine::run_from_manifest で同じ実行を再現できる
+#[derive(Debug, PartialEq, Clone)]
+pub struct ReplayManifest {
+    pub rom: Vec<u8>,
+    pub profile: MachineProfile,
+    // 実行を始めるときのレジスタと入力ポート
+    pub register: Register,
+    pub input: u8,
+    // (サイクル, 値): そのサイクル以降に始まる命令の前に入力ポートを書き換える
+    pub inputs: Vec<(usize, u8)>,
+    pub max_cycles: Option<usize>,
+}
+
+impl ReplayManifest {
+    pub fn new(rom: Vec<u8>, profile: MachineProfile) -> Self {
+        ReplayManifest {
+            rom,
+            profile,
+            register: Register::new(),
+            input: 0,
+            inputs: Vec::new(),
+            max_cycles: None,
+        }
+    }
+}
+
+// 1行に1項目のテキスト。# 以降はコメント
+//
+//     version 1
+//     profile td4-strict
+//     mode standard
+//     outputs pulsed      (パルス出力のときだけ)
+//     inputs registered   (入力をクロックに同期させるときだけ)
+//     debounce 3          (チャタリング除去をするときだけ)
+//     flags jumps-keep-carry  (プロファイルと違うフラグモデルのときだけ)
+//     beyond-rom wrap     (ROMの外の読み方がプロファイルと違うときだけ)
+//     self-jump continue  (自分自身へのジャンプで止めないときだけ)
+//     rom 31 01 e1 b0
+//     register pc=0x0 a=0b0000 b=0b0000 c=0
+//     input 0b0000
+//     input-at 12 0b0011
+//     max-cycles 1000
+impl fmt::Display for ReplayManifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# td4emu replay manifest")?;
+        writeln!(f, "version {}", VERSION)?;
+        writeln!(f, "profile {}", self.profile.name)?;
+        match &self.profile.mode {
+            Mode::Standard => writeln!(f, "mode standard")?,
+            Mode::Extended(extensions) => {
+                write!(f, "mode extended ports={}", extensions.ports)?;
+                if let Some(interrupt) = extensions.interrupt {
+                    write!(f, " interrupt={}:0x{:x}", interrupt.bit, interrupt.vector)?;
+                    if interrupt.save_to == SaveTarget::Shadow {
+                        write!(f, " shadow")?;
+                    }
+                }
+                writeln!(f)?;
+            }
+        }
+        if self.profile.output_model == OutputModel::Pulsed {
+            writeln!(f, "outputs pulsed")?;
+        }
+        if self.profile.input_sampling == InputSampling::Registered {
+            writeln!(f, "inputs registered")?;
+        }
+        if self.profile.input_debounce > 0 {
+            writeln!(f, "debounce {}", self.profile.input_debounce)?;
+        }
+        let preset = self.profile.name.parse::<MachineProfile>().ok();
+        if preset.as_ref().map(|preset| preset.flag_model) != Some(self.profile.flag_model) {
+            writeln!(f, "flags {}", self.profile.flag_model.name())?;
+        }
+        if preset.map(|preset| preset.fetch_beyond_rom) != Some(self.profile.fetch_beyond_rom) {
+            writeln!(f, "beyond-rom {}", self.profile.fetch_beyond_rom.name())?;
+        }
+        if !self.profile.halt_on_self_jump {
+            writeln!(f, "self-jump continue")?;
+        }
+        let rom: Vec<String> = self
+            .rom
+            .iter()
+            .map(|data| format!("{:02x}", data))
+            .collect();
+        writeln!(f, "rom {}", rom.join(" "))?;
+        writeln!(
+            f,
+            "register pc=0x{:x} a=0b{:04b} b=0b{:04b} c={}",
+            self.register.pc(),
+            self.register.register_a(),
+            self.register.register_b(),
+            self.register.carry_flag()
+        )?;
+        writeln!(f, "input 0b{:04b}", self.input)?;
+        for (cycle, input) in &self.inputs {
+            writeln!(f, "input-at {} 0b{:04b}", cycle, input)?;
+        }
+        if let Some(max_cycles) = self.max_cycles {
+            writeln!(f, "max-cycles {}", max_cycles)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ReplayManifest {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut manifest = ReplayManifest::new(Vec::new(), MachineProfile::default());
+        let mut mode = None;
+        let mut outputs = None;
+        let mut inputs = None;
+        let mut debounce = None;
+        let mut flags = None;
+        let mut beyond_rom = None;
+        let mut self_jump = None;
+        let mut has_version = false;
+
+        for (number, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: &str| {
+                EmulatorErr::new(&format!("manifest line {}: {}", number + 1, message))
+            };
+            let mut words = line.split_whitespace();
+            let key = words.next().unwrap();
+            let values: Vec<&str> = words.collect();
+
+            match (key, values.as_slice()) {
+                ("version", [version]) => {
+                    if *version != VERSION.to_string() {
+                        return Err(error(&format!("unsupported version {}", version)));
+                    }
+                    has_version = true;
+                }
+                ("profile", [name]) => manifest.profile = name.parse()?,
+                ("mode", ["standard"]) => mode = Some(Mode::Standard),
+                ("mode", ["extended", options @ ..]) => {
+                    mode = Some(Mode::Extended(
+                        parse_extensions(options).map_err(|err| error(&err.to_string()))?,
+                    ))
+                }
+                ("outputs", [model]) => {
+                    outputs = Some(
+                        model
+                            .parse()
+                            .map_err(|err: EmulatorErr| error(&err.to_string()))?,
+                    )
+                }
+                ("inputs", [sampling]) => {
+                    inputs = Some(
+                        sampling
+                            .parse()
+                            .map_err(|err: EmulatorErr| error(&err.to_string()))?,
+                    )
+                }
+                ("debounce", [cycles]) => {
+                    debounce = Some(
+                        cycles
+                            .parse()
+                            .map_err(|_| error(&format!("invalid cycle count {}", cycles)))?,
+                    )
+                }
+                ("flags", [model]) => {
+                    flags = Some(
+                        model
+                            .parse()
+                            .map_err(|err: EmulatorErr| error(&err.to_string()))?,
+                    )
+                }
+                ("beyond-rom", [policy]) => {
+                    beyond_rom = Some(
+                        policy
+                            .parse()
+                            .map_err(|err: EmulatorErr| error(&err.to_string()))?,
+                    )
+                }
+                ("self-jump", ["halt"]) => self_jump = Some(true),
+                ("self-jump", ["continue"]) => self_jump = Some(false),
+                ("rom", bytes) => {
+                    manifest.rom = bytes
+                        .iter()
+                        .map(|byte| u8::from_str_radix(byte, 16))
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| error("rom must be hex bytes"))?
+                }
+                ("register", fields) => {
+                    for field in fields {
+                        let (name, value) = field
+                            .split_once('=')
+                            .ok_or_else(|| error(&format!("invalid register field {}", field)))?;
+                        let value = parse_number(value)?;
+                        match name {
+                            "pc" => manifest.register.set_pc(value),
+                            "a" => manifest.register.set_register_a(value),
+                            "b" => manifest.register.set_register_b(value),
+                            "c" => manifest.register.set_carry_flag(value),
+                            _ => return Err(error(&format!("unknown register {}", name))),
+                        }
+                    }
+                }
+                ("input", [value]) => manifest.input = parse_number(value)?,
+                ("input-at", [cycle, value]) => {
+                    let cycle = cycle
+                        .parse()
+                        .map_err(|_| error(&format!("invalid cycle {}", cycle)))?;
+                    manifest.inputs.push((cycle, parse_number(value)?));
+                }
+                ("max-cycles", [max_cycles]) => {
+                    manifest.max_cycles = Some(
+                        max_cycles
+                            .parse()
+                            .map_err(|_| error(&format!("invalid cycle count {}", max_cycles)))?,
+                    )
+                }
+                _ => return Err(error(&format!("can't read {}", line))),
+            }
+        }
+
+        if !has_version {
+            return Err(EmulatorErr::new("Not a replay manifest (no version line)"));
+        }
+        if let Some(mode) = mode {
+            manifest.profile.mode = mode;
+        }
+        if let Some(outputs) = outputs {
+            manifest.profile.output_model = outputs;
+        }
+        if let Some(inputs) = inputs {
+            manifest.profile.input_sampling = inputs;
+        }
+        if let Some(debounce) = debounce {
+            manifest.profile.input_debounce = debounce;
+        }
+        if let Some(flags) = flags {
+            manifest.profile.flag_model = flags;
+        }
+        if let Some(beyond_rom) = beyond_rom {
+            manifest.profile.fetch_beyond_rom = beyond_rom;
+        }
+        if let Some(self_jump) = self_jump {
+            manifest.profile.halt_on_self_jump = self_jump;
+        }
+        manifest.profile.validate()?;
+        Ok(manifest)
+    }
+}
+
+// ports=n interrupt=bit:vector shadow
+fn parse_extensions(options: &[&str]) -> Result<Extensions, EmulatorErr> {
+    let mut extensions = Extensions::default();
+    for option in options {
+        match option.split_once('=') {
+            Some(("ports", ports)) => {
+                extensions.ports = ports
+                    .parse()
+                    .map_err(|_| EmulatorErr::new(&format!("invalid ports {}", ports)))?
+            }
+            Some(("interrupt", spec)) => {
+                let (bit, vector) = spec
+                    .split_once(':')
+                    .ok_or_else(|| EmulatorErr::new(&format!("invalid interrupt {}", spec)))?;
+                extensions.interrupt =
+                    Some(Interrupt::new(parse_number(bit)?, parse_number(vector)?));
+            }
+            None if *option == "shadow" => {
+                extensions.interrupt = extensions
+                    .interrupt
+                    .map(|interrupt| interrupt.save_to(SaveTarget::Shadow));
+            }
+            _ => return Err(EmulatorErr::new(&format!("unknown option {}", option))),
+        }
+    }
+    Ok(extensions)
+}
+
+#[cfg(test)]
+mod replay_tests {
+    use crate::mode::{Extensions, Interrupt, Mode, SaveTarget};
+    use crate::profile::{FetchBeyondRom, FlagModel, InputSampling, MachineProfile, OutputModel};
+    use crate::replay::ReplayManifest;
+
+    #[test]
+    fn test_round_trip() {
+        let mut profile = MachineProfile::td4_book();
+        profile.mode = Mode::Extended(Extensions {
+            interrupt: Some(Interrupt::new(2, 0xc).save_to(SaveTarget::Shadow)),
+            ports: 2,
+        });
+        profile.output_model = OutputModel::Pulsed;
+        profile.input_sampling = InputSampling::Registered;
+        profile.input_debounce = 3;
+        let mut manifest = ReplayManifest::new(vec![0x31, 0x01, 0xe1, 0xb0], profile);
+        manifest.register.set_register_b(0b0110);
+        manifest.input = 0b0001;
+        manifest.inputs = vec![(12, 0b0011)];
+        manifest.max_cycles = Some(100);
+
+        let text = manifest.to_string();
+        assert!(text.contains("mode extended ports=2 interrupt=2:0xc shadow\n"));
+        assert!(text.contains("outputs pulsed\ninputs registered\ndebounce 3\nrom 31 01 e1 b0\n"));
+        assert!(text.contains("register pc=0x0 a=0b0000 b=0b0110 c=0\n"));
+        assert!(!text.contains("flags"));
+        assert_eq!(text.parse::<ReplayManifest>().unwrap(), manifest);
+
+        let profile = MachineProfile {
+            halt_on_self_jump: false,
+            ..MachineProfile::default()
+        };
+        let manifest = ReplayManifest::new(vec![0xf0], profile);
+        let text = manifest.to_string();
+        assert!(text.contains("self-jump continue\n"));
+        assert_eq!(text.parse::<ReplayManifest>().unwrap(), manifest);
+    }
+
+    #[test]
+    fn test_flag_model() {
+        let profile = MachineProfile {
+            flag_model: FlagModel::JumpsKeepCarry,
+            ..MachineProfile::default()
+        };
+        let manifest = ReplayManifest::new(vec![0xf0], profile);
+        let text = manifest.to_string();
+        assert!(text.contains("flags jumps-keep-carry\n"));
+        assert_eq!(text.parse::<ReplayManifest>().unwrap(), manifest);
+        assert!("version 1\nflags sticky".parse::<ReplayManifest>().is_err());
+
+        let profile = MachineProfile {
+            fetch_beyond_rom: FetchBeyondRom::Wrap,
+            ..MachineProfile::default()
+        };
+        let manifest = ReplayManifest::new(vec![0x01], profile);
+        let text = manifest.to_string();
+        assert!(text.contains("beyond-rom wrap\n"));
+        assert_eq!(text.parse::<ReplayManifest>().unwrap(), manifest);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!("rom 31".parse::<ReplayManifest>().is_err());
+        let err = "version 1\nrom 3g".parse::<ReplayManifest>().unwrap_err();
+        assert_eq!(err.to_string(), "manifest line 2: rom must be hex bytes");
+        assert!("version 2".parse::<ReplayManifest>().is_err());
+        assert!("version 1\nprofile td5".parse::<ReplayManifest>().is_err());
+        assert!("version 1\noutputs toggled"
+            .parse::<ReplayManifest>()
+            .is_err());
+        let err = "version 1\ndebounce -1"
+            .parse::<ReplayManifest>()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "manifest line 2: invalid cycle count -1");
+    }
+}
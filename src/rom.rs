@@ -1,17 +1,261 @@
+use crate::debug_info::{DebugInfo, Region};
+use crate::error::EmulatorErr;
+use crate::instruction::Instruction;
+
+// .td4rom の先頭に置く識別子とバージョン
+const CONTAINER_MAGIC: &[u8; 4] = b"TD4R";
+const CONTAINER_VERSION: u8 = 1;
+
+#[derive(Debug)]
 pub struct Rom {
-    pub memory_array: Vec<u8>,
+    memory_array: Vec<u8>,
+    // 読み込んだときにデコードしておいた命令。書き換えたアドレスはデコードし直す
+    decoded: Vec<Option<Instruction>>,
 }
 
 impl Rom {
     pub fn new(memory_array: Vec<u8>) -> Self {
-        Self { memory_array }
+        let decoded = memory_array
+            .iter()
+            .map(|data| Instruction::decode(*data))
+            .collect();
+        Self {
+            memory_array,
+            decoded,
+        }
     }
 
     pub fn read(&self, pc: u8) -> u8 {
         self.memory_array[pc as usize]
     }
 
-    pub fn size(&self) -> u8 {
-        self.memory_array.len() as u8
+    pub fn write(&mut self, pc: u8, data: u8) {
+        self.memory_array[pc as usize] = data;
+        self.decoded[pc as usize] = Instruction::decode(data);
+    }
+
+    // 256バイトのROMもあるのでu8では表せない
+    pub fn size(&self) -> usize {
+        self.memory_array.len()
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.memory_array
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.memory_array
+    }
+
+    // 各アドレスのバイトを命令として読んだもの (データのバイトも命令として読む)
+    pub fn decoded(&self) -> &[Option<Instruction>] {
+        &self.decoded
+    }
+}
+
+// .td4rom にプログラムと一緒に保存する情報
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct RomMetadata {
+    // 作ったときのマシンプロファイルの名前
+    pub profile: String,
+    pub name: Option<String>,
+    pub debug_info: Option<DebugInfo>,
+}
+
+impl Rom {
+    // 識別子, バージョン, プロファイル名, 名前, プログラム, デバッグ情報, CRC-32 の順に並べる
+    // 文字列とデバッグ情報は長さを前に置き、名前とデバッグ情報がなければ長さを0にする
+    pub fn save_container(&self, metadata: &RomMetadata) -> Vec<u8> {
+        let mut bytes = CONTAINER_MAGIC.to_vec();
+        bytes.push(CONTAINER_VERSION);
+        push_string(&mut bytes, &metadata.profile);
+        push_string(&mut bytes, metadata.name.as_deref().unwrap_or(""));
+        push_block(&mut bytes, &self.memory_array);
+
+        let regions: Vec<u8> = match &metadata.debug_info {
+            Some(info) => (0..info.len())
+                .map(|address| match info.region(address as u8) {
+                    Some(Region::Data) => 1,
+                    _ => 0,
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        push_block(&mut bytes, &regions);
+
+        let crc = crc32(&bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    // 壊れたり途中で切れたりしたファイルを生のバイト列として実行しないよう、全体のCRCを確かめる
+    pub fn load_container(bytes: &[u8]) -> Result<(Rom, RomMetadata), EmulatorErr> {
+        if bytes.len() < 5 || &bytes[..4] != CONTAINER_MAGIC {
+            return Err(EmulatorErr::new("Not a .td4rom image"));
+        }
+        if bytes[4] != CONTAINER_VERSION {
+            return Err(EmulatorErr::new(&format!(
+                "Unsupported .td4rom version: {}",
+                bytes[4]
+            )));
+        }
+        if bytes.len() < 9 {
+            return Err(EmulatorErr::new(".td4rom image is truncated"));
+        }
+        let (body, crc) = bytes.split_at(bytes.len() - 4);
+        if crc32(body).to_le_bytes() != crc {
+            return Err(EmulatorErr::new(
+                ".td4rom checksum mismatch: the image is truncated or corrupted",
+            ));
+        }
+
+        let mut pos = 5;
+        let profile = read_string(body, &mut pos)?;
+        let name = read_string(body, &mut pos)?;
+        let program = read_block(body, &mut pos)?.to_vec();
+        let regions = read_block(body, &mut pos)?;
+        if pos != body.len() {
+            return Err(EmulatorErr::new(".td4rom image has trailing bytes"));
+        }
+
+        let debug_info = if regions.is_empty() {
+            None
+        } else {
+            let mut info = DebugInfo::new();
+            for region in regions {
+                info.push(if *region == 1 {
+                    Region::Data
+                } else {
+                    Region::Code
+                });
+            }
+            Some(info)
+        };
+        let metadata = RomMetadata {
+            profile,
+            name: if name.is_empty() { None } else { Some(name) },
+            debug_info,
+        };
+        Ok((Rom::new(program), metadata))
+    }
+}
+
+fn push_string(bytes: &mut Vec<u8>, text: &str) {
+    // 長さは1バイトなので255バイトで切る
+    let text = &text.as_bytes()[..text.len().min(0xff)];
+    bytes.push(text.len() as u8);
+    bytes.extend_from_slice(text);
+}
+
+fn read_string(bytes: &[u8], pos: &mut usize) -> Result<String, EmulatorErr> {
+    let len = *bytes
+        .get(*pos)
+        .ok_or_else(|| EmulatorErr::new(".td4rom image is truncated"))? as usize;
+    *pos += 1;
+    let text = bytes
+        .get(*pos..*pos + len)
+        .ok_or_else(|| EmulatorErr::new(".td4rom image is truncated"))?;
+    *pos += len;
+    String::from_utf8(text.to_vec()).map_err(|_| EmulatorErr::new("Invalid string in .td4rom"))
+}
+
+// 長さ (2バイト, リトルエンディアン) の後に中身を置く
+fn push_block(bytes: &mut Vec<u8>, block: &[u8]) {
+    bytes.extend_from_slice(&(block.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(block);
+}
+
+fn read_block<'a>(bytes: &'a [u8], pos: &mut usize) -> Result<&'a [u8], EmulatorErr> {
+    let len = bytes
+        .get(*pos..*pos + 2)
+        .ok_or_else(|| EmulatorErr::new(".td4rom image is truncated"))?;
+    let len = u16::from_le_bytes([len[0], len[1]]) as usize;
+    *pos += 2;
+    let block = bytes
+        .get(*pos..*pos + len)
+        .ok_or_else(|| EmulatorErr::new(".td4rom image is truncated"))?;
+    *pos += len;
+    Ok(block)
+}
+
+// zipやPNGと同じCRC-32 (多項式 0xEDB88320)
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod rom_tests {
+    use crate::compiler::assemble_with_debug_info;
+    use crate::instruction::{Instruction, Reg};
+    use crate::rom::{crc32, Rom, RomMetadata};
+
+    #[test]
+    fn test_decoded_follows_write() {
+        let mut rom = Rom::new(vec![0b00110001, 0b10010000]);
+        assert_eq!(
+            rom.decoded(),
+            &[
+                Some(Instruction::Mov { reg: Reg::A, im: 1 }),
+                Some(Instruction::OutB { port: 0 })
+            ]
+        );
+
+        rom.write(1, 0b11110000);
+        assert_eq!(rom.decoded()[1], Some(Instruction::Jmp { im: 0 }));
+        assert_eq!(rom.bytes(), &[0b00110001, 0b11110000]);
+    }
+
+    #[test]
+    fn test_container_round_trip() {
+        let (program, debug_info) = assemble_with_debug_info("out B\n.byte 0b10110001").unwrap();
+        let metadata = RomMetadata {
+            profile: "td4-book".to_string(),
+            name: Some("blink".to_string()),
+            debug_info: Some(debug_info),
+        };
+        let image = Rom::new(program.clone()).save_container(&metadata);
+        assert_eq!(&image[..5], b"TD4R\x01");
+
+        let (rom, loaded) = Rom::load_container(&image).unwrap();
+        assert_eq!(rom.bytes(), &program[..]);
+        assert_eq!(loaded, metadata);
+
+        let image = Rom::new(program).save_container(&RomMetadata::default());
+        assert_eq!(
+            Rom::load_container(&image).unwrap().1,
+            RomMetadata::default()
+        );
+    }
+
+    #[test]
+    fn test_container_errors() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let image = Rom::new(vec![0b00110001, 0b10010000]).save_container(&RomMetadata::default());
+        let error = |bytes: &[u8]| match Rom::load_container(bytes) {
+            Ok(_) => panic!("loaded a broken image"),
+            Err(err) => err.to_string(),
+        };
+        assert_eq!(error(&[0b00110001, 0b10010000]), "Not a .td4rom image");
+        assert!(error(&image[..image.len() - 1]).contains("checksum mismatch"));
+
+        let mut corrupted = image.clone();
+        corrupted[9] ^= 0x01;
+        assert!(error(&corrupted).contains("checksum mismatch"));
+
+        let mut future = image;
+        future[4] = 2;
+        assert_eq!(error(&future), "Unsupported .td4rom version: 2");
     }
 }
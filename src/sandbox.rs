@@ -0,0 +1,328 @@
+use crate::emulator::CpuEmulator;
+use crate::port::Ports;
+use crate::profile::MachineProfile;
+use crate::register::Register;
+use crate::rom::Rom;
+use crate::tracer::TraceRecord;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+
+// 信頼できないROMを実行するときの上限 (採点やWebサービス向け)
+#[derive(Debug, Clone)]
+pub struct ExecConfig {
+    pub profile: MachineProfile,
+    pub input: u8,
+    // 実行を始めるときのレジスタ。実機のリセット直後は全て0
+    pub register: Register,
+    // 停止するまでに使ってよいサイクル数
+    pub max_cycles: usize,
+    pub max_wall_time: Duration,
+    // 記録するトレースの最大件数。None ならトレースを取らない
+    pub max_trace: Option<usize>,
+}
+
+impl Default for ExecConfig {
+    fn default() -> Self {
+        ExecConfig {
+            profile: MachineProfile::default(),
+            input: 0,
+            register: Register::new(),
+            max_cycles: 100_000,
+            max_wall_time: Duration::from_secs(1),
+            max_trace: None,
+        }
+    }
+}
+
+// 上限に達したか、実行できなかった理由
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExecError {
+    // プロファイルや入力が実行できる値ではない
+    InvalidConfig(String),
+    RomTooLarge {
+        size: usize,
+        limit: usize,
+    },
+    CycleLimit(usize),
+    WallTime(Duration),
+    TraceLimit(usize),
+    // 未定義のopcodeやスタックのあふれなど、プログラムが原因のエラー
+    Fault {
+        cycle: usize,
+        pc: u8,
+        message: String,
+    },
+    // エミュレータや周辺装置のバグ。本来は起きない
+    Panic(String),
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecError::InvalidConfig(message) => write!(f, "invalid config: {}", message),
+            ExecError::RomTooLarge { size, limit } => write!(
+                f,
+                "Maximum memory size is {} but the program is {} bytes",
+                limit, size
+            ),
+            ExecError::CycleLimit(cycles) => write!(f, "didn't halt within {} cycles", cycles),
+            ExecError::WallTime(time) => write!(f, "didn't halt within {:?}", time),
+            ExecError::TraceLimit(records) => {
+                write!(f, "trace exceeded {} records", records)
+            }
+            ExecError::Fault { cycle, pc, message } => {
+                write!(f, "cycle {} at 0x{:x}: {}", cycle, pc, message)
+            }
+            ExecError::Panic(message) => write!(f, "panicked: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ExecError {}
+
+// 停止するまで実行した結果
+#[derive(Debug, PartialEq, Clone)]
+pub struct ExecRun {
+    pub register: Register,
+    // OUT命令で書き込まれた (サイクル, 値)
+    pub outputs: Vec<(usize, u8)>,
+    pub cycles: usize,
+    pub instructions: usize,
+    pub trace: Vec<TraceRecord>,
+}
+
+// ROMを停止するまで実行する。上限を超えたときもpanicせずにエラーを返す
+pub fn execute(rom: &[u8], config: &ExecConfig) -> Result<ExecRun, ExecError> {
+    execute_watched(rom, config, &mut |_, _| false)
+}
+
+// execute と同じだが、命令を実行するたびに watch に (サイクル, 出力ポート0に書いた値) を渡す
+// watch が true を返したら停止を待たずにそこまでの結果を返す
+pub fn execute_watched(
+    rom: &[u8],
+    config: &ExecConfig,
+    watch: &mut dyn FnMut(usize, Option<u8>) -> bool,
+) -> Result<ExecRun, ExecError> {
+    config
+        .profile
+        .validate()
+        .map_err(|err| ExecError::InvalidConfig(err.to_string()))?;
+    if config.input > config.profile.register_mask() {
+        return Err(ExecError::InvalidConfig(format!(
+            "input {} doesn't fit in {} bits",
+            config.input, config.profile.register_bits
+        )));
+    }
+    if rom.len() > config.profile.rom_size {
+        return Err(ExecError::RomTooLarge {
+            size: rom.len(),
+            limit: config.profile.rom_size,
+        });
+    }
+
+    // 監査で見落としたpanicもここで止める
+    panic::catch_unwind(AssertUnwindSafe(|| run(rom, config, watch))).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(ExecError::Panic(message))
+    })
+}
+
+fn run(
+    rom: &[u8],
+    config: &ExecConfig,
+    watch: &mut dyn FnMut(usize, Option<u8>) -> bool,
+) -> Result<ExecRun, ExecError> {
+    let mut emulator = CpuEmulator::with_profile(
+        config.register.clone(),
+        Ports::new(config.input, 0),
+        Rom::new(rom.to_vec()),
+        config.profile.clone(),
+    );
+    emulator.set_quiet(true);
+
+    let start = Instant::now();
+    let mut trace = Vec::new();
+    while !emulator.does_halt() {
+        if emulator.cycles() >= config.max_cycles {
+            return Err(ExecError::CycleLimit(config.max_cycles));
+        }
+        if start.elapsed() >= config.max_wall_time {
+            return Err(ExecError::WallTime(config.max_wall_time));
+        }
+        let cycle = emulator.cycles();
+        let pc = emulator.register().pc();
+        emulator.step().map_err(|err| ExecError::Fault {
+            cycle,
+            pc,
+            message: err.to_string(),
+        })?;
+
+        if let Some(max_trace) = config.max_trace {
+            if trace.len() >= max_trace {
+                return Err(ExecError::TraceLimit(max_trace));
+            }
+            trace.push(TraceRecord {
+                cycle,
+                pc,
+                instruction: rom.get(pc as usize).copied().unwrap_or(0),
+                register: emulator.register(),
+                output: emulator.output(),
+                flag_model: Some(config.profile.flag_model),
+            });
+        }
+
+        let output = emulator
+            .last_output_writes()
+            .into_iter()
+            .rev()
+            .find(|(port, _)| *port == 0)
+            .map(|(_, output)| output);
+        if watch(cycle, output) {
+            break;
+        }
+    }
+
+    let stats = emulator.stats();
+    Ok(ExecRun {
+        register: emulator.register(),
+        outputs: emulator.output_history(),
+        cycles: stats.cycles,
+        instructions: stats.instructions,
+        trace,
+    })
+}
+
+#[cfg(test)]
+mod sandbox_tests {
+    use crate::profile::MachineProfile;
+    use crate::sandbox::{execute, execute_watched, ExecConfig, ExecError};
+    use std::time::Duration;
+
+    // out 0011, jmp 0001 (停止)
+    const HALTS: [u8; 2] = [0b10110011, 0b11110001];
+    // jmp 0001, jmp 0000 (止まらない)
+    const LOOPS: [u8; 2] = [0b11110001, 0b11110000];
+
+    #[test]
+    fn test_execute() {
+        let config = ExecConfig {
+            max_trace: Some(1),
+            ..ExecConfig::default()
+        };
+        let run = execute(&HALTS, &config).unwrap();
+        assert_eq!(run.outputs, vec![(0, 0b0011)]);
+        assert_eq!((run.cycles, run.instructions), (1, 1));
+        assert_eq!(run.trace.len(), 1);
+        assert_eq!(run.register.pc(), 1);
+    }
+
+    #[test]
+    fn test_watch() {
+        let mut seen = Vec::new();
+        let run = execute_watched(&LOOPS, &ExecConfig::default(), &mut |cycle, output| {
+            seen.push((cycle, output));
+            cycle == 2
+        })
+        .unwrap();
+        assert_eq!(seen, vec![(0, None), (1, None), (2, None)]);
+        assert_eq!(run.cycles, 3);
+
+        let mut seen = Vec::new();
+        execute_watched(&HALTS, &ExecConfig::default(), &mut |cycle, output| {
+            seen.push((cycle, output));
+            false
+        })
+        .unwrap();
+        assert_eq!(seen, vec![(0, Some(0b0011))]);
+    }
+
+    #[test]
+    fn test_limits() {
+        let config = ExecConfig {
+            max_cycles: 50,
+            ..ExecConfig::default()
+        };
+        assert_eq!(execute(&LOOPS, &config), Err(ExecError::CycleLimit(50)));
+
+        let config = ExecConfig {
+            max_cycles: usize::MAX,
+            max_wall_time: Duration::ZERO,
+            ..ExecConfig::default()
+        };
+        assert_eq!(
+            execute(&LOOPS, &config),
+            Err(ExecError::WallTime(Duration::ZERO))
+        );
+
+        let config = ExecConfig {
+            max_trace: Some(10),
+            ..ExecConfig::default()
+        };
+        assert_eq!(execute(&LOOPS, &config), Err(ExecError::TraceLimit(10)));
+    }
+
+    #[test]
+    fn test_invalid_programs() {
+        let config = ExecConfig::default();
+        assert_eq!(
+            execute(&[0; 17], &config),
+            Err(ExecError::RomTooLarge {
+                size: 17,
+                limit: 16
+            })
+        );
+        // 標準モードでは拡張命令 (call) は実行できない
+        let err = execute(&[0b00110001, 0b10000001], &config).unwrap_err();
+        assert!(matches!(
+            err,
+            ExecError::Fault {
+                cycle: 1,
+                pc: 1,
+                ..
+            }
+        ));
+        assert_eq!(
+            execute(
+                &HALTS,
+                &ExecConfig {
+                    input: 16,
+                    ..ExecConfig::default()
+                }
+            ),
+            Err(ExecError::InvalidConfig(
+                "input 16 doesn't fit in 4 bits".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_no_panic_at_the_end_of_an_8bit_rom() {
+        let config = ExecConfig {
+            profile: MachineProfile {
+                rom_size: 256,
+                pc_bits: 8,
+                ..MachineProfile::td4_extended()
+            },
+            max_cycles: 1000,
+            ..ExecConfig::default()
+        };
+        // 0xff の add A 0001 のあとPCは0に戻る
+        let mut rom = vec![0b00000001; 256];
+        assert_eq!(execute(&rom, &config), Err(ExecError::CycleLimit(1000)));
+
+        // 0xff の call は次のアドレスとして0を積む
+        rom[0xff] = 0b10000000;
+        let config = ExecConfig {
+            max_cycles: 10_000,
+            ..config
+        };
+        let err = execute(&rom, &config).unwrap_err();
+        assert!(matches!(err, ExecError::Fault { pc: 0xff, .. }));
+        assert!(err.to_string().contains("Stack overflow"));
+    }
+}
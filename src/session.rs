@@ -0,0 +1,185 @@
+use crate::debugger::Snapshot;
+use crate::error::EmulatorErr;
+use std::fmt;
+use std::str::FromStr;
+
+const VERSION: u8 = 1;
+
+// デバッガの作業内容 (save-session / load-session)
+// プログラムを直して読み込み直してもブレークポイントなどを作り直さなくて済むようにする
+#[derive(Debug, PartialEq, Clone)]
+pub struct DebugSession {
+    // 読み込んだプログラムのファイル。--example やROMイメージなら None
+    pub program: Option<String>,
+    pub rom: Vec<u8>,
+    // 保存したときのマシンの状態 (ROMは rom)
+    pub state: Snapshot,
+    // break 0x3, break 0x5 if A == 3, break on out == 0b1111 のようにデバッガのコマンドで持つ
+    pub breakpoints: Vec<String>,
+    pub snapshots: Vec<(String, Snapshot)>,
+}
+
+// 1行に1項目のテキスト。# 以降はコメント
+//
+//     version 1
+//     program example/adder.sasm
+//     rom 31 01 e1 b0
+//     state pc=0x1 a=0b0001 b=0b0000 c=0 in=0b0000 out=0b0000 stack= shadow=0x0 cycles=1 rom=31,01,e1,b0
+//     break 0x3 if A == 3
+//     snapshot before pc=0x0 ... cycles=0 rom=31,01,e1,b0
+//
+// state と snapshot は Snapshot の1行の形。state の rom は読むときに rom の行で置き換える
+impl fmt::Display for DebugSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# td4emu debugger session")?;
+        writeln!(f, "version {}", VERSION)?;
+        if let Some(program) = &self.program {
+            writeln!(f, "program {}", program)?;
+        }
+        writeln!(f, "rom {}", hex(&self.rom, " "))?;
+        writeln!(f, "state {}", self.state)?;
+        for breakpoint in &self.breakpoints {
+            writeln!(f, "{}", breakpoint)?;
+        }
+        for (name, snapshot) in &self.snapshots {
+            writeln!(f, "snapshot {} {}", name, snapshot)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DebugSession {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut session = DebugSession {
+            program: None,
+            rom: Vec::new(),
+            state: "".parse()?,
+            breakpoints: Vec::new(),
+            snapshots: Vec::new(),
+        };
+        let mut has_version = false;
+
+        for (number, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: &str| {
+                EmulatorErr::new(&format!("session line {}: {}", number + 1, message))
+            };
+            let (key, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let values: Vec<&str> = rest.split_whitespace().collect();
+
+            match (key, values.as_slice()) {
+                // 古い版を読むときは、版ごとに違う項目をここで今の形に直す
+                ("version", [version]) => {
+                    match version.parse::<u8>() {
+                        Ok(version) if (1..=VERSION).contains(&version) => {}
+                        _ => {
+                            return Err(error(&format!(
+                                "unsupported version {} (this td4emu reads up to version {})",
+                                version, VERSION
+                            )))
+                        }
+                    }
+                    has_version = true;
+                }
+                // ファイル名には空白が入ることがある
+                ("program", [_, ..]) => session.program = Some(rest.trim().to_string()),
+                ("rom", bytes) => {
+                    session.rom = parse_hex(bytes).map_err(|_| error("rom must be hex bytes"))?
+                }
+                ("state", _) => {
+                    session.state = rest
+                        .parse()
+                        .map_err(|err: EmulatorErr| error(&err.to_string()))?
+                }
+                ("break", [_, ..]) => session.breakpoints.push(line.to_string()),
+                ("snapshot", [name, fields @ ..]) => {
+                    let snapshot = fields
+                        .join(" ")
+                        .parse()
+                        .map_err(|err: EmulatorErr| error(&err.to_string()))?;
+                    session.snapshots.push((name.to_string(), snapshot));
+                }
+                _ => return Err(error(&format!("can't read {}", line))),
+            }
+        }
+
+        if !has_version {
+            return Err(EmulatorErr::new("Not a debugger session (no version line)"));
+        }
+        session.state.rom = session.rom.clone();
+        Ok(session)
+    }
+}
+
+fn hex(bytes: &[u8], separator: &str) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+fn parse_hex(bytes: &[&str]) -> Result<Vec<u8>, std::num::ParseIntError> {
+    bytes
+        .iter()
+        .filter(|byte| !byte.is_empty())
+        .map(|byte| u8::from_str_radix(byte, 16))
+        .collect()
+}
+
+#[cfg(test)]
+mod session_tests {
+    use crate::debugger::Snapshot;
+    use crate::register::Register;
+    use crate::session::DebugSession;
+
+    #[test]
+    fn test_round_trip() {
+        let mut register = Register::new();
+        register.set_pc(3);
+        register.set_register_a(0b0101);
+        let state = Snapshot {
+            register,
+            input: 0b0011,
+            output: 0b1000,
+            rom: vec![0x31, 0x01, 0xe1, 0xb0],
+            stack: vec![2, 7],
+            shadow_pc: 1,
+            cycles: 42,
+        };
+        let session = DebugSession {
+            program: Some("my programs/adder.sasm".to_string()),
+            rom: state.rom.clone(),
+            state: state.clone(),
+            breakpoints: vec![
+                "break 0x3 if A == 3".to_string(),
+                "break on carry".to_string(),
+            ],
+            snapshots: vec![("start".to_string(), Snapshot { cycles: 0, ..state })],
+        };
+        let text = session.to_string();
+        assert!(text.contains(
+            "state pc=0x3 a=0b0101 b=0b0000 c=0 in=0b0011 out=0b1000 stack=02,07 shadow=0x1 cycles=42 rom=31,01,e1,b0\n"
+        ));
+        assert_eq!(text.parse::<DebugSession>().unwrap(), session);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!("rom 31".parse::<DebugSession>().is_err());
+        let err = "version 1\nstate pc=0x1 x=2"
+            .parse::<DebugSession>()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "session line 2: unknown field x");
+        let err = "version 2".parse::<DebugSession>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "session line 1: unsupported version 2 (this td4emu reads up to version 1)"
+        );
+    }
+}
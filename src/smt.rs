@@ -0,0 +1,459 @@
+use crate::error::EmulatorErr;
+use crate::isa::{semantics, CarryEffect, Location, PcEffect, Precondition, Transfer};
+use crate::op::{LowBits, Opcode};
+use crate::profile::{MachineProfile, UndefinedOpcodePolicy};
+
+// ROMの遷移関係を SMT-LIB (ビットベクタ) で書き出す。Z3 などのソルバで性質を検証するため
+// 命令の意味は isa::apply と同じく isa.rs の表から作る
+//
+// 状態は pc (8bit), a, b (レジスタ幅), c (Bool), 出力ポート out0.. (4bit以上)
+// 入力ポート in0.. は実行中に変わらない値として trans の引数にする
+// 停止したら (ROMの外に出るか自分へのジャンプ) 状態は変わらない
+#[derive(Debug, PartialEq, Clone)]
+pub struct SmtConfig {
+    pub profile: MachineProfile,
+    // 展開するステップ数。0なら init と trans の定義だけを書く
+    pub steps: usize,
+}
+
+impl Default for SmtConfig {
+    fn default() -> Self {
+        SmtConfig {
+            profile: MachineProfile::default(),
+            steps: 16,
+        }
+    }
+}
+
+// 桁あふれを見るための計算の幅
+const WIDE: u8 = 9;
+
+// 1命令の書き出しに使う幅とポート数
+struct Widths {
+    register: u8,
+    output: u8,
+    ports: usize,
+}
+
+impl Widths {
+    fn new(profile: &MachineProfile) -> Self {
+        Widths {
+            register: profile.register_bits,
+            output: profile.register_bits.max(4),
+            ports: profile.mode.port_count(),
+        }
+    }
+
+    // (名前, 型) の並び。next なら _next を付ける
+    fn state(&self, suffix: &str) -> Vec<(String, String)> {
+        let mut state = vec![
+            (format!("pc{}", suffix), sort(8)),
+            (format!("a{}", suffix), sort(self.register)),
+            (format!("b{}", suffix), sort(self.register)),
+            (format!("c{}", suffix), "Bool".to_string()),
+        ];
+        for port in 0..self.ports {
+            state.push((format!("out{}{}", port, suffix), sort(self.output)));
+        }
+        state
+    }
+
+    fn inputs(&self) -> Vec<(String, String)> {
+        (0..self.ports)
+            .map(|port| (format!("in{}", port), sort(self.register)))
+            .collect()
+    }
+}
+
+fn sort(bits: u8) -> String {
+    format!("(_ BitVec {})", bits)
+}
+
+fn literal(value: u8, bits: u8) -> String {
+    let digits = format!("{:08b}", value);
+    format!("#b{}", &digits[8 - bits as usize..])
+}
+
+fn address(pc: u8) -> String {
+    format!("#x{:02x}", pc)
+}
+
+fn extend(expr: &str, from: u8, to: u8) -> String {
+    match to - from {
+        0 => expr.to_string(),
+        bits => format!("((_ zero_extend {}) {})", bits, expr),
+    }
+}
+
+fn parameters(names: &[(String, String)]) -> String {
+    let names: Vec<String> = names
+        .iter()
+        .map(|(name, sort)| format!("({} {})", name, sort))
+        .collect();
+    names.join(" ")
+}
+
+fn arguments(names: &[(String, String)]) -> String {
+    let names: Vec<&str> = names.iter().map(|(name, _)| name.as_str()).collect();
+    names.join(" ")
+}
+
+// 次の状態の各変数の式
+struct Next {
+    pc: String,
+    a: String,
+    b: String,
+    c: String,
+    outputs: Vec<String>,
+}
+
+impl Next {
+    // 何も変えない
+    fn unchanged(widths: &Widths) -> Self {
+        Next {
+            pc: "pc".to_string(),
+            a: "a".to_string(),
+            b: "b".to_string(),
+            c: "c".to_string(),
+            outputs: (0..widths.ports)
+                .map(|port| format!("out{}", port))
+                .collect(),
+        }
+    }
+
+    fn constraint(&self) -> String {
+        let mut equalities = vec![
+            format!("(= pc_next {})", self.pc),
+            format!("(= a_next {})", self.a),
+            format!("(= b_next {})", self.b),
+            format!("(= c_next {})", self.c),
+        ];
+        for (port, output) in self.outputs.iter().enumerate() {
+            equalities.push(format!("(= out{}_next {})", port, output));
+        }
+        format!("(and {})", equalities.join(" "))
+    }
+}
+
+// アドレス pc の1命令の遷移。isa::apply と同じ順に表を読む
+fn transition(
+    profile: &MachineProfile,
+    widths: &Widths,
+    pc: u8,
+    data: u8,
+) -> Result<Next, EmulatorErr> {
+    let mut next = Next::unchanged(widths);
+    let following = pc.wrapping_add(1);
+    let unsupported = |what: &str| {
+        EmulatorErr::new(&format!(
+            "smt export doesn't support {} (at 0x{:x})",
+            what, pc
+        ))
+    };
+    let decoded = Opcode::decode(data).and_then(|(opcode, im)| {
+        semantics(opcode)
+            .filter(|semantics| {
+                profile.mode.is_extended()
+                    || !semantics.requires.contains(&Precondition::ExtendedMode)
+            })
+            .map(|semantics| (semantics, im))
+    });
+    let (semantics, im) = match decoded {
+        Some(decoded) => decoded,
+        None => {
+            return match profile.undefined_opcode_policy {
+                UndefinedOpcodePolicy::Error => Err(EmulatorErr::new(&format!(
+                    "No match for opcode 0x{:02x} at 0x{:x}",
+                    data, pc
+                ))),
+                UndefinedOpcodePolicy::Nop => {
+                    next.pc = address(following);
+                    Ok(next)
+                }
+            };
+        }
+    };
+
+    let mut port = 0;
+    if semantics.opcode.low_bits() == LowBits::Port {
+        port = im as usize;
+    }
+    for precondition in semantics.requires {
+        match precondition {
+            Precondition::PortExists if port >= widths.ports => {
+                if profile.mode.is_extended() {
+                    return Err(EmulatorErr::new(&format!(
+                        "port {} doesn't exist (at 0x{:x})",
+                        port, pc
+                    )));
+                }
+                port = 0;
+            }
+            Precondition::StackNotFull | Precondition::StackNotEmpty => {
+                return Err(unsupported("the stack"));
+            }
+            _ => (),
+        }
+    }
+
+    // (式, 幅)
+    let read = |location| match location {
+        Location::Immediate => Ok((literal(im, 4), 4)),
+        Location::A => Ok(("a".to_string(), widths.register)),
+        Location::B => Ok(("b".to_string(), widths.register)),
+        Location::Input => Ok((format!("in{}", port), widths.register)),
+        Location::Output | Location::Memory => Err(unsupported("memory")),
+    };
+    // 書き込む場所の幅に合わせる。レジスタへは下位bitだけを残す
+    let fit = |(expr, bits): (String, u8), to: Location| match to {
+        Location::Output => extend(&expr, bits, widths.output),
+        _ if bits > widths.register => format!("((_ extract {} 0) {})", widths.register - 1, expr),
+        _ if bits < widths.register => extend(&expr, bits, widths.register),
+        _ => expr,
+    };
+    let write = |next: &mut Next, to, value: String| {
+        match to {
+            Location::A => next.a = value,
+            Location::B => next.b = value,
+            Location::Output => next.outputs[port] = value,
+            _ => return Err(unsupported("memory")),
+        }
+        Ok(())
+    };
+    let wide = |(expr, bits): (String, u8)| extend(&expr, bits, WIDE);
+
+    let alu_carry = match semantics.transfer {
+        Transfer::None => "false".to_string(),
+        Transfer::Move { to, from } => {
+            let value = fit(read(from)?, to);
+            write(&mut next, to, value)?;
+            "false".to_string()
+        }
+        Transfer::Add { to, from } | Transfer::Sub { to, from } => {
+            let (lhs, rhs) = (wide(read(to)?), wide(read(from)?));
+            let (result, carry) = match semantics.transfer {
+                Transfer::Add { .. } => {
+                    let sum = format!("(bvadd {} {})", lhs, rhs);
+                    let mask = literal(profile.register_mask(), WIDE - 1);
+                    let carry = format!("(bvugt {} #b0{})", sum, &mask[2..]);
+                    (sum, carry)
+                }
+                _ => (
+                    format!("(bvsub {} {})", lhs, rhs),
+                    format!("(bvult {} {})", lhs, rhs),
+                ),
+            };
+            write(&mut next, to, fit((result, WIDE), to))?;
+            carry
+        }
+        Transfer::Compare { lhs, rhs } => {
+            format!("(bvult {} {})", wide(read(lhs)?), wide(read(rhs)?))
+        }
+        Transfer::Exchange { lhs, rhs } => {
+            let (lhs_value, rhs_value) = (fit(read(rhs)?, lhs), fit(read(lhs)?, rhs));
+            write(&mut next, lhs, lhs_value)?;
+            write(&mut next, rhs, rhs_value)?;
+            "false".to_string()
+        }
+    };
+    next.c = match semantics.carry {
+        CarryEffect::Unaffected
+            if profile
+                .flag_model
+                .keeps_carry(semantics.pc != PcEffect::Next) =>
+        {
+            "c".to_string()
+        }
+        CarryEffect::Unaffected => "false".to_string(),
+        CarryEffect::Carry | CarryEffect::Borrow => alu_carry,
+    };
+
+    next.pc = match semantics.pc {
+        PcEffect::Next => address(following),
+        PcEffect::Jump => address(im),
+        PcEffect::JumpIfNoCarry => format!("(ite c {} {})", address(following), address(im)),
+        PcEffect::Call | PcEffect::Return => return Err(unsupported("the stack")),
+    };
+    Ok(next)
+}
+
+// init, halted, trans を定義して、steps ステップ分の状態を宣言して制約を付ける
+pub fn export(rom: &[u8], config: &SmtConfig) -> Result<String, EmulatorErr> {
+    let profile = &config.profile;
+    profile.validate()?;
+    let widths = Widths::new(profile);
+    let state = widths.state("");
+    let inputs = widths.inputs();
+    let mut smt = String::new();
+
+    smt.push_str("; td4emu transition relation\n");
+    smt.push_str(&format!("; profile {}\n", profile.name));
+    smt.push_str("(set-logic QF_BV)\n\n");
+
+    let mut reset = vec![
+        format!("(= pc {})", address(0)),
+        format!("(= a {})", literal(0, widths.register)),
+        format!("(= b {})", literal(0, widths.register)),
+        "(not c)".to_string(),
+    ];
+    for port in 0..widths.ports {
+        reset.push(format!("(= out{} {})", port, literal(0, widths.output)));
+    }
+    smt.push_str(&format!(
+        "(define-fun init ({}) Bool\n  (and {}))\n\n",
+        parameters(&state),
+        reset.join(" ")
+    ));
+
+    let mut halts = Vec::new();
+    if rom.len() <= u8::MAX as usize {
+        halts.push(format!("(bvuge pc {})", address(rom.len() as u8)));
+    }
+    for (pc, data) in rom.iter().enumerate() {
+        if *data == Opcode::Jmp.encode(pc as u8) {
+            halts.push(format!("(= pc {})", address(pc as u8)));
+        }
+    }
+    smt.push_str(&format!(
+        "(define-fun halted ((pc {})) Bool\n  (or false {}))\n\n",
+        sort(8),
+        halts.join(" ")
+    ));
+
+    let mut trans_parameters = inputs.clone();
+    trans_parameters.extend(state.clone());
+    trans_parameters.extend(widths.state("_next"));
+    smt.push_str(&format!(
+        "(define-fun trans ({}) Bool\n",
+        parameters(&trans_parameters)
+    ));
+    let mut cases = 0;
+    for (pc, data) in rom.iter().enumerate() {
+        let pc = pc as u8;
+        if *data == Opcode::Jmp.encode(pc) {
+            continue;
+        }
+        let next = transition(profile, &widths, pc, *data)?;
+        smt.push_str(&format!(
+            "  (ite (= pc {}) {}\n",
+            address(pc),
+            next.constraint()
+        ));
+        cases += 1;
+    }
+    smt.push_str(&format!(
+        "  {}{})\n",
+        Next::unchanged(&widths).constraint(),
+        ")".repeat(cases)
+    ));
+
+    if config.steps == 0 {
+        return Ok(smt);
+    }
+    smt.push('\n');
+    for (name, sort) in &inputs {
+        smt.push_str(&format!("(declare-const {} {})\n", name, sort));
+    }
+    let step_state = |step: usize| widths.state(&format!("_{}", step));
+    for step in 0..=config.steps {
+        for (name, sort) in step_state(step) {
+            smt.push_str(&format!("(declare-const {} {})\n", name, sort));
+        }
+    }
+    smt.push_str(&format!("(assert (init {}))\n", arguments(&step_state(0))));
+    for step in 0..config.steps {
+        smt.push_str(&format!(
+            "(assert (trans {} {} {}))\n",
+            arguments(&inputs),
+            arguments(&step_state(step)),
+            arguments(&step_state(step + 1))
+        ));
+    }
+
+    let example: Vec<String> = (0..=config.steps)
+        .map(|step| format!("(= out0_{} {})", step, literal(0xf, widths.output)))
+        .collect();
+    smt.push_str(&format!(
+        "\n; add a property and check it, for example whether out0 can become {} within {} steps:\n",
+        literal(0xf, widths.output),
+        config.steps
+    ));
+    smt.push_str(&format!("; (assert (or {}))\n", example.join(" ")));
+    smt.push_str("; (check-sat)\n");
+    Ok(smt)
+}
+
+#[cfg(test)]
+mod smt_tests {
+    use crate::compiler::assemble;
+    use crate::profile::MachineProfile;
+    use crate::smt::{export, SmtConfig};
+
+    fn balanced(text: &str) -> bool {
+        let mut depth = 0i32;
+        for line in text.lines().filter(|line| !line.starts_with(';')) {
+            for c in line.chars() {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => (),
+                }
+                if depth < 0 {
+                    return false;
+                }
+            }
+        }
+        depth == 0
+    }
+
+    #[test]
+    fn test_export() {
+        let rom = assemble("in A\nadd A 0011\njnc 0100\nmov A 1111\nmov B A\nout B").unwrap();
+        let smt = export(&rom, &SmtConfig::default()).unwrap();
+        assert!(balanced(&smt));
+        assert!(smt.contains(
+            "(define-fun halted ((pc (_ BitVec 8))) Bool\n  (or false (bvuge pc #x06)))"
+        ));
+        assert!(smt.contains(
+            "  (ite (= pc #x00) (and (= pc_next #x01) (= a_next in0) (= b_next b) (= c_next false) (= out0_next out0))\n"
+        ));
+        assert!(smt.contains("(= pc_next (ite c #x03 #x04))"));
+        assert!(smt.contains(
+            "(= a_next ((_ extract 3 0) (bvadd ((_ zero_extend 5) a) ((_ zero_extend 5) #b0011))))"
+        ));
+        assert!(smt.contains("(= c_next (bvugt (bvadd ((_ zero_extend 5) a) ((_ zero_extend 5) #b0011)) #b000001111))"));
+        assert!(smt.contains("(= out0_next b)"));
+        assert!(smt.contains("(assert (init pc_0 a_0 b_0 c_0 out0_0))\n"));
+        assert!(smt.contains(
+            "(assert (trans in0 pc_15 a_15 b_15 c_15 out0_15 pc_16 a_16 b_16 c_16 out0_16))\n"
+        ));
+
+        let config = SmtConfig {
+            steps: 0,
+            ..SmtConfig::default()
+        };
+        let smt = export(&rom, &config).unwrap();
+        assert!(balanced(&smt));
+        assert!(!smt.contains("declare-const"));
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let config = SmtConfig {
+            profile: MachineProfile::td4_extended(),
+            ..SmtConfig::default()
+        };
+        let rom = assemble("call 0011").unwrap();
+        let err = export(&rom, &config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "smt export doesn't support the stack (at 0x0)"
+        );
+        let rom = assemble("swap A B\nsub A 0001").unwrap();
+        let smt = export(&rom, &config).unwrap();
+        assert!(smt.contains("(= a_next b) (= b_next a)"));
+        assert!(smt.contains("(= c_next (bvult ((_ zero_extend 5) a) ((_ zero_extend 5) #b0001)))"));
+        // 標準モードでは未定義のopcode
+        assert!(export(&rom, &SmtConfig::default()).is_err());
+    }
+}
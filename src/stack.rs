@@ -0,0 +1,74 @@
+use crate::error::EmulatorErr;
+
+// CALL/RET用のハードウェアの戻りアドレススタック (拡張モード)
+// メモリを持たないTD4に合わせて深さは固定で小さい
+pub const DEPTH: usize = 4;
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Stack {
+    entries: Vec<u8>,
+}
+
+impl Stack {
+    pub fn new() -> Self {
+        Stack {
+            entries: Vec::with_capacity(DEPTH),
+        }
+    }
+
+    pub fn push(&mut self, address: u8) -> Result<(), EmulatorErr> {
+        if self.entries.len() >= DEPTH {
+            return Err(EmulatorErr::new(&format!(
+                "Stack overflow: CALL nested more than {} levels",
+                DEPTH
+            )));
+        }
+        self.entries.push(address);
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Result<u8, EmulatorErr> {
+        self.entries
+            .pop()
+            .ok_or_else(|| EmulatorErr::new("Stack underflow: RET without CALL"))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // 古いものから順に並べた戻りアドレス
+    pub fn entries(&self) -> &[u8] {
+        &self.entries
+    }
+}
+
+#[cfg(test)]
+mod stack_tests {
+    use crate::stack::{Stack, DEPTH};
+
+    #[test]
+    fn test_push_pop() {
+        let mut stack = Stack::new();
+        stack.push(1).unwrap();
+        stack.push(2).unwrap();
+        assert_eq!(stack.entries(), &[1, 2]);
+        assert_eq!(stack.pop().unwrap(), 2);
+        assert_eq!(stack.pop().unwrap(), 1);
+        assert!(stack.pop().is_err());
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut stack = Stack::new();
+        for address in 0..DEPTH as u8 {
+            stack.push(address).unwrap();
+        }
+        assert!(stack.push(0).is_err());
+        assert_eq!(stack.len(), DEPTH);
+    }
+}
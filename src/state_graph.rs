@@ -0,0 +1,213 @@
+use crate::disassembler::disassemble;
+use crate::error::EmulatorErr;
+use crate::isa::{apply, ArchState};
+use crate::op::Opcode;
+use crate::profile::MachineProfile;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+// リセットから到達できる全ての状態と、その間の遷移のグラフ
+// 入力ポートはどの値にもなりうるものとして、状態には含めない
+// 出力ポートは次の状態に影響しないので、これも状態には含めない
+#[derive(Debug, PartialEq, Clone)]
+pub struct StateGraph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Node {
+    pub state: ArchState,
+    pub halted: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Edge {
+    pub from: usize,
+    // None なら命令の実行でエラーになった
+    pub to: Option<usize>,
+    pub instruction: String,
+    // この遷移になる入力の値。全ての入力で同じなら空
+    pub inputs: Vec<u8>,
+}
+
+// 状態の数がこれを超えたら諦める
+pub const MAX_STATES: usize = 1 << 16;
+
+type Key = (u8, u8, u8, u8, Vec<u8>, Vec<u8>);
+
+fn key(state: &ArchState) -> Key {
+    let register = &state.register;
+    (
+        register.pc(),
+        register.register_a(),
+        register.register_b(),
+        register.carry_flag(),
+        state.stack.clone(),
+        state.ram.clone(),
+    )
+}
+
+fn does_halt(rom: &[u8], state: &ArchState) -> bool {
+    let pc = state.register.pc();
+    match rom.get(pc as usize) {
+        Some(data) => *data == Opcode::Jmp.encode(pc),
+        None => true,
+    }
+}
+
+pub fn explore(rom: &[u8], profile: &MachineProfile) -> Result<StateGraph, EmulatorErr> {
+    profile.validate()?;
+    let mask = profile.register_mask();
+    let reset = ArchState::new(profile);
+    let mut ids = HashMap::new();
+    ids.insert(key(&reset), 0);
+    let mut nodes = vec![Node {
+        halted: does_halt(rom, &reset),
+        state: reset,
+    }];
+    let mut edges = Vec::new();
+    let mut pending = VecDeque::from([0]);
+
+    while let Some(id) = pending.pop_front() {
+        if nodes[id].halted {
+            continue;
+        }
+        let state = nodes[id].state.clone();
+        let data = rom[state.register.pc() as usize];
+        // 行き先ごとにその遷移になる入力の値を集める
+        let mut targets: BTreeMap<Option<usize>, Vec<u8>> = BTreeMap::new();
+        for input in 0..=mask {
+            let mut state = state.clone();
+            state.inputs[0] = input;
+            let to = match apply(profile, &state, data) {
+                Ok(mut next) => {
+                    next.outputs.iter_mut().for_each(|output| *output = 0);
+                    next.inputs[0] = 0;
+                    let next_id = ids.len();
+                    let to = *ids.entry(key(&next)).or_insert(next_id);
+                    if to == next_id {
+                        if nodes.len() >= MAX_STATES {
+                            return Err(EmulatorErr::new(&format!(
+                                "More than {} reachable states",
+                                MAX_STATES
+                            )));
+                        }
+                        nodes.push(Node {
+                            halted: does_halt(rom, &next),
+                            state: next,
+                        });
+                        pending.push_back(to);
+                    }
+                    Some(to)
+                }
+                Err(_) => None,
+            };
+            targets.entry(to).or_default().push(input);
+        }
+        let split = targets.len() > 1;
+        for (to, inputs) in targets {
+            edges.push(Edge {
+                from: id,
+                to,
+                instruction: disassemble(data),
+                inputs: if split { inputs } else { Vec::new() },
+            });
+        }
+    }
+    Ok(StateGraph { nodes, edges })
+}
+
+impl StateGraph {
+    // Graphviz の dot 形式。停止した状態は二重丸にする
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph states {\n");
+        dot.push_str("  node [shape=ellipse fontname=monospace];\n");
+        for (id, node) in self.nodes.iter().enumerate() {
+            let register = &node.state.register;
+            let mut label = format!(
+                "PC={:x} A={:04b}\\nB={:04b} C={}",
+                register.pc(),
+                register.register_a(),
+                register.register_b(),
+                register.carry_flag()
+            );
+            if !node.state.stack.is_empty() {
+                let stack: Vec<String> = node
+                    .state
+                    .stack
+                    .iter()
+                    .map(|address| format!("{:x}", address))
+                    .collect();
+                label.push_str(&format!("\\nstack {}", stack.join(" ")));
+            }
+            let shape = if node.halted { " peripheries=2" } else { "" };
+            dot.push_str(&format!("  s{} [label=\"{}\"{}];\n", id, label, shape));
+        }
+        if self.edges.iter().any(|edge| edge.to.is_none()) {
+            dot.push_str("  error [shape=box label=\"error\"];\n");
+        }
+        for edge in &self.edges {
+            let to = match edge.to {
+                Some(to) => format!("s{}", to),
+                None => "error".to_string(),
+            };
+            let mut label = edge.instruction.clone();
+            if !edge.inputs.is_empty() {
+                let inputs: Vec<String> = edge
+                    .inputs
+                    .iter()
+                    .map(|input| format!("{:04b}", input))
+                    .collect();
+                label.push_str(&format!("\\nIN={}", inputs.join(",")));
+            }
+            dot.push_str(&format!(
+                "  s{} -> {} [label=\"{}\"];\n",
+                edge.from, to, label
+            ));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod state_graph_tests {
+    use crate::compiler::assemble;
+    use crate::profile::MachineProfile;
+    use crate::state_graph::explore;
+
+    #[test]
+    fn test_explore() {
+        // 0 から 4 ずつ数えて桁あふれで抜けるループ。たどる状態は10個だけ
+        let rom = assemble("add A 0100\njnc 0000\nout 0001").unwrap();
+        let graph = explore(&rom, &MachineProfile::default()).unwrap();
+        assert_eq!(graph.nodes.len(), 10);
+        assert!(graph.nodes.last().unwrap().halted);
+        assert!(graph.edges.iter().all(|edge| edge.inputs.is_empty()));
+
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph states {\n"));
+        assert!(dot.contains("  s0 [label=\"PC=0 A=0000\\nB=0000 C=0\"];\n"));
+        assert!(dot.contains("  s0 -> s1 [label=\"add A 0100\"];\n"));
+        assert!(dot.contains("peripheries=2"));
+    }
+
+    #[test]
+    fn test_inputs() {
+        // in B の行き先は入力ごとに分かれる
+        let rom = assemble("in B\nout B").unwrap();
+        let graph = explore(&rom, &MachineProfile::default()).unwrap();
+        assert_eq!(graph.nodes.len(), 1 + 16 + 16);
+        let edge = &graph.edges[1];
+        assert_eq!(
+            (edge.from, edge.to, edge.inputs.clone()),
+            (0, Some(2), vec![1])
+        );
+        assert!(graph.to_dot().contains("[label=\"in B\\nIN=0001\"]"));
+
+        let profile = MachineProfile::td4_extended();
+        let rom = assemble("ret").unwrap();
+        let graph = explore(&rom, &profile).unwrap();
+        assert!(graph.to_dot().contains("  s0 -> error [label=\"ret\"];\n"));
+    }
+}
@@ -0,0 +1,193 @@
+use crate::debugger::parse_number;
+use crate::error::EmulatorErr;
+use crate::port::InputSource;
+use std::str::FromStr;
+
+// 入力ポート0の波形を書く小さな言語 (--stimulus)。スイッチを押して離す様子を書くのに使う
+//
+//     0..5: 0b0000; 5..9: 0b1111
+//     repeat 4 every 2: toggle bit0   # 9 から2サイクルごとに bit0 を反転する
+//     20..: 0                         # 20 から後はずっと 0
+//
+// 文は ; か改行で区切る。a..b は a から b の手前まで、repeat はその前の文の終わりから続ける
+// 値は次の文まで保つ。値の代わりに toggle / set / clear bitN で今の値の1bitを変えられる
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Stimulus {
+    // 値が変わるサイクルと新しい値。サイクルの順に並ぶ
+    changes: Vec<(usize, u8)>,
+}
+
+impl Stimulus {
+    pub fn changes(&self) -> &[(usize, u8)] {
+        &self.changes
+    }
+
+    // cycle で入力ポートに入っている値。最初の変化より前なら None (ポートの値を変えない)
+    pub fn value_at(&self, cycle: usize) -> Option<u8> {
+        self.changes
+            .iter()
+            .take_while(|(at, _)| *at <= cycle)
+            .last()
+            .map(|(_, value)| *value)
+    }
+
+    fn change(&mut self, cycle: usize, value: u8) {
+        if self.changes.last().map(|(_, last)| *last) != Some(value) {
+            self.changes.push((cycle, value));
+        }
+    }
+}
+
+// 値か、今の値の1bitを変える操作
+fn apply(action: &str, current: u8) -> Result<u8, EmulatorErr> {
+    let words: Vec<&str> = action.split_whitespace().collect();
+    let (operation, bit) = match words.as_slice() {
+        [value] => return parse_number(value),
+        [operation, bit] => (*operation, *bit),
+        _ => return Err(EmulatorErr::new(&format!("can't read {}", action))),
+    };
+    let bit = bit
+        .strip_prefix("bit")
+        .and_then(|bit| bit.parse::<u8>().ok())
+        .filter(|bit| *bit < 8)
+        .ok_or_else(|| EmulatorErr::new(&format!("{} is not bit0 to bit7", bit)))?;
+    match operation {
+        "toggle" => Ok(current ^ (1 << bit)),
+        "set" => Ok(current | (1 << bit)),
+        "clear" => Ok(current & !(1 << bit)),
+        _ => Err(EmulatorErr::new(&format!(
+            "unknown operation {}",
+            operation
+        ))),
+    }
+}
+
+impl FromStr for Stimulus {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut stimulus = Stimulus::default();
+        let mut value = 0;
+        // 前の文が終わったサイクル。None なら最後まで続く文のあと
+        let mut end = Some(0);
+        let statements = s
+            .lines()
+            .flat_map(|line| line.split('#').next().unwrap().split(';'))
+            .map(str::trim)
+            .filter(|statement| !statement.is_empty());
+        for statement in statements {
+            let error =
+                |message: &str| EmulatorErr::new(&format!("stimulus {}: {}", statement, message));
+            let (head, action) = statement
+                .split_once(':')
+                .ok_or_else(|| error("expected `cycles: value`"))?;
+            let start = end.ok_or_else(|| error("comes after a statement that never ends"))?;
+            let number = |text: &str| {
+                text.trim()
+                    .parse::<usize>()
+                    .map_err(|_| error(&format!("{} is not a cycle count", text.trim())))
+            };
+            let head: Vec<&str> = head.split_whitespace().collect();
+            match head.as_slice() {
+                ["repeat", count] | ["repeat", count, "every", _] => {
+                    let every = match head.get(3) {
+                        Some(every) => number(every)?,
+                        None => 1,
+                    };
+                    if every == 0 {
+                        return Err(error("every must be at least 1"));
+                    }
+                    for index in 0..number(count)? {
+                        value = apply(action, value).map_err(|err| error(&err.to_string()))?;
+                        stimulus.change(start + index * every, value);
+                    }
+                    end = Some(start + number(count)? * every);
+                }
+                [range] => {
+                    let (from, to) = range
+                        .split_once("..")
+                        .ok_or_else(|| error(&format!("{} is not a range like 0..5", range)))?;
+                    let from = number(from)?;
+                    if from < start {
+                        return Err(error(&format!(
+                            "starts at {} but the previous statement lasts until {}",
+                            from, start
+                        )));
+                    }
+                    end = match to {
+                        "" => None,
+                        to if number(to)? > from => Some(number(to)?),
+                        _ => return Err(error("the range is empty")),
+                    };
+                    value = apply(action, value).map_err(|err| error(&err.to_string()))?;
+                    stimulus.change(from, value);
+                }
+                _ => return Err(error("expected a range or repeat")),
+            }
+        }
+        Ok(stimulus)
+    }
+}
+
+// IN命令などで読むときに、そのサイクルの値をポート0に入れる
+impl InputSource for Stimulus {
+    fn next_input(&mut self, cycle: usize, port: usize) -> Result<Option<u8>, EmulatorErr> {
+        Ok(if port == 0 {
+            self.value_at(cycle)
+        } else {
+            None
+        })
+    }
+}
+
+#[cfg(test)]
+mod stimulus_tests {
+    use crate::port::InputSource;
+    use crate::stimulus::Stimulus;
+
+    #[test]
+    fn test_parse() {
+        let stimulus: Stimulus = "0..5: 0b0000; 5..9: 0b1111; repeat 4: toggle bit0"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            stimulus.changes(),
+            [(0, 0), (5, 15), (9, 14), (10, 15), (11, 14), (12, 15)]
+        );
+        assert_eq!(stimulus.value_at(7), Some(15));
+        // 最後の値を保つ
+        assert_eq!(stimulus.value_at(100), Some(15));
+
+        let stimulus: Stimulus = "
+# ボタンを3回押す
+3..4: set bit2
+repeat 2 every 3: toggle bit2
+10..: 0x1
+"
+        .parse()
+        .unwrap();
+        assert_eq!(stimulus.changes(), [(3, 4), (4, 0), (7, 4), (10, 1)]);
+        assert_eq!(stimulus.value_at(2), None);
+        let mut source = stimulus.clone();
+        assert_eq!(source.next_input(5, 0).unwrap(), Some(0));
+        assert_eq!(source.next_input(5, 1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = "0..5: 1; 3..6: 2".parse::<Stimulus>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "stimulus 3..6: 2: starts at 3 but the previous statement lasts until 5"
+        );
+        let err = "repeat 2: flip bit0".parse::<Stimulus>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "stimulus repeat 2: flip bit0: unknown operation flip"
+        );
+        assert!("0..: 1; 5..6: 2".parse::<Stimulus>().is_err());
+        assert!("5..5: 1".parse::<Stimulus>().is_err());
+        assert!("0..2: toggle bit8".parse::<Stimulus>().is_err());
+        assert!("0b0001".parse::<Stimulus>().is_err());
+    }
+}
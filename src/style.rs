@@ -0,0 +1,154 @@
+use crate::error::EmulatorErr;
+use std::io::IsTerminal;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// 端末に色を付けて表示するかどうか (--color auto|always|never)
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ColorChoice {
+    // 標準出力が端末で、NO_COLOR が設定されていなければ色を付ける
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    // terminal は出力先が端末かどうか、no_color は NO_COLOR の値
+    pub fn resolve(&self, terminal: bool, no_color: Option<&str>) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            // NO_COLOR は空でない値が設定されているときだけ有効 (https://no-color.org)
+            ColorChoice::Auto => terminal && no_color.is_none_or(str::is_empty),
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(EmulatorErr::new(&format!("Unknown color choice: {}", s))),
+        }
+    }
+}
+
+// CLIの全サブコマンドで共有する設定。ライブラリとして使うときは色を付けない
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// 環境を見て色を付けるかどうかを決める
+pub fn init(choice: ColorChoice) {
+    let no_color = std::env::var("NO_COLOR").ok();
+    let terminal = std::io::stdout().is_terminal();
+    ENABLED.store(
+        choice.resolve(terminal, no_color.as_deref()),
+        Ordering::Relaxed,
+    );
+}
+
+pub fn current() -> Style {
+    Style::new(ENABLED.load(Ordering::Relaxed))
+}
+
+// 文字列にANSIエスケープシーケンスで色を付ける。color でなければそのまま返す
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Style {
+    color: bool,
+}
+
+impl Style {
+    pub fn new(color: bool) -> Self {
+        Style { color }
+    }
+
+    pub fn color(&self) -> bool {
+        self.color
+    }
+
+    pub fn red(&self, text: &str) -> String {
+        self.paint("31", text)
+    }
+
+    pub fn yellow(&self, text: &str) -> String {
+        self.paint("33", text)
+    }
+
+    pub fn underline(&self, text: &str) -> String {
+        self.paint("4", text)
+    }
+
+    // 前回の表示から変わった値を太字の水色で目立たせる
+    pub fn changed(&self, text: &str, changed: bool) -> String {
+        if changed {
+            self.paint("1;36", text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    // エラーを赤で表示し、先頭の "line 3 in macro ..." のような位置に下線を引く
+    pub fn error(&self, message: &str) -> String {
+        match message.split_once(": ") {
+            Some((location, rest)) if location.starts_with("line ") => format!(
+                "{}{}",
+                self.underline(&self.red(location)),
+                self.red(&format!(": {}", rest))
+            ),
+            _ => self.red(message),
+        }
+    }
+
+    pub fn warning(&self, message: &str) -> String {
+        format!("{} {}", self.yellow("Warning:"), message)
+    }
+
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+// 警告を標準エラー出力に表示する
+pub fn warn(message: &str) {
+    eprintln!("{}", current().warning(message));
+}
+
+#[cfg(test)]
+mod style_tests {
+    use crate::style::{ColorChoice, Style};
+
+    #[test]
+    fn test_resolve() {
+        assert!(ColorChoice::Auto.resolve(true, None));
+        assert!(ColorChoice::Auto.resolve(true, Some("")));
+        assert!(!ColorChoice::Auto.resolve(true, Some("1")));
+        assert!(!ColorChoice::Auto.resolve(false, None));
+        assert!(ColorChoice::Always.resolve(false, Some("1")));
+        assert!(!ColorChoice::Never.resolve(true, None));
+        assert_eq!("never".parse::<ColorChoice>().unwrap(), ColorChoice::Never);
+        assert!("rainbow".parse::<ColorChoice>().is_err());
+    }
+
+    #[test]
+    fn test_paint() {
+        let style = Style::new(true);
+        assert_eq!(
+            style.error("line 2: unknown instruction"),
+            "\x1b[4m\x1b[31mline 2\x1b[0m\x1b[0m\x1b[31m: unknown instruction\x1b[0m"
+        );
+        assert_eq!(style.warning("x"), "\x1b[33mWarning:\x1b[0m x");
+        assert_eq!(style.changed("0b0001", false), "0b0001");
+        assert_eq!(style.changed("0b0001", true), "\x1b[1;36m0b0001\x1b[0m");
+
+        let plain = Style::new(false);
+        assert_eq!(plain.error("line 2: unknown"), "line 2: unknown");
+        assert_eq!(plain.warning("x"), "Warning: x");
+    }
+}
@@ -0,0 +1,212 @@
+use crate::debugger::Snapshot;
+use crate::disassembler::disassemble;
+use crate::error::EmulatorErr;
+use crate::machine::Machine;
+
+// マシンの状態を模式図のSVGにする (td4emu svg)
+// 左にROMの表とPCの矢印、右にレジスタの箱、入力スイッチと出力LEDを並べる
+// 1サイクルごとに書き出したものをつなげばアニメーションになる
+
+const ROW_HEIGHT: usize = 18;
+const TOP: usize = 44;
+// ROMが短くても実機と同じ16行を描く
+const MIN_ROWS: usize = 16;
+const PANEL_X: usize = 330;
+
+const STYLE: &str = "text { font-family: 'DejaVu Sans Mono', Menlo, Consolas, monospace; font-size: 13px; fill: #222; }
+.title { font-size: 15px; font-weight: bold; }
+.row.current { fill: #fff3b0; }
+.empty { fill: #aaa; }
+.box { fill: #f4f6fb; stroke: #557; }
+.pc { fill: #d33; }
+.switch { stroke: #557; }
+.switch.on { fill: #557; }
+.switch.off { fill: #fff; }
+.led { stroke: #733; }
+.led.on { fill: #f33; }
+.led.off { fill: #eee; }";
+
+pub fn render(snapshot: &Snapshot) -> String {
+    let rows = snapshot.rom.len().max(MIN_ROWS);
+    let height = TOP + rows * ROW_HEIGHT + 16;
+    let pc = snapshot.register.pc() as usize;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"560\" height=\"{height}\" viewBox=\"0 0 560 {height}\">\n"
+    );
+    svg.push_str(&format!("<style>\n{}\n</style>\n", STYLE));
+    svg.push_str("<rect width=\"100%\" height=\"100%\" fill=\"#fff\"/>\n");
+    svg.push_str(&format!(
+        "<text class=\"title\" x=\"30\" y=\"24\">ROM</text>\n\
+         <text class=\"title\" x=\"{PANEL_X}\" y=\"24\">cycle {}</text>\n",
+        snapshot.cycles
+    ));
+
+    // ROMの表。実行する命令の行を塗り、左に矢印を描く
+    for row in 0..rows {
+        let y = TOP + row * ROW_HEIGHT;
+        if row == pc {
+            svg.push_str(&format!(
+                "<rect class=\"row current\" x=\"28\" y=\"{}\" width=\"280\" height=\"{}\"/>\n\
+                 <polygon class=\"pc\" points=\"8,{} 22,{} 8,{}\"/>\n",
+                y - 13,
+                ROW_HEIGHT,
+                y - 11,
+                y - 5,
+                y + 1
+            ));
+        }
+        let (bits, instruction, class) = match snapshot.rom.get(row) {
+            Some(byte) => (format!("{:08b}", byte), disassemble(*byte), "row"),
+            None => ("--------".to_string(), String::new(), "row empty"),
+        };
+        svg.push_str(&format!(
+            "<text class=\"{class}\" x=\"32\" y=\"{y}\">0x{row:x}</text>\
+             <text class=\"{class}\" x=\"72\" y=\"{y}\">{bits}</text>\
+             <text class=\"{class}\" x=\"160\" y=\"{y}\">{}</text>\n",
+            escape(&instruction)
+        ));
+    }
+
+    // レジスタは名前と2進数の値を入れた箱
+    let register = &snapshot.register;
+    let boxes = [
+        ("A", format!("{:04b}", register.register_a())),
+        ("B", format!("{:04b}", register.register_b())),
+        ("C", register.carry_flag().to_string()),
+        ("PC", format!("{:04b}", register.pc())),
+    ];
+    for (index, (name, value)) in boxes.iter().enumerate() {
+        let x = PANEL_X + (index % 2) * 110;
+        let y = TOP + (index / 2) * 50;
+        svg.push_str(&format!(
+            "<rect class=\"box\" x=\"{x}\" y=\"{}\" width=\"100\" height=\"40\" rx=\"4\"/>\
+             <text x=\"{}\" y=\"{}\">{name}</text>\
+             <text x=\"{}\" y=\"{}\">{value}</text>\n",
+            y - 8,
+            x + 8,
+            y + 8,
+            x + 40,
+            y + 24
+        ));
+    }
+
+    // 入力スイッチと出力LEDは上位ビットを左に並べる
+    let ports = [
+        ("IN", snapshot.input, TOP + 120, "switch"),
+        ("OUT", snapshot.output, TOP + 170, "led"),
+    ];
+    for (name, value, y, class) in ports {
+        svg.push_str(&format!(
+            "<text x=\"{PANEL_X}\" y=\"{}\">{name}</text>\n",
+            y + 5
+        ));
+        for bit in 0..4 {
+            let x = PANEL_X + 60 + bit * 34;
+            let state = if value >> (3 - bit) & 1 == 1 {
+                "on"
+            } else {
+                "off"
+            };
+            let shape = match class {
+                "switch" => format!(
+                    "<rect class=\"switch {state}\" x=\"{}\" y=\"{}\" width=\"20\" height=\"20\" rx=\"3\"/>",
+                    x - 10,
+                    y - 10
+                ),
+                _ => format!("<circle class=\"led {state}\" cx=\"{x}\" cy=\"{y}\" r=\"11\"/>"),
+            };
+            svg.push_str(&shape);
+            svg.push('\n');
+        }
+    }
+
+    if !snapshot.stack.is_empty() {
+        let stack: Vec<String> = snapshot
+            .stack
+            .iter()
+            .map(|address| format!("0x{:x}", address))
+            .collect();
+        svg.push_str(&format!(
+            "<text x=\"{PANEL_X}\" y=\"{}\">stack {}</text>\n",
+            TOP + 215,
+            stack.join(", ")
+        ));
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+// 今の状態と、止まるか max_cycles に達するまでの1サイクルごとの状態
+pub fn frames(machine: &mut Machine, max_cycles: usize) -> Result<Vec<Snapshot>, EmulatorErr> {
+    let mut frames = vec![Snapshot::take(machine.emulator())];
+    while !machine.emulator().does_halt() && machine.emulator().cycles() < max_cycles {
+        machine.step()?;
+        frames.push(Snapshot::take(machine.emulator()));
+    }
+    Ok(frames)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod svg_tests {
+    use crate::compiler::assemble;
+    use crate::debugger::Snapshot;
+    use crate::machine::Machine;
+    use crate::profile::MachineProfile;
+    use crate::register::Register;
+    use crate::svg::{frames, render};
+
+    #[test]
+    fn test_render() {
+        let mut register = Register::new();
+        register.set_pc(2);
+        register.set_register_a(0b0101);
+        let snapshot = Snapshot {
+            register,
+            input: 0b0001,
+            output: 0b1010,
+            rom: assemble("mov A 0101\nmov B A\nout B\njmp 0011").unwrap(),
+            stack: Vec::new(),
+            shadow_pc: 0,
+            cycles: 2,
+        };
+        let svg = render(&snapshot);
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+        assert!(svg.ends_with("</svg>\n"));
+        assert!(svg.contains(">cycle 2</text>"));
+
+        // ROMは短くても16行。矢印と塗りは pc の行だけ
+        assert_eq!(svg.matches(">0x").count(), 16);
+        assert_eq!(svg.matches(">--------</text>").count(), 12);
+        assert_eq!(svg.matches("class=\"pc\"").count(), 1);
+        assert!(svg.contains("<text class=\"row\" x=\"160\" y=\"80\">out B</text>"));
+        assert!(svg.contains("<rect class=\"row current\" x=\"28\" y=\"67\""));
+
+        assert!(svg.contains(">A</text><text x=\"370\" y=\"68\">0101</text>"));
+        assert_eq!(svg.matches("class=\"led on\"").count(), 2);
+        assert_eq!(svg.matches("class=\"led off\"").count(), 2);
+        assert_eq!(svg.matches("class=\"switch on\"").count(), 1);
+        assert!(!svg.contains("stack"));
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut machine = Machine::new(MachineProfile::default());
+        machine.set_quiet(true);
+        machine
+            .load_source("add A 0001\nout 0001\njmp 0010")
+            .unwrap();
+        let snapshots = frames(&mut machine, 100).unwrap();
+        // リセット直後と2命令のあと。自分へのジャンプで止まる
+        assert_eq!(snapshots.len(), 3);
+        assert_eq!(snapshots[2].output, 1);
+        machine.reset();
+        assert_eq!(frames(&mut machine, 1).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,358 @@
+use crate::batch;
+use crate::equiv::{self, EquivConfig, Outcome};
+use crate::error::EmulatorErr;
+use crate::json::Json;
+use crate::register::Register;
+use crate::sandbox::{execute, ExecConfig, ExecError, ExecRun};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+// 入力ポートの値を1つずつ変えてプログラムを実行する (td4emu sweep)
+// 入力の関数を計算するプログラムの真理値表を作るのに使う
+#[derive(Debug, PartialEq, Clone)]
+pub struct SweepRow {
+    pub input: u8,
+    pub result: Result<ExecRun, ExecError>,
+}
+
+impl SweepRow {
+    // 最後にOUT命令で書き込んだ値。一度も書き込まなければ0
+    pub fn output(&self) -> Option<u8> {
+        self.result
+            .as_ref()
+            .ok()
+            .map(|run| run.outputs.last().map_or(0, |(_, output)| *output))
+    }
+
+    // 止まらなかった入力は output が null で、error に理由が入る
+    pub fn to_json(&self) -> Json {
+        let json = Json::object([
+            ("input", self.input.into()),
+            ("output", self.output().into()),
+        ]);
+        match &self.result {
+            Ok(run) => json
+                .with(
+                    "outputs",
+                    Json::array(run.outputs.iter().map(|(_, output)| *output)),
+                )
+                .with("cycles", run.cycles)
+                .with("error", Json::Null),
+            Err(err) => json
+                .with("outputs", Json::Array(Vec::new()))
+                .with("cycles", Json::Null)
+                .with("error", err.to_string()),
+        }
+    }
+}
+
+// inputs の値ごとに config の入力を置き換えて停止するまで実行する
+pub fn sweep(rom: &[u8], inputs: RangeInclusive<u8>, config: &ExecConfig) -> Vec<SweepRow> {
+    batch::map(inputs.collect(), |input| SweepRow {
+        input,
+        result: execute(
+            rom,
+            &ExecConfig {
+                input,
+                ..config.clone()
+            },
+        ),
+    })
+}
+
+// 実行を始めるときのレジスタA, Bとキャリー
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct InitialState {
+    pub a: u8,
+    pub b: u8,
+    pub carry: u8,
+}
+
+// 初期状態を全て試した結果 (td4emu run --sweep-initial)
+// 実機の電源投入直後のレジスタは0とは限らないので、初期化を忘れたプログラムを見つけるのに使う
+#[derive(Debug, PartialEq, Clone)]
+pub struct InitialSweep {
+    // リセット直後 (全て0) からの結果
+    pub reset: Outcome,
+    // 試した初期状態の数
+    pub states: usize,
+    // リセット直後と出力が変わった初期状態
+    pub differing: Vec<(InitialState, Outcome)>,
+}
+
+impl InitialSweep {
+    pub fn is_robust(&self) -> bool {
+        self.differing.is_empty()
+    }
+
+    // それだけを0から変えると出力が変わるレジスタ
+    pub fn depends_on(&self) -> Vec<&'static str> {
+        let changed =
+            |only: fn(&InitialState) -> bool| self.differing.iter().any(|(state, _)| only(state));
+        let mut registers = Vec::new();
+        if changed(|state| state.a != 0 && state.b == 0 && state.carry == 0) {
+            registers.push("A");
+        }
+        if changed(|state| state.a == 0 && state.b != 0 && state.carry == 0) {
+            registers.push("B");
+        }
+        if changed(|state| state.a == 0 && state.b == 0 && state.carry != 0) {
+            registers.push("C");
+        }
+        registers
+    }
+}
+
+// A, B, キャリーの全ての組み合わせから実行して、リセット直後と出力を比べる
+// 止まらないプログラムは config.max_cycles までの出力を比べる
+pub fn sweep_initial(rom: &[u8], config: &ExecConfig) -> InitialSweep {
+    let mask = config.profile.register_mask();
+    let mut states = Vec::new();
+    for a in 0..=mask {
+        for b in 0..=mask {
+            for carry in 0..=1 {
+                states.push(InitialState { a, b, carry });
+            }
+        }
+    }
+    let equiv_config = EquivConfig {
+        profile: config.profile.clone(),
+        max_cycles: config.max_cycles,
+        registers: true,
+    };
+    let total = states.len();
+    let mut outcomes = batch::map(states, |state| {
+        let mut register = Register::new();
+        register.set_register_a(state.a);
+        register.set_register_b(state.b);
+        register.set_carry_flag(state.carry);
+        let outcome = equiv::run_from(rom, register, config.input, &equiv_config);
+        (state, outcome)
+    });
+    // 先頭が全て0の状態
+    let (_, reset) = outcomes.remove(0);
+    InitialSweep {
+        states: total,
+        differing: outcomes
+            .into_iter()
+            .filter(|(_, outcome)| !outcome.matches(&reset))
+            .collect(),
+        reset,
+    }
+}
+
+const EXAMPLES: usize = 8;
+
+impl fmt::Display for InitialState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "A {:04b}, B {:04b}, C {}", self.a, self.b, self.carry)
+    }
+}
+
+// 出力が変わった初期状態は多すぎないように先頭の EXAMPLES 件だけ表示する
+//
+//     From reset (A 0000, B 0000, C 0): out 0011, halted
+//     The output depends on the initial value of A
+//     240 of 512 initial states change the output, for example:
+//       A 0001, B 0000, C 0: out 0100, halted
+impl fmt::Display for InitialSweep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reset = InitialState {
+            a: 0,
+            b: 0,
+            carry: 0,
+        };
+        writeln!(f, "From reset ({}): {}", reset, self.reset)?;
+        if self.is_robust() {
+            return writeln!(
+                f,
+                "The output is the same from all {} initial states of A, B and C",
+                self.states
+            );
+        }
+        let registers = self.depends_on();
+        if !registers.is_empty() {
+            writeln!(
+                f,
+                "The output depends on the initial value of {}",
+                registers.join(", ")
+            )?;
+        }
+        writeln!(
+            f,
+            "{} of {} initial states change the output, for example:",
+            self.differing.len(),
+            self.states
+        )?;
+        for (state, outcome) in self.differing.iter().take(EXAMPLES) {
+            writeln!(f, "  {}: {}", state, outcome)?;
+        }
+        Ok(())
+    }
+}
+
+// "0..16" (16は含まない), "3..=7", "5" の形。limit は入力ポートの最大値
+pub fn parse_range(text: &str, limit: u8) -> Result<RangeInclusive<u8>, EmulatorErr> {
+    let error = || EmulatorErr::new(&format!("Invalid input range: {}", text));
+    let number = |text: &str| text.trim().parse::<u16>().map_err(|_| error());
+    let (start, end) = match text.split_once("..") {
+        Some((start, end)) => match end.strip_prefix('=') {
+            Some(end) => (number(start)?, number(end)?),
+            None => (
+                number(start)?,
+                number(end)?.checked_sub(1).ok_or_else(error)?,
+            ),
+        },
+        None => (number(text)?, number(text)?),
+    };
+    if start > end {
+        return Err(error());
+    }
+    if end > limit as u16 {
+        return Err(EmulatorErr::new(&format!(
+            "Input {} doesn't fit in the input port. Maximum value is {}",
+            end, limit
+        )));
+    }
+    Ok(start as u8..=end as u8)
+}
+
+// 入力ごとに1行の表。sequence なら最後の値ではなくOUT命令で書き込んだ値を全て並べる
+//
+//     input  output  cycles
+//     0000   0011    4
+//     0001   0100    4
+//     0010   -       didn't halt within 1000 cycles
+pub fn report(rows: &[SweepRow], bits: u8, sequence: bool) -> String {
+    let width = bits as usize;
+    let binary = |value: u8| format!("{:0width$b}", value, width = width);
+    let mut table = vec![(
+        "input".to_string(),
+        if sequence { "outputs" } else { "output" }.to_string(),
+        "cycles".to_string(),
+    )];
+    for row in rows {
+        let (output, cycles) = match &row.result {
+            Ok(run) if sequence => (
+                run.outputs
+                    .iter()
+                    .map(|(_, output)| binary(*output))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                run.cycles.to_string(),
+            ),
+            Ok(run) => (binary(row.output().unwrap_or(0)), run.cycles.to_string()),
+            Err(err) => ("-".to_string(), err.to_string()),
+        };
+        table.push((binary(row.input), output, cycles));
+    }
+
+    let input_width = table.iter().map(|(input, _, _)| input.len()).max().unwrap();
+    let output_width = table
+        .iter()
+        .map(|(_, output, _)| output.len())
+        .max()
+        .unwrap();
+    table
+        .iter()
+        .map(|(input, output, cycles)| {
+            format!(
+                "{:input_width$}  {:output_width$}  {}\n",
+                input,
+                output,
+                cycles,
+                input_width = input_width,
+                output_width = output_width
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod sweep_tests {
+    use crate::compiler::assemble;
+    use crate::sandbox::{ExecConfig, ExecError};
+    use crate::sweep::{parse_range, report, sweep, sweep_initial};
+
+    #[test]
+    fn test_sweep() {
+        // 入力に3を足して出力する。桁あふれしたら止まらない
+        let rom = assemble("in A\nadd A 0011\njnc 0100\njmp 0000\nmov B A\nout B").unwrap();
+        let config = ExecConfig {
+            max_cycles: 20,
+            ..ExecConfig::default()
+        };
+        let rows = sweep(&rom, 12..=13, &config);
+        assert_eq!(rows[0].output(), Some(15));
+        assert_eq!(rows[1].output(), None);
+        assert_eq!(rows[1].result, Err(ExecError::CycleLimit(20)));
+        assert_eq!(
+            report(&rows, 4, false),
+            "input  output  cycles\n\
+             1100   1111    5\n\
+             1101   -       didn't halt within 20 cycles\n"
+        );
+
+        let rom = assemble("in B\nout B\nout 0000").unwrap();
+        let rows = sweep(&rom, 5..=5, &config);
+        assert_eq!(
+            report(&rows, 4, true),
+            "input  outputs    cycles\n0101   0101 0000  3\n"
+        );
+    }
+
+    #[test]
+    fn test_to_json() {
+        let rom = assemble("in A\nadd A 0011\njnc 0100\njmp 0000\nmov B A\nout B").unwrap();
+        let config = ExecConfig {
+            max_cycles: 20,
+            ..ExecConfig::default()
+        };
+        let rows = sweep(&rom, 12..=13, &config);
+        assert_eq!(
+            rows[0].to_json().to_string(),
+            "{\"input\":12,\"output\":15,\"outputs\":[15],\"cycles\":5,\"error\":null}"
+        );
+        assert_eq!(
+            rows[1].to_json().to_string(),
+            "{\"input\":13,\"output\":null,\"outputs\":[],\"cycles\":null,\
+             \"error\":\"didn't halt within 20 cycles\"}"
+        );
+    }
+
+    #[test]
+    fn test_sweep_initial() {
+        // A を0にしないまま数えはじめる
+        let rom = assemble("add A 0001\nmov B A\nout B").unwrap();
+        let result = sweep_initial(&rom, &ExecConfig::default());
+        assert_eq!(result.states, 512);
+        assert_eq!(result.depends_on(), vec!["A"]);
+        // A の値ごとに C の0と1の2通り
+        assert_eq!(result.differing.len(), 15 * 16 * 2);
+        let text = result.to_string();
+        assert!(text.starts_with(
+            "From reset (A 0000, B 0000, C 0): out 0001, halted\n\
+             The output depends on the initial value of A\n\
+             480 of 512 initial states change the output, for example:\n  \
+             A 0001, B 0000, C 0: out 0010, halted\n"
+        ));
+
+        let rom = assemble("mov A 0000\nadd A 0001\nmov B A\nout B").unwrap();
+        let result = sweep_initial(&rom, &ExecConfig::default());
+        assert!(result.is_robust());
+        assert!(result
+            .to_string()
+            .ends_with("The output is the same from all 512 initial states of A, B and C\n"));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("0..16", 15).unwrap(), 0..=15);
+        assert_eq!(parse_range("3..=7", 15).unwrap(), 3..=7);
+        assert_eq!(parse_range("5", 15).unwrap(), 5..=5);
+        assert!(parse_range("0..17", 15).is_err());
+        assert!(parse_range("7..3", 15).is_err());
+        assert!(parse_range("0..0", 15).is_err());
+        assert!(parse_range("a..b", 15).is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use crate::error::EmulatorErr;
+use crate::rom::Rom;
+use std::str::FromStr;
+
+// 実機のROMは8個のDIPスイッチが16行並んだもの
+pub const ROWS: usize = 16;
+
+// 1行のスイッチを左から読んだときのビットの並び
+// 本の回路図は左が最上位ビットだが、右を最上位ビットに配線した基板もある
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum BitOrder {
+    #[default]
+    MsbFirst,
+    LsbFirst,
+}
+
+impl BitOrder {
+    // スイッチの並びとROMのバイトを相互に変換する (逆順にするだけなので向きは問わない)
+    pub fn arrange(&self, byte: u8) -> u8 {
+        match self {
+            BitOrder::MsbFirst => byte,
+            BitOrder::LsbFirst => byte.reverse_bits(),
+        }
+    }
+}
+
+impl FromStr for BitOrder {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "msb-first" => Ok(BitOrder::MsbFirst),
+            "lsb-first" => Ok(BitOrder::LsbFirst),
+            _ => Err(EmulatorErr::new(&format!("Unknown bit order: {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SwitchBank {
+    rows: [u8; ROWS],
+    order: BitOrder,
+}
+
+impl SwitchBank {
+    pub fn new() -> Self {
+        Self {
+            rows: [0; ROWS],
+            order: BitOrder::MsbFirst,
+        }
+    }
+
+    pub fn bit_order(&self) -> BitOrder {
+        self.order
+    }
+
+    // 一覧の読み書きと図の表示でのスイッチの並び。ROMの中身は変わらない
+    pub fn set_bit_order(&mut self, order: BitOrder) {
+        self.order = order;
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EmulatorErr> {
+        if bytes.len() > ROWS {
+            return Err(EmulatorErr::new(&format!(
+                "ROM has only {} rows of switches but the program is {} bytes",
+                ROWS,
+                bytes.len()
+            )));
+        }
+        let mut bank = Self::new();
+        bank.rows[..bytes.len()].copy_from_slice(bytes);
+        Ok(bank)
+    }
+
+    // 1行に8個のスイッチを並べたテキストを読み込む
+    // "0x3 1011 0001" のように行頭にアドレスを書くこともできる。#以降はコメント
+    pub fn from_listing(listing: &str) -> Result<Self, EmulatorErr> {
+        Self::from_listing_with(listing, BitOrder::MsbFirst)
+    }
+
+    pub fn from_listing_with(listing: &str, order: BitOrder) -> Result<Self, EmulatorErr> {
+        let mut bank = Self::new();
+        bank.order = order;
+        let mut next = 0;
+
+        for (number, line) in listing.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut words = line.split_whitespace().peekable();
+            let row = match words.peek() {
+                Some(word) if word.starts_with("0x") => {
+                    let address = u8::from_str_radix(&word[2..], 16).map_err(|_| {
+                        EmulatorErr::new(&format!("line {}: invalid address {}", number + 1, word))
+                    })?;
+                    words.next();
+                    address as usize
+                }
+                _ => next,
+            };
+            if row >= ROWS {
+                return Err(EmulatorErr::new(&format!(
+                    "line {}: row {} is out of range",
+                    number + 1,
+                    row
+                )));
+            }
+
+            let switches: String = words.collect();
+            if switches.len() != 8 {
+                return Err(EmulatorErr::new(&format!(
+                    "line {}: a row needs exactly 8 switches",
+                    number + 1
+                )));
+            }
+            let value = u8::from_str_radix(&switches, 2).map_err(|_| {
+                EmulatorErr::new(&format!(
+                    "line {}: switches must be 0 or 1: {}",
+                    number + 1,
+                    switches
+                ))
+            })?;
+            bank.rows[row] = order.arrange(value);
+            next = row + 1;
+        }
+
+        Ok(bank)
+    }
+
+    pub fn to_listing(&self) -> String {
+        let mut listing = match self.order {
+            BitOrder::MsbFirst => String::from("# TD4 ROM switches (1 = ON)\n"),
+            BitOrder::LsbFirst => String::from("# TD4 ROM switches (1 = ON, LSB first)\n"),
+        };
+        for (address, row) in self.rows.iter().enumerate() {
+            let row = self.order.arrange(*row);
+            listing.push_str(&format!(
+                "0x{:x} {:04b} {:04b}\n",
+                address,
+                row >> 4,
+                row & 0x0f
+            ));
+        }
+        listing
+    }
+
+    // スイッチの状態を図として表示する (●がON)
+    pub fn render(&self) -> String {
+        let mut screen = match self.order {
+            BitOrder::MsbFirst => String::from("     7 6 5 4  3 2 1 0\n"),
+            BitOrder::LsbFirst => String::from("     0 1 2 3  4 5 6 7\n"),
+        };
+        for (address, row) in self.rows.iter().enumerate() {
+            // 左のスイッチから順に bit 7..0 として読めるように並べ替える
+            let row = self.order.arrange(*row);
+            let switch = |bit: u8| if row >> bit & 1 == 1 { '●' } else { '○' };
+            screen.push_str(&format!(
+                "0x{:x}  {} {} {} {}  {} {} {} {}\n",
+                address,
+                switch(7),
+                switch(6),
+                switch(5),
+                switch(4),
+                switch(3),
+                switch(2),
+                switch(1),
+                switch(0)
+            ));
+        }
+        screen
+    }
+
+    pub fn row(&self, row: usize) -> u8 {
+        self.rows[row]
+    }
+
+    pub fn set_row(&mut self, row: usize, value: u8) -> Result<(), EmulatorErr> {
+        Self::check_row(row)?;
+        self.rows[row] = value;
+        Ok(())
+    }
+
+    pub fn toggle(&mut self, row: usize, bit: u8) -> Result<(), EmulatorErr> {
+        Self::check_row(row)?;
+        if bit > 7 {
+            return Err(EmulatorErr::new(&format!("switch {} is out of range", bit)));
+        }
+        self.rows[row] ^= 1 << bit;
+        Ok(())
+    }
+
+    pub fn to_rom(&self) -> Rom {
+        Rom::new(self.rows.to_vec())
+    }
+
+    fn check_row(row: usize) -> Result<(), EmulatorErr> {
+        if row >= ROWS {
+            return Err(EmulatorErr::new(&format!("row {} is out of range", row)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod switches_tests {
+    use crate::switches::{BitOrder, SwitchBank};
+
+    #[test]
+    fn test_from_listing() {
+        let listing = "# simple calc
+00110001
+0000 0001
+0x3 10010000 # out B
+";
+        let bank = SwitchBank::from_listing(listing).unwrap();
+        assert_eq!(bank.row(0), 0b00110001);
+        assert_eq!(bank.row(1), 0b00000001);
+        assert_eq!(bank.row(2), 0);
+        assert_eq!(bank.row(3), 0b10010000);
+        assert_eq!(bank.to_rom().size(), 16);
+    }
+
+    #[test]
+    fn test_from_listing_errors() {
+        assert!(SwitchBank::from_listing("0011000").is_err());
+        assert!(SwitchBank::from_listing("00110002").is_err());
+        assert!(SwitchBank::from_listing("0x10 00110001").is_err());
+    }
+
+    #[test]
+    fn test_listing_round_trip() {
+        let bank = SwitchBank::from_bytes(&[0b00110001, 0b11110000]).unwrap();
+        let restored = SwitchBank::from_listing(&bank.to_listing()).unwrap();
+        assert_eq!(bank, restored);
+    }
+
+    #[test]
+    fn test_toggle() {
+        let mut bank = SwitchBank::new();
+        bank.toggle(2, 7).unwrap();
+        bank.toggle(2, 0).unwrap();
+        assert_eq!(bank.row(2), 0b10000001);
+        bank.toggle(2, 0).unwrap();
+        assert_eq!(bank.row(2), 0b10000000);
+        assert!(bank.toggle(16, 0).is_err());
+        assert!(bank.toggle(0, 8).is_err());
+    }
+
+    #[test]
+    fn test_lsb_first() {
+        let bank = SwitchBank::from_listing_with("0x0 1000 1100", BitOrder::LsbFirst).unwrap();
+        // 左から bit 0..7 の順に並んでいる
+        assert_eq!(bank.row(0), 0b00110001);
+        assert!(bank.to_listing().contains("0x0 1000 1100\n"));
+        assert!(bank
+            .render()
+            .starts_with("     0 1 2 3  4 5 6 7\n0x0  ● ○ ○ ○  ● ● ○ ○\n"));
+
+        let restored = SwitchBank::from_listing_with(&bank.to_listing(), BitOrder::LsbFirst);
+        assert_eq!(restored.unwrap(), bank);
+        assert_eq!("lsb-first".parse::<BitOrder>().unwrap(), BitOrder::LsbFirst);
+        assert!("lsb".parse::<BitOrder>().is_err());
+    }
+}
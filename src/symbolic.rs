@@ -0,0 +1,624 @@
+use crate::error::EmulatorErr;
+use crate::isa::{semantics, CarryEffect, Location, PcEffect, Precondition, Transfer};
+use crate::op::{LowBits, Opcode};
+use crate::profile::{MachineProfile, UndefinedOpcodePolicy};
+use crate::stack::DEPTH;
+use std::collections::HashMap;
+use std::fmt;
+
+// 入力ポート (と、指定すればレジスタA, Bの初期値) を変数のまま実行する記号実行
+// 分岐するたびに道を分け、道ごとにその道を通る条件と出力の式を求める
+// 命令の意味は isa.rs の表から読むので、命令を増やしてもここを変える必要はない
+//
+// 値は4bitなので、条件を満たす入力があるかは変数の値を全て試して確かめる
+#[derive(Debug, PartialEq, Clone)]
+pub struct SymbolicConfig {
+    pub profile: MachineProfile,
+    // trueならレジスタA, Bの初期値も変数にする (falseなら0)
+    pub registers: bool,
+    // 1つの道で同じアドレスを実行してよい回数 (ループを展開する深さ)
+    pub unroll: usize,
+}
+
+impl Default for SymbolicConfig {
+    fn default() -> Self {
+        SymbolicConfig {
+            profile: MachineProfile::default(),
+            registers: false,
+            unroll: 16,
+        }
+    }
+}
+
+// 実行を始めたときの値
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
+pub enum Var {
+    // 入力ポート。実行中は変わらないものとする
+    Input(u8),
+    A,
+    B,
+}
+
+// 値の式。+ と - はレジスタのビット幅で桁あふれする
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Expr {
+    Const(u8),
+    Var(Var),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+}
+
+// キャリーフラグの値
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Flag {
+    Const(bool),
+    // lhs + rhs が桁あふれする
+    Carry(Expr, Expr),
+    // lhs - rhs で引けない (lhs < rhs)
+    Borrow(Expr, Expr),
+}
+
+// 道を通るための条件。flag が holds であること
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Condition {
+    pub flag: Flag,
+    pub holds: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum PathEnd {
+    Halted,
+    // 同じアドレスを unroll 回より多く実行しようとした
+    Unrolled(u8),
+    // 未定義のopcodeやスタックのあふれ
+    Fault(String),
+    // 記号実行では扱えない命令 (ld / st)
+    Unsupported(String),
+}
+
+// 1つの実行の道
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Path {
+    pub conditions: Vec<Condition>,
+    // OUT命令で書き込んだ (ポート, 値) の順
+    pub outputs: Vec<(usize, Expr)>,
+    pub a: Expr,
+    pub b: Expr,
+    pub end: PathEnd,
+    // この道を通る変数の値の組の数と、全ての組の数
+    pub cases: usize,
+    pub total: usize,
+}
+
+impl Expr {
+    fn add(lhs: Expr, rhs: Expr, mask: u8) -> Expr {
+        match (lhs, rhs) {
+            (Expr::Const(lhs), Expr::Const(rhs)) => Expr::Const(lhs.wrapping_add(rhs) & mask),
+            (lhs, Expr::Const(0)) | (Expr::Const(0), lhs) => lhs,
+            // 定数は右にまとめる
+            (Expr::Const(c), rhs) => Expr::add(rhs, Expr::Const(c), mask),
+            (Expr::Add(inner, c1), Expr::Const(c2)) if matches!(*c1, Expr::Const(_)) => {
+                let c1 = match *c1 {
+                    Expr::Const(c1) => c1,
+                    _ => unreachable!(),
+                };
+                Expr::add(*inner, Expr::Const(c1.wrapping_add(c2) & mask), mask)
+            }
+            (lhs, rhs) => Expr::Add(Box::new(lhs), Box::new(rhs)),
+        }
+    }
+
+    fn sub(lhs: Expr, rhs: Expr, mask: u8) -> Expr {
+        match (lhs, rhs) {
+            (Expr::Const(lhs), Expr::Const(rhs)) => Expr::Const(lhs.wrapping_sub(rhs) & mask),
+            (lhs, Expr::Const(0)) => lhs,
+            (lhs, rhs) => Expr::Sub(Box::new(lhs), Box::new(rhs)),
+        }
+    }
+
+    pub fn eval(&self, values: &HashMap<Var, u8>, mask: u8) -> u8 {
+        match self {
+            Expr::Const(value) => *value,
+            Expr::Var(var) => values.get(var).copied().unwrap_or(0),
+            Expr::Add(lhs, rhs) => {
+                lhs.eval(values, mask).wrapping_add(rhs.eval(values, mask)) & mask
+            }
+            Expr::Sub(lhs, rhs) => {
+                lhs.eval(values, mask).wrapping_sub(rhs.eval(values, mask)) & mask
+            }
+        }
+    }
+
+    fn vars(&self, vars: &mut Vec<Var>) {
+        match self {
+            Expr::Const(_) => (),
+            Expr::Var(var) if !vars.contains(var) => vars.push(*var),
+            Expr::Var(_) => (),
+            Expr::Add(lhs, rhs) | Expr::Sub(lhs, rhs) => {
+                lhs.vars(vars);
+                rhs.vars(vars);
+            }
+        }
+    }
+}
+
+impl Flag {
+    fn carry(lhs: Expr, rhs: Expr, mask: u8) -> Flag {
+        match (&lhs, &rhs) {
+            (Expr::Const(l), Expr::Const(r)) => Flag::Const(*l as u16 + *r as u16 > mask as u16),
+            (_, Expr::Const(0)) | (Expr::Const(0), _) => Flag::Const(false),
+            _ => Flag::Carry(lhs, rhs),
+        }
+    }
+
+    fn borrow(lhs: Expr, rhs: Expr) -> Flag {
+        match (&lhs, &rhs) {
+            (Expr::Const(l), Expr::Const(r)) => Flag::Const(l < r),
+            (_, Expr::Const(0)) => Flag::Const(false),
+            _ => Flag::Borrow(lhs, rhs),
+        }
+    }
+
+    pub fn eval(&self, values: &HashMap<Var, u8>, mask: u8) -> bool {
+        match self {
+            Flag::Const(value) => *value,
+            Flag::Carry(lhs, rhs) => {
+                lhs.eval(values, mask) as u16 + rhs.eval(values, mask) as u16 > mask as u16
+            }
+            Flag::Borrow(lhs, rhs) => lhs.eval(values, mask) < rhs.eval(values, mask),
+        }
+    }
+}
+
+// 記号実行の途中の状態
+#[derive(Clone)]
+struct State {
+    pc: u8,
+    a: Expr,
+    b: Expr,
+    carry: Flag,
+    stack: Vec<u8>,
+    outputs: Vec<(usize, Expr)>,
+    conditions: Vec<Condition>,
+    // アドレスごとの実行した回数
+    visits: HashMap<u8, usize>,
+}
+
+// ROMを記号実行して、通りうる道を全て返す
+pub fn execute(rom: &[u8], config: &SymbolicConfig) -> Result<Vec<Path>, EmulatorErr> {
+    let profile = &config.profile;
+    profile.validate()?;
+    let mask = profile.register_mask();
+    let (a, b) = match config.registers {
+        true => (Expr::Var(Var::A), Expr::Var(Var::B)),
+        false => (Expr::Const(0), Expr::Const(0)),
+    };
+    let mut pending = vec![State {
+        pc: 0,
+        a,
+        b,
+        carry: Flag::Const(false),
+        stack: Vec::new(),
+        outputs: Vec::new(),
+        conditions: Vec::new(),
+        visits: HashMap::new(),
+    }];
+    let mut ends = Vec::new();
+
+    while let Some(mut state) = pending.pop() {
+        let pc = state.pc;
+        let data = match rom.get(pc as usize) {
+            Some(data) if *data != Opcode::Jmp.encode(pc) => *data,
+            _ => {
+                ends.push((state, PathEnd::Halted));
+                continue;
+            }
+        };
+        let visits = state.visits.entry(pc).or_insert(0);
+        *visits += 1;
+        if *visits > config.unroll {
+            ends.push((state, PathEnd::Unrolled(pc)));
+            continue;
+        }
+        match step(&mut state, data, profile)? {
+            Step::Next(pc) => {
+                state.pc = pc;
+                pending.push(state);
+            }
+            // 両方の道を調べる。通れない道は捨てる
+            Step::Branch { flag, taken, next } => {
+                for (holds, pc) in [(true, next), (false, taken)] {
+                    let mut branch = state.clone();
+                    branch.pc = pc;
+                    branch.conditions.push(Condition {
+                        flag: flag.clone(),
+                        holds,
+                    });
+                    if count(&branch.conditions, config, mask)?.0 > 0 {
+                        pending.push(branch);
+                    }
+                }
+            }
+            Step::End(end) => ends.push((state, end)),
+        }
+    }
+
+    ends.into_iter()
+        .map(|(state, end)| {
+            let (cases, total) = count(&state.conditions, config, mask)?;
+            Ok(Path {
+                conditions: state.conditions,
+                outputs: state.outputs,
+                a: state.a,
+                b: state.b,
+                end,
+                cases,
+                total,
+            })
+        })
+        .collect()
+}
+
+enum Step {
+    Next(u8),
+    // flag が成り立てば next へ、成り立たなければ taken へ (JNC)
+    Branch { flag: Flag, taken: u8, next: u8 },
+    End(PathEnd),
+}
+
+// 1命令を記号のまま実行する。isa::apply と同じ順に表を読む
+fn step(state: &mut State, data: u8, profile: &MachineProfile) -> Result<Step, EmulatorErr> {
+    let mask = profile.register_mask();
+    let following = state.pc.wrapping_add(1);
+    let decoded = Opcode::decode(data).and_then(|(opcode, im)| {
+        semantics(opcode)
+            .filter(|semantics| {
+                profile.mode.is_extended()
+                    || !semantics.requires.contains(&Precondition::ExtendedMode)
+            })
+            .map(|semantics| (semantics, im))
+    });
+    let (semantics, im) = match decoded {
+        Some(decoded) => decoded,
+        None => {
+            return Ok(match profile.undefined_opcode_policy {
+                UndefinedOpcodePolicy::Error => {
+                    Step::End(PathEnd::Fault("No match for opcode".to_string()))
+                }
+                UndefinedOpcodePolicy::Nop => Step::Next(following),
+            })
+        }
+    };
+
+    let mut port = 0;
+    if semantics.opcode.low_bits() == LowBits::Port {
+        port = im as usize;
+    }
+    for precondition in semantics.requires {
+        match precondition {
+            Precondition::PortExists if port >= profile.mode.port_count() => {
+                if profile.mode.is_extended() {
+                    let message = format!("port {} doesn't exist", port);
+                    return Ok(Step::End(PathEnd::Fault(message)));
+                }
+                port = 0;
+            }
+            Precondition::StackNotFull if state.stack.len() >= DEPTH => {
+                return Ok(Step::End(PathEnd::Fault("Stack overflow".to_string())));
+            }
+            Precondition::StackNotEmpty if state.stack.is_empty() => {
+                return Ok(Step::End(PathEnd::Fault("Stack underflow".to_string())));
+            }
+            _ => (),
+        }
+    }
+
+    let read = |state: &State, location| match location {
+        Location::Immediate => Some(Expr::Const(im)),
+        Location::A => Some(state.a.clone()),
+        Location::B => Some(state.b.clone()),
+        Location::Input => Some(Expr::Var(Var::Input(port as u8))),
+        Location::Output => None,
+        Location::Memory => None,
+    };
+    let unsupported = || {
+        Ok(Step::End(PathEnd::Unsupported(format!(
+            "{:?} can't be executed symbolically",
+            semantics.opcode
+        ))))
+    };
+    let write = |state: &mut State, location, value| match location {
+        Location::A => state.a = value,
+        Location::B => state.b = value,
+        Location::Output => state.outputs.push((port, value)),
+        Location::Immediate | Location::Input | Location::Memory => (),
+    };
+
+    let alu_carry = match semantics.transfer {
+        Transfer::None => Flag::Const(false),
+        Transfer::Move { to, from } => {
+            if to == Location::Memory {
+                return unsupported();
+            }
+            match read(state, from) {
+                Some(value) => write(state, to, value),
+                None => return unsupported(),
+            }
+            Flag::Const(false)
+        }
+        Transfer::Add { to, from } | Transfer::Sub { to, from } => {
+            let (lhs, rhs) = match (read(state, to), read(state, from)) {
+                (Some(lhs), Some(rhs)) => (lhs, rhs),
+                _ => return unsupported(),
+            };
+            let (value, carry) = match semantics.transfer {
+                Transfer::Add { .. } => (
+                    Expr::add(lhs.clone(), rhs.clone(), mask),
+                    Flag::carry(lhs, rhs, mask),
+                ),
+                _ => (
+                    Expr::sub(lhs.clone(), rhs.clone(), mask),
+                    Flag::borrow(lhs, rhs),
+                ),
+            };
+            write(state, to, value);
+            carry
+        }
+        Transfer::Compare { lhs, rhs } => match (read(state, lhs), read(state, rhs)) {
+            (Some(lhs), Some(rhs)) => Flag::borrow(lhs, rhs),
+            _ => return unsupported(),
+        },
+        Transfer::Exchange { lhs, rhs } => match (read(state, lhs), read(state, rhs)) {
+            (Some(lhs_value), Some(rhs_value)) => {
+                write(state, lhs, rhs_value);
+                write(state, rhs, lhs_value);
+                Flag::Const(false)
+            }
+            _ => return unsupported(),
+        },
+    };
+
+    // 分岐は実行する前のキャリーで決まる
+    let carry = std::mem::replace(&mut state.carry, Flag::Const(false));
+    state.carry = match semantics.carry {
+        CarryEffect::Unaffected
+            if profile
+                .flag_model
+                .keeps_carry(semantics.pc != PcEffect::Next) =>
+        {
+            carry.clone()
+        }
+        CarryEffect::Unaffected => Flag::Const(false),
+        CarryEffect::Carry | CarryEffect::Borrow => alu_carry,
+    };
+
+    Ok(match semantics.pc {
+        PcEffect::Next => Step::Next(following),
+        PcEffect::Jump => Step::Next(im),
+        PcEffect::JumpIfNoCarry => match carry {
+            Flag::Const(true) => Step::Next(following),
+            Flag::Const(false) => Step::Next(im),
+            flag => Step::Branch {
+                flag,
+                taken: im,
+                next: following,
+            },
+        },
+        PcEffect::Call => {
+            state.stack.push(following);
+            Step::Next(im)
+        }
+        PcEffect::Return => Step::Next(state.stack.pop().unwrap_or(0)),
+    })
+}
+
+// 条件を満たす変数の値の組の数と、全ての組の数
+// 変数は入力ポート0 (と A, B) に条件に出てくるものを加えたもの
+fn count(
+    conditions: &[Condition],
+    config: &SymbolicConfig,
+    mask: u8,
+) -> Result<(usize, usize), EmulatorErr> {
+    let mut vars = vec![Var::Input(0)];
+    if config.registers {
+        vars.extend([Var::A, Var::B]);
+    }
+    for condition in conditions {
+        match &condition.flag {
+            Flag::Const(_) => (),
+            Flag::Carry(lhs, rhs) | Flag::Borrow(lhs, rhs) => {
+                lhs.vars(&mut vars);
+                rhs.vars(&mut vars);
+            }
+        }
+    }
+    let bits = (mask.count_ones() as usize) * vars.len();
+    if bits > 16 {
+        return Err(EmulatorErr::new(&format!(
+            "too many symbolic values to check ({} bits)",
+            bits
+        )));
+    }
+
+    let total = 1usize << bits;
+    let mut values = HashMap::new();
+    let satisfied = (0..total)
+        .filter(|assignment| {
+            for (index, var) in vars.iter().enumerate() {
+                let shift = index * mask.count_ones() as usize;
+                values.insert(*var, (assignment >> shift) as u8 & mask);
+            }
+            conditions
+                .iter()
+                .all(|condition| condition.flag.eval(&values, mask) == condition.holds)
+        })
+        .count();
+    Ok((satisfied, total))
+}
+
+impl fmt::Display for Var {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Var::Input(0) => write!(f, "IN"),
+            Var::Input(port) => write!(f, "IN{}", port),
+            Var::A => write!(f, "A0"),
+            Var::B => write!(f, "B0"),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // 入れ子の式は括弧で囲む
+        let operand = |expr: &Expr| match expr {
+            Expr::Add(..) | Expr::Sub(..) => format!("({})", expr),
+            _ => expr.to_string(),
+        };
+        match self {
+            Expr::Const(value) => write!(f, "{}", value),
+            Expr::Var(var) => write!(f, "{}", var),
+            Expr::Add(lhs, rhs) => write!(f, "{} + {}", operand(lhs), operand(rhs)),
+            Expr::Sub(lhs, rhs) => write!(f, "{} - {}", operand(lhs), operand(rhs)),
+        }
+    }
+}
+
+// IN + 3 carries / IN + 3 doesn't carry のように書く
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operand = |expr: &Expr| match expr {
+            Expr::Add(..) | Expr::Sub(..) => format!("({})", expr),
+            _ => expr.to_string(),
+        };
+        match (&self.flag, self.holds) {
+            (Flag::Const(value), holds) => write!(f, "{}", value == &holds),
+            (Flag::Carry(lhs, rhs), true) => {
+                write!(f, "{} + {} carries", operand(lhs), operand(rhs))
+            }
+            (Flag::Carry(lhs, rhs), false) => {
+                write!(f, "{} + {} doesn't carry", operand(lhs), operand(rhs))
+            }
+            (Flag::Borrow(lhs, rhs), true) => write!(f, "{} < {}", operand(lhs), operand(rhs)),
+            (Flag::Borrow(lhs, rhs), false) => {
+                write!(f, "{} >= {}", operand(lhs), operand(rhs))
+            }
+        }
+    }
+}
+
+// path 1 (13 of 16 cases): IN + 3 doesn't carry
+//   out IN + 3
+//   halted with A = IN + 3, B = 0
+pub fn report(paths: &[Path]) -> String {
+    let mut report = String::new();
+    for (index, path) in paths.iter().enumerate() {
+        let conditions: Vec<String> = path.conditions.iter().map(|c| c.to_string()).collect();
+        report.push_str(&format!(
+            "path {} ({} of {} cases): {}\n",
+            index + 1,
+            path.cases,
+            path.total,
+            match conditions.is_empty() {
+                true => "always".to_string(),
+                false => conditions.join(" and "),
+            }
+        ));
+        for (port, output) in &path.outputs {
+            match port {
+                0 => report.push_str(&format!("  out {}\n", output)),
+                _ => report.push_str(&format!("  out {} on port {}\n", output, port)),
+            }
+        }
+        let end = match &path.end {
+            PathEnd::Halted => "halted".to_string(),
+            PathEnd::Unrolled(pc) => format!("stopped unrolling at 0x{:x}", pc),
+            PathEnd::Fault(message) | PathEnd::Unsupported(message) => message.clone(),
+        };
+        report.push_str(&format!("  {} with A = {}, B = {}\n", end, path.a, path.b));
+    }
+    report
+}
+
+#[cfg(test)]
+mod symbolic_tests {
+    use crate::compiler::assemble;
+    use crate::profile::MachineProfile;
+    use crate::symbolic::{execute, report, Expr, PathEnd, SymbolicConfig, Var};
+
+    #[test]
+    fn test_straight_line() {
+        let rom = assemble("in A\nadd A 0011\nmov B A\nout B").unwrap();
+        let paths = execute(&rom, &SymbolicConfig::default()).unwrap();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].outputs[0].1.to_string(), "IN + 3");
+        assert_eq!((paths[0].cases, paths[0].total), (16, 16));
+        assert_eq!(
+            report(&paths),
+            "path 1 (16 of 16 cases): always\n  out IN + 3\n  halted with A = IN + 3, B = IN + 3\n"
+        );
+    }
+
+    #[test]
+    fn test_branches() {
+        // 桁あふれすれば 1111、しなければ IN + 3 を出力する
+        let rom = assemble("in A\nadd A 0011\njnc 0100\nmov A 1111\nmov B A\nout B").unwrap();
+        let paths = execute(&rom, &SymbolicConfig::default()).unwrap();
+        let mut summary: Vec<(String, String, usize)> = paths
+            .iter()
+            .map(|path| {
+                (
+                    path.conditions[0].to_string(),
+                    path.outputs[0].1.to_string(),
+                    path.cases,
+                )
+            })
+            .collect();
+        summary.sort();
+        assert_eq!(
+            summary,
+            vec![
+                ("IN + 3 carries".to_string(), "15".to_string(), 3),
+                ("IN + 3 doesn't carry".to_string(), "IN + 3".to_string(), 13),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_infeasible_and_loops() {
+        // 2回目の add は1回目と同じ条件なので、一方の道は通れない
+        let rom = assemble("in A\nadd A 0000\njnc 0011\nout 0001").unwrap();
+        let paths = execute(&rom, &SymbolicConfig::default()).unwrap();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].outputs[0].1, Expr::Const(1));
+
+        // 0から数え上げて桁あふれで抜けるループ
+        let rom = assemble("add A 0001\njnc 0000\nout 0101").unwrap();
+        let paths = execute(&rom, &SymbolicConfig::default()).unwrap();
+        assert_eq!(paths[0].end, PathEnd::Halted);
+        let config = SymbolicConfig {
+            unroll: 4,
+            ..SymbolicConfig::default()
+        };
+        let paths = execute(&rom, &config).unwrap();
+        assert_eq!(paths[0].end, PathEnd::Unrolled(0));
+    }
+
+    #[test]
+    fn test_registers() {
+        let config = SymbolicConfig {
+            registers: true,
+            profile: MachineProfile::td4_extended(),
+            ..SymbolicConfig::default()
+        };
+        let rom = assemble("swap A B\nsub A 0001\nout B").unwrap();
+        let paths = execute(&rom, &config).unwrap();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].outputs[0].1, Expr::Var(Var::A));
+        assert_eq!(paths[0].a.to_string(), "B0 - 1");
+        assert_eq!(paths[0].total, 4096);
+
+        let rom = assemble("ld A [B]").unwrap();
+        let paths = execute(&rom, &config).unwrap();
+        assert!(matches!(paths[0].end, PathEnd::Unsupported(_)));
+    }
+}
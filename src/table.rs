@@ -0,0 +1,101 @@
+use crate::disassembler::disassemble;
+use crate::error::EmulatorErr;
+use crate::tracer::TraceRecord;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TableFormat {
+    Markdown,
+    Csv,
+}
+
+const HEADER: [&str; 7] = ["cycle", "PC", "instruction", "A", "B", "C", "OUT"];
+
+// 1命令ごとに、実行した命令とその直後のレジスタと出力を1行にした状態表
+// 講義資料や本の手でトレースする演習にそのまま貼れるように値は2進数で書く
+pub fn state_table(records: &[TraceRecord], format: TableFormat) -> String {
+    let rows: Vec<[String; 7]> = records
+        .iter()
+        .map(|record| {
+            [
+                record.cycle.to_string(),
+                format!("{:04b}", record.pc),
+                disassemble(record.instruction),
+                format!("{:04b}", record.register.register_a()),
+                format!("{:04b}", record.register.register_b()),
+                record.register.carry_flag().to_string(),
+                format!("{:04b}", record.output),
+            ]
+        })
+        .collect();
+
+    let mut table = String::new();
+    match format {
+        TableFormat::Markdown => {
+            table.push_str(&format!("| {} |\n", HEADER.join(" | ")));
+            table.push_str(&format!("|{}\n", "---|".repeat(HEADER.len())));
+            for row in rows {
+                table.push_str(&format!("| {} |\n", row.join(" | ")));
+            }
+        }
+        TableFormat::Csv => {
+            table.push_str(&format!("{}\n", HEADER.join(",")));
+            for row in rows {
+                // 逆アセンブル結果にカンマは含まれない
+                table.push_str(&format!("{}\n", row.join(",")));
+            }
+        }
+    }
+    table
+}
+
+impl FromStr for TableFormat {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "md" | "markdown" => Ok(TableFormat::Markdown),
+            "csv" => Ok(TableFormat::Csv),
+            _ => Err(EmulatorErr::new(&format!("Unknown table format: {}", s))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod table_tests {
+    use crate::machine::Machine;
+    use crate::profile::MachineProfile;
+    use crate::table::{state_table, TableFormat};
+    use crate::tracer::{TraceRecord, TracerConfig};
+
+    fn records() -> Vec<TraceRecord> {
+        let mut machine = Machine::new(MachineProfile::default());
+        machine.set_quiet(true);
+        machine.set_tracer(TracerConfig::default());
+        machine
+            .load_source("mov A 1111\nadd A 0001\nout 0101")
+            .unwrap();
+        machine.run(None).unwrap();
+        machine.take_tracer().unwrap().records()
+    }
+
+    #[test]
+    fn test_markdown() {
+        assert_eq!(
+            state_table(&records(), TableFormat::Markdown),
+            "| cycle | PC | instruction | A | B | C | OUT |\n\
+             |---|---|---|---|---|---|---|\n\
+             | 0 | 0000 | mov A 1111 | 1111 | 0000 | 0 | 0000 |\n\
+             | 1 | 0001 | add A 0001 | 0000 | 0000 | 1 | 0000 |\n\
+             | 2 | 0010 | out 0101 | 0000 | 0000 | 0 | 0101 |\n"
+        );
+    }
+
+    #[test]
+    fn test_csv() {
+        let table = state_table(&records(), TableFormat::Csv);
+        assert!(table.starts_with("cycle,PC,instruction,A,B,C,OUT\n0,0000,mov A 1111,"));
+        assert_eq!(table.lines().count(), 4);
+        assert!("tsv".parse::<TableFormat>().is_err());
+    }
+}
@@ -0,0 +1,267 @@
+use crate::batch;
+use crate::error::EmulatorErr;
+use crate::grader::{GradeCase, GradeSpec};
+use crate::profile::MachineProfile;
+use crate::register::Register;
+use crate::sandbox::{execute, ExecConfig, ExecError};
+use std::collections::BTreeSet;
+use std::fmt;
+
+// テストベクタを探すときの条件
+#[derive(Debug, PartialEq, Clone)]
+pub struct TestGenConfig {
+    pub profile: MachineProfile,
+    // これまでに止まらない入力はテストベクタにしない
+    pub max_cycles: usize,
+    // 入力だけでは通らない命令があれば、レジスタA, Bの初期値も変えて探す
+    pub registers: bool,
+}
+
+impl Default for TestGenConfig {
+    fn default() -> Self {
+        TestGenConfig {
+            profile: MachineProfile::default(),
+            max_cycles: 1000,
+            registers: false,
+        }
+    }
+}
+
+// 見つけたテストベクタと、どのベクタでも実行されなかった命令のアドレス
+#[derive(Debug, PartialEq, Clone)]
+pub struct TestVectors {
+    pub spec: GradeSpec,
+    pub instructions: usize,
+    pub uncovered: Vec<u8>,
+}
+
+struct Candidate {
+    case: GradeCase,
+    covered: BTreeSet<u8>,
+}
+
+// ROMの全ての命令を1度は実行する入力と初期状態の組を、なるべく少なく選ぶ
+// 止まったときの出力を期待値にするので、プログラムが正しいかどうかは確かめない
+pub fn generate(rom: &[u8], config: &TestGenConfig) -> Result<TestVectors, EmulatorErr> {
+    let mask = config.profile.register_mask();
+    let instructions: BTreeSet<u8> = (0..rom.len()).map(|address| address as u8).collect();
+
+    let starts = (0..=mask).map(|input| (input, 0, 0)).collect();
+    let mut candidates = run_all(rom, config, starts)?;
+    let reached: BTreeSet<u8> = candidates
+        .iter()
+        .flat_map(|candidate| candidate.covered.iter().copied())
+        .collect();
+    // リセット直後から通れる命令だけで済むなら、初期値を変えたケースは作らない
+    if config.registers && reached != instructions {
+        let mut starts = Vec::new();
+        for a in 0..=mask {
+            for b in 0..=mask {
+                if (a, b) != (0, 0) {
+                    starts.extend((0..=mask).map(|input| (input, a, b)));
+                }
+            }
+        }
+        candidates.extend(run_all(rom, config, starts)?);
+    }
+
+    // まだ通っていない命令を一番多く通るものから貪欲に選ぶ
+    let mut chosen: Vec<usize> = Vec::new();
+    let mut covered = BTreeSet::new();
+    loop {
+        let best = candidates
+            .iter()
+            .enumerate()
+            .map(|(index, candidate)| (index, candidate.covered.difference(&covered).count()))
+            .filter(|(_, count)| *count > 0)
+            .fold(
+                None,
+                |best: Option<(usize, usize)>, (index, count)| match best {
+                    Some((_, best_count)) if best_count >= count => best,
+                    _ => Some((index, count)),
+                },
+            );
+        let Some((index, _)) = best else { break };
+        covered.extend(candidates[index].covered.iter().copied());
+        chosen.push(index);
+    }
+    // 後から選んだケースで全て通れるようになったものは外す
+    let mut position = 0;
+    while position < chosen.len() {
+        let others: BTreeSet<u8> = chosen
+            .iter()
+            .enumerate()
+            .filter(|(other, _)| *other != position)
+            .flat_map(|(_, index)| candidates[*index].covered.iter().copied())
+            .collect();
+        if others == covered {
+            chosen.remove(position);
+        } else {
+            position += 1;
+        }
+    }
+    chosen.sort();
+
+    Ok(TestVectors {
+        spec: GradeSpec {
+            profile: config.profile.clone(),
+            max_cycles: config.max_cycles,
+            max_bytes: None,
+            cases: chosen
+                .into_iter()
+                .map(|index| candidates[index].case.clone())
+                .collect(),
+        },
+        instructions: instructions.len(),
+        uncovered: instructions.difference(&covered).copied().collect(),
+    })
+}
+
+// (入力, A, B) ごとに実行して候補を集める
+fn run_all(
+    rom: &[u8],
+    config: &TestGenConfig,
+    starts: Vec<(u8, u8, u8)>,
+) -> Result<Vec<Candidate>, EmulatorErr> {
+    let runs = batch::map(starts, |(input, a, b)| run(rom, config, input, a, b));
+    let mut candidates = Vec::new();
+    for candidate in runs {
+        candidates.extend(candidate?);
+    }
+    Ok(candidates)
+}
+
+// 止まらなかったりエラーになったりした実行は候補にしない
+fn run(
+    rom: &[u8],
+    config: &TestGenConfig,
+    input: u8,
+    a: u8,
+    b: u8,
+) -> Result<Option<Candidate>, EmulatorErr> {
+    let mut register = Register::new();
+    register.set_register_a(a);
+    register.set_register_b(b);
+    let exec_config = ExecConfig {
+        profile: config.profile.clone(),
+        input,
+        register,
+        max_cycles: config.max_cycles,
+        max_trace: Some(config.max_cycles),
+        ..ExecConfig::default()
+    };
+    let run = match execute(rom, &exec_config) {
+        Ok(run) => run,
+        Err(err @ ExecError::InvalidConfig(_)) | Err(err @ ExecError::RomTooLarge { .. }) => {
+            return Err(EmulatorErr::new(&err.to_string()))
+        }
+        Err(_) => return Ok(None),
+    };
+
+    // 停止を表す自分へのジャンプは実行されないが、そこまで来たら通ったことにする
+    let mut covered: BTreeSet<u8> = run.trace.iter().map(|record| record.pc).collect();
+    let pc = run.register.pc();
+    if (pc as usize) < rom.len() {
+        covered.insert(pc);
+    }
+    let mut name = format!("input {:04b}", input);
+    if (a, b) != (0, 0) {
+        name.push_str(&format!(", a {:04b}, b {:04b}", a, b));
+    }
+    Ok(Some(Candidate {
+        case: GradeCase {
+            name,
+            input,
+            a,
+            b,
+            outputs: run.outputs.iter().map(|(_, output)| *output).collect(),
+            expect: Vec::new(),
+        },
+        covered,
+    }))
+}
+
+// # 2 cases cover 5 of 6 instructions
+// # never executed: 0x5
+//
+// profile = "td4-strict"
+// ...
+impl fmt::Display for TestVectors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "# {} {} cover {} of {} instructions",
+            self.spec.cases.len(),
+            if self.spec.cases.len() == 1 {
+                "case"
+            } else {
+                "cases"
+            },
+            self.instructions - self.uncovered.len(),
+            self.instructions
+        )?;
+        if !self.uncovered.is_empty() {
+            let addresses: Vec<String> = self
+                .uncovered
+                .iter()
+                .map(|address| format!("0x{:x}", address))
+                .collect();
+            writeln!(f, "# never executed: {}", addresses.join(", "))?;
+        }
+        writeln!(f)?;
+        write!(f, "{}", self.spec)
+    }
+}
+
+#[cfg(test)]
+mod testgen_tests {
+    use crate::compiler::assemble;
+    use crate::grader::{grade_source, GradeResult, GradeSpec};
+    use crate::testgen::{generate, TestGenConfig};
+
+    #[test]
+    fn test_generate() {
+        // 入力が0なら 0001、それ以外は 0010 を出力する
+        let source = "in A\nadd A 1111\njnc 0101\nout 0010\njmp 0100\nout 0001\njmp 0110";
+        let rom = assemble(source).unwrap();
+        let vectors = generate(&rom, &TestGenConfig::default()).unwrap();
+        assert_eq!(vectors.uncovered, Vec::<u8>::new());
+        let cases = &vectors.spec.cases;
+        assert_eq!(cases.len(), 2);
+        assert_eq!((cases[0].input, cases[0].outputs.clone()), (0, vec![1]));
+        assert_eq!((cases[1].input, cases[1].outputs.clone()), (1, vec![2]));
+
+        // 書き出したものを読み直して、元のプログラムが合格する
+        let spec: GradeSpec = vectors.to_string().parse().unwrap();
+        assert_eq!(spec, vectors.spec);
+        let mut result = GradeResult {
+            file: "a.sasm".to_string(),
+            passed: false,
+            cycles: 0,
+            bytes: 0,
+            error: None,
+        };
+        assert!(grade_source(&spec, source, &mut result).is_ok());
+    }
+
+    #[test]
+    fn test_initial_registers() {
+        // リセット直後は B が0なので、B が0でないときの分岐は初期値を変えないと通れない
+        let rom = assemble("mov A B\nadd A 1111\njnc 0100\nout 0001\nout 0010\njmp 0101").unwrap();
+        let vectors = generate(&rom, &TestGenConfig::default()).unwrap();
+        assert_eq!(vectors.uncovered, vec![3]);
+        assert!(vectors
+            .to_string()
+            .starts_with("# 1 case cover 5 of 6 instructions\n# never executed: 0x3\n"));
+
+        let config = TestGenConfig {
+            registers: true,
+            ..TestGenConfig::default()
+        };
+        let vectors = generate(&rom, &config).unwrap();
+        assert!(vectors.uncovered.is_empty());
+        assert_eq!(vectors.spec.cases.len(), 1);
+        assert_eq!(vectors.spec.cases[0].b, 1);
+        assert_eq!(vectors.spec.cases[0].outputs, vec![1, 2]);
+    }
+}
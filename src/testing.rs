@@ -0,0 +1,283 @@
+use crate::compiler::assemble_with_profile;
+use crate::emulator::CpuEmulator;
+use crate::error::EmulatorErr;
+use crate::macros::MacroExpander;
+use crate::parser::Syntax;
+use crate::port::Ports;
+use crate::profile::MachineProfile;
+use crate::register::Register;
+use crate::rom::Rom;
+use crate::stimulus::Stimulus;
+
+// TD4のプログラムをテストするためのヘルパー
+// run_program で実行し、結果の TestRun に対してアサーションを書く
+//
+//     let run = run_program("out 0011", TestConfig::default()).unwrap();
+//     run.assert_output_sequence(&[3]).assert_halts_within(1);
+
+// ソースコードかアセンブル済みのバイト列
+pub enum Program<'a> {
+    Source(&'a str),
+    Bytes(Vec<u8>),
+}
+
+impl<'a> From<&'a str> for Program<'a> {
+    fn from(source: &'a str) -> Self {
+        Program::Source(source)
+    }
+}
+
+impl From<Vec<u8>> for Program<'_> {
+    fn from(bytes: Vec<u8>) -> Self {
+        Program::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Program<'_> {
+    fn from(bytes: &[u8]) -> Self {
+        Program::Bytes(bytes.to_vec())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TestConfig {
+    pub input: u8,
+    pub register: Register,
+    pub profile: MachineProfile,
+    // 無限ループするプログラムでも止まるように実行する命令数を制限する
+    pub max_steps: usize,
+    // あれば input の代わりにサイクルごとの入力ポート0の値を決める
+    pub stimulus: Option<Stimulus>,
+}
+
+impl TestConfig {
+    pub fn with_input(input: u8) -> Self {
+        TestConfig {
+            input,
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for TestConfig {
+    fn default() -> Self {
+        TestConfig {
+            input: 0,
+            register: Register::new(),
+            profile: MachineProfile::default(),
+            max_steps: 1000,
+            stimulus: None,
+        }
+    }
+}
+
+// 1命令を実行した直後の状態
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceEntry {
+    // 実行した命令のアドレスとその中身
+    pub pc: u8,
+    pub instruction: u8,
+    pub register: Register,
+    pub output: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestRun {
+    pub register: Register,
+    pub output: u8,
+    // OUT命令で書き込まれた値の並び
+    pub outputs: Vec<u8>,
+    pub trace: Vec<TraceEntry>,
+    pub halted: bool,
+}
+
+pub fn run_program<'a>(
+    program: impl Into<Program<'a>>,
+    config: TestConfig,
+) -> Result<TestRun, EmulatorErr> {
+    let bytes = match program.into() {
+        Program::Source(source) => {
+            assemble_with_profile(source, &MacroExpander::new(), Syntax::V1, &config.profile)?.0
+        }
+        Program::Bytes(bytes) => bytes,
+    };
+    if bytes.len() > config.profile.rom_size {
+        return Err(EmulatorErr::new(&format!(
+            "Maximum memory size is {} but the program is {} bytes",
+            config.profile.rom_size,
+            bytes.len()
+        )));
+    }
+
+    let mut emulator = CpuEmulator::with_profile(
+        config.register,
+        Ports::new(config.input, 0),
+        Rom::new(bytes),
+        config.profile,
+    );
+    emulator.set_quiet(true);
+    let mut trace = Vec::new();
+    while !emulator.does_halt() && trace.len() < config.max_steps {
+        let pc = emulator.register().pc();
+        let instruction = emulator.rom()[pc as usize];
+        if let Some(value) = config
+            .stimulus
+            .as_ref()
+            .and_then(|stimulus| stimulus.value_at(emulator.cycles()))
+        {
+            emulator.set_input_port(0, value)?;
+        }
+        emulator.step()?;
+        trace.push(TraceEntry {
+            pc,
+            instruction,
+            register: emulator.register(),
+            output: emulator.output(),
+        });
+    }
+
+    Ok(TestRun {
+        register: emulator.register(),
+        output: emulator.output(),
+        outputs: emulator
+            .output_history()
+            .iter()
+            .map(|(_, output)| *output)
+            .collect(),
+        trace,
+        halted: emulator.does_halt(),
+    })
+}
+
+impl TestRun {
+    // 実行した命令数
+    pub fn steps(&self) -> usize {
+        self.trace.len()
+    }
+
+    #[track_caller]
+    pub fn assert_output_sequence(&self, expected: &[u8]) -> &Self {
+        assert_eq!(self.outputs, expected, "unexpected output sequence");
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_reg_a(&self, expected: u8) -> &Self {
+        assert_eq!(
+            self.register.register_a(),
+            expected,
+            "unexpected register A"
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_reg_b(&self, expected: u8) -> &Self {
+        assert_eq!(
+            self.register.register_b(),
+            expected,
+            "unexpected register B"
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_carry(&self, expected: u8) -> &Self {
+        assert_eq!(
+            self.register.carry_flag(),
+            expected,
+            "unexpected carry flag"
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_halts_within(&self, steps: usize) -> &Self {
+        assert!(
+            self.halted && self.steps() <= steps,
+            "expected to halt within {} steps but {} after {} steps",
+            steps,
+            if self.halted {
+                "halted"
+            } else {
+                "still running"
+            },
+            self.steps()
+        );
+        self
+    }
+}
+
+#[cfg(test)]
+mod testing_tests {
+    use crate::profile::{FetchBeyondRom, MachineProfile};
+    use crate::testing::{run_program, TestConfig};
+
+    #[test]
+    fn test_run_source() {
+        let run = run_program(
+            "mov A 0011\nadd A 0001\nmov B A\nout B",
+            TestConfig::default(),
+        )
+        .unwrap();
+        run.assert_reg_a(4)
+            .assert_reg_b(4)
+            .assert_carry(0)
+            .assert_output_sequence(&[4])
+            .assert_halts_within(4);
+        assert_eq!(run.trace.len(), 4);
+        assert_eq!(run.trace[1].pc, 1);
+        assert_eq!(run.trace[1].register.register_a(), 4);
+    }
+
+    #[test]
+    fn test_run_bytes_with_input() {
+        // in A, out 0001
+        let run = run_program(vec![0b00100000, 0b10110001], TestConfig::with_input(9)).unwrap();
+        run.assert_reg_a(9).assert_output_sequence(&[1]);
+    }
+
+    #[test]
+    fn test_stimulus() {
+        let config = TestConfig {
+            stimulus: Some("0..2: 0b0011; repeat 2: toggle bit2".parse().unwrap()),
+            ..TestConfig::default()
+        };
+        run_program("in B\nout B\nin B\nout B\nin B\nout B", config)
+            .unwrap()
+            .assert_output_sequence(&[0b0011, 0b0111, 0b0011]);
+    }
+
+    #[test]
+    fn test_max_steps() {
+        let run = run_program("out 1\njmp 0", TestConfig::default()).unwrap();
+        assert!(!run.halted);
+        assert_eq!(run.steps(), 1000);
+    }
+
+    #[test]
+    fn test_fetch_beyond_rom_wraps() {
+        // 3命令の後ろはROMの外なので先頭に戻って繰り返す
+        let config = TestConfig {
+            profile: MachineProfile {
+                fetch_beyond_rom: FetchBeyondRom::Wrap,
+                ..MachineProfile::default()
+            },
+            max_steps: 9,
+            ..TestConfig::default()
+        };
+        let run = run_program("add A 0001\nmov B A\nout B", config).unwrap();
+        assert!(!run.halted);
+        run.assert_output_sequence(&[1, 2, 3]);
+        let pcs: Vec<u8> = run.trace.iter().map(|entry| entry.pc).collect();
+        assert_eq!(pcs, vec![0, 1, 2, 0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected to halt within 1 steps")]
+    fn test_assert_halts_within_fails() {
+        run_program("out 1\nout 2", TestConfig::default())
+            .unwrap()
+            .assert_halts_within(1);
+    }
+}
@@ -0,0 +1,344 @@
+use crate::error::EmulatorErr;
+use crate::fuzz::Xorshift;
+use crate::json::Json;
+use crate::op::Opcode;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+// 命令ごとに消費するクロック数を決める
+pub trait TimingModel: Send {
+    fn cycles(&self, opcode: &Opcode) -> usize;
+}
+
+// 実機のTD4は全命令1クロックで実行される
+pub struct UniformTiming;
+
+impl TimingModel for UniformTiming {
+    fn cycles(&self, _opcode: &Opcode) -> usize {
+        1
+    }
+}
+
+// 命令ごとにクロック数を指定する (CPIやパイプラインの説明用)
+pub struct TableTiming {
+    default: usize,
+    table: HashMap<Opcode, usize>,
+}
+
+impl TableTiming {
+    pub fn new(default: usize) -> Self {
+        Self {
+            default,
+            table: HashMap::new(),
+        }
+    }
+
+    pub fn with(mut self, opcode: Opcode, cycles: usize) -> Self {
+        self.table.insert(opcode, cycles);
+        self
+    }
+}
+
+impl TimingModel for TableTiming {
+    fn cycles(&self, opcode: &Opcode) -> usize {
+        *self.table.get(opcode).unwrap_or(&self.default)
+    }
+}
+
+// 実行結果の統計
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ExecStats {
+    pub instructions: usize,
+    pub cycles: usize,
+    // 命令のコスト (CostModel) の合計
+    pub energy: u64,
+    // JNC命令のアドレスごとの分岐の結果
+    pub branches: BTreeMap<u8, BranchStats>,
+}
+
+// 1つのJNC命令で分岐した回数としなかった回数
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct BranchStats {
+    pub taken: usize,
+    pub not_taken: usize,
+}
+
+impl BranchStats {
+    pub fn total(&self) -> usize {
+        self.taken + self.not_taken
+    }
+
+    // 分岐した割合
+    pub fn taken_ratio(&self) -> f64 {
+        match self.total() {
+            0 => 0.0,
+            total => self.taken as f64 / total as f64,
+        }
+    }
+
+    // いつも分岐する、または一度も分岐しないJNCはループの終了条件の誤りであることが多い
+    pub fn is_one_sided(&self) -> bool {
+        self.total() > 0 && (self.taken == 0 || self.not_taken == 0)
+    }
+}
+
+impl fmt::Display for BranchStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "taken {}/{} ({:.0}%)",
+            self.taken,
+            self.total(),
+            self.taken_ratio() * 100.0
+        )?;
+        match (self.taken, self.not_taken) {
+            (0, 0) => Ok(()),
+            (_, 0) => write!(f, " always taken"),
+            (0, _) => write!(f, " never taken"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ExecStats {
+    // 1命令あたりの平均クロック数
+    pub fn cpi(&self) -> f64 {
+        if self.instructions == 0 {
+            return 0.0;
+        }
+        self.cycles as f64 / self.instructions as f64
+    }
+
+    // energy は --cost がなければ1命令1の既定のコストで数えた値
+    pub fn to_json(&self) -> Json {
+        Json::object([
+            ("instructions", self.instructions.into()),
+            ("cycles", self.cycles.into()),
+            ("cpi", self.cpi().into()),
+            ("energy", self.energy.into()),
+            (
+                "branches",
+                Json::array(self.branches.iter().map(|(address, branch)| {
+                    Json::object([
+                        ("address", (*address).into()),
+                        ("taken", branch.taken.into()),
+                        ("not_taken", branch.not_taken.into()),
+                    ])
+                })),
+            ),
+        ])
+    }
+}
+
+// 実機のRC発振のずれ。0.05 なら1クロックごとに周期が±5%の範囲でばらつく
+// drift は周期をいつも同じ割合だけ長く (負なら短く) する
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct ClockJitter {
+    pub jitter: f64,
+    pub drift: f64,
+    pub seed: u64,
+}
+
+impl ClockJitter {
+    pub fn validate(&self) -> Result<(), EmulatorErr> {
+        if !(0.0..1.0).contains(&self.jitter) {
+            return Err(EmulatorErr::new(
+                "Clock jitter must be at least 0% and below 100%",
+            ));
+        }
+        if self.drift <= -1.0 || !self.drift.is_finite() {
+            return Err(EmulatorErr::new("Clock drift must be above -100%"));
+        }
+        Ok(())
+    }
+}
+
+// 5%, -2.5%, 0.05 のような割合を読む
+pub fn parse_ratio(text: &str) -> Result<f64, EmulatorErr> {
+    let error = || EmulatorErr::new(&format!("Invalid ratio: {}", text));
+    match text.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().map(|percent| percent / 100.0),
+        None => text.parse::<f64>(),
+    }
+    .ok()
+    .filter(|ratio| ratio.is_finite())
+    .ok_or_else(error)
+}
+
+// ずれのあるクロック。クロックごとの周期を決めて、理想の時間と合わせて足していく
+pub struct Clock {
+    hz: f64,
+    jitter: ClockJitter,
+    rng: Xorshift,
+    drift: ClockDrift,
+}
+
+impl Clock {
+    pub fn new(hz: f64, jitter: ClockJitter) -> Self {
+        Clock {
+            hz,
+            jitter,
+            rng: Xorshift::new(jitter.seed),
+            drift: ClockDrift::default(),
+        }
+    }
+
+    pub fn hz(&self) -> f64 {
+        self.hz
+    }
+
+    // cycles クロックにかかる実際の時間 (秒)
+    pub fn advance(&mut self, cycles: usize) -> f64 {
+        let period = 1.0 / self.hz;
+        let mut elapsed = 0.0;
+        for _ in 0..cycles {
+            // 上位53ビットから [-1, 1) の一様乱数を作る
+            let noise = if self.jitter.jitter > 0.0 {
+                (self.rng.next_u64() >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+            } else {
+                0.0
+            };
+            elapsed += period * (1.0 + self.jitter.drift) * (1.0 + self.jitter.jitter * noise);
+        }
+        self.drift.ideal += period * cycles as f64;
+        self.drift.actual += elapsed;
+        elapsed
+    }
+
+    pub fn drift(&self) -> ClockDrift {
+        self.drift
+    }
+}
+
+// これまでに進んだ理想の時間と実際の時間 (秒)
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct ClockDrift {
+    pub ideal: f64,
+    pub actual: f64,
+}
+
+impl ClockDrift {
+    pub fn to_json(&self) -> Json {
+        Json::object([
+            ("ideal_seconds", self.ideal.into()),
+            ("real_seconds", self.actual.into()),
+        ])
+    }
+
+    // 実際の時間が理想より遅れた (長くかかった) 割合
+    pub fn ratio(&self) -> f64 {
+        if self.ideal == 0.0 {
+            return 0.0;
+        }
+        (self.actual - self.ideal) / self.ideal
+    }
+}
+
+// Clock: 10.000 s ideal, 10.412 s real (+0.412 s, +4.12%)
+impl fmt::Display for ClockDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Clock: {:.3} s ideal, {:.3} s real ({:+.3} s, {:+.2}%)",
+            self.ideal,
+            self.actual,
+            self.actual - self.ideal,
+            self.ratio() * 100.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod timing_tests {
+    use crate::op::Opcode;
+    use crate::timing::{
+        parse_ratio, BranchStats, Clock, ClockJitter, ExecStats, TableTiming, TimingModel,
+        UniformTiming,
+    };
+
+    #[test]
+    fn test_uniform_timing() {
+        assert_eq!(UniformTiming.cycles(&Opcode::Jmp), 1);
+        assert_eq!(UniformTiming.cycles(&Opcode::AddA), 1);
+    }
+
+    #[test]
+    fn test_table_timing() {
+        let timing = TableTiming::new(1)
+            .with(Opcode::Jmp, 3)
+            .with(Opcode::Jnc, 2);
+        assert_eq!(timing.cycles(&Opcode::Jmp), 3);
+        assert_eq!(timing.cycles(&Opcode::Jnc), 2);
+        assert_eq!(timing.cycles(&Opcode::MovA), 1);
+    }
+
+    #[test]
+    fn test_cpi() {
+        let stats = ExecStats {
+            instructions: 4,
+            cycles: 6,
+            ..ExecStats::default()
+        };
+        assert_eq!(stats.cpi(), 1.5);
+        assert_eq!(ExecStats::default().cpi(), 0.0);
+    }
+
+    #[test]
+    fn test_branch_stats() {
+        let always = BranchStats {
+            taken: 5,
+            not_taken: 0,
+        };
+        assert!(always.is_one_sided());
+        assert_eq!(always.to_string(), "taken 5/5 (100%) always taken");
+
+        let never = BranchStats {
+            taken: 0,
+            not_taken: 2,
+        };
+        assert_eq!(never.to_string(), "taken 0/2 (0%) never taken");
+        assert!(!BranchStats::default().is_one_sided());
+    }
+
+    #[test]
+    fn test_clock_jitter() {
+        // ずれがなければ理想どおり
+        let mut clock = Clock::new(10.0, ClockJitter::default());
+        assert!((clock.advance(20) - 2.0).abs() < 1e-9);
+        assert!(clock.drift().ratio().abs() < 1e-9);
+
+        // 周期が5%長く、±10%ばらつく
+        let jitter = ClockJitter {
+            jitter: 0.1,
+            drift: 0.05,
+            seed: 7,
+        };
+        let mut clock = Clock::new(1.0, jitter);
+        let periods: Vec<f64> = (0..1000).map(|_| clock.advance(1)).collect();
+        assert!(periods.iter().all(|period| (0.945..1.155).contains(period)));
+        assert!(periods.iter().any(|period| *period < 1.0));
+        let drift = clock.drift();
+        assert_eq!(drift.ideal, 1000.0);
+        assert!((drift.ratio() - 0.05).abs() < 0.01);
+
+        // 同じシードなら同じずれになる
+        let mut again = Clock::new(1.0, jitter);
+        again.advance(1000);
+        assert_eq!(again.drift(), drift);
+        assert!(drift.to_string().starts_with("Clock: 1000.000 s ideal, 10"));
+    }
+
+    #[test]
+    fn test_parse_ratio() {
+        assert_eq!(parse_ratio("5%").unwrap(), 0.05);
+        assert_eq!(parse_ratio("-2.5%").unwrap(), -0.025);
+        assert_eq!(parse_ratio("0.1").unwrap(), 0.1);
+        assert!(parse_ratio("five").is_err());
+        assert!(ClockJitter {
+            jitter: 1.0,
+            ..ClockJitter::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum Register {
     A,
     B,
@@ -6,9 +6,9 @@ pub enum Register {
 
 impl From<String> for Register {
     fn from(a: String) -> Self {
-        if a == "A".to_string() {
+        if a == "A" {
             Register::A
-        } else if a == "B".to_string() {
+        } else if a == "B" {
             Register::B
         } else {
             panic!("couldn't parse")
@@ -16,7 +16,7 @@ impl From<String> for Register {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Token {
     Mov(Register, u8),
     MovAB,
@@ -24,7 +24,23 @@ pub enum Token {
     Add(Register, u8),
     Jmp(u8),
     Jnc(u8),
-    In(Register),
+    // 2つめの値は拡張モードで選ぶポートの番号
+    In(Register, u8),
     OutIm(u8),
-    OutB,
+    OutB(u8),
+    // 拡張モードのサブルーチン呼び出し
+    Call(u8),
+    Ret,
+    // 拡張モードの減算と比較 (キャリーはボロー)
+    Sub(Register, u8),
+    Cmp,
+    // 拡張モードのメモリの読み書き (ld A [B] / st [B] A)
+    Ld,
+    St,
+    // 拡張モードのAとBの入れ替え (swap A B)
+    Swap,
+    // .byte / .data で置かれる生のデータ
+    Byte(u8),
+    // .org で以降の命令を置くアドレス
+    Org(u8),
 }
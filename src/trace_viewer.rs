@@ -0,0 +1,275 @@
+use crate::disassembler::disassemble;
+use crate::tracer::TraceRecord;
+
+// 「次に変化したところ」へ移動するときに見るフィールド
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TraceField {
+    Pc,
+    A,
+    B,
+    Carry,
+    Out,
+}
+
+impl TraceField {
+    pub const ALL: [TraceField; 5] = [
+        TraceField::Pc,
+        TraceField::A,
+        TraceField::B,
+        TraceField::Carry,
+        TraceField::Out,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TraceField::Pc => "PC",
+            TraceField::A => "A",
+            TraceField::B => "B",
+            TraceField::Carry => "C",
+            TraceField::Out => "OUT",
+        }
+    }
+
+    // 命令を実行した直後の値
+    pub fn read(&self, record: &TraceRecord) -> u8 {
+        match self {
+            TraceField::Pc => record.register.pc(),
+            TraceField::A => record.register.register_a(),
+            TraceField::B => record.register.register_b(),
+            TraceField::Carry => record.register.carry_flag(),
+            TraceField::Out => record.output,
+        }
+    }
+}
+
+// トレースを1命令ずつ行き来しながら表示する
+// キー入力や画面の扱いはフロントエンドに任せ、ここでは位置の移動と表示内容だけを決める
+pub struct TraceViewer {
+    records: Vec<TraceRecord>,
+    // トレースから分かったROMの中身。実行されなかったアドレスはNone
+    rom: Vec<Option<u8>>,
+    cursor: usize,
+    field: TraceField,
+}
+
+impl TraceViewer {
+    pub fn new(records: Vec<TraceRecord>) -> Self {
+        let mut rom = vec![None; 16];
+        for record in &records {
+            let address = record.pc as usize;
+            if address >= rom.len() {
+                rom.resize(address + 1, None);
+            }
+            rom[address] = Some(record.instruction);
+        }
+        TraceViewer {
+            records,
+            rom,
+            cursor: 0,
+            field: TraceField::Pc,
+        }
+    }
+
+    // 実行中に書き換えられていなければトレースより正確なROMを表示できる
+    pub fn set_rom(&mut self, rom: &[u8]) {
+        self.rom = rom.iter().map(|data| Some(*data)).collect();
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn current(&self) -> Option<&TraceRecord> {
+        self.records.get(self.cursor)
+    }
+
+    pub fn field(&self) -> TraceField {
+        self.field
+    }
+
+    pub fn select_field(&mut self, field: TraceField) {
+        self.field = field;
+    }
+
+    // 注目するフィールドを PC -> A -> B -> C -> OUT の順に切り替える
+    pub fn select_next_field(&mut self) {
+        let index = TraceField::ALL
+            .iter()
+            .position(|field| *field == self.field)
+            .unwrap_or(0);
+        self.field = TraceField::ALL[(index + 1) % TraceField::ALL.len()];
+    }
+
+    pub fn forward(&mut self, n: usize) {
+        self.cursor = (self.cursor + n).min(self.records.len().saturating_sub(1));
+    }
+
+    pub fn back(&mut self, n: usize) {
+        self.cursor = self.cursor.saturating_sub(n);
+    }
+
+    pub fn first(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn last(&mut self) {
+        self.cursor = self.records.len().saturating_sub(1);
+    }
+
+    // 直前の命令から注目するフィールドが変わったか
+    fn changed_at(&self, index: usize) -> bool {
+        index > 0
+            && self.field.read(&self.records[index]) != self.field.read(&self.records[index - 1])
+    }
+
+    // 注目するフィールドが次に変わる命令へ移動する。なければ動かずfalseを返す
+    pub fn next_change(&mut self) -> bool {
+        match (self.cursor + 1..self.records.len()).find(|index| self.changed_at(*index)) {
+            Some(index) => {
+                self.cursor = index;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn prev_change(&mut self) -> bool {
+        match (1..self.cursor).rev().find(|index| self.changed_at(*index)) {
+            Some(index) => {
+                self.cursor = index;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // 今の位置の状態とROMを表示する。変化したフィールドには * を付け、注目中のフィールドは [] で囲む
+    pub fn render(&self) -> String {
+        let record = match self.current() {
+            Some(record) => record,
+            None => return "The trace is empty\n".to_string(),
+        };
+
+        let mut screen = format!(
+            "Cycle {}  ({}/{})\n",
+            record.cycle,
+            self.cursor + 1,
+            self.records.len()
+        );
+        let fields: Vec<String> = TraceField::ALL
+            .iter()
+            .map(|field| {
+                let value = field.read(record);
+                let text = match field {
+                    TraceField::Pc => format!("PC: 0x{:x}", value),
+                    TraceField::Carry => format!("C: {}", value),
+                    _ => format!("{}: 0b{:04b}", field.name(), value),
+                };
+                let mark = if self.changed_at_field(*field) {
+                    "*"
+                } else {
+                    ""
+                };
+                if *field == self.field {
+                    format!("[{}{}]", text, mark)
+                } else {
+                    format!("{}{}", text, mark)
+                }
+            })
+            .collect();
+        screen.push_str(&fields.join("  "));
+        screen.push('\n');
+        screen.push_str(&format!(
+            "Executed: 0x{:x}  {:08b}  {}\n\n",
+            record.pc,
+            record.instruction,
+            disassemble(record.instruction)
+        ));
+
+        for (address, data) in self.rom.iter().enumerate() {
+            let marker = if address == record.pc as usize {
+                ">"
+            } else {
+                " "
+            };
+            let line = match data {
+                Some(data) => format!("{:08b}  {}", data, disassemble(*data)),
+                None => "--------".to_string(),
+            };
+            screen.push_str(&format!("{} 0x{:x}  {}\n", marker, address, line));
+        }
+        screen
+    }
+
+    fn changed_at_field(&self, field: TraceField) -> bool {
+        self.cursor > 0
+            && field.read(&self.records[self.cursor]) != field.read(&self.records[self.cursor - 1])
+    }
+}
+
+#[cfg(test)]
+mod trace_viewer_tests {
+    use crate::emulator::CpuEmulator;
+    use crate::port::Ports;
+    use crate::register::Register;
+    use crate::rom::Rom;
+    use crate::trace_viewer::{TraceField, TraceViewer};
+    use crate::tracer::{Tracer, TracerConfig};
+
+    // mov A 1110, add A 0001, jnc 0001, out 1111 をトレースする
+    fn viewer() -> TraceViewer {
+        let mut emu = CpuEmulator::with(
+            Register::new(),
+            Ports::new(0b0000, 0b0000),
+            Rom::new(vec![0b00111110, 0b00000001, 0b11100001, 0b10111111]),
+        );
+        emu.set_quiet(true);
+        emu.set_tracer(Tracer::new(TracerConfig::default()));
+        emu.exec().unwrap();
+        TraceViewer::new(emu.take_tracer().unwrap().records())
+    }
+
+    #[test]
+    fn test_navigation() {
+        let mut viewer = viewer();
+        // mov, add, jnc, add, jnc, out の6命令
+        viewer.back(1);
+        assert_eq!(viewer.cursor(), 0);
+        viewer.forward(100);
+        assert_eq!(viewer.cursor(), 5);
+        viewer.first();
+
+        viewer.select_field(TraceField::Out);
+        assert!(viewer.next_change());
+        assert_eq!(viewer.cursor(), 5);
+        assert!(!viewer.next_change());
+
+        viewer.select_next_field();
+        assert_eq!(viewer.field(), TraceField::Pc);
+        viewer.select_field(TraceField::Carry);
+        assert!(viewer.prev_change());
+        // 2回目の add で桁あふれし、次の jnc でクリアされる
+        assert_eq!(viewer.cursor(), 4);
+        assert!(viewer.prev_change());
+        assert_eq!(viewer.cursor(), 3);
+        assert!(!viewer.prev_change());
+    }
+
+    #[test]
+    fn test_render() {
+        let mut viewer = viewer();
+        viewer.forward(1);
+        viewer.select_field(TraceField::A);
+        let screen = viewer.render();
+        assert!(screen
+            .starts_with("Cycle 1  (2/6)\nPC: 0x2*  [A: 0b1111*]  B: 0b0000  C: 0  OUT: 0b0000\n"));
+        assert!(screen.contains("Executed: 0x1  00000001  add A 0001\n"));
+        assert!(screen.contains("> 0x1  00000001  add A 0001\n"));
+        assert!(screen.contains("  0x4  --------\n"));
+
+        assert_eq!(
+            TraceViewer::new(Vec::new()).render(),
+            "The trace is empty\n"
+        );
+    }
+}
@@ -0,0 +1,420 @@
+use crate::disassembler::{disassemble_with, DisasmStyle};
+use crate::error::EmulatorErr;
+use crate::profile::FlagModel;
+use crate::register::Register;
+use crate::style::Style;
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+// バイナリ形式のトレースの先頭に置く識別子とバージョン
+// 2 でヘッダーにフラグモデルを足した。古い版は migrate で今の版に直してから読む
+const MAGIC: &[u8; 4] = b"TD4T";
+const VERSION: u8 = 2;
+
+// 命令を1つ実行した直後の状態
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceRecord {
+    // 命令を実行し始めたサイクル
+    pub cycle: usize,
+    // 実行した命令のアドレスとその中身
+    pub pc: u8,
+    pub instruction: u8,
+    pub register: Register,
+    pub output: u8,
+    // キャリーを決めたフラグモデル。バイナリ形式からは復元できないので None
+    pub flag_model: Option<FlagModel>,
+}
+
+// Tracer::encode で書き出す形式
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TraceEncoding {
+    Text,
+    // 1命令7バイト + サイクルの差分で、長い実行でも小さく保存できる
+    Binary,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TracerConfig {
+    // Some(n) なら直近のnサイクル分だけ残すリングバッファになる
+    pub capacity: Option<usize>,
+    pub encoding: TraceEncoding,
+}
+
+impl Default for TracerConfig {
+    fn default() -> Self {
+        TracerConfig {
+            capacity: None,
+            encoding: TraceEncoding::Text,
+        }
+    }
+}
+
+pub struct Tracer {
+    config: TracerConfig,
+    records: VecDeque<TraceRecord>,
+    // リングバッファからあふれて捨てた数
+    dropped: usize,
+}
+
+impl Tracer {
+    pub fn new(config: TracerConfig) -> Self {
+        Tracer {
+            config,
+            records: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn record(&mut self, record: TraceRecord) {
+        if let Some(capacity) = self.config.capacity {
+            if capacity == 0 {
+                self.dropped += 1;
+                return;
+            }
+            // サイクル数で残す範囲を決めるので、複数サイクルの命令があれば件数はcapacityより少なくなる
+            while self
+                .records
+                .front()
+                .is_some_and(|first| first.cycle + capacity <= record.cycle)
+            {
+                self.records.pop_front();
+                self.dropped += 1;
+            }
+        }
+        self.records.push_back(record);
+    }
+
+    pub fn records(&self) -> Vec<TraceRecord> {
+        self.records.iter().cloned().collect()
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let records = self.records();
+        match self.config.encoding {
+            TraceEncoding::Text => pretty_print(&records).into_bytes(),
+            TraceEncoding::Binary => encode_binary(&records),
+        }
+    }
+}
+
+// ヘッダーのあと、フラグモデル (1バイト)、1命令ずつ、サイクルの差分 (可変長), PC, 命令,
+// 実行後のPC, A, B, C, OUT の順に並べる
+pub fn encode_binary(records: &[TraceRecord]) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    // 1つのトレースは1つのエミュレータで記録するので、フラグモデルは全ての命令で同じ
+    bytes.push(encode_flag_model(
+        records.first().and_then(|record| record.flag_model),
+    ));
+
+    let mut last_cycle = 0;
+    for record in records {
+        push_varint(&mut bytes, record.cycle - last_cycle);
+        last_cycle = record.cycle;
+        bytes.extend_from_slice(&[
+            record.pc,
+            record.instruction,
+            record.register.pc(),
+            record.register.register_a(),
+            record.register.register_b(),
+            record.register.carry_flag(),
+            record.output,
+        ]);
+    }
+    bytes
+}
+
+pub fn decode_binary(bytes: &[u8]) -> Result<Vec<TraceRecord>, EmulatorErr> {
+    if bytes.len() < 5 || &bytes[..4] != MAGIC {
+        return Err(EmulatorErr::new("Not a binary trace"));
+    }
+    let bytes = migrate(bytes[4], &bytes[5..])?;
+    let flag_model = decode_flag_model(
+        *bytes
+            .first()
+            .ok_or_else(|| EmulatorErr::new("Binary trace is truncated"))?,
+    )?;
+
+    let mut records = Vec::new();
+    let mut pos = 1;
+    let mut cycle = 0;
+    while pos < bytes.len() {
+        cycle += read_varint(&bytes, &mut pos)?;
+        let fields = bytes
+            .get(pos..pos + 7)
+            .ok_or_else(|| EmulatorErr::new("Binary trace is truncated"))?;
+        pos += 7;
+
+        let mut register = Register::new();
+        register.set_pc(fields[2]);
+        register.set_register_a(fields[3]);
+        register.set_register_b(fields[4]);
+        register.set_carry_flag(fields[5]);
+        records.push(TraceRecord {
+            cycle,
+            pc: fields[0],
+            instruction: fields[1],
+            register,
+            output: fields[6],
+            flag_model,
+        });
+    }
+    Ok(records)
+}
+
+// 古い版のヘッダーより後ろを1版ずつ今の版の形に直す
+fn migrate(version: u8, body: &[u8]) -> Result<Vec<u8>, EmulatorErr> {
+    if version == 0 || version > VERSION {
+        return Err(EmulatorErr::new(&format!(
+            "Unsupported trace version: {} (this td4emu reads up to version {})",
+            version, VERSION
+        )));
+    }
+    let mut body = body.to_vec();
+    for from in version..VERSION {
+        match from {
+            // 1 にはフラグモデルがないので「不明」を入れる
+            1 => body.insert(0, 0),
+            _ => unreachable!(),
+        }
+    }
+    Ok(body)
+}
+
+// 0 は不明、それ以外は FlagModel::NAMES の位置 + 1
+fn encode_flag_model(flag_model: Option<FlagModel>) -> u8 {
+    flag_model.map_or(0, |model| {
+        FlagModel::NAMES
+            .iter()
+            .position(|name| *name == model.name())
+            .unwrap() as u8
+            + 1
+    })
+}
+
+fn decode_flag_model(code: u8) -> Result<Option<FlagModel>, EmulatorErr> {
+    match code {
+        0 => Ok(None),
+        _ => FlagModel::NAMES
+            .get(code as usize - 1)
+            .ok_or_else(|| EmulatorErr::new(&format!("Unknown flag model in trace: {}", code)))?
+            .parse()
+            .map(Some),
+    }
+}
+
+// 7bitずつ下位から並べ、続きがあれば最上位bitを立てる
+fn push_varint(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<usize, EmulatorErr> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *bytes
+            .get(*pos)
+            .ok_or_else(|| EmulatorErr::new("Binary trace is truncated"))?;
+        *pos += 1;
+        if shift >= usize::BITS {
+            return Err(EmulatorErr::new("Cycle in binary trace is too large"));
+        }
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+pub fn pretty_print(records: &[TraceRecord]) -> String {
+    pretty_print_styled(records, Style::default())
+}
+
+// 端末に表示するときは前の行から変わったレジスタと出力を目立たせる
+pub fn pretty_print_styled(records: &[TraceRecord], style: Style) -> String {
+    pretty_print_with(records, style, &DisasmStyle::default())
+}
+
+// 命令の列は disasm の書き方で逆アセンブルする
+pub fn pretty_print_with(records: &[TraceRecord], style: Style, disasm: &DisasmStyle) -> String {
+    let mut text = String::new();
+    let mut previous: Option<&TraceRecord> = None;
+    for record in records {
+        let changed = |value: fn(&TraceRecord) -> u8| {
+            previous.is_some_and(|previous| value(previous) != value(record))
+        };
+        // キャリーが変わった命令には、その変化を決めたフラグモデルを添える
+        let note = match record.flag_model {
+            Some(model) if changed(|record| record.register.carry_flag()) => {
+                format!("  [{}]", model.name())
+            }
+            _ => String::new(),
+        };
+        let raw = match disasm.raw {
+            true => format!("{:08b}  ", record.instruction),
+            false => String::new(),
+        };
+        text.push_str(&format!(
+            "{:>6}  0x{:x}  {}{:<12}  A: {} B: {} C: {} OUT: {}{}\n",
+            record.cycle,
+            record.pc,
+            raw,
+            disassemble_with(record.instruction, disasm),
+            style.changed(
+                &format!("0b{:04b}", record.register.register_a()),
+                changed(|record| record.register.register_a())
+            ),
+            style.changed(
+                &format!("0b{:04b}", record.register.register_b()),
+                changed(|record| record.register.register_b())
+            ),
+            style.changed(
+                &record.register.carry_flag().to_string(),
+                changed(|record| record.register.carry_flag())
+            ),
+            style.changed(
+                &format!("0b{:04b}", record.output),
+                changed(|record| record.output)
+            ),
+            note,
+        ));
+        previous = Some(record);
+    }
+    text
+}
+
+impl FromStr for TraceEncoding {
+    type Err = EmulatorErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(TraceEncoding::Text),
+            "bin" | "binary" => Ok(TraceEncoding::Binary),
+            _ => Err(EmulatorErr::new(&format!("Unknown trace format: {}", s))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tracer_tests {
+    use crate::profile::FlagModel;
+    use crate::register::Register;
+    use crate::style::Style;
+    use crate::tracer::{
+        decode_binary, encode_binary, pretty_print, pretty_print_styled, TraceEncoding,
+        TraceRecord, Tracer, TracerConfig,
+    };
+
+    fn record(cycle: usize, pc: u8) -> TraceRecord {
+        let mut register = Register::new();
+        register.set_pc(pc + 1);
+        register.set_register_a(pc);
+        TraceRecord {
+            cycle,
+            pc,
+            instruction: 0b00000001,
+            register,
+            output: 0,
+            flag_model: None,
+        }
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut tracer = Tracer::new(TracerConfig {
+            capacity: Some(3),
+            ..TracerConfig::default()
+        });
+        for cycle in 0..10 {
+            tracer.record(record(cycle, cycle as u8 % 16));
+        }
+        let cycles: Vec<usize> = tracer.records().iter().map(|r| r.cycle).collect();
+        assert_eq!(cycles, vec![7, 8, 9]);
+        assert_eq!(tracer.dropped(), 7);
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let records = vec![record(0, 0), record(1, 1), record(300, 2)];
+        let bytes = encode_binary(&records);
+        // ヘッダ6バイト + 1命令7バイト + サイクルの差分 (300は2バイト)
+        assert_eq!(bytes.len(), 6 + 21 + 4);
+        assert_eq!(decode_binary(&bytes).unwrap(), records);
+
+        assert!(decode_binary(b"TD4X\x01").is_err());
+        assert!(decode_binary(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_version_migration() {
+        let mut records = vec![record(0, 0), record(2, 1)];
+        for record in records.iter_mut() {
+            record.flag_model = Some(FlagModel::ArithmeticOnly);
+        }
+        let bytes = encode_binary(&records);
+        assert_eq!(&bytes[4..6], &[2, 2]);
+        assert_eq!(decode_binary(&bytes).unwrap(), records);
+
+        // 版1 (フラグモデルなし) で書いたトレースもそのまま読める
+        let mut version1 = b"TD4T\x01".to_vec();
+        version1.extend_from_slice(&bytes[6..]);
+        let migrated = decode_binary(&version1).unwrap();
+        assert_eq!(migrated.len(), 2);
+        assert_eq!(migrated[1].cycle, 2);
+        assert_eq!(migrated[1].flag_model, None);
+
+        let err = decode_binary(b"TD4T\x03").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unsupported trace version: 3 (this td4emu reads up to version 2)"
+        );
+        assert!(decode_binary(b"TD4T\x02\x09").is_err());
+    }
+
+    #[test]
+    fn test_pretty_print() {
+        let text = pretty_print(&[record(5, 2)]);
+        assert_eq!(
+            text,
+            "     5  0x2  00000001  add A 0001    A: 0b0010 B: 0b0000 C: 0 OUT: 0b0000\n"
+        );
+
+        let tracer = Tracer::new(TracerConfig {
+            capacity: None,
+            encoding: TraceEncoding::Binary,
+        });
+        assert_eq!(tracer.encode(), b"TD4T\x02\x00");
+    }
+
+    #[test]
+    fn test_pretty_print_highlights_changes() {
+        let text = pretty_print_styled(&[record(5, 2), record(6, 3)], Style::new(true));
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(!lines[0].contains('\x1b'));
+        assert!(lines[1].contains("A: \x1b[1;36m0b0011\x1b[0m B: 0b0000"));
+    }
+
+    #[test]
+    fn test_pretty_print_flag_model() {
+        let mut records = vec![record(0, 0), record(1, 1), record(2, 2)];
+        records[1].register.set_carry_flag(1);
+        for record in records.iter_mut() {
+            record.flag_model = Some(FlagModel::JumpsKeepCarry);
+        }
+        let text = pretty_print(&records);
+        let lines: Vec<&str> = text.lines().collect();
+        // キャリーが変わった行にだけ付く
+        assert!(!lines[0].contains('['));
+        assert!(lines[1].ends_with("C: 1 OUT: 0b0000  [jumps-keep-carry]"));
+        assert!(lines[2].ends_with("C: 0 OUT: 0b0000  [jumps-keep-carry]"));
+    }
+}
@@ -0,0 +1,214 @@
+use crate::compiler::assemble_with_profile;
+use crate::debugger::{Debugger, Snapshot};
+use crate::directive::ProgramConfig;
+use crate::emulator::CpuEmulator;
+use crate::error::EmulatorErr;
+use crate::json;
+use crate::macros::MacroExpander;
+use crate::parser::Syntax;
+use crate::port::Ports;
+use crate::profile::MachineProfile;
+use crate::register::Register;
+use crate::rom::Rom;
+use crate::style;
+use std::thread;
+use tiny_http::{Header, Request, Response, Server};
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+// ブラウザで動かすデモ (td4emu web)
+// / でページを返し、/ws のWebSocketで1メッセージずつコマンドを受け取って状態を返す
+//
+//     load\n<ソースコード>   アセンブルして読み込む (;! の設定も読む)
+//     step 1, set IN 5, reset, break 3 ...   デバッガのコマンド
+//
+// 返事はいつも {"ok":true,"message":"...","pc":0,"a":0,...} の形のJSON
+// "state" には Snapshot の1行の形で同じ状態を入れる
+const PAGE: &str = include_str!("web.html");
+
+// 止まるまで実行するコマンドはサーバーが返事をできなくなるので受け付けない
+// セッションのファイルはサーバー側に読み書きすることになるので受け付けない
+const REFUSED: [&str; 7] = [
+    "continue",
+    "c",
+    "until",
+    "u",
+    "next-out",
+    "save-session",
+    "load-session",
+];
+
+pub struct Session {
+    profile: MachineProfile,
+    debugger: Option<Debugger>,
+}
+
+impl Session {
+    pub fn new(profile: MachineProfile) -> Self {
+        Session {
+            profile,
+            debugger: None,
+        }
+    }
+
+    // 1メッセージを処理して返事のJSONを返す
+    pub fn handle(&mut self, message: &str) -> String {
+        let result = match message.split_once('\n') {
+            Some(("load", source)) => self.load(source),
+            None if message.trim() == "load" => self.load(""),
+            _ => self.execute(message),
+        };
+        match result {
+            Ok(message) => self.reply(true, &message),
+            Err(err) => self.reply(false, &err.to_string()),
+        }
+    }
+
+    fn load(&mut self, source: &str) -> Result<String, EmulatorErr> {
+        let config: ProgramConfig = source.parse()?;
+        let profile = config.profile.unwrap_or_else(|| self.profile.clone());
+        let (program, _) =
+            assemble_with_profile(source, &MacroExpander::new(), Syntax::V1, &profile)?;
+        if program.len() > profile.rom_size {
+            return Err(EmulatorErr::new(&format!(
+                "the program is {} bytes but {} has only {} bytes of ROM",
+                program.len(),
+                profile.name,
+                profile.rom_size
+            )));
+        }
+        let size = program.len();
+        let ports = Ports::new(config.input.unwrap_or(0), 0b0000);
+        let mut emulator =
+            CpuEmulator::with_profile(Register::new(), ports, Rom::new(program), profile);
+        emulator.set_quiet(true);
+        self.debugger = Some(Debugger::new(emulator));
+        Ok(format!("Loaded {} bytes", size))
+    }
+
+    fn execute(&mut self, command: &str) -> Result<String, EmulatorErr> {
+        let debugger = self
+            .debugger
+            .as_mut()
+            .ok_or_else(|| EmulatorErr::new("No program loaded"))?;
+        let name = command.split_whitespace().next().unwrap_or("");
+        if REFUSED.contains(&name) {
+            return Err(EmulatorErr::new(&format!(
+                "{} is not available in the web UI. Use step n instead",
+                name
+            )));
+        }
+        debugger.execute(command)
+    }
+
+    fn reply(&self, ok: bool, message: &str) -> String {
+        let mut reply = format!("{{\"ok\":{},\"message\":{}", ok, json::string(message));
+        if let Some(debugger) = &self.debugger {
+            let emulator = debugger.emulator();
+            let register = emulator.register();
+            let rom: Vec<String> = emulator.rom().iter().map(|byte| byte.to_string()).collect();
+            reply.push_str(&format!(
+                ",\"pc\":{},\"a\":{},\"b\":{},\"carry\":{},\"input\":{},\"output\":{},\"halted\":{},\"rom\":[{}],\"state\":{}",
+                register.pc(),
+                register.register_a(),
+                register.register_b(),
+                register.carry_flag(),
+                emulator.input(),
+                emulator.output(),
+                emulator.does_halt(),
+                rom.join(","),
+                json::string(&Snapshot::take(emulator).to_string())
+            ));
+        }
+        reply.push('}');
+        reply
+    }
+}
+
+// address (127.0.0.1:8040 など) で待ち受ける。接続ごとに別々のエミュレータを動かす
+pub fn serve(address: &str, profile: MachineProfile) -> Result<(), EmulatorErr> {
+    let server = Server::http(address)
+        .map_err(|err| EmulatorErr::new(&format!("can't listen on {}: {}", address, err)))?;
+    println!("Serving the web UI on http://{}/", address);
+    for request in server.incoming_requests() {
+        let result = match request.url() {
+            "/" | "/index.html" => {
+                let html = Header::from_bytes("Content-Type", "text/html; charset=utf-8").unwrap();
+                request.respond(Response::from_string(PAGE).with_header(html))
+            }
+            "/ws" => {
+                let profile = profile.clone();
+                thread::spawn(move || {
+                    if let Err(err) = control(request, profile) {
+                        style::warn(&format!("web UI connection closed: {}", err));
+                    }
+                });
+                Ok(())
+            }
+            _ => request.respond(Response::from_string("Not Found").with_status_code(404)),
+        };
+        if let Err(err) = result {
+            style::warn(&format!("failed to respond: {}", err));
+        }
+    }
+    Ok(())
+}
+
+// WebSocketに切り替えて、閉じられるまでコマンドに答える
+fn control(request: Request, profile: MachineProfile) -> Result<(), EmulatorErr> {
+    let key = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Sec-WebSocket-Key"))
+        .map(|header| header.value.to_string())
+        .ok_or_else(|| EmulatorErr::new("not a WebSocket request"))?;
+    let accept = Header::from_bytes("Sec-WebSocket-Accept", derive_accept_key(key.as_bytes()))
+        .map_err(|_| EmulatorErr::new("invalid WebSocket key"))?;
+    let stream = request.upgrade("websocket", Response::empty(101).with_header(accept));
+    let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+
+    let mut session = Session::new(profile);
+    loop {
+        let reply = match socket.read() {
+            Ok(Message::Text(text)) => session.handle(&text),
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(_) => continue,
+            Err(err) => return Err(EmulatorErr::new(&err.to_string())),
+        };
+        socket
+            .send(Message::text(reply))
+            .map_err(|err| EmulatorErr::new(&err.to_string()))?;
+    }
+}
+
+#[cfg(test)]
+mod web_tests {
+    use crate::profile::MachineProfile;
+    use crate::web::Session;
+
+    #[test]
+    fn test_session() {
+        let mut session = Session::new(MachineProfile::default());
+        assert_eq!(
+            session.handle("step"),
+            "{\"ok\":false,\"message\":\"No program loaded\"}"
+        );
+
+        let reply = session.handle("load\n;!input 0b0011\nin B\nout B\njmp 0010");
+        assert!(reply.starts_with("{\"ok\":true,\"message\":\"Loaded 3 bytes\""));
+        assert!(reply.contains("\"input\":3"));
+
+        let reply = session.handle("step 2");
+        assert!(reply.contains("\"pc\":2,\"a\":0,\"b\":3,\"carry\":0,\"input\":3,\"output\":3"));
+        assert!(reply.contains("\"halted\":true"));
+        assert!(reply.contains("\"rom\":[96,144,242]"));
+        assert!(reply.contains(
+            "\"state\":\"pc=0x2 a=0b0000 b=0b0011 c=0 in=0b0011 out=0b0011 stack= shadow=0x0 cycles=2 rom=60,90,f2\""
+        ));
+
+        let reply = session.handle("continue");
+        assert!(reply.starts_with("{\"ok\":false,\"message\":\"continue is not available"));
+        assert!(session.handle("load\nmov A 10000").contains("\"ok\":false"));
+    }
+}